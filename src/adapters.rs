@@ -1,43 +1,123 @@
 //! Adapter implementations for domain traits (imperative shell)
 
-use crate::domain::{LogLevel, Logger, QueryExecutor, VariableResolver};
+use crate::Module;
+use crate::config::{MainConfig, ModuleConfig};
+use crate::content_type::{ContentType, negotiate_content_type};
+use crate::domain::{
+    self, BatchExecutor, DbError, LogLevel, Logger, ParameterSet, QueryExecutor, ValidatedConfig,
+    VariableResolver, WriteExecutor, WriteOutcome,
+};
+use crate::formatter::StreamedResponse;
+use crate::handler_types::StageRequest;
+use crate::nginx_helpers;
 use crate::query;
-use crate::types::{DatabasePath, SqlQuery};
+use crate::types::{DatabasePath, NginxVariable, RowShape, SqlQuery, StreamBuffer, WriteQuery};
 use crate::variable;
-use ngx::http::Request;
+use ngx::core::Status;
+use ngx::ffi::ngx_log_t;
+use ngx::http::{HTTPStatus, HttpModuleLocationConf, HttpModuleMainConf, Request};
 use ngx::ngx_log_error;
 use serde_json::Value;
 use std::collections::HashMap;
 
 /// Adapter for nginx variable resolution
+///
+/// `$arg_` values arrive as they appear in the query string; they are
+/// percent-decoded unless the location has `sqlite_decode_args off`.
+///
+/// Each variable is resolved once and remembered for as long as the resolver
+/// lives, so a variable bound to several parameters is looked up (and
+/// decoded) once and every binding sees the same value. A request's
+/// parameters are resolved with one resolver, and the queries it runs share
+/// the result.
 pub struct NginxVariableResolver<'a> {
     request: &'a mut Request,
+    decode_args: bool,
+    resolved: HashMap<String, Result<String, String>>,
+    resolved_values: HashMap<String, Result<Vec<String>, String>>,
 }
 
 impl<'a> NginxVariableResolver<'a> {
     pub fn new(request: &'a mut Request) -> Self {
-        NginxVariableResolver { request }
+        let decode_args = Module::location_conf(request)
+            .and_then(|config| config.decode_args)
+            .unwrap_or(true);
+        NginxVariableResolver {
+            request,
+            decode_args,
+            resolved: HashMap::new(),
+            resolved_values: HashMap::new(),
+        }
+    }
+
+    fn decoded(&self, var_name: &str, value: String) -> String {
+        if self.decode_args && var_name.starts_with("$arg_") {
+            domain::form_decode(value.as_bytes())
+        } else {
+            value
+        }
     }
 }
 
 impl<'a> VariableResolver for NginxVariableResolver<'a> {
     fn resolve(&mut self, var_name: &str) -> Result<String, String> {
-        variable::resolve_variable(self.request, var_name)
+        if let Some(resolved) = self.resolved.get(var_name) {
+            return resolved.clone();
+        }
+        let resolved = variable::resolve_variable(self.request, var_name)
+            .map(|value| self.decoded(var_name, value));
+        self.resolved.insert(var_name.to_string(), resolved.clone());
+        resolved
+    }
+
+    fn resolve_variable(&mut self, variable: &NginxVariable) -> Result<String, String> {
+        let Some(index) = variable.index() else {
+            return self.resolve(variable.as_str());
+        };
+        // By index or by name, a variable has one value
+        if let Some(resolved) = self.resolved.get(variable.as_str()) {
+            return resolved.clone();
+        }
+        let resolved = variable::resolve_indexed_variable(self.request, index, variable.as_str())
+            .map(|value| self.decoded(variable.as_str(), value));
+        self.resolved
+            .insert(variable.as_str().to_string(), resolved.clone());
+        resolved
+    }
+
+    fn resolve_values(&mut self, var_name: &str) -> Result<Vec<String>, String> {
+        if let Some(resolved) = self.resolved_values.get(var_name) {
+            return resolved.clone();
+        }
+        let resolved = variable::resolve_variable_values(self.request, var_name).map(|values| {
+            values
+                .into_iter()
+                .map(|value| self.decoded(var_name, value))
+                .collect()
+        });
+        self.resolved_values
+            .insert(var_name.to_string(), resolved.clone());
+        resolved
+    }
+
+    fn arg_names(&mut self) -> Vec<String> {
+        variable::arg_names(self.request)
     }
 }
 
-/// Adapter for nginx logging
-pub struct NginxLogger<'a> {
-    request: &'a mut Request,
+/// Adapter for nginx logging, to the log of the request it was made for
+pub struct NginxLogger {
+    log: *mut ngx_log_t,
 }
 
-impl<'a> NginxLogger<'a> {
-    pub fn new(request: &'a mut Request) -> Self {
-        NginxLogger { request }
+impl NginxLogger {
+    pub fn new(request: &Request) -> Self {
+        // Use safe Request::log() method to get log pointer
+        NginxLogger { log: request.log() }
     }
 }
 
-impl<'a> Logger for NginxLogger<'a> {
+impl Logger for NginxLogger {
     fn log(&self, level: LogLevel, module: &str, message: &str) {
         let log_level = match level {
             LogLevel::Error => 3, // NGX_LOG_ERR
@@ -46,25 +126,168 @@ impl<'a> Logger for NginxLogger<'a> {
             LogLevel::Debug => 7, // NGX_LOG_DEBUG
         };
 
-        // Use safe Request::log() method to get log pointer
-        let log = self.request.log();
-        if !log.is_null() {
-            ngx_log_error!(log_level, log, "[sqlite-serve:{}] {}", module, message);
+        if !self.log.is_null() {
+            ngx_log_error!(log_level, self.log, "[sqlite-serve:{}] {}", module, message);
         }
     }
 }
 
-/// Adapter for SQLite query execution
-pub struct SqliteQueryExecutor;
+/// Adapter for the request stages: nginx's request, read and answered
+/// through the module's helpers
+impl StageRequest for Request {
+    fn method(&self) -> String {
+        Request::method(self).as_str().to_string()
+    }
+
+    fn variables(&mut self) -> Box<dyn VariableResolver + '_> {
+        Box::new(NginxVariableResolver::new(self))
+    }
+
+    fn logger(&self) -> Box<dyn Logger> {
+        Box::new(NginxLogger::new(self))
+    }
+
+    fn location_conf(&self) -> Option<&ModuleConfig> {
+        Module::location_conf(self)
+    }
+
+    fn main_conf(&self) -> Option<&MainConfig> {
+        Module::main_conf(self)
+    }
+
+    fn accepted_content_type(&self) -> ContentType {
+        negotiate_content_type(self)
+    }
 
-impl QueryExecutor for SqliteQueryExecutor {
+    fn record_error(&mut self, code: &str, message: &str) {
+        nginx_helpers::record_error(self, code, message);
+    }
+
+    fn add_header(&mut self, name: &'static str, value: &str) -> bool {
+        self.add_header_out(name, value).is_some()
+    }
+
+    fn send(
+        &mut self,
+        status: HTTPStatus,
+        headers: &[(&'static str, &str)],
+        body: &[u8],
+    ) -> Status {
+        nginx_helpers::try_send_response(self, body, headers, status)
+            .unwrap_or_else(|e| nginx_helpers::internal_error(self, e))
+    }
+
+    fn send_streamed(&mut self, response: StreamedResponse, buffer: StreamBuffer) -> Status {
+        nginx_helpers::send_streamed_response(self, response, buffer)
+    }
+
+    fn body_read(&mut self) -> bool {
+        nginx_helpers::request_body_read(self)
+    }
+
+    fn body(&mut self) -> Result<Vec<u8>, String> {
+        nginx_helpers::request_body(self)
+    }
+
+    fn discard_body(&mut self) {
+        self.discard_request_body();
+    }
+
+    fn nginx(&mut self) -> Option<&mut Request> {
+        Some(self)
+    }
+}
+
+/// Adapter for SQLite query execution, reading every row in its location's
+/// [`RowShape`] and refusing writes that change more rows than it allows
+pub struct SqliteQueryExecutor<'a> {
+    rows: &'a RowShape,
+    max_changes: Option<usize>,
+}
+
+impl<'a> SqliteQueryExecutor<'a> {
+    /// An executor for `config`'s queries: its main query, sources, menus and
+    /// expansions alike
+    pub fn for_location(config: &'a ValidatedConfig) -> Self {
+        SqliteQueryExecutor {
+            rows: &config.rows,
+            max_changes: config.mutation_max_rows.map(|max| max.get()),
+        }
+    }
+}
+
+impl QueryExecutor for SqliteQueryExecutor<'_> {
     fn execute(
         &self,
         db_path: &DatabasePath,
         query: &SqlQuery,
-        params: &[(String, String)],
-    ) -> Result<Vec<HashMap<String, Value>>, String> {
-        query::execute_query(db_path.as_str(), query.as_str(), params).map_err(|e| e.to_string())
+        params: &[(String, Value)],
+    ) -> Result<Vec<HashMap<String, Value>>, DbError> {
+        query::execute_query(db_path, query.as_str(), params, self.rows).map_err(db_error)
+    }
+}
+
+impl WriteExecutor for SqliteQueryExecutor<'_> {
+    fn execute_write(
+        &self,
+        db_path: &DatabasePath,
+        queries: &[&WriteQuery],
+        params: &[(String, Value)],
+    ) -> Result<WriteOutcome, DbError> {
+        let queries: Vec<&str> = queries.iter().map(|q| q.as_str()).collect();
+        query::execute_write(db_path, &queries, params, self.max_changes)
+            .map(|(changes, last_insert_rowid)| WriteOutcome {
+                changes,
+                last_insert_rowid,
+            })
+            .map_err(write_error)
+    }
+}
+
+impl BatchExecutor for SqliteQueryExecutor<'_> {
+    fn execute_batch_query(
+        &self,
+        db_path: &DatabasePath,
+        query: &SqlQuery,
+        sets: &[ParameterSet],
+    ) -> Result<Vec<Vec<HashMap<String, Value>>>, DbError> {
+        query::execute_batch_query(db_path, query.as_str(), sets, self.rows).map_err(batch_error)
+    }
+
+    fn execute_batch_write(
+        &self,
+        db_path: &DatabasePath,
+        queries: &[&WriteQuery],
+        sets: &[ParameterSet],
+    ) -> Result<Vec<WriteOutcome>, DbError> {
+        let queries: Vec<&str> = queries.iter().map(|q| q.as_str()).collect();
+        query::execute_batch_write(db_path, &queries, sets, self.max_changes)
+            .map(|outcomes| {
+                outcomes
+                    .into_iter()
+                    .map(|(changes, last_insert_rowid)| WriteOutcome {
+                        changes,
+                        last_insert_rowid,
+                    })
+                    .collect()
+            })
+            .map_err(batch_error)
+    }
+}
+
+fn db_error(error: rusqlite::Error) -> DbError {
+    DbError::new(query::error_code(&error), error.to_string())
+}
+
+fn write_error(error: query::WriteError) -> DbError {
+    DbError::new(error.code(), error.to_string())
+}
+
+fn batch_error(error: query::BatchError) -> DbError {
+    let code = error.source.code();
+    match error.set {
+        Some(set) => DbError::new(code, format!("parameter set {}: {}", set, error.source)),
+        None => DbError::new(code, error.source.to_string()),
     }
 }
 
@@ -88,7 +311,10 @@ mod tests {
                 .unwrap();
         }
 
-        let executor = SqliteQueryExecutor;
+        let executor = SqliteQueryExecutor {
+            rows: &RowShape::default(),
+            max_changes: None,
+        };
         let db_path = DatabasePath::parse(temp_path).unwrap();
         let query = SqlQuery::parse("SELECT * FROM test").unwrap();
 
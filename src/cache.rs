@@ -0,0 +1,331 @@
+//! Per-worker caches that spare requests work an earlier request did
+//!
+//! Each worker keeps one of each on its [`WorkerState`](crate::worker::WorkerState):
+//! menu rows and `sqlite_db_map` lookups, which expire after their
+//! `max_age`, and pages' rendered sizes. They live only in the worker's
+//! memory, so a reload or binary upgrade starts new workers with empty
+//! caches. Request processing reaches the first and last through the
+//! [`MenuCache`] and [`PageSizes`] traits.
+
+use crate::domain::{MenuCache, PAGE_CAPACITY, PageSizes};
+use crate::types::{DatabaseMap, NavMenu, RowShape};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// `sqlite_nav` rows kept between requests, so a menu shown on every page
+/// costs one query per `max_age` rather than one per request
+///
+/// Entries are keyed by database, query and the location's [`RowShape`], so
+/// locations that inherit the same menu share its rows unless they hide
+/// different columns.
+#[derive(Debug, Default)]
+pub struct NavCache {
+    entries: Mutex<HashMap<NavKey, NavEntry>>,
+}
+
+/// A menu's database and query, and the shape its rows were read in
+type NavKey = (String, String, RowShape);
+
+/// A menu's rows and when they were fetched
+type NavEntry = (Instant, Vec<HashMap<String, Value>>);
+
+impl NavCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The menu's rows read in `shape`, if they were fetched less than its
+    /// `max_age` before `now`
+    pub fn get(
+        &self,
+        menu: &NavMenu,
+        shape: &RowShape,
+        now: Instant,
+    ) -> Option<Vec<HashMap<String, Value>>> {
+        let entries = self.entries.lock().ok()?;
+        let (fetched, rows) = entries.get(&Self::key(menu, shape))?;
+        (now.saturating_duration_since(*fetched) < menu.max_age()).then(|| rows.clone())
+    }
+
+    /// Keep rows freshly fetched in `shape` for later requests
+    pub fn store(
+        &self,
+        menu: &NavMenu,
+        shape: &RowShape,
+        rows: Vec<HashMap<String, Value>>,
+        now: Instant,
+    ) {
+        if menu.max_age().is_zero() {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(Self::key(menu, shape), (now, rows));
+        }
+    }
+
+    fn key(menu: &NavMenu, shape: &RowShape) -> NavKey {
+        (
+            menu.source().db_path().as_str().to_string(),
+            menu.source().query().as_str().to_string(),
+            shape.clone(),
+        )
+    }
+}
+
+impl MenuCache for NavCache {
+    fn cached(&self, menu: &NavMenu, shape: &RowShape) -> Option<Vec<HashMap<String, Value>>> {
+        self.get(menu, shape, Instant::now())
+    }
+
+    fn keep(&self, menu: &NavMenu, shape: &RowShape, rows: Vec<HashMap<String, Value>>) {
+        self.store(menu, shape, rows, Instant::now())
+    }
+}
+
+/// `sqlite_db_map` lookups kept between requests, so naming a host's
+/// database costs one control query per `max_age` rather than one per request
+///
+/// Lookups that found no database are kept too, so requests for unknown
+/// hosts do not each query the control database. At most
+/// [`DatabaseMapCache::MAX_ENTRIES`] lookups are kept.
+#[derive(Debug, Default)]
+pub struct DatabaseMapCache {
+    entries: Mutex<HashMap<DatabaseMapKey, DatabaseMapEntry>>,
+}
+
+/// A map's control database and query, and the values of its parameters
+type DatabaseMapKey = (String, String, Vec<String>);
+
+/// The database a lookup named, if any, and when it was looked up
+type DatabaseMapEntry = (Instant, Option<String>);
+
+impl DatabaseMapCache {
+    pub const MAX_ENTRIES: usize = 10_000;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The database `map` named for `values`, if it was looked up less than
+    /// its `max_age` before `now`; `Some(None)` when it named none
+    pub fn get(
+        &self,
+        map: &DatabaseMap,
+        values: &[String],
+        now: Instant,
+    ) -> Option<Option<String>> {
+        let entries = self.entries.lock().ok()?;
+        let (looked_up, database) = entries.get(&Self::key(map, values.to_vec()))?;
+        (now.saturating_duration_since(*looked_up) < map.max_age()).then(|| database.clone())
+    }
+
+    /// Keep a fresh lookup for later requests, first dropping expired ones
+    /// (or, if none have expired, every one) when the cache is full
+    pub fn store(
+        &self,
+        map: &DatabaseMap,
+        values: Vec<String>,
+        database: Option<String>,
+        now: Instant,
+    ) {
+        if map.max_age().is_zero() {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() >= Self::MAX_ENTRIES {
+                entries.retain(|_, (looked_up, _)| {
+                    now.saturating_duration_since(*looked_up) < map.max_age()
+                });
+                if entries.len() >= Self::MAX_ENTRIES {
+                    entries.clear();
+                }
+            }
+            entries.insert(Self::key(map, values), (now, database));
+        }
+    }
+
+    fn key(map: &DatabaseMap, values: Vec<String>) -> DatabaseMapKey {
+        (
+            map.control().as_str().to_string(),
+            map.query().as_str().to_string(),
+            values,
+        )
+    }
+}
+
+/// How large each template's pages have been, so the next page's output
+/// buffer can be allocated at its expected size instead of growing while
+/// it renders
+///
+/// A template's size is kept per row, since a list's page grows with its
+/// rows, and follows recent pages more than old ones.
+#[derive(Debug, Default)]
+pub struct RenderSizes {
+    entries: Mutex<HashMap<String, RenderSize>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RenderSize {
+    /// Bytes per row of recent pages with rows
+    per_row: Option<usize>,
+    /// The last page's bytes
+    last: usize,
+}
+
+impl RenderSizes {
+    /// The buffer every page starts with
+    pub const MIN_CAPACITY: usize = PAGE_CAPACITY;
+    /// No page is expected to be larger than this, however many rows it has
+    pub const MAX_CAPACITY: usize = 16 * 1024 * 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PageSizes for RenderSizes {
+    /// The buffer to render `template` with `rows` rows into: the expected
+    /// size with an eighth to spare
+    fn estimate(&self, template: &str, rows: usize) -> usize {
+        let expected = self
+            .entries
+            .lock()
+            .ok()
+            .and_then(|entries| entries.get(template).copied())
+            .map(|size| match size.per_row {
+                Some(per_row) if rows > 0 => per_row.saturating_mul(rows),
+                _ => size.last,
+            })
+            .unwrap_or(0);
+        expected
+            .saturating_add(expected / 8)
+            .clamp(Self::MIN_CAPACITY, Self::MAX_CAPACITY)
+    }
+
+    /// Remember that `template` rendered `rows` rows as `bytes` bytes
+    fn record(&self, template: &str, rows: usize, bytes: usize) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let size = entries.entry(template.to_string()).or_insert(RenderSize {
+            per_row: None,
+            last: bytes,
+        });
+        size.last = bytes;
+        if rows > 0 {
+            let per_row = bytes.div_ceil(rows);
+            size.per_row = Some(match size.per_row {
+                Some(previous) => (previous * 3 + per_row).div_ceil(4),
+                None => per_row,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_database_map_cache() {
+        let map = DatabaseMap::parse(
+            "tenants.db",
+            "SELECT db_path FROM tenants WHERE host = :host",
+            &[],
+        )
+        .unwrap();
+        let cache = DatabaseMapCache::new();
+        let now = Instant::now();
+        let acme = vec!["acme.example.com".to_string()];
+        assert!(cache.get(&map, &acme, now).is_none());
+
+        cache.store(&map, acme.clone(), Some("/srv/acme.db".to_string()), now);
+        cache.store(&map, vec!["unknown".to_string()], None, now);
+        assert_eq!(
+            cache.get(&map, &acme, now + Duration::from_secs(30)),
+            Some(Some("/srv/acme.db".to_string()))
+        );
+        // A host with no database is remembered as such
+        assert_eq!(cache.get(&map, &["unknown".to_string()], now), Some(None));
+        assert!(
+            cache
+                .get(&map, &acme, now + DatabaseMap::DEFAULT_MAX_AGE)
+                .is_none()
+        );
+
+        // A full cache makes room by dropping what has expired
+        let later = now + DatabaseMap::DEFAULT_MAX_AGE;
+        for i in 0..DatabaseMapCache::MAX_ENTRIES {
+            cache.store(&map, vec![i.to_string()], None, later);
+        }
+        assert!(cache.get(&map, &acme, now).is_none());
+        assert_eq!(cache.get(&map, &["1".to_string()], later), Some(None));
+
+        // max_age=0 looks up every request
+        let uncached = DatabaseMap::parse("tenants.db", "SELECT 1", &["max_age=0"]).unwrap();
+        cache.store(&uncached, vec![], None, now);
+        assert!(cache.get(&uncached, &[], now).is_none());
+    }
+
+    #[test]
+    fn test_render_sizes() {
+        let sizes = RenderSizes::new();
+        assert_eq!(sizes.estimate("list.hbs", 100), RenderSizes::MIN_CAPACITY);
+
+        // 100 rows in 40,000 bytes: 400 bytes a row, with an eighth to spare
+        sizes.record("list.hbs", 100, 40_000);
+        assert_eq!(sizes.estimate("list.hbs", 200), 90_000);
+        assert_eq!(sizes.estimate("list.hbs", 10), RenderSizes::MIN_CAPACITY);
+        assert_eq!(sizes.estimate("other.hbs", 200), RenderSizes::MIN_CAPACITY);
+
+        // Recent pages count for more than older ones
+        sizes.record("list.hbs", 100, 80_000);
+        assert_eq!(sizes.estimate("list.hbs", 100), 56_250);
+
+        // A page without rows is expected to be as large as the last one
+        sizes.record("list.hbs", 0, 20_000);
+        assert_eq!(sizes.estimate("list.hbs", 0), 22_500);
+        assert_eq!(sizes.estimate("list.hbs", 100), 56_250);
+
+        assert_eq!(
+            sizes.estimate("list.hbs", usize::MAX),
+            RenderSizes::MAX_CAPACITY
+        );
+    }
+
+    #[test]
+    fn test_nav_cache_expiry() {
+        let menu = NavMenu::parse("main_menu", "site.db", "SELECT * FROM menu", &[]).unwrap();
+        let shape = RowShape::default();
+        let cache = NavCache::new();
+        let now = Instant::now();
+        assert!(cache.get(&menu, &shape, now).is_none());
+
+        cache.store(&menu, &shape, vec![HashMap::new()], now);
+        assert_eq!(cache.get(&menu, &shape, now).unwrap().len(), 1);
+        assert!(
+            cache
+                .get(&menu, &shape, now + Duration::from_secs(30))
+                .is_some()
+        );
+        assert!(
+            cache
+                .get(&menu, &shape, now + NavMenu::DEFAULT_MAX_AGE)
+                .is_none()
+        );
+
+        // A location hiding other columns reads the menu for itself
+        let hiding = RowShape::new().with_hidden_columns(vec!["secret".to_string()]);
+        assert!(cache.get(&menu, &hiding, now).is_none());
+
+        // max_age=0 turns caching off
+        let uncached =
+            NavMenu::parse("main_menu", "site.db", "SELECT * FROM menu", &["max_age=0"]).unwrap();
+        let cache = NavCache::new();
+        cache.store(&uncached, &shape, vec![HashMap::new()], now);
+        assert!(cache.get(&uncached, &shape, now).is_none());
+    }
+}
@@ -0,0 +1,252 @@
+//! What this build and each location support (`sqlite_capabilities`)
+//!
+//! The matrix says which optional parts of the module were compiled in and
+//! which each location turns on: its formats, caches, guards on writes,
+//! mutations and streaming. It is read from the configuration as loaded, so
+//! it answers "does this deployment do X at /path" without a test request.
+
+use crate::config::ModuleConfig;
+use crate::content_type::ContentType;
+use crate::formatter::FormatterRegistry;
+use crate::types::WriteMethod;
+use serde_json::{Value, json};
+
+/// The matrix for this build and `locations`, given by name
+pub fn matrix(locations: &[(String, &ModuleConfig)]) -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "compiled": compiled(),
+        "locations": locations
+            .iter()
+            .map(|(name, config)| location(name, config))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// The optional features, formats and engines of this build
+fn compiled() -> Value {
+    let formats: Vec<&str> = FormatterRegistry::standard()
+        .iter()
+        .map(|f| f.content_type().media_type())
+        .collect();
+    json!({
+        "features": {
+            "pdf": cfg!(feature = "pdf"),
+            "xlsx": cfg!(feature = "xlsx"),
+        },
+        "formats": formats,
+        "engines": {
+            "database": format!("sqlite {}", rusqlite::version()),
+            "templates": "handlebars",
+        },
+    })
+}
+
+/// What one location answers with and which features it turns on
+fn location(name: &str, config: &ModuleConfig) -> Value {
+    let try_static = config.try_static.as_ref().filter(|t| t.enabled());
+    let memo = config.memo_table.as_ref().filter(|m| m.enabled());
+    let stream = config.stream_buffer.as_ref().filter(|b| b.enabled());
+    json!({
+        "location": name,
+        "handler": handler(config),
+        "database": if config.db_path.is_none() && config.db_map.is_none() {
+            Value::Null
+        } else if config.database_per_request() {
+            "per_request".into()
+        } else {
+            "fixed".into()
+        },
+        "formats": formats(config),
+        "cache": {
+            "static": try_static.map(|t| json!({ "max_age": t.max_age(), "save": t.save() })),
+            "memo": memo.map(|m| json!({ "table": m.table(), "ttl": m.ttl().as_secs() })),
+        },
+        "guards": {
+            "captcha": config.captcha.as_ref().map(|c| c.provider().as_str()),
+            "honeypot": config.honeypot.is_some(),
+        },
+        "mutations": {
+            "methods": write_methods(config),
+            "batch": config.batch.unwrap_or(false),
+            "rest": config.rest.as_ref().map(|r| r.table()),
+            "upsert": config.upsert.as_ref().map(|u| u.table()),
+            "soft_delete": config.soft_delete.as_ref().map(|s| s.column()),
+            "history": config.history,
+            "max_rows": config.mutation_max_rows.map(|m| m.get()),
+        },
+        "streaming": stream.map(|b| json!({
+            "buffer": b.size(),
+            "overflow": config.stream_overflow.unwrap_or_default().as_str(),
+        })),
+    })
+}
+
+/// Which of the module's handlers answers the location
+fn handler(config: &ModuleConfig) -> &'static str {
+    if config.capabilities.is_some() {
+        "capabilities"
+    } else if config.typescript {
+        "typescript"
+    } else if config.health {
+        "health"
+    } else if config.blob.is_some() {
+        "blob"
+    } else if config.rest.is_some() {
+        "rest"
+    } else if config.feed.as_ref().is_some_and(|f| f.enabled()) {
+        "feed"
+    } else {
+        "query"
+    }
+}
+
+/// The media types a location may answer with: a feed its own, and other
+/// queries every compiled format, less those made from a page when there is
+/// no template
+fn formats(config: &ModuleConfig) -> Vec<&'static str> {
+    let fixed = match handler(config) {
+        "capabilities" | "health" => Some("application/json"),
+        "typescript" => Some("application/typescript"),
+        // Blobs are sent as the type stored with them
+        "blob" => return Vec::new(),
+        _ => None,
+    };
+    if let Some(fixed) = fixed {
+        return vec![fixed];
+    }
+    let feed = config.feed.as_ref().filter(|f| f.enabled());
+    FormatterRegistry::standard()
+        .iter()
+        .map(|f| (f, f.content_type()))
+        .filter(|(f, content_type)| match feed {
+            Some(feed) => *content_type == ContentType::for_feed(feed.format()),
+            None => !content_type.is_feed() && (config.template_path.is_some() || !f.needs_page()),
+        })
+        .map(|(_, content_type)| content_type.media_type())
+        .collect()
+}
+
+/// The write methods a location accepts, as `sqlite_methods`,
+/// `sqlite_write_query`, `sqlite_rest` and `sqlite_upsert` set them up
+fn write_methods(config: &ModuleConfig) -> Vec<&'static str> {
+    let writes = !config.write_queries.is_empty() || config.rest.is_some();
+    let methods = if config.upsert.is_some() {
+        vec![WriteMethod::Post]
+    } else if !writes {
+        Vec::new()
+    } else if !config.write_methods.is_empty() {
+        config.write_methods.clone()
+    } else if config.rest.is_some() {
+        vec![
+            WriteMethod::Post,
+            WriteMethod::Put,
+            WriteMethod::Patch,
+            WriteMethod::Delete,
+        ]
+    } else {
+        vec![WriteMethod::Post]
+    };
+    methods.iter().map(WriteMethod::as_str).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        Captcha, Feed, MemoTable, NginxVariable, RestTable, RowLimit, StreamBuffer, TryStatic,
+    };
+
+    #[test]
+    fn test_matrix() {
+        let books = ModuleConfig {
+            db_path: Some("/srv/books.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            try_static: Some(TryStatic::parse(&["on", "max_age=60"]).unwrap()),
+            memo_table: Some(MemoTable::parse(&["memo", "ttl=1h"]).unwrap()),
+            stream_buffer: Some(StreamBuffer::parse("8k").unwrap()),
+            write_queries: vec!["INSERT INTO books (title) VALUES (:title)".to_string()],
+            captcha: Some(Captcha::parse(&["provider=turnstile", "secret=k"]).unwrap()),
+            ..Default::default()
+        };
+        let api = ModuleConfig {
+            db_path: Some("/srv/$host.db".to_string()),
+            rest: Some(RestTable::parse("books", None).unwrap()),
+            write_methods: vec![WriteMethod::Post, WriteMethod::Delete],
+            mutation_max_rows: Some(RowLimit::parse("1").unwrap()),
+            ..Default::default()
+        };
+        let feed = ModuleConfig {
+            db_path: Some("/srv/books.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            feed: Some(Feed::parse(&["rss"]).unwrap()),
+            ..Default::default()
+        };
+        let endpoint = ModuleConfig {
+            capabilities: Some(NginxVariable::parse("$sqlite_debug").unwrap()),
+            ..Default::default()
+        };
+        let matrix = matrix(&[
+            ("/books".to_string(), &books),
+            ("/api/books".to_string(), &api),
+            ("/books.rss".to_string(), &feed),
+            ("/__sqlite_serve/capabilities".to_string(), &endpoint),
+        ]);
+
+        assert_eq!(matrix["version"], env!("CARGO_PKG_VERSION"));
+        let compiled = &matrix["compiled"];
+        assert_eq!(compiled["features"]["pdf"], cfg!(feature = "pdf"));
+        assert_eq!(compiled["features"]["xlsx"], cfg!(feature = "xlsx"));
+        assert_eq!(
+            compiled["formats"].as_array().unwrap().len(),
+            5 + usize::from(cfg!(feature = "pdf")) + usize::from(cfg!(feature = "xlsx"))
+        );
+        assert!(
+            compiled["engines"]["database"]
+                .as_str()
+                .unwrap()
+                .starts_with("sqlite 3.")
+        );
+
+        let locations = matrix["locations"].as_array().unwrap();
+        let books = &locations[0];
+        assert_eq!(books["handler"], "query");
+        assert_eq!(books["database"], "fixed");
+        assert_eq!(books["formats"][0], "text/html");
+        assert!(!books["formats"].to_string().contains("rss"));
+        assert_eq!(
+            books["cache"],
+            json!({ "static": { "max_age": 60, "save": false }, "memo": { "table": "memo", "ttl": 3600 } })
+        );
+        assert_eq!(books["guards"]["captcha"], "turnstile");
+        assert_eq!(books["mutations"]["methods"], json!(["POST"]));
+        assert_eq!(
+            books["streaming"],
+            json!({ "buffer": 8192, "overflow": "wait" })
+        );
+
+        let api = &locations[1];
+        assert_eq!(api["handler"], "rest");
+        assert_eq!(api["database"], "per_request");
+        // Without a template only the formats made from rows are available
+        let mut from_rows = vec!["application/json", "application/x-ndjson"];
+        if cfg!(feature = "xlsx") {
+            from_rows.push("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet");
+        }
+        assert_eq!(api["formats"], json!(from_rows));
+        assert_eq!(api["mutations"]["methods"], json!(["POST", "DELETE"]));
+        assert_eq!(api["mutations"]["rest"], "books");
+        assert_eq!(api["mutations"]["max_rows"], 1);
+        assert_eq!(api["cache"], json!({ "static": null, "memo": null }));
+        assert_eq!(api["streaming"], Value::Null);
+
+        assert_eq!(locations[2]["handler"], "feed");
+        assert_eq!(locations[2]["formats"], json!(["application/rss+xml"]));
+        assert_eq!(locations[2]["mutations"]["methods"], json!([]));
+
+        assert_eq!(locations[3]["handler"], "capabilities");
+        assert_eq!(locations[3]["database"], Value::Null);
+        assert_eq!(locations[3]["formats"], json!(["application/json"]));
+    }
+}
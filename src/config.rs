@@ -1,38 +1,263 @@
 //! Configuration structures for the sqlite-serve module
 
+use crate::rest::TableColumn;
+use crate::types::{
+    AggregateSpec, BlobEncoding, BlobRoute, Breadcrumbs, Captcha, CountStrategy, DatabaseMap,
+    DatabaseTemplate, DryRun, EmailNotification, Expansion, Feed, Filter, HitCounter, Honeypot,
+    JsonExpansion, JsonNulls, MaxLength, MemoTable, NavMenu, NginxVariable, Pagination, ParamType,
+    Report, RestTable, RowLimit, SoftDelete, SortParam, StreamBuffer, StreamOverflow,
+    TemplateVariants, Theme, Trending, TryStatic, UpsertTable, WriteMethod,
+};
+use ngx::ffi::ngx_http_core_loc_conf_t;
 use ngx::http::MergeConfigError;
 
 /// Location-specific configuration
-#[derive(Debug, Default)]
+///
+/// Settings directives (`sqlite_db`, `sqlite_attach`, `sqlite_allow_tables`,
+/// `sqlite_max_template_rows`, `sqlite_aggregate`, `sqlite_expand_json`,
+/// `sqlite_try_static`, `sqlite_timing`, `sqlite_nav`, `sqlite_breadcrumbs`,
+/// `sqlite_theme`, `sqlite_hit_counter`, `sqlite_trending`,
+/// `sqlite_param_max_length`, `sqlite_memo_table`, `sqlite_count`, `sqlite_decode_args`,
+/// `sqlite_trace`, `sqlite_db_map`, `sqlite_intercept_errors`, `sqlite_dev_mode`,
+/// `sqlite_stream_buffer`, `sqlite_stream_overflow`, `sqlite_dry_run`,
+/// `sqlite_mutation_max_rows`, `sqlite_json_nulls`, `sqlite_blob_encoding`)
+/// are also accepted in `http` and `server` blocks; nginx merges those levels
+/// down, so each location inherits the innermost value set above it unless
+/// it sets its own.
+///
+/// `None` means the directive was not used at this level. List settings
+/// inherit when this level adds no entries. Settings whose values need no
+/// request context are parsed into typed values by the directive handlers.
+#[derive(Debug, Clone, Default)]
 pub struct ModuleConfig {
-    pub db_path: String,
-    pub query: String,
-    pub template_path: String,
+    pub db_path: Option<String>,
+    pub db_map: Option<DatabaseMap>, // sqlite_db_map: a control table names each request's database
+    pub attachments: Vec<(String, String)>, // (alias, db_path) pairs
+    pub query: Option<String>,
+    pub fallback_query: Option<String>,
+    pub pagination: Option<Pagination>,
+    pub count_query: Option<String>, // counts every page's rows for sqlite_paginate
+    pub count: Option<CountStrategy>,
+    pub query_rules: Vec<(String, String)>, // (variable, query) pairs, first match wins
+    pub sort_param: Option<SortParam>,      // sqlite_sort_param: ORDER BY chosen per request
+    pub filters: Vec<Filter>,               // sqlite_filter conditions, each applied when set
+    pub template_path: Option<String>,
+    pub theme: Option<Theme>,
+    pub template_variants: Option<TemplateVariants>,
+    pub form_template: Option<String>, // re-rendered when a write fails validation
+    pub feed: Option<Feed>,            // sqlite_feed: answer with an Atom or RSS document
     pub query_params: Vec<(String, String)>, // (param_name, variable_name) pairs
+    pub param_types: Vec<(String, ParamType)>, // (param_name, type) for typed named params
+    pub param_defaults: Vec<(String, String)>, // (param_name, default) for missing variables
+    pub required_params: Vec<String>,  // param names from sqlite_param_required
+    pub param_patterns: Vec<(String, String)>, // (param_name, regex) from pattern=
+    pub param_max_lengths: Vec<(String, MaxLength)>, // (param_name, limit) from max_length=
+    pub param_max_length: Option<MaxLength>, // sqlite_param_max_length, for every variable
+    pub bind_args: Option<bool>,       // sqlite_bind_args: every query argument as :arg_<name>
+    pub allowed_tables: Vec<String>,
+    pub allowed_fields: Vec<String>, // sqlite_allow_fields: what ?fields= may name
+    pub hidden_columns: Vec<String>, // sqlite_hide_columns: never returned
+    pub blob_encoding: Option<BlobEncoding>, // sqlite_blob_encoding: hex, base64 or left out
+    pub max_template_rows: Option<RowLimit>,
+    pub mutation_max_rows: Option<RowLimit>, // sqlite_mutation_max_rows: wider writes roll back
+    pub aggregate: Option<AggregateSpec>,
+    pub expand_json: Option<JsonExpansion>,
+    pub json_nulls: Option<JsonNulls>, // sqlite_json_nulls: keep or omit NULL-valued keys
+    pub try_static: Option<TryStatic>,
+    pub export_dir: Option<String>, // sqlite_export: POST renders every page here
+    pub report: Option<Report>,     // sqlite_report: POST saves the rows as a file
+    pub timing: Option<bool>,
+    pub trace: Option<bool>, // sqlite_trace: logs each request's operations
+    pub decode_args: Option<bool>, // sqlite_decode_args: off keeps $arg_ values raw
+    pub intercept_errors: Option<bool>, // sqlite_intercept_errors: failures go to error_page
+    pub dev_mode: Option<bool>, // sqlite_dev_mode: error pages show template source
+    pub dry_run: Option<DryRun>, // sqlite_dry_run: reports bindings instead of running
+    pub stream_buffer: Option<StreamBuffer>, // sqlite_stream_buffer: unsent bytes a client may keep
+    pub stream_overflow: Option<StreamOverflow>, // sqlite_stream_overflow: wait for or drop it
+    pub breadcrumbs: Option<Breadcrumbs>,
+    pub hit_counter: Option<HitCounter>,
+    pub trending: Option<Trending>,
+    pub memo_table: Option<MemoTable>,
+    pub expansions: Vec<Expansion>, // sqlite_expand relations, by name
+    pub sources: Vec<(String, String, String)>, // (name, db_path, query) triples
+    pub nav_menus: Vec<NavMenu>,
+    pub write_queries: Vec<String>, // run in order, in one transaction
+    pub write_methods: Vec<WriteMethod>,
+    pub batch: Option<bool>,
+    pub honeypot: Option<Honeypot>,
+    pub captcha: Option<Captcha>,
+    pub notify_email: Option<EmailNotification>,
+    pub rest: Option<RestTable>,
+    pub upsert: Option<UpsertTable>,
+    pub soft_delete: Option<SoftDelete>,
+    pub history: Vec<String>, // sqlite_history tables, read as they were with ?as_of=
+    pub blob: Option<BlobRoute>, // sqlite_blob: serves the table's blobs by hash instead
+    pub rest_columns: Vec<TableColumn>, // sqlite_rest or sqlite_upsert table, read at startup
+    pub variable_indexes: Vec<(String, usize)>, // nginx's index of each bound variable, from startup
+    pub typescript: bool, // sqlite_typescript: serves the declarations instead
+    pub health: bool,     // sqlite_health: serves the worker's and reports' status instead
+    pub capabilities: Option<NginxVariable>, // sqlite_capabilities: serves the matrix when set
 }
 
-/// Global (HTTP main) configuration for shared templates
+impl ModuleConfig {
+    /// Whether each request names its own database, with `sqlite_db_map` or
+    /// variables in `sqlite_db`, so no one database can be checked at startup
+    pub fn database_per_request(&self) -> bool {
+        self.db_map.is_some()
+            || self
+                .db_path
+                .as_deref()
+                .is_some_and(DatabaseTemplate::has_variables)
+    }
+
+    /// Whether the location answers with one of the module's own endpoints
+    /// (`sqlite_typescript`, `sqlite_blob`, `sqlite_capabilities`,
+    /// `sqlite_health`) rather than a query of its own
+    pub fn serves_endpoint(&self) -> bool {
+        self.typescript || self.health || self.blob.is_some() || self.capabilities.is_some()
+    }
+}
+
+/// Global (HTTP main) configuration for shared templates and named queries
 #[derive(Debug, Default)]
 pub struct MainConfig {
-    pub global_templates_dir: String,
+    pub global_templates_dir: Option<String>,
+    pub query_definitions: Vec<(String, String)>, // (name, sql) pairs
+    pub locations: Vec<RegisteredLocation>,
+    /// Indexes of `$sqlite_error_code` and `$sqlite_error_message`, set once
+    /// the configuration is loaded
+    pub error_variables: Option<[usize; 2]>,
+    /// Index of `$sqlite_stream_buffered`, set with them
+    pub stream_variable: Option<usize>,
+}
+
+/// A location that installed the sqlite-serve handler
+///
+/// The pointers reference nginx pool-allocated configuration that lives for the
+/// whole configuration cycle; they are only used after merging completes, when
+/// named query references are resolved in place, and read by
+/// `sqlite_typescript`, `sqlite_capabilities` and `sqlite_health` requests.
+#[derive(Debug)]
+pub struct RegisteredLocation {
+    pub config: *mut ModuleConfig,
+    pub core_conf: *const ngx_http_core_loc_conf_t,
+}
+
+/// Inherit a setting from the enclosing configuration level when it is unset here
+///
+/// `None` means "not set at this level"; a value set at an inner level always
+/// wins, even if it is empty.
+fn inherit<T: Clone>(setting: &mut Option<T>, prev: &Option<T>) {
+    if setting.is_none() {
+        *setting = prev.clone();
+    }
 }
 
 impl ngx::http::Merge for ModuleConfig {
     fn merge(&mut self, prev: &ModuleConfig) -> Result<(), MergeConfigError> {
-        if self.db_path.is_empty() {
+        // Both name the database, so the innermost level that sets either decides
+        if self.db_path.is_none() && self.db_map.is_none() {
             self.db_path = prev.db_path.clone();
+            self.db_map = prev.db_map.clone();
+        }
+        inherit(&mut self.query, &prev.query);
+        inherit(&mut self.fallback_query, &prev.fallback_query);
+        inherit(&mut self.pagination, &prev.pagination);
+        inherit(&mut self.count_query, &prev.count_query);
+        inherit(&mut self.count, &prev.count);
+        inherit(&mut self.sort_param, &prev.sort_param);
+        inherit(&mut self.template_path, &prev.template_path);
+        inherit(&mut self.theme, &prev.theme);
+        inherit(&mut self.template_variants, &prev.template_variants);
+        inherit(&mut self.form_template, &prev.form_template);
+        inherit(&mut self.feed, &prev.feed);
+        inherit(&mut self.max_template_rows, &prev.max_template_rows);
+        inherit(&mut self.mutation_max_rows, &prev.mutation_max_rows);
+        inherit(&mut self.aggregate, &prev.aggregate);
+        inherit(&mut self.batch, &prev.batch);
+        inherit(&mut self.bind_args, &prev.bind_args);
+        inherit(&mut self.honeypot, &prev.honeypot);
+        inherit(&mut self.captcha, &prev.captcha);
+        inherit(&mut self.notify_email, &prev.notify_email);
+        inherit(&mut self.expand_json, &prev.expand_json);
+        inherit(&mut self.json_nulls, &prev.json_nulls);
+        inherit(&mut self.blob_encoding, &prev.blob_encoding);
+        inherit(&mut self.try_static, &prev.try_static);
+        inherit(&mut self.export_dir, &prev.export_dir);
+        inherit(&mut self.report, &prev.report);
+        inherit(&mut self.timing, &prev.timing);
+        inherit(&mut self.trace, &prev.trace);
+        inherit(&mut self.decode_args, &prev.decode_args);
+        inherit(&mut self.intercept_errors, &prev.intercept_errors);
+        inherit(&mut self.dev_mode, &prev.dev_mode);
+        inherit(&mut self.dry_run, &prev.dry_run);
+        inherit(&mut self.stream_buffer, &prev.stream_buffer);
+        inherit(&mut self.stream_overflow, &prev.stream_overflow);
+        inherit(&mut self.breadcrumbs, &prev.breadcrumbs);
+        inherit(&mut self.hit_counter, &prev.hit_counter);
+        inherit(&mut self.trending, &prev.trending);
+        inherit(&mut self.memo_table, &prev.memo_table);
+        inherit(&mut self.param_max_length, &prev.param_max_length);
+        inherit(&mut self.rest, &prev.rest);
+        inherit(&mut self.upsert, &prev.upsert);
+        inherit(&mut self.soft_delete, &prev.soft_delete);
+        inherit(&mut self.blob, &prev.blob);
+
+        if self.write_queries.is_empty() {
+            self.write_queries = prev.write_queries.clone();
+        }
+
+        if self.attachments.is_empty() {
+            self.attachments = prev.attachments.clone();
         }
 
-        if self.query.is_empty() {
-            self.query = prev.query.clone();
+        if self.query_rules.is_empty() {
+            self.query_rules = prev.query_rules.clone();
         }
 
-        if self.template_path.is_empty() {
-            self.template_path = prev.template_path.clone();
+        if self.filters.is_empty() {
+            self.filters = prev.filters.clone();
         }
 
         if self.query_params.is_empty() {
             self.query_params = prev.query_params.clone();
+            self.param_types = prev.param_types.clone();
+            self.param_defaults = prev.param_defaults.clone();
+            self.required_params = prev.required_params.clone();
+            self.param_patterns = prev.param_patterns.clone();
+            self.param_max_lengths = prev.param_max_lengths.clone();
+        }
+
+        if self.allowed_tables.is_empty() {
+            self.allowed_tables = prev.allowed_tables.clone();
+        }
+
+        if self.allowed_fields.is_empty() {
+            self.allowed_fields = prev.allowed_fields.clone();
+        }
+
+        if self.hidden_columns.is_empty() {
+            self.hidden_columns = prev.hidden_columns.clone();
+        }
+
+        if self.sources.is_empty() {
+            self.sources = prev.sources.clone();
+        }
+
+        if self.nav_menus.is_empty() {
+            self.nav_menus = prev.nav_menus.clone();
+        }
+
+        if self.expansions.is_empty() {
+            self.expansions = prev.expansions.clone();
+        }
+
+        if self.write_methods.is_empty() {
+            self.write_methods = prev.write_methods.clone();
+        }
+
+        if self.history.is_empty() {
+            self.history = prev.history.clone();
         }
 
         Ok(())
@@ -41,8 +266,9 @@ impl ngx::http::Merge for ModuleConfig {
 
 impl ngx::http::Merge for MainConfig {
     fn merge(&mut self, prev: &MainConfig) -> Result<(), MergeConfigError> {
-        if self.global_templates_dir.is_empty() {
-            self.global_templates_dir = prev.global_templates_dir.clone();
+        inherit(&mut self.global_templates_dir, &prev.global_templates_dir);
+        if self.query_definitions.is_empty() {
+            self.query_definitions = prev.query_definitions.clone();
         }
         Ok(())
     }
@@ -51,82 +277,373 @@ impl ngx::http::Merge for MainConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::FeedFormat;
     use ngx::http::Merge;
 
     #[test]
     fn test_module_config_default() {
         let config = ModuleConfig::default();
-        assert!(config.db_path.is_empty());
-        assert!(config.query.is_empty());
-        assert!(config.template_path.is_empty());
+        assert!(config.db_path.is_none());
+        assert!(config.db_map.is_none());
+        assert!(config.query.is_none());
+        assert!(config.fallback_query.is_none());
+        assert!(config.query_rules.is_empty());
+        assert!(config.sort_param.is_none());
+        assert!(config.filters.is_empty());
+        assert!(config.template_path.is_none());
+        assert!(config.feed.is_none());
         assert!(config.query_params.is_empty());
+        assert!(config.allowed_tables.is_empty());
+        assert!(config.allowed_fields.is_empty());
+        assert!(config.hidden_columns.is_empty());
+        assert!(config.max_template_rows.is_none());
+        assert!(config.mutation_max_rows.is_none());
+        assert!(config.json_nulls.is_none());
+        assert!(config.blob_encoding.is_none());
+        assert!(config.aggregate.is_none());
+        assert!(config.sources.is_empty());
+        assert!(config.write_queries.is_empty());
+        assert!(config.write_methods.is_empty());
+        assert!(config.batch.is_none());
+        assert!(config.rest.is_none());
+        assert!(config.upsert.is_none());
+        assert!(config.soft_delete.is_none());
+        assert!(config.history.is_empty());
+        assert!(config.blob.is_none());
+        assert!(config.hit_counter.is_none());
+        assert!(config.trending.is_none());
+        assert!(config.memo_table.is_none());
+        assert!(config.report.is_none());
+        assert!(config.count.is_none());
+        assert!(config.decode_args.is_none());
+        assert!(config.intercept_errors.is_none());
+        assert!(config.dev_mode.is_none());
+        assert!(config.dry_run.is_none());
+        assert!(config.trace.is_none());
+        assert!(config.expansions.is_empty());
+        assert!(config.param_max_length.is_none());
+        assert!(config.bind_args.is_none());
+        assert!(config.rest_columns.is_empty());
+        assert!(config.variable_indexes.is_empty());
+        assert!(!config.typescript);
+        assert!(!config.health);
+        assert!(config.capabilities.is_none());
     }
 
     #[test]
     fn test_module_config_merge() {
         let mut config = ModuleConfig {
-            db_path: String::new(),
-            query: String::new(),
-            template_path: String::new(),
+            db_path: None,
+            query: None,
+            template_path: None,
             query_params: vec![],
+            ..Default::default()
         };
 
         let prev = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: "SELECT * FROM test".to_string(),
-            template_path: "test.hbs".to_string(),
+            db_path: Some("test.db".to_string()),
+            db_map: Some(
+                DatabaseMap::parse(
+                    "tenants.db",
+                    "SELECT path FROM tenants WHERE host = :host",
+                    &[],
+                )
+                .unwrap(),
+            ),
+            attachments: vec![("stats".to_string(), "stats.db".to_string())],
+            query: Some("SELECT * FROM test".to_string()),
+            fallback_query: Some("SELECT * FROM test LIMIT 1".to_string()),
+            pagination: Some(Pagination::parse(&["per_page=10"]).unwrap()),
+            count_query: Some("SELECT COUNT(*) FROM test".to_string()),
+            count: Some(CountStrategy::None),
+            query_rules: vec![(
+                "$arg_q".to_string(),
+                "SELECT * FROM test WHERE name = :q".to_string(),
+            )],
+            sort_param: Some(SortParam::parse("$arg_sort", &["allow=name"]).unwrap()),
+            filters: vec![Filter::parse("name", "=", "$arg_name", None).unwrap()],
+            template_path: Some("test.hbs".to_string()),
+            theme: Some(Theme::parse("$cookie_theme", &["default=light"]).unwrap()),
+            template_variants: Some(TemplateVariants::parse("$bucket", &["b=test-b.hbs"]).unwrap()),
+            feed: Some(Feed::parse(&["rss"]).unwrap()),
+            form_template: Some("form.hbs".to_string()),
             query_params: vec![("id".to_string(), "$arg_id".to_string())],
+            param_types: vec![("id".to_string(), ParamType::Integer)],
+            param_defaults: vec![("id".to_string(), "1".to_string())],
+            required_params: vec!["name".to_string()],
+            param_patterns: vec![("id".to_string(), "[0-9]+".to_string())],
+            param_max_lengths: vec![("id".to_string(), MaxLength::parse("10").unwrap())],
+            param_max_length: Some(MaxLength::parse("256").unwrap()),
+            bind_args: Some(true),
+            allowed_tables: vec!["test".to_string()],
+            allowed_fields: vec!["title".to_string()],
+            hidden_columns: vec!["password_hash".to_string()],
+            blob_encoding: Some(BlobEncoding::Base64),
+            max_template_rows: Some(RowLimit::parse("50").unwrap()),
+            mutation_max_rows: Some(RowLimit::parse("1").unwrap()),
+            aggregate: Some(AggregateSpec::parse("max", "columns=price").unwrap()),
+            expand_json: Some(JsonExpansion::All),
+            json_nulls: Some(JsonNulls::Omit),
+            try_static: Some(TryStatic::parse(&["on", "save=on"]).unwrap()),
+            export_dir: Some("/srv/export".to_string()),
+            report: Some(Report::parse(&["weekly", "/srv/reports"]).unwrap()),
+            timing: Some(true),
+            trace: Some(true),
+            decode_args: Some(false),
+            intercept_errors: Some(true),
+            dev_mode: Some(true),
+            dry_run: Some(DryRun::parse("$sqlite_dry_run", &[]).unwrap()),
+            stream_buffer: Some(StreamBuffer::parse("64k").unwrap()),
+            stream_overflow: Some(StreamOverflow::Drop),
+            breadcrumbs: Some(Breadcrumbs::parse(&["on"]).unwrap()),
+            hit_counter: Some(HitCounter::parse(&["table=hits", "key=$uri"]).unwrap()),
+            trending: Some(Trending::parse(&["limit=5"]).unwrap()),
+            memo_table: Some(MemoTable::parse(&["memo", "ttl=1h"]).unwrap()),
+            expansions: vec![
+                Expansion::parse("author", "SELECT * FROM authors WHERE id = :author_id", &[])
+                    .unwrap(),
+            ],
+            sources: vec![(
+                "recent".to_string(),
+                "test.db".to_string(),
+                "SELECT * FROM test".to_string(),
+            )],
+            nav_menus: vec![NavMenu::parse("menu", "test.db", "SELECT * FROM test", &[]).unwrap()],
+            write_queries: vec!["INSERT INTO test (name) VALUES (:name)".to_string()],
+            write_methods: vec![WriteMethod::Post, WriteMethod::Delete],
+            batch: Some(true),
+            honeypot: Some(Honeypot::parse(&["field=website"]).unwrap()),
+            captcha: Some(Captcha::parse(&["provider=turnstile", "secret=k"]).unwrap()),
+            notify_email: Some(
+                EmailNotification::parse(&["template=mail.hbs", "to=owner@example.com"]).unwrap(),
+            ),
+            rest: Some(RestTable::parse("test", None).unwrap()),
+            upsert: Some(UpsertTable::parse("test", "(id)").unwrap()),
+            soft_delete: Some(SoftDelete::parse("column=deleted_at").unwrap()),
+            history: vec!["test".to_string()],
+            blob: Some(BlobRoute::parse("files", &[]).unwrap()),
+            rest_columns: vec![],
+            variable_indexes: vec![],
+            typescript: false,
+            health: false,
+            capabilities: None,
         };
 
         config.merge(&prev).unwrap();
 
-        assert_eq!(config.db_path, "test.db");
-        assert_eq!(config.query, "SELECT * FROM test");
-        assert_eq!(config.template_path, "test.hbs");
+        assert_eq!(config.db_path.as_deref(), Some("test.db"));
+        assert!(config.db_map.is_some());
+        assert_eq!(config.attachments.len(), 1);
+        assert_eq!(config.query.as_deref(), Some("SELECT * FROM test"));
+        assert_eq!(
+            config.fallback_query.as_deref(),
+            Some("SELECT * FROM test LIMIT 1")
+        );
+        assert_eq!(config.pagination.unwrap().per_page().get(), 10);
+        assert_eq!(
+            config.count_query.as_deref(),
+            Some("SELECT COUNT(*) FROM test")
+        );
+        assert_eq!(config.count, Some(CountStrategy::None));
+        assert_eq!(config.query_rules.len(), 1);
+        assert_eq!(config.sort_param.unwrap().allowed(), ["name"]);
+        assert_eq!(config.filters.len(), 1);
+        assert_eq!(config.template_path.as_deref(), Some("test.hbs"));
+        assert_eq!(config.theme.unwrap().default(), "light");
+        assert!(config.template_variants.unwrap().template("b").is_some());
+        assert_eq!(config.form_template.as_deref(), Some("form.hbs"));
+        assert_eq!(config.feed.unwrap().format(), FeedFormat::Rss);
         assert_eq!(config.query_params.len(), 1);
+        assert_eq!(
+            config.param_types,
+            vec![("id".to_string(), ParamType::Integer)]
+        );
+        assert_eq!(
+            config.param_defaults,
+            vec![("id".to_string(), "1".to_string())]
+        );
+        assert_eq!(config.required_params, vec!["name".to_string()]);
+        assert_eq!(
+            config.param_patterns,
+            vec![("id".to_string(), "[0-9]+".to_string())]
+        );
+        assert_eq!(config.param_max_lengths.len(), 1);
+        assert_eq!(config.param_max_length.unwrap().get(), 256);
+        assert_eq!(config.bind_args, Some(true));
+        assert_eq!(config.allowed_tables, vec!["test".to_string()]);
+        assert_eq!(config.allowed_fields, vec!["title".to_string()]);
+        assert_eq!(config.hidden_columns, vec!["password_hash".to_string()]);
+        assert_eq!(config.blob_encoding, Some(BlobEncoding::Base64));
+        assert_eq!(config.max_template_rows.unwrap().get(), 50);
+        assert_eq!(config.mutation_max_rows.unwrap().get(), 1);
+        assert!(config.aggregate.is_some());
+        assert_eq!(config.expand_json, Some(JsonExpansion::All));
+        assert_eq!(config.json_nulls, Some(JsonNulls::Omit));
+        assert!(config.try_static.unwrap().save());
+        assert_eq!(config.export_dir.as_deref(), Some("/srv/export"));
+        assert_eq!(config.report.unwrap().name(), "weekly");
+        assert_eq!(config.timing, Some(true));
+        assert_eq!(config.trace, Some(true));
+        assert_eq!(config.decode_args, Some(false));
+        assert_eq!(config.intercept_errors, Some(true));
+        assert_eq!(config.dev_mode, Some(true));
+        assert!(config.dry_run.is_some());
+        assert_eq!(config.stream_buffer.unwrap().size(), 65536);
+        assert_eq!(config.stream_overflow, Some(StreamOverflow::Drop));
+        assert!(config.breadcrumbs.unwrap().enabled());
+        assert_eq!(config.hit_counter.unwrap().table(), "hits");
+        assert_eq!(config.trending.unwrap().limit(), 5);
+        assert_eq!(config.memo_table.unwrap().table(), "memo");
+        assert_eq!(config.expansions[0].name(), "author");
+        assert_eq!(config.sources.len(), 1);
+        assert_eq!(config.nav_menus[0].name(), "menu");
+        assert_eq!(config.write_queries.len(), 1);
+        assert_eq!(config.write_methods.len(), 2);
+        assert_eq!(config.batch, Some(true));
+        assert_eq!(config.honeypot.unwrap().field(), "website");
+        assert_eq!(config.captcha.unwrap().secret(), "k");
+        assert_eq!(config.notify_email.unwrap().to(), &["owner@example.com"]);
+        assert_eq!(config.rest.unwrap().table(), "test");
+        assert_eq!(config.upsert.unwrap().keys(), ["id"]);
+        assert_eq!(config.soft_delete.unwrap().column(), "deleted_at");
+        assert_eq!(config.history, vec!["test".to_string()]);
+        assert_eq!(config.blob.unwrap().table(), "files");
     }
 
     #[test]
     fn test_module_config_merge_preserves_existing() {
         let mut config = ModuleConfig {
-            db_path: "existing.db".to_string(),
-            query: "SELECT 1".to_string(),
-            template_path: "existing.hbs".to_string(),
+            db_path: Some("existing.db".to_string()),
+            query: Some("SELECT 1".to_string()),
+            template_path: Some("existing.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
         let prev = ModuleConfig {
-            db_path: "prev.db".to_string(),
-            query: "SELECT 2".to_string(),
-            template_path: "prev.hbs".to_string(),
+            db_path: Some("prev.db".to_string()),
+            query: Some("SELECT 2".to_string()),
+            template_path: Some("prev.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
         config.merge(&prev).unwrap();
 
         // Should keep existing values
-        assert_eq!(config.db_path, "existing.db");
-        assert_eq!(config.query, "SELECT 1");
-        assert_eq!(config.template_path, "existing.hbs");
+        assert_eq!(config.db_path.as_deref(), Some("existing.db"));
+        assert_eq!(config.query.as_deref(), Some("SELECT 1"));
+        assert_eq!(config.template_path.as_deref(), Some("existing.hbs"));
+    }
+
+    #[test]
+    fn test_module_config_merge_keeps_empty_value() {
+        // An explicitly empty value is not "unset" and must not be replaced
+        let mut config = ModuleConfig {
+            fallback_query: Some(String::new()),
+            ..Default::default()
+        };
+        let prev = ModuleConfig {
+            fallback_query: Some("SELECT 1".to_string()),
+            ..Default::default()
+        };
+
+        config.merge(&prev).unwrap();
+        assert_eq!(config.fallback_query.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_module_config_merge_chain() {
+        // http -> server -> location, each level inheriting what it leaves unset
+        let http = ModuleConfig {
+            db_path: Some("catalog.db".to_string()),
+            max_template_rows: Some(RowLimit::parse("500").unwrap()),
+            ..Default::default()
+        };
+        let mut server = ModuleConfig {
+            max_template_rows: Some(RowLimit::parse("100").unwrap()),
+            ..Default::default()
+        };
+        server.merge(&http).unwrap();
+
+        let mut location = ModuleConfig {
+            query: Some("SELECT * FROM books".to_string()),
+            ..Default::default()
+        };
+        location.merge(&server).unwrap();
+
+        assert_eq!(location.db_path.as_deref(), Some("catalog.db"));
+        assert_eq!(location.max_template_rows.unwrap().get(), 100);
+        assert_eq!(location.query.as_deref(), Some("SELECT * FROM books"));
+    }
+
+    #[test]
+    fn test_module_config_merge_database_choice() {
+        let server = ModuleConfig {
+            db_map: Some(
+                DatabaseMap::parse(
+                    "tenants.db",
+                    "SELECT path FROM tenants WHERE host = :host",
+                    &[],
+                )
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let mut mapped = ModuleConfig::default();
+        mapped.merge(&server).unwrap();
+        assert!(mapped.db_map.is_some());
+        assert!(mapped.database_per_request());
+
+        // A location's own sqlite_db replaces the server's map
+        let mut own = ModuleConfig {
+            db_path: Some("shared.db".to_string()),
+            ..Default::default()
+        };
+        own.merge(&server).unwrap();
+        assert!(own.db_map.is_none());
+        assert!(!own.database_per_request());
+
+        let tenants = ModuleConfig {
+            db_path: Some("/srv/tenants/$host.db".to_string()),
+            ..Default::default()
+        };
+        assert!(tenants.database_per_request());
     }
 
     #[test]
     fn test_main_config_default() {
         let config = MainConfig::default();
-        assert!(config.global_templates_dir.is_empty());
+        assert!(config.global_templates_dir.is_none());
+        assert!(config.query_definitions.is_empty());
+        assert!(config.locations.is_empty());
+        assert!(config.error_variables.is_none());
+        assert!(config.stream_variable.is_none());
     }
 
     #[test]
     fn test_main_config_merge() {
         let mut config = MainConfig {
-            global_templates_dir: String::new(),
+            global_templates_dir: None,
+            query_definitions: vec![],
+            locations: vec![],
+            error_variables: None,
+            stream_variable: None,
         };
 
         let prev = MainConfig {
-            global_templates_dir: "templates/global".to_string(),
+            global_templates_dir: Some("templates/global".to_string()),
+            query_definitions: vec![("all_books".to_string(), "SELECT * FROM books".to_string())],
+            locations: vec![],
+            error_variables: None,
+            stream_variable: None,
         };
 
         config.merge(&prev).unwrap();
-        assert_eq!(config.global_templates_dir, "templates/global");
+        assert_eq!(
+            config.global_templates_dir.as_deref(),
+            Some("templates/global")
+        );
+        assert_eq!(config.query_definitions.len(), 1);
     }
 }
@@ -0,0 +1,154 @@
+//! Locations' validated configuration, shared by a worker's requests
+//!
+//! Validating a location's directives into a [`ValidatedConfig`] happens on
+//! its first request in each worker rather than on every request. The cache
+//! lives on the worker's [`WorkerState`](crate::worker::WorkerState).
+
+use crate::config::ModuleConfig;
+use crate::domain::ValidatedConfig;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// A location, by the address of its configuration
+///
+/// nginx allocates locations' configuration from the configuration cycle's
+/// pool, so an address is only unique within one cycle: once a cycle's pool
+/// is freed, a later cycle may place another location at the same address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LocationId(usize);
+
+impl LocationId {
+    pub fn of(config: &ModuleConfig) -> Self {
+        LocationId(config as *const ModuleConfig as usize)
+    }
+}
+
+/// Locations' configuration, validated on a location's first request and
+/// shared by the ones after it
+///
+/// A location is identified by its [`LocationId`], with the database its
+/// request resolved when `sqlite_db` is chosen per request. A configuration
+/// that is not valid is kept as `None`, so it is not validated again
+/// either. At most [`ConfigCache::MAX_ENTRIES`] are kept.
+///
+/// Entries are never invalidated one by one, as a location's configuration
+/// only changes with a new configuration cycle:
+///
+/// - a reload or binary upgrade starts new workers, whose caches start
+///   empty, and the old workers' caches go with them when they exit
+/// - with `master_process off`, a reload loads the new configuration in the
+///   same process, so loading one [`clear`](Self::clear)s the cache before
+///   a request can look up a location of the new cycle
+#[derive(Debug, Default)]
+pub struct ConfigCache {
+    entries: Mutex<HashMap<ConfigKey, Option<Arc<ValidatedConfig>>>>,
+}
+
+/// A location, and its request's own database
+type ConfigKey = (LocationId, Option<String>);
+
+impl ConfigCache {
+    pub const MAX_ENTRIES: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The location's validated configuration, from `validate` the first
+    /// time it is asked for
+    pub fn get_or_validate(
+        &self,
+        location: LocationId,
+        database: Option<&str>,
+        validate: impl FnOnce() -> Option<Arc<ValidatedConfig>>,
+    ) -> Option<Arc<ValidatedConfig>> {
+        let key = (location, database.map(str::to_string));
+        if let Some(cached) = self
+            .entries
+            .lock()
+            .ok()
+            .and_then(|entries| entries.get(&key).cloned())
+        {
+            return cached;
+        }
+        let validated = validate();
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() >= Self::MAX_ENTRIES {
+                entries.clear();
+            }
+            entries.insert(key, validated.clone());
+        }
+        validated
+    }
+
+    /// Forget every location, when a new configuration is loaded
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TemplatePath;
+
+    #[test]
+    fn test_config_cache() {
+        let cache = ConfigCache::new();
+        let config = Arc::new(ValidatedConfig {
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            ..ValidatedConfig::for_test("SELECT * FROM books")
+        });
+        let validations = std::cell::Cell::new(0);
+        let validate = |valid: bool| {
+            let config = Arc::clone(&config);
+            let validations = &validations;
+            move || {
+                validations.set(validations.get() + 1);
+                Some(config).filter(|_| valid)
+            }
+        };
+
+        // The first request validates; later ones share its configuration
+        let first = cache
+            .get_or_validate(LocationId(1), None, validate(true))
+            .unwrap();
+        let second = cache
+            .get_or_validate(LocationId(1), None, validate(true))
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(validations.get(), 1);
+
+        // Each tenant's database is its own entry, as is each location
+        assert!(
+            cache
+                .get_or_validate(LocationId(1), Some("a.db"), validate(true))
+                .is_some()
+        );
+        assert_eq!(validations.get(), 2);
+
+        // An invalid configuration is not validated again
+        assert!(
+            cache
+                .get_or_validate(LocationId(2), None, validate(false))
+                .is_none()
+        );
+        assert!(
+            cache
+                .get_or_validate(LocationId(2), None, validate(true))
+                .is_none()
+        );
+        assert_eq!(validations.get(), 3);
+
+        // Loading a configuration forgets every location
+        cache.clear();
+        assert!(
+            cache
+                .get_or_validate(LocationId(2), None, validate(true))
+                .is_some()
+        );
+        assert_eq!(validations.get(), 4);
+    }
+}
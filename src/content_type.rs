@@ -1,11 +1,18 @@
 //! Content type negotiation based on Accept headers
 
+use crate::types::FeedFormat;
 use ngx::http::Request;
+use std::cmp::Reverse;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ContentType {
     Html,
     Json,
+    Ndjson,
+    Pdf,
+    Xlsx,
+    Atom,
+    Rss,
 }
 
 impl ContentType {
@@ -13,45 +20,167 @@ impl ContentType {
         match self {
             ContentType::Html => "text/html; charset=utf-8",
             ContentType::Json => "application/json; charset=utf-8",
+            ContentType::Ndjson => "application/x-ndjson",
+            ContentType::Pdf => "application/pdf",
+            ContentType::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            }
+            ContentType::Atom => "application/atom+xml; charset=utf-8",
+            ContentType::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+
+    /// The type of a `sqlite_feed` document
+    pub fn for_feed(format: FeedFormat) -> ContentType {
+        match format {
+            FeedFormat::Atom => ContentType::Atom,
+            FeedFormat::Rss => ContentType::Rss,
         }
     }
+
+    /// Whether this is a `sqlite_feed` document, which only feed locations
+    /// answer with
+    pub fn is_feed(&self) -> bool {
+        matches!(self, ContentType::Atom | ContentType::Rss)
+    }
+
+    /// The type a `?format=` argument asks for; HTML and JSON are chosen by
+    /// the Accept header instead
+    pub fn from_format(format: &str) -> Option<ContentType> {
+        if format.eq_ignore_ascii_case("pdf") {
+            Some(ContentType::Pdf)
+        } else if format.eq_ignore_ascii_case("xlsx") {
+            Some(ContentType::Xlsx)
+        } else if format.eq_ignore_ascii_case("ndjson") {
+            Some(ContentType::Ndjson)
+        } else {
+            None
+        }
+    }
+
+    /// The type an Accept header prefers among HTML, JSON and NDJSON
+    ///
+    /// Each type takes the quality of the most specific media range that
+    /// matches it (`application/json` over `application/*` over `*/*`), and
+    /// the highest quality wins. Ties go to the type matched more
+    /// specifically, then to the one whose range is listed first, then to
+    /// HTML. HTML is also the answer when the header accepts none of them.
+    pub fn from_accept(accept: &str) -> ContentType {
+        let ranges: Vec<MediaRange> = accept.split(',').filter_map(MediaRange::parse).collect();
+        [ContentType::Html, ContentType::Json, ContentType::Ndjson]
+            .into_iter()
+            .enumerate()
+            .filter_map(|(preference, content_type)| {
+                let media_type = content_type.media_type();
+                ranges
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(position, range)| {
+                        range
+                            .specificity(media_type)
+                            .map(|specificity| (specificity, range.quality, position))
+                    })
+                    .max_by_key(|(specificity, _, position)| (*specificity, Reverse(*position)))
+                    .filter(|(_, quality, _)| *quality > 0)
+                    .map(|(specificity, quality, position)| {
+                        let rank = (quality, specificity, Reverse(position), Reverse(preference));
+                        (rank, content_type)
+                    })
+            })
+            .max_by_key(|(rank, _)| *rank)
+            .map_or(ContentType::Html, |(_, content_type)| content_type)
+    }
+
+    /// The header's media type without its parameters, e.g. `text/html`
+    pub fn media_type(&self) -> &'static str {
+        let header = self.content_type_header();
+        header.split(';').next().unwrap_or(header)
+    }
 }
 
-/// Determine response content type based on Accept header
-pub fn negotiate_content_type(request: &Request) -> ContentType {
-    // Use safe iterator API to access headers
-    for (key, value) in request.headers_in_iterator() {
-        // NgxStr supports case-insensitive comparison with str
-        if key
-            .to_str()
-            .map(|k| k.eq_ignore_ascii_case("accept"))
-            .unwrap_or(false)
-        {
-            // Convert NgxStr to str and process
-            if let Ok(value_str) = value.to_str() {
-                let value_lower = value_str.to_lowercase();
-
-                // Check if JSON is preferred over HTML
-                if value_lower.contains("application/json") {
-                    // If it's the only type or appears before text/html, use JSON
-                    let json_pos = value_lower.find("application/json");
-                    let html_pos = value_lower.find("text/html");
-
-                    if let (Some(_), None) = (json_pos, html_pos) {
-                        return ContentType::Json;
-                    }
-                    if let (Some(j), Some(h)) = (json_pos, html_pos)
-                        && j < h
-                    {
-                        return ContentType::Json;
-                    }
-                }
+/// One media range of an Accept header, e.g. `application/*;q=0.8`
+struct MediaRange {
+    type_: String,
+    subtype: String,
+    /// The `q` parameter in thousandths, 1000 when it is not given
+    quality: u16,
+}
+
+impl MediaRange {
+    /// Parse a media range; a malformed one is left out
+    fn parse(range: &str) -> Option<MediaRange> {
+        let mut parts = range.split(';');
+        let (type_, subtype) = parts.next()?.trim().split_once('/')?;
+        if type_.is_empty() || subtype.is_empty() || (type_ == "*" && subtype != "*") {
+            return None;
+        }
+        let mut quality = 1000;
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=')
+                && name.trim().eq_ignore_ascii_case("q")
+            {
+                quality = parse_quality(value.trim())?;
+                // Parameters after q are accept-extensions, not the range's
+                break;
             }
         }
+        Some(MediaRange {
+            type_: type_.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            quality,
+        })
     }
 
-    // Default to HTML
-    ContentType::Html
+    /// How specifically the range matches `media_type`: 2 for the type
+    /// itself, 1 for `type/*` and 0 for `*/*`; `None` when it does not
+    fn specificity(&self, media_type: &str) -> Option<u8> {
+        let (type_, subtype) = media_type.split_once('/')?;
+        match (self.type_.as_str(), self.subtype.as_str()) {
+            ("*", "*") => Some(0),
+            (t, "*") if t == type_ => Some(1),
+            (t, s) if t == type_ && s == subtype => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/// A qvalue (`0` to `1`, with at most three decimals) in thousandths
+fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Determine response content type based on Accept header
+pub fn negotiate_content_type(request: &Request) -> ContentType {
+    // Use safe iterator API to access headers; NgxStr supports
+    // case-insensitive comparison with str
+    let accepted = request
+        .headers_in_iterator()
+        .filter(|(key, _)| {
+            key.to_str()
+                .map(|k| k.eq_ignore_ascii_case("accept"))
+                .unwrap_or(false)
+        })
+        .filter_map(|(_, value)| value.to_str().ok());
+    negotiate_accept(accepted)
+}
+
+/// The content type the first of a request's Accept headers naming one
+/// other than HTML asks for, defaulting to HTML
+pub fn negotiate_accept<'a>(accepted: impl IntoIterator<Item = &'a str>) -> ContentType {
+    accepted
+        .into_iter()
+        .map(ContentType::from_accept)
+        .find(|content_type| *content_type != ContentType::Html)
+        .unwrap_or(ContentType::Html)
 }
 
 #[cfg(test)]
@@ -68,6 +197,130 @@ mod tests {
             ContentType::Json.content_type_header(),
             "application/json; charset=utf-8"
         );
+        assert_eq!(ContentType::Pdf.content_type_header(), "application/pdf");
+        assert_eq!(
+            ContentType::Ndjson.content_type_header(),
+            "application/x-ndjson"
+        );
+        assert_eq!(
+            ContentType::for_feed(FeedFormat::Rss).content_type_header(),
+            "application/rss+xml; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn test_from_format() {
+        assert_eq!(ContentType::from_format("PDF"), Some(ContentType::Pdf));
+        assert_eq!(ContentType::from_format("xlsx"), Some(ContentType::Xlsx));
+        assert_eq!(
+            ContentType::from_format("ndjson"),
+            Some(ContentType::Ndjson)
+        );
+        assert_eq!(ContentType::from_format("json"), None);
+        assert_eq!(ContentType::from_format(""), None);
+    }
+
+    #[test]
+    fn test_from_accept() {
+        assert_eq!(
+            ContentType::from_accept("application/json"),
+            ContentType::Json
+        );
+        assert_eq!(
+            ContentType::from_accept("application/json, text/html"),
+            ContentType::Json
+        );
+        assert_eq!(
+            ContentType::from_accept("text/html, application/json"),
+            ContentType::Html
+        );
+        assert_eq!(
+            ContentType::from_accept("Application/X-NDJSON"),
+            ContentType::Ndjson
+        );
+        assert_eq!(
+            ContentType::from_accept("application/x-ndjson, application/json;q=0.9"),
+            ContentType::Ndjson
+        );
+        assert_eq!(ContentType::from_accept("*/*"), ContentType::Html);
+        assert_eq!(ContentType::from_accept(""), ContentType::Html);
+    }
+
+    #[test]
+    fn test_negotiate_accept() {
+        assert_eq!(negotiate_accept([]), ContentType::Html);
+        assert_eq!(
+            negotiate_accept(["text/html", "application/json"]),
+            ContentType::Json
+        );
+        assert_eq!(
+            negotiate_accept(["application/x-ndjson", "application/json"]),
+            ContentType::Ndjson
+        );
+    }
+
+    #[test]
+    fn test_from_accept_quality() {
+        // The highest quality wins, wherever it is listed
+        assert_eq!(
+            ContentType::from_accept("application/json;q=0.9, text/html;q=1.0"),
+            ContentType::Html
+        );
+        assert_eq!(
+            ContentType::from_accept("text/html;q=0.5, application/json"),
+            ContentType::Json
+        );
+        // A browser's header keeps HTML
+        assert_eq!(
+            ContentType::from_accept(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+            ),
+            ContentType::Html
+        );
+        // The most specific range sets a type's quality
+        assert_eq!(
+            ContentType::from_accept("text/html;q=0, */*"),
+            ContentType::Json
+        );
+        assert_eq!(
+            ContentType::from_accept("application/*;q=0.8, text/*;q=0.5"),
+            ContentType::Json
+        );
+        assert_eq!(
+            ContentType::from_accept("application/*, application/x-ndjson"),
+            ContentType::Ndjson
+        );
+        assert_eq!(
+            ContentType::from_accept("text/*, application/json"),
+            ContentType::Json
+        );
+        // Parameters other than q, and those after it, do not matter
+        assert_eq!(
+            ContentType::from_accept("text/html;level=1;q=0.2, application/json;q=0.4;ext=1"),
+            ContentType::Json
+        );
+        // Nothing acceptable, or nothing well-formed, leaves HTML
+        assert_eq!(
+            ContentType::from_accept("application/json;q=0"),
+            ContentType::Html
+        );
+        assert_eq!(
+            ContentType::from_accept("image/png, application/json;q=2"),
+            ContentType::Html
+        );
+        assert_eq!(ContentType::from_accept("json, */json"), ContentType::Html);
+    }
+
+    #[test]
+    fn test_parse_quality() {
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0.85"), Some(850));
+        assert_eq!(parse_quality("0"), Some(0));
+        assert_eq!(parse_quality("1.5"), None);
+        assert_eq!(parse_quality("0.1234"), None);
+        assert_eq!(parse_quality("-0.5"), None);
+        assert_eq!(parse_quality("high"), None);
     }
 
     #[test]
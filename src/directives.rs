@@ -0,0 +1,237 @@
+//! What each location directive records in its [`ModuleConfig`]
+//!
+//! nginx checks where a directive is written and how many arguments it has
+//! against the module's command table, then its handler in `lib.rs` hands the
+//! arguments to [`set`]. Parsing them needs nothing from nginx, so fixtures
+//! (`crate::fixture`) configure their locations through the same function.
+
+use crate::config::ModuleConfig;
+use crate::parsing;
+use crate::types::{
+    AggregateSpec, BlobEncoding, BlobRoute, Breadcrumbs, Captcha, CountStrategy, DatabaseMap,
+    DatabaseTemplate, DryRun, EmailNotification, Expansion, Feed, Filter, HitCounter, Honeypot,
+    JsonExpansion, JsonNulls, MaxLength, MemoTable, NavMenu, NginxVariable, Pagination, Report,
+    RestTable, RowLimit, SoftDelete, SortParam, StreamBuffer, StreamOverflow, TemplateVariants,
+    Theme, Trending, TryStatic, UpsertTable, WriteMethod, is_identifier,
+};
+
+/// Record the location directive `name` with its `args` (the words after
+/// its name), which nginx has already counted
+pub fn set(conf: &mut ModuleConfig, name: &str, args: &[String]) -> Result<(), String> {
+    let values: Vec<&str> = args.iter().map(String::as_str).collect();
+    match name {
+        "sqlite_db" => {
+            // sqlite_db /data/$host.db names a database per request
+            if DatabaseTemplate::has_variables(&args[0]) {
+                DatabaseTemplate::parse(&args[0])?;
+            }
+            conf.db_path = Some(args[0].clone());
+        }
+        // sqlite_db_map tenants.db "SELECT db_path FROM tenants WHERE host = :host" [max_age=60s]
+        "sqlite_db_map" => {
+            conf.db_map = Some(DatabaseMap::parse(&args[0], &args[1], &values[2..])?)
+        }
+        // sqlite_attach stats /var/data/stats.db
+        "sqlite_attach" => conf.attachments.push((args[0].clone(), args[1].clone())),
+        "sqlite_query" => conf.query = Some(args[0].clone()),
+        // sqlite_query_if $arg_genre "SELECT ... WHERE genre = :genre"
+        "sqlite_query_if" => conf.query_rules.push((args[0].clone(), args[1].clone())),
+        // sqlite_sort_param $arg_sort allow=title,year,rating
+        "sqlite_sort_param" => conf.sort_param = Some(SortParam::parse(&args[0], &values[1..])?),
+        // sqlite_filter year >= $arg_min_year int
+        "sqlite_filter" => conf.filters.push(Filter::parse(
+            &args[0],
+            &args[1],
+            &args[2],
+            values.get(3).copied(),
+        )?),
+        "sqlite_fallback_query" => conf.fallback_query = Some(args[0].clone()),
+        // sqlite_paginate per_page=20 [param=page] | per_page=20 mode=cursor key=id secret=...
+        "sqlite_paginate" => conf.pagination = Some(Pagination::parse(&values)?),
+        "sqlite_count_query" => conf.count_query = Some(args[0].clone()),
+        // sqlite_count exact | estimate [table=name] | none
+        "sqlite_count" => conf.count = Some(CountStrategy::parse(&values)?),
+        // sqlite_source top_rated stats.db "SELECT ..."
+        "sqlite_source" => conf
+            .sources
+            .push((args[0].clone(), args[1].clone(), args[2].clone())),
+        // sqlite_nav main_menu site.db "SELECT label, url FROM menu" [max_age=300s]
+        "sqlite_nav" => {
+            conf.nav_menus
+                .push(NavMenu::parse(&args[0], &args[1], &args[2], &values[3..])?)
+        }
+        "sqlite_write_query" => conf.write_queries.push(args[0].clone()),
+        // sqlite_methods POST PUT DELETE
+        "sqlite_methods" => {
+            for value in &values {
+                let method = WriteMethod::parse(value)?;
+                if !conf.write_methods.contains(&method) {
+                    conf.write_methods.push(method);
+                }
+            }
+        }
+        "sqlite_batch" => conf.batch = Some(on_off(&args[0])?),
+        // sqlite_honeypot field=website [min_time=3s] [time_field=form_time]
+        "sqlite_honeypot" => conf.honeypot = Some(Honeypot::parse(&values)?),
+        // sqlite_captcha provider=turnstile secret=... [verify=/uri] [field=name]
+        "sqlite_captcha" => conf.captcha = Some(Captcha::parse(&values)?),
+        // sqlite_notify_email template=mail.hbs to=owner@example.com [from=...] [subject=...]
+        "sqlite_notify_email" => conf.notify_email = Some(EmailNotification::parse(&values)?),
+        // sqlite_rest books [key=id]
+        "sqlite_rest" => conf.rest = Some(RestTable::parse(&args[0], values.get(1).copied())?),
+        // sqlite_upsert books (isbn) | sqlite_upsert shelves (shelf, position)
+        "sqlite_upsert" => conf.upsert = Some(UpsertTable::parse(&args[0], &args[1..].join(" "))?),
+        "sqlite_template" => conf.template_path = Some(args[0].clone()),
+        "sqlite_form_template" => conf.form_template = Some(args[0].clone()),
+        // sqlite_template_variant $variable [secret=key] name=file.hbs...
+        "sqlite_template_variant" => {
+            conf.template_variants = Some(TemplateVariants::parse(&args[0], &values[1..])?)
+        }
+        // sqlite_feed atom|rss [title=column] [link=column] [updated=column]
+        //             [content=column] [name=title] | off
+        "sqlite_feed" => conf.feed = Some(Feed::parse(&values)?),
+        "sqlite_param" => parsing::add_parameter(conf, args.to_vec())?,
+        "sqlite_param_required" => parsing::add_required_parameter(conf, args.to_vec())?,
+        "sqlite_param_max_length" => conf.param_max_length = Some(MaxLength::parse(&args[0])?),
+        "sqlite_bind_args" => conf.bind_args = Some(on_off(&args[0])?),
+        // sqlite_allow_tables books authors ...
+        "sqlite_allow_tables" => conf.allowed_tables.extend_from_slice(args),
+        // sqlite_allow_fields id title author ...
+        "sqlite_allow_fields" => conf.allowed_fields.extend_from_slice(args),
+        // sqlite_hide_columns password_hash internal_notes ...
+        "sqlite_hide_columns" => conf.hidden_columns.extend_from_slice(args),
+        "sqlite_max_template_rows" => conf.max_template_rows = Some(RowLimit::parse(&args[0])?),
+        "sqlite_mutation_max_rows" => conf.mutation_max_rows = Some(RowLimit::parse(&args[0])?),
+        // sqlite_aggregate min,max,sum,avg columns=price,pages
+        "sqlite_aggregate" => conf.aggregate = Some(AggregateSpec::parse(&args[0], &args[1])?),
+        // sqlite_expand author "SELECT * FROM authors WHERE id = :author_id" [many]
+        "sqlite_expand" => {
+            conf.expansions
+                .push(Expansion::parse(&args[0], &args[1], &values[2..])?)
+        }
+        // sqlite_expand_json on|off|columns=tags,meta
+        "sqlite_expand_json" => conf.expand_json = Some(JsonExpansion::parse(&args[0])?),
+        // sqlite_json_nulls keep|omit
+        "sqlite_json_nulls" => conf.json_nulls = Some(JsonNulls::parse(&args[0])?),
+        // sqlite_blob_encoding hex|base64|skip
+        "sqlite_blob_encoding" => conf.blob_encoding = Some(BlobEncoding::parse(&args[0])?),
+        // sqlite_try_static on [max_age=300s] [save=on] | off
+        "sqlite_try_static" => conf.try_static = Some(TryStatic::parse(&values)?),
+        // sqlite_breadcrumbs on ["labels_query=SELECT ..."] | off
+        "sqlite_breadcrumbs" => conf.breadcrumbs = Some(Breadcrumbs::parse(&values)?),
+        // sqlite_hit_counter table=page_views key=$uri [daily=page_views_daily]
+        "sqlite_hit_counter" => conf.hit_counter = Some(HitCounter::parse(&values)?),
+        // sqlite_trending on | [days=7] [limit=10] [max_age=60] | off
+        "sqlite_trending" => conf.trending = Some(Trending::parse(&values)?),
+        // sqlite_memo_table name [ttl=1h] | off
+        "sqlite_memo_table" => conf.memo_table = Some(MemoTable::parse(&values)?),
+        // sqlite_theme $variable default=name [root=directory]
+        "sqlite_theme" => conf.theme = Some(Theme::parse(&args[0], &values[1..])?),
+        "sqlite_export" => conf.export_dir = Some(args[0].clone()),
+        // sqlite_report weekly /var/reports [format=ndjson] [every=1d]
+        "sqlite_report" => conf.report = Some(Report::parse(&values)?),
+        "sqlite_health" => conf.health = true,
+        "sqlite_typescript" => conf.typescript = true,
+        // sqlite_capabilities $sqlite_debug
+        "sqlite_capabilities" => conf.capabilities = Some(NginxVariable::parse(args[0].clone())?),
+        // sqlite_soft_delete column=deleted_at
+        "sqlite_soft_delete" => conf.soft_delete = Some(SoftDelete::parse(&args[0])?),
+        // sqlite_history books authors ...
+        "sqlite_history" => {
+            for table in args {
+                if !is_identifier(table) {
+                    return Err(format!(
+                        "table name must be letters, digits or underscores: '{}'",
+                        table
+                    ));
+                }
+                conf.history.push(table.clone());
+            }
+        }
+        // sqlite_blob files [data=data] [hash=sha256] [type=mime]
+        "sqlite_blob" => conf.blob = Some(BlobRoute::parse(&args[0], &values[1..])?),
+        "sqlite_timing" => conf.timing = Some(on_off(&args[0])?),
+        "sqlite_trace" => conf.trace = Some(on_off(&args[0])?),
+        "sqlite_decode_args" => conf.decode_args = Some(on_off(&args[0])?),
+        "sqlite_intercept_errors" => conf.intercept_errors = Some(on_off(&args[0])?),
+        "sqlite_dev_mode" => conf.dev_mode = Some(on_off(&args[0])?),
+        // sqlite_dry_run $sqlite_debug redact=:password,:token
+        "sqlite_dry_run" => conf.dry_run = Some(DryRun::parse(&args[0], &values[1..])?),
+        // sqlite_stream_buffer size|off
+        "sqlite_stream_buffer" => conf.stream_buffer = Some(StreamBuffer::parse(&args[0])?),
+        // sqlite_stream_overflow wait|drop
+        "sqlite_stream_overflow" => conf.stream_overflow = Some(StreamOverflow::parse(&args[0])?),
+        other => return Err(format!("{} is not a location directive", other)),
+    }
+    Ok(())
+}
+
+/// Whether `name` makes its location answer requests itself, so the
+/// module's handler is installed there
+pub fn serves(name: &str) -> bool {
+    matches!(
+        name,
+        "sqlite_template"
+            | "sqlite_feed"
+            | "sqlite_rest"
+            | "sqlite_export"
+            | "sqlite_report"
+            | "sqlite_health"
+            | "sqlite_typescript"
+            | "sqlite_capabilities"
+            | "sqlite_blob"
+    )
+}
+
+/// `on` or `off`
+fn on_off(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        other => Err(format!("expected on or off, got '{}'", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn test_set() {
+        let mut conf = ModuleConfig::default();
+        set(&mut conf, "sqlite_db", &args(&["/srv/books.db"])).unwrap();
+        set(&mut conf, "sqlite_query", &args(&["SELECT * FROM books"])).unwrap();
+        set(
+            &mut conf,
+            "sqlite_methods",
+            &args(&["POST", "DELETE", "POST"]),
+        )
+        .unwrap();
+        set(&mut conf, "sqlite_timing", &args(&["on"])).unwrap();
+        set(
+            &mut conf,
+            "sqlite_upsert",
+            &args(&["shelves", "(shelf,", "position)"]),
+        )
+        .unwrap();
+        set(&mut conf, "sqlite_health", &[]).unwrap();
+        assert_eq!(conf.db_path.as_deref(), Some("/srv/books.db"));
+        assert_eq!(conf.query.as_deref(), Some("SELECT * FROM books"));
+        assert_eq!(conf.write_methods.len(), 2);
+        assert_eq!(conf.timing, Some(true));
+        assert!(conf.upsert.is_some());
+        assert!(conf.health);
+
+        assert!(set(&mut conf, "sqlite_db", &args(&["data/$host.db"])).is_err());
+        assert!(set(&mut conf, "sqlite_batch", &args(&["yes"])).is_err());
+        assert!(set(&mut conf, "sqlite_history", &args(&["books", "a-b"])).is_err());
+        assert!(set(&mut conf, "sqlite_query_define", &args(&["a", "b"])).is_err());
+
+        assert!(serves("sqlite_template"));
+        assert!(!serves("sqlite_query"));
+    }
+}
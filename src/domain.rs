@@ -1,9 +1,22 @@
 //! Pure functional core with dependency injection (Functional Core, Imperative Shell)
 
-use crate::types::{DatabasePath, ParameterBinding, SqlQuery, TemplatePath};
+use crate::guard;
+use crate::types::{
+    AggregateFn, AggregateSpec, Breadcrumbs, Captcha, CountStrategy, CursorKey, DataSource,
+    DatabasePath, DryRun, EmailNotification, Expansion, Feed, Filter, HitCounter, Honeypot,
+    JsonExpansion, JsonNulls, MaxLength, MemoTable, NavMenu, NginxVariable, PageMode, Pagination,
+    ParamName, ParamPattern, ParamType, ParameterBinding, QueryRule, Report, RowLimit, RowShape,
+    SortParam, SqlQuery, StreamBuffer, TemplatePath, TemplateVariants, Theme, TryStatic,
+    WriteMethod, WriteQuery, is_email_address,
+};
+use hmac::{Hmac, Mac};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Log levels for structured logging
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -40,43 +53,289 @@ pub trait Logger {
     }
 }
 
+impl<L: Logger + ?Sized> Logger for Box<L> {
+    fn log(&self, level: LogLevel, module: &str, message: &str) {
+        (**self).log(level, module, message);
+    }
+}
+
+/// Counters and timings for dependency injection
+pub trait Metrics {
+    /// Add `by` to the counter `name`
+    fn count(&self, name: &str, by: u64);
+
+    /// Record that one `name` took `duration`
+    fn time(&self, name: &str, duration: Duration);
+}
+
+/// Metrics nothing is listening to
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {
+    fn count(&self, _name: &str, _by: u64) {}
+
+    fn time(&self, _name: &str, _duration: Duration) {}
+}
+
 /// Configuration for a location (validated at parse time)
 #[derive(Debug, Clone)]
 pub struct ValidatedConfig {
     pub db_path: DatabasePath,
     pub query: SqlQuery,
-    pub template_path: TemplatePath,
+    pub fallback_query: Option<SqlQuery>,
+    pub pagination: Option<Pagination>, // limit reads to the requested page
+    pub count_query: Option<SqlQuery>,  // counts every page's rows; derived when unset
+    pub query_rules: Vec<QueryRule>,
+    pub template_path: Option<TemplatePath>,
     pub parameters: Vec<ParameterBinding>,
+    pub max_template_rows: Option<RowLimit>,
+    pub aggregate: Option<AggregateSpec>,
+    pub sources: Vec<DataSource>,
+    pub write_queries: Vec<(WriteMethod, WriteQuery)>, // statements run for each write method, in order
+    pub mutation_max_rows: Option<RowLimit>, // most rows one run of the statements may change
+    pub batch: bool,                         // read parameter sets from the request body
+    pub form_template: Option<TemplatePath>, // re-rendered when a write fails validation
+    pub honeypot: Option<Honeypot>,          // rejects writes that look automated
+    pub captcha: Option<Captcha>,            // verifies a captcha token before writing
+    pub notify_email: Option<EmailNotification>, // sent after a write succeeds
+    pub expand_json: JsonExpansion,          // JSON text columns to nest in results
+    pub json_nulls: JsonNulls,               // whether JSON rows keep NULL-valued keys
+    pub rows: RowShape,                      // hidden columns and BLOB encoding of every row read
+    pub try_static: Option<TryStatic>,       // serve and save pre-rendered pages
+    pub export_dir: Option<String>,          // POST renders the query's URIs here
+    pub report: Option<Report>,              // POST saves the rows as this file
+    pub timing: bool,                        // report query and render durations
+    pub trace: bool,                         // log each request's operations as a tree
+    pub nav_menus: Vec<NavMenu>,             // rows for the `{{#nav}}` helper
+    pub breadcrumbs: Option<Breadcrumbs>,    // links to each parent of the request path
+    pub theme: Option<Theme>,                // template directories chosen per request
+    pub template_variants: Option<TemplateVariants>, // alternate templates chosen per request
+    pub hit_counter: Option<HitCounter>,     // counts reads and binds the total as :hits
+    pub trending: Option<NavMenu>,           // ranked pages for the `{{#trending}}` helper
+    pub memo_table: Option<MemoTable>,       // keeps computed JSON responses in the database
+    pub expansions: Vec<Expansion>,          // related rows JSON responses can nest (`?expand=`)
+    pub sort: Option<SortParam>,             // ORDER BY columns a request may choose
+    pub filters: Vec<Filter>,                // conditions applied when their variables are set
+    pub allowed_fields: Vec<String>,         // what ?fields= may name; empty allows every field
+    pub feed: Option<Feed>,                  // answers with an Atom or RSS document of the rows
+    pub stream: Option<StreamBuffer>,        // sends streamed formats as a slow client takes them
+    pub dry_run: Option<DryRun>, // reports bindings instead of running when its variable is set
+}
+
+/// Where a request is served from: the location's document root and the
+/// request's URI
+///
+/// These change from request to request while a location's
+/// [`ValidatedConfig`] does not, so they are kept apart from it and passed
+/// alongside it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestPaths {
     pub doc_root: String,
     pub uri: String,
 }
 
+impl RequestPaths {
+    pub fn new(doc_root: impl Into<String>, uri: impl Into<String>) -> Self {
+        RequestPaths {
+            doc_root: doc_root.into(),
+            uri: uri.into(),
+        }
+    }
+}
+
 impl ValidatedConfig {
-    pub fn resolve_template_path(&self) -> ResolvedTemplate {
-        let full_path = format!(
-            "{}{}/{}",
-            self.doc_root,
-            self.uri,
-            self.template_path.as_str()
-        );
-        let directory = Path::new(&full_path)
-            .parent()
-            .and_then(|p| p.to_str())
-            .unwrap_or("")
-            .to_string();
+    /// Locate the template under the document root; `None` for JSON-only
+    /// locations (`sqlite_rest` without `sqlite_template`)
+    pub fn resolve_template_path(&self, paths: &RequestPaths) -> Option<ResolvedTemplate> {
+        self.template_path
+            .as_ref()
+            .map(|t| self.resolve_in_location(paths, t))
+    }
+
+    /// Locate the `sqlite_form_template`, like the main template
+    pub fn resolve_form_template_path(&self, paths: &RequestPaths) -> Option<ResolvedTemplate> {
+        self.form_template
+            .as_ref()
+            .map(|t| self.resolve_in_location(paths, t))
+    }
+
+    /// Locate every `sqlite_template_variant` template, like the main template
+    pub fn resolve_variant_template_paths(&self, paths: &RequestPaths) -> Vec<ResolvedTemplate> {
+        self.template_variants
+            .iter()
+            .flat_map(|variants| variants.templates())
+            .map(|t| self.resolve_in_location(paths, t))
+            .collect()
+    }
+
+    /// Locate the `sqlite_notify_email` body template, like the main template
+    pub fn resolve_email_template_path(&self, paths: &RequestPaths) -> Option<ResolvedTemplate> {
+        self.notify_email
+            .as_ref()
+            .map(|n| self.resolve_in_location(paths, n.template()))
+    }
+
+    /// Whether a parameter is bound from the request body, which must then be
+    /// read before parameters are resolved
+    pub fn reads_body(&self) -> bool {
+        self.parameters
+            .iter()
+            .filter_map(ParameterBinding::variable)
+            .any(|variable| variable.reads_body())
+    }
+
+    /// The database files a page is rendered from: the main one, its
+    /// attachments, every `sqlite_source` and every `sqlite_nav` menu
+    pub fn database_files(&self) -> Vec<&str> {
+        let mut files = vec![self.db_path.as_str()];
+        files.extend(self.db_path.attachments().iter().map(|a| a.path()));
+        files.extend(self.sources.iter().map(|s| s.db_path().as_str()));
+        files.extend(self.nav_menus.iter().map(|m| m.source().db_path().as_str()));
+        files
+    }
+
+    /// Locate the main template for a request whose `sqlite_theme` is
+    /// `active`: the active theme's file when it `exists`, else the default
+    /// theme's, whose directory then supplies any partials the active theme
+    /// lacks
+    pub fn resolve_themed_template_path(
+        &self,
+        paths: &RequestPaths,
+        active: &str,
+        exists: impl Fn(&str) -> bool,
+    ) -> Option<ResolvedTemplate> {
+        self.template_path
+            .as_ref()
+            .map(|t| self.resolve_themed(paths, t, active, &exists))
+    }
+
+    /// Locate the `sqlite_form_template` for the active theme, like the main
+    /// template
+    pub fn resolve_themed_form_template_path(
+        &self,
+        paths: &RequestPaths,
+        active: &str,
+        exists: impl Fn(&str) -> bool,
+    ) -> Option<ResolvedTemplate> {
+        self.form_template
+            .as_ref()
+            .map(|t| self.resolve_themed(paths, t, active, &exists))
+    }
+
+    fn resolve_themed(
+        &self,
+        paths: &RequestPaths,
+        template_path: &TemplatePath,
+        active: &str,
+        exists: &dyn Fn(&str) -> bool,
+    ) -> ResolvedTemplate {
+        let default = self.resolve_in_location(paths, template_path);
+        let Some(theme) = self.theme.as_ref().filter(|t| t.default() != active) else {
+            return default;
+        };
 
+        let themed = resolve_under(
+            &theme_dir(&paths.doc_root, theme, active),
+            &paths.uri,
+            template_path,
+        );
         ResolvedTemplate {
-            full_path,
-            directory,
+            full_path: if exists(&themed.full_path) {
+                themed.full_path
+            } else {
+                default.full_path
+            },
+            directory: themed.directory,
+            fallback_directory: Some(default.directory),
+        }
+    }
+
+    /// Resolve beside the request URI, under the default theme's directory
+    /// when there is a `sqlite_theme`
+    fn resolve_in_location(
+        &self,
+        paths: &RequestPaths,
+        template_path: &TemplatePath,
+    ) -> ResolvedTemplate {
+        let root = match &self.theme {
+            Some(theme) => theme_dir(&paths.doc_root, theme, theme.default()),
+            None => paths.doc_root.clone(),
+        };
+        resolve_under(&root, &paths.uri, template_path)
+    }
+
+    /// A configuration for tests: `query` against `test.db`, with every other
+    /// directive unset; tests set what they exercise with struct update syntax
+    #[cfg(test)]
+    pub fn for_test(query: &str) -> Self {
+        ValidatedConfig {
+            db_path: DatabasePath::parse("test.db").unwrap(),
+            query: SqlQuery::parse(query).unwrap(),
+            fallback_query: None,
+            pagination: None,
+            count_query: None,
+            query_rules: vec![],
+            template_path: None,
+            parameters: vec![],
+            max_template_rows: None,
+            aggregate: None,
+            sources: vec![],
+            write_queries: vec![],
+            mutation_max_rows: None,
+            batch: false,
+            form_template: None,
+            honeypot: None,
+            captcha: None,
+            notify_email: None,
+            expand_json: JsonExpansion::Off,
+            json_nulls: JsonNulls::Keep,
+            rows: RowShape::default(),
+            try_static: None,
+            export_dir: None,
+            report: None,
+            timing: false,
+            trace: false,
+            nav_menus: vec![],
+            breadcrumbs: None,
+            theme: None,
+            template_variants: None,
+            hit_counter: None,
+            trending: None,
+            memo_table: None,
+            expansions: vec![],
+            sort: None,
+            filters: vec![],
+            allowed_fields: vec![],
+            feed: None,
+            stream: None,
+            dry_run: None,
         }
     }
 }
 
+fn resolve_under(root: &str, uri: &str, template_path: &TemplatePath) -> ResolvedTemplate {
+    let full_path = format!("{}{}/{}", root, uri, template_path.as_str());
+    let directory = Path::new(&full_path)
+        .parent()
+        .and_then(|p| p.to_str())
+        .unwrap_or("")
+        .to_string();
+
+    ResolvedTemplate {
+        full_path,
+        directory,
+        fallback_directory: None,
+    }
+}
+
 /// Template resolution result
 #[derive(Debug)]
 pub struct ResolvedTemplate {
     pub full_path: String,
     pub directory: String,
+    /// Partials loaded before `directory`'s, which replace them (the default
+    /// theme's, when another theme is active)
+    pub fallback_directory: Option<String>,
 }
 
 impl ResolvedTemplate {
@@ -90,361 +349,5734 @@ impl ResolvedTemplate {
 }
 
 /// Resolve template path relative to document root and URI (pure function)
-pub fn resolve_template_path(config: &ValidatedConfig) -> ResolvedTemplate {
-    config.resolve_template_path()
+pub fn resolve_template_path(
+    config: &ValidatedConfig,
+    paths: &RequestPaths,
+) -> Option<ResolvedTemplate> {
+    config.resolve_template_path(paths)
 }
 
-/// Parameter resolution strategy (dependency injection)
-pub trait VariableResolver {
-    fn resolve(&mut self, var_name: &str) -> Result<String, String>;
+/// A theme's directory under the document root
+fn theme_dir(doc_root: &str, theme: &Theme, name: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        doc_root.trim_end_matches('/'),
+        theme.root(),
+        name
+    )
 }
 
-/// Resolve all parameters using the provided resolver
-pub fn resolve_parameters(
-    bindings: &[ParameterBinding],
+/// Turn JSON text in result columns into nested values (pure function)
+///
+/// SQLite's JSON functions (`json_object()`, `json_group_array()`, ...) return
+/// text, so without this templates and JSON responses see a string. With
+/// [`JsonExpansion::All`] only text holding an object or array is expanded,
+/// which leaves strings such as `"42"` alone; named columns are expanded
+/// whenever their text is valid JSON. Text that does not parse is kept.
+pub fn expand_json(rows: &mut [HashMap<String, Value>], expansion: &JsonExpansion) {
+    if *expansion == JsonExpansion::Off {
+        return;
+    }
+    for row in rows.iter_mut() {
+        for (column, value) in row.iter_mut() {
+            let Value::String(text) = value else {
+                continue;
+            };
+            let parsed = match expansion {
+                JsonExpansion::All if text.trim_start().starts_with(['{', '[']) => {
+                    serde_json::from_str(text).ok()
+                }
+                JsonExpansion::Columns(columns) if columns.contains(column) => {
+                    serde_json::from_str(text).ok()
+                }
+                _ => None,
+            };
+            if let Some(parsed) = parsed {
+                *value = parsed;
+            }
+        }
+    }
+}
+
+/// Drop the keys of NULL values from rows and the objects nested in them,
+/// for `sqlite_json_nulls omit` (pure function)
+///
+/// Array elements are kept, so a list keeps its length and positions.
+pub fn omit_nulls(rows: &mut [HashMap<String, Value>]) {
+    fn omit(value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                object.retain(|_, v| !v.is_null());
+                object.values_mut().for_each(omit);
+            }
+            Value::Array(items) => items.iter_mut().for_each(omit),
+            _ => {}
+        }
+    }
+    for row in rows.iter_mut() {
+        row.retain(|_, v| !v.is_null());
+        row.values_mut().for_each(omit);
+    }
+}
+
+/// The `sqlite_expand` relations a request asks for with `?expand=a,b`
+///
+/// Names are comma-separated and repeats are ignored; a name that is not one
+/// of the location's expansions fails the request. Without expansions the
+/// argument is not looked at.
+pub fn requested_expansions<'a>(
+    expansions: &'a [Expansion],
     resolver: &mut dyn VariableResolver,
-) -> Result<Vec<(String, String)>, String> {
-    let mut resolved = Vec::new();
+) -> Result<Vec<&'a Expansion>, ParamError> {
+    if expansions.is_empty() {
+        return Ok(Vec::new());
+    }
+    let requested = resolver.resolve("$arg_expand").unwrap_or_default();
+    let mut selected: Vec<&Expansion> = Vec::new();
+    for name in requested
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+    {
+        // The message lists what may be asked for, not what was
+        let expansion = expansions
+            .iter()
+            .find(|e| e.name() == name)
+            .ok_or_else(|| ParamError::Invalid {
+                name: "expand".to_string(),
+                message: format!(
+                    "must name one of {}",
+                    expansions
+                        .iter()
+                        .map(Expansion::name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            })?;
+        if !selected.iter().any(|e| e.name() == name) {
+            selected.push(expansion);
+        }
+    }
+    Ok(selected)
+}
 
-    for binding in bindings {
-        match binding {
-            ParameterBinding::Positional { variable } => {
-                let value = resolver.resolve(variable.as_str())?;
-                resolved.push((String::new(), value));
+/// Keys at most this many at a time are bound to one expansion query, well
+/// under SQLite's limit on bound parameters
+const EXPANSION_BATCH: usize = 500;
+
+/// Nest the related rows of each expansion under its name in every row
+///
+/// An expansion's query runs once per [`EXPANSION_BATCH`] distinct keys rather
+/// than once per row. A row whose key is NULL or matches nothing gets null
+/// (an empty array for `many`); a related row without the compared column
+/// fails, as it could not be matched back.
+pub fn expand_relations(
+    rows: &mut [HashMap<String, Value>],
+    expansions: &[&Expansion],
+    executor: &dyn QueryExecutor,
+    db_path: &DatabasePath,
+    trace: Option<&dyn Tracer>,
+) -> Result<(), DbError> {
+    // Keys compare as SQLite would after affinity: "7" and 7 are one key
+    let key_of = |value: &Value| match value {
+        Value::Null => None,
+        Value::String(text) => Some(text.clone()),
+        other => Some(other.to_string()),
+    };
+
+    for expansion in expansions {
+        let mut keys: Vec<&Value> = Vec::new();
+        let mut seen = std::collections::HashSet::new();
+        for row in rows.iter() {
+            if let Some(value) = row.get(expansion.key())
+                && let Some(key) = key_of(value)
+                && seen.insert(key)
+            {
+                keys.push(value);
             }
-            ParameterBinding::PositionalLiteral { value } => {
-                resolved.push((String::new(), value.clone()));
+        }
+
+        let span = trace.map(|trace| trace.begin(&format!("expand {}", expansion.name()), None));
+        let mut related: HashMap<String, Vec<Value>> = HashMap::new();
+        let mut related_rows = 0;
+        for batch in keys.chunks(EXPANSION_BATCH) {
+            let params: Vec<(String, Value)> = batch
+                .iter()
+                .map(|&key| (expansion.param().to_string(), key.clone()))
+                .collect();
+            let batch_rows = traced(trace, "batch query", span, || {
+                executor.execute(db_path, expansion.batched(), &params)
+            })?;
+            related_rows += batch_rows.len();
+            for related_row in batch_rows {
+                let key = related_row
+                    .get(expansion.column())
+                    .ok_or_else(|| {
+                        DbError::internal(format!(
+                            "sqlite_expand {} must select {}",
+                            expansion.name(),
+                            expansion.column()
+                        ))
+                    })
+                    .map(key_of)?;
+                if let Some(key) = key {
+                    let object = Value::Object(related_row.into_iter().collect());
+                    related.entry(key).or_default().push(object);
+                }
             }
-            ParameterBinding::Named { name, variable } => {
-                let value = resolver.resolve(variable.as_str())?;
-                resolved.push((name.as_str().to_string(), value));
+        }
+
+        for row in rows.iter_mut() {
+            let matches = row
+                .get(expansion.key())
+                .and_then(key_of)
+                .and_then(|key| related.get(&key));
+            let value = match (expansion.many(), matches) {
+                (true, Some(matches)) => Value::Array(matches.clone()),
+                (true, None) => Value::Array(Vec::new()),
+                (false, Some(matches)) => matches[0].clone(),
+                (false, None) => Value::Null,
+            };
+            row.insert(expansion.name().to_string(), value);
+        }
+        if let Some((trace, span)) = trace.zip(span) {
+            trace.end(span, Some(related_rows));
+        }
+    }
+    Ok(())
+}
+
+/// The fields a response is cut down to with `?fields=`: each kept column
+/// or expansion, with the fields kept in an expansion's related rows (empty
+/// to keep them all)
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FieldSelection {
+    fields: Vec<(String, Vec<String>)>,
+}
+
+impl FieldSelection {
+    /// The kept fields, sorted so equal selections compare and hash alike
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .fields
+            .iter()
+            .flat_map(|(name, nested)| {
+                std::iter::once(name.clone()).chain(
+                    nested
+                        .iter()
+                        .map(move |field| format!("{}.{}", name, field)),
+                )
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Whether a row's column or expansion `name` is kept
+    pub fn keeps(&self, name: &str) -> bool {
+        self.fields.iter().any(|(kept, _)| kept == name)
+    }
+}
+
+/// The columns and relations `?fields=` may name under `sqlite_allow_fields`
+/// (pure function)
+///
+/// A relation that is allowed may also be narrowed to any of its fields.
+/// Without an allowlist everything is left as it is.
+pub fn allowed_fields<'a>(
+    allowed: &[String],
+    columns: Vec<String>,
+    expansions: Vec<(&'a Expansion, Vec<String>)>,
+) -> (Vec<String>, Vec<(&'a Expansion, Vec<String>)>) {
+    if allowed.is_empty() {
+        return (columns, expansions);
+    }
+    let is_allowed = |name: &str| allowed.iter().any(|a| a == name);
+    (
+        columns.into_iter().filter(|c| is_allowed(c)).collect(),
+        expansions
+            .into_iter()
+            .filter(|(expansion, _)| is_allowed(expansion.name()))
+            .collect(),
+    )
+}
+
+/// Parse `?fields=id,title,author.name` against the columns the query
+/// returns and the columns each `sqlite_expand` relation returns
+///
+/// A dotted field names a column of an expansion's related rows; naming an
+/// expansion, or one of its fields, expands it even without `?expand=`, so
+/// the expansions that must also run are returned. A field that is neither a
+/// column nor an expansion fails the request.
+pub fn select_fields<'a>(
+    requested: &str,
+    columns: &[String],
+    expansions: &[(&'a Expansion, Vec<String>)],
+) -> Result<(FieldSelection, Vec<&'a Expansion>), ParamError> {
+    // The message lists what may be asked for, not what was
+    let invalid = || {
+        let mut available: Vec<String> = columns.to_vec();
+        for (expansion, related) in expansions {
+            available.push(expansion.name().to_string());
+            available.extend(
+                related
+                    .iter()
+                    .map(|column| format!("{}.{}", expansion.name(), column)),
+            );
+        }
+        ParamError::Invalid {
+            name: "fields".to_string(),
+            message: format!("must name one of {}", available.join(", ")),
+        }
+    };
+
+    let mut selection = FieldSelection::default();
+    let mut expanded: Vec<&Expansion> = Vec::new();
+    let mut whole: Vec<&str> = Vec::new();
+    for field in requested
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+    {
+        let (name, nested) = match field.split_once('.') {
+            Some((name, nested)) => (name, Some(nested)),
+            None => (field, None),
+        };
+        let expansion = expansions.iter().find(|(e, _)| e.name() == name);
+        match (expansion, nested) {
+            (Some((expansion, related)), nested)
+                if nested.is_none_or(|nested| related.iter().any(|c| c == nested)) =>
+            {
+                if !expanded.iter().any(|e| e.name() == name) {
+                    expanded.push(expansion);
+                }
             }
-            ParameterBinding::NamedLiteral { name, value } => {
-                resolved.push((name.as_str().to_string(), value.clone()));
+            (None, None) if columns.iter().any(|c| c == name) => {}
+            _ => return Err(invalid()),
+        }
+
+        match selection.fields.iter_mut().find(|(kept, _)| kept == name) {
+            Some((_, kept)) => {
+                if let Some(nested) = nested
+                    && !kept.iter().any(|f| f == nested)
+                {
+                    kept.push(nested.to_string());
+                }
             }
+            None => selection.fields.push((
+                name.to_string(),
+                nested.map(str::to_string).into_iter().collect(),
+            )),
+        }
+        if nested.is_none() {
+            whole.push(name);
+        }
+    }
+    // A relation asked for on its own keeps all its fields
+    for (name, kept) in selection.fields.iter_mut() {
+        if whole.contains(&name.as_str()) {
+            kept.clear();
         }
     }
+    Ok((selection, expanded))
+}
 
-    Ok(resolved)
+/// Drop every column and expansion a [`FieldSelection`] does not keep from
+/// each row, and every unkept field from the related rows nested in it
+pub fn apply_fields(rows: &mut [HashMap<String, Value>], selection: &FieldSelection) {
+    for row in rows.iter_mut() {
+        row.retain(|name, _| selection.keeps(name));
+        for (name, nested) in &selection.fields {
+            if nested.is_empty() {
+                continue;
+            }
+            let keep = |object: &mut Value| {
+                if let Value::Object(object) = object {
+                    object.retain(|field, _| nested.contains(field));
+                }
+            };
+            match row.get_mut(name) {
+                Some(Value::Array(related)) => related.iter_mut().for_each(keep),
+                Some(related) => keep(related),
+                None => {}
+            }
+        }
+    }
 }
 
-/// Query execution strategy (dependency injection)
-pub trait QueryExecutor {
-    fn execute(
-        &self,
-        db_path: &DatabasePath,
-        query: &SqlQuery,
-        params: &[(String, String)],
-    ) -> Result<Vec<HashMap<String, Value>>, String>;
+/// Rows with the `pagination` object of their page
+pub type JsonRows = (Vec<HashMap<String, Value>>, Option<Value>);
+
+/// The rows of a location without a template, as its JSON response gives
+/// them: the requested page with its `pagination` object, if paginated,
+/// their `expansions` nested, JSON text expanded and only the `fields` kept
+pub fn json_rows(
+    executor: &dyn QueryExecutor,
+    config: &ValidatedConfig,
+    params: &[(String, Value)],
+    expansions: &[&Expansion],
+    fields: Option<&FieldSelection>,
+    page: &PagePosition,
+    trace: Option<&dyn Tracer>,
+) -> Result<JsonRows, DbError> {
+    let (query, count) = match &config.pagination {
+        Some(pagination) => {
+            let (query, count) =
+                page_queries(config, pagination, page).map_err(DbError::internal)?;
+            (query, Some((pagination, count)))
+        }
+        None => (config.query.clone(), None),
+    };
+    let mut results = traced(trace, "main query", None, || {
+        executor.execute(&config.db_path, &query, params)
+    })?;
+    let pagination = match &count {
+        Some((pagination, count)) => {
+            let total = match count {
+                Some(count) => {
+                    let rows = traced(trace, "count", None, || {
+                        executor.execute(&config.db_path, count, params)
+                    })?;
+                    Some(total_rows(&rows).map_err(DbError::internal)?)
+                }
+                None => None,
+            };
+            Some(page_context(page, pagination, total, &mut results).map_err(DbError::internal)?)
+        }
+        None => None,
+    };
+    expand_relations(&mut results, expansions, executor, &config.db_path, trace)?;
+    expand_json(&mut results, &config.expand_json);
+    if let Some(fields) = fields {
+        apply_fields(&mut results, fields);
+    }
+    if config.json_nulls == JsonNulls::Omit {
+        omit_nulls(&mut results);
+    }
+    Ok((results, pagination))
 }
 
-/// Template loading strategy (dependency injection)
-pub trait TemplateLoader {
-    fn load_from_dir(&mut self, dir_path: &str) -> Result<usize, String>;
-    fn register_template(&mut self, name: &str, path: &str) -> Result<(), String>;
+/// The JSON response of [`json_rows`]: the rows, or for a paginated location
+/// an object with the rows as `results` beside its `pagination`
+pub fn rows_document(results: Vec<HashMap<String, Value>>, pagination: Option<Value>) -> Value {
+    match pagination {
+        Some(pagination) => serde_json::json!({
+            "results": results,
+            "pagination": pagination,
+        }),
+        None => serde_json::json!(results),
+    }
 }
 
-/// Template rendering strategy (dependency injection)
-pub trait TemplateRenderer {
-    fn render(&self, template_name: &str, data: &Value) -> Result<String, String>;
+/// Build the data passed to the main template (pure function)
+///
+/// Results beyond `max_rows` are dropped so huge result sets cannot blow up
+/// render time; `meta.truncated` tells the template whether that happened.
+/// Statistics are computed over the full result set, before truncation.
+/// `meta.fallback` is set when the rows came from `sqlite_fallback_query`.
+pub fn build_template_context(
+    mut results: Vec<HashMap<String, Value>>,
+    max_rows: Option<RowLimit>,
+    aggregate: Option<&AggregateSpec>,
+    fallback: bool,
+) -> Value {
+    let stats = aggregate.map(|spec| compute_column_stats(&results, spec));
+    let total_rows = results.len();
+    let truncated = match max_rows {
+        Some(limit) if total_rows > limit.get() => {
+            results.truncate(limit.get());
+            true
+        }
+        _ => false,
+    };
+
+    let mut data = serde_json::json!({
+        "results": results,
+        "meta": {
+            "truncated": truncated,
+            "total_rows": total_rows,
+            "fallback": fallback,
+        },
+    });
+    if let Some(stats) = stats {
+        data["stats"] = stats;
+    }
+    data
 }
 
-/// Pure business logic for request handling
-pub struct RequestProcessor<Q, L: TemplateLoader + TemplateRenderer, Log: Logger> {
-    query_executor: Q,
-    template_loader: L,
-    logger: Log,
+/// The request's parameters as templates see them under `params` (pure
+/// function)
+///
+/// Named parameters are keyed by name without their `:`, `@` or `$`, and
+/// positional ones by their place among the positional ones, from 1; each
+/// keeps the value it was bound with, so a list parameter is an array.
+pub fn template_params(params: &[(String, Value)]) -> Value {
+    let mut positional = 0;
+    let mut object = serde_json::Map::new();
+    for (name, value) in params {
+        let key = if name.is_empty() {
+            positional += 1;
+            positional.to_string()
+        } else {
+            field_name(name)
+        };
+        object.insert(key, value.clone());
+    }
+    Value::Object(object)
 }
 
-impl<Q, L, Log> RequestProcessor<Q, L, Log>
-where
-    Q: QueryExecutor,
-    L: TemplateLoader + TemplateRenderer,
-    Log: Logger,
-{
-    pub fn new(query_executor: Q, template_loader: L, logger: Log) -> Self {
-        RequestProcessor {
-            query_executor,
-            template_loader,
-            logger,
+/// A feature's part of the main template's data
+///
+/// [`build_template_context`] gives the rows and their summary; each feature
+/// then adds its own keys through a provider, so features stay independent of
+/// one another and each can be tested on its own.
+pub trait ContextProvider {
+    /// Add this provider's keys to the template data
+    fn provide(&self, data: &mut serde_json::Map<String, Value>);
+}
+
+/// Add every provider's keys to the template data, in order
+pub fn provide_context(data: &mut Value, providers: &[&dyn ContextProvider]) {
+    if let Value::Object(data) = data {
+        for provider in providers {
+            provider.provide(data);
         }
     }
+}
 
-    /// Process a request (pure, testable business logic)
-    pub fn process(
-        &mut self,
-        config: &ValidatedConfig,
-        resolved_template: &ResolvedTemplate,
-        resolved_params: &[(String, String)],
-        global_template_dir: Option<&str>,
-    ) -> Result<String, String> {
-        self.logger.debug(
-            "processor",
-            &format!("Processing request for {}", config.uri),
-        );
+/// What the request asked for: its parameters, under `params`
+pub struct RequestInfo<'a> {
+    pub params: &'a [(String, Value)],
+}
 
-        // Execute query
-        self.logger.debug(
-            "query",
-            &format!("Executing query: {}", config.query.as_str()),
-        );
-        let results = self
-            .query_executor
-            .execute(&config.db_path, &config.query, resolved_params)
-            .map_err(|e| {
-                self.logger
-                    .error("query", &format!("Query execution failed: {}", e));
-                format!("query execution failed: {}", e)
-            })?;
+impl ContextProvider for RequestInfo<'_> {
+    fn provide(&self, data: &mut serde_json::Map<String, Value>) {
+        data.insert("params".to_string(), template_params(self.params));
+    }
+}
 
-        self.logger
-            .debug("query", &format!("Query returned {} rows", results.len()));
+/// The `sqlite_paginate` page shown, under `pagination`
+pub struct PageInfo(pub Option<Value>);
 
-        // Load global templates if provided
-        if let Some(dir) = global_template_dir {
-            self.logger.debug(
-                "templates",
-                &format!("Loading global templates from: {}", dir),
+impl ContextProvider for PageInfo {
+    fn provide(&self, data: &mut serde_json::Map<String, Value>) {
+        if let Some(pagination) = &self.0 {
+            data.insert("pagination".to_string(), pagination.clone());
+        }
+    }
+}
+
+/// Rows of the location's `sqlite_source` queries, each under its name
+pub struct SourceRows<'a>(pub Vec<(&'a str, Vec<HashMap<String, Value>>)>);
+
+impl ContextProvider for SourceRows<'_> {
+    fn provide(&self, data: &mut serde_json::Map<String, Value>) {
+        for (name, rows) in &self.0 {
+            data.insert(name.to_string(), serde_json::json!(rows));
+        }
+    }
+}
+
+/// Links to other pages: `sqlite_nav` menus under `nav`, the
+/// `sqlite_breadcrumbs` trail and the `sqlite_trending` pages, each only when
+/// the location has it
+#[derive(Default)]
+pub struct Navigation {
+    pub nav: Option<Value>,
+    pub breadcrumbs: Option<Value>,
+    pub trending: Option<Vec<HashMap<String, Value>>>,
+}
+
+impl ContextProvider for Navigation {
+    fn provide(&self, data: &mut serde_json::Map<String, Value>) {
+        if let Some(nav) = &self.nav {
+            data.insert("nav".to_string(), nav.clone());
+        }
+        if let Some(breadcrumbs) = &self.breadcrumbs {
+            data.insert("breadcrumbs".to_string(), breadcrumbs.clone());
+        }
+        if let Some(trending) = &self.trending {
+            data.insert("trending".to_string(), serde_json::json!(trending));
+        }
+    }
+}
+
+/// Counts and timings: the `sqlite_hit_counter` total under `hits`, and with
+/// `sqlite_timing` the query time under `timing`
+#[derive(Default)]
+pub struct Measurements {
+    pub hits: Option<u64>,
+    pub query_time: Option<Duration>,
+}
+
+impl ContextProvider for Measurements {
+    fn provide(&self, data: &mut serde_json::Map<String, Value>) {
+        if let Some(hits) = self.hits {
+            data.insert("hits".to_string(), serde_json::json!(hits));
+        }
+        if let Some(query_time) = self.query_time {
+            data.insert(
+                "timing".to_string(),
+                serde_json::json!({ "query_ms": millis(query_time) }),
             );
-            match self.template_loader.load_from_dir(dir) {
-                Ok(count) => {
-                    self.logger.info(
-                        "templates",
-                        &format!("Loaded {} global template(s) from '{}'", count, dir),
-                    );
-                }
-                Err(e) => {
-                    self.logger.warn(
-                        "templates",
-                        &format!("Failed to load global templates from '{}': {}", dir, e),
-                    );
-                }
-            }
         }
+    }
+}
 
-        // Load local templates
-        self.logger.debug(
-            "templates",
-            &format!(
-                "Loading local templates from: {}",
-                resolved_template.directory()
-            ),
-        );
-        match self
-            .template_loader
-            .load_from_dir(resolved_template.directory())
-        {
-            Ok(count) => {
-                self.logger
-                    .debug("templates", &format!("Loaded {} local template(s)", count));
+/// Compute summary statistics per column, e.g. `{"price": {"max": 12.5}}`
+///
+/// Only numeric values take part; NULLs and text are skipped. A column with no
+/// numeric values yields `null` for every statistic, like SQL's aggregates.
+pub fn compute_column_stats(results: &[HashMap<String, Value>], spec: &AggregateSpec) -> Value {
+    let mut stats = serde_json::Map::new();
+
+    for column in spec.columns() {
+        let numbers: Vec<&serde_json::Number> = results
+            .iter()
+            .filter_map(|row| match row.get(column) {
+                Some(Value::Number(n)) => Some(n),
+                _ => None,
+            })
+            .collect();
+
+        let mut column_stats = serde_json::Map::new();
+        for function in spec.functions() {
+            let value = if numbers.is_empty() {
+                Value::Null
+            } else {
+                aggregate_numbers(*function, &numbers)
+            };
+            column_stats.insert(function.as_str().to_string(), value);
+        }
+        stats.insert(column.clone(), Value::Object(column_stats));
+    }
+
+    Value::Object(stats)
+}
+
+/// Apply one aggregate to a non-empty list of numbers
+///
+/// Integer columns keep integer min/max/sum; anything else falls back to f64.
+fn aggregate_numbers(function: AggregateFn, numbers: &[&serde_json::Number]) -> Value {
+    let floats = || numbers.iter().filter_map(|n| n.as_f64());
+    let integers: Option<Vec<i64>> = numbers.iter().map(|n| n.as_i64()).collect();
+
+    match (function, integers) {
+        (AggregateFn::Min, Some(ints)) => Value::from(ints.into_iter().min()),
+        (AggregateFn::Max, Some(ints)) => Value::from(ints.into_iter().max()),
+        (AggregateFn::Sum, Some(ints)) => {
+            match ints.iter().try_fold(0i64, |a, &b| a.checked_add(b)) {
+                Some(sum) => Value::from(sum),
+                None => Value::from(floats().sum::<f64>()),
             }
-            Err(e) => {
-                self.logger.warn(
-                    "templates",
+        }
+        (AggregateFn::Min, None) => Value::from(floats().fold(f64::INFINITY, f64::min)),
+        (AggregateFn::Max, None) => Value::from(floats().fold(f64::NEG_INFINITY, f64::max)),
+        (AggregateFn::Sum, None) => Value::from(floats().sum::<f64>()),
+        (AggregateFn::Avg, _) => Value::from(floats().sum::<f64>() / numbers.len() as f64),
+    }
+}
+
+/// Parameter resolution strategy (dependency injection)
+pub trait VariableResolver {
+    fn resolve(&mut self, var_name: &str) -> Result<String, String>;
+
+    /// A configured variable, by the index nginx gave it when it has one
+    fn resolve_variable(&mut self, variable: &NginxVariable) -> Result<String, String> {
+        self.resolve(variable.as_str())
+    }
+
+    /// Every value of a variable that may repeat, such as a query argument
+    /// given more than once
+    fn resolve_values(&mut self, var_name: &str) -> Result<Vec<String>, String> {
+        self.resolve(var_name).map(|value| vec![value])
+    }
+
+    /// The names of the request's query-string arguments, lowercased
+    fn arg_names(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl<R: VariableResolver + ?Sized> VariableResolver for Box<R> {
+    fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+        (**self).resolve(var_name)
+    }
+
+    fn resolve_variable(&mut self, variable: &NginxVariable) -> Result<String, String> {
+        (**self).resolve_variable(variable)
+    }
+
+    fn resolve_values(&mut self, var_name: &str) -> Result<Vec<String>, String> {
+        (**self).resolve_values(var_name)
+    }
+
+    fn arg_names(&mut self) -> Vec<String> {
+        (**self).arg_names()
+    }
+}
+
+/// Resolves `$json_body.` variables from the request body parsed as JSON,
+/// `$post_` variables from it decoded as a form, and every other variable
+/// with `inner`
+///
+/// A body that is not JSON, or lacks the field, leaves a `$json_body.`
+/// variable missing; `$post_` variables are only read from bodies sent as
+/// `application/x-www-form-urlencoded`.
+pub struct BodyResolver<'a> {
+    inner: &'a mut dyn VariableResolver,
+    body: Result<Value, String>,
+    form: Option<Vec<(String, String)>>,
+}
+
+impl<'a> BodyResolver<'a> {
+    pub fn new(inner: &'a mut dyn VariableResolver, body: &[u8]) -> Self {
+        let form = inner
+            .resolve("$content_type")
+            .ok()
+            .filter(|content_type| {
+                content_type.split(';').next().is_some_and(|media| {
+                    media
+                        .trim()
+                        .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+                })
+            })
+            .map(|_| form_fields(body));
+        let body =
+            serde_json::from_slice(body).map_err(|e| format!("request body is not JSON: {}", e));
+        BodyResolver { inner, body, form }
+    }
+
+    fn field(&self, var_name: &str, path: &str) -> Result<&Value, String> {
+        let body = self.body.as_ref().map_err(Clone::clone)?;
+        json_field(body, path)
+            .filter(|value| !value.is_null())
+            .ok_or_else(|| format!("{} is not in the request body", var_name))
+    }
+
+    /// Every value of a form field, matching names case-insensitively as
+    /// nginx matches query arguments
+    fn form_values(&self, var_name: &str, name: &str) -> Result<Vec<String>, String> {
+        let form = self
+            .form
+            .as_ref()
+            .ok_or_else(|| "request body is not a form".to_string())?;
+        let values: Vec<String> = form
+            .iter()
+            .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+            .collect();
+        if values.is_empty() {
+            return Err(format!("{} is not in the request body", var_name));
+        }
+        Ok(values)
+    }
+}
+
+impl VariableResolver for BodyResolver<'_> {
+    fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+        if let Some(path) = var_name.strip_prefix("$json_body.") {
+            self.field(var_name, path).map(json_text)
+        } else if let Some(name) = var_name.strip_prefix("$post_") {
+            let mut values = self.form_values(var_name, name)?;
+            Ok(values.swap_remove(0))
+        } else {
+            self.inner.resolve(var_name)
+        }
+    }
+
+    fn resolve_variable(&mut self, variable: &NginxVariable) -> Result<String, String> {
+        if variable.reads_body() {
+            self.resolve(variable.as_str())
+        } else {
+            self.inner.resolve_variable(variable)
+        }
+    }
+
+    /// An array field gives one value per item, and a repeated form field
+    /// each of its values, so either can bind a list
+    fn resolve_values(&mut self, var_name: &str) -> Result<Vec<String>, String> {
+        if let Some(path) = var_name.strip_prefix("$json_body.") {
+            match self.field(var_name, path)? {
+                Value::Array(items) => Ok(items
+                    .iter()
+                    .filter(|item| !item.is_null())
+                    .map(json_text)
+                    .collect()),
+                value => Ok(vec![json_text(value)]),
+            }
+        } else if let Some(name) = var_name.strip_prefix("$post_") {
+            self.form_values(var_name, name)
+        } else {
+            self.inner.resolve_values(var_name)
+        }
+    }
+
+    fn arg_names(&mut self) -> Vec<String> {
+        self.inner.arg_names()
+    }
+}
+
+/// The fields of an `application/x-www-form-urlencoded` body, decoded: `+`
+/// is a space and `%XX` a byte, and invalid UTF-8 is replaced
+pub fn form_fields(body: &[u8]) -> Vec<(String, String)> {
+    body.split(|&b| b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, |&b| b == b'=');
+            let name = parts.next().unwrap_or_default();
+            let value = parts.next().unwrap_or_default();
+            (form_decode(name), form_decode(value))
+        })
+        .collect()
+}
+
+/// Decode a query string or form value: `+` is a space and `%XX` a byte,
+/// and invalid UTF-8 is replaced; a `%` not followed by two hex digits is
+/// kept as it is
+pub fn form_decode(text: &[u8]) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let escaped = text
+            .get(i + 1..i + 3)
+            .filter(|_| text[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (text[i], escaped) {
+            (_, Some(byte)) => {
+                bytes.push(byte);
+                i += 2;
+            }
+            (b'+', None) => bytes.push(b' '),
+            (other, None) => bytes.push(other),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The value at a dotted path in a JSON document; a numeric segment indexes
+/// an array (`items.0.sku`)
+pub fn json_field<'v>(value: &'v Value, path: &str) -> Option<&'v Value> {
+    path.split('.')
+        .try_fold(value, |value, segment| match value {
+            Value::Object(fields) => fields.get(segment),
+            Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => None,
+        })
+}
+
+/// The value at a path in SQLite's JSON path syntax, as `json_extract` reads
+/// it: `$` for the document, then `.key` or `."quoted key"` for a member and
+/// `[n]` or `[#-n]` (from the end) for an array element
+///
+/// `Ok(None)` when the document has nothing at the path; a malformed path
+/// is an error.
+pub fn json_path<'v>(value: &'v Value, path: &str) -> Result<Option<&'v Value>, String> {
+    let bad_path = || format!("bad JSON path: {}", path);
+    let mut rest = path.strip_prefix('$').ok_or_else(bad_path)?;
+    let mut value = Some(value);
+    while !rest.is_empty() {
+        if let Some(member) = rest.strip_prefix('.') {
+            let (key, after) = match member.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"').ok_or_else(bad_path)?;
+                    (&quoted[..end], &quoted[end + 1..])
+                }
+                None => {
+                    let end = member.find(['.', '[']).unwrap_or(member.len());
+                    (&member[..end], &member[end..])
+                }
+            };
+            if key.is_empty() && !member.starts_with('"') {
+                return Err(bad_path());
+            }
+            value = value.and_then(|value| value.get(key));
+            rest = after;
+        } else if let Some(element) = rest.strip_prefix('[') {
+            let end = element.find(']').ok_or_else(bad_path)?;
+            let index = &element[..end];
+            let items = value.and_then(Value::as_array);
+            let position = match index.strip_prefix('#') {
+                Some("") => items.map(Vec::len),
+                Some(back) => {
+                    let back: usize = back
+                        .strip_prefix('-')
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(bad_path)?;
+                    items.and_then(|items| items.len().checked_sub(back))
+                }
+                None => Some(index.parse().map_err(|_| bad_path())?),
+            };
+            value = items
+                .zip(position)
+                .and_then(|(items, position)| items.get(position));
+            rest = &element[end + 1..];
+        } else {
+            return Err(bad_path());
+        }
+    }
+    Ok(value)
+}
+
+/// A JSON value as a parameter's text: strings as they are, anything else
+/// as JSON
+fn json_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Why a request's parameters could not be bound; answered with 400
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamError {
+    /// `sqlite_param_required` parameters whose variables are missing or empty
+    Missing(Vec<String>),
+    /// A value that does not convert to its parameter's type
+    Invalid { name: String, message: String },
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Missing(names) => {
+                write!(f, "missing required parameter(s) {}", names.join(", "))
+            }
+            ParamError::Invalid { name, message } => write!(f, "parameter {}: {}", name, message),
+        }
+    }
+}
+
+impl ParamError {
+    /// The error code answering it: `missing_parameter` or `invalid_parameter`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParamError::Missing(_) => "missing_parameter",
+            ParamError::Invalid { .. } => "invalid_parameter",
+        }
+    }
+}
+
+/// JSON body answering a [`ParamError`] (pure function)
+///
+/// Like a constraint violation, each offending parameter is listed under
+/// `errors` by name without its `:`, so a form can show the message beside
+/// the field.
+pub fn param_error_body(error: &ParamError) -> Value {
+    let (message, errors) = match error {
+        ParamError::Missing(names) => (
+            "Missing required parameters",
+            names
+                .iter()
+                .map(|name| (field_name(name), Value::from("is required")))
+                .collect(),
+        ),
+        ParamError::Invalid { name, message } => (
+            "Invalid parameter",
+            serde_json::Map::from_iter([(field_name(name), Value::from(message.as_str()))]),
+        ),
+    };
+    serde_json::json!({
+        "error": message,
+        "code": error.code(),
+        "details": error.to_string(),
+        "errors": errors,
+    })
+}
+
+fn field_name(param: &str) -> String {
+    param.trim_start_matches([':', '@', '$']).to_string()
+}
+
+/// Resolve all parameters using the provided resolver
+///
+/// Values are text unless their binding is typed, in which case they are
+/// converted (see [`typed_value`]); a value that is longer than its binding's
+/// maximum length, does not match its pattern or does not convert fails
+/// resolution. A variable that cannot be resolved binds NULL (an empty list
+/// for `:name[]`), unless its binding has a default, which is also used for
+/// an empty value, or is required, in which case a missing or empty value
+/// fails resolution. Every missing required parameter is reported at once.
+///
+/// With `sqlite_bind_args`, every query-string argument that no other binding
+/// already names is bound last as `:arg_<name>`, raw like `$arg_name`.
+pub fn resolve_parameters(
+    bindings: &[ParameterBinding],
+    resolver: &mut dyn VariableResolver,
+) -> Result<ParameterSet, ParamError> {
+    let mut resolved = Vec::new();
+    let mut missing = Vec::new();
+
+    for binding in bindings {
+        match binding {
+            ParameterBinding::Positional {
+                variable,
+                max_length,
+            } => {
+                let value = resolver.resolve_variable(variable).ok();
+                if let Some(value) = &value {
+                    within(variable.as_str(), *max_length, value)?;
+                }
+                resolved.push((String::new(), value.map_or(Value::Null, Value::String)));
+            }
+            ParameterBinding::PositionalLiteral { value } => {
+                resolved.push((String::new(), Value::String(value.clone())));
+            }
+            ParameterBinding::PositionalDefault {
+                variable,
+                default,
+                max_length,
+            } => {
+                let value = resolver.resolve_variable(variable).ok();
+                if let Some(value) = &value {
+                    within(variable.as_str(), *max_length, value)?;
+                }
+                let value = value
+                    .filter(|value| !value.is_empty())
+                    .unwrap_or_else(|| default.clone());
+                resolved.push((String::new(), Value::String(value)));
+            }
+            ParameterBinding::Named {
+                name,
+                variable,
+                param_type,
+                default,
+                required,
+                pattern,
+                max_length,
+            } => {
+                // An empty value only counts as missing with a fallback
+                let falls_back = *required || default.is_some();
+                let value = resolver.resolve_variable(variable).ok();
+                if let Some(value) = &value {
+                    within(name.as_str(), *max_length, value)?;
+                }
+                let value = value
+                    .filter(|value| !(falls_back && value.is_empty()))
+                    .or_else(|| default.clone());
+                let value = match value {
+                    Some(value) => {
+                        matching(name, pattern.as_ref(), &value)?;
+                        typed(name, *param_type, &value)?
+                    }
+                    None if *required => {
+                        missing.push(name.as_str().to_string());
+                        continue;
+                    }
+                    None => Value::Null,
+                };
+                resolved.push((name.as_str().to_string(), value));
+            }
+            ParameterBinding::NamedLiteral {
+                name,
+                value,
+                param_type,
+            } => {
+                resolved.push((name.as_str().to_string(), typed(name, *param_type, value)?));
+            }
+            ParameterBinding::OptionalNamed {
+                name,
+                variable,
+                max_length,
+            } => {
+                let value = resolver.resolve_variable(variable).unwrap_or_default();
+                within(name.as_str(), *max_length, &value)?;
+                resolved.push((name.as_str().to_string(), Value::String(value)));
+            }
+            ParameterBinding::NamedList {
+                name,
+                variable,
+                param_type,
+                required,
+                pattern,
+                max_length,
+            } => {
+                // One entry per value, whether repeated or comma-separated;
+                // the executor expands the placeholder
+                let values = resolver
+                    .resolve_values(variable.as_str())
+                    .unwrap_or_default();
+                for value in &values {
+                    within(name.as_str(), *max_length, value)?;
+                }
+                let before = resolved.len();
+                for item in values
+                    .iter()
+                    .flat_map(|value| value.split(','))
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                {
+                    matching(name, pattern.as_ref(), item)?;
+                    resolved.push((name.as_str().to_string(), typed(name, *param_type, item)?));
+                }
+                if *required && resolved.len() == before {
+                    missing.push(name.as_str().to_string());
+                }
+            }
+            ParameterBinding::QueryArgs { max_length } => {
+                // Explicit bindings come first and win
+                for arg in resolver.arg_names() {
+                    let name = format!(":arg_{}", arg);
+                    if resolved.iter().any(|(bound, _)| *bound == name) {
+                        continue;
+                    }
+                    let value = resolver
+                        .resolve(&format!("$arg_{}", arg))
+                        .unwrap_or_default();
+                    within(&name, *max_length, &value)?;
+                    resolved.push((name, Value::String(value)));
+                }
+            }
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(ParamError::Missing(missing));
+    }
+    Ok(resolved)
+}
+
+/// Bind a page's `sqlite_hit_counter` total as `:hits`
+///
+/// Locations with positional parameters are left alone, since positional and
+/// named parameters cannot be mixed, as are those binding their own `:hits`.
+pub fn bind_hits(params: &mut ParameterSet, hits: u64) {
+    if params
+        .iter()
+        .all(|(name, _)| !name.is_empty() && name != ":hits")
+    {
+        params.push((":hits".to_string(), Value::from(hits)));
+    }
+}
+
+/// The `sqlite_memo_table` key of a JSON response: the hex SHA-256 of its
+/// query, page, bound parameters, requested expansions and `?fields=`
+///
+/// The key depends only on what the response is computed from, so hosts
+/// sharing the database file find each other's responses.
+pub fn memo_key(
+    query: &SqlQuery,
+    page: &PagePosition,
+    params: &[(String, Value)],
+    expansions: &[&Expansion],
+    fields: Option<&FieldSelection>,
+) -> String {
+    let expanded: Vec<&str> = expansions.iter().map(|e| e.name()).collect();
+    let fields = fields.map(FieldSelection::names);
+    let input = serde_json::json!([query.as_str(), page, params, expanded, fields]).to_string();
+    Sha256::digest(input.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Check a value against its parameter's maximum length, if it has one,
+/// before anything else looks at it
+fn within(name: &str, max_length: Option<MaxLength>, value: &str) -> Result<(), ParamError> {
+    match max_length {
+        // Counting stops at the limit, so a huge value costs no more than that
+        Some(limit) if value.chars().nth(limit.get()).is_some() => Err(ParamError::Invalid {
+            name: name.to_string(),
+            message: format!("is longer than {} characters", limit.get()),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Check a value against its parameter's `pattern=`, if it has one
+///
+/// The message leaves the value out, so junk input stays out of the logs.
+fn matching(
+    name: &ParamName,
+    pattern: Option<&ParamPattern>,
+    value: &str,
+) -> Result<(), ParamError> {
+    match pattern {
+        Some(pattern) if !pattern.is_match(value) => Err(ParamError::Invalid {
+            name: name.as_str().to_string(),
+            message: format!("does not match pattern {}", pattern.as_str()),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn typed(name: &ParamName, param_type: ParamType, value: &str) -> Result<Value, ParamError> {
+    typed_value(param_type, value).map_err(|message| ParamError::Invalid {
+        name: name.as_str().to_string(),
+        message,
+    })
+}
+
+/// Convert a resolved value to its `sqlite_param` type (pure function)
+///
+/// Integers and floats are parsed after trimming whitespace; booleans accept
+/// `true`/`false`, `1`/`0`, `on`/`off` and `yes`/`no` and bind as 1 and 0. An
+/// empty value of any type but text binds NULL, as an empty form field means
+/// no value.
+pub fn typed_value(param_type: ParamType, value: &str) -> Result<Value, String> {
+    let trimmed = value.trim();
+    if param_type != ParamType::Text && trimmed.is_empty() {
+        return Ok(Value::Null);
+    }
+    let invalid = || format!("expected {}, got '{}'", param_type.as_str(), value);
+
+    match param_type {
+        ParamType::Text => Ok(Value::String(value.to_string())),
+        ParamType::Integer => trimmed
+            .parse::<i64>()
+            .map(Value::from)
+            .map_err(|_| invalid()),
+        ParamType::Real => trimmed
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(invalid),
+        ParamType::Bool => match trimmed.to_ascii_lowercase().as_str() {
+            "true" | "1" | "on" | "yes" => Ok(Value::Bool(true)),
+            "false" | "0" | "off" | "no" => Ok(Value::Bool(false)),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+/// Pick the query for this request: the first `sqlite_query_if` rule whose
+/// variable resolves to a non-empty value, otherwise the location's query
+///
+/// A variable that cannot be resolved counts as absent.
+pub fn select_query<'a>(
+    config: &'a ValidatedConfig,
+    resolver: &mut dyn VariableResolver,
+) -> &'a SqlQuery {
+    config
+        .query_rules
+        .iter()
+        .find(|rule| {
+            resolver
+                .resolve(rule.variable().as_str())
+                .is_ok_and(|value| !value.is_empty())
+        })
+        .map(|rule| rule.query())
+        .unwrap_or(&config.query)
+}
+
+/// The query sorted as the request asks with `sqlite_sort_param`, or `None`
+/// when it does not ask (pure function)
+///
+/// The query is wrapped so its own `ORDER BY`, `LIMIT` and the like are left
+/// alone; a sort naming a column not allowed is refused. A variable that
+/// cannot be resolved counts as empty.
+pub fn sorted_query(
+    query: &SqlQuery,
+    sort: &SortParam,
+    resolver: &mut dyn VariableResolver,
+) -> Result<Option<SqlQuery>, ParamError> {
+    let variable = sort.variable().as_str();
+    let value = resolver.resolve(variable).unwrap_or_default();
+    let invalid = |message: String| ParamError::Invalid {
+        name: variable.to_string(),
+        message,
+    };
+    let Some(order) = sort.order_by(&value).map_err(invalid)? else {
+        return Ok(None);
+    };
+    // The newline ends any trailing `--` comment before the parenthesis
+    SqlQuery::parse(format!(
+        "SELECT * FROM ({}\n) ORDER BY {}",
+        query.as_str().trim().trim_end_matches(';'),
+        order
+    ))
+    .map(Some)
+    .map_err(invalid)
+}
+
+/// What a `sqlite_dry_run` request is answered with: the statements it would
+/// run and every value they would bind, after defaults, checks and type
+/// conversion, each with the variable it came from (pure function)
+///
+/// Positional values are named `?1`, `?2`, ... in order. A value the dry run
+/// redacts is replaced with `"[redacted]"`, keeping its type.
+pub fn dry_run_report(
+    query: &SqlQuery,
+    writes: &[&WriteQuery],
+    params: &ParameterSet,
+    config: &ValidatedConfig,
+    dry_run: &DryRun,
+) -> Value {
+    let positional: Vec<&ParameterBinding> = config
+        .parameters
+        .iter()
+        .filter(|binding| {
+            matches!(
+                binding,
+                ParameterBinding::Positional { .. }
+                    | ParameterBinding::PositionalLiteral { .. }
+                    | ParameterBinding::PositionalDefault { .. }
+            )
+        })
+        .collect();
+    let mut position = 0;
+    let params: Vec<Value> = params
+        .iter()
+        .map(|(name, value)| {
+            let (name, source) = if name.is_empty() {
+                position += 1;
+                let source = positional
+                    .get(position - 1)
+                    .and_then(|binding| binding.variable())
+                    .map(NginxVariable::as_str);
+                (format!("?{}", position), source.map(str::to_string))
+            } else {
+                (name.clone(), param_source(name, config))
+            };
+            let value_type = match value {
+                Value::Null => "null",
+                Value::Bool(_) => "bool",
+                Value::Number(n) if n.is_f64() => "float",
+                Value::Number(_) => "int",
+                _ => "text",
+            };
+            let value = if dry_run.redacts(&name) {
+                Value::from("[redacted]")
+            } else {
+                value.clone()
+            };
+            serde_json::json!({
+                "name": name,
+                "value": value,
+                "type": value_type,
+                "source": source,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "dry_run": true,
+        "query": query.as_str(),
+        "writes": writes.iter().map(|write| write.as_str()).collect::<Vec<_>>(),
+        "params": params,
+    })
+}
+
+/// The variable a named value came from: its binding's, its filter's, or
+/// `$arg_<name>` for `sqlite_bind_args`; `None` for a literal
+fn param_source(name: &str, config: &ValidatedConfig) -> Option<String> {
+    if let Some(binding) = config
+        .parameters
+        .iter()
+        .find(|binding| binding.name() == Some(name))
+    {
+        return binding.variable().map(|v| v.as_str().to_string());
+    }
+    if let Some(filter) = name
+        .strip_prefix(":filter_")
+        .and_then(|n| n.parse::<usize>().ok())
+        .and_then(|n| config.filters.get(n.checked_sub(1)?))
+    {
+        return Some(filter.variable().as_str().to_string());
+    }
+    name.strip_prefix(":arg_")
+        .map(|arg| format!("$arg_{}", arg))
+}
+
+/// The query narrowed by every `sqlite_filter` whose variable has a value,
+/// with the values to bind, or `None` when none has (pure function)
+///
+/// Like [`sorted_query`], the query is wrapped, so the conditions compare its
+/// result columns. Each value is converted to its filter's type and bound as
+/// `:filter_<n>`, numbered by the filter's place in the configuration; a
+/// value that does not convert is refused. A variable that cannot be
+/// resolved counts as empty.
+pub fn filtered_query(
+    query: &SqlQuery,
+    filters: &[Filter],
+    resolver: &mut dyn VariableResolver,
+) -> Result<Option<(SqlQuery, ParameterSet)>, ParamError> {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut values = ParameterSet::new();
+    for (i, filter) in filters.iter().enumerate() {
+        let variable = filter.variable().as_str();
+        let value = resolver.resolve(variable).unwrap_or_default();
+        if value.is_empty() {
+            continue;
+        }
+        let value =
+            typed_value(filter.param_type(), &value).map_err(|message| ParamError::Invalid {
+                name: variable.to_string(),
+                message,
+            })?;
+        let name = format!(":filter_{}", i + 1);
+        conditions.push(format!(
+            "\"{}\" {} {}",
+            filter.column(),
+            filter.operator().as_sql(),
+            name
+        ));
+        values.push((name, value));
+    }
+    if conditions.is_empty() {
+        return Ok(None);
+    }
+    // The newline ends any trailing `--` comment before the parenthesis
+    let filtered = SqlQuery::parse(format!(
+        "SELECT * FROM ({}\n) WHERE {}",
+        query.as_str().trim().trim_end_matches(';'),
+        conditions.join(" AND ")
+    ))
+    .map_err(|message| ParamError::Invalid {
+        name: filters[0].variable().as_str().to_string(),
+        message,
+    })?;
+    Ok(Some((filtered, values)))
+}
+
+/// The `sqlite_theme` a request selects: its variable's value when that is a
+/// theme name, otherwise the default theme
+pub fn active_theme(theme: &Theme, resolver: &mut dyn VariableResolver) -> String {
+    resolver
+        .resolve(theme.variable().as_str())
+        .ok()
+        .filter(|name| Theme::is_name(name))
+        .unwrap_or_else(|| theme.default().to_string())
+}
+
+/// The alternate template a request selects with `sqlite_template_variant`
+///
+/// The variable's value names an allowlisted variant; with a secret it must
+/// be `name.signature`, where the signature is the hex HMAC-SHA256 of the
+/// name keyed with the secret. Anything else, such as an unknown name or a bad
+/// signature, keeps the main template.
+pub fn template_variant<'a>(
+    variants: &'a TemplateVariants,
+    resolver: &mut dyn VariableResolver,
+) -> Option<&'a TemplatePath> {
+    let value = resolver.resolve(variants.variable().as_str()).ok()?;
+    let name = match variants.secret() {
+        Some(secret) => {
+            let (name, signature) = value.rsplit_once('.')?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+            mac.update(name.as_bytes());
+            mac.verify_slice(&decode_hex(signature)?).ok()?;
+            name
+        }
+        None => value.as_str(),
+    };
+    variants.template(name)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The page a `sqlite_paginate` request asks for; a missing or invalid page
+/// number means the first page
+pub fn requested_page(pagination: &Pagination, resolver: &mut dyn VariableResolver) -> u64 {
+    match pagination.mode() {
+        PageMode::Offset(variable) => resolver
+            .resolve(variable.as_str())
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .filter(|&page| page > 0)
+            .unwrap_or(1),
+        PageMode::Cursor(_) => 1,
+    }
+}
+
+/// Which page of a `sqlite_paginate` location a request wants
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PagePosition {
+    /// A numbered page; page 1 is also where `mode=cursor` pages start
+    Number(u64),
+    /// The rows after this key (`mode=cursor`, `?after=`)
+    After(Value),
+    /// The rows before this key (`mode=cursor`, `?before=`)
+    Before(Value),
+}
+
+impl PagePosition {
+    /// The page number, counting every cursor page as page 1
+    pub fn number(&self) -> u64 {
+        match self {
+            PagePosition::Number(page) => *page,
+            PagePosition::After(_) | PagePosition::Before(_) => 1,
+        }
+    }
+}
+
+/// The page a request asks for: its page number, or with `mode=cursor` the
+/// key in its `?after=` (or else `?before=`) cursor
+///
+/// A cursor that was not signed with the location's secret fails the
+/// request, so clients cannot page from keys of their choosing.
+pub fn requested_position(
+    pagination: &Pagination,
+    resolver: &mut dyn VariableResolver,
+) -> Result<PagePosition, ParamError> {
+    let PageMode::Cursor(key) = pagination.mode() else {
+        return Ok(PagePosition::Number(requested_page(pagination, resolver)));
+    };
+    for (name, position) in [
+        ("after", PagePosition::After as fn(Value) -> PagePosition),
+        ("before", PagePosition::Before),
+    ] {
+        let cursor = resolver
+            .resolve(&format!("$arg_{}", name))
+            .unwrap_or_default();
+        if cursor.is_empty() {
+            continue;
+        }
+        return decode_cursor(key.secret(), &cursor)
+            .map(position)
+            .ok_or_else(|| ParamError::Invalid {
+                name: name.to_string(),
+                message: "is not a valid cursor".to_string(),
+            });
+    }
+    Ok(PagePosition::Number(1))
+}
+
+/// An opaque cursor for a key: the hex of the key as JSON, a dot, and the
+/// hex HMAC-SHA256 of that JSON keyed with the `sqlite_paginate` secret
+pub fn encode_cursor(secret: &str, key: &Value) -> String {
+    let payload = key.to_string();
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    format!(
+        "{}.{}",
+        encode_hex(payload.as_bytes()),
+        encode_hex(&mac.finalize().into_bytes())
+    )
+}
+
+/// The key in a cursor from [`encode_cursor`]; `None` unless it was signed
+/// with `secret` and holds a number or string
+fn decode_cursor(secret: &str, cursor: &str) -> Option<Value> {
+    let (payload, signature) = cursor.split_once('.')?;
+    let payload = decode_hex(payload)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(&payload);
+    mac.verify_slice(&decode_hex(signature)?).ok()?;
+    serde_json::from_slice(&payload)
+        .ok()
+        .filter(|key| matches!(key, Value::Number(_) | Value::String(_)))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The queries for one page of a `sqlite_paginate` location: the location's
+/// query limited to `page`, and the query counting the rows of every page
+/// (`sqlite_count_query` or the `sqlite_count estimate` query, or else a
+/// `COUNT(*)` over the location's query)
+///
+/// Both bind the same parameters as the location's query. A `mode=cursor`
+/// page is not counted; its query compares the key with the cursor's and
+/// asks for one row more than a page, so [`cursor_context`] can tell whether
+/// another page follows. With `sqlite_count none`, a numbered page is not
+/// counted either and asks for the extra row the same way.
+pub fn page_queries(
+    config: &ValidatedConfig,
+    pagination: &Pagination,
+    page: &PagePosition,
+) -> Result<(SqlQuery, Option<SqlQuery>), String> {
+    // The newline ends any trailing `--` comment before the parenthesis
+    let query = config.query.as_str().trim().trim_end_matches(';');
+    let per_page = pagination.per_page().get() as u64;
+    if let PageMode::Cursor(key) = pagination.mode() {
+        return Ok((cursor_query(query, key, per_page, page)?, None));
+    }
+
+    let offset = page
+        .number()
+        .saturating_sub(1)
+        .saturating_mul(per_page)
+        .min(i64::MAX as u64);
+    if pagination.count() == &CountStrategy::None {
+        let paged = SqlQuery::parse(format!(
+            "SELECT * FROM ({}\n) LIMIT {} OFFSET {}",
+            query,
+            per_page.saturating_add(1),
+            offset
+        ))?;
+        return Ok((paged, None));
+    }
+    let paged = SqlQuery::parse(format!(
+        "SELECT * FROM ({}\n) LIMIT {} OFFSET {}",
+        query, per_page, offset
+    ))?;
+    let count = match &config.count_query {
+        Some(count) => count.clone(),
+        None => SqlQuery::parse(format!("SELECT COUNT(*) FROM ({}\n)", query))?,
+    };
+    Ok((paged, Some(count)))
+}
+
+/// A `mode=cursor` page's query: the rows past the cursor's key in the key's
+/// order, or for `?before=` the rows ahead of it in reverse order
+///
+/// The key is written into the query as a literal rather than bound, so the
+/// location's own parameters are untouched; it was signed by this module
+/// and is quoted.
+fn cursor_query(
+    query: &str,
+    key: &CursorKey,
+    per_page: u64,
+    page: &PagePosition,
+) -> Result<SqlQuery, String> {
+    let (forward, backward) = if key.descending() {
+        (("<", "DESC"), (">", "ASC"))
+    } else {
+        ((">", "ASC"), ("<", "DESC"))
+    };
+    let (filter, order) = match page {
+        PagePosition::Number(_) => (String::new(), forward.1),
+        PagePosition::After(value) => (cursor_filter(key, forward.0, value)?, forward.1),
+        PagePosition::Before(value) => (cursor_filter(key, backward.0, value)?, backward.1),
+    };
+    SqlQuery::parse(format!(
+        "SELECT * FROM ({}\n){} ORDER BY \"{}\" {} LIMIT {}",
+        query,
+        filter,
+        key.column(),
+        order,
+        per_page.saturating_add(1)
+    ))
+}
+
+fn cursor_filter(key: &CursorKey, operator: &str, value: &Value) -> Result<String, String> {
+    let literal = match value {
+        Value::Number(number) => number.to_string(),
+        Value::String(text) => format!("'{}'", text.replace('\'', "''")),
+        _ => return Err("cursor keys are numbers or strings".to_string()),
+    };
+    Ok(format!(
+        " WHERE \"{}\" {} {}",
+        key.column(),
+        operator,
+        literal
+    ))
+}
+
+/// The `pagination` object of a `mode=cursor` page, e.g.
+/// `{"per_page": 20, "next": "...", "prev": null}`, where `next` goes in
+/// `?after=` and `prev` in `?before=`
+///
+/// `rows` are the rows of the page's query, which asks for one extra row:
+/// it is dropped, and a `?before=` page's rows are put back in key order.
+/// `next` and `prev` are `null` when there is no such page.
+pub fn cursor_context(
+    page: &PagePosition,
+    pagination: &Pagination,
+    rows: &mut Vec<HashMap<String, Value>>,
+) -> Result<Value, String> {
+    let PageMode::Cursor(key) = pagination.mode() else {
+        return Err("sqlite_paginate is not in mode=cursor".to_string());
+    };
+    let per_page = pagination.per_page().get();
+    let more = rows.len() > per_page;
+    rows.truncate(per_page);
+    let (has_prev, has_next) = match page {
+        PagePosition::Number(_) => (false, more),
+        PagePosition::After(_) => (true, more),
+        PagePosition::Before(_) => {
+            rows.reverse();
+            (more, true)
+        }
+    };
+
+    let cursor_at = |row: Option<&HashMap<String, Value>>| -> Result<Option<String>, String> {
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        match row.get(key.column()) {
+            Some(value @ (Value::Number(_) | Value::String(_))) => {
+                Ok(Some(encode_cursor(key.secret(), value)))
+            }
+            Some(_) => Err(format!("cursor key {} must not be NULL", key.column())),
+            None => Err(format!(
+                "cursor key {} must be a result column",
+                key.column()
+            )),
+        }
+    };
+    let next = if has_next {
+        cursor_at(rows.last())?
+    } else {
+        None
+    };
+    let prev = if has_prev {
+        cursor_at(rows.first())?
+    } else {
+        None
+    };
+    Ok(serde_json::json!({
+        "per_page": per_page,
+        "next": next,
+        "prev": prev,
+    }))
+}
+
+/// An RFC 8288 `Link` header for a page's neighbours, e.g.
+/// `</books?page=3>; rel="next", </books?page=1>; rel="prev"`, built from
+/// the request's own URI and its `pagination` object
+///
+/// Numbered pages link `first`, `prev`, `next` and `last`; cursor pages link
+/// `prev` and `next`. The page arguments are replaced and every other
+/// argument is kept. `None` when there is nothing to link to.
+pub fn pagination_links(
+    request_uri: &str,
+    pagination: &Pagination,
+    context: &Value,
+) -> Option<String> {
+    let (path, query) = request_uri.split_once('?').unwrap_or((request_uri, ""));
+    let (replaced, links): (Vec<&str>, Vec<(&str, String)>) = match pagination.mode() {
+        PageMode::Offset(variable) => {
+            let name = variable.name().strip_prefix("arg_").unwrap_or_default();
+            let last = context["page_count"].as_u64().filter(|&n| n > 0);
+            let pages = [
+                ("first", last.map(|_| 1)),
+                ("prev", context["prev"].as_u64()),
+                ("next", context["next"].as_u64()),
+                ("last", last),
+            ];
+            let links = pages
+                .into_iter()
+                .filter_map(|(rel, page)| Some((rel, format!("{}={}", name, page?))))
+                .collect();
+            (vec![name], links)
+        }
+        PageMode::Cursor(_) => {
+            let cursors = [
+                ("prev", "before", &context["prev"]),
+                ("next", "after", &context["next"]),
+            ];
+            let links = cursors
+                .into_iter()
+                .filter_map(|(rel, arg, cursor)| {
+                    Some((rel, format!("{}={}", arg, cursor.as_str()?)))
+                })
+                .collect();
+            (vec!["after", "before"], links)
+        }
+    };
+    if links.is_empty() {
+        return None;
+    }
+
+    // nginx matches argument names case-insensitively, so replacing does too
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|pair| {
+            let name = pair.split('=').next().unwrap_or_default();
+            !name.is_empty() && !replaced.iter().any(|r| r.eq_ignore_ascii_case(name))
+        })
+        .collect();
+    let header: Vec<String> = links
+        .iter()
+        .map(|(rel, arg)| {
+            let args: Vec<&str> = kept.iter().copied().chain([arg.as_str()]).collect();
+            format!("<{}?{}>; rel=\"{}\"", path, args.join("&"), rel)
+        })
+        .collect();
+    Some(header.join(", "))
+}
+
+/// The total a count query found, from its one row and column
+pub fn total_rows(rows: &[HashMap<String, Value>]) -> Result<u64, String> {
+    match rows {
+        [row] if row.len() == 1 => row
+            .values()
+            .next()
+            .and_then(Value::as_u64)
+            .ok_or_else(|| "count query must return a non-negative integer".to_string()),
+        _ => Err("count query must return one row with one column".to_string()),
+    }
+}
+
+/// The `pagination` object for templates and JSON responses, e.g.
+/// `{"page": 2, "per_page": 20, "total": 45, "page_count": 3, "prev": 1,
+/// "next": 3, "count": "exact"}`
+///
+/// `prev` and `next` are `null` when there is no such page, and `count`
+/// says how `total` was found (`sqlite_count`).
+pub fn pagination_context(page: u64, pagination: &Pagination, total: u64) -> Value {
+    let per_page = pagination.per_page().get() as u64;
+    let page_count = total.div_ceil(per_page);
+    serde_json::json!({
+        "page": page,
+        "per_page": per_page,
+        "total": total,
+        "page_count": page_count,
+        "prev": (page > 1).then(|| (page - 1).min(page_count.max(1))),
+        "next": (page < page_count).then_some(page + 1),
+        "count": pagination.count().name(),
+    })
+}
+
+/// The `pagination` object of any page: [`cursor_context`] for a cursor
+/// page, [`pagination_context`] for a numbered page with its `total`, or for
+/// an uncounted one (`sqlite_count none`) the same object with `total` and
+/// `page_count` `null`
+///
+/// An uncounted page's query asks for one extra row, which tells whether
+/// `next` exists and is then dropped.
+pub fn page_context(
+    page: &PagePosition,
+    pagination: &Pagination,
+    total: Option<u64>,
+    rows: &mut Vec<HashMap<String, Value>>,
+) -> Result<Value, String> {
+    if let PageMode::Cursor(_) = pagination.mode() {
+        return cursor_context(page, pagination, rows);
+    }
+    let number = page.number();
+    if let Some(total) = total {
+        return Ok(pagination_context(number, pagination, total));
+    }
+    let per_page = pagination.per_page().get();
+    let more = rows.len() > per_page;
+    rows.truncate(per_page);
+    Ok(serde_json::json!({
+        "page": number,
+        "per_page": per_page,
+        "total": null,
+        "page_count": null,
+        "prev": (number > 1).then(|| number - 1),
+        "next": more.then_some(number + 1),
+        "count": pagination.count().name(),
+    }))
+}
+
+/// The links of a `sqlite_breadcrumbs` trail for a request path, as
+/// (url, segment) pairs from the site root (`/`, with an empty segment) down
+/// to the path itself
+///
+/// `/books/fiction/` gives `/`, `/books` and `/books/fiction`.
+pub fn breadcrumb_trail(uri: &str) -> Vec<(String, String)> {
+    let mut trail = vec![("/".to_string(), String::new())];
+    let mut url = String::new();
+    for segment in uri.split('/').filter(|segment| !segment.is_empty()) {
+        url.push('/');
+        url.push_str(segment);
+        trail.push((url.clone(), segment.to_string()));
+    }
+    trail
+}
+
+/// A crumb's label from a `labels_query` row: its `label` column, or its only
+/// column; `None` when the row has neither or the value is NULL
+fn breadcrumb_label(row: &HashMap<String, Value>) -> Option<String> {
+    let value = match row.get("label") {
+        Some(value) => value,
+        None if row.len() == 1 => row.values().next()?,
+        None => return None,
+    };
+    match value {
+        Value::Null => None,
+        Value::String(label) => Some(label.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// `Cache-Control` for `sqlite_blob` responses: a hash names one content
+/// for ever, so caches may keep it without asking again
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// The SHA-256 a `sqlite_blob` request asks for: the last segment of its
+/// path, lowercased, if it is 64 hex digits
+pub fn content_hash(uri: &str) -> Option<String> {
+    uri.rsplit('/')
+        .find(|segment| !segment.is_empty())
+        .filter(|segment| segment.len() == 64 && segment.bytes().all(|b| b.is_ascii_hexdigit()))
+        .map(str::to_ascii_lowercase)
+}
+
+/// The SHA-256 of `bytes`, as lowercase hex
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    encode_hex(&Sha256::digest(bytes))
+}
+
+/// Whether an `If-None-Match` header names the entity tag `"<hash>"`
+///
+/// The header may list several tags, weak ones included, or be `*`.
+pub fn etag_matches(if_none_match: &str, hash: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|tag| {
+        let tag = tag.strip_prefix("W/").unwrap_or(tag);
+        tag == "*" || tag.strip_prefix('"').and_then(|t| t.strip_suffix('"')) == Some(hash)
+    })
+}
+
+/// What a [`Stage`] decided about a request
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flow<R> {
+    /// Leave the request to the next stage
+    Continue,
+    /// Answer the request; the stages after this one do not run
+    Respond(R),
+}
+
+/// One step of handling a request, such as resolving its parameters or
+/// running its query
+///
+/// Each stage reads and adds to a context `C` the stages before it filled
+/// in, and may answer the request with an `R`. A location's stages are
+/// picked from its configuration, so features it does not use cost nothing.
+pub trait Stage<C, R> {
+    /// The stage's name, for logs
+    fn name(&self) -> &'static str;
+
+    fn run(&self, context: &mut C) -> Flow<R>;
+}
+
+/// Run `stages` in order until one answers, returning its name and answer,
+/// or `None` when none does
+pub fn run_stages<C, R>(
+    stages: &[Box<dyn Stage<C, R> + '_>],
+    context: &mut C,
+) -> Option<(&'static str, R)> {
+    stages.iter().find_map(|stage| match stage.run(context) {
+        Flow::Continue => None,
+        Flow::Respond(answer) => Some((stage.name(), answer)),
+    })
+}
+
+/// How a request is served, decided by its HTTP method
+#[derive(Debug, PartialEq, Eq)]
+pub enum MethodRoute<'a> {
+    /// Run the location's query and render the results
+    Read,
+    /// Run the method's write statements, in order, as one transaction
+    Write(WriteMethod, Vec<&'a WriteQuery>),
+    /// The location has write statements, but none for this method
+    NotAllowed,
+}
+
+/// Route a request by method (pure function)
+///
+/// Locations without write statements serve every method as a read, as they
+/// always have. Once writes are configured, GET and HEAD still read, the
+/// configured methods write, and anything else is not allowed.
+pub fn route_method<'a>(config: &'a ValidatedConfig, method: &str) -> MethodRoute<'a> {
+    if config.write_queries.is_empty() || method == "GET" || method == "HEAD" {
+        return MethodRoute::Read;
+    }
+    let Some(write_method) = config
+        .write_queries
+        .iter()
+        .map(|(allowed, _)| *allowed)
+        .find(|allowed| allowed.as_str() == method)
+    else {
+        return MethodRoute::NotAllowed;
+    };
+    let statements = config
+        .write_queries
+        .iter()
+        .filter(|(allowed, _)| *allowed == write_method)
+        .map(|(_, query)| query)
+        .collect();
+    MethodRoute::Write(write_method, statements)
+}
+
+/// The pages a `sqlite_export` run renders, from the sitemap query's `uri`
+/// column (pure function)
+///
+/// Each URI must be a path (no query string), and repeats are rendered once.
+pub fn export_uris(rows: &[HashMap<String, Value>]) -> Result<Vec<String>, String> {
+    let mut uris: Vec<String> = Vec::with_capacity(rows.len());
+    for row in rows {
+        let uri = match row.get("uri") {
+            Some(Value::String(uri)) => uri,
+            Some(other) => return Err(format!("uri must be text, got {}", other)),
+            None => return Err("the export query must return a uri column".to_string()),
+        };
+        if !uri.starts_with('/') || uri.contains(['?', '#']) {
+            return Err(format!("uri must be a path starting with /: '{}'", uri));
+        }
+        if !uris.contains(uri) {
+            uris.push(uri.clone());
+        }
+    }
+    Ok(uris)
+}
+
+/// How a successful write is answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteResponse {
+    /// 201, with the change count and new rowid as JSON
+    Created,
+    /// 204, without a body
+    NoContent,
+}
+
+/// POST creates; every other write method answers without content (pure function)
+pub fn write_response(method: WriteMethod) -> WriteResponse {
+    match method {
+        WriteMethod::Post => WriteResponse::Created,
+        WriteMethod::Put | WriteMethod::Patch | WriteMethod::Delete => WriteResponse::NoContent,
+    }
+}
+
+/// Machine-readable class of a database failure, sent to clients as `"code"`
+/// so they can branch on it instead of parsing SQLite's message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The database is locked by another connection
+    Busy,
+    /// The database or its directory cannot be written
+    ReadOnly,
+    /// The file is damaged or not a database
+    Corrupt,
+    /// The file cannot be opened
+    CannotOpen,
+    ConstraintUnique,
+    ConstraintPrimaryKey,
+    ConstraintNotNull,
+    ConstraintCheck,
+    ConstraintForeignKey,
+    /// Any other constraint violation
+    Constraint,
+    /// A value has the wrong type for its column (e.g. a non-integer rowid)
+    Mismatch,
+    /// A write changed more rows than `sqlite_mutation_max_rows` allows
+    TooManyRows,
+    /// Anything else
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Busy => "busy",
+            ErrorCode::ReadOnly => "readonly",
+            ErrorCode::Corrupt => "corrupt",
+            ErrorCode::CannotOpen => "cannot_open",
+            ErrorCode::ConstraintUnique => "constraint_unique",
+            ErrorCode::ConstraintPrimaryKey => "constraint_primary_key",
+            ErrorCode::ConstraintNotNull => "constraint_not_null",
+            ErrorCode::ConstraintCheck => "constraint_check",
+            ErrorCode::ConstraintForeignKey => "constraint_foreign_key",
+            ErrorCode::Constraint => "constraint",
+            ErrorCode::Mismatch => "mismatch",
+            ErrorCode::TooManyRows => "too_many_rows",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    /// The HTTP status for a failure of this class (pure function)
+    ///
+    /// Clashing with an existing row is a conflict (409), other rejected
+    /// values are unprocessable (422), a write too wide for its limit is a bad
+    /// request (400), a busy database is temporary (503) and everything else
+    /// is the server's problem (500).
+    pub fn http_status(self) -> u16 {
+        match self {
+            ErrorCode::ConstraintUnique | ErrorCode::ConstraintPrimaryKey => 409,
+            ErrorCode::ConstraintNotNull
+            | ErrorCode::ConstraintCheck
+            | ErrorCode::ConstraintForeignKey
+            | ErrorCode::Constraint
+            | ErrorCode::Mismatch => 422,
+            ErrorCode::TooManyRows => 400,
+            ErrorCode::Busy => 503,
+            ErrorCode::ReadOnly
+            | ErrorCode::Corrupt
+            | ErrorCode::CannotOpen
+            | ErrorCode::Internal => 500,
+        }
+    }
+}
+
+/// A failed query or statement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl DbError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        DbError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// An error that is not SQLite's (e.g. a contained panic)
+    pub fn internal(message: impl Into<String>) -> Self {
+        DbError::new(ErrorCode::Internal, message)
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// JSON body answering a database failure (pure function)
+///
+/// `rolled_back` marks failures that undid a write transaction; constraint
+/// violations that name their columns add per-field `errors`.
+pub fn error_body(error: &str, failure: &DbError, rolled_back: bool) -> Value {
+    let mut body = serde_json::json!({
+        "error": error,
+        "code": failure.code.as_str(),
+        "details": failure.message,
+    });
+    if let Some(errors) = field_errors(failure) {
+        body["errors"] = Value::Object(errors);
+    }
+    if rolled_back {
+        body["rolled_back"] = Value::Bool(true);
+    }
+    body
+}
+
+/// Per-field messages for a constraint violation, parsed from SQLite's
+/// message (pure function)
+///
+/// `UNIQUE constraint failed: users.email` gives `{"email": "already exists"}`,
+/// a NOT NULL violation "is required" and a CHECK violation "is invalid",
+/// keyed by the column the check starts with (or the constraint's name).
+/// Violations that name no column, such as foreign keys, give `None`.
+pub fn field_errors(failure: &DbError) -> Option<serde_json::Map<String, Value>> {
+    let message = match failure.code {
+        ErrorCode::ConstraintUnique | ErrorCode::ConstraintPrimaryKey => "already exists",
+        ErrorCode::ConstraintNotNull => "is required",
+        ErrorCode::ConstraintCheck => "is invalid",
+        _ => return None,
+    };
+    let (_, detail) = failure.message.split_once("constraint failed: ")?;
+
+    let fields: Vec<&str> = if failure.code == ErrorCode::ConstraintCheck {
+        let name_end = detail
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(detail.len());
+        vec![&detail[..name_end]]
+    } else {
+        // table.column, table.column, ...
+        detail
+            .split(", ")
+            .map(|column| column.rsplit('.').next().unwrap_or(column))
+            .collect()
+    };
+
+    let errors: serde_json::Map<String, Value> = fields
+        .into_iter()
+        .filter(|field| !field.is_empty())
+        .map(|field| (field.to_string(), Value::from(message)))
+        .collect();
+    (!errors.is_empty()).then_some(errors)
+}
+
+/// Build the data passed to a `sqlite_form_template` re-rendered after a write
+/// failed validation (pure function)
+///
+/// `values` holds the submitted parameters by name, without their `:`, so the
+/// form can refill its fields (a repeated name gives an array); `errors` holds
+/// the per-field messages and `error` the failure's code and details.
+pub fn build_form_context(params: &[(String, Value)], failure: &DbError) -> Value {
+    serde_json::json!({
+        "values": submitted_values(params),
+        "errors": field_errors(failure).unwrap_or_default(),
+        "error": {
+            "code": failure.code.as_str(),
+            "details": failure.message,
+        },
+    })
+}
+
+/// Named parameters keyed by name without their `:`; a repeated name gives an array
+fn submitted_values(params: &[(String, Value)]) -> serde_json::Map<String, Value> {
+    let mut values = serde_json::Map::new();
+    for (name, value) in params.iter().filter(|(name, _)| !name.is_empty()) {
+        let field = name.trim_start_matches([':', '@', '$']);
+        let value = value.clone();
+        match values.get_mut(field) {
+            Some(Value::Array(items)) => items.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                values.insert(field.to_string(), value);
+            }
+        }
+    }
+    values
+}
+
+/// Template data for a `sqlite_notify_email` body (pure function)
+///
+/// `values` holds the submitted parameters as in [`build_form_context`], next
+/// to the write's `method`, `changes` and `last_insert_rowid`.
+pub fn build_email_context(
+    method: WriteMethod,
+    params: &[(String, Value)],
+    outcome: &WriteOutcome,
+) -> Value {
+    serde_json::json!({
+        "method": method.as_str(),
+        "values": submitted_values(params),
+        "changes": outcome.changes,
+        "last_insert_rowid": outcome.last_insert_rowid,
+    })
+}
+
+/// A complete message for `sendmail -t` (pure function)
+///
+/// Headers come from the configuration, except `Reply-To`, which is the
+/// submitted value of the `reply_to` parameter when that is a plain address
+/// (anything else is left out rather than risk injecting headers).
+pub fn compose_email(
+    notification: &EmailNotification,
+    params: &[(String, Value)],
+    body: &str,
+) -> String {
+    let mut message = format!("To: {}\n", notification.to().join(", "));
+    if let Some(from) = notification.from() {
+        message.push_str(&format!("From: {}\n", from));
+    }
+    let reply_to = notification.reply_to().and_then(|field| {
+        params
+            .iter()
+            .find(|(name, _)| name.trim_start_matches([':', '@', '$']) == field)
+            .and_then(|(_, value)| value.as_str())
+            .map(str::trim)
+            .filter(|value| is_email_address(value))
+    });
+    if let Some(address) = reply_to {
+        message.push_str(&format!("Reply-To: {}\n", address));
+    }
+    message.push_str(&format!("Subject: {}\n", notification.subject()));
+    message.push_str("MIME-Version: 1.0\n");
+    message.push_str("Content-Type: text/plain; charset=utf-8\n");
+    message.push_str("Content-Transfer-Encoding: 8bit\n\n");
+    message.push_str(body);
+    if !body.ends_with('\n') {
+        message.push('\n');
+    }
+    message
+}
+
+/// Result of running a method's write statements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOutcome {
+    /// Rows changed across every statement
+    pub changes: usize,
+    pub last_insert_rowid: i64,
+}
+
+/// Write execution strategy (dependency injection)
+///
+/// The statements run in one transaction: either all of them take effect or,
+/// when any fails, none do.
+pub trait WriteExecutor {
+    fn execute_write(
+        &self,
+        db_path: &DatabasePath,
+        queries: &[&WriteQuery],
+        params: &[(String, Value)],
+    ) -> Result<WriteOutcome, DbError>;
+}
+
+/// Parameter values for one run of a statement, by name (empty for
+/// positional ones), keeping their JSON types: strings bind as text, numbers
+/// as INTEGER or REAL, booleans as 1 and 0, and `null` as NULL
+pub type ParameterSet = Vec<(String, Value)>;
+
+/// Why a write looks automated, if it does (pure function)
+///
+/// Bots fill in the honeypot field that people never see, and submit sooner
+/// than `min_time` seconds after the form was rendered (the form carries
+/// `{{now}}` in its time field). A missing or unreadable time counts as too soon.
+pub fn honeypot_rejection(
+    honeypot: &Honeypot,
+    resolver: &mut dyn VariableResolver,
+    now: u64,
+) -> Option<String> {
+    let mut arg = |field: &str| {
+        resolver
+            .resolve(&format!("$arg_{}", field))
+            .unwrap_or_default()
+    };
+
+    if !arg(honeypot.field()).is_empty() {
+        return Some(format!(
+            "honeypot field '{}' was filled in",
+            honeypot.field()
+        ));
+    }
+
+    let min_time = honeypot.min_time()?;
+    match arg(honeypot.time_field()).parse::<u64>() {
+        Ok(rendered) if now.saturating_sub(rendered) >= min_time => None,
+        Ok(rendered) => Some(format!(
+            "submitted {}s after the form was rendered (minimum {}s)",
+            now.saturating_sub(rendered),
+            min_time
+        )),
+        Err(_) => Some(format!(
+            "form time field '{}' is missing",
+            honeypot.time_field()
+        )),
+    }
+}
+
+/// Query string for a `sqlite_captcha` verification subrequest (pure function)
+///
+/// The verify location forwards it to the provider's siteverify endpoint,
+/// which takes the same `secret`, `response` and `remoteip` fields.
+pub fn captcha_verify_args(captcha: &Captcha, token: &str, remote_ip: Option<&str>) -> String {
+    let mut args = format!(
+        "secret={}&response={}",
+        percent_encode(captcha.secret()),
+        percent_encode(token)
+    );
+    if let Some(ip) = remote_ip.filter(|ip| !ip.is_empty()) {
+        args.push_str("&remoteip=");
+        args.push_str(&percent_encode(ip));
+    }
+    args
+}
+
+/// Whether a provider's siteverify answer accepts the token (pure function)
+///
+/// Every supported provider answers `{"success": true, ...}`; anything else,
+/// including an unreadable answer, is a failure.
+pub fn captcha_passed(response: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(response)
+        .is_ok_and(|answer| answer["success"] == Value::Bool(true))
+}
+
+/// Percent-encode everything but unreserved characters (RFC 3986)
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Whether a request is answered as a batch (pure function)
+///
+/// With `sqlite_batch on`, requests routed to write statements and POSTs to
+/// read-only locations take their parameter sets from the request body.
+pub fn is_batch(config: &ValidatedConfig, route: &MethodRoute, method: &str) -> bool {
+    config.batch
+        && match route {
+            MethodRoute::Write(..) => true,
+            MethodRoute::Read => method == "POST",
+            MethodRoute::NotAllowed => false,
+        }
+}
+
+/// Parse a batch request body: a JSON array of parameter sets (pure function)
+///
+/// Objects bind by name (`{"title": "Dune"}` binds `:title`) on top of the
+/// location's named parameters, so a set only needs the values that vary.
+/// Arrays bind by position and replace the location's parameters entirely.
+/// Values must be strings, numbers, booleans or null.
+pub fn parse_parameter_sets(
+    body: &[u8],
+    defaults: &[(String, Value)],
+) -> Result<Vec<ParameterSet>, String> {
+    let sets: Vec<Value> = serde_json::from_slice(body)
+        .map_err(|e| format!("body must be a JSON array of parameter sets: {}", e))?;
+
+    sets.into_iter()
+        .enumerate()
+        .map(|(i, set)| parameter_set(set).map_err(|e| format!("parameter set {}: {}", i, e)))
+        .map(|set| {
+            let set = set?;
+            if set.iter().all(|(name, _)| name.is_empty()) {
+                return Ok(set);
+            }
+            let mut merged: ParameterSet = defaults
+                .iter()
+                .filter(|(name, _)| !name.is_empty() && !set.iter().any(|(n, _)| n == name))
+                .cloned()
+                .collect();
+            merged.extend(set);
+            Ok(merged)
+        })
+        .collect()
+}
+
+fn parameter_set(set: Value) -> Result<ParameterSet, String> {
+    let values: Vec<(String, Value)> = match set {
+        Value::Object(fields) => fields
+            .into_iter()
+            .map(|(name, value)| {
+                let name = if name.starts_with([':', '@', '$']) {
+                    name
+                } else {
+                    format!(":{}", name)
+                };
+                (name, value)
+            })
+            .collect(),
+        Value::Array(values) => values.into_iter().map(|v| (String::new(), v)).collect(),
+        _ => return Err("must be an object or an array".to_string()),
+    };
+
+    if let Some((name, _)) = values
+        .iter()
+        .find(|(_, value)| matches!(value, Value::Array(_) | Value::Object(_)))
+    {
+        return Err(if name.is_empty() {
+            "values must be scalars".to_string()
+        } else {
+            format!("value for {} must be a scalar", name)
+        });
+    }
+    Ok(values)
+}
+
+/// Batch execution strategy (dependency injection)
+///
+/// Every parameter set runs inside one transaction; an error names the set
+/// that failed, and nothing the batch wrote is kept.
+pub trait BatchExecutor {
+    fn execute_batch_query(
+        &self,
+        db_path: &DatabasePath,
+        query: &SqlQuery,
+        sets: &[ParameterSet],
+    ) -> Result<Vec<Vec<HashMap<String, Value>>>, DbError>;
+
+    fn execute_batch_write(
+        &self,
+        db_path: &DatabasePath,
+        queries: &[&WriteQuery],
+        sets: &[ParameterSet],
+    ) -> Result<Vec<WriteOutcome>, DbError>;
+}
+
+/// Query execution strategy (dependency injection)
+pub trait QueryExecutor {
+    fn execute(
+        &self,
+        db_path: &DatabasePath,
+        query: &SqlQuery,
+        params: &[(String, Value)],
+    ) -> Result<Vec<HashMap<String, Value>>, DbError>;
+}
+
+/// Rows returned by one query execution, or the reason it failed
+type QueryOutcome = Result<Vec<HashMap<String, Value>>, DbError>;
+
+/// Template loading strategy (dependency injection)
+pub trait TemplateLoader {
+    fn load_from_dir(&mut self, dir_path: &str) -> Result<usize, String>;
+    fn register_template(&mut self, name: &str, path: &str) -> Result<(), String>;
+}
+
+/// Template rendering strategy (dependency injection)
+pub trait TemplateRenderer {
+    fn render(&self, template_name: &str, data: &Value) -> Result<String, String>;
+
+    /// Render into an output buffer of at least `capacity` bytes, so a page
+    /// no longer than that is written without growing it
+    fn render_sized(
+        &self,
+        template_name: &str,
+        data: &Value,
+        _capacity: usize,
+    ) -> Result<String, String> {
+        self.render(template_name, data)
+    }
+}
+
+/// `sqlite_nav` and `sqlite_trending` rows kept between requests, for
+/// dependency injection
+pub trait MenuCache {
+    /// The menu's rows read in `shape`, if they were fetched less than its
+    /// `max_age` ago
+    fn cached(&self, menu: &NavMenu, shape: &RowShape) -> Option<Vec<HashMap<String, Value>>>;
+
+    /// Keep rows freshly fetched in `shape` for later requests
+    fn keep(&self, menu: &NavMenu, shape: &RowShape, rows: Vec<HashMap<String, Value>>);
+}
+
+/// How large pages have rendered, for dependency injection
+pub trait PageSizes {
+    /// The buffer to render `template` with `rows` rows into
+    fn estimate(&self, template: &str, rows: usize) -> usize;
+
+    /// Remember that `template` rendered `rows` rows as `bytes` bytes
+    fn record(&self, template: &str, rows: usize, bytes: usize);
+}
+
+/// The buffer a page starts with when nothing says how large it will be, as
+/// large as Handlebars' own
+pub const PAGE_CAPACITY: usize = 8 * 1024;
+
+/// Pure business logic for request handling
+pub struct RequestProcessor<Q, L: TemplateLoader + TemplateRenderer, Log: Logger> {
+    query_executor: Q,
+    template_loader: L,
+    logger: Log,
+    metrics: Arc<dyn Metrics>,
+    timings: Option<Timings>,
+    found_rows: usize,
+    nav_cache: Option<Arc<dyn MenuCache>>,
+    render_sizes: Option<Arc<dyn PageSizes>>,
+    page: PagePosition,
+    hits: Option<u64>,
+    trace: Option<Arc<dyn Tracer>>,
+    clock: Arc<dyn Clock>,
+    fields: Option<FieldSelection>,
+    /// The traced operation the processor's queries run under
+    querying: Option<SpanId>,
+}
+
+/// How long a request spent querying and rendering
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timings {
+    /// The main query, sources and any fallback query
+    pub query: Duration,
+    /// Loading and rendering templates; `None` for JSON responses
+    pub render: Option<Duration>,
+}
+
+impl Timings {
+    /// The `Server-Timing` header value, e.g. `query;dur=1.25, render;dur=0.4`
+    pub fn server_timing(&self) -> String {
+        let mut value = format!("query;dur={}", millis(self.query));
+        if let Some(render) = self.render {
+            value.push_str(&format!(", render;dur={}", millis(render)));
+        }
+        value
+    }
+}
+
+/// A duration in milliseconds, to the microsecond
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+/// An operation recorded by a [`Tracer`], numbered in the order operations
+/// began
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanId(pub usize);
+
+/// Records the operations one request runs, for `sqlite_trace` (dependency
+/// injection)
+///
+/// Operations may be recorded from several threads, as sources run while
+/// the main query does.
+pub trait Tracer: Send + Sync {
+    /// Start an operation under `parent`, or at the top when there is none
+    fn begin(&self, operation: &str, parent: Option<SpanId>) -> SpanId;
+
+    /// Finish an operation, with the rows it returned if it returns rows
+    fn end(&self, span: SpanId, rows: Option<usize>);
+
+    /// Finish an operation that failed
+    fn fail(&self, span: SpanId);
+}
+
+/// Time passing, for dependency injection
+pub trait Clock {
+    /// The time since some fixed point, e.g. when the clock was made
+    fn elapsed(&self) -> Duration;
+}
+
+/// A clock that never moves, for processors nothing times
+pub struct NoClock;
+
+impl Clock for NoClock {
+    fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Run `run` as `operation` under `parent` when the request is traced,
+/// noting how many rows it returned
+pub fn traced<T, E>(
+    trace: Option<&dyn Tracer>,
+    operation: &str,
+    parent: Option<SpanId>,
+    run: impl FnOnce() -> Result<Vec<T>, E>,
+) -> Result<Vec<T>, E> {
+    let Some(trace) = trace else {
+        return run();
+    };
+    let span = trace.begin(operation, parent);
+    let result = run();
+    match &result {
+        Ok(rows) => trace.end(span, Some(rows.len())),
+        Err(_) => trace.fail(span),
+    }
+    result
+}
+
+/// A W3C `traceparent` header, if it is well formed (`00-` then a 32 and a
+/// 16 digit hex id and two hex digits of flags), so a trace can be filed
+/// under the caller's
+pub fn trace_parent(header: &str) -> Option<&str> {
+    let parts: Vec<&str> = header.trim().split('-').collect();
+    let hex = |part: &str, len: usize| {
+        part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    match parts.as_slice() {
+        [version, trace_id, parent_id, flags]
+            if hex(version, 2)
+                && *version != "ff"
+                && hex(trace_id, 32)
+                && hex(parent_id, 16)
+                && hex(flags, 2)
+                && trace_id.bytes().any(|b| b != b'0')
+                && parent_id.bytes().any(|b| b != b'0') =>
+        {
+            Some(header.trim())
+        }
+        _ => None,
+    }
+}
+
+impl<Q, L, Log> RequestProcessor<Q, L, Log>
+where
+    Q: QueryExecutor + Sync,
+    L: TemplateLoader + TemplateRenderer,
+    Log: Logger,
+{
+    pub fn new(query_executor: Q, template_loader: L, logger: Log) -> Self {
+        RequestProcessor {
+            query_executor,
+            template_loader,
+            logger,
+            metrics: Arc::new(NoMetrics),
+            timings: None,
+            found_rows: 0,
+            nav_cache: None,
+            render_sizes: None,
+            page: PagePosition::Number(1),
+            hits: None,
+            trace: None,
+            clock: Arc::new(NoClock),
+            fields: None,
+            querying: None,
+        }
+    }
+
+    /// Render page `page` of a `sqlite_paginate` location (the first page
+    /// unless set)
+    pub fn with_page(mut self, page: PagePosition) -> Self {
+        self.page = page;
+        self
+    }
+
+    /// Give templates the page's `sqlite_hit_counter` total as `hits`
+    pub fn with_hits(mut self, hits: Option<u64>) -> Self {
+        self.hits = hits;
+        self
+    }
+
+    /// Give templates only the columns `?fields=` asks for
+    pub fn with_fields(mut self, fields: Option<FieldSelection>) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    /// Count rows and time queries and rendering in `metrics`: `query` and
+    /// `render` timings, `rows`, `fallback` and `truncated` counters, and
+    /// `render_bytes` with how many pages fit their estimated size
+    /// (`render_presized`) or outgrew it (`render_outgrown`)
+    pub fn with_metrics(mut self, metrics: Option<Arc<dyn Metrics>>) -> Self {
+        self.metrics = metrics.unwrap_or_else(|| Arc::new(NoMetrics));
+        self
+    }
+
+    /// Record the operations of [`process`](Self::process) in `trace`
+    pub fn with_trace(mut self, trace: Option<Arc<dyn Tracer>>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Time querying and rendering by `clock`; without one, [`timings`]
+    /// are all zero
+    ///
+    /// [`timings`]: Self::timings
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Reuse `sqlite_nav` rows from `cache`; without one, menus are queried
+    /// for every page
+    pub fn with_nav_cache(mut self, cache: Option<Arc<dyn MenuCache>>) -> Self {
+        self.nav_cache = cache;
+        self
+    }
+
+    /// Size pages' output buffers by what `sizes` has seen their templates
+    /// render; without it, every page starts at [`PAGE_CAPACITY`] and grows as
+    /// it renders
+    pub fn with_render_sizes(mut self, sizes: Option<Arc<dyn PageSizes>>) -> Self {
+        self.render_sizes = sizes;
+        self
+    }
+
+    /// How long the last [`process`](Self::process) call spent querying and
+    /// rendering
+    pub fn timings(&self) -> Option<Timings> {
+        self.timings
+    }
+
+    /// How many rows the last successful [`process`](Self::process) call's
+    /// query found; a fallback query's rows stand in for a page that was not
+    /// found, so they are not counted
+    pub fn found_rows(&self) -> usize {
+        self.found_rows
+    }
+
+    /// Run the main query and every `sqlite_source` query at the same time
+    ///
+    /// Sources run on scoped threads (one per source, none when there are no
+    /// sources) while the main query runs on the calling thread. Sources take
+    /// no parameters; results come back in configuration order.
+    fn execute_concurrently(
+        &self,
+        config: &ValidatedConfig,
+        resolved_params: &[(String, Value)],
+    ) -> (QueryOutcome, Vec<QueryOutcome>) {
+        let executor = &self.query_executor;
+        let (trace, querying) = (self.trace.as_deref(), self.querying);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = config
+                .sources
+                .iter()
+                .map(|source| {
+                    scope.spawn(move || {
+                        guard::catch_panic(|| {
+                            let operation = format!("source {}", source.name());
+                            traced(trace, &operation, querying, || {
+                                executor.execute(source.db_path(), source.query(), &[])
+                            })
+                        })
+                        .unwrap_or_else(|e| Err(DbError::internal(e)))
+                    })
+                })
+                .collect();
+
+            let results = traced(trace, "main query", querying, || {
+                executor.execute(&config.db_path, &config.query, resolved_params)
+            });
+            let sources = handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(DbError::internal("source query panicked")))
+                })
+                .collect();
+            (results, sources)
+        })
+    }
+
+    /// Run one of the processor's queries as a traced `operation`
+    fn traced<T>(
+        &self,
+        operation: &str,
+        run: impl FnOnce() -> Result<Vec<T>, DbError>,
+    ) -> Result<Vec<T>, DbError> {
+        traced(self.trace.as_deref(), operation, self.querying, run)
+    }
+
+    /// Rows for each `sqlite_nav` menu, keyed by menu name, from the cache
+    /// while they are fresh
+    fn fetch_nav_menus(&self, config: &ValidatedConfig) -> Result<Value, String> {
+        let mut menus = serde_json::Map::new();
+        for menu in &config.nav_menus {
+            let rows = self.cached_rows(config, menu, "Nav menu")?;
+            menus.insert(menu.name().to_string(), serde_json::json!(rows));
+        }
+        Ok(Value::Object(menus))
+    }
+
+    /// A menu's rows (`what` names its kind in errors), from the cache while
+    /// they are fresh
+    fn cached_rows(
+        &self,
+        config: &ValidatedConfig,
+        menu: &NavMenu,
+        what: &str,
+    ) -> Result<Vec<HashMap<String, Value>>, String> {
+        let cached = self
+            .nav_cache
+            .as_ref()
+            .and_then(|c| c.cached(menu, &config.rows));
+        let mut rows = match cached {
+            Some(rows) => rows,
+            None => {
+                let source = menu.source();
+                let operation = format!("{} {}", what.to_lowercase(), menu.name());
+                let rows = self
+                    .traced(&operation, || {
+                        self.query_executor
+                            .execute(source.db_path(), source.query(), &[])
+                    })
+                    .map_err(|e| {
+                        self.logger.error(
+                            "query",
+                            &format!("{} '{}' failed: {}", what, menu.name(), e),
+                        );
+                        let what = what.to_lowercase();
+                        format!("{} '{}' execution failed: {}", what, menu.name(), e)
+                    })?;
+                if let Some(cache) = &self.nav_cache {
+                    cache.keep(menu, &config.rows, rows.clone());
+                }
+                rows
+            }
+        };
+        expand_json(&mut rows, &config.expand_json);
+        Ok(rows)
+    }
+
+    /// The `breadcrumbs` template array for the request path: `url`,
+    /// `segment`, `label` and `current` (the last crumb) for each link
+    ///
+    /// Labels come from `labels_query` run once per crumb with `:path` and
+    /// `:segment` bound; without a label the crumb shows its segment, and the
+    /// root shows "Home".
+    fn build_breadcrumbs(
+        &self,
+        config: &ValidatedConfig,
+        uri: &str,
+        breadcrumbs: &Breadcrumbs,
+    ) -> Result<Value, String> {
+        let trail = breadcrumb_trail(uri);
+        let last = trail.len() - 1;
+        let mut crumbs = Vec::with_capacity(trail.len());
+        for (index, (url, segment)) in trail.into_iter().enumerate() {
+            let mut label = None;
+            if let Some(query) = breadcrumbs.labels_query() {
+                let params = [
+                    (":path".to_string(), Value::from(url.as_str())),
+                    (":segment".to_string(), Value::from(segment.as_str())),
+                ];
+                let rows = self
+                    .traced("breadcrumb label", || {
+                        self.query_executor.execute(&config.db_path, query, &params)
+                    })
+                    .map_err(|e| {
+                        self.logger
+                            .error("query", &format!("Breadcrumb labels failed: {}", e));
+                        format!("breadcrumb labels query execution failed: {}", e)
+                    })?;
+                label = rows.first().and_then(breadcrumb_label);
+            }
+            let label = label.unwrap_or_else(|| match index {
+                0 => "Home".to_string(),
+                _ => segment.clone(),
+            });
+            crumbs.push(serde_json::json!({
+                "url": url,
+                "segment": segment,
+                "label": label,
+                "current": index == last,
+            }));
+        }
+        Ok(Value::Array(crumbs))
+    }
+
+    /// Load the global templates and those beside `resolved_template` (as
+    /// partials), then register `resolved_template` under `name`
+    fn load_templates(
+        &mut self,
+        resolved_template: &ResolvedTemplate,
+        name: &str,
+        global_template_dir: Option<&str>,
+    ) -> Result<(), String> {
+        // Load global templates if provided
+        if let Some(dir) = global_template_dir {
+            self.logger.debug(
+                "templates",
+                &format!("Loading global templates from: {}", dir),
+            );
+            match self.template_loader.load_from_dir(dir) {
+                Ok(count) => {
+                    self.logger.info(
+                        "templates",
+                        &format!("Loaded {} global template(s) from '{}'", count, dir),
+                    );
+                }
+                Err(e) => {
+                    self.logger.warn(
+                        "templates",
+                        &format!("Failed to load global templates from '{}': {}", dir, e),
+                    );
+                }
+            }
+        }
+
+        // Load the default theme's partials, for the active theme to replace
+        if let Some(dir) = resolved_template.fallback_directory.as_deref() {
+            match self.template_loader.load_from_dir(dir) {
+                Ok(count) => {
+                    self.logger.debug(
+                        "templates",
+                        &format!("Loaded {} fallback template(s) from '{}'", count, dir),
+                    );
+                }
+                Err(e) => {
+                    self.logger.warn(
+                        "templates",
+                        &format!("Failed to load fallback templates from '{}': {}", dir, e),
+                    );
+                }
+            }
+        }
+
+        // Load local templates
+        self.logger.debug(
+            "templates",
+            &format!(
+                "Loading local templates from: {}",
+                resolved_template.directory()
+            ),
+        );
+        match self
+            .template_loader
+            .load_from_dir(resolved_template.directory())
+        {
+            Ok(count) => {
+                self.logger
+                    .debug("templates", &format!("Loaded {} local template(s)", count));
+            }
+            Err(e) => {
+                self.logger.warn(
+                    "templates",
                     &format!("Failed to load local templates: {}", e),
                 );
             }
         }
 
-        // Register main template
-        self.logger.debug(
-            "templates",
-            &format!(
-                "Registering main template: {}",
-                resolved_template.full_path()
-            ),
+        // Register the template being rendered
+        self.logger.debug(
+            "templates",
+            &format!("Registering {}: {}", name, resolved_template.full_path()),
+        );
+        self.template_loader
+            .register_template(name, resolved_template.full_path())
+            .map_err(|e| {
+                self.logger
+                    .error("template", &format!("Failed to register template: {}", e));
+                format!("failed to register template: {}", e)
+            })
+    }
+
+    /// Render the `sqlite_form_template` with the context from
+    /// [`build_form_context`]
+    pub fn render_form(
+        &mut self,
+        form_template: &ResolvedTemplate,
+        data: &Value,
+        global_template_dir: Option<&str>,
+    ) -> Result<String, String> {
+        self.load_templates(form_template, "form", global_template_dir)?;
+        self.template_loader.render("form", data).map_err(|e| {
+            self.logger
+                .error("render", &format!("Form rendering failed: {}", e));
+            format!("rendering failed: {}", e)
+        })
+    }
+
+    /// Render a `sqlite_notify_email` body, with the same partials as the
+    /// location's other templates
+    pub fn render_email(
+        &mut self,
+        email_template: &ResolvedTemplate,
+        data: &Value,
+        global_template_dir: Option<&str>,
+    ) -> Result<String, String> {
+        self.load_templates(email_template, "email", global_template_dir)?;
+        self.template_loader.render("email", data).map_err(|e| {
+            self.logger
+                .error("render", &format!("Email rendering failed: {}", e));
+            format!("rendering failed: {}", e)
+        })
+    }
+
+    /// Process a request (pure, testable business logic)
+    pub fn process(
+        &mut self,
+        config: &ValidatedConfig,
+        paths: &RequestPaths,
+        resolved_template: &ResolvedTemplate,
+        resolved_params: &[(String, Value)],
+        global_template_dir: Option<&str>,
+    ) -> Result<String, String> {
+        self.logger.debug(
+            "processor",
+            &format!("Processing request for {}", paths.uri),
+        );
+        self.found_rows = 0;
+
+        // With sqlite_paginate, run the query for this page only
+        let paged_config;
+        let (config, count) = match &config.pagination {
+            Some(pagination) => {
+                let (query, count) = page_queries(config, pagination, &self.page)?;
+                paged_config = ValidatedConfig {
+                    query,
+                    ..config.clone()
+                };
+                (&paged_config, Some((pagination, count)))
+            }
+            None => (config, None),
+        };
+
+        // Execute query
+        self.logger.debug(
+            "query",
+            &format!("Executing query: {}", config.query.as_str()),
+        );
+        let query_started = self.clock.elapsed();
+        let trace = self.trace.clone();
+        self.querying = trace.as_ref().map(|trace| trace.begin("query", None));
+        let (results, sources) = self.execute_concurrently(config, resolved_params);
+        let results = results.map_err(|e| {
+            self.logger
+                .error("query", &format!("Query execution failed: {}", e));
+            format!("query execution failed: {}", e)
+        })?;
+        let mut source_rows = Vec::with_capacity(sources.len());
+        for (source, rows) in config.sources.iter().zip(sources) {
+            let mut rows = rows.map_err(|e| {
+                self.logger.error(
+                    "query",
+                    &format!("Source '{}' failed: {}", source.name(), e),
+                );
+                format!("source '{}' execution failed: {}", source.name(), e)
+            })?;
+            self.logger.debug(
+                "query",
+                &format!("Source '{}' returned {} rows", source.name(), rows.len()),
+            );
+            expand_json(&mut rows, &config.expand_json);
+            source_rows.push((source.name(), rows));
+        }
+        let nav = self.fetch_nav_menus(config)?;
+        let trending = config
+            .trending
+            .as_ref()
+            .map(|menu| self.cached_rows(config, menu, "Trending"))
+            .transpose()?;
+        let breadcrumbs = config
+            .breadcrumbs
+            .as_ref()
+            .map(|breadcrumbs| self.build_breadcrumbs(config, &paths.uri, breadcrumbs))
+            .transpose()?;
+
+        self.logger
+            .debug("query", &format!("Query returned {} rows", results.len()));
+
+        let mut results = results;
+        let mut total = None;
+        let mut pagination = None;
+        if let Some((settings, count)) = &count {
+            if let Some(count) = count {
+                let counted = self
+                    .traced("count", || {
+                        self.query_executor
+                            .execute(&config.db_path, count, resolved_params)
+                    })
+                    .map_err(|e| e.to_string())
+                    .and_then(|rows| total_rows(&rows))
+                    .map_err(|e| {
+                        self.logger
+                            .error("query", &format!("Count query failed: {}", e));
+                        format!("count query execution failed: {}", e)
+                    })?;
+                total = Some(counted);
+            }
+            let context = page_context(&self.page, settings, total, &mut results).map_err(|e| {
+                self.logger
+                    .error("query", &format!("Pagination failed: {}", e));
+                format!("pagination failed: {}", e)
+            })?;
+            pagination = Some(context);
+        }
+
+        // Run the fallback query when the primary query found nothing (on
+        // any page; without a count, when the first page is empty)
+        let nothing_found = match total {
+            Some(total) => total == 0,
+            None => self.page == PagePosition::Number(1),
+        };
+        let (results, fallback_used) = match &config.fallback_query {
+            Some(fallback) if results.is_empty() && nothing_found => {
+                self.logger.debug(
+                    "query",
+                    &format!("Executing fallback query: {}", fallback.as_str()),
+                );
+                let results = self
+                    .traced("fallback query", || {
+                        self.query_executor.execute(&config.db_path, fallback, &[])
+                    })
+                    .map_err(|e| {
+                        self.logger
+                            .error("query", &format!("Fallback query failed: {}", e));
+                        format!("fallback query execution failed: {}", e)
+                    })?;
+                self.metrics.count("fallback", 1);
+                (results, true)
+            }
+            _ => (results, false),
+        };
+        let mut results = results;
+        expand_json(&mut results, &config.expand_json);
+        let query_time = self.clock.elapsed().saturating_sub(query_started);
+        if let Some((trace, querying)) = trace.as_deref().zip(self.querying) {
+            trace.end(querying, Some(results.len()));
+        }
+
+        let render_started = self.clock.elapsed();
+        let rendering = trace.as_ref().map(|trace| trace.begin("render", None));
+        self.load_templates(resolved_template, "template", global_template_dir)?;
+
+        // Render
+        self.logger
+            .debug("render", "Rendering template with query results");
+        let total_rows = results.len();
+        let mut data = build_template_context(
+            results,
+            config.max_template_rows,
+            config.aggregate.as_ref(),
+            fallback_used,
+        );
+        // Statistics above were computed before the rows were narrowed
+        if let Some(fields) = &self.fields
+            && let Some(Value::Array(rows)) = data.get_mut("results")
+        {
+            for row in rows.iter_mut().filter_map(Value::as_object_mut) {
+                row.retain(|name, _| fields.keeps(name));
+            }
+        }
+        provide_context(
+            &mut data,
+            &[
+                &RequestInfo {
+                    params: resolved_params,
+                },
+                &SourceRows(source_rows),
+                &Navigation {
+                    nav: Some(nav).filter(|_| !config.nav_menus.is_empty()),
+                    breadcrumbs,
+                    trending,
+                },
+                &PageInfo(pagination),
+                &Measurements {
+                    hits: self.hits,
+                    query_time: Some(query_time).filter(|_| config.timing),
+                },
+            ],
+        );
+        if let Some(limit) = config.max_template_rows
+            && total_rows > limit.get()
+        {
+            self.metrics.count("truncated", 1);
+            self.logger.warn(
+                "render",
+                &format!(
+                    "Truncated {} rows to sqlite_max_template_rows {}",
+                    total_rows,
+                    limit.get()
+                ),
+            );
+        }
+        let rendered_rows = config
+            .max_template_rows
+            .map_or(total_rows, |limit| total_rows.min(limit.get()));
+        let template_key = resolved_template.full_path.as_str();
+        let capacity = self.render_sizes.as_ref().map_or(PAGE_CAPACITY, |sizes| {
+            sizes.estimate(template_key, rendered_rows)
+        });
+        let html = self
+            .template_loader
+            .render_sized("template", &data, capacity)
+            .map_err(|e| {
+                self.logger
+                    .error("render", &format!("Template rendering failed: {}", e));
+                format!("rendering failed: {}", e)
+            })?;
+        if let Some(sizes) = &self.render_sizes {
+            sizes.record(template_key, rendered_rows, html.len());
+        }
+        // A page longer than its buffer had to grow it at least once
+        let outgrown = if html.len() > capacity {
+            "render_outgrown"
+        } else {
+            "render_presized"
+        };
+        self.metrics.count(outgrown, 1);
+        self.metrics.count("render_bytes", html.len() as u64);
+
+        if let Some((trace, rendering)) = trace.as_deref().zip(rendering) {
+            trace.end(rendering, None);
+        }
+        let render_time = self.clock.elapsed().saturating_sub(render_started);
+        self.metrics.count("rows", total_rows as u64);
+        self.metrics.time("query", query_time);
+        self.metrics.time("render", render_time);
+        self.timings = Some(Timings {
+            query: query_time,
+            render: Some(render_time),
+        });
+        if !fallback_used {
+            self.found_rows = total_rows;
+        }
+        Ok(html)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::Trace;
+    use crate::types::{NginxVariable, ParamName};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_resolve_template_path() {
+        let template = TemplatePath::parse("list.hbs").unwrap();
+        let resolved = resolve_template_path(
+            &ValidatedConfig {
+                template_path: Some(template),
+                ..ValidatedConfig::for_test("SELECT whatever")
+            },
+            &RequestPaths::new("server_root", "/books"),
+        )
+        .unwrap();
+
+        assert_eq!(resolved.full_path(), "server_root/books/list.hbs");
+        assert_eq!(resolved.directory(), "server_root/books");
+    }
+
+    #[test]
+    fn test_resolve_template_path_with_trailing_slash() {
+        let template = TemplatePath::parse("index.hbs").unwrap();
+        let resolved = resolve_template_path(
+            &ValidatedConfig {
+                template_path: Some(template),
+                ..ValidatedConfig::for_test("SELECT whatever")
+            },
+            &RequestPaths::new("public/", "/docs/"),
+        )
+        .unwrap();
+
+        assert!(resolved.full_path().contains("public//docs/"));
+    }
+
+    #[test]
+    fn test_resolve_themed_template_path() {
+        let config = ValidatedConfig {
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            theme: Some(Theme::parse("$cookie_theme", &["default=light", "root=/skins/"]).unwrap()),
+            ..ValidatedConfig::for_test("SELECT whatever")
+        };
+        let paths = RequestPaths::new("server_root/", "/books");
+
+        // Without a request, templates come from the default theme
+        let resolved = config.resolve_template_path(&paths).unwrap();
+        assert_eq!(
+            resolved.full_path(),
+            "server_root/skins/light/books/list.hbs"
+        );
+        assert_eq!(resolved.fallback_directory, None);
+
+        let resolved = config
+            .resolve_themed_template_path(&paths, "dark", |_| true)
+            .unwrap();
+        assert_eq!(
+            resolved.full_path(),
+            "server_root/skins/dark/books/list.hbs"
+        );
+        assert_eq!(resolved.directory(), "server_root/skins/dark/books");
+        assert_eq!(
+            resolved.fallback_directory.as_deref(),
+            Some("server_root/skins/light/books")
+        );
+
+        // A theme without the template falls back to the default's, but
+        // still supplies its own partials
+        let resolved = config
+            .resolve_themed_template_path(&paths, "dark", |_| false)
+            .unwrap();
+        assert_eq!(
+            resolved.full_path(),
+            "server_root/skins/light/books/list.hbs"
+        );
+        assert_eq!(resolved.directory(), "server_root/skins/dark/books");
+
+        let resolved = config
+            .resolve_themed_template_path(&paths, "light", |_| true)
+            .unwrap();
+        assert_eq!(resolved.directory(), "server_root/skins/light/books");
+        assert_eq!(resolved.fallback_directory, None);
+    }
+
+    /// The signed selection of a variant: `name.` followed by the hex
+    /// HMAC-SHA256 of the name, keyed with the `sqlite_template_variant` secret
+    fn sign_template_variant(secret: &str, name: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(name.as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{}.{}", name, signature)
+    }
+
+    #[test]
+    fn test_template_variant() {
+        struct Header(String);
+        impl VariableResolver for Header {
+            fn resolve(&mut self, _var_name: &str) -> Result<String, String> {
+                Ok(self.0.clone())
+            }
+        }
+        let header = |value: &str| Header(value.to_string());
+
+        let signed = TemplateVariants::parse(
+            "$http_x_sqlite_template",
+            &["secret=k3y", "variant-b=list-b.hbs"],
+        )
+        .unwrap();
+        let selection = sign_template_variant("k3y", "variant-b");
+        assert_eq!(
+            template_variant(&signed, &mut header(&selection))
+                .unwrap()
+                .as_str(),
+            "list-b.hbs"
+        );
+        // Unsigned, tampered, signed with another key, or not allowlisted
+        assert!(template_variant(&signed, &mut header("variant-b")).is_none());
+        let tampered = selection.replace("variant-b", "variant-c");
+        assert!(template_variant(&signed, &mut header(&tampered)).is_none());
+        let forged = sign_template_variant("guess", "variant-b");
+        assert!(template_variant(&signed, &mut header(&forged)).is_none());
+        let unlisted = sign_template_variant("k3y", "variant-c");
+        assert!(template_variant(&signed, &mut header(&unlisted)).is_none());
+        assert!(template_variant(&signed, &mut header("variant-b.zz")).is_none());
+
+        let unsigned = TemplateVariants::parse("$bucket", &["b=list-b.hbs"]).unwrap();
+        assert!(template_variant(&unsigned, &mut header("b")).is_some());
+        assert!(template_variant(&unsigned, &mut header("a")).is_none());
+    }
+
+    #[test]
+    fn test_active_theme() {
+        struct Cookie(&'static str);
+        impl VariableResolver for Cookie {
+            fn resolve(&mut self, _var_name: &str) -> Result<String, String> {
+                match self.0 {
+                    "" => Err("not set".to_string()),
+                    value => Ok(value.to_string()),
+                }
+            }
+        }
+
+        let theme = Theme::parse("$cookie_theme", &["default=light"]).unwrap();
+        assert_eq!(active_theme(&theme, &mut Cookie("dark")), "dark");
+        assert_eq!(active_theme(&theme, &mut Cookie("")), "light");
+        assert_eq!(active_theme(&theme, &mut Cookie("../../etc")), "light");
+    }
+
+    // Mock implementations for testing
+    struct MockVariableResolver;
+    impl VariableResolver for MockVariableResolver {
+        fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+            match var_name {
+                "$arg_id" => Ok("123".to_string()),
+                "$arg_genre" => Ok("Fiction".to_string()),
+                "$arg_ids" => Ok("1, 2,,3".to_string()),
+                "$arg_empty" => Ok(String::new()),
+                _ => Err(format!("unknown variable: {}", var_name)),
+            }
+        }
+
+        fn resolve_values(&mut self, var_name: &str) -> Result<Vec<String>, String> {
+            match var_name {
+                "$arg_tag" => Ok(vec!["a".to_string(), "b,c".to_string(), String::new()]),
+                _ => self.resolve(var_name).map(|value| vec![value]),
+            }
+        }
+    }
+
+    struct MockQueryExecutor;
+    impl QueryExecutor for MockQueryExecutor {
+        fn execute(
+            &self,
+            _db_path: &DatabasePath,
+            _query: &SqlQuery,
+            _params: &[(String, Value)],
+        ) -> Result<Vec<HashMap<String, Value>>, DbError> {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), Value::Number(1.into()));
+            row.insert("title".to_string(), Value::String("Test Book".to_string()));
+            Ok(vec![row])
+        }
+    }
+
+    struct MockTemplateSystem;
+    impl TemplateLoader for MockTemplateSystem {
+        fn load_from_dir(&mut self, _dir_path: &str) -> Result<usize, String> {
+            Ok(0)
+        }
+        fn register_template(&mut self, _name: &str, _path: &str) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    impl TemplateRenderer for MockTemplateSystem {
+        fn render(&self, _template_name: &str, data: &Value) -> Result<String, String> {
+            Ok(format!("Rendered: {:?}", data))
+        }
+    }
+
+    struct MockLogger;
+    impl Logger for MockLogger {
+        fn log(&self, _level: LogLevel, _module: &str, _message: &str) {
+            // No-op for tests
+        }
+    }
+
+    #[test]
+    fn test_select_query() {
+        let mut config = ValidatedConfig {
+            query_rules: vec![
+                QueryRule::parse("$arg_missing", "SELECT * FROM books WHERE 0").unwrap(),
+                QueryRule::parse("$arg_empty", "SELECT * FROM books WHERE 1").unwrap(),
+                QueryRule::parse("$arg_genre", "SELECT * FROM books WHERE genre = :genre").unwrap(),
+                QueryRule::parse("$arg_id", "SELECT * FROM books WHERE id = :id").unwrap(),
+            ],
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            ..ValidatedConfig::for_test("SELECT * FROM books")
+        };
+
+        struct Resolver;
+        impl VariableResolver for Resolver {
+            fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+                match var_name {
+                    "$arg_empty" => Ok(String::new()),
+                    other => MockVariableResolver.resolve(other),
+                }
+            }
+        }
+
+        // Unknown and empty variables are skipped; first present one wins
+        let selected = select_query(&config, &mut Resolver);
+        assert!(selected.as_str().contains("genre = :genre"));
+
+        config.query_rules.truncate(2);
+        let selected = select_query(&config, &mut Resolver);
+        assert_eq!(selected.as_str(), "SELECT * FROM books");
+    }
+
+    #[test]
+    fn test_sorted_query() {
+        struct Sort(&'static str);
+        impl VariableResolver for Sort {
+            fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+                match var_name {
+                    "$arg_sort" => Ok(self.0.to_string()),
+                    other => Err(format!("variable not found: {}", other)),
+                }
+            }
+        }
+        let query = SqlQuery::parse("SELECT * FROM books -- by id").unwrap();
+        let sort = SortParam::parse("$arg_sort", &["allow=title,year"]).unwrap();
+
+        let sorted = sorted_query(&query, &sort, &mut Sort("-year,title")).unwrap();
+        assert_eq!(
+            sorted.unwrap().as_str(),
+            "SELECT * FROM (SELECT * FROM books -- by id\n) ORDER BY \"year\" DESC, \"title\" ASC"
+        );
+        assert!(
+            sorted_query(&query, &sort, &mut Sort(""))
+                .unwrap()
+                .is_none()
+        );
+
+        let Err(ParamError::Invalid { name, message }) =
+            sorted_query(&query, &sort, &mut Sort("rating"))
+        else {
+            panic!("an unlisted column is refused");
+        };
+        assert_eq!(name, "$arg_sort");
+        assert!(message.contains("cannot sort by 'rating'"));
+
+        // Without the variable the query is left as it is
+        let other = SortParam::parse("$arg_order", &["allow=title"]).unwrap();
+        assert!(
+            sorted_query(&query, &other, &mut Sort("title"))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_filtered_query() {
+        struct Args(HashMap<&'static str, &'static str>);
+        impl VariableResolver for Args {
+            fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+                self.0
+                    .get(var_name)
+                    .map(|value| value.to_string())
+                    .ok_or_else(|| format!("variable not found: {}", var_name))
+            }
+        }
+        let query = SqlQuery::parse("SELECT * FROM books;").unwrap();
+        let filters = vec![
+            Filter::parse("genre", "=", "$arg_genre", None).unwrap(),
+            Filter::parse("year", ">=", "$arg_min_year", Some("int")).unwrap(),
+            Filter::parse("title", "like", "$arg_q", None).unwrap(),
+        ];
+
+        let mut args = Args(HashMap::from([
+            ("$arg_min_year", "1960"),
+            ("$arg_q", "%dune%"),
+        ]));
+        let (filtered, values) = filtered_query(&query, &filters, &mut args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            filtered.as_str(),
+            "SELECT * FROM (SELECT * FROM books\n) WHERE \"year\" >= :filter_2 AND \"title\" LIKE :filter_3"
+        );
+        assert_eq!(
+            values,
+            vec![
+                (":filter_2".to_string(), Value::from(1960)),
+                (":filter_3".to_string(), Value::from("%dune%")),
+            ]
+        );
+
+        // Empty and missing variables add no condition
+        let mut args = Args(HashMap::from([("$arg_genre", "")]));
+        assert!(
+            filtered_query(&query, &filters, &mut args)
+                .unwrap()
+                .is_none()
+        );
+
+        let mut args = Args(HashMap::from([("$arg_min_year", "recent")]));
+        let Err(ParamError::Invalid { name, .. }) = filtered_query(&query, &filters, &mut args)
+        else {
+            panic!("a value that is not an int is refused");
+        };
+        assert_eq!(name, "$arg_min_year");
+    }
+
+    #[test]
+    fn test_dry_run_report() {
+        use crate::config::ModuleConfig;
+        use crate::types::ParamType;
+
+        let config = crate::parsing::parse_config(&ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some(
+                "SELECT * FROM books WHERE genre = :genre AND year >= :year AND kind = :kind"
+                    .to_string(),
+            ),
+            template_path: Some("list.hbs".to_string()),
+            query_params: vec![
+                (":genre".to_string(), "$arg_genre".to_string()),
+                (":year".to_string(), "$arg_year".to_string()),
+                (":kind".to_string(), "novel".to_string()),
+                (":password".to_string(), "$arg_password".to_string()),
+            ],
+            param_types: vec![(":year".to_string(), ParamType::Integer)],
+            param_defaults: vec![(":year".to_string(), "1900".to_string())],
+            filters: vec![Filter::parse("title", "like", "$arg_q", None).unwrap()],
+            write_queries: vec!["INSERT INTO log (genre) VALUES (:genre)".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let dry_run = DryRun::parse("$arg_dry_run", &["redact=:password"]).unwrap();
+        let params = vec![
+            (":genre".to_string(), Value::from("sf")),
+            (":year".to_string(), Value::from(1900)),
+            (":kind".to_string(), Value::from("novel")),
+            (":password".to_string(), Value::from("hunter2")),
+            (":filter_1".to_string(), Value::from("%dune%")),
+            (":arg_page".to_string(), Value::Null),
+        ];
+        let writes: Vec<&WriteQuery> = config.write_queries.iter().map(|(_, w)| w).collect();
+        let report = dry_run_report(&config.query, &writes, &params, &config, &dry_run);
+
+        assert_eq!(report["dry_run"], true);
+        assert_eq!(report["query"], config.query.as_str());
+        assert_eq!(
+            report["writes"],
+            serde_json::json!(["INSERT INTO log (genre) VALUES (:genre)"])
+        );
+        assert_eq!(
+            report["params"],
+            serde_json::json!([
+                { "name": ":genre", "value": "sf", "type": "text", "source": "$arg_genre" },
+                { "name": ":year", "value": 1900, "type": "int", "source": "$arg_year" },
+                { "name": ":kind", "value": "novel", "type": "text", "source": null },
+                { "name": ":password", "value": "[redacted]", "type": "text", "source": "$arg_password" },
+                { "name": ":filter_1", "value": "%dune%", "type": "text", "source": "$arg_q" },
+                { "name": ":arg_page", "value": null, "type": "null", "source": "$arg_page" },
+            ])
+        );
+
+        // Positional values are numbered in order
+        let positional = crate::parsing::parse_config(&ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books WHERE id = ?".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            query_params: vec![(String::new(), "$arg_id".to_string())],
+            ..Default::default()
+        })
+        .unwrap();
+        let params = vec![(String::new(), Value::from("7"))];
+        let report = dry_run_report(&positional.query, &[], &params, &positional, &dry_run);
+        assert_eq!(
+            report["params"],
+            serde_json::json!([{ "name": "?1", "value": "7", "type": "text", "source": "$arg_id" }])
+        );
+    }
+
+    #[test]
+    fn test_pagination() {
+        let pagination = Pagination::parse(&["per_page=20"]).unwrap();
+        let mut config = ValidatedConfig {
+            pagination: Some(pagination.clone()),
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            ..ValidatedConfig::for_test("SELECT * FROM books WHERE genre = :genre;")
+        };
+
+        let (paged, count) = page_queries(&config, &pagination, &PagePosition::Number(3)).unwrap();
+        assert_eq!(
+            paged.as_str(),
+            "SELECT * FROM (SELECT * FROM books WHERE genre = :genre\n) LIMIT 20 OFFSET 40"
+        );
+        assert_eq!(
+            count.unwrap().as_str(),
+            "SELECT COUNT(*) FROM (SELECT * FROM books WHERE genre = :genre\n)"
+        );
+
+        config.count_query = Some(SqlQuery::parse("SELECT n FROM book_counts").unwrap());
+        let (_, count) = page_queries(&config, &pagination, &PagePosition::Number(1)).unwrap();
+        assert_eq!(count.unwrap().as_str(), "SELECT n FROM book_counts");
+
+        // A missing or invalid page number is the first page
+        assert_eq!(requested_page(&pagination, &mut MockVariableResolver), 1);
+        let by_id = Pagination::parse(&["per_page=20", "param=id"]).unwrap();
+        assert_eq!(requested_page(&by_id, &mut MockVariableResolver), 123);
+        let by_genre = Pagination::parse(&["per_page=20", "param=genre"]).unwrap();
+        assert_eq!(requested_page(&by_genre, &mut MockVariableResolver), 1);
+
+        let row = |value: Value| HashMap::from([("COUNT(*)".to_string(), value)]);
+        assert_eq!(total_rows(&[row(serde_json::json!(45))]).unwrap(), 45);
+        assert!(total_rows(&[]).is_err());
+        assert!(total_rows(&[row(serde_json::json!("many"))]).is_err());
+
+        assert_eq!(
+            pagination_context(2, &pagination, 45),
+            serde_json::json!({
+                "page": 2, "per_page": 20, "total": 45, "page_count": 3, "prev": 1, "next": 3,
+                "count": "exact",
+            })
+        );
+        let last = pagination_context(3, &pagination, 45);
+        assert_eq!(
+            (last["prev"].clone(), last["next"].clone()),
+            (serde_json::json!(2), Value::Null)
+        );
+        let past_the_end = pagination_context(9, &pagination, 45);
+        assert_eq!(past_the_end["prev"], 3);
+        let empty = pagination_context(1, &pagination, 0);
+        assert_eq!(
+            (empty["page_count"].clone(), empty["prev"].clone()),
+            (serde_json::json!(0), Value::Null)
+        );
+
+        // sqlite_count none: no count query, and one row more than a page
+        let uncounted = pagination.clone().with_count(CountStrategy::None);
+        let (paged, count) = page_queries(&config, &uncounted, &PagePosition::Number(3)).unwrap();
+        assert!(paged.as_str().ends_with("LIMIT 21 OFFSET 40"));
+        assert!(count.is_none());
+        let mut rows = vec![HashMap::new(); 21];
+        let context = page_context(&PagePosition::Number(3), &uncounted, None, &mut rows).unwrap();
+        assert_eq!(
+            context,
+            serde_json::json!({
+                "page": 3, "per_page": 20, "total": null, "page_count": null, "prev": 2, "next": 4,
+                "count": "none",
+            })
+        );
+        assert_eq!(rows.len(), 20);
+        rows.truncate(5);
+        let context = page_context(&PagePosition::Number(1), &uncounted, None, &mut rows).unwrap();
+        assert_eq!(
+            (context["prev"].clone(), context["next"].clone()),
+            (Value::Null, Value::Null)
+        );
+        assert_eq!(rows.len(), 5);
+
+        // An estimate is reported as such
+        let estimated = pagination.with_count(CountStrategy::Estimate(None));
+        let context =
+            page_context(&PagePosition::Number(1), &estimated, Some(45), &mut rows).unwrap();
+        assert_eq!(
+            (context["total"].clone(), context["count"].clone()),
+            (serde_json::json!(45), serde_json::json!("estimate"))
+        );
+    }
+
+    #[test]
+    fn test_form_decode() {
+        assert_eq!(form_decode(b"science%20fiction"), "science fiction");
+        assert_eq!(form_decode(b"science+fiction"), "science fiction");
+        assert_eq!(form_decode(b"caf%C3%A9%2C%2B1"), "café,+1");
+        // A stray percent is kept, and bytes that are not UTF-8 are replaced
+        assert_eq!(form_decode(b"100%"), "100%");
+        assert_eq!(form_decode(b"%zz%4"), "%zz%4");
+        assert_eq!(form_decode(b"%FF"), "\u{fffd}");
+    }
+
+    #[test]
+    fn test_json_path() {
+        let doc = serde_json::json!({
+            "title": "Dune",
+            "tags": ["sf", "classic"],
+            "meta data": {"pages": 412},
+        });
+        let at = |path| json_path(&doc, path).unwrap().cloned();
+        assert_eq!(at("$"), Some(doc.clone()));
+        assert_eq!(at("$.title"), Some(serde_json::json!("Dune")));
+        assert_eq!(at("$.tags[1]"), Some(serde_json::json!("classic")));
+        assert_eq!(at("$.tags[#-1]"), Some(serde_json::json!("classic")));
+        assert_eq!(at("$.\"meta data\".pages"), Some(serde_json::json!(412)));
+        assert_eq!(at("$.tags[2]"), None);
+        assert_eq!(at("$.tags[#]"), None);
+        assert_eq!(at("$.tags[#-3]"), None);
+        assert_eq!(at("$.title.length"), None);
+        assert_eq!(at("$.missing[0]"), None);
+
+        for path in [
+            "title",
+            "$.",
+            "$[x]",
+            "$.tags[0",
+            "$.\"open",
+            "$tags",
+            "$.tags[#1]",
+        ] {
+            assert!(json_path(&doc, path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_cursor_pagination() {
+        let pagination =
+            Pagination::parse(&["per_page=2", "mode=cursor", "key=id", "secret=s3cret"]).unwrap();
+        let mut config = ValidatedConfig {
+            pagination: Some(pagination.clone()),
+            ..ValidatedConfig::for_test("SELECT id, title FROM books;")
+        };
+
+        let paged = |config: &ValidatedConfig, page: PagePosition| {
+            let pagination = config.pagination.as_ref().unwrap();
+            let (paged, count) = page_queries(config, pagination, &page).unwrap();
+            assert!(count.is_none(), "cursor pages are not counted");
+            paged.as_str().to_string()
+        };
+        let inner = "SELECT * FROM (SELECT id, title FROM books\n)";
+        assert_eq!(
+            paged(&config, PagePosition::Number(1)),
+            format!("{} ORDER BY \"id\" ASC LIMIT 3", inner)
+        );
+        assert_eq!(
+            paged(&config, PagePosition::After(Value::from(7))),
+            format!("{} WHERE \"id\" > 7 ORDER BY \"id\" ASC LIMIT 3", inner)
+        );
+        assert_eq!(
+            paged(&config, PagePosition::Before(Value::from("it's"))),
+            format!(
+                "{} WHERE \"id\" < 'it''s' ORDER BY \"id\" DESC LIMIT 3",
+                inner
+            )
+        );
+        config.pagination = Some(
+            Pagination::parse(&[
+                "per_page=2",
+                "mode=cursor",
+                "key=id",
+                "secret=s3cret",
+                "order=desc",
+            ])
+            .unwrap(),
+        );
+        assert_eq!(
+            paged(&config, PagePosition::After(Value::from(7))),
+            format!("{} WHERE \"id\" < 7 ORDER BY \"id\" DESC LIMIT 3", inner)
+        );
+
+        // Only cursors signed with the secret are honoured
+        struct Cursors(&'static str, String);
+        impl VariableResolver for Cursors {
+            fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+                if var_name == self.0 {
+                    Ok(self.1.clone())
+                } else {
+                    Err("not set".to_string())
+                }
+            }
+        }
+        let signed = encode_cursor("s3cret", &Value::from(7));
+        assert_eq!(
+            requested_position(&pagination, &mut Cursors("$arg_after", signed.clone())),
+            Ok(PagePosition::After(Value::from(7)))
+        );
+        assert_eq!(
+            requested_position(&pagination, &mut Cursors("$arg_before", signed.clone())),
+            Ok(PagePosition::Before(Value::from(7)))
+        );
+        assert_eq!(
+            requested_position(&pagination, &mut MockVariableResolver),
+            Ok(PagePosition::Number(1))
+        );
+        let forged = encode_cursor("guess", &Value::from(7));
+        assert_eq!(
+            requested_position(&pagination, &mut Cursors("$arg_after", forged)),
+            Err(ParamError::Invalid {
+                name: "after".to_string(),
+                message: "is not a valid cursor".to_string(),
+            })
+        );
+        let tampered = format!(
+            "{}{}",
+            encode_hex(b"8"),
+            &signed[signed.find('.').unwrap()..]
+        );
+        assert!(requested_position(&pagination, &mut Cursors("$arg_after", tampered)).is_err());
+
+        let rows = |ids: &[i64]| -> Vec<HashMap<String, Value>> {
+            ids.iter()
+                .map(|&id| HashMap::from([("id".to_string(), Value::from(id))]))
+                .collect()
+        };
+        let ids = |rows: &[HashMap<String, Value>]| -> Vec<i64> {
+            rows.iter().map(|row| row["id"].as_i64().unwrap()).collect()
+        };
+
+        // The extra row says another page follows, and is dropped
+        let mut first = rows(&[1, 2, 3]);
+        let context = cursor_context(&PagePosition::Number(1), &pagination, &mut first).unwrap();
+        assert_eq!(ids(&first), vec![1, 2]);
+        assert_eq!(context["per_page"], 2);
+        assert_eq!(context["next"], encode_cursor("s3cret", &Value::from(2)));
+        assert_eq!(context["prev"], Value::Null);
+
+        let mut last = rows(&[5]);
+        let context =
+            cursor_context(&PagePosition::After(Value::from(4)), &pagination, &mut last).unwrap();
+        assert_eq!(context["next"], Value::Null);
+        assert_eq!(context["prev"], encode_cursor("s3cret", &Value::from(5)));
+
+        // A page before a cursor comes back in reverse and is put in order
+        let mut before = rows(&[4, 3, 2]);
+        let context = cursor_context(
+            &PagePosition::Before(Value::from(5)),
+            &pagination,
+            &mut before,
+        )
+        .unwrap();
+        assert_eq!(ids(&before), vec![3, 4]);
+        assert_eq!(context["next"], encode_cursor("s3cret", &Value::from(4)));
+        assert_eq!(context["prev"], encode_cursor("s3cret", &Value::from(3)));
+
+        let mut unkeyed = vec![HashMap::from([("title".to_string(), Value::from("Dune"))]); 3];
+        let error = cursor_context(&PagePosition::Number(1), &pagination, &mut unkeyed);
+        assert!(error.unwrap_err().contains("result column"));
+    }
+
+    #[test]
+    fn test_pagination_links() {
+        let pagination = Pagination::parse(&["per_page=20", "param=p"]).unwrap();
+        let middle = pagination_context(2, &pagination, 45);
+        assert_eq!(
+            pagination_links("/books?genre=sf&P=2&x", &pagination, &middle).unwrap(),
+            "</books?genre=sf&x&p=1>; rel=\"first\", </books?genre=sf&x&p=1>; rel=\"prev\", \
+             </books?genre=sf&x&p=3>; rel=\"next\", </books?genre=sf&x&p=3>; rel=\"last\""
+        );
+        let first = pagination_context(1, &pagination, 45);
+        assert_eq!(
+            pagination_links("/books", &pagination, &first).unwrap(),
+            "</books?p=1>; rel=\"first\", </books?p=2>; rel=\"next\", </books?p=3>; rel=\"last\""
+        );
+        let empty = pagination_context(1, &pagination, 0);
+        assert_eq!(pagination_links("/books", &pagination, &empty), None);
+
+        let cursors =
+            Pagination::parse(&["per_page=20", "mode=cursor", "key=id", "secret=s"]).unwrap();
+        let context = serde_json::json!({"per_page": 20, "next": "6e.ff", "prev": null});
+        assert_eq!(
+            pagination_links("/events?before=1.2&q=x", &cursors, &context).unwrap(),
+            "</events?q=x&after=6e.ff>; rel=\"next\""
+        );
+    }
+
+    #[test]
+    fn test_run_stages() {
+        /// Notes that it ran, and answers when its name is `answer`
+        struct Step(&'static str);
+        impl Stage<Vec<&'static str>, u16> for Step {
+            fn name(&self) -> &'static str {
+                self.0
+            }
+            fn run(&self, ran: &mut Vec<&'static str>) -> Flow<u16> {
+                ran.push(self.0);
+                match self.0 {
+                    "answer" => Flow::Respond(200),
+                    _ => Flow::Continue,
+                }
+            }
+        }
+
+        let stages: Vec<Box<dyn Stage<Vec<&'static str>, u16>>> = vec![
+            Box::new(Step("params")),
+            Box::new(Step("answer")),
+            Box::new(Step("never")),
+        ];
+        let mut ran = Vec::new();
+        assert_eq!(run_stages(&stages, &mut ran), Some(("answer", 200)));
+        assert_eq!(ran, vec!["params", "answer"]);
+
+        let mut ran = Vec::new();
+        assert_eq!(run_stages(&stages[..1], &mut ran), None);
+        assert_eq!(ran, vec!["params"]);
+    }
+
+    #[test]
+    fn test_route_method() {
+        let mut config = ValidatedConfig {
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            ..ValidatedConfig::for_test("SELECT * FROM books")
+        };
+
+        // Without write statements every method reads
+        assert_eq!(route_method(&config, "POST"), MethodRoute::Read);
+
+        let insert = WriteQuery::parse("INSERT INTO books (title) VALUES (:title)").unwrap();
+        let count = WriteQuery::parse("UPDATE stats SET books = books + 1").unwrap();
+        config.write_queries = vec![
+            (WriteMethod::Post, insert.clone()),
+            (WriteMethod::Put, insert.clone()),
+            (WriteMethod::Post, count.clone()),
+        ];
+        assert_eq!(route_method(&config, "GET"), MethodRoute::Read);
+        assert_eq!(route_method(&config, "HEAD"), MethodRoute::Read);
+        assert!(matches!(
+            route_method(&config, "PUT"),
+            MethodRoute::Write(WriteMethod::Put, _)
+        ));
+        assert_eq!(route_method(&config, "DELETE"), MethodRoute::NotAllowed);
+        // A method runs all of its statements, in configuration order
+        assert_eq!(
+            route_method(&config, "POST"),
+            MethodRoute::Write(WriteMethod::Post, vec![&insert, &count])
+        );
+
+        assert_eq!(write_response(WriteMethod::Post), WriteResponse::Created);
+        assert_eq!(
+            write_response(WriteMethod::Delete),
+            WriteResponse::NoContent
+        );
+    }
+
+    #[test]
+    fn test_parse_parameter_sets() {
+        let defaults = vec![
+            (":user".to_string(), Value::from("alice")),
+            (":title".to_string(), Value::from("untitled")),
+        ];
+        let body = br#"[{"title": "Dune", "year": 1965}, {"@year": null}]"#;
+        let sets = parse_parameter_sets(body, &defaults).unwrap();
+
+        assert_eq!(sets.len(), 2);
+        // Body values override the location's parameters of the same name
+        assert_eq!(
+            sets[0],
+            vec![
+                (":user".to_string(), Value::from("alice")),
+                (":title".to_string(), Value::from("Dune")),
+                (":year".to_string(), Value::from(1965)),
+            ]
+        );
+        assert!(sets[1].contains(&("@year".to_string(), Value::Null)));
+        assert!(sets[1].contains(&(":title".to_string(), Value::from("untitled"))));
+
+        // Arrays bind by position, without the location's parameters
+        let sets = parse_parameter_sets(br#"[[1, "a"]]"#, &defaults).unwrap();
+        assert_eq!(
+            sets[0],
+            vec![
+                (String::new(), Value::from(1)),
+                (String::new(), Value::from("a"))
+            ]
+        );
+
+        assert!(parse_parameter_sets(b"[]", &defaults).unwrap().is_empty());
+        assert!(parse_parameter_sets(b"{}", &defaults).is_err());
+        assert!(parse_parameter_sets(b"[1]", &defaults).is_err());
+        let error = parse_parameter_sets(br#"[{}, {"tags": [1]}]"#, &defaults).unwrap_err();
+        assert_eq!(error, "parameter set 1: value for :tags must be a scalar");
+    }
+
+    #[test]
+    fn test_error_status_and_body() {
+        assert_eq!(ErrorCode::ConstraintUnique.http_status(), 409);
+        assert_eq!(ErrorCode::ConstraintNotNull.http_status(), 422);
+        assert_eq!(ErrorCode::Busy.http_status(), 503);
+        assert_eq!(ErrorCode::TooManyRows.http_status(), 400);
+        assert_eq!(ErrorCode::Corrupt.http_status(), 500);
+
+        let failure = DbError::new(
+            ErrorCode::ConstraintUnique,
+            "UNIQUE constraint failed: users.email",
+        );
+        assert_eq!(
+            error_body("Write execution failed", &failure, true),
+            serde_json::json!({
+                "error": "Write execution failed",
+                "code": "constraint_unique",
+                "details": "UNIQUE constraint failed: users.email",
+                "errors": {"email": "already exists"},
+                "rolled_back": true
+            })
+        );
+        let body = error_body("Query execution failed", &DbError::internal("boom"), false);
+        assert_eq!(body["code"], "internal");
+        assert!(body.get("rolled_back").is_none());
+        assert!(body.get("errors").is_none());
+    }
+
+    #[test]
+    fn test_build_form_context() {
+        let params = vec![
+            (":title".to_string(), Value::from("Dune")),
+            (":tags".to_string(), Value::from("a")),
+            (":tags".to_string(), Value::from("b")),
+            (String::new(), Value::from("positional")),
+        ];
+        let failure = DbError::new(
+            ErrorCode::ConstraintNotNull,
+            "NOT NULL constraint failed: books.author",
+        );
+
+        assert_eq!(
+            build_form_context(&params, &failure),
+            serde_json::json!({
+                "values": {"title": "Dune", "tags": ["a", "b"]},
+                "errors": {"author": "is required"},
+                "error": {
+                    "code": "constraint_not_null",
+                    "details": "NOT NULL constraint failed: books.author",
+                },
+            })
+        );
+
+        let mut processor =
+            RequestProcessor::new(MockQueryExecutor, MockTemplateSystem, MockLogger);
+        let form = ResolvedTemplate {
+            full_path: "/srv/books/form.hbs".to_string(),
+            directory: "/srv/books".to_string(),
+            fallback_directory: None,
+        };
+        let html = processor
+            .render_form(&form, &build_form_context(&params, &failure), None)
+            .unwrap();
+        assert!(html.contains("is required"));
+    }
+
+    #[test]
+    fn test_export_uris() {
+        let row = |uri: Value| HashMap::from([("uri".to_string(), uri)]);
+        let rows = vec![
+            row(Value::from("/")),
+            row(Value::from("/book/1")),
+            row(Value::from("/book/1")),
+        ];
+        assert_eq!(export_uris(&rows).unwrap(), vec!["/", "/book/1"]);
+        assert!(export_uris(&[]).unwrap().is_empty());
+
+        assert!(export_uris(&[row(Value::from("book/1"))]).is_err());
+        assert!(export_uris(&[row(Value::from("/books?page=2"))]).is_err());
+        assert!(export_uris(&[row(Value::from(1))]).is_err());
+        let no_uri = vec![HashMap::from([("path".to_string(), Value::from("/"))])];
+        assert!(export_uris(&no_uri).unwrap_err().contains("uri column"));
+    }
+
+    #[test]
+    fn test_requested_expansions() {
+        struct Expand(&'static str);
+        impl VariableResolver for Expand {
+            fn resolve(&mut self, _var_name: &str) -> Result<String, String> {
+                Ok(self.0.to_string())
+            }
+        }
+        let expansions = vec![
+            Expansion::parse("author", "SELECT * FROM authors WHERE id = :author_id", &[]).unwrap(),
+            Expansion::parse(
+                "reviews",
+                "SELECT * FROM reviews WHERE book_id = :id",
+                &["many"],
+            )
+            .unwrap(),
+        ];
+        let names = |requested| {
+            requested_expansions(&expansions, &mut Expand(requested))
+                .map(|selected| selected.iter().map(|e| e.name()).collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            names("reviews, author,reviews"),
+            Ok(vec!["reviews", "author"])
+        );
+        assert_eq!(names(""), Ok(vec![]));
+        assert_eq!(
+            names("author,password"),
+            Err(ParamError::Invalid {
+                name: "expand".to_string(),
+                message: "must name one of author, reviews".to_string(),
+            })
+        );
+        // Locations without expansions ignore the argument
+        assert!(
+            requested_expansions(&[], &mut Expand("author"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_expand_relations() {
+        // Answers an expansion query with the related rows for the bound keys
+        struct Related(Mutex<Vec<usize>>);
+        impl QueryExecutor for Related {
+            fn execute(
+                &self,
+                _db_path: &DatabasePath,
+                query: &SqlQuery,
+                params: &[(String, Value)],
+            ) -> Result<Vec<HashMap<String, Value>>, DbError> {
+                self.0.lock().unwrap().push(params.len());
+                let keys: Vec<&Value> = params.iter().map(|(_, key)| key).collect();
+                let rows: Vec<(i64, &str)> = if query.as_str().contains("authors") {
+                    vec![(1, "Le Guin"), (2, "Butler")]
+                } else {
+                    vec![(10, "Superb"), (10, "Fine"), (11, "Dull")]
+                };
+                Ok(rows
+                    .into_iter()
+                    .filter(|(id, _)| keys.iter().any(|key| key.to_string() == id.to_string()))
+                    .map(|(id, text)| {
+                        let column = if query.as_str().contains("authors") {
+                            "id"
+                        } else {
+                            "book_id"
+                        };
+                        HashMap::from([
+                            (column.to_string(), Value::from(id)),
+                            ("text".to_string(), Value::from(text)),
+                        ])
+                    })
+                    .collect())
+            }
+        }
+
+        let book = |id: i64, author: Value| {
+            HashMap::from([
+                ("id".to_string(), Value::from(id)),
+                ("author_id".to_string(), author),
+            ])
+        };
+        let mut rows = vec![
+            book(10, Value::from(1)),
+            book(11, Value::from("1")),
+            book(12, Value::from(2)),
+            book(13, Value::Null),
+        ];
+        let author =
+            Expansion::parse("author", "SELECT * FROM authors WHERE id = :author_id", &[]).unwrap();
+        let reviews = Expansion::parse(
+            "reviews",
+            "SELECT * FROM reviews WHERE book_id = :id",
+            &["many"],
+        )
+        .unwrap();
+        let executor = Related(Mutex::new(Vec::new()));
+        let db = DatabasePath::parse("catalog.db").unwrap();
+        let trace = Trace::new();
+        expand_relations(
+            &mut rows,
+            &[&author, &reviews],
+            &executor,
+            &db,
+            Some(&trace),
+        )
+        .unwrap();
+
+        // One query per expansion, over the distinct keys
+        assert_eq!(*executor.0.lock().unwrap(), vec![2, 4]);
+        let operations = trace.to_json();
+        assert_eq!(operations[0]["operation"], "expand author");
+        assert_eq!(operations[0]["children"][0]["operation"], "batch query");
+        assert_eq!(operations[1]["operation"], "expand reviews");
+        assert_eq!(rows[0]["author"]["text"], "Le Guin");
+        assert_eq!(rows[1]["author"]["text"], "Le Guin");
+        assert_eq!(rows[2]["author"]["text"], "Butler");
+        assert_eq!(rows[3]["author"], Value::Null);
+        assert_eq!(rows[0]["reviews"].as_array().unwrap().len(), 2);
+        assert_eq!(rows[1]["reviews"][0]["text"], "Dull");
+        assert_eq!(rows[2]["reviews"], serde_json::json!([]));
+
+        // Related rows must carry the compared column
+        let unmatched = Expansion::parse(
+            "author",
+            "SELECT id, title FROM authors WHERE author_key = :author_id",
+            &[],
+        )
+        .unwrap();
+        let error = expand_relations(&mut rows, &[&unmatched], &MockQueryExecutor, &db, None);
+        assert!(
+            error
+                .unwrap_err()
+                .to_string()
+                .contains("must select author_key")
+        );
+    }
+
+    #[test]
+    fn test_select_fields() {
+        let author =
+            Expansion::parse("author", "SELECT * FROM authors WHERE id = :author_id", &[]).unwrap();
+        let reviews = Expansion::parse(
+            "reviews",
+            "SELECT * FROM reviews WHERE book_id = :id",
+            &["many"],
+        )
+        .unwrap();
+        let columns: Vec<String> = ["id", "title", "author_id"].map(String::from).to_vec();
+        let related = vec![
+            (&author, vec!["id".to_string(), "name".to_string()]),
+            (&reviews, vec!["book_id".to_string(), "text".to_string()]),
+        ];
+
+        let (selection, expanded) =
+            select_fields(" id, title,author.name ,id", &columns, &related).unwrap();
+        assert_eq!(
+            selection.names(),
+            vec!["author", "author.name", "id", "title"]
+        );
+        assert_eq!(expanded.len(), 1);
+        assert_eq!(expanded[0].name(), "author");
+
+        // A relation named on its own keeps every field
+        let (selection, expanded) =
+            select_fields("reviews.text,reviews,author", &columns, &related).unwrap();
+        assert_eq!(selection.names(), vec!["author", "reviews"]);
+        let names: Vec<&str> = expanded.iter().map(|e| e.name()).collect();
+        assert_eq!(names, vec!["reviews", "author"]);
+
+        let mut rows = vec![HashMap::from([
+            ("id".to_string(), Value::from(1)),
+            ("title".to_string(), Value::from("Kindred")),
+            ("author_id".to_string(), Value::from(2)),
+            (
+                "author".to_string(),
+                serde_json::json!({"id": 2, "name": "Butler"}),
+            ),
+            (
+                "reviews".to_string(),
+                serde_json::json!([{"book_id": 1, "text": "Superb"}]),
+            ),
+        ])];
+        let (selection, _) =
+            select_fields("title,author.name,reviews.text", &columns, &related).unwrap();
+        apply_fields(&mut rows, &selection);
+        assert_eq!(
+            serde_json::json!(rows),
+            serde_json::json!([{
+                "title": "Kindred",
+                "author": {"name": "Butler"},
+                "reviews": [{"text": "Superb"}],
+            }])
+        );
+
+        for unknown in ["isbn", "author.email", "title.text", "publisher.name"] {
+            let Err(ParamError::Invalid { name, message }) =
+                select_fields(unknown, &columns, &related)
+            else {
+                panic!("{} should be refused", unknown);
+            };
+            assert_eq!(name, "fields");
+            assert!(message.contains("id, title, author_id, author, author.id"));
+        }
+
+        // sqlite_allow_fields narrows what may be asked for
+        let allowed: Vec<String> = ["title", "author"].map(String::from).to_vec();
+        let (columns, related) = allowed_fields(&allowed, columns, related);
+        assert_eq!(columns, vec!["title"]);
+        assert!(select_fields("title,author.name", &columns, &related).is_ok());
+        let Err(ParamError::Invalid { message, .. }) =
+            select_fields("id,reviews", &columns, &related)
+        else {
+            panic!("fields outside the allowlist should be refused");
+        };
+        assert_eq!(
+            message,
+            "must name one of title, author, author.id, author.name"
+        );
+        let (all, _) = allowed_fields(&[], vec!["id".to_string()], vec![]);
+        assert_eq!(all, vec!["id"]);
+    }
+
+    #[test]
+    fn test_expand_json() {
+        let row = || {
+            HashMap::from([
+                ("tags".to_string(), Value::from(r#"["sf","classic"]"#)),
+                ("author".to_string(), Value::from(r#"{"name":"Le Guin"}"#)),
+                ("year".to_string(), Value::from("1969")),
+                ("note".to_string(), Value::from("[draft")),
+                ("pages".to_string(), Value::from(304)),
+            ])
+        };
+
+        let mut rows = vec![row()];
+        expand_json(&mut rows, &JsonExpansion::Off);
+        assert_eq!(rows[0]["tags"], Value::from(r#"["sf","classic"]"#));
+
+        expand_json(&mut rows, &JsonExpansion::All);
+        assert_eq!(rows[0]["tags"], serde_json::json!(["sf", "classic"]));
+        assert_eq!(rows[0]["author"]["name"], "Le Guin");
+        // Scalars and text that does not parse are left as they are
+        assert_eq!(rows[0]["year"], Value::from("1969"));
+        assert_eq!(rows[0]["note"], Value::from("[draft"));
+        assert_eq!(rows[0]["pages"], Value::from(304));
+
+        let mut rows = vec![row()];
+        let columns = JsonExpansion::Columns(vec!["year".to_string(), "tags".to_string()]);
+        expand_json(&mut rows, &columns);
+        assert_eq!(rows[0]["year"], Value::from(1969));
+        assert_eq!(rows[0]["tags"][1], "classic");
+        assert_eq!(rows[0]["author"], Value::from(r#"{"name":"Le Guin"}"#));
+    }
+
+    #[test]
+    fn test_omit_nulls() {
+        let mut rows = vec![
+            HashMap::from([
+                ("id".to_string(), Value::from(1)),
+                ("subtitle".to_string(), Value::Null),
+                (
+                    "author".to_string(),
+                    serde_json::json!({ "name": "Le Guin", "died": null }),
+                ),
+                ("tags".to_string(), serde_json::json!(["sf", null])),
+            ]),
+            HashMap::from([("id".to_string(), Value::Null)]),
+        ];
+        omit_nulls(&mut rows);
+        assert_eq!(
+            serde_json::json!(rows),
+            serde_json::json!([
+                { "id": 1, "author": { "name": "Le Guin" }, "tags": ["sf", null] },
+                {}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_compose_email() {
+        let params = vec![
+            (":name".to_string(), Value::from("Ada")),
+            (":email".to_string(), Value::from(" ada@example.com ")),
+        ];
+        let outcome = WriteOutcome {
+            changes: 1,
+            last_insert_rowid: 42,
+        };
+        assert_eq!(
+            build_email_context(WriteMethod::Post, &params, &outcome),
+            serde_json::json!({
+                "method": "POST",
+                "values": {"name": "Ada", "email": " ada@example.com "},
+                "changes": 1,
+                "last_insert_rowid": 42,
+            })
+        );
+
+        let notification = EmailNotification::parse(&[
+            "template=contact.hbs",
+            "to=a@example.com,b@example.com",
+            "from=site@example.com",
+            "subject=New message",
+            "reply_to=email",
+        ])
+        .unwrap();
+        assert_eq!(
+            compose_email(&notification, &params, "Hello"),
+            "To: a@example.com, b@example.com\n\
+             From: site@example.com\n\
+             Reply-To: ada@example.com\n\
+             Subject: New message\n\
+             MIME-Version: 1.0\n\
+             Content-Type: text/plain; charset=utf-8\n\
+             Content-Transfer-Encoding: 8bit\n\
+             \n\
+             Hello\n"
+        );
+
+        // A submitted address that could smuggle in headers is dropped
+        let forged = vec![(
+            ":email".to_string(),
+            Value::from("x@example.com\nBcc: victim@example.com"),
+        )];
+        let message = compose_email(&notification, &forged, "Hello\n");
+        assert!(!message.contains("Bcc"));
+        assert!(!message.contains("Reply-To"));
+    }
+
+    #[test]
+    fn test_captcha_verification() {
+        let captcha = Captcha::parse(&["provider=turnstile", "secret=a&b"]).unwrap();
+        assert_eq!(
+            captcha_verify_args(&captcha, "tok en/+", Some("203.0.113.7")),
+            "secret=a%26b&response=tok%20en%2F%2B&remoteip=203.0.113.7"
+        );
+        assert_eq!(
+            captcha_verify_args(&captcha, "t", None),
+            "secret=a%26b&response=t"
+        );
+
+        assert!(captcha_passed(
+            br#"{"success": true, "hostname": "example.com"}"#
+        ));
+        assert!(!captcha_passed(
+            br#"{"success": false, "error-codes": ["invalid-input-response"]}"#
+        ));
+        assert!(!captcha_passed(br#"{"success": "true"}"#));
+        assert!(!captcha_passed(b"<html>Bad Gateway</html>"));
+    }
+
+    #[test]
+    fn test_honeypot_rejection() {
+        struct Form(&'static str, &'static str);
+        impl VariableResolver for Form {
+            fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+                match var_name {
+                    "$arg_website" => Ok(self.0.to_string()),
+                    "$arg_form_time" if !self.1.is_empty() => Ok(self.1.to_string()),
+                    _ => Err(format!("variable not valid: {}", var_name)),
+                }
+            }
+        }
+
+        let honeypot = Honeypot::parse(&["field=website"]).unwrap();
+        assert_eq!(honeypot_rejection(&honeypot, &mut Form("", ""), 1000), None);
+        assert!(
+            honeypot_rejection(&honeypot, &mut Form("http://spam", ""), 1000)
+                .unwrap()
+                .contains("'website'")
+        );
+
+        let honeypot = Honeypot::parse(&["field=website", "min_time=3"]).unwrap();
+        assert_eq!(
+            honeypot_rejection(&honeypot, &mut Form("", "990"), 1000),
+            None
+        );
+        assert!(
+            honeypot_rejection(&honeypot, &mut Form("", "999"), 1000)
+                .unwrap()
+                .contains("submitted 1s")
+        );
+        // Forms rendered "in the future" and forms without a time are rejected
+        assert!(honeypot_rejection(&honeypot, &mut Form("", "2000"), 1000).is_some());
+        assert!(
+            honeypot_rejection(&honeypot, &mut Form("", ""), 1000)
+                .unwrap()
+                .contains("missing")
+        );
+    }
+
+    #[test]
+    fn test_field_errors() {
+        let errors =
+            |code, message: &str| field_errors(&DbError::new(code, message)).map(Value::Object);
+
+        assert_eq!(
+            errors(
+                ErrorCode::ConstraintUnique,
+                "parameter set 2: UNIQUE constraint failed: users.first, users.last"
+            ),
+            Some(serde_json::json!({"first": "already exists", "last": "already exists"}))
+        );
+        assert_eq!(
+            errors(
+                ErrorCode::ConstraintNotNull,
+                "NOT NULL constraint failed: users.email"
+            ),
+            Some(serde_json::json!({"email": "is required"}))
+        );
+        assert_eq!(
+            errors(
+                ErrorCode::ConstraintCheck,
+                "CHECK constraint failed: age >= 0"
+            ),
+            Some(serde_json::json!({"age": "is invalid"}))
+        );
+        assert_eq!(
+            errors(
+                ErrorCode::ConstraintCheck,
+                "CHECK constraint failed: length(name) > 0"
+            ),
+            Some(serde_json::json!({"length": "is invalid"}))
+        );
+        assert_eq!(
+            errors(
+                ErrorCode::ConstraintForeignKey,
+                "FOREIGN KEY constraint failed"
+            ),
+            None
+        );
+        assert_eq!(errors(ErrorCode::ConstraintUnique, "something else"), None);
+    }
+
+    #[test]
+    fn test_is_batch() {
+        let mut config = ValidatedConfig::for_test("SELECT * FROM books");
+        let insert = WriteQuery::parse("INSERT INTO books (title) VALUES (:title)").unwrap();
+        let write = MethodRoute::Write(WriteMethod::Post, vec![&insert]);
+
+        assert!(!is_batch(&config, &write, "POST"));
+
+        config.batch = true;
+        assert!(is_batch(&config, &write, "POST"));
+        assert!(is_batch(&config, &MethodRoute::Read, "POST"));
+        assert!(!is_batch(&config, &MethodRoute::Read, "GET"));
+        assert!(!is_batch(&config, &MethodRoute::NotAllowed, "PUT"));
+    }
+
+    #[test]
+    fn test_resolve_parameters_positional() {
+        let bindings = vec![ParameterBinding::Positional {
+            variable: NginxVariable::parse("$arg_id").unwrap(),
+            max_length: None,
+        }];
+
+        let mut resolver = MockVariableResolver;
+        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
+
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].0, ""); // Positional (no name)
+        assert_eq!(resolved[0].1, "123");
+    }
+
+    #[test]
+    fn test_resolve_parameters_named() {
+        let bindings = vec![ParameterBinding::Named {
+            name: ParamName::parse(":book_id").unwrap(),
+            variable: NginxVariable::parse("$arg_id").unwrap(),
+            param_type: ParamType::Text,
+            default: None,
+            required: false,
+            pattern: None,
+            max_length: None,
+        }];
+
+        let mut resolver = MockVariableResolver;
+        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
+
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].0, ":book_id");
+        assert_eq!(resolved[0].1, "123");
+    }
+
+    #[test]
+    fn test_resolve_parameters_by_index() {
+        // Only the variable nginx indexed is looked up by its index
+        struct Indexed;
+        impl VariableResolver for Indexed {
+            fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+                MockVariableResolver.resolve(var_name)
+            }
+
+            fn resolve_variable(&mut self, variable: &NginxVariable) -> Result<String, String> {
+                match variable.index() {
+                    Some(index) => Ok(format!("#{}", index)),
+                    None => self.resolve(variable.as_str()),
+                }
+            }
+        }
+        let named = |name: &str, variable: &str| ParameterBinding::OptionalNamed {
+            name: ParamName::parse(name).unwrap(),
+            variable: NginxVariable::parse(variable).unwrap(),
+            max_length: None,
+        };
+        let bindings: Vec<ParameterBinding> = vec![
+            named(":id", "$arg_id"),
+            named(":genre", "$arg_genre"),
+            named(":title", "$json_body.title"),
+        ]
+        .into_iter()
+        .map(|binding| binding.with_variable_index(&[("$arg_id".to_string(), 4)]))
+        .collect();
+
+        let mut inner = Indexed;
+        let mut resolver = BodyResolver::new(&mut inner, br#"{"title": "Dune"}"#);
+        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
+        assert_eq!(resolved[0].1, "#4");
+        assert_eq!(resolved[1].1, "Fiction");
+        assert_eq!(resolved[2].1, "Dune");
+    }
+
+    #[test]
+    fn test_body_resolver() {
+        let body = br#"{"title": "Dune", "year": 1965, "author": {"name": "Herbert"},
+                        "tags": ["sf", 7], "isbn": null}"#;
+        let mut inner = MockVariableResolver;
+        let mut resolver = BodyResolver::new(&mut inner, body);
+        assert_eq!(resolver.resolve("$json_body.title").unwrap(), "Dune");
+        assert_eq!(resolver.resolve("$json_body.year").unwrap(), "1965");
+        assert_eq!(
+            resolver.resolve("$json_body.author.name").unwrap(),
+            "Herbert"
+        );
+        assert_eq!(resolver.resolve("$json_body.tags.1").unwrap(), "7");
+        assert_eq!(resolver.resolve("$json_body.tags").unwrap(), r#"["sf",7]"#);
+        assert_eq!(
+            resolver.resolve_values("$json_body.tags").unwrap(),
+            vec!["sf", "7"]
+        );
+        // Null and absent fields are missing; other variables go to nginx
+        assert!(resolver.resolve("$json_body.isbn").is_err());
+        assert!(resolver.resolve("$json_body.author.born").is_err());
+        assert_eq!(resolver.resolve("$arg_id").unwrap(), "123");
+
+        let bindings = vec![ParameterBinding::Named {
+            name: ParamName::parse(":year").unwrap(),
+            variable: NginxVariable::parse("$json_body.year").unwrap(),
+            param_type: ParamType::Integer,
+            default: None,
+            required: true,
+            pattern: None,
+            max_length: None,
+        }];
+        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
+        assert_eq!(resolved[0], (":year".to_string(), Value::from(1965)));
+
+        // A body that is not JSON has no fields
+        let mut inner = MockVariableResolver;
+        let mut resolver = BodyResolver::new(&mut inner, b"title=Dune");
+        assert!(
+            resolver
+                .resolve("$json_body.title")
+                .unwrap_err()
+                .contains("not JSON")
+        );
+        assert_eq!(
+            resolve_parameters(&bindings, &mut resolver),
+            Err(ParamError::Missing(vec![":year".to_string()]))
+        );
+    }
+
+    #[test]
+    fn test_form_body_resolver() {
+        struct Form;
+        impl VariableResolver for Form {
+            fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+                match var_name {
+                    "$content_type" => {
+                        Ok("Application/X-WWW-Form-URLencoded; charset=UTF-8".to_string())
+                    }
+                    _ => MockVariableResolver.resolve(var_name),
+                }
+            }
+        }
+        let body =
+            b"title=The+Left+Hand%20of%20Darkness&tag=sf&Tag=classic&empty=&bad=%zz%E2%9C%93";
+        let mut inner = Form;
+        let mut resolver = BodyResolver::new(&mut inner, body);
+        assert_eq!(
+            resolver.resolve("$post_title").unwrap(),
+            "The Left Hand of Darkness"
+        );
+        assert_eq!(
+            resolver.resolve("$post_TITLE").unwrap(),
+            "The Left Hand of Darkness"
+        );
+        assert_eq!(resolver.resolve("$post_tag").unwrap(), "sf");
+        assert_eq!(
+            resolver.resolve_values("$post_tag").unwrap(),
+            vec!["sf", "classic"]
+        );
+        assert_eq!(resolver.resolve("$post_empty").unwrap(), "");
+        assert_eq!(resolver.resolve("$post_bad").unwrap(), "%zz✓");
+        assert!(resolver.resolve("$post_author").is_err());
+        assert_eq!(resolver.resolve("$arg_id").unwrap(), "123");
+
+        // Only form bodies have form fields
+        let mut inner = MockVariableResolver;
+        let mut resolver = BodyResolver::new(&mut inner, b"title=Dune");
+        assert!(
+            resolver
+                .resolve("$post_title")
+                .unwrap_err()
+                .contains("not a form")
+        );
+    }
+
+    #[test]
+    fn test_resolve_parameters_optional_named() {
+        let bindings = vec![
+            ParameterBinding::OptionalNamed {
+                name: ParamName::parse(":id").unwrap(),
+                variable: NginxVariable::parse("$arg_id").unwrap(),
+                max_length: None,
+            },
+            ParameterBinding::OptionalNamed {
+                name: ParamName::parse(":title").unwrap(),
+                variable: NginxVariable::parse("$arg_title").unwrap(),
+                max_length: None,
+            },
+        ];
+
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        assert_eq!(resolved[0], (":id".to_string(), Value::from("123")));
+        assert_eq!(resolved[1], (":title".to_string(), Value::from("")));
+    }
+
+    #[test]
+    fn test_resolve_parameters_list() {
+        let bindings = vec![ParameterBinding::NamedList {
+            name: ParamName::parse(":ids").unwrap(),
+            variable: NginxVariable::parse("$arg_ids").unwrap(),
+            param_type: ParamType::Text,
+            required: false,
+            pattern: None,
+            max_length: None,
+        }];
+
+        // One entry per non-empty value
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        let values: Vec<&str> = resolved.iter().filter_map(|(_, v)| v.as_str()).collect();
+        assert_eq!(values, vec!["1", "2", "3"]);
+        assert!(resolved.iter().all(|(name, _)| name == ":ids"));
+
+        // Repeated arguments (?tag=a&tag=b,c&tag=) contribute every value
+        let bindings = vec![ParameterBinding::NamedList {
+            name: ParamName::parse(":tags").unwrap(),
+            variable: NginxVariable::parse("$arg_tag").unwrap(),
+            param_type: ParamType::Text,
+            required: false,
+            pattern: None,
+            max_length: None,
+        }];
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        let values: Vec<&str> = resolved.iter().filter_map(|(_, v)| v.as_str()).collect();
+        assert_eq!(values, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_resolve_parameters_typed() {
+        let bindings = vec![
+            ParameterBinding::Named {
+                name: ParamName::parse(":id").unwrap(),
+                variable: NginxVariable::parse("$arg_id").unwrap(),
+                param_type: ParamType::Integer,
+                default: None,
+                required: false,
+                pattern: None,
+                max_length: None,
+            },
+            ParameterBinding::NamedList {
+                name: ParamName::parse(":ids").unwrap(),
+                variable: NginxVariable::parse("$arg_ids").unwrap(),
+                param_type: ParamType::Integer,
+                required: false,
+                pattern: None,
+                max_length: None,
+            },
+            ParameterBinding::NamedLiteral {
+                name: ParamName::parse(":published").unwrap(),
+                value: "yes".to_string(),
+                param_type: ParamType::Bool,
+            },
+        ];
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        let values: Vec<&Value> = resolved.iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            values,
+            vec![
+                &Value::from(123),
+                &Value::from(1),
+                &Value::from(2),
+                &Value::from(3),
+                &Value::Bool(true)
+            ]
+        );
+
+        // A value that does not convert fails the request
+        let bindings = vec![ParameterBinding::Named {
+            name: ParamName::parse(":genre").unwrap(),
+            variable: NginxVariable::parse("$arg_genre").unwrap(),
+            param_type: ParamType::Integer,
+            default: None,
+            required: false,
+            pattern: None,
+            max_length: None,
+        }];
+        let error = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap_err();
+        assert_eq!(
+            error,
+            ParamError::Invalid {
+                name: ":genre".to_string(),
+                message: "expected int, got 'Fiction'".to_string()
+            }
+        );
+        assert_eq!(error.code(), "invalid_parameter");
+    }
+
+    #[test]
+    fn test_resolve_parameters_pattern() {
+        let named = |variable: &str, pattern: &str| ParameterBinding::Named {
+            name: ParamName::parse(":genre").unwrap(),
+            variable: NginxVariable::parse(variable).unwrap(),
+            param_type: ParamType::Text,
+            default: None,
+            required: false,
+            pattern: Some(ParamPattern::parse(pattern).unwrap()),
+            max_length: None,
+        };
+
+        let resolved = resolve_parameters(
+            &[named("$arg_genre", "[A-Za-z]+")],
+            &mut MockVariableResolver,
+        )
+        .unwrap();
+        assert_eq!(resolved[0].1, Value::from("Fiction"));
+
+        let error = resolve_parameters(
+            &[named("$arg_genre", "^[a-z]+$")],
+            &mut MockVariableResolver,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            ParamError::Invalid {
+                name: ":genre".to_string(),
+                message: "does not match pattern ^[a-z]+$".to_string(),
+            }
+        );
+
+        // A missing variable still binds NULL; nothing was supplied to check
+        let resolved = resolve_parameters(
+            &[named("$arg_missing", "[a-z]+")],
+            &mut MockVariableResolver,
+        )
+        .unwrap();
+        assert_eq!(resolved[0].1, Value::Null);
+
+        // Every list item must match
+        let list = |pattern: &str| ParameterBinding::NamedList {
+            name: ParamName::parse(":ids").unwrap(),
+            variable: NginxVariable::parse("$arg_ids").unwrap(),
+            param_type: ParamType::Integer,
+            required: false,
+            pattern: Some(ParamPattern::parse(pattern).unwrap()),
+            max_length: None,
+        };
+        assert!(resolve_parameters(&[list("[0-9]+")], &mut MockVariableResolver).is_ok());
+        assert!(resolve_parameters(&[list("[12]")], &mut MockVariableResolver).is_err());
+    }
+
+    #[test]
+    fn test_resolve_parameters_max_length() {
+        let limit = |n: &str| Some(MaxLength::parse(n).unwrap());
+        let named = |max_length| ParameterBinding::Named {
+            name: ParamName::parse(":genre").unwrap(),
+            variable: NginxVariable::parse("$arg_genre").unwrap(),
+            param_type: ParamType::Text,
+            default: Some("Fiction".to_string()),
+            required: false,
+            pattern: None,
+            max_length,
+        };
+
+        // "Fiction" is seven characters
+        assert!(resolve_parameters(&[named(limit("7"))], &mut MockVariableResolver).is_ok());
+        let error =
+            resolve_parameters(&[named(limit("6"))], &mut MockVariableResolver).unwrap_err();
+        assert_eq!(
+            error,
+            ParamError::Invalid {
+                name: ":genre".to_string(),
+                message: "is longer than 6 characters".to_string(),
+            }
+        );
+
+        // Positional parameters are named by their variable
+        let positional = ParameterBinding::Positional {
+            variable: NginxVariable::parse("$arg_genre").unwrap(),
+            max_length: limit("3"),
+        };
+        let error = resolve_parameters(&[positional], &mut MockVariableResolver).unwrap_err();
+        assert!(matches!(error, ParamError::Invalid { name, .. } if name == "$arg_genre"));
+
+        // A list checks each value before splitting it
+        let list = |max_length| ParameterBinding::NamedList {
+            name: ParamName::parse(":ids").unwrap(),
+            variable: NginxVariable::parse("$arg_ids").unwrap(),
+            param_type: ParamType::Text,
+            required: false,
+            pattern: None,
+            max_length,
+        };
+        assert!(resolve_parameters(&[list(limit("7"))], &mut MockVariableResolver).is_ok());
+        assert!(resolve_parameters(&[list(limit("6"))], &mut MockVariableResolver).is_err());
+    }
+
+    #[test]
+    fn test_memo_key() {
+        let query = SqlQuery::parse("SELECT * FROM books WHERE genre = :genre").unwrap();
+        let params = vec![(":genre".to_string(), Value::from("Fiction"))];
+        let key = memo_key(&query, &PagePosition::Number(1), &params, &[], None);
+        assert_eq!(key.len(), 64);
+        assert_eq!(
+            key,
+            memo_key(&query, &PagePosition::Number(1), &params, &[], None)
+        );
+
+        // Anything the response is computed from changes the key
+        assert_ne!(
+            key,
+            memo_key(&query, &PagePosition::Number(2), &params, &[], None)
+        );
+        let other = vec![(":genre".to_string(), Value::from("History"))];
+        assert_ne!(
+            key,
+            memo_key(&query, &PagePosition::Number(1), &other, &[], None)
+        );
+        let typed = vec![(":genre".to_string(), Value::from(1))];
+        assert_ne!(
+            memo_key(&query, &PagePosition::Number(1), &typed, &[], None),
+            memo_key(
+                &query,
+                &PagePosition::Number(1),
+                &[(":genre".to_string(), Value::from("1"))],
+                &[],
+                None
+            )
+        );
+        let all = SqlQuery::parse("SELECT * FROM books").unwrap();
+        assert_ne!(
+            key,
+            memo_key(&all, &PagePosition::Number(1), &params, &[], None)
+        );
+        let author =
+            Expansion::parse("author", "SELECT * FROM authors WHERE id = :author_id", &[]).unwrap();
+        assert_ne!(
+            key,
+            memo_key(&query, &PagePosition::Number(1), &params, &[&author], None)
+        );
+        let (fields, _) = select_fields("title", &["title".to_string()], &[]).unwrap();
+        assert_ne!(
+            key,
+            memo_key(
+                &query,
+                &PagePosition::Number(1),
+                &params,
+                &[],
+                Some(&fields)
+            )
+        );
+    }
+
+    #[test]
+    fn test_resolve_parameters_query_args() {
+        struct Args;
+        impl VariableResolver for Args {
+            fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+                match var_name {
+                    "$arg_genre" => Ok("Fiction".to_string()),
+                    "$arg_page" => Ok("2".to_string()),
+                    _ => Err(format!("variable not found: {}", var_name)),
+                }
+            }
+
+            fn arg_names(&mut self) -> Vec<String> {
+                vec!["genre".to_string(), "page".to_string()]
+            }
+        }
+
+        let page = ParameterBinding::Named {
+            name: ParamName::parse(":arg_page").unwrap(),
+            variable: NginxVariable::parse("$arg_page").unwrap(),
+            param_type: ParamType::Integer,
+            default: None,
+            required: false,
+            pattern: None,
+            max_length: None,
+        };
+        let args = ParameterBinding::QueryArgs { max_length: None };
+        let params = resolve_parameters(&[page, args], &mut Args).unwrap();
+        // The explicit binding keeps its type
+        assert_eq!(
+            params,
+            vec![
+                (":arg_page".to_string(), Value::from(2)),
+                (":arg_genre".to_string(), Value::from("Fiction")),
+            ]
+        );
+
+        let capped = ParameterBinding::QueryArgs {
+            max_length: Some(MaxLength::parse("6").unwrap()),
+        };
+        let error = resolve_parameters(&[capped], &mut Args).unwrap_err();
+        assert!(matches!(error, ParamError::Invalid { name, .. } if name == ":arg_genre"));
+        // Without a query string nothing is bound
+        let args = ParameterBinding::QueryArgs { max_length: None };
+        assert!(
+            resolve_parameters(&[args], &mut MockVariableResolver)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_resolve_parameters_default() {
+        let named = |variable: &str, default: Option<&str>| ParameterBinding::Named {
+            name: ParamName::parse(":page").unwrap(),
+            variable: NginxVariable::parse(variable).unwrap(),
+            param_type: ParamType::Integer,
+            default: default.map(str::to_string),
+            required: false,
+            pattern: None,
+            max_length: None,
+        };
+
+        // Missing and empty variables fall back to the default
+        let bindings = vec![
+            named("$arg_page", Some("1")),
+            named("$arg_empty", Some("1")),
+            named("$arg_id", Some("1")),
+        ];
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        let values: Vec<&Value> = resolved.iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            values,
+            vec![&Value::from(1), &Value::from(1), &Value::from(123)]
+        );
+
+        // Without one, a missing variable binds NULL
+        let bindings = vec![named("$arg_page", None)];
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        assert_eq!(resolved, vec![(":page".to_string(), Value::Null)]);
+
+        // Positional parameters fall back the same way, as text
+        let positional = |variable: &str| ParameterBinding::PositionalDefault {
+            variable: NginxVariable::parse(variable).unwrap(),
+            default: "1".to_string(),
+            max_length: None,
+        };
+        let bindings = vec![
+            positional("$arg_page"),
+            positional("$arg_empty"),
+            positional("$arg_id"),
+        ];
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        let values: Vec<&Value> = resolved.iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            values,
+            vec![&Value::from("1"), &Value::from("1"), &Value::from("123")]
+        );
+    }
+
+    #[test]
+    fn test_typed_value() {
+        assert_eq!(
+            typed_value(ParamType::Text, " 42 ").unwrap(),
+            Value::from(" 42 ")
+        );
+        assert_eq!(
+            typed_value(ParamType::Integer, " 42 ").unwrap(),
+            Value::from(42)
+        );
+        assert_eq!(
+            typed_value(ParamType::Integer, "-7").unwrap(),
+            Value::from(-7)
+        );
+        assert!(typed_value(ParamType::Integer, "4.2").is_err());
+        assert!(typed_value(ParamType::Integer, "99999999999999999999").is_err());
+        assert_eq!(
+            typed_value(ParamType::Real, "2.5").unwrap(),
+            Value::from(2.5)
+        );
+        assert!(typed_value(ParamType::Real, "NaN").is_err());
+        assert!(typed_value(ParamType::Real, "inf").is_err());
+        assert_eq!(
+            typed_value(ParamType::Bool, "On").unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            typed_value(ParamType::Bool, "0").unwrap(),
+            Value::Bool(false)
+        );
+        assert!(typed_value(ParamType::Bool, "maybe").is_err());
+
+        // An empty value is NULL for every type but text
+        assert_eq!(typed_value(ParamType::Integer, "").unwrap(), Value::Null);
+        assert_eq!(typed_value(ParamType::Bool, " ").unwrap(), Value::Null);
+        assert_eq!(typed_value(ParamType::Text, "").unwrap(), Value::from(""));
+    }
+
+    #[test]
+    fn test_resolve_parameters_literal() {
+        let bindings = vec![ParameterBinding::PositionalLiteral {
+            value: "constant".to_string(),
+        }];
+
+        let mut resolver = MockVariableResolver;
+        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
+
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].1, "constant");
+    }
+
+    #[test]
+    fn test_request_processor_integration() {
+        let config = ValidatedConfig {
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            ..ValidatedConfig::for_test("SELECT * FROM books")
+        };
+
+        let resolved_template = ResolvedTemplate {
+            full_path: "templates/list.hbs".to_string(),
+            directory: "templates".to_string(),
+            fallback_directory: None,
+        };
+
+        let mut processor =
+            RequestProcessor::new(MockQueryExecutor, MockTemplateSystem, MockLogger);
+        assert!(processor.timings().is_none());
+
+        let result = processor.process(
+            &config,
+            &RequestPaths::default(),
+            &resolved_template,
+            &[],
+            None,
+        );
+
+        assert!(result.is_ok());
+        let html = result.unwrap();
+        assert!(html.contains("Rendered"));
+        assert!(!html.contains("query_ms"));
+        assert!(processor.timings().unwrap().render.is_some());
+        assert!(processor.found_rows() > 0);
+
+        // Templates see the parameters the query was run with
+        let params = vec![(":q".to_string(), Value::from("dune"))];
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &params,
+                None,
+            )
+            .unwrap();
+        assert!(html.contains(r#""params": Object {"q": String("dune")}"#));
+
+        // sqlite_timing exposes the query time to the template
+        let config = ValidatedConfig {
+            timing: true,
+            ..config
+        };
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        assert!(html.contains("query_ms"));
+
+        // sqlite_trace records the queries under the query phase, then rendering
+        let trace = Arc::new(Trace::new());
+        let mut processor =
+            RequestProcessor::new(MockQueryExecutor, MockTemplateSystem, MockLogger)
+                .with_trace(Some(trace.clone()));
+        processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        let operations = trace.to_json();
+        assert_eq!(operations[0]["operation"], "query");
+        assert_eq!(operations[0]["rows"], 1);
+        assert_eq!(operations[0]["children"][0]["operation"], "main query");
+        assert_eq!(operations[0]["children"][0]["rows"], 1);
+        assert_eq!(operations[1]["operation"], "render");
+        assert!(operations[1]["duration_ms"].is_number());
+
+        // Rows and timings are reported to the processor's metrics
+        let metrics = Arc::new(crate::metrics::MetricsStore::new());
+        let mut processor =
+            RequestProcessor::new(MockQueryExecutor, MockTemplateSystem, MockLogger)
+                .with_metrics(Some(Arc::clone(&metrics) as Arc<dyn Metrics>));
+        for _ in 0..2 {
+            processor
+                .process(
+                    &config,
+                    &RequestPaths::default(),
+                    &resolved_template,
+                    &[],
+                    None,
+                )
+                .unwrap();
+        }
+        assert_eq!(metrics.counter("rows"), 2);
+        assert_eq!(metrics.counter("fallback"), 0);
+        assert_eq!(metrics.counter("render_presized"), 2);
+        assert_eq!(metrics.counter("render_outgrown"), 0);
+        let summary = metrics.summary();
+        assert!(summary.contains("query=2/"), "{}", summary);
+        assert!(summary.contains("render=2/"), "{}", summary);
+    }
+
+    #[test]
+    fn test_trace_parent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(trace_parent(header), Some(header));
+        assert_eq!(trace_parent(&format!(" {} ", header)), Some(header));
+        assert_eq!(trace_parent(""), None);
+        assert_eq!(
+            trace_parent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
         );
-        self.template_loader
-            .register_template("template", resolved_template.full_path())
-            .map_err(|e| {
-                self.logger.error(
-                    "template",
-                    &format!(
-                        "Failed to register template '{}': {}",
-                        resolved_template.full_path(),
-                        e
-                    ),
-                );
-                format!("failed to register template: {}", e)
-            })?;
+        assert_eq!(
+            trace_parent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            trace_parent("00-4bf92f35\"injected-00f067aa0ba902b7-01"),
+            None
+        );
+    }
 
-        // Render
-        self.logger
-            .debug("render", "Rendering template with query results");
-        let data = serde_json::json!({"results": results});
-        self.template_loader.render("template", &data).map_err(|e| {
-            self.logger
-                .error("render", &format!("Template rendering failed: {}", e));
-            format!("rendering failed: {}", e)
-        })
+    #[test]
+    fn test_server_timing() {
+        let timings = Timings {
+            query: Duration::from_micros(1250),
+            render: Some(Duration::from_micros(400)),
+        };
+        assert_eq!(timings.server_timing(), "query;dur=1.25, render;dur=0.4");
+        assert_eq!(millis(Duration::from_secs(2)), 2000.0);
+
+        let json_only = Timings {
+            render: None,
+            ..timings
+        };
+        assert_eq!(json_only.server_timing(), "query;dur=1.25");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::types::{NginxVariable, ParamName};
+    /// Returns no rows for the primary query and one row for anything else
+    struct EmptyPrimaryExecutor;
+    impl QueryExecutor for EmptyPrimaryExecutor {
+        fn execute(
+            &self,
+            _db_path: &DatabasePath,
+            query: &SqlQuery,
+            params: &[(String, Value)],
+        ) -> Result<Vec<HashMap<String, Value>>, DbError> {
+            if query.as_str().contains("WHERE") {
+                Ok(vec![])
+            } else {
+                assert!(params.is_empty(), "fallback query must run without params");
+                MockQueryExecutor.execute(&DatabasePath::parse("x").unwrap(), query, params)
+            }
+        }
+    }
 
     #[test]
-    fn test_resolve_template_path() {
-        let template = TemplatePath::parse("list.hbs").unwrap();
-        let resolved = resolve_template_path(&ValidatedConfig {
-            db_path: DatabasePath::parse("asdf").expect("fail"),
-            query: SqlQuery::parse("SELECT whatever").expect("fail"),
-            template_path: template,
-            parameters: Vec::new(),
-            doc_root: "server_root".into(),
-            uri: "/books".into(),
-        });
+    fn test_request_processor_fallback_query() {
+        let mut config = ValidatedConfig {
+            fallback_query: Some(SqlQuery::parse("SELECT * FROM books LIMIT 3").unwrap()),
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            ..ValidatedConfig::for_test("SELECT * FROM books WHERE id = ?")
+        };
+        let resolved_template = ResolvedTemplate {
+            full_path: "templates/list.hbs".to_string(),
+            directory: "templates".to_string(),
+            fallback_directory: None,
+        };
+        let params = [(String::new(), Value::from("42"))];
 
-        assert_eq!(resolved.full_path(), "server_root/books/list.hbs");
-        assert_eq!(resolved.directory(), "server_root/books");
+        let mut processor =
+            RequestProcessor::new(EmptyPrimaryExecutor, MockTemplateSystem, MockLogger);
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &params,
+                None,
+            )
+            .unwrap();
+        assert!(html.contains("Test Book"));
+        assert!(html.contains(r#""fallback": Bool(true)"#));
+        assert_eq!(processor.found_rows(), 0);
+
+        config.fallback_query = None;
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &params,
+                None,
+            )
+            .unwrap();
+        assert!(!html.contains("Test Book"));
+        assert!(html.contains(r#""fallback": Bool(false)"#));
     }
 
     #[test]
-    fn test_resolve_template_path_with_trailing_slash() {
-        let template = TemplatePath::parse("index.hbs").unwrap();
-        let resolved = resolve_template_path(&ValidatedConfig {
-            db_path: DatabasePath::parse("asdf").expect("fail"),
-            query: SqlQuery::parse("SELECT whatever").expect("fail"),
-            template_path: template,
-            parameters: Vec::new(),
-            doc_root: "public/".into(),
-            uri: "/docs/".into(),
-        });
+    fn test_request_processor_sources() {
+        let mut config = ValidatedConfig {
+            template_path: Some(TemplatePath::parse("dashboard.hbs").unwrap()),
+            sources: vec![
+                DataSource::parse("recent", "a.db", "SELECT * FROM books").unwrap(),
+                DataSource::parse("empty", "b.db", "SELECT * FROM books WHERE 0").unwrap(),
+            ],
+            ..ValidatedConfig::for_test("SELECT * FROM books")
+        };
+        let resolved_template = ResolvedTemplate {
+            full_path: "templates/dashboard.hbs".to_string(),
+            directory: "templates".to_string(),
+            fallback_directory: None,
+        };
 
-        assert!(resolved.full_path().contains("public//docs/"));
+        let mut processor =
+            RequestProcessor::new(EmptyPrimaryExecutor, MockTemplateSystem, MockLogger);
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        assert!(html.contains(r#""recent": Array [Object"#));
+        assert!(html.contains(r#""empty": Array []"#));
+
+        config.sources.clear();
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        assert!(!html.contains("recent"));
     }
 
-    // Mock implementations for testing
-    struct MockVariableResolver;
-    impl VariableResolver for MockVariableResolver {
-        fn resolve(&mut self, var_name: &str) -> Result<String, String> {
-            match var_name {
-                "$arg_id" => Ok("123".to_string()),
-                "$arg_genre" => Ok("Fiction".to_string()),
-                _ => Err(format!("unknown variable: {}", var_name)),
-            }
-        }
+    #[test]
+    fn test_content_hash() {
+        let hash = sha256_hex(b"hello");
+        assert_eq!(
+            hash,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(
+            content_hash(&format!("/files/{}", hash)),
+            Some(hash.clone())
+        );
+        assert_eq!(
+            content_hash(&format!("/files/{}/", hash.to_uppercase())),
+            Some(hash.clone())
+        );
+        assert_eq!(content_hash("/files/"), None);
+        assert_eq!(content_hash("/files/2cf24dba"), None);
+        assert_eq!(content_hash(&format!("/files/{}.png", hash)), None);
+
+        assert!(etag_matches(&format!("\"{}\"", hash), &hash));
+        assert!(etag_matches(&format!("\"abc\", W/\"{}\"", hash), &hash));
+        assert!(etag_matches("*", &hash));
+        assert!(!etag_matches("\"abc\"", &hash));
+        assert!(!etag_matches(&hash, &hash));
+        assert!(!etag_matches("", &hash));
     }
 
-    struct MockQueryExecutor;
-    impl QueryExecutor for MockQueryExecutor {
+    #[test]
+    fn test_breadcrumb_trail() {
+        let urls = |uri: &str| -> Vec<String> {
+            breadcrumb_trail(uri)
+                .into_iter()
+                .map(|(url, _)| url)
+                .collect()
+        };
+        assert_eq!(
+            urls("/books/fiction/"),
+            vec!["/", "/books", "/books/fiction"]
+        );
+        assert_eq!(urls("/"), vec!["/"]);
+        assert_eq!(
+            breadcrumb_trail("/books//dune"),
+            vec![
+                ("/".to_string(), String::new()),
+                ("/books".to_string(), "books".to_string()),
+                ("/books/dune".to_string(), "dune".to_string()),
+            ]
+        );
+    }
+
+    /// Labels `/books` from its path; every other crumb has no label row
+    struct LabelExecutor;
+    impl QueryExecutor for LabelExecutor {
         fn execute(
             &self,
-            _db_path: &DatabasePath,
-            _query: &SqlQuery,
-            _params: &[(String, String)],
-        ) -> Result<Vec<HashMap<String, Value>>, String> {
-            let mut row = HashMap::new();
-            row.insert("id".to_string(), Value::Number(1.into()));
-            row.insert("title".to_string(), Value::String("Test Book".to_string()));
-            Ok(vec![row])
+            db_path: &DatabasePath,
+            query: &SqlQuery,
+            params: &[(String, Value)],
+        ) -> Result<Vec<HashMap<String, Value>>, DbError> {
+            if !query.as_str().contains("pages") {
+                return MockQueryExecutor.execute(db_path, query, params);
+            }
+            assert_eq!(params[0].0, ":path");
+            Ok(match params[0].1.as_str().unwrap_or_default() {
+                "/books" => vec![HashMap::from([(
+                    "title".to_string(),
+                    Value::String("All Books".to_string()),
+                )])],
+                _ => vec![],
+            })
         }
     }
 
-    struct MockTemplateSystem;
-    impl TemplateLoader for MockTemplateSystem {
-        fn load_from_dir(&mut self, _dir_path: &str) -> Result<usize, String> {
-            Ok(0)
-        }
-        fn register_template(&mut self, _name: &str, _path: &str) -> Result<(), String> {
-            Ok(())
-        }
-    }
+    #[test]
+    fn test_request_processor_breadcrumbs() {
+        let mut config = ValidatedConfig {
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            breadcrumbs: Some(
+                Breadcrumbs::parse(&[
+                    "on",
+                    "labels_query=SELECT title FROM pages WHERE path = :path",
+                ])
+                .unwrap(),
+            ),
+            ..ValidatedConfig::for_test("SELECT * FROM books")
+        };
+        let paths = RequestPaths::new("", "/books/dune");
+        let resolved_template = ResolvedTemplate {
+            full_path: "templates/list.hbs".to_string(),
+            directory: "templates".to_string(),
+            fallback_directory: None,
+        };
 
-    impl TemplateRenderer for MockTemplateSystem {
-        fn render(&self, _template_name: &str, data: &Value) -> Result<String, String> {
-            Ok(format!("Rendered: {:?}", data))
-        }
+        let mut processor = RequestProcessor::new(LabelExecutor, MockTemplateSystem, MockLogger);
+        let html = processor
+            .process(&config, &paths, &resolved_template, &[], None)
+            .unwrap();
+        assert!(html.contains(r#""label": String("Home")"#));
+        assert!(html.contains(r#""label": String("All Books")"#));
+        assert!(html.contains(r#""label": String("dune")"#));
+        assert!(html.contains(r#""current": Bool(true)"#));
+
+        config.breadcrumbs = None;
+        let html = processor
+            .process(&config, &paths, &resolved_template, &[], None)
+            .unwrap();
+        assert!(!html.contains("breadcrumbs"));
     }
 
-    struct MockLogger;
-    impl Logger for MockLogger {
-        fn log(&self, _level: LogLevel, _module: &str, _message: &str) {
-            // No-op for tests
+    /// Answers count queries with 45 and any other query with one book
+    struct CountingBooksExecutor;
+    impl QueryExecutor for CountingBooksExecutor {
+        fn execute(
+            &self,
+            db_path: &DatabasePath,
+            query: &SqlQuery,
+            params: &[(String, Value)],
+        ) -> Result<Vec<HashMap<String, Value>>, DbError> {
+            if query.as_str().starts_with("SELECT COUNT(*)") {
+                Ok(vec![HashMap::from([(
+                    "COUNT(*)".to_string(),
+                    serde_json::json!(45),
+                )])])
+            } else {
+                assert!(query.as_str().ends_with("LIMIT 20 OFFSET 20"));
+                MockQueryExecutor.execute(db_path, query, params)
+            }
         }
     }
 
     #[test]
-    fn test_resolve_parameters_positional() {
-        let bindings = vec![ParameterBinding::Positional {
-            variable: NginxVariable::parse("$arg_id").unwrap(),
-        }];
+    fn test_request_processor_pagination() {
+        let config = ValidatedConfig {
+            pagination: Some(Pagination::parse(&["per_page=20"]).unwrap()),
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            ..ValidatedConfig::for_test("SELECT * FROM books")
+        };
+        let resolved_template = ResolvedTemplate {
+            full_path: "templates/list.hbs".to_string(),
+            directory: "templates".to_string(),
+            fallback_directory: None,
+        };
 
-        let mut resolver = MockVariableResolver;
-        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
+        let mut processor =
+            RequestProcessor::new(CountingBooksExecutor, MockTemplateSystem, MockLogger)
+                .with_page(PagePosition::Number(2))
+                .with_hits(Some(7));
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        assert!(html.contains(r#""hits": Number(7)"#));
+        assert!(html.contains(r#""page_count": Number(3)"#));
+        assert!(html.contains(r#""total": Number(45)"#));
+        assert!(html.contains(r#""next": Number(3)"#));
+        assert!(html.contains(r#""id": Number(1)"#));
 
-        assert_eq!(resolved.len(), 1);
-        assert_eq!(resolved[0].0, ""); // Positional (no name)
-        assert_eq!(resolved[0].1, "123");
+        // ?fields= narrows the rows templates see
+        let (fields, _) =
+            select_fields("title", &["id".to_string(), "title".to_string()], &[]).unwrap();
+        let mut processor =
+            RequestProcessor::new(CountingBooksExecutor, MockTemplateSystem, MockLogger)
+                .with_page(PagePosition::Number(2))
+                .with_fields(Some(fields));
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        assert!(html.contains(r#""title": String("Test Book")"#));
+        assert!(!html.contains(r#""id": Number(1)"#));
     }
 
-    #[test]
-    fn test_resolve_parameters_named() {
-        let bindings = vec![ParameterBinding::Named {
-            name: ParamName::parse(":book_id").unwrap(),
-            variable: NginxVariable::parse("$arg_id").unwrap(),
-        }];
-
-        let mut resolver = MockVariableResolver;
-        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
-
-        assert_eq!(resolved.len(), 1);
-        assert_eq!(resolved[0].0, ":book_id");
-        assert_eq!(resolved[0].1, "123");
+    /// Counts the queries it runs against `menu` tables
+    #[derive(Default)]
+    struct CountingExecutor {
+        menu_queries: std::sync::atomic::AtomicUsize,
+    }
+    impl QueryExecutor for CountingExecutor {
+        fn execute(
+            &self,
+            db_path: &DatabasePath,
+            query: &SqlQuery,
+            params: &[(String, Value)],
+        ) -> Result<Vec<HashMap<String, Value>>, DbError> {
+            if query.as_str().contains("menu") {
+                self.menu_queries
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            MockQueryExecutor.execute(db_path, query, params)
+        }
     }
 
     #[test]
-    fn test_resolve_parameters_literal() {
-        let bindings = vec![ParameterBinding::PositionalLiteral {
-            value: "constant".to_string(),
-        }];
+    fn test_request_processor_nav_menus() {
+        let mut config = ValidatedConfig {
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            nav_menus: vec![
+                NavMenu::parse("main_menu", "site.db", "SELECT * FROM menu", &[]).unwrap(),
+            ],
+            ..ValidatedConfig::for_test("SELECT * FROM books")
+        };
+        let resolved_template = ResolvedTemplate {
+            full_path: "templates/list.hbs".to_string(),
+            directory: "templates".to_string(),
+            fallback_directory: None,
+        };
+        assert_eq!(config.database_files(), vec!["test.db", "site.db"]);
 
-        let mut resolver = MockVariableResolver;
-        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
+        let cache = Arc::new(crate::cache::NavCache::new());
+        let mut processor =
+            RequestProcessor::new(CountingExecutor::default(), MockTemplateSystem, MockLogger)
+                .with_nav_cache(Some(cache));
+        for _ in 0..3 {
+            let html = processor
+                .process(
+                    &config,
+                    &RequestPaths::default(),
+                    &resolved_template,
+                    &[],
+                    None,
+                )
+                .unwrap();
+            assert!(html.contains(r#""nav": Object {"main_menu": Array [Object"#));
+        }
+        // The menu was queried once and then served from the cache
+        assert_eq!(
+            processor
+                .query_executor
+                .menu_queries
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
 
-        assert_eq!(resolved.len(), 1);
-        assert_eq!(resolved[0].1, "constant");
+        // Without a cache every page queries the menu
+        let mut processor =
+            RequestProcessor::new(CountingExecutor::default(), MockTemplateSystem, MockLogger);
+        processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            processor
+                .query_executor
+                .menu_queries
+                .load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+
+        config.nav_menus.clear();
+        let html = processor
+            .process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+            .unwrap();
+        assert!(!html.contains("nav"));
+    }
+
+    /// Panics while running any query that reads the `secrets` table
+    struct PanickingExecutor;
+    impl QueryExecutor for PanickingExecutor {
+        fn execute(
+            &self,
+            db_path: &DatabasePath,
+            query: &SqlQuery,
+            params: &[(String, Value)],
+        ) -> Result<Vec<HashMap<String, Value>>, DbError> {
+            if query.as_str().contains("secrets") {
+                panic!("bug while reading {}", query.as_str());
+            }
+            MockQueryExecutor.execute(db_path, query, params)
+        }
     }
 
     #[test]
-    fn test_request_processor_integration() {
-        let config = ValidatedConfig {
-            db_path: DatabasePath::parse("test.db").unwrap(),
-            query: SqlQuery::parse("SELECT * FROM books").unwrap(),
-            template_path: TemplatePath::parse("list.hbs").unwrap(),
-            parameters: vec![],
-            doc_root: "".into(),
-            uri: "".into(),
+    fn test_request_processor_panics_are_contained() {
+        let mut config = ValidatedConfig {
+            template_path: Some(TemplatePath::parse("list.hbs").unwrap()),
+            ..ValidatedConfig::for_test("SELECT * FROM secrets")
         };
-
         let resolved_template = ResolvedTemplate {
             full_path: "templates/list.hbs".to_string(),
             directory: "templates".to_string(),
+            fallback_directory: None,
         };
-
         let mut processor =
-            RequestProcessor::new(MockQueryExecutor, MockTemplateSystem, MockLogger);
+            RequestProcessor::new(PanickingExecutor, MockTemplateSystem, MockLogger);
 
-        let result = processor.process(&config, &resolved_template, &[], None);
+        // A panic in the main query reaches the handler's guard, without its message
+        let error = guard::catch_panic(|| {
+            processor.process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+        })
+        .unwrap_err();
+        assert!(error.contains("redacted"));
+        assert!(!error.contains("secrets"));
 
-        assert!(result.is_ok());
-        assert!(result.unwrap().contains("Rendered"));
+        // A panic on a source thread fails the request like any source error
+        config.query = SqlQuery::parse("SELECT * FROM books").unwrap();
+        config.sources = vec![DataSource::parse("leak", "a.db", "SELECT * FROM secrets").unwrap()];
+        let error = guard::catch_panic(|| {
+            processor.process(
+                &config,
+                &RequestPaths::default(),
+                &resolved_template,
+                &[],
+                None,
+            )
+        })
+        .unwrap()
+        .unwrap_err();
+        assert!(error.contains("source 'leak' execution failed: panic at"));
+        assert!(!error.contains("secrets"));
     }
 
     // Additional edge case tests
     #[test]
     fn test_resolve_template_path_empty_uri() {
         let template = TemplatePath::parse("index.hbs").unwrap();
-        let resolved = resolve_template_path(&ValidatedConfig {
-            db_path: DatabasePath::parse("test.db").unwrap(),
-            query: SqlQuery::parse("SELECT id FROM test").unwrap(),
-            template_path: template,
-            parameters: Vec::new(),
-            doc_root: "/var/www".into(),
-            uri: "".into(),
-        });
+        let resolved = resolve_template_path(
+            &ValidatedConfig {
+                template_path: Some(template),
+                ..ValidatedConfig::for_test("SELECT id FROM test")
+            },
+            &RequestPaths::new("/var/www", ""),
+        )
+        .unwrap();
 
         assert_eq!(resolved.full_path(), "/var/www/index.hbs");
         assert_eq!(resolved.directory(), "/var/www");
@@ -453,14 +6085,14 @@ mod tests {
     #[test]
     fn test_resolve_template_path_root_uri() {
         let template = TemplatePath::parse("home.hbs").unwrap();
-        let resolved = resolve_template_path(&ValidatedConfig {
-            db_path: DatabasePath::parse("test.db").unwrap(),
-            query: SqlQuery::parse("SELECT id FROM test").unwrap(),
-            template_path: template,
-            parameters: Vec::new(),
-            doc_root: "/www".into(),
-            uri: "/".into(),
-        });
+        let resolved = resolve_template_path(
+            &ValidatedConfig {
+                template_path: Some(template),
+                ..ValidatedConfig::for_test("SELECT id FROM test")
+            },
+            &RequestPaths::new("/www", "/"),
+        )
+        .unwrap();
 
         assert_eq!(resolved.full_path(), "/www//home.hbs");
     }
@@ -468,14 +6100,14 @@ mod tests {
     #[test]
     fn test_resolve_template_path_nested_uri() {
         let template = TemplatePath::parse("view.hbs").unwrap();
-        let resolved = resolve_template_path(&ValidatedConfig {
-            db_path: DatabasePath::parse("test.db").unwrap(),
-            query: SqlQuery::parse("SELECT id FROM test").unwrap(),
-            template_path: template,
-            parameters: Vec::new(),
-            doc_root: "public".into(),
-            uri: "/api/v1/books".into(),
-        });
+        let resolved = resolve_template_path(
+            &ValidatedConfig {
+                template_path: Some(template),
+                ..ValidatedConfig::for_test("SELECT id FROM test")
+            },
+            &RequestPaths::new("public", "/api/v1/books"),
+        )
+        .unwrap();
 
         assert_eq!(resolved.full_path(), "public/api/v1/books/view.hbs");
         assert_eq!(resolved.directory(), "public/api/v1/books");
@@ -486,9 +6118,11 @@ mod tests {
         let bindings = vec![
             ParameterBinding::Positional {
                 variable: NginxVariable::parse("$arg_id").unwrap(),
+                max_length: None,
             },
             ParameterBinding::Positional {
                 variable: NginxVariable::parse("$arg_genre").unwrap(),
+                max_length: None,
             },
         ];
 
@@ -506,10 +6140,20 @@ mod tests {
             ParameterBinding::Named {
                 name: ParamName::parse(":id").unwrap(),
                 variable: NginxVariable::parse("$arg_id").unwrap(),
+                param_type: ParamType::Text,
+                default: None,
+                required: false,
+                pattern: None,
+                max_length: None,
             },
             ParameterBinding::Named {
                 name: ParamName::parse(":genre").unwrap(),
                 variable: NginxVariable::parse("$arg_genre").unwrap(),
+                param_type: ParamType::Text,
+                default: None,
+                required: false,
+                pattern: None,
+                max_length: None,
             },
         ];
 
@@ -529,12 +6173,18 @@ mod tests {
             ParameterBinding::Named {
                 name: ParamName::parse(":id").unwrap(),
                 variable: NginxVariable::parse("$arg_id").unwrap(),
+                param_type: ParamType::Text,
+                default: None,
+                required: false,
+                pattern: None,
+                max_length: None,
             },
             ParameterBinding::PositionalLiteral {
                 value: "constant".to_string(),
             },
             ParameterBinding::Positional {
                 variable: NginxVariable::parse("$arg_genre").unwrap(),
+                max_length: None,
             },
         ];
 
@@ -553,6 +6203,7 @@ mod tests {
         let bindings = vec![ParameterBinding::NamedLiteral {
             name: ParamName::parse(":status").unwrap(),
             value: "active".to_string(),
+            param_type: ParamType::Text,
         }];
 
         let mut resolver = MockVariableResolver;
@@ -567,13 +6218,107 @@ mod tests {
     fn test_resolve_parameters_error_handling() {
         let bindings = vec![ParameterBinding::Positional {
             variable: NginxVariable::parse("$unknown_var").unwrap(),
+            max_length: None,
         }];
 
+        // A parameter that is not required binds NULL when it cannot be resolved
         let mut resolver = MockVariableResolver;
-        let result = resolve_parameters(&bindings, &mut resolver);
+        let resolved = resolve_parameters(&bindings, &mut resolver).unwrap();
+        assert_eq!(resolved, vec![(String::new(), Value::Null)]);
+    }
+
+    #[test]
+    fn test_resolve_parameters_required() {
+        let named = |name: &str, variable: &str| ParameterBinding::Named {
+            name: ParamName::parse(name).unwrap(),
+            variable: NginxVariable::parse(variable).unwrap(),
+            param_type: ParamType::Text,
+            default: None,
+            required: true,
+            pattern: None,
+            max_length: None,
+        };
+        let list = |variable: &str| ParameterBinding::NamedList {
+            name: ParamName::parse(":ids").unwrap(),
+            variable: NginxVariable::parse(variable).unwrap(),
+            param_type: ParamType::Integer,
+            required: true,
+            pattern: None,
+            max_length: None,
+        };
+
+        let bindings = vec![named(":id", "$arg_id"), list("$arg_ids")];
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        assert_eq!(resolved.len(), 4);
+
+        // Every missing or empty one is reported together
+        let bindings = vec![
+            named(":id", "$arg_id"),
+            named(":page", "$arg_page"),
+            named(":title", "$arg_empty"),
+            list("$arg_missing"),
+        ];
+        let error = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap_err();
+        assert_eq!(
+            error,
+            ParamError::Missing(vec![
+                ":page".to_string(),
+                ":title".to_string(),
+                ":ids".to_string()
+            ])
+        );
+        assert_eq!(
+            param_error_body(&error),
+            serde_json::json!({
+                "error": "Missing required parameters",
+                "code": "missing_parameter",
+                "details": "missing required parameter(s) :page, :title, :ids",
+                "errors": {"page": "is required", "title": "is required", "ids": "is required"},
+            })
+        );
+        assert_eq!(error.code(), "missing_parameter");
+
+        // Without sqlite_param_required, missing values bind NULL or an empty list
+        let bindings = vec![
+            ParameterBinding::Named {
+                name: ParamName::parse(":page").unwrap(),
+                variable: NginxVariable::parse("$arg_page").unwrap(),
+                param_type: ParamType::Text,
+                default: None,
+                required: false,
+                pattern: None,
+                max_length: None,
+            },
+            ParameterBinding::NamedList {
+                name: ParamName::parse(":ids").unwrap(),
+                variable: NginxVariable::parse("$arg_missing").unwrap(),
+                param_type: ParamType::Integer,
+                required: false,
+                pattern: None,
+                max_length: None,
+            },
+        ];
+        let resolved = resolve_parameters(&bindings, &mut MockVariableResolver).unwrap();
+        assert_eq!(resolved, vec![(":page".to_string(), Value::Null)]);
+    }
+
+    #[test]
+    fn test_bind_hits() {
+        let mut params = vec![(":slug".to_string(), serde_json::json!("about"))];
+        bind_hits(&mut params, 42);
+        assert_eq!(params[1], (":hits".to_string(), serde_json::json!(42)));
 
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("unknown variable"));
+        let mut params = Vec::new();
+        bind_hits(&mut params, 1);
+        assert_eq!(params, vec![(":hits".to_string(), serde_json::json!(1))]);
+
+        let mut positional = vec![(String::new(), serde_json::json!("about"))];
+        bind_hits(&mut positional, 42);
+        assert_eq!(positional.len(), 1);
+
+        let mut own = vec![(":hits".to_string(), serde_json::json!("7"))];
+        bind_hits(&mut own, 42);
+        assert_eq!(own, vec![(":hits".to_string(), serde_json::json!("7"))]);
     }
 
     #[test]
@@ -585,11 +6330,159 @@ mod tests {
         assert_eq!(resolved.len(), 0);
     }
 
+    fn rows(count: i64) -> Vec<HashMap<String, Value>> {
+        (0..count)
+            .map(|i| {
+                let mut row = HashMap::new();
+                row.insert("id".to_string(), Value::Number(i.into()));
+                row
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_template_context_unlimited() {
+        let data = build_template_context(rows(5), None, None, false);
+        assert_eq!(data["results"].as_array().unwrap().len(), 5);
+        assert_eq!(data["meta"]["truncated"], Value::Bool(false));
+        assert_eq!(data["meta"]["total_rows"], 5);
+    }
+
+    #[test]
+    fn test_template_params() {
+        let params = vec![
+            (":q".to_string(), Value::from("dune")),
+            (String::new(), Value::from(2)),
+            ("@ids".to_string(), serde_json::json!([1, 2])),
+            (String::new(), Value::Null),
+        ];
+        assert_eq!(
+            template_params(&params),
+            serde_json::json!({"q": "dune", "1": 2, "ids": [1, 2], "2": null})
+        );
+        assert_eq!(template_params(&[]), serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_context_providers() {
+        let mut data = build_template_context(rows(1), None, None, false);
+        let params = vec![(":q".to_string(), Value::from("dune"))];
+        provide_context(
+            &mut data,
+            &[
+                &RequestInfo { params: &params },
+                &SourceRows(vec![("genres", rows(2))]),
+                &Navigation {
+                    breadcrumbs: Some(serde_json::json!([{"url": "/"}])),
+                    ..Default::default()
+                },
+                &PageInfo(Some(serde_json::json!({"page": 2}))),
+                &Measurements {
+                    hits: Some(7),
+                    query_time: Some(Duration::from_micros(1500)),
+                },
+            ],
+        );
+        assert_eq!(data["params"], serde_json::json!({"q": "dune"}));
+        assert_eq!(data["genres"].as_array().unwrap().len(), 2);
+        assert_eq!(data["breadcrumbs"][0]["url"], "/");
+        assert_eq!(data["pagination"]["page"], 2);
+        assert_eq!(data["hits"], 7);
+        assert_eq!(data["timing"]["query_ms"], 1.5);
+        assert_eq!(data["results"].as_array().unwrap().len(), 1);
+
+        // A feature the location does not use adds nothing
+        let mut data = build_template_context(rows(1), None, None, false);
+        provide_context(
+            &mut data,
+            &[
+                &Navigation::default(),
+                &PageInfo(None),
+                &Measurements::default(),
+            ],
+        );
+        let keys: Vec<&String> = data.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["meta", "results"]);
+    }
+
+    #[test]
+    fn test_build_template_context_truncates() {
+        let limit = RowLimit::parse("3").unwrap();
+        let data = build_template_context(rows(5), Some(limit), None, false);
+        let results = data["results"].as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2]["id"], 2);
+        assert_eq!(data["meta"]["truncated"], Value::Bool(true));
+        assert_eq!(data["meta"]["total_rows"], 5);
+    }
+
+    #[test]
+    fn test_build_template_context_at_limit() {
+        let limit = RowLimit::parse("5").unwrap();
+        let data = build_template_context(rows(5), Some(limit), None, false);
+        assert_eq!(data["results"].as_array().unwrap().len(), 5);
+        assert_eq!(data["meta"]["truncated"], Value::Bool(false));
+    }
+
+    fn book(price: Value, pages: Value) -> HashMap<String, Value> {
+        let mut row = HashMap::new();
+        row.insert("price".to_string(), price);
+        row.insert("pages".to_string(), pages);
+        row
+    }
+
+    #[test]
+    fn test_compute_column_stats() {
+        let results = vec![
+            book(serde_json::json!(10.5), serde_json::json!(300)),
+            book(serde_json::json!(4), serde_json::json!(120)),
+            book(Value::Null, serde_json::json!(90)),
+        ];
+        let spec = AggregateSpec::parse("min,max,sum,avg", "columns=price,pages").unwrap();
+        let stats = compute_column_stats(&results, &spec);
+
+        assert_eq!(stats["price"]["min"], 4.0);
+        assert_eq!(stats["price"]["max"], 10.5);
+        assert_eq!(stats["price"]["sum"], 14.5);
+        assert_eq!(stats["price"]["avg"], 7.25);
+        assert_eq!(stats["pages"]["min"], 90);
+        assert_eq!(stats["pages"]["max"], 300);
+        assert_eq!(stats["pages"]["sum"], 510);
+        assert_eq!(stats["pages"]["avg"], 170.0);
+    }
+
+    #[test]
+    fn test_compute_column_stats_no_numeric_values() {
+        let results = vec![book(Value::String("n/a".into()), Value::Null)];
+        let spec = AggregateSpec::parse("min,sum", "columns=price,missing").unwrap();
+        let stats = compute_column_stats(&results, &spec);
+
+        assert_eq!(stats["price"]["min"], Value::Null);
+        assert_eq!(stats["price"]["sum"], Value::Null);
+        assert_eq!(stats["missing"]["min"], Value::Null);
+    }
+
+    #[test]
+    fn test_build_template_context_stats_ignore_truncation() {
+        let spec = AggregateSpec::parse("max", "columns=id").unwrap();
+        let limit = RowLimit::parse("2").unwrap();
+        let data = build_template_context(rows(5), Some(limit), Some(&spec), false);
+
+        assert_eq!(data["results"].as_array().unwrap().len(), 2);
+        assert_eq!(data["stats"]["id"]["max"], 4);
+        assert!(
+            build_template_context(rows(1), None, None, false)
+                .get("stats")
+                .is_none()
+        );
+    }
+
     #[test]
     fn test_resolved_template_accessors() {
         let resolved = ResolvedTemplate {
             full_path: "/var/www/templates/books/list.hbs".to_string(),
             directory: "/var/www/templates/books".to_string(),
+            fallback_directory: None,
         };
 
         assert_eq!(resolved.full_path(), "/var/www/templates/books/list.hbs");
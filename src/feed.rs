@@ -0,0 +1,430 @@
+//! Atom and RSS documents from a location's rows (`sqlite_feed`, pure functions)
+//!
+//! Each row is an entry whose title, link, update time and content are read
+//! from the columns the directive names. Links relative to the server are
+//! made absolute, since feed readers fetch them from elsewhere. Times are
+//! read the way SQLite writes them (`2024-05-01 12:00:00`, with an optional
+//! `T`, fraction and UTC offset, or Unix seconds) and written in the form
+//! each format requires. The feed itself was updated when its newest entry
+//! was.
+
+use crate::types::{Feed, FeedFormat};
+use serde_json::Value;
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// One row's entry
+struct Entry {
+    title: String,
+    link: String,
+    updated: Option<i64>,
+    content: Option<String>,
+}
+
+/// The feed document for `rows`, served at `uri` on `base_url`
+/// (`https://example.com`); `now` (Unix seconds) dates a feed none of whose
+/// entries say when they were updated
+pub fn render(
+    feed: &Feed,
+    rows: &[Value],
+    base_url: &str,
+    uri: &str,
+    now: i64,
+) -> Result<String, String> {
+    let entries = rows
+        .iter()
+        .enumerate()
+        .map(|(index, row)| {
+            entry(feed, row, base_url).map_err(|e| format!("row {}: {}", index + 1, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let updated = entries
+        .iter()
+        .filter_map(|entry| entry.updated)
+        .max()
+        .unwrap_or(now);
+    let url = format!("{}{}", base_url, uri);
+    let title = feed.name().unwrap_or(uri);
+    Ok(match feed.format() {
+        FeedFormat::Atom => atom(title, &url, base_url, updated, &entries),
+        FeedFormat::Rss => rss(title, &url, base_url, updated, &entries),
+    })
+}
+
+fn entry(feed: &Feed, row: &Value, base_url: &str) -> Result<Entry, String> {
+    let required =
+        |column: &str| text(row, column).ok_or_else(|| format!("column '{}' has no value", column));
+    let link = required(feed.link())?;
+    Ok(Entry {
+        title: required(feed.title())?,
+        link: absolute(&link, base_url),
+        updated: row.get(feed.updated()).and_then(timestamp),
+        content: text(row, feed.content()),
+    })
+}
+
+fn atom(title: &str, url: &str, base_url: &str, updated: i64, entries: &[Entry]) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str(&format!("  <title>{}</title>\n", escape(title)));
+    out.push_str(&format!("  <id>{}</id>\n", escape(url)));
+    out.push_str(&format!(
+        "  <link rel=\"self\" href=\"{}\"/>\n",
+        escape(url)
+    ));
+    out.push_str(&format!("  <link href=\"{}/\"/>\n", escape(base_url)));
+    out.push_str(&format!("  <updated>{}</updated>\n", rfc3339(updated)));
+    out.push_str(&format!(
+        "  <author><name>{}</name></author>\n",
+        escape(title)
+    ));
+    for entry in entries {
+        out.push_str("  <entry>\n");
+        out.push_str(&format!("    <title>{}</title>\n", escape(&entry.title)));
+        out.push_str(&format!("    <id>{}</id>\n", escape(&entry.link)));
+        out.push_str(&format!("    <link href=\"{}\"/>\n", escape(&entry.link)));
+        // An entry that does not say is as recent as the feed
+        out.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            rfc3339(entry.updated.unwrap_or(updated))
+        ));
+        if let Some(content) = &entry.content {
+            out.push_str(&format!(
+                "    <content type=\"html\">{}</content>\n",
+                escape(content)
+            ));
+        }
+        out.push_str("  </entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+fn rss(title: &str, url: &str, base_url: &str, updated: i64, entries: &[Entry]) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str("  <channel>\n");
+    out.push_str(&format!("    <title>{}</title>\n", escape(title)));
+    out.push_str(&format!("    <link>{}/</link>\n", escape(base_url)));
+    out.push_str(&format!(
+        "    <description>{}</description>\n",
+        escape(title)
+    ));
+    out.push_str(&format!(
+        "    <atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+        escape(url)
+    ));
+    out.push_str(&format!(
+        "    <lastBuildDate>{}</lastBuildDate>\n",
+        rfc822(updated)
+    ));
+    for entry in entries {
+        out.push_str("    <item>\n");
+        out.push_str(&format!("      <title>{}</title>\n", escape(&entry.title)));
+        out.push_str(&format!("      <link>{}</link>\n", escape(&entry.link)));
+        out.push_str(&format!("      <guid>{}</guid>\n", escape(&entry.link)));
+        if let Some(updated) = entry.updated {
+            out.push_str(&format!("      <pubDate>{}</pubDate>\n", rfc822(updated)));
+        }
+        if let Some(content) = &entry.content {
+            out.push_str(&format!(
+                "      <description>{}</description>\n",
+                escape(content)
+            ));
+        }
+        out.push_str("    </item>\n");
+    }
+    out.push_str("  </channel>\n</rss>\n");
+    out
+}
+
+/// A column's value as text; `None` when the row has no such column or it is
+/// null
+fn text(row: &Value, column: &str) -> Option<String> {
+    match row.get(column)? {
+        Value::Null => None,
+        Value::String(text) => Some(text.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// `link` as an absolute URL, joined to `base_url` when it is a path
+fn absolute(link: &str, base_url: &str) -> String {
+    if link.contains("://") {
+        link.to_string()
+    } else if link.starts_with('/') {
+        format!("{}{}", base_url, link)
+    } else {
+        format!("{}/{}", base_url, link)
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A time in Unix seconds: a number as it is, or text SQLite's date
+/// functions read
+fn timestamp(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(seconds) => seconds.as_i64(),
+        Value::String(text) => parse_time(text),
+        _ => None,
+    }
+}
+
+/// `YYYY-MM-DD`, then optionally `HH:MM[:SS[.fff]]` after a space or `T`,
+/// then optionally `Z` or an offset `±HH:MM`; UTC unless an offset says
+/// otherwise
+fn parse_time(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if text.get(4..5) != Some("-") || text.get(7..8) != Some("-") {
+        return None;
+    }
+    let year: i64 = digits(text.get(0..4)?)?;
+    let month: u32 = digits(text.get(5..7)?)?;
+    let day: u32 = digits(text.get(8..10)?)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut seconds = days_from_civil(year, month, day) * 86_400;
+
+    let mut rest = &text[10..];
+    if let Some(time) = rest.strip_prefix(['T', ' ']) {
+        if time.get(2..3) != Some(":") {
+            return None;
+        }
+        let hours: i64 = digits(time.get(0..2)?)?;
+        let minutes: i64 = digits(time.get(3..5)?)?;
+        seconds += hours * 3600 + minutes * 60;
+        rest = &time[5..];
+        if let Some(after) = rest.strip_prefix(':') {
+            seconds += digits::<i64>(after.get(0..2)?)?;
+            rest = after[2..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+        }
+    }
+
+    let offset = match rest.trim_start() {
+        "" | "Z" => 0,
+        zone => {
+            let sign = match zone.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let (hours, minutes) = zone[1..].split_once(':')?;
+            sign * (digits::<i64>(hours)? * 3600 + digits::<i64>(minutes)? * 60)
+        }
+    };
+    Some(seconds - offset)
+}
+
+/// `text` as a number, when it is nothing but ASCII digits
+fn digits<T: std::str::FromStr>(text: &str) -> Option<T> {
+    if text.bytes().all(|b| b.is_ascii_digit()) {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date `days` after 1970-01-01, as year, month and day
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Atom's time format, e.g. `2024-05-01T12:00:00Z`
+fn rfc3339(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let time = seconds.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// RSS's time format, e.g. `Wed, 01 May 2024 12:00:00 GMT`
+fn rfc822(seconds: i64) -> String {
+    let days = seconds.div_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    let time = seconds.rem_euclid(86_400);
+    // 1970-01-01 was a Thursday
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days + 4).rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn posts() -> Vec<Value> {
+        vec![
+            json!({
+                "title": "Hello & welcome",
+                "link": "/posts/1",
+                "updated": "2024-05-01 12:00:00",
+                "content": "<p>First</p>",
+            }),
+            json!({
+                "title": "Elsewhere",
+                "link": "https://other.example/post",
+                "updated": null,
+                "content": null,
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_atom() {
+        let feed = Feed::parse(&["atom", "name=Blog"]).unwrap();
+        let document = render(&feed, &posts(), "https://example.com", "/feed", 0).unwrap();
+        assert_eq!(
+            document,
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Blog</title>
+  <id>https://example.com/feed</id>
+  <link rel="self" href="https://example.com/feed"/>
+  <link href="https://example.com/"/>
+  <updated>2024-05-01T12:00:00Z</updated>
+  <author><name>Blog</name></author>
+  <entry>
+    <title>Hello &amp; welcome</title>
+    <id>https://example.com/posts/1</id>
+    <link href="https://example.com/posts/1"/>
+    <updated>2024-05-01T12:00:00Z</updated>
+    <content type="html">&lt;p&gt;First&lt;/p&gt;</content>
+  </entry>
+  <entry>
+    <title>Elsewhere</title>
+    <id>https://other.example/post</id>
+    <link href="https://other.example/post"/>
+    <updated>2024-05-01T12:00:00Z</updated>
+  </entry>
+</feed>
+"#
+        );
+
+        // An empty feed is dated now, and titled after its URI
+        let empty = render(&feed, &[], "https://example.com", "/feed", 86_400).unwrap();
+        assert!(empty.contains("<updated>1970-01-02T00:00:00Z</updated>"));
+        let unnamed = Feed::parse(&["atom"]).unwrap();
+        let empty = render(&unnamed, &[], "https://example.com", "/feed", 0).unwrap();
+        assert!(empty.contains("<title>/feed</title>"));
+    }
+
+    #[test]
+    fn test_rss() {
+        let feed = Feed::parse(&["rss", "title=headline", "link=url"]).unwrap();
+        let rows = vec![json!({
+            "headline": "Hello",
+            "url": "posts/1",
+            "updated": 1714564800,
+            "content": "<p>First</p>",
+        })];
+        let document = render(&feed, &rows, "http://example.com", "/feed.xml", 0).unwrap();
+        assert_eq!(
+            document,
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom">
+  <channel>
+    <title>/feed.xml</title>
+    <link>http://example.com/</link>
+    <description>/feed.xml</description>
+    <atom:link href="http://example.com/feed.xml" rel="self" type="application/rss+xml"/>
+    <lastBuildDate>Wed, 01 May 2024 12:00:00 GMT</lastBuildDate>
+    <item>
+      <title>Hello</title>
+      <link>http://example.com/posts/1</link>
+      <guid>http://example.com/posts/1</guid>
+      <pubDate>Wed, 01 May 2024 12:00:00 GMT</pubDate>
+      <description>&lt;p&gt;First&lt;/p&gt;</description>
+    </item>
+  </channel>
+</rss>
+"#
+        );
+
+        // Entries need a title and a link
+        let untitled = vec![json!({"headline": null, "url": "/posts/2"})];
+        assert_eq!(
+            render(&feed, &untitled, "http://example.com", "/feed.xml", 0).unwrap_err(),
+            "row 1: column 'headline' has no value"
+        );
+    }
+
+    #[test]
+    fn test_times() {
+        let may_first = Some(1_714_564_800);
+        assert_eq!(parse_time("2024-05-01 12:00:00"), may_first);
+        assert_eq!(parse_time("2024-05-01T12:00:00.250Z"), may_first);
+        assert_eq!(parse_time("2024-05-01T14:00:00+02:00"), may_first);
+        assert_eq!(parse_time("2024-05-01 12:00"), may_first);
+        assert_eq!(parse_time("2024-05-01"), Some(1_714_521_600));
+        assert_eq!(parse_time("1969-12-31 23:59:59"), Some(-1));
+        assert_eq!(parse_time("yesterday"), None);
+        assert_eq!(parse_time("2024-13-01"), None);
+        assert_eq!(parse_time("2024-05-01 noon"), None);
+        assert_eq!(parse_time("2024-05-01 12:00 CEST"), None);
+        assert_eq!(parse_time("2024-+5-01"), None);
+
+        assert_eq!(rfc3339(1_714_564_800), "2024-05-01T12:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc822(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(rfc822(1_709_164_800), "Thu, 29 Feb 2024 00:00:00 GMT");
+    }
+}
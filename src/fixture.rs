@@ -0,0 +1,1127 @@
+//! Behaviour tests declared as fixtures (`tests/fixtures/*.toml`)
+//!
+//! A fixture creates a database, configures one location with nginx
+//! directives, gives it templates, then sends it requests and checks the
+//! answers:
+//!
+//! ```toml
+//! [database]
+//! schema = "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT)"
+//! rows.books = [[1, "Dune"], [2, "Emma"]]
+//!
+//! [location]
+//! directives = '''
+//! sqlite_query "SELECT * FROM books WHERE id = :id";
+//! sqlite_param :id $arg_id int;
+//! sqlite_template book.hbs;
+//! '''
+//!
+//! [templates]
+//! "books/book.hbs" = "{{#each results}}<h1>{{title}}</h1>{{/each}}"
+//!
+//! [[case]]
+//! request = "GET /books?id=1"
+//! status = 200
+//! contains = ["<h1>Dune</h1>"]
+//! ```
+//!
+//! Directives are checked against the module's command table and recorded
+//! by the handlers nginx calls ([`directives::set`]). `sqlite_db` is set to
+//! the fixture's database unless the directives set it, and a `sqlite_nav`
+//! menu's relative database is found beside it, so `fixture.db` names the
+//! fixture's own. A location whose configuration must be refused gives the
+//! reason as `location.error` instead of cases.
+//!
+//! Requests run through the location's own request stages
+//! ([`handler_types::process_request`]) with a mock request in nginx's place:
+//! its variables come from the request line, `headers` and `body`, and what
+//! the stages send is the answer checked. Directives whose work needs nginx
+//! itself (captchas, batches, exports, ...) are refused.
+
+use crate::config::{MainConfig, ModuleConfig};
+use crate::content_type::{ContentType, negotiate_accept};
+use crate::domain::{self, LogLevel, Logger, RequestPaths, VariableResolver};
+use crate::formatter::StreamedResponse;
+use crate::handler_types::{self, StageRequest};
+use crate::types::StreamBuffer;
+use crate::{directives, parsing, variable};
+use ngx::core::Status;
+use ngx::ffi::{NGX_CONF_1MORE, NGX_CONF_2MORE, NGX_CONF_NOARGS, NGX_HTTP_LOC_CONF, ngx_uint_t};
+use ngx::http::HTTPStatus;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::ptr::addr_of;
+
+/// An answer to a fixture's request
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn empty(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The variables of a fixture's request, from its request line and headers
+#[derive(Clone, Copy)]
+struct Variables<'a> {
+    method: &'a str,
+    path: &'a str,
+    args: &'a str,
+    headers: &'a [(String, String)],
+    decode_args: bool,
+}
+
+impl Variables<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn decoded(&self, value: String) -> String {
+        if self.decode_args {
+            domain::form_decode(value.as_bytes())
+        } else {
+            value
+        }
+    }
+}
+
+impl VariableResolver for Variables<'_> {
+    fn resolve(&mut self, var_name: &str) -> Result<String, String> {
+        let not_found = || format!("variable not found: {}", var_name);
+        let value = match var_name {
+            "$request_method" => self.method.to_string(),
+            "$uri" => self.path.to_string(),
+            "$args" | "$query_string" => self.args.to_string(),
+            "$request_uri" if self.args.is_empty() => self.path.to_string(),
+            "$request_uri" => format!("{}?{}", self.path, self.args),
+            "$scheme" => "http".to_string(),
+            "$host" => self.header("Host").unwrap_or("localhost").to_string(),
+            "$remote_addr" => "127.0.0.1".to_string(),
+            _ if !var_name.starts_with('$') => var_name.to_string(),
+            _ => {
+                if let Some(name) = var_name.strip_prefix("$arg_") {
+                    let value = variable::arg_values(self.args.as_bytes(), name)?
+                        .into_iter()
+                        .next()
+                        .ok_or_else(not_found)?;
+                    self.decoded(value)
+                } else if let Some(index) = var_name.strip_prefix("$uri_segment_") {
+                    index
+                        .parse()
+                        .ok()
+                        .and_then(|index| variable::path_segment(self.path, index))
+                        .ok_or_else(not_found)?
+                        .to_string()
+                } else if let Some(name) = var_name.strip_prefix("$cookie_") {
+                    self.header("Cookie")
+                        .and_then(|cookies| {
+                            cookies.split(';').find_map(|cookie| {
+                                let (key, value) = cookie.trim().split_once('=')?;
+                                (key == name).then_some(value)
+                            })
+                        })
+                        .ok_or_else(not_found)?
+                        .to_string()
+                } else {
+                    let name = var_name
+                        .strip_prefix("$http_")
+                        .or_else(|| var_name.strip_prefix("$sent_http_"))
+                        .unwrap_or(&var_name[1..]);
+                    self.header(&name.replace('_', "-"))
+                        .ok_or_else(not_found)?
+                        .to_string()
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    fn resolve_values(&mut self, var_name: &str) -> Result<Vec<String>, String> {
+        if let Some(name) = var_name.strip_prefix("$arg_") {
+            let values = variable::arg_values(self.args.as_bytes(), name)?;
+            if !values.is_empty() {
+                return Ok(values.into_iter().map(|v| self.decoded(v)).collect());
+            }
+        }
+        self.resolve(var_name).map(|value| vec![value])
+    }
+
+    fn arg_names(&mut self) -> Vec<String> {
+        variable::query_arg_names(self.args.as_bytes())
+    }
+}
+
+/// Fixtures check answers, not logs
+struct Silent;
+
+impl Logger for Silent {
+    fn log(&self, _level: LogLevel, _module: &str, _message: &str) {}
+}
+
+/// A request as nginx would give it to the module's stages, keeping what
+/// they send as its [`Response`]
+struct MockRequest<'a> {
+    variables: Variables<'a>,
+    body: &'a [u8],
+    conf: &'a ModuleConfig,
+    main_conf: MainConfig,
+    headers_out: Vec<(String, String)>,
+    response: Option<Response>,
+}
+
+impl StageRequest for MockRequest<'_> {
+    fn method(&self) -> String {
+        self.variables.method.to_string()
+    }
+
+    fn variables(&mut self) -> Box<dyn VariableResolver + '_> {
+        Box::new(self.variables)
+    }
+
+    fn logger(&self) -> Box<dyn Logger> {
+        Box::new(Silent)
+    }
+
+    fn location_conf(&self) -> Option<&ModuleConfig> {
+        Some(self.conf)
+    }
+
+    fn main_conf(&self) -> Option<&MainConfig> {
+        Some(&self.main_conf)
+    }
+
+    fn accepted_content_type(&self) -> ContentType {
+        negotiate_accept(
+            self.variables
+                .headers
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("Accept"))
+                .map(|(_, value)| value.as_str()),
+        )
+    }
+
+    fn record_error(&mut self, _code: &str, _message: &str) {}
+
+    fn add_header(&mut self, name: &'static str, value: &str) -> bool {
+        self.headers_out.push((name.to_string(), value.to_string()));
+        true
+    }
+
+    fn send(
+        &mut self,
+        status: HTTPStatus,
+        headers: &[(&'static str, &str)],
+        body: &[u8],
+    ) -> Status {
+        for (name, value) in headers {
+            self.add_header(name, value);
+        }
+        self.response = Some(Response {
+            status: status.0 as u16,
+            headers: std::mem::take(&mut self.headers_out),
+            body: String::from_utf8_lossy(body).into_owned(),
+        });
+        Status::NGX_DONE
+    }
+
+    fn send_streamed(&mut self, response: StreamedResponse, _buffer: StreamBuffer) -> Status {
+        let headers: Vec<(&'static str, &str)> = response
+            .headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        let body: Vec<u8> = response.lines.flatten().collect();
+        self.send(HTTPStatus::OK, &headers, &body)
+    }
+
+    fn body_read(&mut self) -> bool {
+        true
+    }
+
+    fn body(&mut self) -> Result<Vec<u8>, String> {
+        Ok(self.body.to_vec())
+    }
+
+    fn discard_body(&mut self) {}
+
+    fn nginx(&mut self) -> Option<&mut ngx::http::Request> {
+        None
+    }
+}
+
+/// Directives whose work needs nginx itself: subrequests, bodies read after
+/// the handler returns, or answers made before the request stages
+const NEEDS_NGINX: &[&str] = &[
+    "sqlite_captcha",
+    "sqlite_batch",
+    "sqlite_export",
+    "sqlite_db_map",
+    "sqlite_health",
+    "sqlite_typescript",
+    "sqlite_capabilities",
+    "sqlite_blob",
+];
+
+/// Record a directive as nginx would: refused unless the module's command
+/// table allows it in a location with this many arguments, then given to
+/// [`directives::set`] as its handler does
+fn directive(conf: &mut ModuleConfig, name: &str, mut args: Vec<String>) -> Result<(), String> {
+    // Only read: nginx takes the table as the module's, and nothing writes it
+    let commands = unsafe { &*addr_of!(crate::ngx_http_howto_commands) };
+    let command = commands
+        .iter()
+        // The table ends with a null command
+        .take_while(|command| !command.name.is_empty())
+        .find(|command| command.name.to_string() == name)
+        .ok_or_else(|| format!("unknown directive {}", name))?;
+    if NEEDS_NGINX.contains(&name) {
+        return Err(format!("{} is not supported in fixtures", name));
+    }
+    if command.type_ & NGX_HTTP_LOC_CONF as ngx_uint_t == 0 {
+        return Err(format!("{} is not allowed in a location", name));
+    }
+    let arity = command.type_;
+    let counted = (args.len() <= 7 && arity & (NGX_CONF_NOARGS as ngx_uint_t) << args.len() != 0)
+        || (!args.is_empty() && arity & NGX_CONF_1MORE as ngx_uint_t != 0)
+        || (args.len() >= 2 && arity & NGX_CONF_2MORE as ngx_uint_t != 0);
+    if !counted {
+        return Err(format!("invalid number of arguments ({})", args.len()));
+    }
+
+    if name == "sqlite_nav"
+        && let Some(dir) = conf
+            .db_path
+            .as_deref()
+            .and_then(|db| Path::new(db).parent())
+        && Path::new(&args[1]).is_relative()
+    {
+        args[1] = dir.join(&args[1]).to_string_lossy().into_owned();
+    }
+    directives::set(conf, name, &args)
+}
+
+/// Split directives written as in nginx.conf into their names and arguments:
+/// words separated by whitespace, quoted with `"` or `'`, each directive
+/// ending with `;`
+fn directives(text: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut directives = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            ';' => {
+                if words.is_empty() {
+                    return Err("unexpected ;".to_string());
+                }
+                let name = words.remove(0);
+                directives.push((name, std::mem::take(&mut words)));
+            }
+            '"' | '\'' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(format!("unterminated {} string", c)),
+                        Some(end) if end == c => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => word.push('\n'),
+                            Some('t') => word.push('\t'),
+                            Some(escaped) => word.push(escaped),
+                            None => return Err(format!("unterminated {} string", c)),
+                        },
+                        Some(other) => word.push(other),
+                    }
+                }
+                words.push(word);
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && c != ';') {
+                    word.push(c);
+                }
+                words.push(word);
+            }
+        }
+    }
+    if !words.is_empty() {
+        return Err(format!("{} is missing its ;", words[0]));
+    }
+    Ok(directives)
+}
+
+/// Fail on any key of `table` not in `known`, so a misspelt expectation is
+/// not silently skipped; a missing table has none
+fn check_keys(table: &Value, known: &[&str], context: &str) -> Result<(), String> {
+    if table.is_null() {
+        return Ok(());
+    }
+    let Some(table) = table.as_object() else {
+        return Err(format!("{} is not a table", context));
+    };
+    match table.keys().find(|key| !known.contains(&key.as_str())) {
+        Some(key) => Err(format!("unknown key {} in {}", key, context)),
+        None => Ok(()),
+    }
+}
+
+fn text<'a>(table: &'a Value, key: &str, default: &'a str) -> Result<&'a str, String> {
+    match &table[key] {
+        Value::Null => Ok(default),
+        Value::String(text) => Ok(text),
+        _ => Err(format!("{} is not a string", key)),
+    }
+}
+
+fn texts<'a>(table: &'a Value, key: &str) -> Result<Vec<&'a str>, String> {
+    match &table[key] {
+        Value::Null => Ok(Vec::new()),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| {
+                item.as_str()
+                    .ok_or_else(|| format!("{} is not strings", key))
+            })
+            .collect(),
+        _ => Err(format!("{} is not an array", key)),
+    }
+}
+
+/// `Name: value` pairs
+fn header_pairs(table: &Value, key: &str) -> Result<Vec<(String, String)>, String> {
+    texts(table, key)?
+        .into_iter()
+        .map(|header| {
+            header
+                .split_once(':')
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .ok_or_else(|| format!("{} is not Name: value", header))
+        })
+        .collect()
+}
+
+/// Create the fixture's tables and insert its rows
+fn seed(db: &str, database: &Value) -> Result<(), String> {
+    check_keys(database, &["schema", "rows"], "database")?;
+    let conn = rusqlite::Connection::open(db).map_err(|e| e.to_string())?;
+    conn.execute_batch(text(database, "schema", "")?)
+        .map_err(|e| format!("schema: {}", e))?;
+    let Some(tables) = database["rows"].as_object() else {
+        return Ok(());
+    };
+    for (table, rows) in tables {
+        for row in rows.as_array().into_iter().flatten() {
+            let values = row
+                .as_array()
+                .ok_or_else(|| format!("rows.{} holds a row that is not an array", table))?;
+            let placeholders = vec!["?"; values.len()].join(", ");
+            let sql = format!("INSERT INTO \"{}\" VALUES ({})", table, placeholders);
+            let values: Vec<rusqlite::types::Value> = values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => rusqlite::types::Value::Null,
+                    Value::Bool(b) => rusqlite::types::Value::Integer(*b as i64),
+                    Value::Number(n) => match n.as_i64() {
+                        Some(i) => rusqlite::types::Value::Integer(i),
+                        None => rusqlite::types::Value::Real(n.as_f64().unwrap_or_default()),
+                    },
+                    Value::String(s) => rusqlite::types::Value::Text(s.clone()),
+                    other => rusqlite::types::Value::Text(other.to_string()),
+                })
+                .collect();
+            conn.execute(&sql, rusqlite::params_from_iter(values))
+                .map_err(|e| format!("rows.{}: {}", table, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Check one case's answer against its expectations
+fn check(case: &Value, response: &Response) -> Result<(), String> {
+    if let Some(status) = case["status"].as_u64()
+        && status != u64::from(response.status)
+    {
+        return Err(format!(
+            "expected status {}, got {}: {}",
+            status, response.status, response.body
+        ));
+    }
+    for (name, value) in header_pairs(case, "response_headers")? {
+        match response.header(&name) {
+            Some(actual) if actual == value => {}
+            actual => {
+                return Err(format!("expected {}: {}, got {:?}", name, value, actual));
+            }
+        }
+    }
+    for expected in texts(case, "contains")? {
+        if !response.body.contains(expected) {
+            return Err(format!("body lacks {:?}: {}", expected, response.body));
+        }
+    }
+    for unexpected in texts(case, "excludes")? {
+        if response.body.contains(unexpected) {
+            return Err(format!("body has {:?}: {}", unexpected, response.body));
+        }
+    }
+    for pattern in texts(case, "matches")? {
+        let regex = regex::Regex::new(pattern).map_err(|e| e.to_string())?;
+        if !regex.is_match(&response.body) {
+            return Err(format!(
+                "body does not match {}: {}",
+                pattern, response.body
+            ));
+        }
+    }
+    if let Some(expected) = case["json"].as_str() {
+        let expected: Value = serde_json::from_str(expected).map_err(|e| format!("json: {}", e))?;
+        let actual: Value = serde_json::from_str(&response.body)
+            .map_err(|e| format!("body is not JSON ({}): {}", e, response.body))?;
+        if actual != expected {
+            return Err(format!("expected JSON {}, got {}", expected, actual));
+        }
+    }
+    Ok(())
+}
+
+/// Run every case of the fixture at `path`
+fn run(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let fixture = toml::parse(&source)?;
+    check_keys(
+        &fixture,
+        &["description", "database", "location", "templates", "case"],
+        "the fixture",
+    )?;
+    let name = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("fixture");
+    let dir = format!("/tmp/test_fixture_{}", name);
+    let doc_root = format!("{}/root", dir);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&doc_root).map_err(|e| e.to_string())?;
+
+    let db = format!("{}/fixture.db", dir);
+    seed(&db, &fixture["database"])?;
+    for (file, template) in fixture["templates"].as_object().into_iter().flatten() {
+        let template = template
+            .as_str()
+            .ok_or_else(|| format!("template {} is not a string", file))?;
+        let file = Path::new(&doc_root).join(file.trim_start_matches('/'));
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        fs::write(&file, template).map_err(|e| e.to_string())?;
+    }
+
+    let location = &fixture["location"];
+    check_keys(location, &["directives", "error"], "location")?;
+    let mut conf = ModuleConfig {
+        db_path: Some(db),
+        ..Default::default()
+    };
+    let configured = directives(text(location, "directives", "")?).and_then(|directives| {
+        directives.into_iter().try_for_each(|(name, args)| {
+            directive(&mut conf, &name, args).map_err(|e| format!("{}: {}", name, e))
+        })
+    });
+    let validated = configured.and_then(|_| parsing::parse_config(&conf));
+    let config = match (validated, location["error"].as_str()) {
+        (Ok(config), None) => config,
+        (Err(e), Some(expected)) if e.contains(expected) => return Ok(()),
+        (Err(e), _) => return Err(format!("configuration refused: {}", e)),
+        (Ok(_), Some(expected)) => {
+            return Err(format!("configuration accepted, expected {}", expected));
+        }
+    };
+
+    let cases = fixture["case"].as_array().cloned().unwrap_or_default();
+    if cases.is_empty() {
+        return Err("no [[case]] to run".to_string());
+    }
+    for case in &cases {
+        check_keys(
+            case,
+            &[
+                "request",
+                "headers",
+                "body",
+                "status",
+                "response_headers",
+                "contains",
+                "excludes",
+                "matches",
+                "json",
+            ],
+            "a case",
+        )?;
+        let line = text(case, "request", "")?;
+        let (method, target) = line
+            .split_once(' ')
+            .ok_or_else(|| format!("request {:?} is not METHOD /uri", line))?;
+        let (path, args) = target.split_once('?').unwrap_or((target, ""));
+        let headers = header_pairs(case, "headers")?;
+        let mut request = MockRequest {
+            variables: Variables {
+                method,
+                path,
+                args,
+                headers: &headers,
+                decode_args: conf.decode_args.unwrap_or(true),
+            },
+            body: text(case, "body", "")?.as_bytes(),
+            conf: &conf,
+            main_conf: MainConfig::default(),
+            headers_out: Vec::new(),
+            response: None,
+        };
+        let paths = RequestPaths::new(doc_root.as_str(), path);
+        let status = handler_types::process_request(&mut request, &config, &paths);
+        let response = request
+            .response
+            .take()
+            .unwrap_or_else(|| Response::empty(status.0 as u16));
+        check(case, &response).map_err(|e| format!("{}: {}", line, e))?;
+    }
+    let _ = fs::remove_dir_all(&dir);
+    Ok(())
+}
+
+/// The subset of TOML fixtures are written in, read as JSON: tables
+/// (`[a.b]`) and arrays of tables (`[[a]]`) become objects and arrays of
+/// objects, and keys may be dotted or quoted
+///
+/// Values are strings (basic, literal and either multi-line), integers,
+/// floats, booleans, arrays and inline tables; dates are not supported.
+mod toml {
+    use serde_json::{Map, Value};
+
+    pub fn parse(source: &str) -> Result<Value, String> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        parser.document().map_err(|e| {
+            let line = parser.chars[..parser.pos.min(parser.chars.len())]
+                .iter()
+                .filter(|&&c| c == '\n')
+                .count();
+            format!("line {}: {}", line + 1, e)
+        })
+    }
+
+    struct Parser {
+        chars: Vec<char>,
+        pos: usize,
+    }
+
+    impl Parser {
+        fn peek(&self) -> Option<char> {
+            self.chars.get(self.pos).copied()
+        }
+
+        fn eat(&mut self, text: &str) -> bool {
+            let matches = text
+                .chars()
+                .enumerate()
+                .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c));
+            if matches {
+                self.pos += text.chars().count();
+            }
+            matches
+        }
+
+        fn expect(&mut self, text: &str) -> Result<(), String> {
+            if self.eat(text) {
+                Ok(())
+            } else {
+                Err(format!("expected {}", text))
+            }
+        }
+
+        /// Spaces and tabs
+        fn skip_spaces(&mut self) {
+            while matches!(self.peek(), Some(' ' | '\t' | '\r')) {
+                self.pos += 1;
+            }
+        }
+
+        /// Whitespace, newlines and comments
+        fn skip_blank(&mut self) {
+            loop {
+                match self.peek() {
+                    Some(c) if c.is_whitespace() => self.pos += 1,
+                    Some('#') => {
+                        while self.peek().is_some_and(|c| c != '\n') {
+                            self.pos += 1;
+                        }
+                    }
+                    _ => return,
+                }
+            }
+        }
+
+        fn end_of_line(&mut self) -> Result<(), String> {
+            self.skip_spaces();
+            if self.peek() == Some('#') {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            }
+            match self.peek() {
+                None | Some('\n') => Ok(()),
+                Some(c) => Err(format!("unexpected {:?}", c)),
+            }
+        }
+
+        fn document(&mut self) -> Result<Value, String> {
+            let mut root = Value::Object(Map::new());
+            let mut current: Vec<String> = Vec::new();
+            loop {
+                self.skip_blank();
+                match self.peek() {
+                    None => return Ok(root),
+                    Some('[') => {
+                        let array = self.eat("[[");
+                        if !array {
+                            self.pos += 1;
+                        }
+                        self.skip_spaces();
+                        let path = self.key()?;
+                        self.skip_spaces();
+                        self.expect(if array { "]]" } else { "]" })?;
+                        if array {
+                            let (last, parent) = path.split_last().expect("keys are not empty");
+                            let entry = table(&mut root, parent)?
+                                .entry(last.clone())
+                                .or_insert_with(|| Value::Array(Vec::new()));
+                            match entry {
+                                Value::Array(tables) => tables.push(Value::Object(Map::new())),
+                                _ => return Err(format!("{} is not an array", last)),
+                            }
+                        } else {
+                            table(&mut root, &path)?;
+                        }
+                        current = path;
+                    }
+                    Some(_) => {
+                        let key = self.key()?;
+                        self.skip_spaces();
+                        self.expect("=")?;
+                        self.skip_spaces();
+                        let value = self.value()?;
+                        insert(table(&mut root, &current)?, &key, value)?;
+                    }
+                }
+                self.end_of_line()?;
+            }
+        }
+
+        /// A dotted key
+        fn key(&mut self) -> Result<Vec<String>, String> {
+            let mut key = Vec::new();
+            loop {
+                let part = match self.peek() {
+                    Some('"') => {
+                        self.pos += 1;
+                        self.basic_string()?
+                    }
+                    Some('\'') => {
+                        self.pos += 1;
+                        self.literal_string()?
+                    }
+                    _ => {
+                        let start = self.pos;
+                        while self
+                            .peek()
+                            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                        {
+                            self.pos += 1;
+                        }
+                        if start == self.pos {
+                            return Err("expected a key".to_string());
+                        }
+                        self.chars[start..self.pos].iter().collect()
+                    }
+                };
+                key.push(part);
+                self.skip_spaces();
+                if !self.eat(".") {
+                    return Ok(key);
+                }
+                self.skip_spaces();
+            }
+        }
+
+        fn value(&mut self) -> Result<Value, String> {
+            if self.eat("\"\"\"") {
+                self.multiline(true).map(Value::String)
+            } else if self.eat("'''") {
+                self.multiline(false).map(Value::String)
+            } else if self.eat("\"") {
+                self.basic_string().map(Value::String)
+            } else if self.eat("'") {
+                self.literal_string().map(Value::String)
+            } else if self.eat("[") {
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank();
+                    if self.eat("]") {
+                        return Ok(Value::Array(items));
+                    }
+                    items.push(self.value()?);
+                    self.skip_blank();
+                    if !self.eat(",") {
+                        self.skip_blank();
+                        self.expect("]")?;
+                        return Ok(Value::Array(items));
+                    }
+                }
+            } else if self.eat("{") {
+                let mut inline = Map::new();
+                self.skip_spaces();
+                if self.eat("}") {
+                    return Ok(Value::Object(inline));
+                }
+                loop {
+                    self.skip_spaces();
+                    let key = self.key()?;
+                    self.expect("=")?;
+                    self.skip_spaces();
+                    let value = self.value()?;
+                    insert(&mut inline, &key, value)?;
+                    self.skip_spaces();
+                    if self.eat("}") {
+                        return Ok(Value::Object(inline));
+                    }
+                    self.expect(",")?;
+                }
+            } else if self.eat("true") {
+                Ok(Value::Bool(true))
+            } else if self.eat("false") {
+                Ok(Value::Bool(false))
+            } else {
+                let start = self.pos;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || "+-._".contains(c))
+                {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos]
+                    .iter()
+                    .filter(|&&c| c != '_')
+                    .collect();
+                if let Ok(integer) = number.parse::<i64>() {
+                    Ok(Value::from(integer))
+                } else if let Ok(float) = number.parse::<f64>() {
+                    Ok(Value::from(float))
+                } else {
+                    Err(format!("expected a value, got {:?}", number))
+                }
+            }
+        }
+
+        /// The rest of a `"` string
+        fn basic_string(&mut self) -> Result<String, String> {
+            let mut text = String::new();
+            loop {
+                match self.peek() {
+                    None | Some('\n') => return Err("unterminated string".to_string()),
+                    Some('"') => {
+                        self.pos += 1;
+                        return Ok(text);
+                    }
+                    Some('\\') => text.push(self.escape()?),
+                    Some(c) => {
+                        self.pos += 1;
+                        text.push(c);
+                    }
+                }
+            }
+        }
+
+        /// The rest of a `'` string
+        fn literal_string(&mut self) -> Result<String, String> {
+            let mut text = String::new();
+            loop {
+                match self.peek() {
+                    None | Some('\n') => return Err("unterminated string".to_string()),
+                    Some('\'') => {
+                        self.pos += 1;
+                        return Ok(text);
+                    }
+                    Some(c) => {
+                        self.pos += 1;
+                        text.push(c);
+                    }
+                }
+            }
+        }
+
+        /// The rest of a `"""` (`basic`) or `'''` string; a newline right
+        /// after the opening quotes is dropped, and in a basic string so is
+        /// a backslash ending a line with the whitespace after it
+        fn multiline(&mut self, basic: bool) -> Result<String, String> {
+            let end = if basic { "\"\"\"" } else { "'''" };
+            self.eat("\r");
+            self.eat("\n");
+            let mut text = String::new();
+            loop {
+                if self.eat(end) {
+                    return Ok(text);
+                }
+                match self.peek() {
+                    None => return Err("unterminated string".to_string()),
+                    Some('\\')
+                        if basic
+                            && self.chars[self.pos + 1..]
+                                .iter()
+                                .take_while(|&&c| c != '\n')
+                                .all(|c| c.is_whitespace()) =>
+                    {
+                        self.pos += 1;
+                        while self.peek().is_some_and(char::is_whitespace) {
+                            self.pos += 1;
+                        }
+                    }
+                    Some('\\') if basic => text.push(self.escape()?),
+                    Some(c) => {
+                        self.pos += 1;
+                        text.push(c);
+                    }
+                }
+            }
+        }
+
+        /// A backslash escape
+        fn escape(&mut self) -> Result<char, String> {
+            self.pos += 1;
+            let c = self.peek().ok_or("unterminated escape")?;
+            self.pos += 1;
+            let hex = |parser: &mut Parser, digits: usize| {
+                let code: String = parser.chars.iter().skip(parser.pos).take(digits).collect();
+                parser.pos += digits;
+                u32::from_str_radix(&code, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("invalid escape \\{}{}", c, code))
+            };
+            match c {
+                'b' => Ok('\u{8}'),
+                't' => Ok('\t'),
+                'n' => Ok('\n'),
+                'f' => Ok('\u{c}'),
+                'r' => Ok('\r'),
+                '"' => Ok('"'),
+                '\\' => Ok('\\'),
+                'u' => hex(self, 4),
+                'U' => hex(self, 8),
+                c => Err(format!("invalid escape \\{}", c)),
+            }
+        }
+    }
+
+    /// The table at `path`, created if missing; a path through an array of
+    /// tables goes through its last table
+    fn table<'a>(
+        root: &'a mut Value,
+        path: &[String],
+    ) -> Result<&'a mut Map<String, Value>, String> {
+        let mut value = root;
+        for part in path {
+            let Value::Object(map) = value else {
+                return Err(format!("{} is not a table", part));
+            };
+            value = map
+                .entry(part.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if let Value::Array(tables) = value {
+                value = tables
+                    .last_mut()
+                    .ok_or_else(|| format!("{} is an empty array", part))?;
+            }
+        }
+        match value {
+            Value::Object(map) => Ok(map),
+            _ => Err(format!("{} is not a table", path.join("."))),
+        }
+    }
+
+    /// Set a dotted key, refusing to set one twice
+    fn insert(table: &mut Map<String, Value>, key: &[String], value: Value) -> Result<(), String> {
+        let (last, parents) = key.split_last().expect("keys are not empty");
+        let mut table = table;
+        for part in parents {
+            table = match table
+                .entry(part.clone())
+                .or_insert_with(|| Value::Object(Map::new()))
+            {
+                Value::Object(map) => map,
+                _ => return Err(format!("{} is not a table", part)),
+            };
+        }
+        if table.contains_key(last) {
+            return Err(format!("{} is set twice", key.join(".")));
+        }
+        table.insert(last.clone(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixtures() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let mut paths: Vec<_> = fs::read_dir(&dir)
+            .expect("tests/fixtures exists")
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|e| e == "toml"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        let failures: Vec<String> = paths
+            .iter()
+            .filter_map(|path| {
+                run(path)
+                    .err()
+                    .map(|e| format!("{}: {}", path.display(), e))
+            })
+            .collect();
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn test_toml() {
+        let document = toml::parse(
+            r#"
+# A comment
+title = "a \"quoted\" \u00e9"   # trailing comment
+literal = 'C:\path'
+numbers = [1, -2, 3.5, 1_000]
+nested = [[1, "a"], [
+  2, "b",  # the second row
+]]
+inline = { a = 1, "b c" = true }
+dotted.key = "x"
+sql = """
+SELECT *
+  FROM books \
+     WHERE 1"""
+
+[table.sub]
+raw = '''
+{{#each results}}
+'''
+
+[[case]]
+status = 200
+[[case]]
+status = 404
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            document,
+            serde_json::json!({
+                "title": "a \"quoted\" é",
+                "literal": "C:\\path",
+                "numbers": [1, -2, 3.5, 1000],
+                "nested": [[1, "a"], [2, "b"]],
+                "inline": {"a": 1, "b c": true},
+                "dotted": {"key": "x"},
+                "sql": "SELECT *\n  FROM books WHERE 1",
+                "table": {"sub": {"raw": "{{#each results}}\n"}},
+                "case": [{"status": 200}, {"status": 404}],
+            })
+        );
+
+        assert_eq!(
+            toml::parse("a = 1\na = 2").unwrap_err(),
+            "line 2: a is set twice"
+        );
+        assert_eq!(
+            toml::parse("a = \"open").unwrap_err(),
+            "line 1: unterminated string"
+        );
+        assert!(toml::parse("a = 1 b").is_err());
+    }
+
+    #[test]
+    fn test_directives() {
+        assert_eq!(
+            directives(
+                "sqlite_query \"SELECT 'a;b' FROM t\";  # the query\n\
+                 sqlite_param :id $arg_id int;\n"
+            )
+            .unwrap(),
+            vec![
+                (
+                    "sqlite_query".to_string(),
+                    vec!["SELECT 'a;b' FROM t".to_string()]
+                ),
+                (
+                    "sqlite_param".to_string(),
+                    vec![":id".to_string(), "$arg_id".to_string(), "int".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(
+            directives("sqlite_query x").unwrap_err(),
+            "sqlite_query is missing its ;"
+        );
+
+        let mut conf = ModuleConfig::default();
+        assert_eq!(
+            directive(&mut conf, "sqlite_captcha", vec![]).unwrap_err(),
+            "sqlite_captcha is not supported in fixtures"
+        );
+        assert_eq!(
+            directive(&mut conf, "sqlite_query", vec![]).unwrap_err(),
+            "invalid number of arguments (0)"
+        );
+        assert_eq!(
+            directive(
+                &mut conf,
+                "sqlite_query_define",
+                vec!["a".into(), "b".into()]
+            )
+            .unwrap_err(),
+            "sqlite_query_define is not allowed in a location"
+        );
+        assert!(directive(&mut conf, "sqlite_health", vec![]).is_err());
+        assert!(directive(&mut conf, "sqlite_typo", vec![]).is_err());
+        directive(
+            &mut conf,
+            "sqlite_methods",
+            vec!["POST".into(), "PUT".into()],
+        )
+        .unwrap();
+        assert_eq!(conf.write_methods.len(), 2);
+    }
+}
@@ -0,0 +1,550 @@
+//! Response formats, one [`ResponseFormatter`] per [`ContentType`] (pure functions)
+//!
+//! A request's content type is negotiated once, and the registry's formatter
+//! for it turns the location's output into the body and headers sent. Some
+//! formats are made from the page the location's template renders (HTML,
+//! PDF) and the rest from its rows, so the handler asks the formatter which
+//! it needs before running the query. A new format is a formatter added to
+//! [`FormatterRegistry::standard`].
+
+use crate::content_type::ContentType;
+use crate::stream::Lines;
+use crate::types::{Feed, FeedFormat};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a location produced for a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output<'a> {
+    /// The page its template rendered
+    Page(&'a str),
+    /// Its rows as the JSON document API clients receive: an array, or
+    /// `results` and `pagination` for paginated locations
+    Rows(&'a str),
+}
+
+/// What a formatter knows about the request besides the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseMeta<'a> {
+    /// The location's URI
+    pub uri: &'a str,
+    /// The server's `scheme://host`, for links that must be absolute
+    pub base_url: &'a str,
+    /// The location's `sqlite_feed`
+    pub feed: Option<&'a Feed>,
+}
+
+/// A response ready to send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormattedResponse {
+    pub body: Vec<u8>,
+    pub headers: Vec<(&'static str, String)>,
+}
+
+/// A response to stream, its body made a line at a time as it is sent
+pub struct StreamedResponse {
+    pub lines: Lines,
+    pub headers: Vec<(&'static str, String)>,
+}
+
+/// One output format
+pub trait ResponseFormatter {
+    /// The content type this formats
+    fn content_type(&self) -> ContentType;
+
+    /// Whether this is made from the rendered page rather than the rows
+    fn needs_page(&self) -> bool;
+
+    /// Whether the body is lines a slow client may be sent a part at a time,
+    /// with `sqlite_stream_buffer`
+    fn streams(&self) -> bool {
+        false
+    }
+
+    /// The response for a request's output with its body as lines, for
+    /// formats that [`stream`](Self::streams)
+    fn render_lines(
+        &self,
+        output: &Output,
+        meta: &ResponseMeta,
+    ) -> Result<StreamedResponse, String> {
+        let response = self.render(output, meta)?;
+        Ok(StreamedResponse {
+            lines: Box::new(std::iter::once(response.body)),
+            headers: response.headers,
+        })
+    }
+
+    /// The response for a request's output; output of the other kind than
+    /// [`needs_page`](Self::needs_page) asks for is refused
+    fn render(&self, output: &Output, meta: &ResponseMeta) -> Result<FormattedResponse, String>;
+}
+
+/// The formatters a request's content type is looked up in
+#[derive(Default)]
+pub struct FormatterRegistry {
+    formatters: Vec<Box<dyn ResponseFormatter>>,
+}
+
+impl FormatterRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every format this build supports
+    pub fn standard() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(HtmlFormatter));
+        registry.register(Box::new(JsonFormatter));
+        registry.register(Box::new(NdjsonFormatter));
+        registry.register(Box::new(FeedFormatter(FeedFormat::Atom)));
+        registry.register(Box::new(FeedFormatter(FeedFormat::Rss)));
+        #[cfg(feature = "pdf")]
+        registry.register(Box::new(PdfFormatter));
+        #[cfg(feature = "xlsx")]
+        registry.register(Box::new(XlsxFormatter));
+        registry
+    }
+
+    /// Add a formatter, replacing any for the same content type
+    pub fn register(&mut self, formatter: Box<dyn ResponseFormatter>) {
+        let content_type = formatter.content_type();
+        self.formatters.retain(|f| f.content_type() != content_type);
+        self.formatters.push(formatter);
+    }
+
+    /// Every formatter, in the order they were registered
+    pub fn iter(&self) -> impl Iterator<Item = &dyn ResponseFormatter> {
+        self.formatters.iter().map(|f| f.as_ref())
+    }
+
+    /// The formatter for `content_type`, if this build has one
+    pub fn get(&self, content_type: ContentType) -> Option<&dyn ResponseFormatter> {
+        self.formatters
+            .iter()
+            .find(|f| f.content_type() == content_type)
+            .map(|f| f.as_ref())
+    }
+}
+
+/// The rendered page, as it is
+pub struct HtmlFormatter;
+
+impl ResponseFormatter for HtmlFormatter {
+    fn content_type(&self) -> ContentType {
+        ContentType::Html
+    }
+
+    fn needs_page(&self) -> bool {
+        true
+    }
+
+    fn render(&self, output: &Output, _meta: &ResponseMeta) -> Result<FormattedResponse, String> {
+        match output {
+            Output::Page(html) => Ok(with_content_type(self, html.as_bytes().to_vec())),
+            Output::Rows(_) => Err("HTML is made from a rendered page".to_string()),
+        }
+    }
+}
+
+/// The rows' JSON document, as it is
+pub struct JsonFormatter;
+
+impl ResponseFormatter for JsonFormatter {
+    fn content_type(&self) -> ContentType {
+        ContentType::Json
+    }
+
+    fn needs_page(&self) -> bool {
+        false
+    }
+
+    fn render(&self, output: &Output, _meta: &ResponseMeta) -> Result<FormattedResponse, String> {
+        match output {
+            Output::Rows(json) => Ok(with_content_type(self, json.as_bytes().to_vec())),
+            Output::Page(_) => Err("JSON is made from rows".to_string()),
+        }
+    }
+}
+
+/// The rows as newline-delimited JSON (`?format=ndjson`): one object per
+/// line, for `jq` and log pipelines
+///
+/// A paginated location's rows are its `results`; the page's neighbours are
+/// still linked from the `Link` header.
+pub struct NdjsonFormatter;
+
+impl ResponseFormatter for NdjsonFormatter {
+    fn content_type(&self) -> ContentType {
+        ContentType::Ndjson
+    }
+
+    fn needs_page(&self) -> bool {
+        false
+    }
+
+    fn streams(&self) -> bool {
+        true
+    }
+
+    fn render(&self, output: &Output, meta: &ResponseMeta) -> Result<FormattedResponse, String> {
+        let Output::Rows(json) = output else {
+            return Err("NDJSON is made from rows".to_string());
+        };
+        let mut body = Vec::with_capacity(json.len());
+        for line in self.render_lines(output, meta)?.lines {
+            body.extend_from_slice(&line);
+        }
+        Ok(with_content_type(self, body))
+    }
+
+    fn render_lines(
+        &self,
+        output: &Output,
+        _meta: &ResponseMeta,
+    ) -> Result<StreamedResponse, String> {
+        let Output::Rows(json) = output else {
+            return Err("NDJSON is made from rows".to_string());
+        };
+        let lines = parse_rows(json)?.into_iter().map(|row| {
+            let mut line = serde_json::to_vec(&row).expect("a JSON value always serializes");
+            line.push(b'\n');
+            line
+        });
+        Ok(StreamedResponse {
+            lines: Box::new(lines),
+            headers: with_content_type(self, Vec::new()).headers,
+        })
+    }
+}
+
+/// The rows as the Atom or RSS document of a `sqlite_feed` location, one
+/// entry per row (a paginated location's `results`)
+pub struct FeedFormatter(pub FeedFormat);
+
+impl ResponseFormatter for FeedFormatter {
+    fn content_type(&self) -> ContentType {
+        ContentType::for_feed(self.0)
+    }
+
+    fn needs_page(&self) -> bool {
+        false
+    }
+
+    fn render(&self, output: &Output, meta: &ResponseMeta) -> Result<FormattedResponse, String> {
+        let Output::Rows(json) = output else {
+            return Err("a feed is made from rows".to_string());
+        };
+        let feed = meta
+            .feed
+            .filter(|feed| feed.format() == self.0)
+            .ok_or("the location has no sqlite_feed in this format")?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let document = crate::feed::render(feed, &parse_rows(json)?, meta.base_url, meta.uri, now)?;
+        Ok(with_content_type(self, document.into_bytes()))
+    }
+}
+
+/// The rendered page as a PDF document (`?format=pdf`), named after the URI
+/// for browsers that save it
+#[cfg(feature = "pdf")]
+pub struct PdfFormatter;
+
+#[cfg(feature = "pdf")]
+impl ResponseFormatter for PdfFormatter {
+    fn content_type(&self) -> ContentType {
+        ContentType::Pdf
+    }
+
+    fn needs_page(&self) -> bool {
+        true
+    }
+
+    fn render(&self, output: &Output, meta: &ResponseMeta) -> Result<FormattedResponse, String> {
+        let Output::Page(html) = output else {
+            return Err("PDF is made from a rendered page".to_string());
+        };
+        let mut response = with_content_type(self, crate::pdf::render(html)?);
+        response.headers.push((
+            "Content-Disposition",
+            format!("inline; filename=\"{}\"", download_name(meta.uri, "pdf")),
+        ));
+        Ok(response)
+    }
+}
+
+/// The rows as an Excel workbook (`?format=xlsx`), one row per result under
+/// a header row of column names
+#[cfg(feature = "xlsx")]
+pub struct XlsxFormatter;
+
+#[cfg(feature = "xlsx")]
+impl ResponseFormatter for XlsxFormatter {
+    fn content_type(&self) -> ContentType {
+        ContentType::Xlsx
+    }
+
+    fn needs_page(&self) -> bool {
+        false
+    }
+
+    fn render(&self, output: &Output, meta: &ResponseMeta) -> Result<FormattedResponse, String> {
+        let Output::Rows(json) = output else {
+            return Err("a workbook is made from rows".to_string());
+        };
+        let mut response = with_content_type(self, crate::xlsx::render(&parse_rows(json)?)?);
+        response.headers.push((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}\"",
+                download_name(meta.uri, "xlsx")
+            ),
+        ));
+        Ok(response)
+    }
+}
+
+/// The download name for a document: its URI's last segment, slugified
+#[cfg_attr(not(any(feature = "pdf", feature = "xlsx")), allow(dead_code))]
+fn download_name(uri: &str, extension: &str) -> String {
+    let slug = uri
+        .rsplit('/')
+        .find(|segment| !segment.is_empty())
+        .map(crate::functions::slugify)
+        .unwrap_or_default();
+    if slug.is_empty() {
+        format!("page.{}", extension)
+    } else {
+        format!("{}.{}", slug, extension)
+    }
+}
+
+/// The rows of a JSON document: the array itself, or a paginated
+/// document's `results`
+fn parse_rows(json: &str) -> Result<Vec<serde_json::Value>, String> {
+    match serde_json::from_str(json).map_err(|e| e.to_string())? {
+        serde_json::Value::Array(rows) => Ok(rows),
+        serde_json::Value::Object(mut page) => match page.remove("results") {
+            Some(serde_json::Value::Array(rows)) => Ok(rows),
+            _ => Err("a paginated document has no results".to_string()),
+        },
+        _ => Err("rows are an array".to_string()),
+    }
+}
+
+fn with_content_type(formatter: &dyn ResponseFormatter, body: Vec<u8>) -> FormattedResponse {
+    FormattedResponse {
+        body,
+        headers: vec![(
+            "Content-Type",
+            formatter.content_type().content_type_header().to_string(),
+        )],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const META: ResponseMeta = ResponseMeta {
+        uri: "/invoices/INV-42",
+        base_url: "https://example.com",
+        feed: None,
+    };
+
+    #[test]
+    fn test_standard_formatters() {
+        let registry = FormatterRegistry::standard();
+
+        let html = registry.get(ContentType::Html).unwrap();
+        assert!(html.needs_page());
+        assert!(!html.streams());
+        let response = html.render(&Output::Page("<p>Hi</p>"), &META).unwrap();
+        assert_eq!(response.body, b"<p>Hi</p>");
+        assert_eq!(
+            response.headers,
+            vec![("Content-Type", "text/html; charset=utf-8".to_string())]
+        );
+        assert!(html.render(&Output::Rows("[]"), &META).is_err());
+
+        let json = registry.get(ContentType::Json).unwrap();
+        assert!(!json.needs_page());
+        let response = json.render(&Output::Rows("[{\"id\":1}]"), &META).unwrap();
+        assert_eq!(response.body, b"[{\"id\":1}]");
+        assert_eq!(
+            response.headers,
+            vec![(
+                "Content-Type",
+                "application/json; charset=utf-8".to_string()
+            )]
+        );
+        assert!(json.render(&Output::Page("<p>Hi</p>"), &META).is_err());
+
+        assert_eq!(
+            registry.get(ContentType::Pdf).is_some(),
+            cfg!(feature = "pdf")
+        );
+        assert_eq!(
+            registry.get(ContentType::Xlsx).is_some(),
+            cfg!(feature = "xlsx")
+        );
+    }
+
+    #[test]
+    fn test_download_name() {
+        assert_eq!(download_name("/invoices/INV-42", "pdf"), "inv-42.pdf");
+        assert_eq!(download_name("/reports/2024/", "xlsx"), "2024.xlsx");
+        assert_eq!(download_name("/", "pdf"), "page.pdf");
+    }
+
+    #[test]
+    fn test_ndjson_formatter() {
+        let registry = FormatterRegistry::standard();
+        let ndjson = registry.get(ContentType::Ndjson).unwrap();
+        assert!(!ndjson.needs_page());
+        assert!(ndjson.streams());
+
+        let response = ndjson
+            .render(&Output::Rows(r#"[{"id":1},{"id":2,"tags":["a"]}]"#), &META)
+            .unwrap();
+        assert_eq!(response.body, b"{\"id\":1}\n{\"id\":2,\"tags\":[\"a\"]}\n");
+        assert_eq!(
+            response.headers,
+            vec![("Content-Type", "application/x-ndjson".to_string())]
+        );
+
+        // Streamed, one line per row
+        let streamed = ndjson
+            .render_lines(&Output::Rows(r#"[{"id":1},{"id":2}]"#), &META)
+            .unwrap();
+        assert_eq!(streamed.headers, response.headers);
+        let lines: Vec<Vec<u8>> = streamed.lines.collect();
+        assert_eq!(
+            lines,
+            vec![b"{\"id\":1}\n".to_vec(), b"{\"id\":2}\n".to_vec()]
+        );
+
+        // A paginated document's results, without its pagination
+        let paginated = r#"{"results":[{"id":3}],"pagination":{"page":2}}"#;
+        let response = ndjson.render(&Output::Rows(paginated), &META).unwrap();
+        assert_eq!(response.body, b"{\"id\":3}\n");
+
+        let empty = ndjson.render(&Output::Rows("[]"), &META).unwrap();
+        assert!(empty.body.is_empty());
+        assert!(ndjson.render(&Output::Rows("42"), &META).is_err());
+        assert!(ndjson.render(&Output::Page("<p>Hi</p>"), &META).is_err());
+    }
+
+    #[test]
+    fn test_feed_formatter() {
+        let registry = FormatterRegistry::standard();
+        let atom = registry.get(ContentType::Atom).unwrap();
+        assert!(!atom.needs_page());
+
+        let feed = Feed::parse(&["atom", "name=Posts"]).unwrap();
+        let meta = ResponseMeta {
+            uri: "/feed",
+            base_url: "https://example.com",
+            feed: Some(&feed),
+        };
+        let paginated = r#"{"results":[{"title":"Hi","link":"/posts/1","updated":"2024-05-01"}],"pagination":{}}"#;
+        let response = atom.render(&Output::Rows(paginated), &meta).unwrap();
+        let document = String::from_utf8(response.body).unwrap();
+        assert!(document.contains("<title>Posts</title>"));
+        assert!(document.contains("<link href=\"https://example.com/posts/1\"/>"));
+        assert_eq!(
+            response.headers,
+            vec![(
+                "Content-Type",
+                "application/atom+xml; charset=utf-8".to_string()
+            )]
+        );
+
+        // The location's feed is in the other format, or there is none
+        let rss = registry.get(ContentType::Rss).unwrap();
+        assert!(rss.render(&Output::Rows("[]"), &meta).is_err());
+        assert!(atom.render(&Output::Rows("[]"), &META).is_err());
+        assert!(atom.render(&Output::Page("<p>Hi</p>"), &meta).is_err());
+    }
+
+    #[cfg(feature = "pdf")]
+    #[test]
+    fn test_pdf_formatter() {
+        let response = PdfFormatter
+            .render(&Output::Page("<h1>Invoice 42</h1>"), &META)
+            .unwrap();
+        assert!(response.body.starts_with(b"%PDF-"));
+        assert_eq!(
+            response.headers,
+            vec![
+                ("Content-Type", "application/pdf".to_string()),
+                (
+                    "Content-Disposition",
+                    "inline; filename=\"inv-42.pdf\"".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[cfg(feature = "xlsx")]
+    #[test]
+    fn test_xlsx_formatter() {
+        let paginated = r#"{"results":[{"id":1,"name":"Ada"}],"pagination":{}}"#;
+        let response = XlsxFormatter
+            .render(&Output::Rows(paginated), &META)
+            .unwrap();
+        // A workbook is a zip archive
+        assert!(response.body.starts_with(b"PK"));
+        assert_eq!(
+            response.headers,
+            vec![
+                (
+                    "Content-Type",
+                    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet".to_string()
+                ),
+                (
+                    "Content-Disposition",
+                    "attachment; filename=\"inv-42.xlsx\"".to_string()
+                ),
+            ]
+        );
+        assert!(
+            XlsxFormatter
+                .render(&Output::Page("<p>Hi</p>"), &META)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_register_replaces() {
+        struct Compact;
+        impl ResponseFormatter for Compact {
+            fn content_type(&self) -> ContentType {
+                ContentType::Json
+            }
+            fn needs_page(&self) -> bool {
+                false
+            }
+            fn render(
+                &self,
+                _output: &Output,
+                _meta: &ResponseMeta,
+            ) -> Result<FormattedResponse, String> {
+                Ok(with_content_type(self, b"{}".to_vec()))
+            }
+        }
+
+        let mut registry = FormatterRegistry::new();
+        assert!(registry.get(ContentType::Json).is_none());
+        registry.register(Box::new(JsonFormatter));
+        registry.register(Box::new(Compact));
+        let response = registry
+            .get(ContentType::Json)
+            .unwrap()
+            .render(&Output::Rows("[]"), &META)
+            .unwrap();
+        assert_eq!(response.body, b"{}");
+        assert_eq!(registry.formatters.len(), 1);
+    }
+}
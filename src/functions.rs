@@ -0,0 +1,148 @@
+//! SQL functions registered on every connection the module opens
+//!
+//! SQLite ships without these, so queries that use them would only work with
+//! extensions loaded by hand; registering them here keeps queries portable
+//! between nginx and the startup report (which prepares the same statements).
+
+use crate::domain;
+use regex::Regex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, Result};
+
+/// Register `regexp()`, `slugify()`, `uuid()` and `sha256()` on a connection
+///
+/// - `regexp(pattern, text)` backs `text REGEXP pattern`; NULL in, NULL out
+/// - `slugify(text)` lowercases ASCII letters and digits and joins the runs
+///   between them with `-` (`"Hello, World!"` gives `hello-world`)
+/// - `uuid()` returns a random version 4 UUID
+/// - `sha256(value)` is the lowercase hex SHA-256 of a blob, or of the bytes
+///   of text or a number, as `sqlite_blob` keys blobs; NULL in, NULL out
+pub fn register(conn: &Connection) -> Result<()> {
+    let deterministic = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+
+    conn.create_scalar_function("regexp", 2, deterministic, |ctx| {
+        // The pattern is compiled once per statement
+        let regex = ctx.get_or_create_aux(
+            0,
+            |pattern| -> Result<Regex, Box<dyn std::error::Error + Send + Sync>> {
+                Ok(Regex::new(pattern.as_str()?)?)
+            },
+        )?;
+        let text = ctx.get::<Option<String>>(1)?;
+        Ok(text.map(|text| regex.is_match(&text)))
+    })?;
+
+    conn.create_scalar_function("slugify", 1, deterministic, |ctx| {
+        Ok(ctx.get::<Option<String>>(0)?.map(|text| slugify(&text)))
+    })?;
+
+    conn.create_scalar_function("uuid", 0, FunctionFlags::SQLITE_UTF8, |_| {
+        let mut bytes = [0u8; 16];
+        // SQLite's own PRNG, the one behind randomblob()
+        unsafe { rusqlite::ffi::sqlite3_randomness(16, bytes.as_mut_ptr().cast()) };
+        Ok(format_uuid_v4(bytes))
+    })?;
+
+    conn.create_scalar_function("sha256", 1, deterministic, |ctx| {
+        Ok(match ctx.get_raw(0) {
+            ValueRef::Null => None,
+            ValueRef::Blob(bytes) | ValueRef::Text(bytes) => Some(domain::sha256_hex(bytes)),
+            ValueRef::Integer(n) => Some(domain::sha256_hex(n.to_string().as_bytes())),
+            ValueRef::Real(f) => Some(domain::sha256_hex(f.to_string().as_bytes())),
+        })
+    })?;
+
+    Ok(())
+}
+
+/// Lowercase ASCII letters and digits, with every other run of characters
+/// turned into a single `-` (none at either end)
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for word in text
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        if !slug.is_empty() {
+            slug.push('-');
+        }
+        slug.push_str(&word.to_ascii_lowercase());
+    }
+    slug
+}
+
+/// Format 16 random bytes as a version 4, variant 1 UUID
+fn format_uuid_v4(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Hello, World!"), "hello-world");
+        assert_eq!(
+            slugify("  The Left Hand of Darkness (1969) "),
+            "the-left-hand-of-darkness-1969"
+        );
+        assert_eq!(slugify("Café"), "caf");
+        assert_eq!(slugify("--"), "");
+    }
+
+    #[test]
+    fn test_registered_functions() {
+        let conn = Connection::open_in_memory().unwrap();
+        register(&conn).unwrap();
+
+        let matched: (bool, bool, Option<bool>) = conn
+            .query_row(
+                "SELECT 'Dune' REGEXP '^D', regexp('^d', 'Dune'), NULL REGEXP 'x'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(matched, (true, false, None));
+        assert!(
+            conn.query_row("SELECT 'x' REGEXP '('", [], |_| Ok(()))
+                .is_err()
+        );
+
+        let slug: String = conn
+            .query_row("SELECT slugify('Dune Messiah')", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(slug, "dune-messiah");
+
+        let (a, b): (String, String) = conn
+            .query_row("SELECT uuid(), uuid()", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 36);
+        assert_eq!(&a[14..15], "4");
+        assert!(matches!(&a[19..20], "8" | "9" | "a" | "b"));
+
+        let hashes: (String, String, Option<String>) = conn
+            .query_row(
+                "SELECT sha256(x'68656c6c6f'), sha256('hello'), sha256(NULL)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert_eq!(hashes, (hello.to_string(), hello.to_string(), None));
+    }
+}
@@ -0,0 +1,71 @@
+//! Panic containment at the FFI boundary
+//!
+//! Unwinding out of an `extern "C"` function is undefined behavior, so every
+//! entry point nginx calls runs its body through [`catch_panic`]. The panic
+//! payload may contain request data (SQL values, paths), so it is never logged
+//! or shown: callers only learn where the panic happened.
+
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+thread_local! {
+    static GUARDED: Cell<bool> = const { Cell::new(false) };
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// Wrap the panic hook so panics inside [`catch_panic`] only record their
+/// location instead of printing the payload to stderr (the nginx error log);
+/// panics anywhere else still reach the previous hook
+fn install_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !GUARDED.with(Cell::get) {
+                return previous(info);
+            }
+            let location = info
+                .location()
+                .map(|l| format!("{}:{}", l.file(), l.line()))
+                .unwrap_or_else(|| "unknown location".to_string());
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some(location));
+        }));
+    });
+}
+
+/// Run `f`, converting a panic into an error that names only its location
+pub fn catch_panic<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    install_hook();
+    let outer = GUARDED.with(|guarded| guarded.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    GUARDED.with(|guarded| guarded.set(outer));
+
+    result.map_err(|_| {
+        let location = LAST_PANIC
+            .with(|last| last.borrow_mut().take())
+            .unwrap_or_else(|| "unknown location".to_string());
+        format!("panic at {} (message redacted)", location)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch_panic_passes_results_through() {
+        assert_eq!(catch_panic(|| 42), Ok(42));
+    }
+
+    #[test]
+    fn test_catch_panic_redacts_message() {
+        let secret = "password=hunter2";
+        let error = catch_panic(|| -> u32 { panic!("leaked {}", secret) }).unwrap_err();
+
+        assert!(error.contains("guard.rs"), "{}", error);
+        assert!(error.contains("redacted"));
+        assert!(!error.contains("hunter2"));
+    }
+}
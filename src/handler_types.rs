@@ -1,186 +1,2165 @@
 //! Handler-specific types that guarantee correctness
 
 use crate::adapters::{NginxLogger, NginxVariableResolver, SqliteQueryExecutor};
-use crate::config::ModuleConfig;
-use crate::content_type::{ContentType, negotiate_content_type};
-use crate::domain::{Logger, RequestProcessor, ValidatedConfig};
-use crate::nginx_helpers::{get_doc_root_and_uri, send_json_response, send_response};
+use crate::config::{MainConfig, ModuleConfig};
+use crate::config_cache::LocationId;
+use crate::content_type::ContentType;
+use crate::domain::{
+    BatchExecutor, DbError, Flow, Logger, MenuCache, MethodRoute, Metrics, PageSizes, RequestPaths,
+    RequestProcessor, Stage, Timings, Tracer, ValidatedConfig, VariableResolver, WriteExecutor,
+    WriteOutcome, WriteResponse,
+};
+use crate::formatter::{
+    FormatterRegistry, Output, ResponseFormatter, ResponseMeta, StreamedResponse,
+};
+use crate::nginx_helpers::{
+    ResponseError, get_doc_root_and_uri, internal_error, read_request_body, record_error,
+    request_body, send_blob_response, send_formatted_response, send_json_response,
+    send_json_response_with_status, send_not_modified, send_response, send_response_with_status,
+    send_typescript_response, start_subrequest,
+};
 use crate::parsing;
-use crate::template::HandlebarsAdapter;
-use crate::{Module, domain};
+use crate::query;
+use crate::report;
+use crate::static_site;
+use crate::template::{self, HandlebarsAdapter};
+use crate::trace::{Stopwatch, Trace};
+use crate::types::{
+    AsOf, BlobRoute, Captcha, CountStrategy, DatabaseMap, DatabaseTemplate, Expansion, HitCounter,
+    JsonNulls, NginxVariable, Pagination, StreamBuffer, WriteMethod, WriteQuery,
+};
+use crate::typescript;
+use crate::{Module, capabilities, domain, health, worker};
 use ngx::core::Status;
-use ngx::http::{HttpModuleLocationConf, HttpModuleMainConf};
+use ngx::http::{HttpModule, HttpModuleLocationConf, HttpModuleMainConf};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub struct ValidConfigToken {
-    config: ValidatedConfig,
+    config: Arc<ValidatedConfig>,
+    paths: RequestPaths,
 }
 
 impl ValidConfigToken {
     /// Try to create a token from nginx request - returns None if config is invalid or request data unavailable
-    pub fn new(request: &mut ngx::http::Request) -> Option<Self> {
+    ///
+    /// A `sqlite_db` path with variables is resolved for the request first;
+    /// a request whose database cannot be named or does not exist is refused
+    /// with the status to answer it with.
+    ///
+    /// A worker validates each location once, and its later requests share
+    /// that configuration with their own [`RequestPaths`]; it is only copied
+    /// for a request that reads the past (`?as_of=`).
+    pub fn new(request: &mut ngx::http::Request) -> Result<Option<Self>, Status> {
         // Extract doc_root and uri from the request
         let (doc_root, uri) = match get_doc_root_and_uri(request) {
             Ok(res) => res,
             Err(e) => {
                 let logger = NginxLogger::new(request);
                 logger.error("nginx", &format!("Path resolution failed: {}", e));
-                return None;
+                return Ok(None);
             }
         };
 
         // Get the module configuration from the request
-        let config = Module::location_conf(request)?;
+        let Some(config) = Module::location_conf(request) else {
+            return Ok(None);
+        };
+
+        // Delegate to validate for actual validation
+        let tenant_db = request_database(request, config)?
+            .filter(|db_path| Some(db_path) != config.db_path.as_ref());
+        let validate = || match &tenant_db {
+            Some(db_path) => {
+                let tenant = ModuleConfig {
+                    db_path: Some(db_path.clone()),
+                    ..config.clone()
+                };
+                Self::validate(&tenant)
+            }
+            None => Self::validate(config),
+        };
+        let location = LocationId::of(config);
+        let validated = match worker::with_state(|state| state.config_cache()) {
+            Some(cache) => cache.get_or_validate(location, tenant_db.as_deref(), validate),
+            None => validate(),
+        };
+        let Some(config) = validated else {
+            return Ok(None);
+        };
+        let mut token = ValidConfigToken {
+            config,
+            paths: RequestPaths::new(doc_root, uri),
+        };
+        if let Some(as_of) = request_as_of(request, &token.config)? {
+            let config = Arc::make_mut(&mut token.config);
+            config.db_path = config.db_path.clone().at(as_of);
+        }
+        Ok(Some(token))
+    }
+
+    /// Create a token from config and context, without the worker's cache
+    #[cfg(test)]
+    fn from_config(config: &ModuleConfig, doc_root: String, uri: String) -> Option<Self> {
+        Self::validate(config).map(|config| ValidConfigToken {
+            config,
+            paths: RequestPaths::new(doc_root, uri),
+        })
+    }
+
+    /// The location's configuration, if it is ours to handle and valid
+    fn validate(config: &ModuleConfig) -> Option<Arc<ValidatedConfig>> {
+        // Locations that never set the required directives are not ours to handle;
+        // export and report locations answer with JSON, so they need no template
+        let has_query = config.query.is_some()
+            && (config.template_path.is_some()
+                || config.feed.is_some()
+                || config.export_dir.is_some()
+                || config.report.is_some());
+        if config.db_path.is_none() || !(has_query || config.rest.is_some()) {
+            return None;
+        }
+
+        // Parse and validate the configuration
+        parsing::parse_config(config).map(Arc::new).ok()
+    }
+
+    pub fn get(&self) -> &ValidatedConfig {
+        &self.config
+    }
+
+    /// The request's document root and URI
+    pub fn paths(&self) -> &RequestPaths {
+        &self.paths
+    }
+}
+
+/// The database a request reads: `sqlite_db` as configured, or with its
+/// variables resolved for this request, or as `sqlite_db_map` names it
+///
+/// A request whose variables name no safe path, or a file that does not
+/// exist, is answered with 404, since it asks for a tenant that is not there.
+pub fn request_database(
+    request: &mut ngx::http::Request,
+    config: &ModuleConfig,
+) -> Result<Option<String>, Status> {
+    if let Some(map) = &config.db_map {
+        return mapped_database(request, map).map(Some);
+    }
+    let Some(db_path) = &config.db_path else {
+        return Ok(None);
+    };
+    if !DatabaseTemplate::has_variables(db_path) {
+        return Ok(Some(db_path.clone()));
+    }
+    // The directive checked the path when the configuration was loaded
+    let template = DatabaseTemplate::parse(db_path).map_err(|e| {
+        NginxLogger::new(request).error("database", &e);
+        record_error(request, "internal", &e);
+        Status::from(ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR)
+    })?;
+    let mut resolver = NginxVariableResolver::new(request);
+    let resolved = template.resolve(|variable| resolver.resolve(variable.as_str()).ok());
+    match resolved {
+        Ok(path) if Path::new(&path).is_file() => Ok(Some(path)),
+        Ok(path) => no_database(request, &format!("No database at {}", path)),
+        Err(e) => no_database(request, &format!("No database: {}", e)),
+    }
+}
+
+/// The database `sqlite_db_map`'s control query names for this request,
+/// reused from this worker's earlier lookups for up to the map's `max_age`
+fn mapped_database(request: &mut ngx::http::Request, map: &DatabaseMap) -> Result<String, Status> {
+    let mut resolver = NginxVariableResolver::new(request);
+    let values: Vec<String> = map
+        .parameters()
+        .iter()
+        .map(|(_, variable)| resolver.resolve(variable.as_str()).unwrap_or_default())
+        .collect();
+
+    let cache = worker::with_state(|state| state.db_map_cache());
+    let now = Instant::now();
+    let database = match cache
+        .as_ref()
+        .and_then(|cache| cache.get(map, &values, now))
+    {
+        Some(database) => database,
+        None => {
+            let database = query::mapped_database(map, &values).map_err(|e| {
+                let message = format!("sqlite_db_map {}: {}", map.control().as_str(), e);
+                NginxLogger::new(request).error("database", &message);
+                record_error(request, "internal", &message);
+                Status::from(ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR)
+            })?;
+            if let Some(cache) = &cache {
+                cache.store(map, values.clone(), database.clone(), now);
+            }
+            database
+        }
+    };
+    match database {
+        Some(path) if Path::new(&path).is_file() => Ok(path),
+        Some(path) => no_database(request, &format!("No database at {}", path)),
+        None => no_database(
+            request,
+            &format!("No database mapped for '{}'", values.join("', '")),
+        ),
+    }
+}
+
+/// Answer a request whose database is not there with 404
+fn no_database<T>(request: &mut ngx::http::Request, message: &str) -> Result<T, Status> {
+    NginxLogger::new(request).info("database", message);
+    record_error(request, "no_database", message);
+    Err(ngx::http::HTTPStatus::NOT_FOUND.into())
+}
+
+/// The time `?as_of=` asks to read a location's `sqlite_history` tables at,
+/// if it has any; a time that cannot be read is answered with 400
+fn request_as_of(
+    request: &mut ngx::http::Request,
+    config: &ValidatedConfig,
+) -> Result<Option<AsOf>, Status> {
+    if config.db_path.history().is_empty() {
+        return Ok(None);
+    }
+    let value = NginxVariableResolver::new(request)
+        .resolve("$arg_as_of")
+        .unwrap_or_default();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    AsOf::parse(&value).map(Some).map_err(|e| {
+        NginxLogger::new(request).info("history", &e);
+        record_error(request, "invalid_parameter", &e);
+        ngx::http::HTTPStatus::BAD_REQUEST.into()
+    })
+}
+
+/// Answer a `sqlite_typescript` location with declarations for every other
+/// location's JSON responses, read from their queries as the databases are now
+pub fn serve_typescript(request: &mut ngx::http::Request) -> Status {
+    let Some(main_conf) = Module::main_conf(request) else {
+        return internal_error(request, ResponseError::MissingMainConfig);
+    };
+    // SAFETY: registered locations point at configuration that lives as long
+    // as the cycle serving this request, and nothing changes it after startup.
+    let locations: Vec<_> = main_conf
+        .locations
+        .iter()
+        .map(|location| unsafe { (&*location.config, &*location.core_conf) })
+        .filter(|(config, _)| !config.serves_endpoint())
+        .map(|(config, core_conf)| {
+            let name = core_conf.name.to_str().unwrap_or("<invalid utf-8>");
+            let validated = if config.database_per_request() {
+                Err("sqlite_db is chosen per request".to_string())
+            } else {
+                parsing::parse_config(config)
+            };
+            (name.to_string(), validated)
+        })
+        .collect();
+    send_typescript_response(request, &typescript::declarations(&locations))
+}
+
+/// Answer a `sqlite_capabilities` location with what this build and every
+/// location support, to requests whose gate variable is set
+///
+/// To other requests the endpoint does not exist, so a deployment does not
+/// reveal its features to anyone who guesses the path.
+pub fn serve_capabilities(request: &mut ngx::http::Request, gate: &NginxVariable) -> Status {
+    let open = NginxVariableResolver::new(request)
+        .resolve_variable(gate)
+        .is_ok_and(|value| !value.is_empty());
+    if !open {
+        return ngx::http::HTTPStatus::NOT_FOUND.into();
+    }
+    let Some(main_conf) = Module::main_conf(request) else {
+        return internal_error(request, ResponseError::MissingMainConfig);
+    };
+    // SAFETY: registered locations point at configuration that lives as long
+    // as the cycle serving this request, and nothing changes it after startup.
+    let locations: Vec<_> = main_conf
+        .locations
+        .iter()
+        .map(|location| unsafe {
+            let name = (*location.core_conf)
+                .name
+                .to_str()
+                .unwrap_or("<invalid utf-8>");
+            (name.to_string(), &*location.config)
+        })
+        .collect();
+    let body = capabilities::matrix(&locations).to_string();
+    send_json_response(request, &body)
+}
+
+/// Answer a `sqlite_health` location with this worker's health and every
+/// report's status, as 503 when something is failing
+pub fn serve_health(request: &mut ngx::http::Request) -> Status {
+    let Some(main_conf) = Module::main_conf(request) else {
+        return internal_error(request, ResponseError::MissingMainConfig);
+    };
+    // SAFETY: see serve_capabilities
+    let locations: Vec<_> = main_conf
+        .locations
+        .iter()
+        .map(|location| unsafe {
+            let name = (*location.core_conf)
+                .name
+                .to_str()
+                .unwrap_or("<invalid utf-8>");
+            (name.to_string(), &*location.config)
+        })
+        .collect();
+    let worker = worker::with_state(|state| {
+        let reports = state.report_scheduler();
+        health::WorkerHealth {
+            pid: state.pid(),
+            uptime_secs: state.uptime().as_secs(),
+            requests: state.requests(),
+            scheduled_runs: reports.runs(),
+            scheduled_failures: reports.failed(),
+            last_error: reports.last_error(),
+        }
+    });
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let (healthy, document) = health::document(worker.as_ref(), &locations, now);
+    let status = if healthy {
+        ngx::http::HTTPStatus::OK
+    } else {
+        ngx::http::HTTPStatus::SERVICE_UNAVAILABLE
+    };
+    send_json_response_with_status(request, &document.to_string(), status)
+}
+
+/// Answer a `sqlite_blob` location: the blob whose SHA-256 is the last path
+/// segment, once its content is checked against that hash
+///
+/// A blob whose content no longer hashes to its key is never sent, since
+/// clients would cache it for good under the wrong name.
+pub fn serve_blob(request: &mut ngx::http::Request, route: &BlobRoute) -> Status {
+    let method = request.method();
+    if method.as_str() != "GET" && method.as_str() != "HEAD" {
+        return ngx::http::HTTPStatus::NOT_ALLOWED.into();
+    }
+    let Some(config) = Module::location_conf(request) else {
+        return ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+    };
+    let db_path = match request_database(request, config) {
+        Ok(Some(db_path)) => db_path,
+        Ok(None) => {
+            NginxLogger::new(request).error("blob", "sqlite_db is not set");
+            return ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+        }
+        Err(status) => return status,
+    };
+    let uri = match get_doc_root_and_uri(request) {
+        Ok((_, uri)) => uri,
+        Err(e) => {
+            NginxLogger::new(request).error("nginx", &format!("Path resolution failed: {}", e));
+            return ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+        }
+    };
+    let Some(hash) = domain::content_hash(&uri) else {
+        return ngx::http::HTTPStatus::NOT_FOUND.into();
+    };
+
+    let (data, content_type) = match query::stored_blob(db_path.as_str(), route, &hash) {
+        Ok(Some(blob)) => blob,
+        Ok(None) => return ngx::http::HTTPStatus::NOT_FOUND.into(),
+        Err(e) => {
+            let message = format!("{}: {}", route.table(), e);
+            NginxLogger::new(request).error("blob", &message);
+            record_error(request, "internal", &message);
+            return ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+        }
+    };
+    if domain::sha256_hex(&data) != hash {
+        let message = format!(
+            "{}: content of {} does not match its hash",
+            route.table(),
+            hash
+        );
+        NginxLogger::new(request).error("blob", &message);
+        record_error(request, "internal", &message);
+        return ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+    }
+
+    let if_none_match = NginxVariableResolver::new(request)
+        .resolve("$http_if_none_match")
+        .unwrap_or_default();
+    if domain::etag_matches(&if_none_match, &hash) {
+        return send_not_modified(request, &hash);
+    }
+    let content_type = content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+    send_blob_response(request, &data, &content_type, &hash)
+}
+
+/// The request the stages answer: nginx's, or a stand-in for it in tests
+///
+/// Stages read and answer a request only through these, so the pipeline
+/// runs the same without nginx; what only nginx can do (waiting for a body,
+/// subrequests) is reached through [`StageRequest::nginx`].
+pub trait StageRequest {
+    /// The request method, e.g. `GET`
+    fn method(&self) -> String;
+
+    /// The request's variables, each resolved once for as long as the
+    /// resolver lives
+    fn variables(&mut self) -> Box<dyn VariableResolver + '_>;
+
+    /// A logger for messages about the request
+    fn logger(&self) -> Box<dyn Logger>;
+
+    /// The location's configuration as written
+    fn location_conf(&self) -> Option<&ModuleConfig>;
+
+    /// The module's http-level configuration
+    fn main_conf(&self) -> Option<&MainConfig>;
+
+    /// The content type the request's Accept headers ask for
+    fn accepted_content_type(&self) -> ContentType;
+
+    /// Set `$sqlite_error_code` and `$sqlite_error_message` to why the
+    /// request failed
+    fn record_error(&mut self, code: &str, message: &str);
+
+    /// Add a response header, `false` if it could not be
+    fn add_header(&mut self, name: &'static str, value: &str) -> bool;
+
+    /// Send the response
+    fn send(
+        &mut self,
+        status: ngx::http::HTTPStatus,
+        headers: &[(&'static str, &str)],
+        body: &[u8],
+    ) -> Status;
+
+    /// Send a response made of lines a part at a time, keeping what is
+    /// unsent within `buffer`
+    fn send_streamed(&mut self, response: StreamedResponse, buffer: StreamBuffer) -> Status;
+
+    /// Whether the request's body has been read
+    fn body_read(&mut self) -> bool;
+
+    /// The request's body, once read
+    fn body(&mut self) -> Result<Vec<u8>, String>;
+
+    /// Discard the body of a request answered without reading it
+    fn discard_body(&mut self);
+
+    /// nginx's request, for the stages only nginx can run
+    fn nginx(&mut self) -> Option<&mut ngx::http::Request>;
+}
+
+/// Process a request with guaranteed valid configuration
+/// Returns Status directly - no Result needed, types prove correctness
+///
+/// The request runs through the location's [`location_stages`] until one
+/// answers it.
+pub fn process_request(
+    request: &mut dyn StageRequest,
+    validated_config: &ValidatedConfig,
+    paths: &RequestPaths,
+) -> Status {
+    // Log initial processing
+    request
+        .logger()
+        .debug("handler", &format!("Processing request for {}", paths.uri));
+
+    let stages = location_stages(validated_config);
+    let mut context = RequestContext::new(request, validated_config, paths);
+    let status = match domain::run_stages(&stages, &mut context) {
+        Some((stage, status)) => {
+            context
+                .request
+                .logger()
+                .debug("handler", &format!("Answered by the {} stage", stage));
+            status
+        }
+        None => {
+            let message = "no stage answered the request";
+            context.request.logger().error("handler", message);
+            context.request.record_error("internal", message);
+            ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+        }
+    };
+    log_trace(context.request, context.trace.as_deref());
+    status
+}
+
+/// What the stages handling a request have learned about it so far
+struct RequestContext<'r, 'c> {
+    request: &'r mut dyn StageRequest,
+    /// The location's configuration as sqlite_query_if, sqlite_filter and
+    /// sqlite_sort_param have left it for this request
+    config: Cow<'c, ValidatedConfig>,
+    /// The location's configuration as written
+    location: &'c ValidatedConfig,
+    paths: &'c RequestPaths,
+    template: Option<domain::ResolvedTemplate>,
+    params: domain::ParameterSet,
+    trace: Option<Arc<Trace>>,
+    hits: Option<u64>,
+    formatters: FormatterRegistry,
+    content_type: ContentType,
+    static_page: Option<PathBuf>,
+    /// Whether the page rendered rows the query found, rather than an error
+    /// page or a fallback query's rows; only such pages are saved
+    found: bool,
+    page: domain::PagePosition,
+    expansions: Vec<&'c Expansion>,
+    fields: Option<domain::FieldSelection>,
+    /// The rendered page, or the rows' JSON document
+    output: Option<String>,
+}
+
+impl<'r, 'c> RequestContext<'r, 'c> {
+    fn new(
+        request: &'r mut dyn StageRequest,
+        config: &'c ValidatedConfig,
+        paths: &'c RequestPaths,
+    ) -> Self {
+        RequestContext {
+            request,
+            config: Cow::Borrowed(config),
+            location: config,
+            paths,
+            template: None,
+            params: Vec::new(),
+            trace: None,
+            hits: None,
+            formatters: FormatterRegistry::standard(),
+            content_type: ContentType::Json,
+            static_page: None,
+            found: false,
+            page: domain::PagePosition::Number(1),
+            expansions: Vec::new(),
+            fields: None,
+            output: None,
+        }
+    }
+}
+
+type RequestStage<'r, 'c> = Box<dyn Stage<RequestContext<'r, 'c>, Status>>;
+
+/// The stages a request to `config`'s location runs through, in order
+///
+/// Stages for directives the location does not use are left out, so a
+/// location pays only for the features it has.
+fn location_stages<'r, 'c>(config: &ValidatedConfig) -> Vec<RequestStage<'r, 'c>> {
+    let mut stages: Vec<RequestStage<'r, 'c>> = vec![Box::new(ResolveTemplate)];
+    if config.reads_body() {
+        stages.push(Box::new(ReadBody));
+    }
+    stages.push(Box::new(ResolveParams));
+    if config.dry_run.is_some() {
+        stages.push(Box::new(DryRunReport));
+    }
+    if config.export_dir.is_some() {
+        stages.push(Box::new(Export));
+    }
+    if config.report.is_some() {
+        stages.push(Box::new(SaveReport));
+    }
+    if config.honeypot.is_some() {
+        stages.push(Box::new(HoneypotCheck));
+    }
+    if config.captcha.is_some() {
+        stages.push(Box::new(CaptchaVerdict));
+    }
+    if config.batch {
+        stages.push(Box::new(Batch));
+    }
+    if config.trace {
+        stages.push(Box::new(StartTrace));
+    }
+    if !config.write_queries.is_empty() {
+        stages.push(Box::new(Write));
+    }
+    if config.hit_counter.is_some() {
+        stages.push(Box::new(CountHit));
+    }
+    if !config.query_rules.is_empty() {
+        stages.push(Box::new(SelectQuery));
+    }
+    if !config.filters.is_empty() {
+        stages.push(Box::new(Filter));
+    }
+    if config.sort.is_some() {
+        stages.push(Box::new(Sort));
+    }
+    stages.push(Box::new(Negotiate));
+    if config.try_static.is_some() {
+        stages.push(Box::new(StaticLookup));
+    }
+    if config.pagination.is_some() {
+        stages.push(Box::new(ChoosePage));
+    }
+    stages.push(Box::new(Shape));
+    stages.push(Box::new(RunQuery));
+    if config.try_static.is_some_and(|t| t.save()) {
+        stages.push(Box::new(StaticSave));
+    }
+    stages.push(Box::new(Respond));
+    stages
+}
+
+/// Resolve the template path (pure function - cannot fail), in the theme
+/// and template variant the request selects
+struct ResolveTemplate;
+
+impl Stage<RequestContext<'_, '_>, Status> for ResolveTemplate {
+    fn name(&self) -> &'static str {
+        "template"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let variant_config = variant_config(ctx.location, ctx.request);
+        let template_config = variant_config.as_ref().unwrap_or(ctx.location);
+        ctx.template = match active_theme(ctx.location, ctx.request) {
+            Some(active) => {
+                template_config.resolve_themed_template_path(ctx.paths, &active, |path| {
+                    Path::new(path).is_file()
+                })
+            }
+            None => domain::resolve_template_path(template_config, ctx.paths),
+        };
+        if let Some(template) = &ctx.template {
+            ctx.request.logger().debug(
+                "template",
+                &format!("Resolved template: {}", template.full_path()),
+            );
+        }
+        Flow::Continue
+    }
+}
+
+/// Parameters bound from the body wait until nginx has read it; the body
+/// handler then processes the request again
+struct ReadBody;
+
+impl Stage<RequestContext<'_, '_>, Status> for ReadBody {
+    fn name(&self) -> &'static str {
+        "body"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        if ctx.request.body_read() {
+            return Flow::Continue;
+        }
+        Flow::Respond(match ctx.request.nginx() {
+            Some(request) => read_request_body(request, crate::body_handler),
+            None => needs_nginx(ctx.request, self.name()),
+        })
+    }
+}
+
+struct ResolveParams;
+
+impl Stage<RequestContext<'_, '_>, Status> for ResolveParams {
+    fn name(&self) -> &'static str {
+        "params"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        match resolve_request_parameters(ctx.request, &ctx.config) {
+            Ok(params) => {
+                ctx.params = params;
+                Flow::Continue
+            }
+            Err(status) => Flow::Respond(status),
+        }
+    }
+}
+
+/// A request that sets the `sqlite_dry_run` variable is answered with the
+/// statements it would run and what they would bind, before anything is
+/// written or queried
+struct DryRunReport;
+
+impl Stage<RequestContext<'_, '_>, Status> for DryRunReport {
+    fn name(&self) -> &'static str {
+        "dry_run"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let Some(dry_run) = &ctx.config.dry_run else {
+            return Flow::Continue;
+        };
+        let mut resolver = ctx.request.variables();
+        let requested = resolver
+            .resolve_variable(dry_run.gate())
+            .is_ok_and(|value| !value.is_empty());
+        if !requested {
+            return Flow::Continue;
+        }
+
+        // The query as sqlite_query_if, sqlite_filter and sqlite_sort_param
+        // would leave it
+        let mut params = ctx.params.clone();
+        let mut query = domain::select_query(&ctx.config, &mut resolver).clone();
+        let shaped = domain::filtered_query(&query, &ctx.config.filters, &mut resolver).and_then(
+            |filtered| {
+                if let Some((filtered, values)) = filtered {
+                    query = filtered;
+                    params.extend(values);
+                }
+                match &ctx.config.sort {
+                    Some(sort) => domain::sorted_query(&query, sort, &mut resolver),
+                    None => Ok(None),
+                }
+            },
+        );
+        drop(resolver);
+        match shaped {
+            Ok(Some(sorted)) => query = sorted,
+            Ok(None) => {}
+            Err(e) => return Flow::Respond(send_param_error(ctx.request, &e)),
+        }
+        let method = ctx.request.method();
+        let writes: Vec<&WriteQuery> = ctx
+            .config
+            .write_queries
+            .iter()
+            .filter(|(write_method, _)| write_method.as_str() == method.as_str())
+            .map(|(_, write)| write)
+            .collect();
+
+        let report = domain::dry_run_report(&query, &writes, &params, &ctx.config, dry_run);
+        ctx.request.logger().info(
+            "dry_run",
+            &format!("Answered a dry run binding {} value(s)", params.len()),
+        );
+        Flow::Respond(send_json_response(ctx.request, &report.to_string()))
+    }
+}
+
+/// POST to a sqlite_export location renders every page its query lists
+struct Export;
+
+impl Stage<RequestContext<'_, '_>, Status> for Export {
+    fn name(&self) -> &'static str {
+        "export"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        match (&ctx.config.export_dir, ctx.request.method().as_str()) {
+            (Some(directory), "POST") => Flow::Respond(match ctx.request.nginx() {
+                Some(request) => start_export(request, &ctx.config, directory),
+                None => needs_nginx(ctx.request, self.name()),
+            }),
+            _ => Flow::Continue,
+        }
+    }
+}
+
+/// POST to a sqlite_report location saves its rows as the report's file
+struct SaveReport;
+
+impl Stage<RequestContext<'_, '_>, Status> for SaveReport {
+    fn name(&self) -> &'static str {
+        "report"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let Some(report) = ctx.config.report.as_ref() else {
+            return Flow::Continue;
+        };
+        if ctx.request.method().as_str() != "POST" {
+            return Flow::Continue;
+        }
+
+        let executor = SqliteQueryExecutor::for_location(&ctx.config);
+        let saved = report::generate(&executor, &ctx.config, report, &ctx.params);
+        let path = report.path().to_string_lossy().into_owned();
+        Flow::Respond(match saved {
+            Ok(rows) => {
+                ctx.request.logger().info(
+                    "report",
+                    &format!(
+                        "Saved {} row(s) of report {} to {}",
+                        rows,
+                        report.name(),
+                        path
+                    ),
+                );
+                let body =
+                    serde_json::json!({ "report": report.name(), "path": path, "rows": rows });
+                send_json_response(ctx.request, &body.to_string())
+            }
+            Err(e) => {
+                ctx.request
+                    .logger()
+                    .error("report", &format!("Report {} failed: {}", report.name(), e));
+                ctx.request.record_error("report_failed", &e);
+                let body = serde_json::json!({
+                    "error": "Report failed",
+                    "code": "report_failed",
+                    "details": e,
+                });
+                send_json_error(
+                    ctx.request,
+                    &body.to_string(),
+                    ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR,
+                )
+            }
+        })
+    }
+}
+
+/// Turn away writes that look automated before touching the database
+struct HoneypotCheck;
+
+impl Stage<RequestContext<'_, '_>, Status> for HoneypotCheck {
+    fn name(&self) -> &'static str {
+        "honeypot"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let method = ctx.request.method();
+        let route = domain::route_method(&ctx.config, method.as_str());
+        let (MethodRoute::Write(..), Some(honeypot)) = (route, &ctx.config.honeypot) else {
+            return Flow::Continue;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let rejection = domain::honeypot_rejection(honeypot, &mut *ctx.request.variables(), now);
+        let Some(reason) = rejection else {
+            return Flow::Continue;
+        };
+        ctx.request
+            .logger()
+            .warn("honeypot", &format!("Rejected write: {}", reason));
+        worker::with_state(|state| state.record_spam_rejection());
+        let error_obj = serde_json::json!({ "error": "Submission rejected" });
+        Flow::Respond(send_json_error(
+            ctx.request,
+            &error_obj.to_string(),
+            ngx::http::HTTPStatus(422),
+        ))
+    }
+}
+
+/// A captcha is verified by a subrequest to the provider; the request is
+/// handled again once the verdict is in
+struct CaptchaVerdict;
+
+impl Stage<RequestContext<'_, '_>, Status> for CaptchaVerdict {
+    fn name(&self) -> &'static str {
+        "captcha"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let method = ctx.request.method();
+        let route = domain::route_method(&ctx.config, method.as_str());
+        let (MethodRoute::Write(..), Some(captcha)) = (route, &ctx.config.captcha) else {
+            return Flow::Continue;
+        };
+        let Some(request) = ctx.request.nginx() else {
+            return Flow::Respond(needs_nginx(ctx.request, self.name()));
+        };
+        match captcha_verdict(request, captcha) {
+            Some(true) => Flow::Continue,
+            Some(false) => {
+                worker::with_state(|state| state.record_spam_rejection());
+                let error_obj = serde_json::json!({ "error": "Captcha verification failed" });
+                Flow::Respond(send_json_error(
+                    request,
+                    &error_obj.to_string(),
+                    ngx::http::HTTPStatus(422),
+                ))
+            }
+            None => Flow::Respond(verify_captcha(request, captcha)),
+        }
+    }
+}
+
+/// Batches carry their parameter sets in the body; process_batch answers
+/// once nginx has read it
+struct Batch;
+
+impl Stage<RequestContext<'_, '_>, Status> for Batch {
+    fn name(&self) -> &'static str {
+        "batch"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let method = ctx.request.method();
+        let route = domain::route_method(&ctx.config, method.as_str());
+        if domain::is_batch(&ctx.config, &route, method.as_str()) {
+            return Flow::Respond(match ctx.request.nginx() {
+                Some(request) => read_request_body(request, crate::batch_body_handler),
+                None => needs_nginx(ctx.request, self.name()),
+            });
+        }
+        Flow::Continue
+    }
+}
+
+/// With sqlite_trace, the operations below are logged once they are done
+struct StartTrace;
+
+impl Stage<RequestContext<'_, '_>, Status> for StartTrace {
+    fn name(&self) -> &'static str {
+        "trace"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        ctx.trace = Some(Arc::new(Trace::new()));
+        Flow::Continue
+    }
+}
+
+/// Methods with a sqlite_write_query write instead of reading
+struct Write;
+
+impl Stage<RequestContext<'_, '_>, Status> for Write {
+    fn name(&self) -> &'static str {
+        "write"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let method = ctx.request.method();
+        match domain::route_method(&ctx.config, method.as_str()) {
+            MethodRoute::Read => Flow::Continue,
+            MethodRoute::Write(write_method, write_queries) => Flow::Respond(execute_write(
+                &ctx.config,
+                ctx.paths,
+                write_method,
+                &write_queries,
+                &ctx.params,
+                ctx.trace.as_deref(),
+                ctx.request,
+            )),
+            MethodRoute::NotAllowed => {
+                ctx.request.logger().warn(
+                    "write",
+                    &format!("Method {} is not allowed here", method.as_str()),
+                );
+                Flow::Respond(ngx::http::HTTPStatus::NOT_ALLOWED.into())
+            }
+        }
+    }
+}
+
+/// Count the read with sqlite_hit_counter; the page's total is bound as
+/// :hits and given to templates as hits
+struct CountHit;
+
+impl Stage<RequestContext<'_, '_>, Status> for CountHit {
+    fn name(&self) -> &'static str {
+        "hits"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        ctx.hits = ctx
+            .config
+            .hit_counter
+            .as_ref()
+            .and_then(|counter| count_hit(ctx.request, &ctx.config, counter));
+        if let Some(hits) = ctx.hits {
+            domain::bind_hits(&mut ctx.params, hits);
+        }
+        Flow::Continue
+    }
+}
+
+/// Pick the query from sqlite_query_if rules, if any match this request
+struct SelectQuery;
+
+impl Stage<RequestContext<'_, '_>, Status> for SelectQuery {
+    fn name(&self) -> &'static str {
+        "query_if"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let selected = domain::select_query(&ctx.config, &mut *ctx.request.variables());
+        if std::ptr::eq(selected, &ctx.config.query) {
+            return Flow::Continue;
+        }
+        let selected = selected.clone();
+        ctx.request.logger().debug(
+            "query",
+            &format!("sqlite_query_if selected: {}", selected.as_str()),
+        );
+        ctx.config.to_mut().query = selected;
+        Flow::Continue
+    }
+}
+
+/// Narrow the rows with each sqlite_filter whose variable is set; pages are
+/// then counted from the filtered query, as sqlite_count_query cannot know
+/// which conditions apply
+struct Filter;
+
+impl Stage<RequestContext<'_, '_>, Status> for Filter {
+    fn name(&self) -> &'static str {
+        "filter"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let filters = &ctx.config.filters;
+        let filtered =
+            domain::filtered_query(&ctx.config.query, filters, &mut *ctx.request.variables());
+        match filtered {
+            Ok(Some((query, values))) => {
+                ctx.params.extend(values);
+                let exact = ctx
+                    .config
+                    .pagination
+                    .as_ref()
+                    .is_some_and(|p| p.count() == &CountStrategy::Exact);
+                let config = ctx.config.to_mut();
+                config.query = query;
+                if exact {
+                    config.count_query = None;
+                }
+                Flow::Continue
+            }
+            Ok(None) => Flow::Continue,
+            Err(e) => Flow::Respond(send_param_error(ctx.request, &e)),
+        }
+    }
+}
+
+/// Sort as the sqlite_sort_param variable asks, if it does
+struct Sort;
+
+impl Stage<RequestContext<'_, '_>, Status> for Sort {
+    fn name(&self) -> &'static str {
+        "sort"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let Some(sort) = &ctx.config.sort else {
+            return Flow::Continue;
+        };
+        let sorted = domain::sorted_query(&ctx.config.query, sort, &mut *ctx.request.variables());
+        match sorted {
+            Ok(Some(query)) => {
+                ctx.config.to_mut().query = query;
+                Flow::Continue
+            }
+            Ok(None) => Flow::Continue,
+            Err(e) => Flow::Respond(send_param_error(ctx.request, &e)),
+        }
+    }
+}
+
+/// Negotiate the response's format; those made from a page need the template
+struct Negotiate;
+
+impl Stage<RequestContext<'_, '_>, Status> for Negotiate {
+    fn name(&self) -> &'static str {
+        "format"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        // A feed location always answers with its feed
+        let formatter = match &ctx.config.feed {
+            Some(feed) => ctx
+                .formatters
+                .get(ContentType::for_feed(feed.format()))
+                .expect("the standard formatters include feeds"),
+            None => negotiate_formatter(ctx.request, &ctx.formatters, ctx.template.is_some()),
+        };
+        ctx.content_type = formatter.content_type();
+        if !formatter.needs_page() {
+            ctx.template = None;
+        }
+        Flow::Continue
+    }
+}
+
+/// With sqlite_try_static, a fresh saved page answers without touching the database
+struct StaticLookup;
+
+impl Stage<RequestContext<'_, '_>, Status> for StaticLookup {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        if ctx.template.is_none() {
+            return Flow::Continue;
+        }
+        ctx.static_page = static_page_path(&ctx.config, ctx.paths, ctx.request);
+        let (Some(path), Some(try_static)) = (&ctx.static_page, ctx.config.try_static) else {
+            return Flow::Continue;
+        };
+        let max_age = try_static.max_age().map(Duration::from_secs);
+        let databases = ctx.config.database_files();
+        match static_site::read_fresh(path, max_age, &databases, SystemTime::now()) {
+            Some(html) => {
+                ctx.request
+                    .logger()
+                    .debug("static", &format!("Serving saved page {}", path.display()));
+                Flow::Respond(send_response(ctx.request, &html))
+            }
+            None => Flow::Continue,
+        }
+    }
+}
+
+/// The page of a sqlite_paginate location; a forged cursor is refused
+struct ChoosePage;
+
+impl Stage<RequestContext<'_, '_>, Status> for ChoosePage {
+    fn name(&self) -> &'static str {
+        "page"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let Some(pagination) = &ctx.config.pagination else {
+            return Flow::Continue;
+        };
+        let position = domain::requested_position(pagination, &mut *ctx.request.variables());
+        match position {
+            Ok(page) => {
+                ctx.page = page;
+                Flow::Continue
+            }
+            Err(e) => Flow::Respond(send_param_error(ctx.request, &e)),
+        }
+    }
+}
+
+/// The relations (`?expand=`) and fields (`?fields=`) of the rows' JSON;
+/// pages pick their fields when they are rendered
+struct Shape;
+
+impl Stage<RequestContext<'_, '_>, Status> for Shape {
+    fn name(&self) -> &'static str {
+        "shape"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        if ctx.template.is_some() {
+            return Flow::Continue;
+        }
+        let available = &ctx.location.expansions;
+        let requested = domain::requested_expansions(available, &mut *ctx.request.variables());
+        ctx.expansions = match requested {
+            Ok(expansions) => expansions,
+            Err(e) => return Flow::Respond(send_param_error(ctx.request, &e)),
+        };
+        ctx.fields = match requested_fields(ctx.request, &ctx.config, available) {
+            Ok(Some((fields, expanded))) => {
+                for expansion in expanded {
+                    if !ctx.expansions.iter().any(|e| e.name() == expansion.name()) {
+                        ctx.expansions.push(expansion);
+                    }
+                }
+                Some(fields)
+            }
+            Ok(None) => None,
+            Err(status) => return Flow::Respond(status),
+        };
+        Flow::Continue
+    }
+}
+
+/// Execute the query, rendering the page or serializing the rows
+struct RunQuery;
+
+impl Stage<RequestContext<'_, '_>, Status> for RunQuery {
+    fn name(&self) -> &'static str {
+        "query"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let output = match ctx.template {
+            Some(_) => execute_with_processor(ctx),
+            None => execute_json(
+                &ctx.config,
+                &ctx.params,
+                &ctx.expansions,
+                ctx.fields.as_ref(),
+                &ctx.page,
+                ctx.trace.as_deref(),
+                ctx.request,
+            )
+            .map_err(|e| send_db_error(ctx.request, "Query execution failed", &e, false)),
+        };
+        match output {
+            Ok(output) => {
+                ctx.output = Some(output);
+                Flow::Continue
+            }
+            Err(status) => Flow::Respond(status),
+        }
+    }
+}
+
+/// Save the rendered page for sqlite_try_static's later requests
+///
+/// Only a page that rendered rows the query found is saved, so error pages
+/// and "not found" pages (no rows, or a fallback query's) are rendered
+/// again each time rather than answering as saved files, and requests for
+/// URIs that match nothing cannot fill the document root.
+struct StaticSave;
+
+impl Stage<RequestContext<'_, '_>, Status> for StaticSave {
+    fn name(&self) -> &'static str {
+        "static_save"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        if ctx.content_type != ContentType::Html || !ctx.found {
+            return Flow::Continue;
+        }
+        if let (Some(path), Some(html)) = (&ctx.static_page, &ctx.output)
+            && let Err(e) = static_site::save(path, html)
+        {
+            ctx.request.logger().warn(
+                "static",
+                &format!("Could not save page {}: {}", path.display(), e),
+            );
+        }
+        Flow::Continue
+    }
+}
+
+/// Put the output in the negotiated format and send it
+struct Respond;
+
+impl Stage<RequestContext<'_, '_>, Status> for Respond {
+    fn name(&self) -> &'static str {
+        "respond"
+    }
+
+    fn run(&self, ctx: &mut RequestContext) -> Flow<Status> {
+        let Some(body) = ctx.output.as_deref() else {
+            return Flow::Continue;
+        };
+        let output = match &ctx.template {
+            Some(_) => Output::Page(body),
+            None => Output::Rows(body),
+        };
+        let formatter = ctx
+            .formatters
+            .get(ctx.content_type)
+            .expect("the negotiated format has a formatter");
+        Flow::Respond(send_formatted(
+            ctx.request,
+            formatter,
+            &output,
+            &ctx.config,
+            ctx.paths,
+        ))
+    }
+}
+
+/// Answer 500 for a stage only nginx can run, asked of a request without it
+fn needs_nginx(request: &mut dyn StageRequest, stage: &str) -> Status {
+    let message = format!("the {} stage needs nginx", stage);
+    request.logger().error("handler", &message);
+    request.record_error("internal", &message);
+    ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+}
+
+/// Log a `sqlite_trace` request's operations as one line of JSON, with the
+/// request's `traceparent` header (if well formed) so they can be filed as
+/// spans under the caller's trace
+fn log_trace(request: &mut dyn StageRequest, trace: Option<&Trace>) {
+    let Some(trace) = trace else {
+        return;
+    };
+    let header = request
+        .variables()
+        .resolve("$http_traceparent")
+        .unwrap_or_default();
+    let line = serde_json::json!({
+        "traceparent": domain::trace_parent(&header),
+        "operations": trace.to_json(),
+    });
+    request.logger().info("trace", &line.to_string());
+}
+
+/// Count a hit on this request's page, returning the page's total: the hits
+/// already in its table plus those this worker has yet to write
+///
+/// Other workers' pending hits show up once they are written, so the total
+/// can lag by a few seconds of traffic. A request whose key variable cannot
+/// be resolved is not counted.
+fn count_hit(
+    request: &mut dyn StageRequest,
+    config: &ValidatedConfig,
+    counter: &HitCounter,
+) -> Option<u64> {
+    let key = request.variables().resolve(counter.key().as_str()).ok()?;
+    let db = config.db_path.as_str();
+    let pending = match worker::with_state(|state| {
+        state
+            .hit_counters()
+            .record(db, counter.table(), counter.daily(), &key)
+    })? {
+        Ok(pending) => pending,
+        Err(e) => {
+            request
+                .logger()
+                .warn("hits", &format!("Hit not counted: {}", e));
+            return None;
+        }
+    };
+    match query::stored_hits(db, counter.table(), &key) {
+        Ok(stored) => Some(stored + pending),
+        Err(e) => {
+            request
+                .logger()
+                .warn("hits", &format!("Could not read hits for {}: {}", key, e));
+            Some(pending)
+        }
+    }
+}
+
+/// The formatter for this request's response
+///
+/// A `?format=` this build has a formatter for wins, then the Accept header;
+/// without a template the location only speaks formats made from rows, JSON
+/// unless NDJSON is asked for.
+fn negotiate_formatter<'a>(
+    request: &mut dyn StageRequest,
+    formatters: &'a FormatterRegistry,
+    has_template: bool,
+) -> &'a dyn ResponseFormatter {
+    let json = || {
+        formatters
+            .get(ContentType::Json)
+            .expect("the standard formatters include JSON")
+    };
+    let requested = request
+        .variables()
+        .resolve("$arg_format")
+        .ok()
+        .and_then(|format| ContentType::from_format(&format))
+        .and_then(|content_type| formatters.get(content_type));
+    requested
+        .or_else(|| formatters.get(request.accepted_content_type()))
+        .filter(|formatter| has_template || !formatter.needs_page())
+        .unwrap_or_else(json)
+}
 
-        // Delegate to from_config for actual validation
-        Self::from_config(config, doc_root, uri)
+/// Put the location's output in the negotiated format and send it
+fn send_formatted(
+    request: &mut dyn StageRequest,
+    formatter: &dyn ResponseFormatter,
+    output: &Output,
+    config: &ValidatedConfig,
+    paths: &RequestPaths,
+) -> Status {
+    let mut resolver = request.variables();
+    let base_url = match (resolver.resolve("$scheme"), resolver.resolve("$host")) {
+        (Ok(scheme), Ok(host)) => format!("{}://{}", scheme, host),
+        _ => String::new(),
+    };
+    drop(resolver);
+    let meta = ResponseMeta {
+        uri: &paths.uri,
+        base_url: &base_url,
+        feed: config.feed.as_ref(),
+    };
+    let sent = match config.stream {
+        Some(buffer) if formatter.streams() => formatter
+            .render_lines(output, &meta)
+            .map(|response| request.send_streamed(response, buffer)),
+        _ => formatter
+            .render(output, &meta)
+            .map(|response| send_formatted_response(request, &response)),
+    };
+    match sent {
+        Ok(status) => status,
+        Err(e) => {
+            let message = format!("{:?} formatting failed: {}", formatter.content_type(), e);
+            request.logger().error("format", &message);
+            request.record_error("internal", &message);
+            ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+        }
     }
+}
 
-    /// Create a token from config and context (testable)
-    /// This is the core validation logic, separated for testing
-    fn from_config(config: &ModuleConfig, doc_root: String, uri: String) -> Option<Self> {
-        // Validate basic config fields
-        if config.db_path.is_empty() || config.query.is_empty() || config.template_path.is_empty() {
-            return None;
+/// Where `sqlite_try_static` keeps this request's page, if it applies
+///
+/// Requests with a query string are always rendered, since their arguments
+/// may change the page.
+fn static_page_path(
+    config: &ValidatedConfig,
+    paths: &RequestPaths,
+    request: &mut dyn StageRequest,
+) -> Option<PathBuf> {
+    config.try_static?;
+    let args = request.variables().resolve("$args");
+    if !args.is_ok_and(|args| args.is_empty()) {
+        return None;
+    }
+    static_site::page_path(&paths.doc_root, &paths.uri)
+}
+
+/// Progress of a request's `sqlite_captcha` check, kept in the module's
+/// request context while the verification subrequest runs
+pub enum CaptchaCheck {
+    Pending,
+    Verified(bool),
+}
+
+/// The captcha verdict for this request, or `None` before it has been asked for
+///
+/// A request without a token fails at once, as does one resumed before its
+/// subrequest recorded a verdict.
+fn captcha_verdict(request: &mut ngx::http::Request, captcha: &Captcha) -> Option<bool> {
+    let check = request
+        .get_module_ctx::<CaptchaCheck>(Module::module())
+        .map(|check| match check {
+            CaptchaCheck::Pending => None,
+            CaptchaCheck::Verified(passed) => Some(*passed),
+        });
+    match check {
+        Some(Some(passed)) => {
+            if !passed {
+                NginxLogger::new(request).warn("captcha", "Rejected write: captcha not accepted");
+            }
+            Some(passed)
+        }
+        Some(None) => {
+            NginxLogger::new(request).error("captcha", "Resumed without a captcha verdict");
+            Some(false)
+        }
+        None => {
+            let token = NginxVariableResolver::new(request)
+                .resolve(&format!("$arg_{}", captcha.field()))
+                .unwrap_or_default();
+            if token.is_empty() {
+                NginxLogger::new(request).warn(
+                    "captcha",
+                    &format!("Rejected write: no captcha token in '{}'", captcha.field()),
+                );
+                return Some(false);
+            }
+            None
         }
+    }
+}
 
-        // Parse and validate the configuration
-        parsing::parse_config(config, doc_root, uri)
-            .map(|c| ValidConfigToken { config: c })
-            .ok()
+/// Ask the provider, through the `verify` location, whether the request's
+/// captcha token is valid; `crate::captcha_verified` records the answer
+fn verify_captcha(request: &mut ngx::http::Request, captcha: &Captcha) -> Status {
+    let mut var_resolver = NginxVariableResolver::new(request);
+    let token = var_resolver
+        .resolve(&format!("$arg_{}", captcha.field()))
+        .unwrap_or_default();
+    let remote_ip = var_resolver.resolve("$remote_addr").ok();
+    let args = domain::captcha_verify_args(captcha, &token, remote_ip.as_deref());
+
+    let check = request.pool().allocate(CaptchaCheck::Pending);
+    if check.is_null() {
+        return internal_error(request, ResponseError::BufferAllocation);
     }
+    request.set_module_ctx(check.cast(), Module::module());
 
-    pub fn get(&self) -> &ValidatedConfig {
-        &self.config
+    NginxLogger::new(request).debug(
+        "captcha",
+        &format!(
+            "Verifying {} token via {}",
+            captcha.provider().as_str(),
+            captcha.verify_uri()
+        ),
+    );
+    start_subrequest(
+        request,
+        captcha.verify_uri(),
+        &args,
+        crate::captcha_verified,
+        check.cast(),
+    )
+}
+
+/// Progress of a `sqlite_export` run, kept in the module's request context
+/// while each page's subrequest runs (export locations have no writes, so this
+/// never meets a [`CaptchaCheck`])
+pub struct ExportRun {
+    directory: String,
+    uris: Vec<String>,
+    next: usize,
+    written: usize,
+    failures: Vec<(String, String)>,
+}
+
+impl ExportRun {
+    /// Record the finished subrequest for the current page and move on
+    pub fn page_done(&mut self, status: usize, body: &[u8]) {
+        let Some(uri) = self.uris.get(self.next) else {
+            return;
+        };
+        let saved = match static_site::page_path(&self.directory, uri) {
+            _ if status != 200 => Err(format!("status {}", status)),
+            Some(path) => {
+                static_site::save(&path, body).map_err(|e| format!("{}: {}", path.display(), e))
+            }
+            None => Err("not a path under the export directory".to_string()),
+        };
+        match saved {
+            Ok(()) => self.written += 1,
+            Err(e) => self.failures.push((uri.clone(), e)),
+        }
+        self.next += 1;
+    }
+
+    /// The answer to the export request
+    fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "directory": self.directory,
+            "exported": self.written,
+            "failed": self
+                .failures
+                .iter()
+                .map(|(uri, error)| serde_json::json!({ "uri": uri, "error": error }))
+                .collect::<Vec<_>>(),
+        })
     }
 }
 
-/// Process a request with guaranteed valid configuration
-/// Returns Status directly - no Result needed, types prove correctness
-pub fn process_request(
+/// Run the location's query for the URIs to export, then render them one
+/// subrequest at a time; `crate::export_page_done` saves each page
+fn start_export(
     request: &mut ngx::http::Request,
-    validated_config: &ValidatedConfig,
+    config: &ValidatedConfig,
+    directory: &str,
 ) -> Status {
-    // Log initial processing
-    NginxLogger::new(request).debug(
-        "handler",
-        &format!("Processing request for {}", validated_config.uri),
-    );
+    use crate::domain::QueryExecutor;
 
-    // Resolve template path (pure function - cannot fail)
-    let resolved_template = domain::resolve_template_path(validated_config);
+    let uris = SqliteQueryExecutor::for_location(config)
+        .execute(&config.db_path, &config.query, &[])
+        .map_err(|e| e.to_string())
+        .and_then(|rows| domain::export_uris(&rows));
+    let uris = match uris {
+        Ok(uris) => uris,
+        Err(e) => {
+            NginxLogger::new(request).error("export", &format!("Export query failed: {}", e));
+            let body = serde_json::json!({ "error": "Export query failed", "details": e });
+            return send_json_error(
+                request,
+                &body.to_string(),
+                ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR,
+            );
+        }
+    };
 
-    NginxLogger::new(request).debug(
-        "template",
-        &format!("Resolved template: {}", resolved_template.full_path()),
+    NginxLogger::new(request).info(
+        "export",
+        &format!("Exporting {} page(s) to {}", uris.len(), directory),
     );
+    let run = request.pool().allocate(ExportRun {
+        directory: directory.to_string(),
+        uris,
+        next: 0,
+        written: 0,
+        failures: Vec::new(),
+    });
+    if run.is_null() {
+        return internal_error(request, ResponseError::BufferAllocation);
+    }
+    request.set_module_ctx(run.cast(), Module::module());
+    continue_export(request)
+}
 
-    // Resolve parameters
-    let mut var_resolver = NginxVariableResolver::new(request);
-    let resolved_params =
-        match domain::resolve_parameters(&validated_config.parameters, &mut var_resolver) {
-            Ok(params) => {
-                if !params.is_empty() {
-                    NginxLogger::new(request)
-                        .debug("params", &format!("Resolved {} parameters", params.len()));
-                }
-                params
+/// Render the next page of the request's export, or answer with a summary
+/// once every page has been tried
+pub fn continue_export(request: &mut ngx::http::Request) -> Status {
+    let progress = request
+        .get_module_ctx::<ExportRun>(Module::module())
+        .map(|run| match run.uris.get(run.next) {
+            // The run lives in the request pool; the subrequest's handler
+            // updates it while this request waits
+            Some(uri) => Ok((uri.clone(), run as *const ExportRun as *mut ExportRun)),
+            None => Err((run.summary(), run.failures.len())),
+        });
+
+    match progress {
+        Some(Ok((uri, run))) => {
+            NginxLogger::new(request).debug("export", &format!("Rendering {}", uri));
+            start_subrequest(request, &uri, "", crate::export_page_done, run.cast())
+        }
+        Some(Err((summary, failed))) => {
+            if failed > 0 {
+                NginxLogger::new(request)
+                    .warn("export", &format!("{} page(s) failed to export", failed));
             }
+            send_json_response(request, &summary.to_string())
+        }
+        None => {
+            NginxLogger::new(request).error("export", "Resumed without an export in progress");
+            ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+        }
+    }
+}
+
+/// Resolve the location's parameters; a failure is logged and answered with
+/// a 400 naming the parameters at fault
+fn resolve_request_parameters(
+    request: &mut dyn StageRequest,
+    config: &ValidatedConfig,
+) -> Result<domain::ParameterSet, Status> {
+    // $json_body. and $post_ fields come from the body process_request had
+    // nginx read
+    let body = if config.reads_body() {
+        match request.body() {
+            Ok(body) => Some(body),
             Err(e) => {
-                NginxLogger::new(request)
-                    .error("params", &format!("Parameter resolution failed: {}", e));
-                return ngx::http::HTTPStatus::BAD_REQUEST.into();
+                request
+                    .logger()
+                    .warn("params", &format!("Unreadable body: {}", e));
+                request.record_error("invalid_body", &e);
+                let error_obj =
+                    serde_json::json!({ "error": "Invalid request body", "details": e });
+                return Err(send_json_error(
+                    request,
+                    &error_obj.to_string(),
+                    ngx::http::HTTPStatus::BAD_REQUEST,
+                ));
             }
-        };
+        }
+    } else {
+        None
+    };
+    let mut var_resolver = request.variables();
+    let resolved = match &body {
+        Some(body) => domain::resolve_parameters(
+            &config.parameters,
+            &mut domain::BodyResolver::new(&mut var_resolver, body),
+        ),
+        None => domain::resolve_parameters(&config.parameters, &mut var_resolver),
+    };
+    drop(var_resolver);
+    match resolved {
+        Ok(params) => {
+            if !params.is_empty() {
+                request
+                    .logger()
+                    .debug("params", &format!("Resolved {} parameters", params.len()));
+            }
+            Ok(params)
+        }
+        Err(e) => Err(send_param_error(request, &e)),
+    }
+}
 
-    // Negotiate content type based on Accept header
-    let content_type = negotiate_content_type(request);
+/// The `?fields=` selection of a request, with the expansions it needs, or
+/// `None` when it does not ask for one
+///
+/// The query and each of `expansions` (none for pages, whose templates are
+/// not given relations) are prepared to learn the columns they return, so
+/// an unknown field is refused before anything runs.
+fn requested_fields<'a>(
+    request: &mut dyn StageRequest,
+    config: &ValidatedConfig,
+    expansions: &'a [Expansion],
+) -> Result<Option<(domain::FieldSelection, Vec<&'a Expansion>)>, Status> {
+    let requested = request
+        .variables()
+        .resolve("$arg_fields")
+        .unwrap_or_default();
+    if requested.trim().is_empty() {
+        return Ok(None);
+    }
 
-    // Execute query and format response
-    match content_type {
-        ContentType::Json => {
-            let json = execute_json(validated_config, &resolved_params, request);
-            send_json_response(request, &json)
+    let queries: Vec<&str> = std::iter::once(config.query.as_str())
+        .chain(expansions.iter().map(|e| e.batched().as_str()))
+        .collect();
+    let mut columns = match query::result_columns(&config.db_path, &queries) {
+        Ok(columns) => columns,
+        Err(e) => {
+            let failure = DbError::new(query::error_code(&e), e.to_string());
+            return Err(send_db_error(
+                request,
+                "Query preparation failed",
+                &failure,
+                false,
+            ));
         }
-        ContentType::Html => {
-            let html = execute_with_processor(
-                validated_config,
-                &resolved_template,
-                &resolved_params,
+    };
+    let related: Vec<(&Expansion, Vec<String>)> =
+        expansions.iter().zip(columns.split_off(1)).collect();
+    let columns = columns.swap_remove(0);
+    let (columns, related) = domain::allowed_fields(&config.allowed_fields, columns, related);
+    match domain::select_fields(&requested, &columns, &related) {
+        Ok(selection) => Ok(Some(selection)),
+        Err(e) => Err(send_param_error(request, &e)),
+    }
+}
+
+/// Log a parameter error and answer it with a 400 naming the parameters at fault
+fn send_param_error(request: &mut dyn StageRequest, error: &domain::ParamError) -> Status {
+    request
+        .logger()
+        .warn("params", &format!("Parameter resolution failed: {}", error));
+    request.record_error(error.code(), &error.to_string());
+    send_json_error(
+        request,
+        &domain::param_error_body(error).to_string(),
+        ngx::http::HTTPStatus::BAD_REQUEST,
+    )
+}
+
+/// Answer a batch once its body has been read: run the method's write
+/// statements, or the read query, once per parameter set in one transaction
+pub fn process_batch(request: &mut ngx::http::Request, config: &ValidatedConfig) -> Status {
+    let resolved_params = match resolve_request_parameters(request, config) {
+        Ok(params) => params,
+        Err(status) => return status,
+    };
+
+    let sets = match request_body(request)
+        .and_then(|body| domain::parse_parameter_sets(&body, &resolved_params))
+    {
+        Ok(sets) => sets,
+        Err(e) => {
+            NginxLogger::new(request).warn("batch", &format!("Invalid batch: {}", e));
+            record_error(request, "invalid_body", &e);
+            let error_obj = serde_json::json!({ "error": "Invalid batch", "details": e });
+            return send_json_error(
                 request,
+                &error_obj.to_string(),
+                ngx::http::HTTPStatus::BAD_REQUEST,
+            );
+        }
+    };
+
+    let executor = SqliteQueryExecutor::for_location(config);
+    let method = request.method();
+    let (result, status) = match domain::route_method(config, method.as_str()) {
+        MethodRoute::Write(write_method, queries) => {
+            let result = executor
+                .execute_batch_write(&config.db_path, &queries, &sets)
+                .map(|outcomes| {
+                    outcomes
+                        .iter()
+                        .map(|outcome| {
+                            serde_json::json!({
+                                "changes": outcome.changes,
+                                "last_insert_rowid": outcome.last_insert_rowid,
+                            })
+                        })
+                        .collect::<serde_json::Value>()
+                });
+            let status = match domain::write_response(write_method) {
+                WriteResponse::Created => ngx::http::HTTPStatus::CREATED,
+                // A batch always reports each set's outcome
+                WriteResponse::NoContent => ngx::http::HTTPStatus::OK,
+            };
+            (result, status)
+        }
+        MethodRoute::Read => {
+            let query = domain::select_query(config, &mut NginxVariableResolver::new(request));
+            let result = executor
+                .execute_batch_query(&config.db_path, query, &sets)
+                .map(|mut results| {
+                    for rows in &mut results {
+                        domain::expand_json(rows, &config.expand_json);
+                        if config.json_nulls == JsonNulls::Omit {
+                            domain::omit_nulls(rows);
+                        }
+                    }
+                    serde_json::json!(results)
+                });
+            (result, ngx::http::HTTPStatus::OK)
+        }
+        MethodRoute::NotAllowed => return ngx::http::HTTPStatus::NOT_ALLOWED.into(),
+    };
+
+    match result {
+        Ok(body) => {
+            NginxLogger::new(request).info(
+                "success",
+                &format!(
+                    "{} batch ran {} parameter set(s)",
+                    method.as_str(),
+                    sets.len()
+                ),
+            );
+            send_json_response_with_status(request, &body.to_string(), status)
+        }
+        Err(e) => {
+            NginxLogger::new(request).error(
+                "batch",
+                &format!("Batch failed, transaction rolled back - Error: {}", e),
             );
-            send_response(request, &html)
+            send_db_error(request, "Batch execution failed", &e, true)
         }
     }
 }
 
-/// Execute query and render with proper dependency injection
-fn execute_with_processor(
-    config: &ValidatedConfig,
-    resolved_template: &domain::ResolvedTemplate,
-    resolved_params: &[(String, String)],
-    request: &mut ngx::http::Request,
-) -> String {
-    let reg = HandlebarsAdapter::new();
+/// A template registry from this worker's pool, with the templates earlier
+/// requests loaded already compiled (a fresh one outside a worker)
+fn pooled_templates() -> HandlebarsAdapter {
+    worker::with_state(|state| state.template_pool())
+        .map(HandlebarsAdapter::pooled)
+        .unwrap_or_else(HandlebarsAdapter::new)
+}
+
+/// Execute query and render the request's template with proper dependency
+/// injection
+///
+/// A failure is rendered as an error page, unless `sqlite_intercept_errors`
+/// is on: then it is answered with 500 for nginx's `error_page` to present.
+fn execute_with_processor(ctx: &mut RequestContext) -> Result<String, Status> {
+    let RequestContext {
+        request,
+        config,
+        paths,
+        template,
+        params: resolved_params,
+        hits,
+        page,
+        trace,
+        found,
+        ..
+    } = ctx;
+    let resolved_template = template
+        .as_ref()
+        .expect("the query stage renders with a template only when there is one");
+    let request = &mut **request;
+    let reg = pooled_templates();
 
     // Get global template directory first (before creating logger)
-    let main_conf = Module::main_conf(request).expect("main config is none");
-    let global_dir = if !main_conf.global_templates_dir.is_empty() {
-        Some(main_conf.global_templates_dir.as_str())
-    } else {
-        None
+    let Some(global_dir) = request
+        .main_conf()
+        .map(|main_conf| main_conf.global_templates_dir.clone())
+    else {
+        return Err(internal_error(request, ResponseError::MissingMainConfig));
     };
 
+    let fields = requested_fields(request, config, &[])?.map(|(fields, _)| fields);
+
     // Now create logger and processor
-    let logger = NginxLogger::new(request);
-    let mut processor = RequestProcessor::new(SqliteQueryExecutor, reg, logger);
+    let logger = request.logger();
+    let executor = SqliteQueryExecutor::for_location(config);
+    let mut processor = RequestProcessor::new(executor, reg, logger)
+        .with_nav_cache(worker::with_state(|state| {
+            state.nav_cache() as Arc<dyn MenuCache>
+        }))
+        .with_metrics(worker::with_state(|state| {
+            state.metrics() as Arc<dyn Metrics>
+        }))
+        .with_render_sizes(worker::with_state(|state| {
+            state.render_sizes() as Arc<dyn PageSizes>
+        }))
+        .with_page(page.clone())
+        .with_hits(*hits)
+        .with_fields(fields)
+        .with_trace(trace.clone().map(|trace| trace as Arc<dyn Tracer>))
+        .with_clock(Arc::new(Stopwatch::start()));
 
     // Process through functional core
-    match processor.process(config, resolved_template, resolved_params, global_dir) {
+    let processed = processor.process(
+        config,
+        paths,
+        resolved_template,
+        resolved_params,
+        global_dir.as_deref(),
+    );
+    let timings = processor.timings();
+    let page = match processed {
         Ok(html) => {
             // Success is already logged in the processor
+            *found = processor.found_rows() > 0;
             html
         }
         Err(e) => {
-            // Errors are already logged in the processor
+            // Errors are already logged in the processor; the variables get
+            // the summary, not the template source after it
+            request.record_error("internal", e.lines().next().unwrap_or_default());
+            if errors_intercepted(request) {
+                return Err(ngx::http::HTTPStatus::INTERNAL_SERVER_ERROR.into());
+            }
             // Return user-friendly error page
-            format!(
-                r#"<!DOCTYPE html>
-<html>
-<head><title>Error - sqlite-serve</title></head>
-<body style="font-family: monospace; max-width: 800px; margin: 2rem auto; padding: 0 1rem;">
-    <h1 style="color: #CC9393;">Request Processing Error</h1>
-    <p style="color: #A6A689;">An error occurred while processing your request.</p>
-    <details style="margin-top: 1rem; background: #1111; padding: 1rem; border-left: 3px solid #CC9393;">
-        <summary style="cursor: pointer; color: #DFAF8F; font-weight: bold;">Error Details</summary>
-        <pre style="margin-top: 1rem; color: #DCDCCC; overflow-x: auto;">{}</pre>
-    </details>
-    <p style="margin-top: 2rem;"><a href="/" style="color: #7CB8BB;">← Back to Home</a></p>
-</body>
-</html>"#,
-                e
-            )
+            template::render_error_page(&e, dev_mode(request))
         }
+    };
+    if let Some(timings) = timings.filter(|_| config.timing) {
+        add_server_timing(request, &timings);
+    }
+    Ok(page)
+}
+
+/// Report `sqlite_timing` durations to the client in a `Server-Timing` header
+fn add_server_timing(request: &mut dyn StageRequest, timings: &Timings) {
+    if !request.add_header("Server-Timing", &timings.server_timing()) {
+        request
+            .logger()
+            .warn("timing", "Could not add the Server-Timing header");
+    }
+}
+
+/// Add the `Link` header pointing a paginated JSON response at its
+/// neighbouring pages
+fn add_link_header(
+    request: &mut dyn StageRequest,
+    pagination: &Pagination,
+    context: &serde_json::Value,
+) {
+    let Ok(request_uri) = request.variables().resolve("$request_uri") else {
+        return;
+    };
+    if let Some(links) = domain::pagination_links(&request_uri, pagination, context)
+        && !request.add_header("Link", &links)
+    {
+        request
+            .logger()
+            .warn("pagination", "Could not add the Link header");
+    }
+}
+
+/// Re-render the `sqlite_form_template` with the submitted values and errors,
+/// answering with the failure's status (e.g. 422)
+///
+/// Only rejected input is answered this way, and only to clients that accept
+/// HTML; `None` leaves the answer to [`send_db_error`].
+fn render_form(
+    config: &ValidatedConfig,
+    paths: &RequestPaths,
+    resolved_params: &[(String, serde_json::Value)],
+    failure: &DbError,
+    request: &mut dyn StageRequest,
+) -> Option<Status> {
+    let form_template = match active_theme(config, request) {
+        Some(active) => config
+            .resolve_themed_form_template_path(paths, &active, |path| Path::new(path).is_file()),
+        None => config.resolve_form_template_path(paths),
+    }?;
+    let status = failure.code.http_status();
+    if status >= 500 || !matches!(request.accepted_content_type(), ContentType::Html) {
+        return None;
+    }
+
+    let global_dir = request
+        .main_conf()
+        .and_then(|main_conf| main_conf.global_templates_dir.clone());
+    let data = domain::build_form_context(resolved_params, failure);
+    let logger = request.logger();
+    let mut processor = RequestProcessor::new(
+        SqliteQueryExecutor::for_location(config),
+        pooled_templates(),
+        logger,
+    );
+    // A form that cannot be rendered falls back to the JSON answer
+    let html = processor
+        .render_form(&form_template, &data, global_dir.as_deref())
+        .ok()?;
+    Some(send_response_with_status(
+        request,
+        &html,
+        ngx::http::HTTPStatus(status.into()),
+    ))
+}
+
+/// Queue the `sqlite_notify_email` message for a successful write
+///
+/// The write has already been committed, so a message that cannot be rendered
+/// or queued is logged and the response is unaffected.
+fn notify_email(
+    config: &ValidatedConfig,
+    paths: &RequestPaths,
+    method: WriteMethod,
+    resolved_params: &[(String, serde_json::Value)],
+    outcome: &WriteOutcome,
+    request: &mut dyn StageRequest,
+) {
+    let Some(notification) = config
+        .notify_email
+        .as_ref()
+        .filter(|n| n.applies_to(method))
+    else {
+        return;
+    };
+    let Some(email_template) = config.resolve_email_template_path(paths) else {
+        return;
+    };
+
+    let global_dir = request
+        .main_conf()
+        .and_then(|main_conf| main_conf.global_templates_dir.clone());
+    let data = domain::build_email_context(method, resolved_params, outcome);
+    let logger = request.logger();
+    let mut processor = RequestProcessor::new(
+        SqliteQueryExecutor::for_location(config),
+        HandlebarsAdapter::plain_text(),
+        logger,
+    );
+    let Ok(body) = processor.render_email(&email_template, &data, global_dir.as_deref()) else {
+        return;
+    };
+
+    let message = domain::compose_email(notification, resolved_params, &body);
+    match worker::with_state(|state| state.mailer().send(notification.sendmail(), message)) {
+        Some(Ok(())) => request.logger().debug(
+            "email",
+            &format!("Queued email to {}", notification.to().join(", ")),
+        ),
+        Some(Err(e)) => request
+            .logger()
+            .error("email", &format!("Email not sent: {}", e)),
+        None => request
+            .logger()
+            .error("email", "Email not sent: no worker state"),
+    }
+}
+
+/// Answer a database failure with the status for its code and a JSON body
+/// naming the code
+fn send_db_error(
+    request: &mut dyn StageRequest,
+    error: &str,
+    failure: &DbError,
+    rolled_back: bool,
+) -> Status {
+    let body = domain::error_body(error, failure, rolled_back);
+    let status = ngx::http::HTTPStatus(failure.code.http_status().into());
+    request.record_error(failure.code.as_str(), &failure.message);
+    send_json_error(request, &body.to_string(), status)
+}
+
+/// Answer a failure with its JSON body, or with `sqlite_intercept_errors on`
+/// with the bare status, which nginx's `error_page` then presents
+fn send_json_error(
+    request: &mut dyn StageRequest,
+    body: &str,
+    status: ngx::http::HTTPStatus,
+) -> Status {
+    if errors_intercepted(request) {
+        return status.into();
     }
+    send_json_response_with_status(request, body, status)
+}
+
+/// Whether the location has `sqlite_intercept_errors on`
+fn errors_intercepted(request: &mut dyn StageRequest) -> bool {
+    request
+        .location_conf()
+        .and_then(|config| config.intercept_errors)
+        .unwrap_or(false)
+}
+
+/// Whether `sqlite_dev_mode` is on, so error pages show the template source
+/// around a syntax error
+fn dev_mode(request: &mut dyn StageRequest) -> bool {
+    request
+        .location_conf()
+        .and_then(|config| config.dev_mode)
+        .unwrap_or(false)
+}
+
+/// The location's configuration with its main template replaced by the
+/// `sqlite_template_variant` this request selects, if it selects one
+fn variant_config(
+    config: &ValidatedConfig,
+    request: &mut dyn StageRequest,
+) -> Option<ValidatedConfig> {
+    let variants = config.template_variants.as_ref()?;
+    let template = domain::template_variant(variants, &mut *request.variables())?;
+    Some(ValidatedConfig {
+        template_path: Some(template.clone()),
+        ..config.clone()
+    })
+}
+
+/// The `sqlite_theme` this request selects, if the location is themed
+fn active_theme(config: &ValidatedConfig, request: &mut dyn StageRequest) -> Option<String> {
+    config
+        .theme
+        .as_ref()
+        .map(|theme| domain::active_theme(theme, &mut *request.variables()))
 }
 
 /// Execute query and return JSON (no template rendering)
+///
+/// With `sqlite_paginate` the rows come wrapped as
+/// `{"results": [...], "pagination": {...}}`; otherwise they are the whole body.
+/// Each `sqlite_expand` relation in `expansions` is nested in every row, and
+/// `fields` drops what `?fields=` did not ask for. With
+/// `sqlite_memo_table`, the body is kept in the database and served from
+/// there until its ttl runs out.
 fn execute_json(
     config: &ValidatedConfig,
-    resolved_params: &[(String, String)],
-    request: &mut ngx::http::Request,
-) -> String {
-    use crate::domain::QueryExecutor;
-
-    NginxLogger::new(request).debug(
+    resolved_params: &[(String, serde_json::Value)],
+    expansions: &[&Expansion],
+    fields: Option<&domain::FieldSelection>,
+    page: &domain::PagePosition,
+    trace: Option<&Trace>,
+    request: &mut dyn StageRequest,
+) -> Result<String, DbError> {
+    request.logger().debug(
         "query",
         &format!("Executing query for JSON: {}", config.query.as_str()),
     );
 
-    let executor = SqliteQueryExecutor;
+    // A response stored in the sqlite_memo_table within its ttl is served as
+    // is; responses read ?as_of= a past time are neither served nor stored
+    let memo = config
+        .memo_table
+        .as_ref()
+        .filter(|_| config.db_path.as_of().is_none());
+    let memo = memo.map(|memo| {
+        (
+            memo,
+            domain::memo_key(&config.query, page, resolved_params, expansions, fields),
+        )
+    });
+    if let Some((memo, key)) = &memo {
+        match query::memo_lookup(&config.db_path, memo.table(), key, memo.ttl().as_secs()) {
+            Ok(Some(payload)) => {
+                request
+                    .logger()
+                    .debug("memo", &format!("Serving stored response {}", key));
+                if let Some(pagination) = &config.pagination
+                    && let Ok(body) = serde_json::from_str::<serde_json::Value>(&payload)
+                {
+                    add_link_header(request, pagination, &body["pagination"]);
+                }
+                return Ok(payload);
+            }
+            Ok(None) => {}
+            Err(e) => request
+                .logger()
+                .warn("memo", &format!("Could not read {}: {}", memo.table(), e)),
+        }
+    }
 
-    match executor.execute(&config.db_path, &config.query, resolved_params) {
-        Ok(results) => {
-            NginxLogger::new(request).info(
+    let started = Instant::now();
+    let outcome = domain::json_rows(
+        &SqliteQueryExecutor::for_location(config),
+        config,
+        resolved_params,
+        expansions,
+        fields,
+        page,
+        trace.map(|trace| trace as &dyn Tracer),
+    );
+    match outcome {
+        Ok((results, pagination)) => {
+            worker::with_state(|state| {
+                let metrics = state.metrics();
+                metrics.time("query", started.elapsed());
+                metrics.count("rows", results.len() as u64);
+            });
+            if config.timing {
+                let timings = Timings {
+                    query: started.elapsed(),
+                    render: None,
+                };
+                add_server_timing(request, &timings);
+            }
+            if let (Some(settings), Some(context)) = (&config.pagination, &pagination) {
+                add_link_header(request, settings, context);
+            }
+            request.logger().info(
                 "success",
                 &format!(
                     "Returned {} JSON results with {} params",
@@ -188,23 +2167,113 @@ fn execute_json(
                     resolved_params.len()
                 ),
             );
-            serde_json::to_string_pretty(&results).unwrap_or_else(|e| {
-                NginxLogger::new(request)
-                    .error("json", &format!("JSON serialization failed: {}", e));
-                "[]".to_string()
-            })
+            let body = domain::rows_document(results, pagination);
+            let json = match serde_json::to_string_pretty(&body) {
+                Ok(json) => json,
+                Err(e) => {
+                    request
+                        .logger()
+                        .error("json", &format!("JSON serialization failed: {}", e));
+                    return Ok("[]".to_string());
+                }
+            };
+            // The response is still served if it cannot be stored
+            if let Some((memo, key)) = &memo
+                && let Err(e) = query::memo_store(&config.db_path, memo.table(), key, &json)
+            {
+                request.logger().warn(
+                    "memo",
+                    &format!("Could not store response in {}: {}", memo.table(), e),
+                );
+            }
+            Ok(json)
         }
         Err(e) => {
-            NginxLogger::new(request).error(
+            request.logger().error(
                 "query",
                 &format!("Query failed: {} - Error: {}", config.query.as_str(), e),
             );
-            let error_obj = serde_json::json!({
-                "error": "Query execution failed",
-                "details": e
+            Err(e)
+        }
+    }
+}
+
+/// Run a method's write statements in one transaction, answering 201 with the
+/// change count and new rowid for POST and 204 for the other methods
+///
+/// If any statement fails, none of them take effect and the answer carries
+/// the failure's status and code (e.g. 409 `constraint_unique`).
+fn execute_write(
+    config: &ValidatedConfig,
+    paths: &RequestPaths,
+    method: WriteMethod,
+    queries: &[&WriteQuery],
+    resolved_params: &[(String, serde_json::Value)],
+    trace: Option<&Trace>,
+    request: &mut dyn StageRequest,
+) -> Status {
+    for query in queries {
+        request.logger().debug(
+            "write",
+            &format!("Executing {} write: {}", method.as_str(), query.as_str()),
+        );
+    }
+
+    let span = trace.map(|trace| trace.begin(&format!("write {}", method.as_str()), None));
+    let executor = SqliteQueryExecutor::for_location(config);
+    let written = executor.execute_write(&config.db_path, queries, resolved_params);
+    if let Some((trace, span)) = trace.zip(span) {
+        match &written {
+            Ok(outcome) => trace.end(span, Some(outcome.changes)),
+            Err(_) => trace.fail(span),
+        }
+    }
+    let outcome = match written {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            request.logger().error(
+                "write",
+                &format!(
+                    "{} write failed, {} statement(s) rolled back - Error: {}",
+                    method.as_str(),
+                    queries.len(),
+                    e
+                ),
+            );
+            if let Some(status) = render_form(config, paths, resolved_params, &e, request) {
+                return status;
+            }
+            return send_db_error(request, "Write execution failed", &e, true);
+        }
+    };
+
+    request.logger().info(
+        "success",
+        &format!(
+            "{} changed {} row(s) with {} params",
+            method.as_str(),
+            outcome.changes,
+            resolved_params.len()
+        ),
+    );
+    notify_email(config, paths, method, resolved_params, &outcome, request);
+
+    match domain::write_response(method) {
+        WriteResponse::Created => {
+            let body = serde_json::json!({
+                "changes": outcome.changes,
+                "last_insert_rowid": outcome.last_insert_rowid,
             });
-            serde_json::to_string(&error_obj)
-                .unwrap_or_else(|_| r#"{"error":"serialization failed"}"#.to_string())
+            send_json_response_with_status(
+                request,
+                &body.to_string(),
+                ngx::http::HTTPStatus::CREATED,
+            )
+        }
+        WriteResponse::NoContent => {
+            // nginx sends a header-only response for a 204 returned by the handler
+            request.discard_body();
+            ngx::http::HTTPStatus::NO_CONTENT.into()
         }
     }
 }
@@ -216,10 +2285,11 @@ mod tests {
     #[test]
     fn test_valid_config_token_accepts_valid() {
         let config = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: "SELECT * FROM test".to_string(),
-            template_path: "test.hbs".to_string(),
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM test".to_string()),
+            template_path: Some("test.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
         let token = ValidConfigToken::from_config(&config, "".into(), "".into());
@@ -227,12 +2297,13 @@ mod tests {
     }
 
     #[test]
-    fn test_valid_config_token_rejects_empty_db() {
+    fn test_valid_config_token_rejects_unset_db() {
         let config = ModuleConfig {
-            db_path: String::new(),
-            query: "SELECT * FROM test".to_string(),
-            template_path: "test.hbs".to_string(),
+            db_path: None,
+            query: Some("SELECT * FROM test".to_string()),
+            template_path: Some("test.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
         let token = ValidConfigToken::from_config(&config, "".into(), "".into());
@@ -240,12 +2311,13 @@ mod tests {
     }
 
     #[test]
-    fn test_valid_config_token_rejects_empty_query() {
+    fn test_valid_config_token_rejects_unset_query() {
         let config = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: String::new(),
-            template_path: "test.hbs".to_string(),
+            db_path: Some("test.db".to_string()),
+            query: None,
+            template_path: Some("test.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
         let token = ValidConfigToken::from_config(&config, "".into(), "".into());
@@ -253,15 +2325,187 @@ mod tests {
     }
 
     #[test]
-    fn test_valid_config_token_rejects_empty_template() {
+    fn test_valid_config_token_rejects_unset_template() {
         let config = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: "SELECT * FROM test".to_string(),
-            template_path: String::new(),
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM test".to_string()),
+            template_path: None,
             query_params: vec![],
+            ..Default::default()
+        };
+
+        let token = ValidConfigToken::from_config(&config, "".into(), "".into());
+        assert!(token.is_none());
+    }
+
+    #[test]
+    fn test_valid_config_token_accepts_rest_without_template() {
+        use crate::rest::TableColumn;
+        use crate::types::RestTable;
+
+        let config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            rest: Some(RestTable::parse("books", None).unwrap()),
+            rest_columns: vec![TableColumn {
+                name: "id".to_string(),
+                declared_type: "INTEGER".to_string(),
+                primary_key: true,
+            }],
+            ..Default::default()
+        };
+
+        let token = ValidConfigToken::from_config(&config, "".into(), "".into());
+        assert!(token.is_some());
+    }
+
+    #[test]
+    fn test_valid_config_token_accepts_export_without_template() {
+        let config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT path FROM pages".to_string()),
+            export_dir: Some("/srv/export".to_string()),
+            ..Default::default()
+        };
+
+        let token = ValidConfigToken::from_config(&config, "".into(), "".into()).unwrap();
+        assert_eq!(token.get().export_dir.as_deref(), Some("/srv/export"));
+        let stages: Vec<_> = location_stages(token.get())
+            .iter()
+            .map(|stage| stage.name())
+            .collect();
+        assert!(stages.contains(&"export"));
+        assert_eq!(stages.last(), Some(&"respond"));
+    }
+
+    #[test]
+    fn test_valid_config_token_accepts_report_without_template() {
+        let config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT title FROM books".to_string()),
+            report: Some(
+                crate::types::Report::parse(&["books", "/srv/reports", "every=1d"]).unwrap(),
+            ),
+            ..Default::default()
+        };
+
+        let token = ValidConfigToken::from_config(&config, "".into(), "".into()).unwrap();
+        assert_eq!(
+            token.get().report.as_ref().map(|report| report.name()),
+            Some("books")
+        );
+        let stages: Vec<_> = location_stages(token.get())
+            .iter()
+            .map(|stage| stage.name())
+            .collect();
+        assert!(stages.contains(&"report"));
+        assert_eq!(stages.last(), Some(&"respond"));
+    }
+
+    #[test]
+    fn test_valid_config_token_rejects_empty_db() {
+        let config = ModuleConfig {
+            db_path: Some(String::new()),
+            query: Some("SELECT * FROM test".to_string()),
+            template_path: Some("test.hbs".to_string()),
+            ..Default::default()
         };
 
         let token = ValidConfigToken::from_config(&config, "".into(), "".into());
         assert!(token.is_none());
     }
+
+    #[test]
+    fn test_valid_config_token_paths() {
+        let config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM test".to_string()),
+            template_path: Some("test.hbs".to_string()),
+            ..Default::default()
+        };
+        let token =
+            ValidConfigToken::from_config(&config, "/var/www".into(), "/books".into()).unwrap();
+        assert_eq!(token.paths(), &RequestPaths::new("/var/www", "/books"));
+        let template = token.get().resolve_template_path(token.paths()).unwrap();
+        assert_eq!(template.full_path(), "/var/www/books/test.hbs");
+    }
+
+    #[test]
+    fn test_location_stages() {
+        use crate::types::{Pagination, SortParam, TryStatic};
+
+        let names = |config: &ModuleConfig| {
+            let validated = parsing::parse_config(config).unwrap();
+            location_stages(&validated)
+                .iter()
+                .map(|stage| stage.name())
+                .collect::<Vec<_>>()
+        };
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM test".to_string()),
+            template_path: Some("test.hbs".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            names(&config),
+            vec!["template", "params", "format", "shape", "query", "respond"]
+        );
+
+        config.sort_param = Some(SortParam::parse("$arg_sort", &["allow=id"]).unwrap());
+        config.pagination = Some(Pagination::parse(&["per_page=10"]).unwrap());
+        config.try_static = Some(TryStatic::parse(&["on", "save=on"]).unwrap());
+        config.write_queries = vec!["DELETE FROM test".to_string()];
+        assert_eq!(
+            names(&config),
+            vec![
+                "template",
+                "params",
+                "write",
+                "sort",
+                "format",
+                "static",
+                "page",
+                "shape",
+                "query",
+                "static_save",
+                "respond",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_run_saves_pages() {
+        let directory = "/tmp/test_sqlite_serve_export";
+        let _ = std::fs::remove_dir_all(directory);
+
+        let mut run = ExportRun {
+            directory: directory.to_string(),
+            uris: vec!["/".to_string(), "/book/1".to_string(), "/gone".to_string()],
+            next: 0,
+            written: 0,
+            failures: Vec::new(),
+        };
+        run.page_done(200, b"<h1>Home</h1>");
+        run.page_done(200, b"<h1>Dune</h1>");
+        run.page_done(404, b"");
+        // Nothing is left to record
+        run.page_done(200, b"extra");
+
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/book/1/index.html", directory)).unwrap(),
+            "<h1>Dune</h1>"
+        );
+        assert!(std::path::Path::new(&format!("{}/index.html", directory)).exists());
+        assert!(!std::path::Path::new(&format!("{}/gone", directory)).exists());
+        assert_eq!(
+            run.summary(),
+            serde_json::json!({
+                "directory": directory,
+                "exported": 2,
+                "failed": [{"uri": "/gone", "error": "status 404"}],
+            })
+        );
+
+        let _ = std::fs::remove_dir_all(directory);
+    }
 }
@@ -0,0 +1,186 @@
+//! This worker's health and the status of every report (`sqlite_health`)
+//!
+//! For each `sqlite_report` location the document gives when its report last
+//! ran, how many rows it saved or why it failed, and when a scheduled report
+//! is next due. The endpoint answers 503 when a report's last run failed, a
+//! scheduled report is overdue by a whole interval (its scheduler is not
+//! running it) or a report's status cannot be read, so an uptime check can
+//! alert on it without parsing the document.
+
+use crate::config::ModuleConfig;
+use crate::query;
+use crate::types::Report;
+use serde_json::{Value, json};
+
+/// What the worker answering the request has done since it started
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerHealth {
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub requests: u64,
+    pub scheduled_runs: u64,
+    pub scheduled_failures: u64,
+    pub last_error: Option<String>,
+}
+
+/// The health document for `locations`, given by name, at `now` (Unix
+/// seconds), and whether everything in it is healthy
+pub fn document(
+    worker: Option<&WorkerHealth>,
+    locations: &[(String, &ModuleConfig)],
+    now: i64,
+) -> (bool, Value) {
+    let reports: Vec<(bool, Value)> = locations
+        .iter()
+        .filter_map(|(name, config)| {
+            let report = config.report.as_ref()?;
+            Some(report_status(name, config, report, now))
+        })
+        .collect();
+    let healthy = reports.iter().all(|(healthy, _)| *healthy);
+    let document = json!({
+        "status": if healthy { "ok" } else { "failing" },
+        "worker": worker.map(|worker| json!({
+            "pid": worker.pid,
+            "uptime_secs": worker.uptime_secs,
+            "requests": worker.requests,
+            "scheduled_runs": worker.scheduled_runs,
+            "scheduled_failures": worker.scheduled_failures,
+            "last_error": worker.last_error,
+        })),
+        "reports": reports.into_iter().map(|(_, report)| report).collect::<Vec<_>>(),
+    });
+    (healthy, document)
+}
+
+/// One report's status, read from its database, and whether it is healthy
+fn report_status(
+    location: &str,
+    config: &ModuleConfig,
+    report: &Report,
+    now: i64,
+) -> (bool, Value) {
+    let mut status = json!({
+        "location": location,
+        "report": report.name(),
+        "path": report.path().to_string_lossy(),
+        "format": report.format().extension(),
+        "every_secs": report.every().map(|every| every.as_secs()),
+    });
+    let db = match config.db_path.as_deref() {
+        Some(db) if !config.database_per_request() => db,
+        // Each request's run goes to its own database; there is no one
+        // status to give
+        _ => {
+            status["database"] = "per_request".into();
+            return (true, status);
+        }
+    };
+    let (last_run, due_at) = match query::report_state(db, report) {
+        Ok(state) => state,
+        Err(e) => {
+            status["error"] = format!("cannot read the report's status: {}", e).into();
+            return (false, status);
+        }
+    };
+    let failed = last_run.as_ref().is_some_and(|run| run.status != "ok");
+    let overdue = match (report.every(), due_at) {
+        (Some(every), Some(due_at)) => now - due_at >= every.as_secs() as i64,
+        _ => false,
+    };
+    status["last_run"] = last_run.map_or(Value::Null, |run| {
+        json!({
+            "status": run.status,
+            "rows": run.rows,
+            "error": run.error,
+            "generated_at": run.generated_at,
+        })
+    });
+    status["next_due"] = due_at.into();
+    status["overdue"] = overdue.into();
+    (!failed && !overdue, status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_document() {
+        let db = "/tmp/test_sqlite_serve_health.db";
+        let _ = std::fs::remove_file(db);
+        Connection::open(db).unwrap();
+        let location = |report: &[&str]| ModuleConfig {
+            db_path: Some(db.to_string()),
+            query: Some("SELECT 1".to_string()),
+            report: Some(Report::parse(report).unwrap()),
+            ..Default::default()
+        };
+        let daily = location(&["daily", "/srv/reports", "every=1d", "format=ndjson"]);
+        let manual = location(&["manual", "/srv/reports"]);
+        let tenant = ModuleConfig {
+            db_path: Some("/srv/tenants/$host.db".to_string()),
+            ..location(&["tenant", "/srv/reports"])
+        };
+        let page = ModuleConfig::default();
+        let locations = vec![
+            ("/daily".to_string(), &daily),
+            ("/manual".to_string(), &manual),
+            ("/tenant".to_string(), &tenant),
+            ("/".to_string(), &page),
+        ];
+        let worker = WorkerHealth {
+            pid: 42,
+            uptime_secs: 60,
+            requests: 3,
+            scheduled_runs: 1,
+            scheduled_failures: 0,
+            last_error: None,
+        };
+
+        // Nothing has run yet
+        let (healthy, status) = document(Some(&worker), &locations, 1_000);
+        assert!(healthy);
+        assert_eq!(status["status"], "ok");
+        assert_eq!(status["worker"]["pid"], 42);
+        let reports = status["reports"].as_array().unwrap();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0]["location"], "/daily");
+        assert_eq!(reports[0]["path"], "/srv/reports/daily.ndjson");
+        assert_eq!(reports[0]["every_secs"], 86_400);
+        assert_eq!(reports[0]["last_run"], Value::Null);
+        assert_eq!(reports[1]["every_secs"], Value::Null);
+        assert_eq!(reports[2]["database"], "per_request");
+
+        // A scheduled run that succeeded
+        let report = daily.report.as_ref().unwrap();
+        assert!(query::claim_report(db, report, 1_000).unwrap());
+        query::record_report(db, report, &Ok(7)).unwrap();
+        let (healthy, status) = document(None, &locations, 1_000);
+        assert!(healthy);
+        assert_eq!(status["worker"], Value::Null);
+        assert_eq!(status["reports"][0]["last_run"]["status"], "ok");
+        assert_eq!(status["reports"][0]["last_run"]["rows"], 7);
+        assert_eq!(status["reports"][0]["next_due"], 1_000 + 86_400);
+
+        // Not run a whole interval after it was due
+        let (healthy, status) = document(None, &locations, 1_000 + 2 * 86_400);
+        assert!(!healthy);
+        assert_eq!(status["status"], "failing");
+        assert_eq!(status["reports"][0]["overdue"], true);
+
+        // A failed run, even of a report run by POST
+        query::record_report(db, manual.report.as_ref().unwrap(), &Err("locked".into())).unwrap();
+        let (healthy, status) = document(None, &locations, 1_000);
+        assert!(!healthy);
+        assert_eq!(status["reports"][0]["overdue"], false);
+        assert_eq!(status["reports"][1]["last_run"]["error"], "locked");
+
+        // A database that cannot be read
+        let _ = std::fs::remove_file(db);
+        let (healthy, status) = document(None, &locations[..1], 1_000);
+        assert!(!healthy);
+        assert!(status["reports"][0]["error"].is_string());
+    }
+}
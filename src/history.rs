@@ -0,0 +1,307 @@
+//! Row change history for `sqlite_history` tables (pure functions)
+//!
+//! Triggers copy a row's previous version into `<table>_history` whenever an
+//! UPDATE or DELETE changes it, and note each INSERT, stamped with the time in
+//! UTC. Reading `?as_of=` a time shows the table as it was then: a row
+//! changed since comes from the first version saved after that time, and a
+//! row inserted since is left out. Rows untouched since the history began are
+//! shown as they are now, so a table's past only reaches back to when its
+//! history was installed.
+
+use crate::rest::TableColumn;
+use crate::types::AsOf;
+
+/// Columns the history table keeps about each saved version
+pub const VERSION_COLUMNS: [&str; 4] = [
+    "history_id",
+    "history_rowid",
+    "history_operation",
+    "history_at",
+];
+
+/// When a change happened, to the millisecond, in the form [`AsOf`] uses
+const NOW: &str = "strftime('%Y-%m-%d %H:%M:%f', 'now')";
+
+/// The table `table`'s past versions are saved in
+pub fn history_table(table: &str) -> String {
+    format!("{}_history", table)
+}
+
+/// Statements that create `table`'s history table and triggers, or bring
+/// them up to date with its columns
+///
+/// `recorded` are the history table's columns, empty when it does not exist
+/// yet. Columns added to the table since are added to the history too, and
+/// the triggers are always recreated so they copy every column.
+pub fn migration(
+    table: &str,
+    columns: &[TableColumn],
+    recorded: &[String],
+) -> Result<Vec<String>, String> {
+    if columns.is_empty() {
+        return Err(format!("no such table '{}'", table));
+    }
+    if let Some(column) = columns
+        .iter()
+        .find(|c| VERSION_COLUMNS.contains(&c.name.as_str()))
+    {
+        return Err(format!(
+            "column '{}' of '{}' is reserved for the history",
+            column.name, table
+        ));
+    }
+    let history = quote(&history_table(table));
+    let table_name = quote(table);
+
+    let mut statements = Vec::new();
+    if recorded.is_empty() {
+        statements.push(format!(
+            "CREATE TABLE {} (history_id INTEGER PRIMARY KEY, history_rowid INTEGER NOT NULL, \
+             history_operation TEXT NOT NULL, history_at TEXT NOT NULL{})",
+            history,
+            columns
+                .iter()
+                .map(|c| format!(", {}", column_definition(c)))
+                .collect::<String>()
+        ));
+    } else {
+        for column in columns.iter().filter(|c| !recorded.contains(&c.name)) {
+            statements.push(format!(
+                "ALTER TABLE {} ADD COLUMN {}",
+                history,
+                column_definition(column)
+            ));
+        }
+    }
+    statements.push(format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {} (history_rowid, history_at)",
+        quote(&format!("{}_history_rowid", table)),
+        history
+    ));
+
+    let names = join(columns, |c| quote(&c.name));
+    let old_values = join(columns, |c| format!("OLD.{}", quote(&c.name)));
+    let save_old = |operation: &str| {
+        format!(
+            "INSERT INTO {} (history_rowid, history_operation, history_at, {}) \
+             VALUES (OLD.rowid, '{}', {}, {});",
+            history, names, operation, NOW, old_values
+        )
+    };
+    let note_insert = format!(
+        "INSERT INTO {} (history_rowid, history_operation, history_at) \
+         SELECT NEW.rowid, 'INSERT', {}",
+        history, NOW
+    );
+    let triggers = [
+        ("insert", "INSERT", format!("{};", note_insert)),
+        (
+            "update",
+            "UPDATE",
+            // A changed rowid is a new row as far as the history goes
+            format!(
+                "{} {} WHERE NEW.rowid IS NOT OLD.rowid;",
+                save_old("UPDATE"),
+                note_insert
+            ),
+        ),
+        ("delete", "DELETE", save_old("DELETE")),
+    ];
+    for (name, event, body) in triggers {
+        let trigger = quote(&format!("{}_history_{}", table, name));
+        statements.push(format!("DROP TRIGGER IF EXISTS {}", trigger));
+        statements.push(format!(
+            "CREATE TRIGGER {} AFTER {} ON {} BEGIN {} END",
+            trigger, event, table_name, body
+        ));
+    }
+    Ok(statements)
+}
+
+/// A temporary view named after `table` that shows it as it was at `as_of`
+///
+/// Temporary objects are found before the database's own, so queries that
+/// name the table read the view instead; a connection opened read-only can
+/// still create them.
+pub fn as_of_view(table: &str, columns: &[TableColumn], as_of: &AsOf) -> String {
+    let history = quote(&history_table(table));
+    let names = join(columns, |c| quote(&c.name));
+    // AsOf only holds digits, dashes, colons, a space and a dot
+    let at = format!("'{}'", as_of.as_str());
+    format!(
+        "DROP VIEW IF EXISTS temp.{view}; \
+         CREATE TEMP VIEW {view} AS \
+         SELECT {names} FROM main.{view} AS current WHERE NOT EXISTS \
+         (SELECT 1 FROM main.{history} AS h WHERE h.history_rowid = current.rowid AND h.history_at > {at}) \
+         UNION ALL \
+         SELECT {names} FROM main.{history} AS h WHERE h.history_operation <> 'INSERT' AND h.history_id = \
+         (SELECT min(e.history_id) FROM main.{history} AS e \
+         WHERE e.history_rowid = h.history_rowid AND e.history_at > {at});",
+        view = quote(table),
+        names = names,
+        history = history,
+        at = at
+    )
+}
+
+/// A history column: the table column's name and type, without its constraints
+fn column_definition(column: &TableColumn) -> String {
+    format!("{} {}", quote(&column.name), column.declared_type)
+        .trim_end()
+        .to_string()
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn join(columns: &[TableColumn], render: impl Fn(&TableColumn) -> String) -> String {
+    columns.iter().map(render).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    fn column(name: &str, declared_type: &str) -> TableColumn {
+        TableColumn {
+            name: name.to_string(),
+            declared_type: declared_type.to_string(),
+            primary_key: name == "id",
+        }
+    }
+
+    fn rows(conn: &Connection, sql: &str) -> Vec<(i64, Option<String>)> {
+        conn.prepare(sql)
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migration() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT NOT NULL);
+             INSERT INTO books VALUES (1, 'Dune');",
+        )
+        .unwrap();
+        let columns = vec![column("id", "INTEGER"), column("title", "TEXT")];
+        for statement in migration("books", &columns, &[]).unwrap() {
+            conn.execute(&statement, []).unwrap();
+        }
+
+        conn.execute_batch(
+            "UPDATE books SET title = 'Dune Messiah' WHERE id = 1;
+             INSERT INTO books VALUES (2, 'Emma');
+             DELETE FROM books WHERE id = 2;",
+        )
+        .unwrap();
+        assert_eq!(
+            rows(
+                &conn,
+                "SELECT history_rowid, history_operation || ':' || coalesce(title, '') \
+                 FROM books_history ORDER BY history_id"
+            ),
+            vec![
+                (1, Some("UPDATE:Dune".to_string())),
+                (2, Some("INSERT:".to_string())),
+                (2, Some("DELETE:Emma".to_string())),
+            ]
+        );
+
+        // A column added later joins the history, and migrating again is harmless
+        conn.execute("ALTER TABLE books ADD COLUMN year INTEGER", [])
+            .unwrap();
+        let columns = vec![
+            column("id", "INTEGER"),
+            column("title", "TEXT"),
+            column("year", "INTEGER"),
+        ];
+        let recorded: Vec<String> = ["history_id", "history_rowid", "id", "title"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        let statements = migration("books", &columns, &recorded).unwrap();
+        assert_eq!(
+            statements[0],
+            "ALTER TABLE \"books_history\" ADD COLUMN \"year\" INTEGER"
+        );
+        for statement in statements {
+            conn.execute(&statement, []).unwrap();
+        }
+        conn.execute("UPDATE books SET year = 1969 WHERE id = 1", [])
+            .unwrap();
+        assert_eq!(
+            rows(
+                &conn,
+                "SELECT count(*), NULL FROM books_history WHERE title = 'Dune Messiah'"
+            ),
+            vec![(1, None)]
+        );
+
+        assert!(migration("books", &[], &[]).is_err());
+        assert!(
+            migration("books", &[column("history_at", "TEXT")], &[])
+                .unwrap_err()
+                .contains("reserved")
+        );
+    }
+
+    #[test]
+    fn test_as_of_view() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);
+             INSERT INTO books VALUES (1, 'Dune'), (2, 'Emma');",
+        )
+        .unwrap();
+        let columns = vec![column("id", "INTEGER"), column("title", "TEXT")];
+        for statement in migration("books", &columns, &[]).unwrap() {
+            conn.execute(&statement, []).unwrap();
+        }
+        conn.execute_batch(
+            "UPDATE books SET title = 'Dune Messiah' WHERE id = 1;
+             DELETE FROM books WHERE id = 2;
+             INSERT INTO books VALUES (3, 'Ulysses');
+             UPDATE books SET id = 4 WHERE id = 3;",
+        )
+        .unwrap();
+        // Give each change its own day
+        conn.execute(
+            "UPDATE books_history SET history_at = '2024-01-0' || history_id || ' 00:00:00.000'",
+            [],
+        )
+        .unwrap();
+
+        let at = |time: &str| {
+            conn.execute_batch(&as_of_view("books", &columns, &AsOf::parse(time).unwrap()))
+                .unwrap();
+            rows(&conn, "SELECT id, title FROM books ORDER BY id")
+        };
+        let book = |id: i64, title: &str| (id, Some(title.to_string()));
+        assert_eq!(at("2023-12-31"), vec![book(1, "Dune"), book(2, "Emma")]);
+        assert_eq!(
+            at("2024-01-01"),
+            vec![book(1, "Dune Messiah"), book(2, "Emma")]
+        );
+        assert_eq!(
+            at("2024-01-03"),
+            vec![book(1, "Dune Messiah"), book(3, "Ulysses")]
+        );
+        assert_eq!(
+            at("2024-01-05"),
+            vec![book(1, "Dune Messiah"), book(4, "Ulysses")]
+        );
+
+        // Dropping the view shows the table as it is
+        conn.execute("DROP VIEW temp.books", []).unwrap();
+        assert_eq!(
+            rows(&conn, "SELECT id, title FROM books ORDER BY id"),
+            vec![book(1, "Dune Messiah"), book(4, "Ulysses")]
+        );
+    }
+}
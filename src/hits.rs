@@ -0,0 +1,326 @@
+//! Page hit counts for `sqlite_hit_counter`
+//!
+//! Each read a counted location answers adds one to its page's count in this
+//! worker's memory, and a background thread adds the counts to the database
+//! every few seconds in one transaction per database. A popular page then
+//! costs one row update per interval rather than one write per request, and
+//! requests never wait for SQLite's write lock. As with mail, the thread
+//! starts with a worker's first hit and whatever is still pending is written
+//! when the worker exits.
+//!
+//! With a daily table, each flush also adds its hits to the current day's
+//! row for the page, so `sqlite_trending` can rank pages over recent days.
+
+use crate::query;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often pending hits are written
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A counted page: its database, tables and key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Page {
+    db: String,
+    table: String,
+    daily: Option<String>,
+    key: String,
+}
+
+/// Hits to add to one page's rows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageHits {
+    pub table: String,
+    pub daily: Option<String>,
+    pub key: String,
+    pub hits: u64,
+}
+
+#[derive(Debug)]
+enum Flusher {
+    Idle,
+    Running(Sender<()>, JoinHandle<()>),
+    Closed,
+}
+
+/// Hits not yet written, and write counters, shared with the flusher thread
+#[derive(Debug, Default)]
+struct Pending {
+    counts: Mutex<HashMap<Page, u64>>,
+    flushed: AtomicU64,
+    failed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// One worker's hit counts
+#[derive(Debug)]
+pub struct HitCounters {
+    flusher: Mutex<Flusher>,
+    pending: Arc<Pending>,
+    interval: Duration,
+}
+
+impl HitCounters {
+    pub fn new() -> Self {
+        Self::with_interval(FLUSH_INTERVAL)
+    }
+
+    fn with_interval(interval: Duration) -> Self {
+        HitCounters {
+            flusher: Mutex::new(Flusher::Idle),
+            pending: Arc::new(Pending::default()),
+            interval,
+        }
+    }
+
+    /// Count one hit on `key` in `table` (and `daily`, if given), starting the
+    /// flusher thread if needed, and return the page's hits this worker has
+    /// yet to write
+    pub fn record(
+        &self,
+        db: &str,
+        table: &str,
+        daily: Option<&str>,
+        key: &str,
+    ) -> Result<u64, String> {
+        let mut flusher = self
+            .flusher
+            .lock()
+            .map_err(|_| "hit counter is poisoned".to_string())?;
+        if matches!(*flusher, Flusher::Idle) {
+            let (sender, receiver) = mpsc::channel::<()>();
+            let pending = Arc::clone(&self.pending);
+            let interval = self.interval;
+            let thread = thread::Builder::new()
+                .name("sqlite-serve-hits".to_string())
+                .spawn(move || {
+                    // Closing the channel flushes once more and stops
+                    while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                        flush(&pending);
+                    }
+                    flush(&pending);
+                })
+                .map_err(|e| format!("cannot start the hit counter thread: {}", e))?;
+            *flusher = Flusher::Running(sender, thread);
+        }
+        if matches!(*flusher, Flusher::Closed) {
+            return Err("the hit counter is closed".to_string());
+        }
+
+        let mut counts = self
+            .pending
+            .counts
+            .lock()
+            .map_err(|_| "hit counter is poisoned".to_string())?;
+        let count = counts
+            .entry(Page {
+                db: db.to_string(),
+                table: table.to_string(),
+                daily: daily.map(str::to_string),
+                key: key.to_string(),
+            })
+            .or_insert(0);
+        *count += 1;
+        Ok(*count)
+    }
+
+    /// Write every pending hit and stop the thread; later hits are refused
+    pub fn finish(&self) {
+        let Ok(mut flusher) = self.flusher.lock() else {
+            return;
+        };
+        if let Flusher::Running(sender, thread) = std::mem::replace(&mut *flusher, Flusher::Closed)
+        {
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+
+    /// Hits written to the database
+    pub fn flushed(&self) -> u64 {
+        self.pending.flushed.load(Ordering::Relaxed)
+    }
+
+    /// Flushes that failed (their hits are kept for the next one)
+    pub fn failed(&self) -> u64 {
+        self.pending.failed.load(Ordering::Relaxed)
+    }
+
+    /// Why the most recent failed flush failed
+    pub fn last_error(&self) -> Option<String> {
+        self.pending
+            .last_error
+            .lock()
+            .ok()
+            .and_then(|last| last.clone())
+    }
+}
+
+impl Default for HitCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Add every pending hit to its table, one transaction per database
+///
+/// A database that cannot be written (locked past the busy timeout, or a
+/// missing table) keeps its hits pending, so they are retried next time.
+fn flush(pending: &Pending) {
+    let Ok(mut counts) = pending.counts.lock() else {
+        return;
+    };
+    let taken = std::mem::take(&mut *counts);
+    // Release the lock so requests can keep counting while this writes
+    drop(counts);
+
+    let mut by_db: BTreeMap<String, Vec<PageHits>> = BTreeMap::new();
+    for (page, hits) in taken {
+        by_db.entry(page.db).or_default().push(PageHits {
+            table: page.table,
+            daily: page.daily,
+            key: page.key,
+            hits,
+        });
+    }
+    for (db, hits) in by_db {
+        match query::add_hits(&db, &hits) {
+            Ok(()) => {
+                let written: u64 = hits.iter().map(|page| page.hits).sum();
+                pending.flushed.fetch_add(written, Ordering::Relaxed);
+            }
+            Err(e) => {
+                pending.failed.fetch_add(1, Ordering::Relaxed);
+                if let Ok(mut last) = pending.last_error.lock() {
+                    *last = Some(format!("{}: {}", db, e));
+                }
+                if let Ok(mut counts) = pending.counts.lock() {
+                    for page in hits {
+                        let pending = Page {
+                            db: db.clone(),
+                            table: page.table,
+                            daily: page.daily,
+                            key: page.key,
+                        };
+                        *counts.entry(pending).or_insert(0) += page.hits;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    fn hits(db: &str, key: &str) -> u64 {
+        query::stored_hits(db, "page_views", key).unwrap()
+    }
+
+    #[test]
+    fn test_hit_counters() {
+        let db = "/tmp/test_hit_counters.db";
+        let _ = std::fs::remove_file(db);
+        Connection::open(db)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE page_views (key TEXT PRIMARY KEY, hits INTEGER NOT NULL DEFAULT 0);
+                 INSERT INTO page_views VALUES ('/about', 10);",
+            )
+            .unwrap();
+
+        let counters = HitCounters::with_interval(Duration::from_secs(3600));
+        assert_eq!(
+            counters.record(db, "page_views", None, "/about").unwrap(),
+            1
+        );
+        assert_eq!(
+            counters.record(db, "page_views", None, "/about").unwrap(),
+            2
+        );
+        assert_eq!(counters.record(db, "page_views", None, "/").unwrap(), 1);
+        // A table that does not exist fails its database's flush
+        let missing = "/tmp/test_hit_counters_missing.db";
+        let _ = std::fs::remove_file(missing);
+        Connection::open(missing).unwrap();
+        counters.record(missing, "page_views", None, "/").unwrap();
+
+        // Nothing is written until the interval passes or the worker exits
+        assert_eq!(hits(db, "/about"), 10);
+        assert_eq!(hits(db, "/"), 0);
+        counters.finish();
+        assert_eq!(hits(db, "/about"), 12);
+        assert_eq!(hits(db, "/"), 1);
+        assert_eq!(counters.flushed(), 3);
+        assert_eq!(counters.failed(), 1);
+        assert!(counters.last_error().unwrap().contains("page_views"));
+
+        assert!(counters.record(db, "page_views", None, "/").is_err());
+        let _ = std::fs::remove_file(db);
+        let _ = std::fs::remove_file(missing);
+    }
+
+    #[test]
+    fn test_daily_counts() {
+        let db = "/tmp/test_hit_counters_daily.db";
+        let _ = std::fs::remove_file(db);
+        let conn = Connection::open(db).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE page_views (key TEXT PRIMARY KEY, hits INTEGER NOT NULL DEFAULT 0);
+             CREATE TABLE page_views_daily (key TEXT, day TEXT, hits INTEGER NOT NULL DEFAULT 0,
+                                            PRIMARY KEY (key, day));
+             INSERT INTO page_views_daily VALUES ('/', date('now', '-1 day'), 5);",
+        )
+        .unwrap();
+
+        let counters = HitCounters::with_interval(Duration::from_secs(3600));
+        let daily = Some("page_views_daily");
+        counters.record(db, "page_views", daily, "/").unwrap();
+        counters.record(db, "page_views", daily, "/").unwrap();
+        counters.finish();
+
+        assert_eq!(hits(db, "/"), 2);
+        let days: Vec<(bool, i64)> = conn
+            .prepare("SELECT day = date('now'), hits FROM page_views_daily ORDER BY day")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(days, vec![(false, 5), (true, 2)]);
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[test]
+    fn test_flush_on_interval() {
+        let db = "/tmp/test_hit_counters_interval.db";
+        let _ = std::fs::remove_file(db);
+        Connection::open(db)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE page_views (key TEXT PRIMARY KEY, hits INTEGER NOT NULL DEFAULT 0);",
+            )
+            .unwrap();
+
+        let counters = HitCounters::with_interval(Duration::from_millis(10));
+        counters.record(db, "page_views", None, "/").unwrap();
+        for _ in 0..100 {
+            if counters.flushed() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(counters.flushed(), 1);
+        assert_eq!(hits(db, "/"), 1);
+        // Written hits are no longer pending
+        assert_eq!(counters.record(db, "page_views", None, "/").unwrap(), 1);
+        counters.finish();
+        let _ = std::fs::remove_file(db);
+    }
+}
@@ -1,28 +1,65 @@
 //! sqlite-serve - NGINX module for serving dynamic content from SQLite databases
 
 mod adapters;
+mod cache;
+mod capabilities;
 mod config;
+mod config_cache;
 mod content_type;
+mod directives;
 mod domain;
+mod feed;
+#[cfg(test)]
+mod fixture;
+mod formatter;
+mod functions;
+mod guard;
 mod handler_types;
+mod health;
+mod history;
+mod hits;
+mod mail;
+mod metrics;
 mod nginx_helpers;
 mod parsing;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod preflight;
 mod query;
+mod report;
+mod rest;
+mod static_site;
+mod stream;
 mod template;
+mod trace;
 mod types;
+mod typescript;
 mod variable;
+mod worker;
+#[cfg(feature = "xlsx")]
+mod xlsx;
 
-use config::{MainConfig, ModuleConfig};
-use handler_types::{ValidConfigToken, process_request};
+use adapters::NginxLogger;
+use config::{MainConfig, ModuleConfig, RegisteredLocation};
+use domain::Logger;
+use handler_types::{
+    CaptchaCheck, ExportRun, ValidConfigToken, continue_export, process_batch, process_request,
+    serve_blob, serve_capabilities, serve_health, serve_typescript,
+};
 use ngx::ffi::{
-    NGX_CONF_TAKE1, NGX_CONF_TAKE2, NGX_HTTP_LOC_CONF, NGX_HTTP_LOC_CONF_OFFSET,
-    NGX_HTTP_MAIN_CONF, NGX_HTTP_MODULE, NGX_RS_MODULE_SIGNATURE, nginx_version, ngx_command_t,
-    ngx_conf_t, ngx_http_module_t, ngx_int_t, ngx_module_t, ngx_str_t, ngx_uint_t,
+    NGX_CONF_1MORE, NGX_CONF_2MORE, NGX_CONF_NOARGS, NGX_CONF_TAKE1, NGX_CONF_TAKE2,
+    NGX_CONF_TAKE3, NGX_CONF_TAKE4, NGX_HTTP_LOC_CONF, NGX_HTTP_LOC_CONF_OFFSET,
+    NGX_HTTP_MAIN_CONF, NGX_HTTP_MODULE, NGX_HTTP_SRV_CONF, NGX_LOG_EMERG, NGX_LOG_ERR,
+    NGX_LOG_NOTICE, NGX_LOG_WARN, NGX_RS_MODULE_SIGNATURE, nginx_version, ngx_command_t,
+    ngx_conf_t, ngx_cycle_t, ngx_http_finalize_request, ngx_http_module_t, ngx_http_request_t,
+    ngx_int_t, ngx_module_t, ngx_str_t, ngx_uint_t,
 };
 use ngx::http::{HttpModule, HttpModuleLocationConf, HttpModuleMainConf, NgxHttpCoreModule};
-use ngx::{core::Status, http, http_request_handler, ngx_modules, ngx_string};
+use ngx::{core::Status, http, http_request_handler, ngx_log_error, ngx_modules, ngx_string};
+use report::ScheduledReport;
 use std::os::raw::{c_char, c_void};
 use std::ptr::addr_of;
+use types::ParameterBinding;
 
 pub struct Module;
 
@@ -31,8 +68,434 @@ impl ngx::http::HttpModule for Module {
         unsafe { &*addr_of!(ngx_http_howto_module) }
     }
 
-    unsafe extern "C" fn postconfiguration(_cf: *mut ngx_conf_t) -> ngx_int_t {
-        Status::NGX_OK.into()
+    unsafe extern "C" fn preconfiguration(cf: *mut ngx_conf_t) -> ngx_int_t {
+        let status =
+            guard::catch_panic(|| variable::add_module_variables(cf)).and_then(|added| added);
+        match status {
+            Ok(()) => Status::NGX_OK.into(),
+            Err(e) => {
+                let cf = unsafe { &*cf };
+                ngx_log_error!(
+                    NGX_LOG_EMERG,
+                    cf.log,
+                    "[sqlite-serve] preconfiguration: {}",
+                    e
+                );
+                Status::NGX_ERROR.into()
+            }
+        }
+    }
+
+    unsafe extern "C" fn postconfiguration(cf: *mut ngx_conf_t) -> ngx_int_t {
+        let cf = unsafe { &*cf };
+        // Locations are known by their configuration's address, which only
+        // identifies them within one configuration cycle
+        worker::with_state(|state| state.config_cache().clear());
+        if !index_module_variables(cf) {
+            return Status::NGX_ERROR.into();
+        }
+        let status = guard::catch_panic(|| match Module::main_conf(cf) {
+            Some(main_conf) if !resolve_named_queries(cf, main_conf) => Status::NGX_ERROR,
+            Some(main_conf) if !load_database_maps(cf, main_conf) => Status::NGX_ERROR,
+            Some(main_conf) if !index_variables(cf, main_conf) => Status::NGX_ERROR,
+            Some(main_conf) if !load_rest_tables(cf, main_conf) => Status::NGX_ERROR,
+            Some(main_conf) if !check_blob_tables(cf, main_conf) => Status::NGX_ERROR,
+            Some(main_conf) if !check_history_tables(cf, main_conf) => Status::NGX_ERROR,
+            Some(main_conf) => log_configuration_report(cf, main_conf),
+            None => Status::NGX_OK,
+        });
+        status
+            .unwrap_or_else(|e| {
+                ngx_log_error!(
+                    NGX_LOG_EMERG,
+                    cf.log,
+                    "[sqlite-serve] postconfiguration: {}",
+                    e
+                );
+                Status::NGX_ERROR
+            })
+            .into()
+    }
+}
+
+/// Replace `sqlite_query @name` references with their `sqlite_query_define` SQL
+///
+/// Returns false (after logging why) when a definition is invalid or a location
+/// references an undefined name.
+fn resolve_named_queries(cf: &ngx_conf_t, main_conf: &MainConfig) -> bool {
+    if let Err(e) = parsing::validate_query_definitions(&main_conf.query_definitions) {
+        ngx_log_error!(NGX_LOG_EMERG, cf.log, "[sqlite-serve] {}", e);
+        return false;
+    }
+
+    let mut ok = true;
+    for location in &main_conf.locations {
+        // SAFETY: see log_configuration_report; nothing else holds a reference
+        // to the location configuration while postconfiguration runs.
+        let (config, core_conf) = unsafe { (&mut *location.config, &*location.core_conf) };
+        let Some(query) = &config.query else {
+            continue;
+        };
+        match parsing::resolve_query_reference(query, &main_conf.query_definitions) {
+            Ok(Some(sql)) => config.query = Some(sql),
+            Ok(None) => {}
+            Err(e) => {
+                ngx_log_error!(
+                    NGX_LOG_EMERG,
+                    cf.log,
+                    "[sqlite-serve] location {}: {}",
+                    core_conf.name.to_str().unwrap_or("<invalid utf-8>"),
+                    e
+                );
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Prepare every `sqlite_db_map` query on its control database, binding its
+/// parameters to the variables they name
+///
+/// Returns false (after logging why) when a control database cannot be
+/// opened or its query is not one the map can use.
+fn load_database_maps(cf: &ngx_conf_t, main_conf: &MainConfig) -> bool {
+    let mut ok = true;
+    for location in &main_conf.locations {
+        // SAFETY: see resolve_named_queries.
+        let (config, core_conf) = unsafe { (&mut *location.config, &*location.core_conf) };
+        let Some(map) = &config.db_map else {
+            continue;
+        };
+        let loaded = query::database_map_parameters(map)
+            .and_then(|names| map.clone().with_parameters(&names))
+            .map_err(|e| format!("{}: {}", map.control().as_str(), e));
+        match loaded {
+            Ok(map) => config.db_map = Some(map),
+            Err(e) => {
+                ngx_log_error!(
+                    NGX_LOG_EMERG,
+                    cf.log,
+                    "[sqlite-serve] location {}: sqlite_db_map {}",
+                    core_conf.name.to_str().unwrap_or("<invalid utf-8>"),
+                    e
+                );
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Have nginx index every variable a location binds to a parameter, so
+/// requests look their values up by index rather than by name
+///
+/// Returns false (after logging why) when nginx cannot index a variable. A
+/// location whose configuration is invalid is left to the configuration
+/// report.
+fn index_variables(cf: &ngx_conf_t, main_conf: &MainConfig) -> bool {
+    let mut ok = true;
+    for location in &main_conf.locations {
+        // SAFETY: see resolve_named_queries.
+        let (config, core_conf) = unsafe { (&mut *location.config, &*location.core_conf) };
+        let name = core_conf.name.to_str().unwrap_or("<invalid utf-8>");
+        let Ok(validated) = parsing::parse_config(config) else {
+            continue;
+        };
+        let mut indexes: Vec<(String, usize)> = Vec::new();
+        for variable in validated
+            .parameters
+            .iter()
+            .filter_map(ParameterBinding::variable)
+            .filter(|variable| variable.provided_by_nginx())
+        {
+            if indexes.iter().any(|(bound, _)| bound == variable.as_str()) {
+                continue;
+            }
+            // nginx allocates the variable from the configuration's pool
+            let conf = cf as *const ngx_conf_t as *mut ngx_conf_t;
+            match variable::variable_index(conf, variable) {
+                Ok(index) => indexes.push((variable.as_str().to_string(), index)),
+                Err(e) => {
+                    ngx_log_error!(
+                        NGX_LOG_EMERG,
+                        cf.log,
+                        "[sqlite-serve] location {}: {}",
+                        name,
+                        e
+                    );
+                    ok = false;
+                }
+            }
+        }
+        config.variable_indexes = indexes;
+    }
+    ok
+}
+
+/// Keep the indexes of `$sqlite_error_code` and `$sqlite_error_message`,
+/// through which failed requests set them, and of `$sqlite_stream_buffered`
+///
+/// Returns false (after logging why) when nginx cannot index them.
+fn index_module_variables(cf: &ngx_conf_t) -> bool {
+    let Some(main_conf) = Module::main_conf_mut(cf) else {
+        return true;
+    };
+    // nginx allocates the variables from the configuration's pool
+    let conf = cf as *const ngx_conf_t as *mut ngx_conf_t;
+    let indexes = variable::error_variable_indexes(conf)
+        .and_then(|errors| Ok((errors, variable::stream_variable_index(conf)?)));
+    match indexes {
+        Ok((errors, stream)) => {
+            main_conf.error_variables = Some(errors);
+            main_conf.stream_variable = Some(stream);
+            true
+        }
+        Err(e) => {
+            ngx_log_error!(NGX_LOG_EMERG, cf.log, "[sqlite-serve] {}", e);
+            false
+        }
+    }
+}
+
+/// Read the columns of every `sqlite_rest` and `sqlite_upsert` table, from
+/// which the location's statements are generated
+///
+/// Returns false (after logging why) when a database cannot be opened or the
+/// table does not exist.
+fn load_rest_tables(cf: &ngx_conf_t, main_conf: &MainConfig) -> bool {
+    let mut ok = true;
+    for location in &main_conf.locations {
+        // SAFETY: see resolve_named_queries.
+        let (config, core_conf) = unsafe { (&mut *location.config, &*location.core_conf) };
+        let (directive, table) = match (&config.rest, &config.upsert) {
+            (Some(rest), _) => ("sqlite_rest", rest.table().to_string()),
+            (None, Some(upsert)) => ("sqlite_upsert", upsert.table().to_string()),
+            (None, None) => continue,
+        };
+        let columns = match &config.db_path {
+            _ if config.database_per_request() => Err(
+                "needs one sqlite_db for every request, to read the columns at startup".to_string(),
+            ),
+            Some(db_path) => query::open_read_only(db_path.as_str())
+                .and_then(|conn| query::table_columns(&conn, &table))
+                .map_err(|e| format!("{}: {}", db_path, e)),
+            None => Err("sqlite_db is not set".to_string()),
+        };
+        match columns {
+            Ok(columns) if !columns.is_empty() => config.rest_columns = columns,
+            result => {
+                let reason = result
+                    .err()
+                    .unwrap_or_else(|| format!("no such table '{}'", table));
+                ngx_log_error!(
+                    NGX_LOG_EMERG,
+                    cf.log,
+                    "[sqlite-serve] location {}: {} {}: {}",
+                    core_conf.name.to_str().unwrap_or("<invalid utf-8>"),
+                    directive,
+                    table,
+                    reason
+                );
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Check that every `sqlite_blob` table has the columns its location reads
+///
+/// Returns false (after logging why) when a database cannot be opened or the
+/// statement reading blobs cannot be prepared.
+fn check_blob_tables(cf: &ngx_conf_t, main_conf: &MainConfig) -> bool {
+    let mut ok = true;
+    for location in &main_conf.locations {
+        // SAFETY: see resolve_named_queries.
+        let (config, core_conf) = unsafe { (&*location.config, &*location.core_conf) };
+        let Some(route) = &config.blob else {
+            continue;
+        };
+        let prepared = match &config.db_path {
+            // Each request's database is checked when it is opened
+            _ if config.database_per_request() => Ok(0),
+            Some(db_path) => query::open_read_only(db_path.as_str())
+                .and_then(|conn| query::prepare_query(&conn, &route.query()))
+                .map_err(|e| format!("{}: {}", db_path, e)),
+            None => Err("sqlite_db is not set".to_string()),
+        };
+        if let Err(e) = prepared {
+            ngx_log_error!(
+                NGX_LOG_EMERG,
+                cf.log,
+                "[sqlite-serve] location {}: sqlite_blob {}: {}",
+                core_conf.name.to_str().unwrap_or("<invalid utf-8>"),
+                route.table(),
+                e
+            );
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Check, without writing to them, that every `sqlite_history` table's
+/// history can be installed once workers start (see [`install_history_tables`])
+///
+/// Returns false (after logging why) when a table does not exist or cannot
+/// have a history, so `nginx -t` reports it without touching the database.
+fn check_history_tables(cf: &ngx_conf_t, main_conf: &MainConfig) -> bool {
+    let mut ok = true;
+    for location in &main_conf.locations {
+        // SAFETY: see resolve_named_queries.
+        let (config, core_conf) = unsafe { (&*location.config, &*location.core_conf) };
+        for table in &config.history {
+            let checked = match &config.db_path {
+                _ if config.database_per_request() => Err(
+                    "needs one sqlite_db for every request, to install the history at startup"
+                        .to_string(),
+                ),
+                Some(db_path) => {
+                    query::check_history(db_path, table).map_err(|e| format!("{}: {}", db_path, e))
+                }
+                None => Err("sqlite_db is not set".to_string()),
+            };
+            if let Err(e) = checked {
+                ngx_log_error!(
+                    NGX_LOG_EMERG,
+                    cf.log,
+                    "[sqlite-serve] location {}: sqlite_history {}: {}",
+                    core_conf.name.to_str().unwrap_or("<invalid utf-8>"),
+                    table,
+                    e
+                );
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Create or update the history table and triggers of every `sqlite_history`
+/// table as a worker starts, so its changes are saved from its first request
+///
+/// This runs in the workers rather than while the configuration is read, so
+/// the database is written as the user that serves it and `nginx -t` leaves
+/// it alone. Every worker installs them; the first brings the history up to
+/// date and the others find nothing to change. A failure is logged and the
+/// worker serves without it.
+fn install_history_tables(cycle: &ngx_cycle_t, main_conf: &MainConfig) {
+    for location in &main_conf.locations {
+        // SAFETY: see resolve_named_queries; the configuration cycle's pool
+        // outlives the worker.
+        let (config, core_conf) = unsafe { (&*location.config, &*location.core_conf) };
+        let Some(db_path) = config
+            .db_path
+            .as_ref()
+            .filter(|_| !config.database_per_request())
+        else {
+            continue;
+        };
+        for table in &config.history {
+            if let Err(e) = query::install_history(db_path, table) {
+                ngx_log_error!(
+                    NGX_LOG_ERR,
+                    cycle.log,
+                    "[sqlite-serve] location {}: sqlite_history {}: {}: {}",
+                    core_conf.name.to_str().unwrap_or("<invalid utf-8>"),
+                    table,
+                    db_path,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// The locations whose `sqlite_report` has `every=`, for this worker's
+/// scheduler
+///
+/// A report whose database is chosen per request has no database to run
+/// in, so it is left out with a warning, as is a location whose
+/// configuration does not validate (requests to it fail the same way).
+fn scheduled_reports(cycle: &ngx_cycle_t, main_conf: &MainConfig) -> Vec<ScheduledReport> {
+    let mut reports = Vec::new();
+    for location in &main_conf.locations {
+        // SAFETY: see install_history_tables
+        let (config, core_conf) = unsafe { (&*location.config, &*location.core_conf) };
+        if config.report.as_ref().and_then(|r| r.every()).is_none() {
+            continue;
+        }
+        let name = core_conf.name.to_str().unwrap_or("<invalid utf-8>");
+        let scheduled = if config.database_per_request() {
+            Err("its sqlite_db is chosen per request".to_string())
+        } else {
+            parsing::parse_config(config).map_err(|e| e.to_string())
+        };
+        match scheduled {
+            Ok(config) => reports.push(ScheduledReport {
+                location: name.to_string(),
+                config,
+            }),
+            Err(e) => ngx_log_error!(
+                NGX_LOG_WARN,
+                cycle.log,
+                "[sqlite-serve] location {}: sqlite_report every= is not scheduled: {}",
+                name,
+                e
+            ),
+        }
+    }
+    reports
+}
+
+/// Inspect every registered location and log a single startup report
+///
+/// Returns NGX_ERROR when a location fails a fatal check (e.g. its query reads
+/// tables outside `sqlite_allow_tables`), which aborts configuration loading.
+fn log_configuration_report(cf: &ngx_conf_t, main_conf: &MainConfig) -> Status {
+    if main_conf.locations.is_empty() {
+        return Status::NGX_OK;
+    }
+
+    let global_dir = main_conf.global_templates_dir.as_deref();
+
+    // SAFETY: registered pointers reference pool-allocated location configuration
+    // that outlives the configuration cycle, and merging has completed by now.
+    let reports: Vec<_> = main_conf
+        .locations
+        .iter()
+        .filter(|location| unsafe { !(*location.config).serves_endpoint() })
+        .map(|location| unsafe {
+            let core_conf = &*location.core_conf;
+            let name = core_conf.name.to_str().unwrap_or("<invalid utf-8>");
+            let root = core_conf.root.to_str().unwrap_or("");
+            preflight::inspect_location(name, &*location.config, root, global_dir)
+        })
+        .collect();
+
+    let level = if reports.iter().all(|r| r.is_healthy()) {
+        NGX_LOG_NOTICE
+    } else {
+        NGX_LOG_WARN
+    };
+    for line in preflight::format_report(&reports) {
+        ngx_log_error!(level, cf.log, "[sqlite-serve:preflight] {}", line);
+    }
+
+    for report in reports.iter().filter(|r| r.is_fatal()) {
+        ngx_log_error!(
+            NGX_LOG_EMERG,
+            cf.log,
+            "[sqlite-serve:preflight] location {} rejected: {}",
+            report.location,
+            report.summary()
+        );
+    }
+    if reports.iter().any(|r| r.is_fatal()) {
+        Status::NGX_ERROR
+    } else {
+        Status::NGX_OK
     }
 }
 
@@ -76,10 +539,10 @@ pub static mut ngx_http_howto_module: ngx_module_t = ngx_module_t {
 
     init_master: None,
     init_module: None,
-    init_process: None,
+    init_process: Some(init_process),
     init_thread: None,
     exit_thread: None,
-    exit_process: None,
+    exit_process: Some(exit_process),
     exit_master: None,
 
     spare_hook0: 0,
@@ -92,9 +555,129 @@ pub static mut ngx_http_howto_module: ngx_module_t = ngx_module_t {
     spare_hook7: 0,
 };
 
+/// Set up this worker's state (see `worker`) once nginx has forked it
+unsafe extern "C" fn init_process(cycle: *mut ngx_cycle_t) -> ngx_int_t {
+    let started = guard::catch_panic(|| {
+        let state = worker::init();
+        // SAFETY: nginx passes the cycle the worker runs
+        let cycle = unsafe { &*cycle };
+        if let Some(main_conf) = Module::main_conf(cycle) {
+            install_history_tables(cycle, main_conf);
+            let reports = scheduled_reports(cycle, main_conf);
+            if let Err(e) = state
+                .report_scheduler()
+                .start(reports, report::SCHEDULE_TICK)
+            {
+                ngx_log_error!(NGX_LOG_ERR, cycle.log, "[sqlite-serve] {}", e);
+            }
+        }
+        state
+    });
+    match started {
+        Ok(state) => unsafe {
+            ngx_log_error!(
+                NGX_LOG_NOTICE,
+                (*cycle).log,
+                "[sqlite-serve] worker {} started",
+                state.pid()
+            );
+            Status::NGX_OK.into()
+        },
+        Err(e) => unsafe {
+            ngx_log_error!(
+                NGX_LOG_EMERG,
+                (*cycle).log,
+                "[sqlite-serve] init_process: {}",
+                e
+            );
+            Status::NGX_ERROR.into()
+        },
+    }
+}
+
+/// Tear down this worker's state before the process exits, delivering any
+/// queued mail, writing any pending hit counts and finishing a scheduled
+/// report run first
+unsafe extern "C" fn exit_process(cycle: *mut ngx_cycle_t) {
+    if let Ok(Some(state)) = guard::catch_panic(worker::shutdown) {
+        let _ = guard::catch_panic(|| state.mailer().finish());
+        let _ = guard::catch_panic(|| state.hit_counters().finish());
+        let _ = guard::catch_panic(|| state.report_scheduler().finish());
+        unsafe {
+            ngx_log_error!(
+                NGX_LOG_NOTICE,
+                (*cycle).log,
+                "[sqlite-serve] worker {} exiting after {} request(s) in {}s, {} rejected as spam, {} email(s) sent, {} failed",
+                state.pid(),
+                state.requests(),
+                state.uptime().as_secs(),
+                state.spam_rejections(),
+                state.mailer().sent(),
+                state.mailer().failed()
+            );
+            if let Some(e) = state.mailer().last_error() {
+                ngx_log_error!(
+                    NGX_LOG_WARN,
+                    (*cycle).log,
+                    "[sqlite-serve] last email failure: {}",
+                    e
+                );
+            }
+            let metrics = state.metrics().summary();
+            if !metrics.is_empty() {
+                ngx_log_error!(
+                    NGX_LOG_NOTICE,
+                    (*cycle).log,
+                    "[sqlite-serve] worker {} metrics: {}",
+                    state.pid(),
+                    metrics
+                );
+            }
+            let hits = state.hit_counters();
+            if hits.flushed() > 0 || hits.failed() > 0 {
+                ngx_log_error!(
+                    NGX_LOG_NOTICE,
+                    (*cycle).log,
+                    "[sqlite-serve] worker {} wrote {} hit(s), {} write(s) failed",
+                    state.pid(),
+                    hits.flushed(),
+                    hits.failed()
+                );
+            }
+            if let Some(e) = hits.last_error() {
+                ngx_log_error!(
+                    NGX_LOG_WARN,
+                    (*cycle).log,
+                    "[sqlite-serve] last hit counter failure: {}",
+                    e
+                );
+            }
+            let reports = state.report_scheduler();
+            if reports.runs() > 0 || reports.failed() > 0 {
+                ngx_log_error!(
+                    NGX_LOG_NOTICE,
+                    (*cycle).log,
+                    "[sqlite-serve] worker {} ran {} scheduled report(s), {} failed",
+                    state.pid(),
+                    reports.runs(),
+                    reports.failed()
+                );
+            }
+            if let Some(e) = reports.last_error() {
+                ngx_log_error!(
+                    NGX_LOG_WARN,
+                    (*cycle).log,
+                    "[sqlite-serve] last scheduled report failure: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
 #[unsafe(no_mangle)]
 #[allow(non_upper_case_globals)]
-static mut ngx_http_howto_commands: [ngx_command_t; 6] = [
+static mut ngx_http_howto_commands: [ngx_command_t; 64] = [
     ngx_command_t {
         name: ngx_string!("sqlite_global_templates"),
         type_: (NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
@@ -103,10 +686,40 @@ static mut ngx_http_howto_commands: [ngx_command_t; 6] = [
         offset: 0,
         post: std::ptr::null_mut(),
     },
+    ngx_command_t {
+        name: ngx_string!("sqlite_query_define"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_CONF_TAKE2) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_define_query),
+        conf: 0,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
     ngx_command_t {
         name: ngx_string!("sqlite_db"),
-        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
-        set: Some(ngx_http_howto_commands_set_db_path),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_db_map"),
+        type_: (NGX_HTTP_MAIN_CONF
+            | NGX_HTTP_SRV_CONF
+            | NGX_HTTP_LOC_CONF
+            | NGX_CONF_TAKE2
+            | NGX_CONF_TAKE3) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_attach"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE2)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
@@ -114,7 +727,151 @@ static mut ngx_http_howto_commands: [ngx_command_t; 6] = [
     ngx_command_t {
         name: ngx_string!("sqlite_query"),
         type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
-        set: Some(ngx_http_howto_commands_set_query),
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_query_if"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE2) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_sort_param"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE2) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_filter"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE3 | NGX_CONF_TAKE4) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_fallback_query"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_paginate"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_count_query"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_count"),
+        type_: (NGX_HTTP_MAIN_CONF
+            | NGX_HTTP_SRV_CONF
+            | NGX_HTTP_LOC_CONF
+            | NGX_CONF_TAKE1
+            | NGX_CONF_TAKE2) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_source"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE3) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_nav"),
+        type_: (NGX_HTTP_MAIN_CONF
+            | NGX_HTTP_SRV_CONF
+            | NGX_HTTP_LOC_CONF
+            | NGX_CONF_TAKE3
+            | NGX_CONF_TAKE4) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_write_query"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_methods"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_batch"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_honeypot"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_captcha"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_notify_email"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_rest"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1 | NGX_CONF_TAKE2) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_upsert"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_2MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
@@ -122,15 +879,355 @@ static mut ngx_http_howto_commands: [ngx_command_t; 6] = [
     ngx_command_t {
         name: ngx_string!("sqlite_template"),
         type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
-        set: Some(ngx_http_howto_commands_set_template_path),
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_form_template"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_template_variant"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_2MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_feed"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
     },
     ngx_command_t {
         name: ngx_string!("sqlite_param"),
-        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1 | NGX_CONF_TAKE2) as ngx_uint_t,
-        set: Some(ngx_http_howto_commands_add_param),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_param_required"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_2MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_param_max_length"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_bind_args"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_allow_tables"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_1MORE)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_allow_fields"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_1MORE)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_hide_columns"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_1MORE)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_max_template_rows"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_mutation_max_rows"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_aggregate"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE2)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_expand_json"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_json_nulls"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_blob_encoding"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_expand"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE2 | NGX_CONF_TAKE3) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_try_static"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_1MORE)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_timing"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_trace"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_decode_args"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_intercept_errors"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_dev_mode"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_dry_run"),
+        type_: (NGX_HTTP_MAIN_CONF
+            | NGX_HTTP_SRV_CONF
+            | NGX_HTTP_LOC_CONF
+            | NGX_CONF_TAKE1
+            | NGX_CONF_TAKE2) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_stream_buffer"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_stream_overflow"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_breadcrumbs"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_1MORE)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_hit_counter"),
+        type_: (NGX_HTTP_MAIN_CONF
+            | NGX_HTTP_SRV_CONF
+            | NGX_HTTP_LOC_CONF
+            | NGX_CONF_TAKE2
+            | NGX_CONF_TAKE3) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_trending"),
+        type_: (NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_1MORE)
+            as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_memo_table"),
+        type_: (NGX_HTTP_MAIN_CONF
+            | NGX_HTTP_SRV_CONF
+            | NGX_HTTP_LOC_CONF
+            | NGX_CONF_TAKE1
+            | NGX_CONF_TAKE2) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_theme"),
+        type_: (NGX_HTTP_MAIN_CONF
+            | NGX_HTTP_SRV_CONF
+            | NGX_HTTP_LOC_CONF
+            | NGX_CONF_TAKE2
+            | NGX_CONF_TAKE3) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_export"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_report"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE2 | NGX_CONF_TAKE3 | NGX_CONF_TAKE4) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_soft_delete"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_history"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_blob"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_typescript"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_health"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("sqlite_capabilities"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_howto_commands_set_location),
         conf: NGX_HTTP_LOC_CONF_OFFSET,
         offset: 0,
         post: std::ptr::null_mut(),
@@ -148,110 +1245,268 @@ static mut ngx_http_howto_commands: [ngx_command_t; 6] = [
     },
 ];
 
-/// Directive handler for sqlite_global_templates
-#[unsafe(no_mangle)]
-extern "C" fn ngx_http_howto_commands_set_global_templates(
-    cf: *mut ngx_conf_t,
-    _cmd: *mut ngx_command_t,
-    conf: *mut c_void,
-) -> *mut c_char {
+/// Log why a directive's arguments were rejected and fail configuration loading
+///
+/// nginx reports the returned message together with the file and line.
+unsafe fn invalid_directive(cf: *mut ngx_conf_t, directive: &str, error: &str) -> *mut c_char {
     unsafe {
-        let conf = &mut *(conf as *mut MainConfig);
-        let args = (*(*cf).args).elts as *mut ngx_str_t;
-        conf.global_templates_dir = (*args.add(1)).to_string();
-    };
-
-    std::ptr::null_mut()
+        ngx_log_error!(
+            NGX_LOG_EMERG,
+            (*cf).log,
+            "[sqlite-serve] \"{}\" directive: {}",
+            directive,
+            error
+        );
+    }
+    c"has an invalid value".as_ptr() as *mut c_char
 }
 
-/// Directive handler for sqlite_db
-#[unsafe(no_mangle)]
-extern "C" fn ngx_http_howto_commands_set_db_path(
+/// Run a directive handler's body, rejecting the directive if it panics
+fn guarded_directive(
     cf: *mut ngx_conf_t,
-    _cmd: *mut ngx_command_t,
-    conf: *mut c_void,
+    directive: &str,
+    body: impl FnOnce() -> *mut c_char,
 ) -> *mut c_char {
-    unsafe {
-        let conf = &mut *(conf as *mut ModuleConfig);
-        let args = (*(*cf).args).elts as *mut ngx_str_t;
-        conf.db_path = (*args.add(1)).to_string();
-    };
-
-    std::ptr::null_mut()
+    guard::catch_panic(body).unwrap_or_else(|e| unsafe { invalid_directive(cf, directive, &e) })
 }
 
-/// Directive handler for sqlite_query
+/// Directive handler for sqlite_global_templates
 #[unsafe(no_mangle)]
-extern "C" fn ngx_http_howto_commands_set_query(
+extern "C" fn ngx_http_howto_commands_set_global_templates(
     cf: *mut ngx_conf_t,
     _cmd: *mut ngx_command_t,
     conf: *mut c_void,
 ) -> *mut c_char {
-    unsafe {
-        let conf = &mut *(conf as *mut ModuleConfig);
-        let args = (*(*cf).args).elts as *mut ngx_str_t;
-        conf.query = (*args.add(1)).to_string();
-    };
+    guarded_directive(cf, "sqlite_global_templates", || {
+        unsafe {
+            let conf = &mut *(conf as *mut MainConfig);
+            let args = (*(*cf).args).elts as *mut ngx_str_t;
+            conf.global_templates_dir = Some((*args.add(1)).to_string());
+        };
 
-    std::ptr::null_mut()
+        std::ptr::null_mut()
+    })
 }
 
-/// Directive handler for sqlite_template
+/// Directive handler for sqlite_query_define
 #[unsafe(no_mangle)]
-extern "C" fn ngx_http_howto_commands_set_template_path(
+extern "C" fn ngx_http_howto_commands_define_query(
     cf: *mut ngx_conf_t,
     _cmd: *mut ngx_command_t,
     conf: *mut c_void,
 ) -> *mut c_char {
-    unsafe {
-        let conf = &mut *(conf as *mut ModuleConfig);
-        let args = (*(*cf).args).elts as *mut ngx_str_t;
-        conf.template_path = (*args.add(1)).to_string();
+    guarded_directive(cf, "sqlite_query_define", || {
+        unsafe {
+            let conf = &mut *(conf as *mut MainConfig);
+            let args = (*(*cf).args).elts as *mut ngx_str_t;
 
-        // Set the content handler for this location
-        let clcf =
-            NgxHttpCoreModule::location_conf_mut(&*cf).expect("failed to get core location conf");
-        clcf.handler = Some(howto_access_handler);
-    };
+            // sqlite_query_define books_by_genre "SELECT ..."
+            let name = (*args.add(1)).to_string();
+            let sql = (*args.add(2)).to_string();
+            conf.query_definitions.push((name, sql));
+        };
 
-    std::ptr::null_mut()
+        std::ptr::null_mut()
+    })
 }
 
-/// Directive handler for sqlite_param
+/// Directive handler for every location directive: nginx has checked where
+/// it is written and how many arguments it has, and `directives::set`
+/// records them
 #[unsafe(no_mangle)]
-extern "C" fn ngx_http_howto_commands_add_param(
+extern "C" fn ngx_http_howto_commands_set_location(
     cf: *mut ngx_conf_t,
-    _cmd: *mut ngx_command_t,
+    cmd: *mut ngx_command_t,
     conf: *mut c_void,
 ) -> *mut c_char {
+    let name = unsafe { (*cmd).name.to_string() };
+    guarded_directive(cf, &name, || {
+        unsafe {
+            let conf = &mut *(conf as *mut ModuleConfig);
+            let args = (*(*cf).args).elts as *mut ngx_str_t;
+            let nelts = (*(*cf).args).nelts;
+
+            let values: Vec<String> = (1..nelts).map(|i| (*args.add(i)).to_string()).collect();
+            if let Err(e) = directives::set(conf, &name, &values) {
+                return invalid_directive(cf, &name, &e);
+            }
+            if directives::serves(&name)
+                && let Err(e) = install_handler(cf, conf)
+            {
+                return invalid_directive(cf, &name, &e);
+            }
+        };
+
+        std::ptr::null_mut()
+    })
+}
+
+/// Set the content handler for the current location and register it for the
+/// startup configuration report (and later named query resolution)
+unsafe fn install_handler(cf: *mut ngx_conf_t, conf: &mut ModuleConfig) -> Result<(), String> {
     unsafe {
-        let conf = &mut *(conf as *mut ModuleConfig);
-        let args = (*(*cf).args).elts as *mut ngx_str_t;
-        let nelts = (*(*cf).args).nelts;
-
-        if nelts == 2 {
-            // Single argument: positional parameter
-            // sqlite_param $arg_id
-            let variable = (*args.add(1)).to_string();
-            conf.query_params.push((String::new(), variable));
-        } else if nelts == 3 {
-            // Two arguments: named parameter
-            // sqlite_param :book_id $arg_id
-            let param_name = (*args.add(1)).to_string();
-            let variable = (*args.add(2)).to_string();
-            conf.query_params.push((param_name, variable));
-        }
-    };
+        let clcf = NgxHttpCoreModule::location_conf_mut(&*cf)
+            .ok_or_else(|| "failed to get core location conf".to_string())?;
+        clcf.handler = Some(howto_access_handler);
 
-    std::ptr::null_mut()
+        let location = RegisteredLocation {
+            config: conf as *mut ModuleConfig,
+            core_conf: clcf as *const _,
+        };
+        if let Some(main_conf) = Module::main_conf_mut(&*cf)
+            && !main_conf
+                .locations
+                .iter()
+                .any(|l| l.config == location.config)
+        {
+            main_conf.locations.push(location);
+        }
+        Ok(())
+    }
 }
 
 // HTTP request handler - correctness guaranteed by types (Ghost of Departed Proofs)
 http_request_handler!(howto_access_handler, |request: &mut http::Request| {
-    // Type-safe gate: only proceed if we have proof of valid config
-    // ValidConfigToken::new handles extraction of all needed data from request
-    match ValidConfigToken::new(request) {
-        Some(valid_config) => process_request(request, valid_config.get()),
-        None => Status::NGX_OK, // Not configured - skip silently
-    }
+    // A panic must not unwind into nginx; it becomes a logged 500 instead
+    let handled = guard::catch_panic(|| {
+        // Type-safe gate: only proceed if we have proof of valid config
+        // ValidConfigToken::new handles extraction of all needed data from request
+        if Module::location_conf(request).is_some_and(|config| config.typescript) {
+            return serve_typescript(request);
+        }
+        if Module::location_conf(request).is_some_and(|config| config.health) {
+            return serve_health(request);
+        }
+        if let Some(gate) =
+            Module::location_conf(request).and_then(|config| config.capabilities.clone())
+        {
+            return serve_capabilities(request, &gate);
+        }
+        if let Some(route) = Module::location_conf(request).and_then(|config| config.blob.clone()) {
+            return serve_blob(request, &route);
+        }
+        match ValidConfigToken::new(request) {
+            Ok(Some(valid_config)) => {
+                worker::with_state(|state| state.record_request());
+                process_request(request, valid_config.get(), valid_config.paths())
+            }
+            Ok(None) => Status::NGX_OK, // Not configured - skip silently
+            Err(status) => status,
+        }
+    });
+    handled.unwrap_or_else(|e| {
+        NginxLogger::new(request).error("handler", &format!("Request failed: {}", e));
+        http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+    })
 });
+
+/// Body handler for requests with parameters bound from the body, called by
+/// nginx once the whole body has been read; it finishes the request the main
+/// handler left open
+extern "C" fn body_handler(r: *mut ngx_http_request_t) {
+    let request = unsafe { http::Request::from_ngx_http_request(r) };
+    let handled = guard::catch_panic(|| match ValidConfigToken::new(request) {
+        Ok(Some(valid_config)) => {
+            process_request(request, valid_config.get(), valid_config.paths())
+        }
+        Ok(None) => http::HTTPStatus::INTERNAL_SERVER_ERROR.into(),
+        Err(status) => status,
+    });
+    let status = handled.unwrap_or_else(|e| {
+        NginxLogger::new(request).error("handler", &format!("Request failed: {}", e));
+        http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+    });
+    unsafe { ngx_http_finalize_request(r, status.0) };
+}
+
+/// Body handler for `sqlite_batch` requests, called by nginx once the whole
+/// body has been read; it finishes the request the main handler left open
+extern "C" fn batch_body_handler(r: *mut ngx_http_request_t) {
+    let request = unsafe { http::Request::from_ngx_http_request(r) };
+    let handled = guard::catch_panic(|| match ValidConfigToken::new(request) {
+        Ok(Some(valid_config)) => process_batch(request, valid_config.get()),
+        Ok(None) => http::HTTPStatus::INTERNAL_SERVER_ERROR.into(),
+        Err(status) => status,
+    });
+    let status = handled.unwrap_or_else(|e| {
+        NginxLogger::new(request).error("handler", &format!("Batch failed: {}", e));
+        http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+    });
+    unsafe { ngx_http_finalize_request(r, status.0) };
+}
+
+/// Post-subrequest handler for `sqlite_captcha`: records the provider's
+/// verdict in the request context and has nginx resume the write
+extern "C" fn captcha_verified(
+    r: *mut ngx_http_request_t,
+    data: *mut c_void,
+    rc: ngx_int_t,
+) -> ngx_int_t {
+    let _ = guard::catch_panic(|| unsafe {
+        let (status, body) = nginx_helpers::subrequest_response(r);
+        let passed = rc == Status::NGX_OK.0 && status == 200 && domain::captcha_passed(&body);
+        *(data as *mut CaptchaCheck) = CaptchaCheck::Verified(passed);
+    });
+    // Resume even after a panic: the check is still pending, which rejects
+    unsafe { nginx_helpers::resume_parent(r, captcha_resume_handler) };
+    rc
+}
+
+/// Post-subrequest handler for each page of a `sqlite_export` run: saves the
+/// page into the export directory and resumes the export request
+extern "C" fn export_page_done(
+    r: *mut ngx_http_request_t,
+    data: *mut c_void,
+    rc: ngx_int_t,
+) -> ngx_int_t {
+    let _ = guard::catch_panic(|| unsafe {
+        let (status, body) = nginx_helpers::subrequest_response(r);
+        let status = if rc == Status::NGX_OK.0 { status } else { 0 };
+        (*(data as *mut ExportRun)).page_done(status, &body);
+    });
+    unsafe { nginx_helpers::resume_parent(r, export_resume_handler) };
+    rc
+}
+
+/// Render the export's next page, or answer once all of them are done
+extern "C" fn export_resume_handler(r: *mut ngx_http_request_t) {
+    let request = unsafe { http::Request::from_ngx_http_request(r) };
+    let status = guard::catch_panic(|| continue_export(request)).unwrap_or_else(|e| {
+        NginxLogger::new(request).error("handler", &format!("Request failed: {}", e));
+        http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+    });
+    unsafe { ngx_http_finalize_request(r, status.0) };
+}
+
+/// Write event handler for a streamed response waiting on its client: sends
+/// what now fits, and finishes the request the main handler left open once
+/// the stream ends
+extern "C" fn stream_write_handler(r: *mut ngx_http_request_t) {
+    let request = unsafe { http::Request::from_ngx_http_request(r) };
+    let status = guard::catch_panic(|| nginx_helpers::resume_stream(request)).unwrap_or_else(|e| {
+        NginxLogger::new(request).error("stream", &format!("Stream failed: {}", e));
+        // The headers are out, so the connection can only be closed
+        Some(Status::NGX_ERROR)
+    });
+    if let Some(status) = status {
+        unsafe { ngx_http_finalize_request(r, status.0) };
+    }
+}
+
+/// Handle a write again once its captcha verdict is in; like
+/// `batch_body_handler`, it finishes the request the main handler left open
+extern "C" fn captcha_resume_handler(r: *mut ngx_http_request_t) {
+    let request = unsafe { http::Request::from_ngx_http_request(r) };
+    let handled = guard::catch_panic(|| match ValidConfigToken::new(request) {
+        Ok(Some(valid_config)) => {
+            process_request(request, valid_config.get(), valid_config.paths())
+        }
+        Ok(None) => http::HTTPStatus::INTERNAL_SERVER_ERROR.into(),
+        Err(status) => status,
+    });
+    let status = handled.unwrap_or_else(|e| {
+        NginxLogger::new(request).error("handler", &format!("Request failed: {}", e));
+        http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+    });
+    unsafe { ngx_http_finalize_request(r, status.0) };
+}
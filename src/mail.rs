@@ -0,0 +1,218 @@
+//! Outgoing mail for `sqlite_notify_email`
+//!
+//! Messages are piped to a sendmail-compatible program (`-t` takes the
+//! recipients from the headers) on a background thread, so a slow mail server
+//! never holds up the event loop. The thread starts with a worker's first
+//! message and is drained when the worker exits. nginx's log is not safe to
+//! use from another thread, so delivery results are counted instead and
+//! reported at exit.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// A message waiting for the delivery thread
+#[derive(Debug)]
+struct Outgoing {
+    sendmail: String,
+    message: String,
+}
+
+#[derive(Debug)]
+enum Queue {
+    Idle,
+    Running(Sender<Outgoing>, JoinHandle<()>),
+    Closed,
+}
+
+/// Delivery counters shared with the background thread
+#[derive(Debug, Default)]
+struct Delivery {
+    sent: AtomicU64,
+    failed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// One worker's queue of outgoing mail
+#[derive(Debug)]
+pub struct Mailer {
+    queue: Mutex<Queue>,
+    delivery: Arc<Delivery>,
+}
+
+impl Mailer {
+    pub fn new() -> Self {
+        Mailer {
+            queue: Mutex::new(Queue::Idle),
+            delivery: Arc::new(Delivery::default()),
+        }
+    }
+
+    /// Queue a message for `sendmail`, starting the delivery thread if needed
+    pub fn send(&self, sendmail: &str, message: String) -> Result<(), String> {
+        let mut queue = self
+            .queue
+            .lock()
+            .map_err(|_| "mail queue is poisoned".to_string())?;
+        if matches!(*queue, Queue::Idle) {
+            let (sender, receiver) = mpsc::channel::<Outgoing>();
+            let delivery = Arc::clone(&self.delivery);
+            let thread = thread::Builder::new()
+                .name("sqlite-serve-mail".to_string())
+                .spawn(move || {
+                    for outgoing in receiver {
+                        match deliver(&outgoing.sendmail, &outgoing.message) {
+                            Ok(()) => delivery.sent.fetch_add(1, Ordering::Relaxed),
+                            Err(e) => {
+                                if let Ok(mut last) = delivery.last_error.lock() {
+                                    *last = Some(e);
+                                }
+                                delivery.failed.fetch_add(1, Ordering::Relaxed)
+                            }
+                        };
+                    }
+                })
+                .map_err(|e| format!("cannot start the mail thread: {}", e))?;
+            *queue = Queue::Running(sender, thread);
+        }
+
+        match &*queue {
+            Queue::Running(sender, _) => sender
+                .send(Outgoing {
+                    sendmail: sendmail.to_string(),
+                    message,
+                })
+                .map_err(|_| "the mail thread has stopped".to_string()),
+            _ => Err("the mail queue is closed".to_string()),
+        }
+    }
+
+    /// Deliver everything still queued and stop the thread; later messages
+    /// are refused
+    pub fn finish(&self) {
+        let Ok(mut queue) = self.queue.lock() else {
+            return;
+        };
+        if let Queue::Running(sender, thread) = std::mem::replace(&mut *queue, Queue::Closed) {
+            // Closing the channel ends the thread's loop once it is empty
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+
+    /// Messages the sendmail program accepted
+    pub fn sent(&self) -> u64 {
+        self.delivery.sent.load(Ordering::Relaxed)
+    }
+
+    /// Messages that could not be handed over
+    pub fn failed(&self) -> u64 {
+        self.delivery.failed.load(Ordering::Relaxed)
+    }
+
+    /// Why the most recent failed message failed
+    pub fn last_error(&self) -> Option<String> {
+        self.delivery
+            .last_error
+            .lock()
+            .ok()
+            .and_then(|last| last.clone())
+    }
+}
+
+impl Default for Mailer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pipe one message to `sendmail -t -i`
+fn deliver(sendmail: &str, message: &str) -> Result<(), String> {
+    let mut child = Command::new(sendmail)
+        .args(["-t", "-i"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("cannot run {}: {}", sendmail, e))?;
+
+    // Taking stdin closes it once written, which ends the message
+    let written = child
+        .stdin
+        .take()
+        .map(|mut stdin| stdin.write_all(message.as_bytes()));
+
+    match child.wait() {
+        Ok(status) if !status.success() => {
+            return Err(format!("{} exited with {}", sendmail, status));
+        }
+        Ok(_) => {}
+        // nginx reaps children in its SIGCHLD handler, which can leave no
+        // status to collect; the write below is all there is to go on
+        Err(e) if e.raw_os_error() == Some(10) => {}
+        Err(e) => return Err(format!("{} did not finish: {}", sendmail, e)),
+    }
+    match written {
+        Some(Ok(())) => Ok(()),
+        Some(Err(e)) => Err(format!("cannot write to {}: {}", sendmail, e)),
+        None => Err(format!("{} has no input", sendmail)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_mailer_delivers_in_background() {
+        let script = "/tmp/test_sqlite_serve_sendmail.sh";
+        let output = "/tmp/test_sqlite_serve_sendmail.out";
+        let _ = fs::remove_file(output);
+        fs::write(script, format!("#!/bin/sh\ncat >> {}\n", output)).unwrap();
+        fs::set_permissions(script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mailer = Mailer::new();
+        mailer
+            .send(script, "Subject: one\n\nfirst\n".to_string())
+            .unwrap();
+        mailer
+            .send(script, "Subject: two\n\nsecond\n".to_string())
+            .unwrap();
+        mailer
+            .send("/nonexistent/sendmail", "Subject: lost\n\n".to_string())
+            .unwrap();
+        mailer.finish();
+
+        assert_eq!(
+            fs::read_to_string(output).unwrap(),
+            "Subject: one\n\nfirst\nSubject: two\n\nsecond\n"
+        );
+        assert_eq!((mailer.sent(), mailer.failed()), (2, 1));
+        assert!(
+            mailer
+                .last_error()
+                .unwrap()
+                .contains("/nonexistent/sendmail")
+        );
+
+        // A finished mailer takes no more mail
+        assert!(
+            mailer
+                .send(script, "Subject: late\n\n".to_string())
+                .is_err()
+        );
+
+        let _ = fs::remove_file(script);
+        let _ = fs::remove_file(output);
+    }
+
+    #[test]
+    fn test_deliver_reports_exit_status() {
+        assert!(deliver("/bin/false", "Subject: x\n\n").is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Per-worker counters and timings, the [`Metrics`] requests report to
+//!
+//! Each worker keeps its own store on its [`WorkerState`](crate::worker::WorkerState)
+//! and logs a summary when it exits. Tests give a processor a store of their
+//! own and read back what it recorded.
+
+use crate::domain::Metrics;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often something was timed, and how long it took
+#[derive(Debug, Clone, Copy, Default)]
+struct Timing {
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+/// Counters and timings by name
+#[derive(Debug, Default)]
+pub struct MetricsStore {
+    counters: Mutex<BTreeMap<String, u64>>,
+    timings: Mutex<BTreeMap<String, Timing>>,
+}
+
+impl MetricsStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The counter `name`, 0 if nothing was counted
+    pub fn counter(&self, name: &str) -> u64 {
+        self.counters
+            .lock()
+            .ok()
+            .and_then(|counters| counters.get(name).copied())
+            .unwrap_or(0)
+    }
+
+    /// Every counter and timing on one line, by name, e.g.
+    /// `requests=12 rows=340 query=12/48.20ms (max 9.10ms)`
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<(String, String)> = Vec::new();
+        if let Ok(counters) = self.counters.lock() {
+            parts.extend(
+                counters
+                    .iter()
+                    .map(|(name, count)| (name.clone(), count.to_string())),
+            );
+        }
+        if let Ok(timings) = self.timings.lock() {
+            parts.extend(timings.iter().map(|(name, timing)| {
+                let value = format!(
+                    "{}/{:.2}ms (max {:.2}ms)",
+                    timing.count,
+                    timing.total.as_secs_f64() * 1000.0,
+                    timing.max.as_secs_f64() * 1000.0
+                );
+                (name.clone(), value)
+            }));
+        }
+        parts.sort();
+        parts
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Metrics for MetricsStore {
+    fn count(&self, name: &str, by: u64) {
+        if let Ok(mut counters) = self.counters.lock() {
+            *counters.entry(name.to_string()).or_insert(0) += by;
+        }
+    }
+
+    fn time(&self, name: &str, duration: Duration) {
+        if let Ok(mut timings) = self.timings.lock() {
+            let timing = timings.entry(name.to_string()).or_default();
+            timing.count += 1;
+            timing.total += duration;
+            timing.max = timing.max.max(duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_store() {
+        let metrics = MetricsStore::new();
+        assert_eq!(metrics.counter("rows"), 0);
+        assert_eq!(metrics.summary(), "");
+
+        metrics.count("rows", 20);
+        metrics.count("rows", 5);
+        metrics.time("query", Duration::from_millis(3));
+        metrics.time("query", Duration::from_millis(1));
+        assert_eq!(metrics.counter("rows"), 25);
+        assert_eq!(metrics.summary(), "query=2/4.00ms (max 3.00ms) rows=25");
+    }
+}
@@ -1,10 +1,69 @@
 //! NGINX-specific helper functions
 
+use crate::adapters::NginxLogger;
 use crate::content_type::ContentType;
-use ngx::core::Buffer;
-use ngx::ffi::ngx_chain_t;
-use ngx::http::{HttpModuleLocationConf, NgxHttpCoreModule, Request};
+use crate::domain::{self, Logger};
+use crate::formatter::{FormattedResponse, StreamedResponse};
+use crate::handler_types::StageRequest;
+use crate::stream::{ResponseStream, StreamEnd, StreamStep};
+use crate::types::StreamBuffer;
+use crate::{Module, variable, worker};
+use ngx::core::{Buffer, Pool};
+use ngx::ffi::{
+    NGX_HTTP_SPECIAL_RESPONSE, NGX_HTTP_SUBREQUEST_IN_MEMORY, NGX_HTTP_SUBREQUEST_WAITED,
+    ngx_add_timer, ngx_buf_t, ngx_buf_tag_t, ngx_chain_get_free_buf, ngx_chain_t,
+    ngx_chain_update_chains, ngx_del_timer, ngx_handle_write_event, ngx_http_output_filter,
+    ngx_http_post_subrequest_t, ngx_http_read_client_request_body, ngx_http_request_t,
+    ngx_http_subrequest, ngx_int_t, ngx_palloc, ngx_pfree, ngx_pool_t, ngx_str_t, ngx_uint_t,
+};
+use ngx::http::{
+    HttpModule, HttpModuleLocationConf, HttpModuleMainConf, NgxHttpCoreModule, Request,
+};
 use ngx::{core::Status, http};
+use std::cell::RefCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::os::raw::c_void;
+use std::ptr::NonNull;
+
+/// Why a response could not be produced; each is logged and answered with a
+/// 500 rather than aborting the worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseError {
+    /// The module's http-level configuration is unavailable
+    MissingMainConfig,
+    /// The request pool could not allocate the response body
+    BufferAllocation,
+    /// The request pool could not allocate a response header
+    HeaderAllocation(&'static str),
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::MissingMainConfig => write!(f, "main configuration is unavailable"),
+            ResponseError::BufferAllocation => write!(f, "failed to allocate response buffer"),
+            ResponseError::HeaderAllocation(name) => {
+                write!(f, "failed to allocate {} header", name)
+            }
+        }
+    }
+}
+
+/// Log a response failure and answer 500
+pub fn internal_error(request: &mut dyn StageRequest, error: ResponseError) -> Status {
+    request.logger().error("response", &error.to_string());
+    request.record_error("internal", &error.to_string());
+    http::HTTPStatus::INTERNAL_SERVER_ERROR.into()
+}
+
+/// Set `$sqlite_error_code` and `$sqlite_error_message` to why a request
+/// failed, for `error_page` locations and access logs
+pub fn record_error(request: &mut Request, code: &str, message: &str) {
+    if let Some(indexes) = Module::main_conf(request).and_then(|conf| conf.error_variables) {
+        variable::set_error_variables(request, indexes, code, message);
+    }
+}
 
 /// Get document root and URI from request
 pub fn get_doc_root_and_uri(request: &mut Request) -> Result<(String, String), String> {
@@ -26,48 +85,895 @@ pub fn get_doc_root_and_uri(request: &mut Request) -> Result<(String, String), S
     Ok((doc_root, uri))
 }
 
+/// Start reading the request body, calling `on_body` once all of it has arrived
+///
+/// The handler must return the status this returns; `on_body` then finishes
+/// the request with `ngx_http_finalize_request`.
+pub fn read_request_body(
+    request: &mut Request,
+    on_body: unsafe extern "C" fn(*mut ngx_http_request_t),
+) -> Status {
+    let r: *mut ngx_http_request_t = request.into();
+    let rc = unsafe {
+        (*r).set_request_body_in_single_buf(1);
+        ngx_http_read_client_request_body(r, Some(on_body))
+    };
+    if rc >= NGX_HTTP_SPECIAL_RESPONSE as ngx_int_t {
+        return Status(rc);
+    }
+    Status::NGX_DONE
+}
+
+/// Whether [`read_request_body`] has already read this request's body
+pub fn request_body_read(request: &mut Request) -> bool {
+    let r: *mut ngx_http_request_t = request.into();
+    unsafe { !(*r).request_body.is_null() }
+}
+
+/// The body read by [`read_request_body`]
+///
+/// Only bodies held in memory are supported: one larger than
+/// `client_body_buffer_size` is written to a temporary file and rejected.
+pub fn request_body(request: &mut Request) -> Result<Vec<u8>, String> {
+    let r: *mut ngx_http_request_t = request.into();
+    unsafe {
+        if (*r).request_body.is_null() {
+            return Ok(Vec::new());
+        }
+        chain_bytes((*(*r).request_body).bufs)
+            .ok_or_else(|| "request body is larger than client_body_buffer_size".to_string())
+    }
+}
+
+/// Copy a chain of in-memory buffers; `None` if any buffer is in a file
+unsafe fn chain_bytes(mut chain: *mut ngx_chain_t) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    unsafe {
+        while !chain.is_null() {
+            let buf = (*chain).buf;
+            if (*buf).in_file() != 0 {
+                return None;
+            }
+            // Special buffers (flags alone) have no memory to copy
+            if !(*buf).pos.is_null() {
+                let len = (*buf).last.offset_from((*buf).pos) as usize;
+                bytes.extend_from_slice(std::slice::from_raw_parts((*buf).pos, len));
+            }
+            chain = (*chain).next;
+        }
+    }
+    Some(bytes)
+}
+
+/// Post-subrequest handler: receives the finished subrequest, the `data`
+/// given to [`start_subrequest`] and the subrequest's result code
+pub type SubrequestDone =
+    unsafe extern "C" fn(*mut ngx_http_request_t, *mut c_void, ngx_int_t) -> ngx_int_t;
+
+/// Start a subrequest to `uri?args` (just `uri` when `args` is empty) whose
+/// response is kept in memory rather than sent to the client; nginx calls
+/// `on_done` with `data` when it finishes
+///
+/// The handler must return the status this returns; `on_done` then resumes
+/// the request with [`resume_parent`].
+pub fn start_subrequest(
+    request: &mut Request,
+    uri: &str,
+    args: &str,
+    on_done: SubrequestDone,
+    data: *mut c_void,
+) -> Status {
+    let r: *mut ngx_http_request_t = request.into();
+    let mut pool = request.pool();
+    unsafe {
+        let uri = pool.allocate(ngx_str_t::from_str((*r).pool, uri));
+        let args = if args.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            pool.allocate(ngx_str_t::from_str((*r).pool, args))
+        };
+        let post = pool.allocate(ngx_http_post_subrequest_t {
+            handler: Some(on_done),
+            data,
+        });
+        if uri.is_null() || post.is_null() || (*uri).data.is_null() {
+            return http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+        }
+        if !args.is_null() && (*args).data.is_null() {
+            return http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+        }
+
+        let mut subrequest: *mut ngx_http_request_t = std::ptr::null_mut();
+        let flags = NGX_HTTP_SUBREQUEST_IN_MEMORY | NGX_HTTP_SUBREQUEST_WAITED;
+        let rc = ngx_http_subrequest(r, uri, args, &mut subrequest, post, flags as ngx_uint_t);
+        if rc != Status::NGX_OK.0 {
+            return http::HTTPStatus::INTERNAL_SERVER_ERROR.into();
+        }
+    }
+    Status::NGX_DONE
+}
+
+/// The status and body of a finished in-memory subrequest
+///
+/// # Safety
+/// `subrequest` must be the request passed to a [`SubrequestDone`] handler.
+pub unsafe fn subrequest_response(subrequest: *mut ngx_http_request_t) -> (ngx_uint_t, Vec<u8>) {
+    unsafe {
+        let status = (*subrequest).headers_out.status;
+        (status, chain_bytes((*subrequest).out).unwrap_or_default())
+    }
+}
+
+/// Have nginx call `handler` on the subrequest's parent once the subrequest
+/// is done; `handler` finishes the parent with `ngx_http_finalize_request`
+///
+/// # Safety
+/// `subrequest` must be the request passed to a [`SubrequestDone`] handler.
+pub unsafe fn resume_parent(
+    subrequest: *mut ngx_http_request_t,
+    handler: unsafe extern "C" fn(*mut ngx_http_request_t),
+) {
+    unsafe { (*(*subrequest).parent).write_event_handler = Some(handler) };
+}
+
 /// Send HTML response
-pub fn send_response(request: &mut Request, body: &str) -> Status {
-    send_response_with_content_type(request, body, &ContentType::Html)
+pub fn send_response(request: &mut dyn StageRequest, body: &str) -> Status {
+    send_response_with_status(request, body, http::HTTPStatus::OK)
+}
+
+/// Send HTML response with a status other than 200
+pub fn send_response_with_status(
+    request: &mut dyn StageRequest,
+    body: &str,
+    status: http::HTTPStatus,
+) -> Status {
+    send_response_with_content_type(request, body, &ContentType::Html, status)
 }
 
 /// Send JSON response
-pub fn send_json_response(request: &mut Request, body: &str) -> Status {
-    send_response_with_content_type(request, body, &ContentType::Json)
+pub fn send_json_response(request: &mut dyn StageRequest, body: &str) -> Status {
+    send_json_response_with_status(request, body, http::HTTPStatus::OK)
 }
 
-/// Create and send nginx response buffer with specified content type
-fn send_response_with_content_type(
+/// Send JSON response with a status other than 200
+pub fn send_json_response_with_status(
+    request: &mut dyn StageRequest,
+    body: &str,
+    status: http::HTTPStatus,
+) -> Status {
+    send_response_with_content_type(request, body, &ContentType::Json, status)
+}
+
+/// Send a response a [`ResponseFormatter`](crate::formatter::ResponseFormatter) made
+pub fn send_formatted_response(
+    request: &mut dyn StageRequest,
+    response: &FormattedResponse,
+) -> Status {
+    let headers: Vec<(&'static str, &str)> = response
+        .headers
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect();
+    request.send(http::HTTPStatus::OK, &headers, &response.body)
+}
+
+/// Send the `sqlite_typescript` declarations, as `sqlite-serve.d.ts` for
+/// browsers that save them
+pub fn send_typescript_response(request: &mut Request, body: &str) -> Status {
+    try_send_response(
+        request,
+        body.as_bytes(),
+        &[
+            ("Content-Type", "application/typescript; charset=utf-8"),
+            (
+                "Content-Disposition",
+                "inline; filename=\"sqlite-serve.d.ts\"",
+            ),
+        ],
+        http::HTTPStatus::OK,
+    )
+    .unwrap_or_else(|e| internal_error(request, e))
+}
+
+/// Send a `sqlite_blob` blob, cacheable for good under its hash
+pub fn send_blob_response(
     request: &mut Request,
+    body: &[u8],
+    content_type: &str,
+    hash: &str,
+) -> Status {
+    let etag = format!("\"{}\"", hash);
+    try_send_response(
+        request,
+        body,
+        &[
+            ("Content-Type", content_type),
+            ("Cache-Control", domain::IMMUTABLE),
+            ("ETag", &etag),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+        http::HTTPStatus::OK,
+    )
+    .unwrap_or_else(|e| internal_error(request, e))
+}
+
+/// Tell a client its copy of the `sqlite_blob` blob under `hash` is current
+pub fn send_not_modified(request: &mut Request, hash: &str) -> Status {
+    let etag = format!("\"{}\"", hash);
+    try_send_response(
+        request,
+        &[],
+        &[("Cache-Control", domain::IMMUTABLE), ("ETag", &etag)],
+        http::HTTPStatus::NOT_MODIFIED,
+    )
+    .unwrap_or_else(|e| internal_error(request, e))
+}
+
+/// Send a response with the specified content type
+fn send_response_with_content_type(
+    request: &mut dyn StageRequest,
     body: &str,
     content_type: &ContentType,
+    status: http::HTTPStatus,
 ) -> Status {
-    // Create output buffer
-    let mut buf = match request.pool().create_buffer_from_str(body) {
-        Some(buf) => buf,
-        None => return http::HTTPStatus::INTERNAL_SERVER_ERROR.into(),
-    };
+    request.send(
+        status,
+        &[("Content-Type", content_type.content_type_header())],
+        body.as_bytes(),
+    )
+}
 
-    buf.set_last_buf(request.is_main());
-    buf.set_last_in_chain(true);
+/// Where an output chain's buffers and links are allocated: the request's
+/// pool, or a fake one in tests
+///
+/// # Safety
+///
+/// Returned pointers must be valid, writable and unaliased for as long as the
+/// allocator is borrowed, and a buffer's `pos..last` must hold a copy of the
+/// bytes it was made from.
+pub unsafe trait ChainAllocator {
+    /// A buffer in memory holding a copy of `bytes`, with no other flags set
+    fn buffer(&mut self, bytes: &[u8]) -> Option<NonNull<ngx_buf_t>>;
+    /// A buffer with no data and no memory, as `ngx_calloc_buf` makes, for
+    /// flags alone
+    fn special(&mut self) -> Option<NonNull<ngx_buf_t>>;
+    /// A chain link, which the builder initialises
+    fn link(&mut self) -> Option<NonNull<ngx_chain_t>>;
+}
 
-    let mut out = ngx_chain_t {
-        buf: buf.as_ngx_buf_mut(),
-        next: std::ptr::null_mut(),
-    };
+// The pool frees everything with the request, after the output filters are
+// done with it
+unsafe impl ChainAllocator for Pool {
+    fn buffer(&mut self, bytes: &[u8]) -> Option<NonNull<ngx_buf_t>> {
+        let mut buf = self.create_buffer(bytes.len())?;
+        let b = NonNull::new(buf.as_ngx_buf_mut())?;
+        // A new temporary buffer is empty (`pos == last`) with room for `bytes`
+        unsafe {
+            let b = b.as_ptr();
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), (*b).last, bytes.len());
+            (*b).last = (*b).last.add(bytes.len());
+        }
+        Some(b)
+    }
+
+    fn special(&mut self) -> Option<NonNull<ngx_buf_t>> {
+        NonNull::new(self.calloc_type::<ngx_buf_t>())
+    }
+
+    fn link(&mut self) -> Option<NonNull<ngx_chain_t>> {
+        NonNull::new(self.alloc_type::<ngx_chain_t>())
+    }
+}
+
+/// A chain of output buffers built from byte slices, one buffer each, with
+/// the flags nginx's output filters read set on the last one
+///
+/// Responses sent in one go end with [`finish`](Self::finish); a streamed
+/// response sends each part as it is ready with [`flush`](Self::flush) and
+/// ends with an empty `finish`.
+pub struct OutputChainBuilder<'a, A: ChainAllocator> {
+    allocator: &'a mut A,
+    head: Option<NonNull<ngx_chain_t>>,
+    tail: Option<NonNull<ngx_chain_t>>,
+    last: Option<NonNull<ngx_buf_t>>,
+}
+
+impl<'a, A: ChainAllocator> OutputChainBuilder<'a, A> {
+    pub fn new(allocator: &'a mut A) -> Self {
+        Self {
+            allocator,
+            head: None,
+            tail: None,
+            last: None,
+        }
+    }
+
+    /// Append a copy of `bytes` as a buffer of its own; an empty slice adds
+    /// nothing, since nginx rejects empty buffers without flags
+    pub fn push(&mut self, bytes: &[u8]) -> Result<&mut Self, ResponseError> {
+        if !bytes.is_empty() {
+            self.append(bytes)?;
+        }
+        Ok(self)
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> Result<NonNull<ngx_buf_t>, ResponseError> {
+        let buf = self
+            .allocator
+            .buffer(bytes)
+            .ok_or(ResponseError::BufferAllocation)?;
+        self.link(buf)
+    }
+
+    fn link(&mut self, buf: NonNull<ngx_buf_t>) -> Result<NonNull<ngx_buf_t>, ResponseError> {
+        let link = self
+            .allocator
+            .link()
+            .ok_or(ResponseError::BufferAllocation)?;
+        unsafe {
+            link.as_ptr().write(ngx_chain_t {
+                buf: buf.as_ptr(),
+                next: std::ptr::null_mut(),
+            });
+            match self.tail {
+                Some(tail) => (*tail.as_ptr()).next = link.as_ptr(),
+                None => self.head = Some(link),
+            }
+        }
+        self.tail = Some(link);
+        self.last = Some(buf);
+        Ok(buf)
+    }
+
+    /// The last buffer, for its flags; an empty chain gets a special buffer
+    ///
+    /// nginx's write filter only accepts an empty buffer that is neither in
+    /// memory nor in a file (`ngx_buf_special`), so an empty one from
+    /// [`ChainAllocator::buffer`] would fail with "zero size buf in writer".
+    fn last_buffer(&mut self) -> Result<&mut ngx_buf_t, ResponseError> {
+        let mut last = match self.last {
+            Some(last) => last,
+            None => {
+                let special = self
+                    .allocator
+                    .special()
+                    .ok_or(ResponseError::BufferAllocation)?;
+                self.link(special)?
+            }
+        };
+        Ok(unsafe { last.as_mut() })
+    }
+
+    fn build(self) -> OutputChain<'a> {
+        OutputChain {
+            head: self.head.expect("a built chain has a buffer"),
+            _allocator: PhantomData,
+        }
+    }
+
+    /// The end of the response: `main` says whether this is the main request,
+    /// whose last buffer ends the client's response, rather than a subrequest,
+    /// whose last buffer only ends its own part
+    pub fn finish(mut self, main: bool) -> Result<OutputChain<'a>, ResponseError> {
+        let last = self.last_buffer()?;
+        last.set_last_buf(main as u32);
+        last.set_last_in_chain(1);
+        Ok(self.build())
+    }
+}
+
+/// A chain ready for the output filters, living as long as the allocator its
+/// buffers came from
+pub struct OutputChain<'a> {
+    head: NonNull<ngx_chain_t>,
+    _allocator: PhantomData<&'a mut ()>,
+}
+
+impl OutputChain<'_> {
+    /// Pass the chain to the request's output filters
+    pub fn send(mut self, request: &mut Request) -> Status {
+        request.output_filter(unsafe { self.head.as_mut() })
+    }
+}
+
+/// Send a response, failing before anything is written if an allocation fails
+pub fn try_send_response(
+    request: &mut Request,
+    body: &[u8],
+    headers: &[(&'static str, &str)],
+    status: http::HTTPStatus,
+) -> Result<Status, ResponseError> {
+    let mut pool = request.pool();
+    let mut out = OutputChainBuilder::new(&mut pool);
+    out.push(body)?;
+    let out = out.finish(request.is_main())?;
 
     request.discard_request_body();
-    request.set_status(http::HTTPStatus::OK);
+    request.set_status(status);
+
+    for &(name, value) in headers {
+        request
+            .add_header_out(name, value)
+            .ok_or(ResponseError::HeaderAllocation(name))?;
+    }
+
+    let rc = request.send_header();
+    if rc == Status::NGX_ERROR || rc > Status::NGX_OK || request.header_only() {
+        return Ok(rc);
+    }
 
-    // Set content type (nginx will handle it based on add_header in config or auto-detection)
-    // For now, we rely on nginx config to set Content-Type via add_header directive
-    request.add_header_out("Content-Type", content_type.content_type_header());
+    out.send(request);
+    Ok(Status::NGX_DONE)
+}
 
+/// Send a response made of lines a part at a time, keeping what nginx holds
+/// unsent for the client within `buffer` (`sqlite_stream_buffer`)
+///
+/// The stream is kept in the module's request context (streamed formats are
+/// only sent for reads, so it never meets a `CaptchaCheck`). When the client
+/// has to be waited for, the request is left open and
+/// `crate::stream_write_handler` carries on once the connection is writable.
+pub fn send_streamed_response(
+    request: &mut Request,
+    response: StreamedResponse,
+    buffer: StreamBuffer,
+) -> Status {
+    let stream = request.pool().allocate(RefCell::new(StreamState {
+        stream: ResponseStream::new(response.lines, buffer),
+        buffers: PartBuffers::default(),
+    }));
+    if stream.is_null() {
+        return internal_error(request, ResponseError::BufferAllocation);
+    }
+
+    request.discard_request_body();
+    request.set_status(http::HTTPStatus::OK);
+    for (name, value) in &response.headers {
+        if request.add_header_out(name, value).is_none() {
+            return internal_error(request, ResponseError::HeaderAllocation(name));
+        }
+    }
     let rc = request.send_header();
     if rc == Status::NGX_ERROR || rc > Status::NGX_OK || request.header_only() {
         return rc;
     }
 
-    request.output_filter(&mut out);
-    Status::NGX_DONE
+    request.set_module_ctx(stream.cast(), Module::module());
+    match continue_stream(request, unsafe { &*stream }) {
+        Some(status) => status,
+        None => {
+            // Kept open for the write handler, which finalizes it
+            let r: *mut ngx_http_request_t = request.into();
+            unsafe {
+                let main = (*r).main;
+                (*main).set_count((*main).count() + 1);
+            }
+            Status::NGX_DONE
+        }
+    }
+}
+
+/// Carry on with the request's stream once its connection is writable: pass
+/// on what nginx still holds, then send what now fits
+///
+/// A client that took nothing for `send_timeout` is given up on. `None`
+/// means the stream is waiting again; otherwise the request is finished with
+/// the status returned.
+pub fn resume_stream(request: &mut Request) -> Option<Status> {
+    let Some(stream) = request_stream(request) else {
+        NginxLogger::new(request).error("stream", "Resumed without a stream in progress");
+        return Some(http::HTTPStatus::INTERNAL_SERVER_ERROR.into());
+    };
+    let r: *mut ngx_http_request_t = request.into();
+    unsafe {
+        let wev = (*(*r).connection).write;
+        if (*wev).timedout() != 0 {
+            (*(*r).connection).set_timedout(1);
+            end_stream(request, &stream.borrow().stream, StreamEnd::TimedOut);
+            return Some(http::HTTPStatus::REQUEST_TIME_OUT.into());
+        }
+        if (*wev).timer_set() != 0 {
+            ngx_del_timer(wev);
+        }
+        if ngx_http_output_filter(r, std::ptr::null_mut()) == Status::NGX_ERROR.0 {
+            end_stream(request, &stream.borrow().stream, StreamEnd::Failed);
+            return Some(Status::NGX_ERROR);
+        }
+    }
+    continue_stream(request, stream)
+}
+
+/// A stream in progress and the buffers its parts are sent in, kept in the
+/// request pool
+struct StreamState {
+    stream: ResponseStream,
+    buffers: PartBuffers,
+}
+
+/// The buffers a stream's parts are copied into, reused once nginx has sent
+/// them
+///
+/// Buffers the output filters still hold are `busy`; once the client has
+/// taken one, `ngx_chain_update_chains` moves it to `free` for a later part.
+/// Since parts are only sent while what is unsent fits the stream's buffer,
+/// a stream needs a few buffers for all its parts rather than a new one each.
+struct PartBuffers {
+    free: *mut ngx_chain_t,
+    busy: *mut ngx_chain_t,
+}
+
+impl Default for PartBuffers {
+    fn default() -> Self {
+        PartBuffers {
+            free: std::ptr::null_mut(),
+            busy: std::ptr::null_mut(),
+        }
+    }
+}
+
+impl PartBuffers {
+    /// The tag marking the stream's own buffers among those nginx hands back
+    fn tag() -> ngx_buf_tag_t {
+        std::ptr::from_ref(Module::module()).cast_mut().cast()
+    }
+
+    /// Pass a copy of `part` to the output filters, flushed, in a free buffer
+    /// or a new one of at least `capacity` bytes
+    fn send(&mut self, request: &mut Request, part: &[u8], capacity: usize) -> Status {
+        let pool = request.pool().as_ptr();
+        let Some(mut out) = (unsafe { self.chain(pool, part, capacity) }) else {
+            return Status::NGX_ERROR;
+        };
+        let r: *mut ngx_http_request_t = request.into();
+        let rc = unsafe { ngx_http_output_filter(r, out) };
+        unsafe {
+            ngx_chain_update_chains(pool, &mut self.free, &mut self.busy, &mut out, Self::tag());
+        }
+        Status(rc)
+    }
+
+    /// A link to a buffer holding `part`, taken from `free` when there is one
+    ///
+    /// # Safety
+    /// `pool` must be the request's pool, which `free` and `busy` came from.
+    unsafe fn chain(
+        &mut self,
+        pool: *mut ngx_pool_t,
+        part: &[u8],
+        capacity: usize,
+    ) -> Option<*mut ngx_chain_t> {
+        unsafe {
+            let link = ngx_chain_get_free_buf(pool, &mut self.free);
+            if link.is_null() {
+                return None;
+            }
+            let buf = &mut *(*link).buf;
+            if fill_part(buf, part, Self::tag()).is_none() {
+                // A new buffer, or a line longer than the others: the small
+                // memory is let go
+                if !buf.start.is_null() {
+                    ngx_pfree(pool, buf.start.cast());
+                }
+                let size = part.len().max(capacity);
+                let start: *mut u8 = ngx_palloc(pool, size).cast();
+                if start.is_null() {
+                    return None;
+                }
+                buf.start = start;
+                buf.end = start.add(size);
+                fill_part(buf, part, Self::tag())?;
+            }
+            Some(link)
+        }
+    }
+}
+
+/// Copy `part` into `buf`'s memory (`start..end`) as a flushed buffer tagged
+/// `tag`; `None` when the memory is too small for it
+///
+/// # Safety
+/// `buf.start..buf.end` must be writable memory, or both null.
+unsafe fn fill_part(buf: &mut ngx_buf_t, part: &[u8], tag: ngx_buf_tag_t) -> Option<()> {
+    let room = (buf.end as usize).saturating_sub(buf.start as usize);
+    if buf.start.is_null() || room < part.len() {
+        return None;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(part.as_ptr(), buf.start, part.len());
+        buf.pos = buf.start;
+        buf.last = buf.start.add(part.len());
+    }
+    buf.set_temporary(1);
+    buf.set_flush(1);
+    buf.tag = tag;
+    Some(())
+}
+
+/// The request's stream, kept in the request pool for as long as the request
+/// rather than borrowed from it
+fn request_stream<'a>(request: &Request) -> Option<&'a RefCell<StreamState>> {
+    let stream = request.get_module_ctx::<RefCell<StreamState>>(Module::module())?;
+    Some(unsafe { &*(stream as *const RefCell<StreamState>) })
+}
+
+/// Hand nginx the parts of the stream that fit, ending the response after
+/// the last one; `None` when the client must be waited for
+fn continue_stream(request: &mut Request, state: &RefCell<StreamState>) -> Option<Status> {
+    let mut state = state.borrow_mut();
+    let StreamState { stream, buffers } = &mut *state;
+    let capacity = stream.capacity();
+    let r: *mut ngx_http_request_t = request.into();
+    loop {
+        let unsent = unsafe { unsent_bytes((*r).out) };
+        let end = match stream.next(unsent) {
+            StreamStep::Send(part) => match buffers.send(request, part, capacity) {
+                rc if rc != Status::NGX_ERROR => continue,
+                _ => StreamEnd::Failed,
+            },
+            StreamStep::Wait => match wait_for_client(request) {
+                Ok(()) => return None,
+                Err(()) => StreamEnd::Failed,
+            },
+            StreamStep::Drop => StreamEnd::Dropped,
+            StreamStep::Done => {
+                let main = request.is_main();
+                let mut pool = request.pool();
+                let rc = OutputChainBuilder::new(&mut pool)
+                    .finish(main)
+                    .map(|out| out.send(request));
+                match rc {
+                    Ok(rc) if rc != Status::NGX_ERROR => {
+                        end_stream(request, stream, StreamEnd::Sent);
+                        return Some(rc);
+                    }
+                    _ => StreamEnd::Failed,
+                }
+            }
+        };
+        end_stream(request, stream, end);
+        return Some(Status::NGX_ERROR);
+    }
+}
+
+/// Have nginx call `crate::stream_write_handler` once the connection is
+/// writable, or once `send_timeout` passes without it
+fn wait_for_client(request: &mut Request) -> Result<(), ()> {
+    let send_timeout = NgxHttpCoreModule::location_conf(request).map(|core| core.send_timeout);
+    let r: *mut ngx_http_request_t = request.into();
+    unsafe {
+        let wev = (*(*r).connection).write;
+        (*r).write_event_handler = Some(crate::stream_write_handler);
+        if ngx_handle_write_event(wev, 0) != Status::NGX_OK.0 {
+            return Err(());
+        }
+        if let Some(send_timeout) = send_timeout {
+            ngx_add_timer(wev, send_timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Log how a stream ended, count it in the worker's metrics and set the
+/// request's `$sqlite_stream_buffered`
+fn end_stream(request: &mut Request, stream: &ResponseStream, end: StreamEnd) {
+    let summary = format!("Stream {}: {}", end.as_str(), stream.summary());
+    match end {
+        StreamEnd::Sent => NginxLogger::new(request).debug("stream", &summary),
+        _ => NginxLogger::new(request).warn("stream", &summary),
+    }
+    worker::with_state(|state| state.record_stream(stream, end));
+    if let Some(index) = Module::main_conf(request).and_then(|conf| conf.stream_variable) {
+        variable::set_stream_variable(request, index, stream.peak());
+    }
+}
+
+/// The bytes held in a chain of in-memory buffers
+///
+/// # Safety
+/// `chain` must be null or a valid chain, such as the request's `out`: what
+/// nginx's write filter holds unsent.
+unsafe fn unsent_bytes(mut chain: *mut ngx_chain_t) -> usize {
+    let mut bytes = 0;
+    unsafe {
+        while !chain.is_null() {
+            let buf = (*chain).buf;
+            if !buf.is_null() && (*buf).in_file() == 0 {
+                bytes += (*buf).last.offset_from((*buf).pos) as usize;
+            }
+            chain = (*chain).next;
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_error_messages() {
+        assert_eq!(
+            ResponseError::MissingMainConfig.to_string(),
+            "main configuration is unavailable"
+        );
+        assert_eq!(
+            ResponseError::HeaderAllocation("Content-Type").to_string(),
+            "failed to allocate Content-Type header"
+        );
+    }
+
+    /// Buffers and links from the heap, failing once `limit` allocations
+    /// have been made
+    // Boxed so the pointers handed out survive the vectors growing
+    #[allow(clippy::vec_box)]
+    #[derive(Default)]
+    struct FakePool {
+        bytes: Vec<Box<[u8]>>,
+        bufs: Vec<Box<ngx_buf_t>>,
+        links: Vec<Box<ngx_chain_t>>,
+        limit: Option<usize>,
+    }
+
+    impl FakePool {
+        fn exhausted(&self) -> bool {
+            self.limit
+                .is_some_and(|limit| self.bufs.len() + self.links.len() >= limit)
+        }
+    }
+
+    unsafe impl ChainAllocator for FakePool {
+        fn buffer(&mut self, bytes: &[u8]) -> Option<NonNull<ngx_buf_t>> {
+            if self.exhausted() {
+                return None;
+            }
+            let mut copy: Box<[u8]> = bytes.into();
+            let mut buf: Box<ngx_buf_t> = Box::new(unsafe { std::mem::zeroed() });
+            buf.pos = copy.as_mut_ptr();
+            buf.last = unsafe { buf.pos.add(copy.len()) };
+            // As `ngx_create_temp_buf` marks the real pool's buffers
+            buf.set_temporary(1);
+            let ptr = NonNull::from(&mut *buf);
+            self.bytes.push(copy);
+            self.bufs.push(buf);
+            Some(ptr)
+        }
+
+        fn special(&mut self) -> Option<NonNull<ngx_buf_t>> {
+            if self.exhausted() {
+                return None;
+            }
+            let mut buf: Box<ngx_buf_t> = Box::new(unsafe { std::mem::zeroed() });
+            let ptr = NonNull::from(&mut *buf);
+            self.bufs.push(buf);
+            Some(ptr)
+        }
+
+        fn link(&mut self) -> Option<NonNull<ngx_chain_t>> {
+            if self.exhausted() {
+                return None;
+            }
+            let mut link: Box<ngx_chain_t> = Box::new(unsafe { std::mem::zeroed() });
+            let ptr = NonNull::from(&mut *link);
+            self.links.push(link);
+            Some(ptr)
+        }
+    }
+
+    /// Each buffer's bytes and its `last_buf`, `last_in_chain` and `flush`,
+    /// checking each is one nginx's write filter accepts: data in memory, or
+    /// an `ngx_buf_special` buffer with no memory and a flag to carry
+    fn buffers(chain: &OutputChain) -> Vec<(Vec<u8>, [u32; 3])> {
+        let mut buffers = Vec::new();
+        let mut link = chain.head.as_ptr();
+        while !link.is_null() {
+            unsafe {
+                let buf = &*(*link).buf;
+                let in_memory = buf.temporary() | buf.memory() | buf.mmap() != 0;
+                let special = !in_memory
+                    && buf.in_file() == 0
+                    && buf.flush() | buf.last_buf() | buf.sync() != 0;
+                if !special {
+                    assert!(in_memory, "a data buffer is in memory");
+                    assert!(buf.last > buf.pos, "zero size buf in writer");
+                }
+                let bytes = if special {
+                    Vec::new()
+                } else {
+                    let len = buf.last.offset_from(buf.pos) as usize;
+                    std::slice::from_raw_parts(buf.pos, len).to_vec()
+                };
+                buffers.push((bytes, [buf.last_buf(), buf.last_in_chain(), buf.flush()]));
+                link = (*link).next;
+            }
+        }
+        buffers
+    }
+
+    #[test]
+    fn test_output_chain() {
+        let mut pool = FakePool::default();
+        let mut builder = OutputChainBuilder::new(&mut pool);
+        builder.push(b"<ul>").unwrap().push(b"").unwrap();
+        builder.push(b"<li>one</li></ul>").unwrap();
+        let chain = builder.finish(true).unwrap();
+        assert_eq!(
+            buffers(&chain),
+            vec![
+                (b"<ul>".to_vec(), [0, 0, 0]),
+                (b"<li>one</li></ul>".to_vec(), [1, 1, 0]),
+            ]
+        );
+
+        // A subrequest's part does not end the client's response
+        let mut pool = FakePool::default();
+        let mut builder = OutputChainBuilder::new(&mut pool);
+        builder.push(b"part").unwrap();
+        let chain = builder.finish(false).unwrap();
+        assert_eq!(buffers(&chain), vec![(b"part".to_vec(), [0, 1, 0])]);
+
+        // Nothing to send still ends the response, with a special buffer
+        let mut pool = FakePool::default();
+        let chain = OutputChainBuilder::new(&mut pool).finish(true).unwrap();
+        assert_eq!(buffers(&chain), vec![(vec![], [1, 1, 0])]);
+    }
+
+    #[test]
+    fn test_part_buffers_are_reused() {
+        use crate::types::StreamOverflow;
+
+        // A buffer as `ngx_chain_get_free_buf` first makes it has no memory
+        let mut buf: ngx_buf_t = unsafe { std::mem::zeroed() };
+        assert!(unsafe { fill_part(&mut buf, b"one\n", PartBuffers::tag()) }.is_none());
+
+        let mut memory = vec![0u8; 8];
+        buf.start = memory.as_mut_ptr();
+        buf.end = unsafe { buf.start.add(memory.len()) };
+        let bytes = |buf: &ngx_buf_t| unsafe {
+            std::slice::from_raw_parts(buf.pos, buf.last.offset_from(buf.pos) as usize).to_vec()
+        };
+
+        // Every part of a stream fits the one buffer, sent and freed in turn
+        let buffer = StreamBuffer::parse("8")
+            .unwrap()
+            .with_overflow(StreamOverflow::Wait);
+        let lines = ["one\n", "two\n", "three\n"].map(|line| line.as_bytes().to_vec());
+        let mut stream = ResponseStream::new(Box::new(lines.into_iter()), buffer);
+        let mut sent = Vec::new();
+        while let StreamStep::Send(part) = stream.next(0) {
+            unsafe { fill_part(&mut buf, part, PartBuffers::tag()) }.unwrap();
+            assert_eq!(buf.pos, memory.as_mut_ptr());
+            assert_eq!((buf.temporary(), buf.flush()), (1, 1));
+            assert_eq!(buf.tag, PartBuffers::tag());
+            sent.push(bytes(&buf));
+            // As `ngx_chain_update_chains` leaves a buffer the client took
+            buf.last = buf.pos;
+        }
+        assert_eq!(sent, vec![b"one\ntwo\n".to_vec(), b"three\n".to_vec()]);
+
+        // A longer part needs new memory
+        assert!(unsafe { fill_part(&mut buf, b"three\nfour\n", PartBuffers::tag()) }.is_none());
+        assert_eq!(buf.pos, buf.last);
+    }
+
+    #[test]
+    fn test_output_chain_allocation_failure() {
+        for limit in 0..4 {
+            let mut pool = FakePool {
+                limit: Some(limit),
+                ..Default::default()
+            };
+            let mut builder = OutputChainBuilder::new(&mut pool);
+            let built = builder
+                .push(b"one")
+                .and_then(|builder| builder.push(b"two"))
+                .map(|_| ());
+            let built = built.and_then(|_| builder.finish(true).map(|_| ()));
+            assert_eq!(
+                built,
+                Err(ResponseError::BufferAllocation),
+                "limit {}",
+                limit
+            );
+        }
+    }
 }
@@ -1,66 +1,841 @@
 //! Parse raw configuration strings into validated domain types
 
 use crate::config::ModuleConfig;
-use crate::domain::ValidatedConfig;
+use crate::domain::{self, ValidatedConfig};
+use crate::rest;
 use crate::types::{
-    DatabasePath, NginxVariable, ParamName, ParameterBinding, SqlQuery, TemplatePath,
+    Attachment, CountStrategy, DataSource, DatabasePath, MaxLength, NavMenu, NginxVariable,
+    PageMode, Pagination, ParamName, ParamPattern, ParamType, ParameterBinding, QueryRule,
+    RestTable, RowShape, SoftDelete, SqlQuery, TemplatePath, UpsertTable, WriteMethod, WriteQuery,
 };
 
 /// Parse raw configuration into validated domain configuration
-pub fn parse_config(
-    config: &ModuleConfig,
-    doc_root: String,
-    uri: String,
-) -> Result<ValidatedConfig, String> {
-    let db_path =
-        DatabasePath::parse(&config.db_path).map_err(|e| format!("invalid db_path: {}", e))?;
+pub fn parse_config(config: &ModuleConfig) -> Result<ValidatedConfig, String> {
+    // A sqlite_db_map location is given its database per request; until then
+    // the control database stands in
+    let db_path = match (&config.db_path, &config.db_map) {
+        (None, Some(map)) => map.control().as_str(),
+        (db_path, _) => required(db_path, "sqlite_db")?,
+    };
+    let db_path = DatabasePath::parse(db_path).map_err(|e| format!("invalid db_path: {}", e))?;
+    let mut attachments: Vec<Attachment> = Vec::new();
+    for (alias, path) in &config.attachments {
+        let attachment = Attachment::parse(alias, path)
+            .map_err(|e| format!("invalid sqlite_attach {}: {}", alias, e))?;
+        if attachments
+            .iter()
+            .any(|a| a.alias().eq_ignore_ascii_case(attachment.alias()))
+        {
+            return Err(format!(
+                "sqlite_attach alias '{}' is used more than once",
+                alias
+            ));
+        }
+        attachments.push(attachment);
+    }
+    let db_path = db_path
+        .with_attachments(attachments)
+        .with_history(config.history.clone());
+
+    let fallback_query = config
+        .fallback_query
+        .as_deref()
+        .map(SqlQuery::parse)
+        .transpose()
+        .map_err(|e| format!("invalid fallback query: {}", e))?;
+
+    let count_query = config
+        .count_query
+        .as_deref()
+        .map(|query| {
+            match config.pagination.as_ref().map(Pagination::mode) {
+                None => return Err("sqlite_count_query needs sqlite_paginate".to_string()),
+                Some(PageMode::Cursor(_)) => {
+                    return Err("sqlite_count_query does not apply to mode=cursor".to_string());
+                }
+                Some(PageMode::Offset(_)) => {}
+            }
+            if config
+                .count
+                .as_ref()
+                .is_some_and(|c| c != &CountStrategy::Exact)
+            {
+                return Err("sqlite_count_query needs sqlite_count exact".to_string());
+            }
+            SqlQuery::parse(query).map_err(|e| format!("invalid sqlite_count_query: {}", e))
+        })
+        .transpose()?;
+
+    let export_dir = config
+        .export_dir
+        .as_deref()
+        .map(|dir| {
+            if config.rest.is_some() || config.upsert.is_some() || !config.write_queries.is_empty()
+            {
+                return Err("sqlite_export cannot be combined with writes".to_string());
+            }
+            if config.pagination.is_some() {
+                return Err("sqlite_export cannot be combined with sqlite_paginate".to_string());
+            }
+            if !dir.starts_with('/') {
+                return Err(format!("sqlite_export needs an absolute path: '{}'", dir));
+            }
+            Ok(dir.trim_end_matches('/').to_string())
+        })
+        .transpose()?;
+
+    if config.report.is_some() {
+        if config.rest.is_some() || config.upsert.is_some() || !config.write_queries.is_empty() {
+            return Err("sqlite_report cannot be combined with writes".to_string());
+        }
+        if config.batch == Some(true) {
+            return Err("sqlite_report cannot be combined with sqlite_batch".to_string());
+        }
+        if export_dir.is_some() {
+            return Err("sqlite_report cannot be combined with sqlite_export".to_string());
+        }
+        if config.pagination.is_some() {
+            return Err("sqlite_report cannot be combined with sqlite_paginate".to_string());
+        }
+    }
+
+    // A sqlite_rest location renders JSON unless it also has a template, and
+    // export and report locations answer GET with their rows as JSON
+    let template_path = match (&config.template_path, &config.rest) {
+        (None, Some(_)) => None,
+        (None, None) if export_dir.is_some() || config.report.is_some() => None,
+        (None, None) if config.feed.as_ref().is_some_and(|f| f.enabled()) => None,
+        (template_path, _) => Some(
+            TemplatePath::parse(required(template_path, "sqlite_template")?)
+                .map_err(|e| format!("invalid template_path: {}", e))?,
+        ),
+    };
+
+    let parameters = parse_parameter_bindings(
+        &config.query_params,
+        &config.param_types,
+        &config.param_defaults,
+        &config.required_params,
+        &config.param_patterns,
+        &config.param_max_lengths,
+    )?;
+
+    let (query, query_rules, mut parameters, write_queries) = match &config.rest {
+        Some(rest) => parse_rest(config, rest, &parameters)?,
+        None => {
+            let query = SqlQuery::parse(required(&config.query, "sqlite_query")?)
+                .map_err(|e| format!("invalid query: {}", e))?;
+            let query_rules = config
+                .query_rules
+                .iter()
+                .map(|(variable, rule_query)| {
+                    QueryRule::parse(variable, rule_query)
+                        .map_err(|e| format!("invalid sqlite_query_if {}: {}", variable, e))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let (parameters, write_queries) = match &config.upsert {
+                Some(upsert) => parse_upsert(config, upsert, parameters)?,
+                None => (parameters, parse_write_queries(config)?),
+            };
+            (query, query_rules, parameters, write_queries)
+        }
+    };
+    // sqlite_count applies to numbered pages; cursor pages are never counted
+    let pagination = config
+        .pagination
+        .clone()
+        .map(|pagination| pagination.with_count(config.count.clone().unwrap_or_default()));
+    let count_query = match (&count_query, &pagination) {
+        (None, Some(pagination)) => match (pagination.mode(), pagination.count()) {
+            (PageMode::Offset(_), CountStrategy::Estimate(table)) => {
+                Some(estimate_query(&query, table.as_deref())?)
+            }
+            _ => None,
+        },
+        _ => count_query,
+    };
+    if config.sort_param.is_some()
+        && matches!(
+            pagination.as_ref().map(|p| p.mode()),
+            Some(PageMode::Cursor(_))
+        )
+    {
+        return Err(
+            "sqlite_sort_param cannot be combined with mode=cursor pages, which follow their key's order"
+                .to_string(),
+        );
+    }
+    // Reads skip soft-deleted rows, and deletes mark rows instead
+    let (query, fallback_query, query_rules, write_queries) = match &config.soft_delete {
+        Some(soft_delete) => soft_deleted(
+            soft_delete,
+            query,
+            fallback_query,
+            query_rules,
+            write_queries,
+        )
+        .map_err(|e| format!("invalid sqlite_soft_delete: {}", e))?,
+        None => (query, fallback_query, query_rules, write_queries),
+    };
+    // Query arguments are bound by name, after the location's own bindings
+    if config.bind_args == Some(true) {
+        if parameters.iter().any(|binding| {
+            matches!(
+                binding,
+                ParameterBinding::Positional { .. } | ParameterBinding::PositionalLiteral { .. }
+            )
+        }) {
+            return Err(
+                "sqlite_bind_args cannot be combined with positional parameters".to_string(),
+            );
+        }
+        parameters.push(ParameterBinding::QueryArgs { max_length: None });
+    }
+    // Filter values are bound by name, as are sqlite_bind_args
+    if !config.filters.is_empty()
+        && parameters.iter().any(|binding| {
+            matches!(
+                binding,
+                ParameterBinding::Positional { .. } | ParameterBinding::PositionalLiteral { .. }
+            )
+        })
+    {
+        return Err("sqlite_filter cannot be combined with positional parameters".to_string());
+    }
+    // Columns sqlite_rest binds for itself are capped too
+    if let Some(limit) = config.param_max_length {
+        for binding in &mut parameters {
+            binding.cap_length(limit);
+        }
+    }
+    let parameters: Vec<ParameterBinding> = parameters
+        .into_iter()
+        .map(|binding| binding.with_variable_index(&config.variable_indexes))
+        .collect();
+
+    if config.honeypot.is_some() && write_queries.is_empty() {
+        return Err("sqlite_honeypot needs sqlite_write_query or sqlite_rest".to_string());
+    }
+    if config.captcha.is_some() && write_queries.is_empty() {
+        return Err("sqlite_captcha needs sqlite_write_query or sqlite_rest".to_string());
+    }
+    if config.notify_email.is_some() && write_queries.is_empty() {
+        return Err("sqlite_notify_email needs sqlite_write_query or sqlite_rest".to_string());
+    }
+
+    let form_template = config
+        .form_template
+        .as_deref()
+        .map(|path| {
+            if write_queries.is_empty() {
+                return Err(
+                    "sqlite_form_template needs sqlite_write_query or sqlite_rest".to_string(),
+                );
+            }
+            TemplatePath::parse(path).map_err(|e| format!("invalid sqlite_form_template: {}", e))
+        })
+        .transpose()?;
+
+    let mut sources: Vec<DataSource> = Vec::new();
+    for (name, source_db, source_query) in &config.sources {
+        let source = DataSource::parse(name, source_db, source_query)
+            .map_err(|e| format!("invalid sqlite_source '{}': {}", name, e))?;
+        if sources.iter().any(|s| s.name() == source.name()) {
+            return Err(format!(
+                "sqlite_source '{}' is defined more than once",
+                name
+            ));
+        }
+        sources.push(source);
+    }
 
-    let query = SqlQuery::parse(&config.query).map_err(|e| format!("invalid query: {}", e))?;
+    let mut seen_menus: Vec<&str> = Vec::new();
+    for menu in &config.nav_menus {
+        if seen_menus.contains(&menu.name()) {
+            return Err(format!(
+                "sqlite_nav '{}' is defined more than once",
+                menu.name()
+            ));
+        }
+        seen_menus.push(menu.name());
+    }
 
-    let template_path = TemplatePath::parse(&config.template_path)
-        .map_err(|e| format!("invalid template_path: {}", e))?;
+    // A saved page would be served in place of the feed
+    if config.feed.as_ref().is_some_and(|f| f.enabled())
+        && config.try_static.is_some_and(|t| t.enabled())
+    {
+        return Err("sqlite_try_static cannot be combined with sqlite_feed".to_string());
+    }
 
-    let parameters = parse_parameter_bindings(&config.query_params)?;
+    // A saved page would be served whatever theme later requests ask for
+    if config.theme.is_some() && config.try_static.is_some_and(|t| t.enabled()) {
+        return Err("sqlite_try_static cannot be combined with sqlite_theme".to_string());
+    }
+    if let Some(variants) = &config.template_variants {
+        if template_path.is_none() {
+            return Err("sqlite_template_variant needs sqlite_template".to_string());
+        }
+        if config.try_static.is_some_and(|t| t.enabled()) {
+            return Err(
+                "sqlite_try_static cannot be combined with sqlite_template_variant".to_string(),
+            );
+        }
+        // Clients could otherwise opt themselves into a staged template
+        let from_client = ["$http_", "$cookie_", "$arg_"]
+            .iter()
+            .any(|prefix| variants.variable().as_str().starts_with(prefix));
+        if from_client && variants.secret().is_none() {
+            return Err(format!(
+                "sqlite_template_variant needs secret= to read {}",
+                variants.variable().as_str()
+            ));
+        }
+    }
+
+    for (i, expansion) in config.expansions.iter().enumerate() {
+        if config.expansions[..i]
+            .iter()
+            .any(|other| other.name() == expansion.name())
+        {
+            return Err(format!(
+                "sqlite_expand {} is defined twice",
+                expansion.name()
+            ));
+        }
+    }
+
+    // The ranking is a built-in nav menu over the hit counter's tables, so it
+    // shares the menus' cache
+    let trending = match (config.trending.filter(|t| t.enabled()), &config.hit_counter) {
+        (None, _) => None,
+        (Some(_), None) => return Err("sqlite_trending needs sqlite_hit_counter".to_string()),
+        (Some(trending), Some(counter)) => Some(
+            NavMenu::parse("trending", db_path.as_str(), &trending.query(counter)?, &[])
+                .map_err(|e| format!("invalid sqlite_trending: {}", e))?
+                .with_max_age(trending.max_age()),
+        ),
+    };
 
     Ok(ValidatedConfig {
         db_path,
         query,
+        fallback_query,
+        pagination,
+        count_query,
+        query_rules,
         template_path,
         parameters,
-        doc_root,
-        uri,
+        max_template_rows: config.max_template_rows,
+        aggregate: config.aggregate.clone(),
+        sources,
+        write_queries,
+        mutation_max_rows: config.mutation_max_rows,
+        batch: config.batch.unwrap_or(false),
+        form_template,
+        honeypot: config.honeypot.clone(),
+        captcha: config.captcha.clone(),
+        notify_email: config.notify_email.clone(),
+        expand_json: config.expand_json.clone().unwrap_or_default(),
+        json_nulls: config.json_nulls.unwrap_or_default(),
+        rows: RowShape::new()
+            .with_hidden_columns(config.hidden_columns.clone())
+            .with_blob_encoding(config.blob_encoding.unwrap_or_default()),
+        try_static: config.try_static.filter(|t| t.enabled()),
+        export_dir,
+        report: config.report.clone(),
+        timing: config.timing.unwrap_or(false),
+        trace: config.trace.unwrap_or(false),
+        nav_menus: config.nav_menus.clone(),
+        breadcrumbs: config.breadcrumbs.clone().filter(|b| b.enabled()),
+        theme: config.theme.clone(),
+        template_variants: config.template_variants.clone(),
+        hit_counter: config.hit_counter.clone(),
+        trending,
+        memo_table: config.memo_table.clone().filter(|m| m.enabled()),
+        expansions: config.expansions.clone(),
+        sort: config.sort_param.clone(),
+        filters: config.filters.clone(),
+        allowed_fields: config.allowed_fields.clone(),
+        feed: config.feed.clone().filter(|f| f.enabled()),
+        stream: config
+            .stream_buffer
+            .filter(|buffer| buffer.enabled())
+            .map(|buffer| buffer.with_overflow(config.stream_overflow.unwrap_or_default())),
+        dry_run: config.dry_run.clone(),
     })
 }
 
+/// Generate a `sqlite_rest` location's statements from the table columns read
+/// at startup; `sqlite_methods`, when set, limits which write methods are enabled
+fn parse_rest(
+    config: &ModuleConfig,
+    rest: &RestTable,
+    parameters: &[ParameterBinding],
+) -> Result<RestParts, String> {
+    if config.query.is_some()
+        || !config.write_queries.is_empty()
+        || !config.query_rules.is_empty()
+        || config.upsert.is_some()
+    {
+        return Err(
+            "sqlite_rest cannot be combined with sqlite_query, sqlite_query_if, sqlite_write_query or sqlite_upsert"
+                .to_string(),
+        );
+    }
+
+    let statements = rest::generate(rest, &config.rest_columns, parameters)
+        .map_err(|e| format!("invalid sqlite_rest {}: {}", rest.table(), e))?;
+    let writes = statements
+        .writes
+        .into_iter()
+        .filter(|(method, _)| {
+            config.write_methods.is_empty() || config.write_methods.contains(method)
+        })
+        .collect();
+    Ok((
+        statements.list,
+        vec![statements.lookup],
+        statements.bindings,
+        writes,
+    ))
+}
+
+/// Generate a `sqlite_upsert` location's POST statement from the table columns
+/// read at startup, binding every column alongside the configured parameters
+fn parse_upsert(
+    config: &ModuleConfig,
+    upsert: &UpsertTable,
+    parameters: Vec<ParameterBinding>,
+) -> Result<UpsertParts, String> {
+    if !config.write_queries.is_empty() || !config.write_methods.is_empty() {
+        return Err(
+            "sqlite_upsert cannot be combined with sqlite_write_query or sqlite_methods"
+                .to_string(),
+        );
+    }
+
+    let (write, bindings) = rest::generate_upsert(upsert, &config.rest_columns, &parameters)
+        .map_err(|e| format!("invalid sqlite_upsert {}: {}", upsert.table(), e))?;
+    Ok((bindings, vec![(WriteMethod::Post, write)]))
+}
+
+/// Bindings and write statements of a `sqlite_upsert` location
+type UpsertParts = (Vec<ParameterBinding>, Vec<(WriteMethod, WriteQuery)>);
+
+/// Read query, lookup rules, bindings and write statements of a location
+type RestParts = (
+    SqlQuery,
+    Vec<QueryRule>,
+    Vec<ParameterBinding>,
+    Vec<(WriteMethod, WriteQuery)>,
+);
+
+/// Pair every `sqlite_write_query` with each of the `sqlite_methods` (POST by
+/// default); a method runs its statements in directive order
+fn parse_write_queries(config: &ModuleConfig) -> Result<Vec<(WriteMethod, WriteQuery)>, String> {
+    if config.write_queries.is_empty() {
+        if config.write_methods.is_empty() {
+            return Ok(Vec::new());
+        }
+        return Err("sqlite_methods requires sqlite_write_query".to_string());
+    }
+
+    let write_queries = config
+        .write_queries
+        .iter()
+        .map(|sql| {
+            WriteQuery::parse(sql.as_str()).map_err(|e| format!("invalid write query: {}", e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let methods = if config.write_methods.is_empty() {
+        vec![WriteMethod::Post]
+    } else {
+        config.write_methods.clone()
+    };
+    Ok(methods
+        .into_iter()
+        .flat_map(|method| {
+            write_queries
+                .iter()
+                .map(move |query| (method, query.clone()))
+        })
+        .collect())
+}
+
+/// A location's read queries and write statements under `sqlite_soft_delete`
+fn soft_deleted(
+    soft_delete: &SoftDelete,
+    query: SqlQuery,
+    fallback_query: Option<SqlQuery>,
+    query_rules: Vec<QueryRule>,
+    write_queries: Vec<(WriteMethod, WriteQuery)>,
+) -> Result<SoftDeleteParts, String> {
+    let query = soft_delete.live_rows(&query)?;
+    let fallback_query = fallback_query
+        .map(|fallback| soft_delete.live_rows(&fallback))
+        .transpose()?;
+    let query_rules = query_rules
+        .iter()
+        .map(|rule| {
+            let query = soft_delete.live_rows(rule.query())?;
+            QueryRule::parse(rule.variable().as_str(), query.as_str())
+        })
+        .collect::<Result<_, _>>()?;
+    let write_queries = write_queries
+        .iter()
+        .map(|(method, write)| Ok((*method, soft_delete.mark_deleted(write)?)))
+        .collect::<Result<_, String>>()?;
+    Ok((query, fallback_query, query_rules, write_queries))
+}
+
+/// Read query, fallback query, lookup rules and write statements of a location
+type SoftDeleteParts = (
+    SqlQuery,
+    Option<SqlQuery>,
+    Vec<QueryRule>,
+    Vec<(WriteMethod, WriteQuery)>,
+);
+
+/// A setting every location needs; unset is reported by directive name
+/// The `sqlite_count estimate` query: the highest rowid of `table`, or of
+/// the first table the location's query reads `FROM`
+///
+/// Rows deleted from the end and any `WHERE` clause are not accounted for,
+/// but SQLite answers from the end of the table's b-tree without a scan.
+fn estimate_query(query: &SqlQuery, table: Option<&str>) -> Result<SqlQuery, String> {
+    let table = match table {
+        Some(table) => table.to_string(),
+        None => {
+            // Only the first FROM counts; a subquery's table is not the one paged
+            let table = regex::Regex::new(
+                r#"(?is)^.*?\bFROM\s+(\(|"?([A-Za-z_][A-Za-z0-9_]*)"?(\s|;|\)|$))"#,
+            )
+            .map_err(|e| e.to_string())?
+            .captures(query.as_str())
+            .and_then(|captures| captures.get(2).map(|table| table.as_str().to_string()));
+            table.ok_or(
+                "sqlite_count estimate cannot tell which table the query reads; add table=name",
+            )?
+        }
+    };
+    SqlQuery::parse(format!("SELECT COALESCE(max(rowid), 0) FROM \"{}\"", table))
+}
+
+fn required<'a>(value: &'a Option<String>, directive: &str) -> Result<&'a str, String> {
+    value
+        .as_deref()
+        .ok_or_else(|| format!("{} is not set", directive))
+}
+
+/// Resolve a `sqlite_query @name` reference against `sqlite_query_define` entries
+///
+/// Returns `Ok(None)` for ordinary SQL, which is left untouched.
+pub fn resolve_query_reference(
+    query: &str,
+    definitions: &[(String, String)],
+) -> Result<Option<String>, String> {
+    let Some(name) = query.trim().strip_prefix('@') else {
+        return Ok(None);
+    };
+
+    definitions
+        .iter()
+        .find(|(defined, _)| defined == name)
+        .map(|(_, sql)| Some(sql.clone()))
+        .ok_or_else(|| format!("unknown named query '@{}'", name))
+}
+
+/// Check that every `sqlite_query_define` name is unique and non-empty
+pub fn validate_query_definitions(definitions: &[(String, String)]) -> Result<(), String> {
+    for (i, (name, _)) in definitions.iter().enumerate() {
+        if name.is_empty() || name.starts_with('@') {
+            return Err(format!("invalid named query name '{}'", name));
+        }
+        if definitions[..i].iter().any(|(other, _)| other == name) {
+            return Err(format!("named query '{}' is defined more than once", name));
+        }
+    }
+    Ok(())
+}
+
+/// Pair up the arguments of a `sqlite_param` directive that binds several
+/// named parameters at once (`:id $arg_id :genre $arg_genre`)
+///
+/// Returns `None` for the single-parameter forms, where the third argument
+/// is a type, default or option rather than another `:name`.
+pub fn parameter_pairs(args: &[String]) -> Option<Result<Vec<(String, String)>, String>> {
+    if !args.get(2).is_some_and(|arg| arg.starts_with(':')) {
+        return None;
+    }
+    if !args.len().is_multiple_of(2) {
+        return Some(Err(format!(
+            "expected :name $variable pairs, got {} arguments",
+            args.len()
+        )));
+    }
+    let names = args.iter().step_by(2);
+    let variables = args.iter().skip(1).step_by(2);
+    let pairs = names
+        .zip(variables)
+        .map(|(name, variable)| {
+            if name.starts_with(':') {
+                Ok((name.clone(), variable.clone()))
+            } else {
+                Err(format!("expected a :name, got '{}'", name))
+            }
+        })
+        .collect();
+    Some(pairs)
+}
+
+/// Record a `sqlite_param` directive's arguments
+///
+/// One argument binds a positional parameter (`$arg_id`); several `:name
+/// $variable` pairs bind named parameters; otherwise one named parameter is
+/// bound, optionally typed and with a default and checks (`:book_id $arg_id
+/// [int] [1] [pattern=regex] [max_length=n]`), where a lone third argument
+/// is a type if it names one.
+pub fn add_parameter(conf: &mut ModuleConfig, args: Vec<String>) -> Result<(), String> {
+    if let Some(pairs) = parameter_pairs(&args) {
+        conf.query_params.extend(pairs?);
+        return Ok(());
+    }
+    let mut args = args.into_iter();
+    let (Some(param_name), variable) = (args.next(), args.next()) else {
+        return Err("expected a parameter".to_string());
+    };
+    let Some(variable) = variable else {
+        conf.query_params.push((String::new(), param_name));
+        return Ok(());
+    };
+    let mut extra: Vec<String> = args.collect();
+    take_param_options(conf, &param_name, &mut extra)?;
+    if extra.len() > 2 {
+        return Err("too many arguments".to_string());
+    }
+    let typed_default = extra.len() == 2;
+    let mut extra = extra.into_iter();
+    let mut default = None;
+    if let Some(third) = extra.next() {
+        match ParamType::parse(&third) {
+            Ok(param_type) => {
+                conf.param_types.push((param_name.clone(), param_type));
+                default = extra.next();
+            }
+            Err(e) if typed_default => return Err(e),
+            Err(_) => default = Some(third),
+        }
+    }
+    if let Some(default) = default {
+        conf.param_defaults.push((param_name.clone(), default));
+    }
+    conf.query_params.push((param_name, variable));
+    Ok(())
+}
+
+/// Record a `sqlite_param_required` directive's arguments (`:book_id
+/// $arg_id [int] [pattern=regex] [max_length=n]`)
+pub fn add_required_parameter(conf: &mut ModuleConfig, args: Vec<String>) -> Result<(), String> {
+    let mut args = args.into_iter();
+    let (Some(param_name), Some(variable)) = (args.next(), args.next()) else {
+        return Err("expected a parameter and a variable".to_string());
+    };
+    let mut extra: Vec<String> = args.collect();
+    take_param_options(conf, &param_name, &mut extra)?;
+    match extra.as_slice() {
+        [] => {}
+        [param_type] => conf
+            .param_types
+            .push((param_name.clone(), ParamType::parse(param_type)?)),
+        _ => return Err("too many arguments".to_string()),
+    }
+    conf.required_params.push(param_name.clone());
+    conf.query_params.push((param_name, variable));
+    Ok(())
+}
+
+/// Remove a `name=value` option (`pattern=`, `max_length=`) from a parameter
+/// directive's arguments, returning its value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    let i = args.iter().position(|arg| arg.starts_with(&prefix))?;
+    Some(args.remove(i)[prefix.len()..].to_string())
+}
+
+/// Record the `pattern=` and `max_length=` options of a parameter directive,
+/// removing them from its arguments
+fn take_param_options(
+    conf: &mut ModuleConfig,
+    param_name: &str,
+    args: &mut Vec<String>,
+) -> Result<(), String> {
+    if let Some(pattern) = take_option(args, "pattern") {
+        conf.param_patterns.push((param_name.to_string(), pattern));
+    }
+    if let Some(max_length) = take_option(args, "max_length") {
+        let max_length = MaxLength::parse(&max_length)?;
+        conf.param_max_lengths
+            .push((param_name.to_string(), max_length));
+    }
+    Ok(())
+}
+
 /// Parse parameter configuration into typed bindings
-fn parse_parameter_bindings(params: &[(String, String)]) -> Result<Vec<ParameterBinding>, String> {
+///
+/// A name ending in `[]` binds a comma-separated variable as a list. Named
+/// parameters listed in `types` bind as that type (each list item, for a list);
+/// the rest bind as text. A pattern or maximum length applies to values taken
+/// from a variable, so a parameter with one must be bound to a variable, and
+/// its default must match the pattern.
+fn parse_parameter_bindings(
+    params: &[(String, String)],
+    types: &[(String, ParamType)],
+    defaults: &[(String, String)],
+    required_params: &[String],
+    patterns: &[(String, String)],
+    max_lengths: &[(String, MaxLength)],
+) -> Result<Vec<ParameterBinding>, String> {
     let mut bindings = Vec::new();
 
-    for (param_name, var_name) in params {
-        let binding = if var_name.starts_with('$') {
+    for (i, (param_name, var_name)) in params.iter().enumerate() {
+        if !param_name.is_empty()
+            && params[..i].iter().any(|(earlier, _)| {
+                earlier.trim_end_matches("[]") == param_name.trim_end_matches("[]")
+            })
+        {
+            return Err(format!(
+                "parameter '{}' is bound more than once",
+                param_name
+            ));
+        }
+
+        let param_type = types
+            .iter()
+            .find(|(name, _)| name == param_name)
+            .map_or(ParamType::Text, |(_, param_type)| *param_type);
+        // "$arg_page|1": a default after the variable
+        let (var_name, inline_default) = match var_name.split_once('|') {
+            Some((variable, default)) if variable.starts_with('$') => {
+                (variable, Some(default.to_string()))
+            }
+            _ => (var_name.as_str(), None),
+        };
+        let default = defaults
+            .iter()
+            .find(|(name, _)| name == param_name)
+            .map(|(_, default)| default.clone());
+        if inline_default.is_some() && default.is_some() {
+            return Err(format!(
+                "parameter '{}' has a default both after '|' and as an argument",
+                param_name
+            ));
+        }
+        let default = inline_default.or(default);
+        if default.is_some() && (param_name.ends_with("[]") || !var_name.starts_with('$')) {
+            return Err(format!(
+                "parameter '{}' takes a default only when bound to a variable",
+                param_name
+            ));
+        }
+        if let Some(default) = &default {
+            domain::typed_value(param_type, default)
+                .map_err(|e| format!("invalid default for '{}': {}", param_name, e))?;
+        }
+        let required = required_params.contains(param_name);
+        if required && (default.is_some() || !var_name.starts_with('$')) {
+            return Err(format!(
+                "required parameter '{}' must be bound to a variable, without a default",
+                param_name
+            ));
+        }
+        let pattern = patterns
+            .iter()
+            .find(|(name, _)| name == param_name)
+            .map(|(_, pattern)| {
+                ParamPattern::parse(pattern)
+                    .map_err(|e| format!("parameter '{}': {}", param_name, e))
+            })
+            .transpose()?;
+        let max_length = max_lengths
+            .iter()
+            .find(|(name, _)| name == param_name)
+            .map(|(_, max_length)| *max_length);
+        if (pattern.is_some() || max_length.is_some())
+            && (param_name.is_empty() || !var_name.starts_with('$'))
+        {
+            return Err(format!(
+                "parameter '{}' takes pattern= and max_length= only when named and bound to a variable",
+                param_name
+            ));
+        }
+        if let (Some(pattern), Some(default)) = (&pattern, &default)
+            && !pattern.is_match(default)
+        {
+            return Err(format!(
+                "default '{}' for '{}' does not match pattern {}",
+                default,
+                param_name,
+                pattern.as_str()
+            ));
+        }
+
+        let binding = if let Some(list_name) = param_name.strip_suffix("[]") {
+            let name = ParamName::parse(list_name)
+                .map_err(|e| format!("invalid param name '{}': {}", param_name, e))?;
+            let variable = NginxVariable::parse(var_name)
+                .map_err(|e| format!("list '{}' must be bound to a variable: {}", param_name, e))?;
+            ParameterBinding::NamedList {
+                name,
+                variable,
+                param_type,
+                required,
+                pattern,
+                max_length,
+            }
+        } else if var_name.starts_with('$') {
             // Variable reference
             let variable = NginxVariable::parse(var_name)
                 .map_err(|e| format!("invalid variable '{}': {}", var_name, e))?;
 
-            if param_name.is_empty() {
-                ParameterBinding::Positional { variable }
-            } else {
-                let name = ParamName::parse(param_name)
-                    .map_err(|e| format!("invalid param name '{}': {}", param_name, e))?;
-                ParameterBinding::Named { name, variable }
+            match (param_name.is_empty(), default) {
+                (true, Some(default)) => ParameterBinding::PositionalDefault {
+                    variable,
+                    default,
+                    max_length,
+                },
+                (true, None) => ParameterBinding::Positional {
+                    variable,
+                    max_length,
+                },
+                (false, default) => {
+                    let name = ParamName::parse(param_name)
+                        .map_err(|e| format!("invalid param name '{}': {}", param_name, e))?;
+                    ParameterBinding::Named {
+                        name,
+                        variable,
+                        param_type,
+                        default,
+                        required,
+                        pattern,
+                        max_length,
+                    }
+                }
             }
         } else {
             // Literal value
             if param_name.is_empty() {
                 ParameterBinding::PositionalLiteral {
-                    value: var_name.clone(),
+                    value: var_name.to_string(),
                 }
             } else {
                 let name = ParamName::parse(param_name)
                     .map_err(|e| format!("invalid param name '{}': {}", param_name, e))?;
+                domain::typed_value(param_type, var_name)
+                    .map_err(|e| format!("invalid value for '{}': {}", param_name, e))?;
                 ParameterBinding::NamedLiteral {
                     name,
-                    value: var_name.clone(),
+                    value: var_name.to_string(),
+                    param_type,
                 }
             }
         };
@@ -74,17 +849,23 @@ fn parse_parameter_bindings(params: &[(String, String)]) -> Result<Vec<Parameter
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::domain::RequestPaths;
+    use crate::types::{
+        AggregateSpec, Expansion, Feed, Filter, HitCounter, NavMenu, Report, RowLimit, SortParam,
+        StreamBuffer, StreamOverflow, TemplateVariants, Theme, Trending, TryStatic, WriteMethod,
+    };
 
     #[test]
     fn test_parse_config_valid() {
         let config = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: "SELECT * FROM books".to_string(),
-            template_path: "list.hbs".to_string(),
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
-        let validated = parse_config(&config, "".into(), "".into()).unwrap();
+        let validated = parse_config(&config).unwrap();
         assert_eq!(validated.db_path.as_str(), "test.db");
         assert!(validated.query.as_str().contains("SELECT"));
     }
@@ -92,13 +873,14 @@ mod tests {
     #[test]
     fn test_parse_config_invalid_query() {
         let config = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: "DELETE FROM books".to_string(),
-            template_path: "list.hbs".to_string(),
+            db_path: Some("test.db".to_string()),
+            query: Some("DELETE FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
-        let result = parse_config(&config, "".into(), "".into());
+        let result = parse_config(&config);
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("SELECT"));
     }
@@ -106,13 +888,14 @@ mod tests {
     #[test]
     fn test_parse_config_invalid_template() {
         let config = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: "SELECT * FROM books".to_string(),
-            template_path: "list.html".to_string(),
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.html".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
-        let result = parse_config(&config, "".into(), "".into());
+        let result = parse_config(&config);
         assert!(result.is_err());
         assert!(result.unwrap_err().contains(".hbs"));
     }
@@ -120,11 +903,11 @@ mod tests {
     #[test]
     fn test_parse_parameter_bindings_positional() {
         let params = vec![(String::new(), "$arg_id".to_string())];
-        let bindings = parse_parameter_bindings(&params).unwrap();
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
 
         assert_eq!(bindings.len(), 1);
         match &bindings[0] {
-            ParameterBinding::Positional { variable } => {
+            ParameterBinding::Positional { variable, .. } => {
                 assert_eq!(variable.name(), "arg_id");
             }
             _ => panic!("expected positional binding"),
@@ -134,11 +917,11 @@ mod tests {
     #[test]
     fn test_parse_parameter_bindings_named() {
         let params = vec![(":book_id".to_string(), "$arg_id".to_string())];
-        let bindings = parse_parameter_bindings(&params).unwrap();
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
 
         assert_eq!(bindings.len(), 1);
         match &bindings[0] {
-            ParameterBinding::Named { name, variable } => {
+            ParameterBinding::Named { name, variable, .. } => {
                 assert_eq!(name.as_str(), ":book_id");
                 assert_eq!(variable.name(), "arg_id");
             }
@@ -149,7 +932,7 @@ mod tests {
     #[test]
     fn test_parse_parameter_bindings_literal() {
         let params = vec![(String::new(), "constant".to_string())];
-        let bindings = parse_parameter_bindings(&params).unwrap();
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
 
         assert_eq!(bindings.len(), 1);
         match &bindings[0] {
@@ -163,7 +946,7 @@ mod tests {
     #[test]
     fn test_parse_parameter_bindings_invalid_variable() {
         let params = vec![(String::new(), "arg_id".to_string())];
-        let bindings = parse_parameter_bindings(&params).unwrap();
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
 
         // Without $, it's treated as a literal
         match &bindings[0] {
@@ -174,6 +957,310 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_parameter_bindings_list() {
+        let params = vec![(":ids[]".to_string(), "$arg_ids".to_string())];
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
+
+        match &bindings[0] {
+            ParameterBinding::NamedList { name, variable, .. } => {
+                assert_eq!(name.as_str(), ":ids");
+                assert_eq!(variable.name(), "arg_ids");
+            }
+            _ => panic!("expected list binding"),
+        }
+
+        let literal = vec![(":ids[]".to_string(), "1,2".to_string())];
+        assert!(parse_parameter_bindings(&literal, &[], &[], &[], &[], &[]).is_err());
+
+        // A name bound twice would be mistaken for a list
+        let twice = vec![
+            (":ids[]".to_string(), "$arg_ids".to_string()),
+            (":ids".to_string(), "$arg_id".to_string()),
+        ];
+        let error = parse_parameter_bindings(&twice, &[], &[], &[], &[], &[]).unwrap_err();
+        assert!(error.contains("bound more than once"));
+    }
+
+    #[test]
+    fn test_parse_parameter_bindings_typed() {
+        let params = vec![
+            (":id".to_string(), "$arg_id".to_string()),
+            (":ids[]".to_string(), "$arg_ids".to_string()),
+            (":limit".to_string(), "10".to_string()),
+            (":title".to_string(), "$arg_title".to_string()),
+        ];
+        let types = vec![
+            (":id".to_string(), ParamType::Integer),
+            (":ids[]".to_string(), ParamType::Integer),
+            (":limit".to_string(), ParamType::Integer),
+        ];
+        let bindings = parse_parameter_bindings(&params, &types, &[], &[], &[], &[]).unwrap();
+        let param_types: Vec<Option<ParamType>> = bindings
+            .iter()
+            .map(|binding| match binding {
+                ParameterBinding::Named { param_type, .. }
+                | ParameterBinding::NamedLiteral { param_type, .. }
+                | ParameterBinding::NamedList { param_type, .. } => Some(*param_type),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            param_types,
+            vec![
+                Some(ParamType::Integer),
+                Some(ParamType::Integer),
+                Some(ParamType::Integer),
+                Some(ParamType::Text)
+            ]
+        );
+
+        // A literal is checked against its type up front
+        let params = vec![(":limit".to_string(), "ten".to_string())];
+        let types = vec![(":limit".to_string(), ParamType::Integer)];
+        let error = parse_parameter_bindings(&params, &types, &[], &[], &[], &[]).unwrap_err();
+        assert!(error.contains("expected int, got 'ten'"));
+    }
+
+    #[test]
+    fn test_parse_parameter_bindings_default() {
+        let params = vec![(":page".to_string(), "$arg_page".to_string())];
+        let types = vec![(":page".to_string(), ParamType::Integer)];
+        let defaults = vec![(":page".to_string(), "1".to_string())];
+        let bindings = parse_parameter_bindings(&params, &types, &defaults, &[], &[], &[]).unwrap();
+        match &bindings[0] {
+            ParameterBinding::Named { default, .. } => assert_eq!(default.as_deref(), Some("1")),
+            _ => panic!("Expected named binding"),
+        }
+
+        // The default must convert to the parameter's type
+        let defaults = vec![(":page".to_string(), "first".to_string())];
+        let error =
+            parse_parameter_bindings(&params, &types, &defaults, &[], &[], &[]).unwrap_err();
+        assert!(error.contains("invalid default for ':page'"));
+
+        // Lists and literals have nothing to fall back from
+        let list = vec![(":ids[]".to_string(), "$arg_ids".to_string())];
+        let defaults = vec![(":ids[]".to_string(), "1".to_string())];
+        assert!(parse_parameter_bindings(&list, &[], &defaults, &[], &[], &[]).is_err());
+        let literal = vec![(":page".to_string(), "2".to_string())];
+        let defaults = vec![(":page".to_string(), "1".to_string())];
+        assert!(parse_parameter_bindings(&literal, &[], &defaults, &[], &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_parameter_bindings_inline_default() {
+        let params = vec![
+            (":page".to_string(), "$arg_page|1".to_string()),
+            (String::new(), "$arg_sort|title".to_string()),
+            (":sep".to_string(), "a|b".to_string()),
+        ];
+        let types = vec![(":page".to_string(), ParamType::Integer)];
+        let bindings = parse_parameter_bindings(&params, &types, &[], &[], &[], &[]).unwrap();
+        match &bindings[0] {
+            ParameterBinding::Named {
+                variable, default, ..
+            } => {
+                assert_eq!(variable.as_str(), "$arg_page");
+                assert_eq!(default.as_deref(), Some("1"));
+            }
+            _ => panic!("Expected named binding"),
+        }
+        match &bindings[1] {
+            ParameterBinding::PositionalDefault {
+                variable, default, ..
+            } => assert_eq!(
+                (variable.as_str(), default.as_str()),
+                ("$arg_sort", "title")
+            ),
+            _ => panic!("Expected positional binding with a default"),
+        }
+        // A literal keeps its '|'
+        match &bindings[2] {
+            ParameterBinding::NamedLiteral { value, .. } => assert_eq!(value, "a|b"),
+            _ => panic!("Expected literal binding"),
+        }
+
+        // The default is checked like any other, and only given once
+        let page = vec![(":page".to_string(), "$arg_page|first".to_string())];
+        let error = parse_parameter_bindings(&page, &types, &[], &[], &[], &[]).unwrap_err();
+        assert!(error.contains("invalid default for ':page'"));
+        let page = vec![(":page".to_string(), "$arg_page|1".to_string())];
+        let defaults = vec![(":page".to_string(), "2".to_string())];
+        let error = parse_parameter_bindings(&page, &[], &defaults, &[], &[], &[]).unwrap_err();
+        assert!(error.contains("both after '|' and as an argument"));
+        let required = vec![":page".to_string()];
+        assert!(parse_parameter_bindings(&page, &[], &[], &required, &[], &[]).is_err());
+        let list = vec![(":ids[]".to_string(), "$arg_ids|1".to_string())];
+        assert!(parse_parameter_bindings(&list, &[], &[], &[], &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_parameter_bindings_required() {
+        let params = vec![
+            (":id".to_string(), "$arg_id".to_string()),
+            (":ids[]".to_string(), "$arg_ids".to_string()),
+            (":q".to_string(), "$arg_q".to_string()),
+        ];
+        let required = vec![":id".to_string(), ":ids[]".to_string()];
+        let bindings = parse_parameter_bindings(&params, &[], &[], &required, &[], &[]).unwrap();
+        let flags: Vec<bool> = bindings
+            .iter()
+            .map(|binding| match binding {
+                ParameterBinding::Named { required, .. }
+                | ParameterBinding::NamedList { required, .. } => *required,
+                _ => panic!("Expected named binding"),
+            })
+            .collect();
+        assert_eq!(flags, vec![true, true, false]);
+
+        // A required parameter needs a variable and no default
+        let defaults = vec![(":id".to_string(), "1".to_string())];
+        let error =
+            parse_parameter_bindings(&params, &[], &defaults, &required, &[], &[]).unwrap_err();
+        assert!(error.contains("required parameter ':id'"));
+        let literal = vec![(":id".to_string(), "1".to_string())];
+        assert!(parse_parameter_bindings(&literal, &[], &[], &required, &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_parameter_bindings_pattern() {
+        let params = vec![
+            (":slug".to_string(), "$arg_slug".to_string()),
+            (":tags[]".to_string(), "$arg_tags".to_string()),
+            (":q".to_string(), "$arg_q".to_string()),
+        ];
+        let patterns = vec![
+            (":slug".to_string(), "^[a-z0-9-]+$".to_string()),
+            (":tags[]".to_string(), "[a-z]+".to_string()),
+        ];
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &patterns, &[]).unwrap();
+        let sources: Vec<Option<&str>> = bindings
+            .iter()
+            .map(|binding| match binding {
+                ParameterBinding::Named { pattern, .. }
+                | ParameterBinding::NamedList { pattern, .. } => {
+                    pattern.as_ref().map(ParamPattern::as_str)
+                }
+                _ => panic!("Expected named binding"),
+            })
+            .collect();
+        assert_eq!(sources, vec![Some("^[a-z0-9-]+$"), Some("[a-z]+"), None]);
+
+        // Defaults must match, and a bad regex is reported with its parameter
+        let defaults = vec![(":slug".to_string(), "Home Page".to_string())];
+        let error =
+            parse_parameter_bindings(&params, &[], &defaults, &[], &patterns, &[]).unwrap_err();
+        assert!(error.contains("does not match"));
+        let bad = vec![(":slug".to_string(), "[a-z".to_string())];
+        let error = parse_parameter_bindings(&params, &[], &[], &[], &bad, &[]).unwrap_err();
+        assert!(error.contains("':slug'"));
+
+        // Literals have nothing to check
+        let literal = vec![(":slug".to_string(), "home".to_string())];
+        assert!(parse_parameter_bindings(&literal, &[], &[], &[], &patterns, &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_config_max_length() {
+        let limit = |n: &str| MaxLength::parse(n).unwrap();
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books WHERE title LIKE :q AND genre = :genre".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            query_params: vec![
+                (":q".to_string(), "$arg_q".to_string()),
+                (":genre".to_string(), "$arg_genre".to_string()),
+                (":kind".to_string(), "novel".to_string()),
+            ],
+            param_max_lengths: vec![(":q".to_string(), limit("100"))],
+            param_max_length: Some(limit("20")),
+            ..Default::default()
+        };
+
+        // A parameter's own limit wins over the location's; literals have none
+        let validated = parse_config(&config).unwrap();
+        let limits: Vec<Option<usize>> = validated
+            .parameters
+            .iter()
+            .map(|binding| match binding {
+                ParameterBinding::Named { max_length, .. } => max_length.map(|m| m.get()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(limits, vec![Some(100), Some(20), None]);
+
+        config.param_max_lengths = vec![(":kind".to_string(), limit("5"))];
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("max_length="));
+    }
+
+    #[test]
+    fn test_parse_config_expansions() {
+        let author = |query: &str| Expansion::parse("author", query, &[]).unwrap();
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            expansions: vec![author("SELECT * FROM authors WHERE id = :author_id")],
+            ..Default::default()
+        };
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.expansions[0].name(), "author");
+
+        config
+            .expansions
+            .push(author("SELECT * FROM people WHERE id = :author_id"));
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("defined twice"));
+    }
+
+    #[test]
+    fn test_parse_config_bind_args() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books WHERE genre = :arg_genre".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            param_max_length: Some(MaxLength::parse("64").unwrap()),
+            bind_args: Some(true),
+            ..Default::default()
+        };
+
+        // The arguments are bound after everything else, under the global cap
+        let validated = parse_config(&config).unwrap();
+        assert!(matches!(
+            validated.parameters.as_slice(),
+            [ParameterBinding::QueryArgs { max_length: Some(limit) }] if limit.get() == 64
+        ));
+
+        config.query_params = vec![(String::new(), "$arg_genre".to_string())];
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("positional"));
+
+        config.bind_args = Some(false);
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.parameters.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_config_reads_body() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books WHERE id = :id".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            query_params: vec![(":id".to_string(), "$arg_id".to_string())],
+            ..Default::default()
+        };
+        let validated = parse_config(&config).unwrap();
+        assert!(!validated.reads_body());
+
+        config
+            .query_params
+            .push((":title".to_string(), "$json_body.book.title".to_string()));
+        let validated = parse_config(&config).unwrap();
+        assert!(validated.reads_body());
+    }
+
     // Additional edge case tests
     #[test]
     fn test_parse_parameter_bindings_multiple_mixed() {
@@ -183,13 +1270,13 @@ mod tests {
             (":status".to_string(), "active".to_string()),
             (String::new(), "100".to_string()),
         ];
-        let bindings = parse_parameter_bindings(&params).unwrap();
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
 
         assert_eq!(bindings.len(), 4);
 
         // First: named variable
         match &bindings[0] {
-            ParameterBinding::Named { name, variable } => {
+            ParameterBinding::Named { name, variable, .. } => {
                 assert_eq!(name.as_str(), ":id");
                 assert_eq!(variable.name(), "arg_id");
             }
@@ -198,7 +1285,7 @@ mod tests {
 
         // Second: positional variable
         match &bindings[1] {
-            ParameterBinding::Positional { variable } => {
+            ParameterBinding::Positional { variable, .. } => {
                 assert_eq!(variable.name(), "arg_limit");
             }
             _ => panic!("expected positional binding"),
@@ -206,7 +1293,7 @@ mod tests {
 
         // Third: named literal
         match &bindings[2] {
-            ParameterBinding::NamedLiteral { name, value } => {
+            ParameterBinding::NamedLiteral { name, value, .. } => {
                 assert_eq!(name.as_str(), ":status");
                 assert_eq!(value, "active");
             }
@@ -225,7 +1312,7 @@ mod tests {
     #[test]
     fn test_parse_parameter_bindings_empty() {
         let params = vec![];
-        let bindings = parse_parameter_bindings(&params).unwrap();
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
         assert_eq!(bindings.len(), 0);
     }
 
@@ -236,7 +1323,7 @@ mod tests {
             (":name".to_string(), "literal2".to_string()),
             (String::new(), "123".to_string()),
         ];
-        let bindings = parse_parameter_bindings(&params).unwrap();
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
 
         assert_eq!(bindings.len(), 3);
         assert!(matches!(
@@ -257,7 +1344,7 @@ mod tests {
             (":name".to_string(), "$arg_b".to_string()),
             (String::new(), "$arg_c".to_string()),
         ];
-        let bindings = parse_parameter_bindings(&params).unwrap();
+        let bindings = parse_parameter_bindings(&params, &[], &[], &[], &[], &[]).unwrap();
 
         assert_eq!(bindings.len(), 3);
         assert!(matches!(bindings[0], ParameterBinding::Positional { .. }));
@@ -268,58 +1355,830 @@ mod tests {
     #[test]
     fn test_parse_config_with_parameters() {
         let config = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: "SELECT * FROM books WHERE id = ?".to_string(),
-            template_path: "book.hbs".to_string(),
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books WHERE id = ?".to_string()),
+            template_path: Some("book.hbs".to_string()),
             query_params: vec![(String::new(), "$arg_id".to_string())],
+            ..Default::default()
         };
 
-        let validated = parse_config(&config, "/var/www".into(), "/books".into()).unwrap();
+        let validated = parse_config(&config).unwrap();
         assert_eq!(validated.parameters.len(), 1);
-        assert_eq!(validated.doc_root, "/var/www");
-        assert_eq!(validated.uri, "/books");
+        let template = validated
+            .resolve_template_path(&RequestPaths::new("/var/www", "/books"))
+            .unwrap();
+        assert_eq!(template.full_path(), "/var/www/books/book.hbs");
+        assert_eq!(validated.parameters[0].variable().unwrap().index(), None);
+
+        // Variables nginx indexed at startup carry their index
+        let indexed = ModuleConfig {
+            variable_indexes: vec![("$arg_id".to_string(), 12)],
+            ..config
+        };
+        let validated = parse_config(&indexed).unwrap();
+        assert_eq!(
+            validated.parameters[0].variable().unwrap().index(),
+            Some(12)
+        );
     }
 
     #[test]
     fn test_parse_config_with_multiple_parameters() {
         let config = ModuleConfig {
-            db_path: "catalog.db".to_string(),
-            query: "SELECT * FROM items WHERE category = :cat AND status = :status".to_string(),
-            template_path: "items.hbs".to_string(),
+            db_path: Some("catalog.db".to_string()),
+            query: Some(
+                "SELECT * FROM items WHERE category = :cat AND status = :status".to_string(),
+            ),
+            template_path: Some("items.hbs".to_string()),
             query_params: vec![
                 (":cat".to_string(), "$arg_category".to_string()),
                 (":status".to_string(), "active".to_string()),
             ],
+            ..Default::default()
         };
 
-        let validated = parse_config(&config, "public".into(), "/api/items".into()).unwrap();
+        let validated = parse_config(&config).unwrap();
         assert_eq!(validated.parameters.len(), 2);
     }
 
     #[test]
     fn test_parse_config_empty_strings() {
         let config = ModuleConfig {
-            db_path: "test.db".to_string(),
-            query: "SELECT 1".to_string(),
-            template_path: "simple.hbs".to_string(),
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT 1".to_string()),
+            template_path: Some("simple.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
+        };
+
+        let validated = parse_config(&config).unwrap();
+        let template = validated
+            .resolve_template_path(&RequestPaths::default())
+            .unwrap();
+        assert_eq!(template.full_path(), "/simple.hbs");
+    }
+
+    #[test]
+    fn test_parse_config_typed_settings() {
+        let config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            max_template_rows: Some(RowLimit::parse("1000").unwrap()),
+            aggregate: Some(AggregateSpec::parse("min,max", "columns=price").unwrap()),
+            ..Default::default()
         };
 
-        let validated = parse_config(&config, "".into(), "".into()).unwrap();
-        assert_eq!(validated.doc_root, "");
-        assert_eq!(validated.uri, "");
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.max_template_rows.unwrap().get(), 1000);
+        assert_eq!(
+            validated.aggregate.unwrap().columns(),
+            &["price".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_parse_config_unset_vs_empty() {
+        let mut config = ModuleConfig {
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            ..Default::default()
+        };
+
+        let result = parse_config(&config);
+        assert_eq!(result.unwrap_err(), "sqlite_db is not set");
+
+        config.db_path = Some(String::new());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("invalid db_path"));
+    }
+
+    #[test]
+    fn test_parameter_pairs() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+        assert_eq!(
+            parameter_pairs(&args(&[":id", "$arg_id", ":genre", "$arg_genre|sf"])),
+            Some(Ok(vec![
+                (":id".to_string(), "$arg_id".to_string()),
+                (":genre".to_string(), "$arg_genre|sf".to_string()),
+            ]))
+        );
+
+        // The single-parameter forms are left to the directive
+        assert_eq!(parameter_pairs(&args(&["$arg_id"])), None);
+        assert_eq!(parameter_pairs(&args(&[":id", "$arg_id"])), None);
+        assert_eq!(
+            parameter_pairs(&args(&[":id", "$arg_id", "int", "1"])),
+            None
+        );
+
+        let error = parameter_pairs(&args(&[":id", "$arg_id", ":genre"])).unwrap();
+        assert!(error.unwrap_err().contains("got 3 arguments"));
+        let error = parameter_pairs(&args(&[
+            ":id",
+            "$arg_id",
+            ":genre",
+            "$arg_genre",
+            "int",
+            "1",
+        ]));
+        assert!(
+            error
+                .unwrap()
+                .unwrap_err()
+                .contains("expected a :name, got 'int'")
+        );
+    }
+
+    #[test]
+    fn test_resolve_query_reference() {
+        let definitions = vec![
+            (
+                "by_genre".to_string(),
+                "SELECT * FROM books WHERE genre = :genre".to_string(),
+            ),
+            ("all".to_string(), "SELECT * FROM books".to_string()),
+        ];
+
+        assert_eq!(
+            resolve_query_reference("@all", &definitions).unwrap(),
+            Some("SELECT * FROM books".to_string())
+        );
+        assert_eq!(
+            resolve_query_reference("SELECT 1", &definitions).unwrap(),
+            None
+        );
+        assert!(
+            resolve_query_reference("@missing", &definitions)
+                .unwrap_err()
+                .contains("'@missing'")
+        );
+    }
+
+    #[test]
+    fn test_validate_query_definitions() {
+        let mut definitions = vec![
+            ("a".to_string(), "SELECT 1".to_string()),
+            ("b".to_string(), "SELECT 2".to_string()),
+        ];
+        assert!(validate_query_definitions(&definitions).is_ok());
+
+        definitions.push(("a".to_string(), "SELECT 3".to_string()));
+        assert!(
+            validate_query_definitions(&definitions)
+                .unwrap_err()
+                .contains("more than once")
+        );
+
+        let definitions = vec![("@a".to_string(), "SELECT 1".to_string())];
+        assert!(validate_query_definitions(&definitions).is_err());
+    }
+
+    #[test]
+    fn test_parse_config_fallback_query() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books WHERE genre = ?".to_string()),
+            fallback_query: Some("SELECT * FROM books ORDER BY rating DESC LIMIT 5".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            ..Default::default()
+        };
+
+        let validated = parse_config(&config).unwrap();
+        assert!(
+            validated
+                .fallback_query
+                .unwrap()
+                .as_str()
+                .contains("rating")
+        );
+
+        config.fallback_query = Some("DELETE FROM books".to_string());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("fallback"));
+    }
+
+    #[test]
+    fn test_parse_config_sources() {
+        let source = |name: &str| {
+            (
+                name.to_string(),
+                "stats.db".to_string(),
+                "SELECT * FROM visits".to_string(),
+            )
+        };
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("dashboard.hbs".to_string()),
+            sources: vec![source("visits"), source("top_books")],
+            ..Default::default()
+        };
+
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.sources.len(), 2);
+        assert_eq!(validated.sources[1].name(), "top_books");
+
+        config.sources.push(source("visits"));
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("more than once"));
+
+        config.sources = vec![source("results")];
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("reserved"));
+    }
+
+    #[test]
+    fn test_parse_config_query_rules() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            query_rules: vec![(
+                "$arg_genre".to_string(),
+                "SELECT * FROM books WHERE genre = :genre".to_string(),
+            )],
+            template_path: Some("list.hbs".to_string()),
+            ..Default::default()
+        };
+
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.query_rules.len(), 1);
+
+        config
+            .query_rules
+            .push(("$arg_q".to_string(), "UPDATE books SET x = 1".to_string()));
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_query_if $arg_q"));
+    }
+
+    #[test]
+    fn test_parse_config_write_query() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            write_queries: vec!["INSERT INTO books (title) VALUES (:title)".to_string()],
+            ..Default::default()
+        };
+
+        // POST is the default write method
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.write_queries.len(), 1);
+        assert_eq!(validated.write_queries[0].0, WriteMethod::Post);
+
+        config.write_methods = vec![WriteMethod::Put, WriteMethod::Delete];
+        let validated = parse_config(&config).unwrap();
+        let methods: Vec<WriteMethod> = validated.write_queries.iter().map(|(m, _)| *m).collect();
+        assert_eq!(methods, vec![WriteMethod::Put, WriteMethod::Delete]);
+
+        // Each method runs every statement, in directive order
+        config
+            .write_queries
+            .push("UPDATE stats SET books = books + 1".to_string());
+        let validated = parse_config(&config).unwrap();
+        let put: Vec<&str> = validated
+            .write_queries
+            .iter()
+            .filter(|(m, _)| *m == WriteMethod::Put)
+            .map(|(_, q)| q.as_str())
+            .collect();
+        assert_eq!(
+            put,
+            vec![
+                "INSERT INTO books (title) VALUES (:title)",
+                "UPDATE stats SET books = books + 1"
+            ]
+        );
+
+        config.write_queries.push("SELECT 1".to_string());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("invalid write query"));
+
+        config.write_queries.clear();
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_methods"));
+    }
+
+    #[test]
+    fn test_parse_config_attachments() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books JOIN stats.loans USING (id)".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            attachments: vec![("stats".to_string(), "stats.db".to_string())],
+            ..Default::default()
+        };
+
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.db_path.attachments()[0].alias(), "stats");
+
+        config
+            .attachments
+            .push(("Stats".to_string(), "other.db".to_string()));
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("more than once"));
+
+        config.attachments = vec![("main".to_string(), "other.db".to_string())];
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("invalid sqlite_attach"));
+    }
+
+    #[test]
+    fn test_parse_config_export() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT '/book/' || id AS uri FROM books".to_string()),
+            export_dir: Some("/srv/export/".to_string()),
+            ..Default::default()
+        };
+
+        // The sitemap needs no template
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.export_dir.as_deref(), Some("/srv/export"));
+
+        config.export_dir = Some("export".to_string());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("absolute path"));
+
+        config.export_dir = Some("/srv/export".to_string());
+        config.write_queries.push("DELETE FROM books".to_string());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("cannot be combined"));
+    }
+
+    #[test]
+    fn test_parse_config_report() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            report: Some(Report::parse(&["books", "/srv/reports"]).unwrap()),
+            ..Default::default()
+        };
+
+        // GET answers with the rows as JSON, so no template is needed
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.report.unwrap().name(), "books");
+
+        config.export_dir = Some("/srv/export".to_string());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_export"));
+
+        config.export_dir = None;
+        config.write_queries.push("DELETE FROM books".to_string());
+        let result = parse_config(&config);
+        assert!(
+            result
+                .unwrap_err()
+                .contains("cannot be combined with writes")
+        );
+    }
+
+    #[test]
+    fn test_parse_config_pagination() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            count_query: Some("SELECT COUNT(*) FROM books".to_string()),
+            ..Default::default()
+        };
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("needs sqlite_paginate"));
+
+        config.pagination = Some(Pagination::parse(&["per_page=10"]).unwrap());
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.pagination.unwrap().per_page().get(), 10);
+        assert!(validated.count_query.is_some());
+
+        let cursor = ["per_page=10", "mode=cursor", "key=id", "secret=s"];
+        config.pagination = Some(Pagination::parse(&cursor).unwrap());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("mode=cursor"));
+        config.count_query = None;
+        assert!(parse_config(&config).is_ok());
+        // sqlite_count set above a cursor location leaves it uncounted
+        config.count = Some(CountStrategy::Estimate(None));
+        let validated = parse_config(&config).unwrap();
+        assert!(validated.count_query.is_none());
+        config.count = None;
+        // Cursor pages keep their key's order
+        config.sort_param = Some(SortParam::parse("$arg_sort", &["allow=title"]).unwrap());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_sort_param"));
+        config.pagination = Some(Pagination::parse(&["per_page=10"]).unwrap());
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.sort.unwrap().allowed(), ["title"]);
+        config.sort_param = None;
+        // Filters bind their values by name
+        config.filters = vec![Filter::parse("year", ">=", "$arg_year", Some("int")).unwrap()];
+        assert_eq!(parse_config(&config).unwrap().filters.len(), 1);
+        config.query_params = vec![(String::new(), "$arg_genre".to_string())];
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_filter"));
+        config.query_params.clear();
+        config.filters.clear();
+
+        config.export_dir = Some("/srv/export".to_string());
+        let result = parse_config(&config);
+        assert!(
+            result
+                .unwrap_err()
+                .contains("cannot be combined with sqlite_paginate")
+        );
+    }
+
+    #[test]
+    fn test_parse_config_count() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM \"books\" WHERE genre = :genre".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            pagination: Some(Pagination::parse(&["per_page=10"]).unwrap()),
+            ..Default::default()
+        };
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.pagination.unwrap().count(), &CountStrategy::Exact);
+
+        config.count = Some(CountStrategy::Estimate(None));
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(
+            validated.count_query.unwrap().as_str(),
+            "SELECT COALESCE(max(rowid), 0) FROM \"books\""
+        );
+        assert_eq!(
+            validated.pagination.unwrap().count(),
+            &CountStrategy::Estimate(None)
+        );
+
+        config.count = Some(CountStrategy::Estimate(Some("book_index".to_string())));
+        let validated = parse_config(&config).unwrap();
+        assert!(
+            validated
+                .count_query
+                .unwrap()
+                .as_str()
+                .ends_with("\"book_index\"")
+        );
+
+        // A query reading from a subquery or another schema names its table
+        config.count = Some(CountStrategy::Estimate(None));
+        for query in [
+            "SELECT * FROM (SELECT * FROM books)",
+            "SELECT * FROM main.books",
+        ] {
+            config.query = Some(query.to_string());
+            let result = parse_config(&config);
+            assert!(result.unwrap_err().contains("table=name"), "{}", query);
+        }
+
+        config.count = Some(CountStrategy::None);
+        let validated = parse_config(&config).unwrap();
+        assert!(validated.count_query.is_none());
+
+        config.count_query = Some("SELECT n FROM book_counts".to_string());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_count exact"));
+    }
+
+    #[test]
+    fn test_parse_config_theme() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            theme: Some(Theme::parse("$cookie_theme", &["default=light"]).unwrap()),
+            ..Default::default()
+        };
+
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.theme.unwrap().default(), "light");
+
+        config.try_static = Some(TryStatic::parse(&["on"]).unwrap());
+        let result = parse_config(&config);
+        assert!(
+            result
+                .unwrap_err()
+                .contains("cannot be combined with sqlite_theme")
+        );
+    }
+
+    #[test]
+    fn test_parse_config_feed() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM posts".to_string()),
+            feed: Some(Feed::parse(&["atom", "content=body"]).unwrap()),
+            ..Default::default()
+        };
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.feed.unwrap().content(), "body");
+
+        config.try_static = Some(TryStatic::parse(&["on"]).unwrap());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_feed"));
+
+        // Turned off, it is as if it were not there
+        config.try_static = None;
+        config.feed = Some(Feed::parse(&["off"]).unwrap());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_template is not set"));
+        config.template_path = Some("posts.hbs".to_string());
+        assert!(parse_config(&config).unwrap().feed.is_none());
+    }
+
+    #[test]
+    fn test_parse_config_stream() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM posts".to_string()),
+            template_path: Some("posts.hbs".to_string()),
+            stream_overflow: Some(StreamOverflow::Drop),
+            ..Default::default()
+        };
+        // An overflow policy alone streams nothing
+        assert!(parse_config(&config).unwrap().stream.is_none());
+
+        config.stream_buffer = Some(StreamBuffer::parse("16k").unwrap());
+        let stream = parse_config(&config).unwrap().stream.unwrap();
+        assert_eq!(stream.size(), 16384);
+        assert_eq!(stream.overflow(), StreamOverflow::Drop);
+
+        config.stream_overflow = None;
+        let stream = parse_config(&config).unwrap().stream.unwrap();
+        assert_eq!(stream.overflow(), StreamOverflow::Wait);
+
+        config.stream_buffer = Some(StreamBuffer::parse("off").unwrap());
+        assert!(parse_config(&config).unwrap().stream.is_none());
+    }
+
+    #[test]
+    fn test_parse_config_template_variants() {
+        let variants = |args: &[&str]| TemplateVariants::parse(args[0], &args[1..]).unwrap();
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            template_variants: Some(variants(&["$bucket", "b=list-b.hbs"])),
+            ..Default::default()
+        };
+        assert!(parse_config(&config).is_ok());
+
+        // Headers and cookies come from the client, so they must be signed
+        config.template_variants = Some(variants(&["$http_x_sqlite_template", "b=list-b.hbs"]));
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("needs secret="));
+        config.template_variants = Some(variants(&[
+            "$http_x_sqlite_template",
+            "secret=k",
+            "b=list-b.hbs",
+        ]));
+        assert!(parse_config(&config).is_ok());
+
+        config.try_static = Some(TryStatic::parse(&["on"]).unwrap());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_template_variant"));
+    }
+
+    #[test]
+    fn test_parse_config_trending() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            trending: Some(Trending::parse(&["limit=3", "max_age=30"]).unwrap()),
+            ..Default::default()
+        };
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("needs sqlite_hit_counter"));
+
+        config.hit_counter = Some(HitCounter::parse(&["table=page_views", "key=$uri"]).unwrap());
+        let validated = parse_config(&config).unwrap();
+        let trending = validated.trending.unwrap();
+        assert_eq!(trending.source().db_path().as_str(), "test.db");
+        assert!(
+            trending
+                .source()
+                .query()
+                .as_str()
+                .contains(r#""page_views""#)
+        );
+        assert_eq!(trending.max_age(), std::time::Duration::from_secs(30));
+
+        // A window needs daily counts
+        config.trending = Some(Trending::parse(&["days=7"]).unwrap());
+        assert!(parse_config(&config).is_err());
+
+        // off turns off an inherited ranking
+        config.trending = Some(Trending::parse(&["off"]).unwrap());
+        config.hit_counter = None;
+        let validated = parse_config(&config).unwrap();
+        assert!(validated.trending.is_none());
+    }
+
+    #[test]
+    fn test_parse_config_nav_menus() {
+        let menu = |name: &str| NavMenu::parse(name, "site.db", "SELECT * FROM menu", &[]).unwrap();
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            nav_menus: vec![menu("main_menu"), menu("footer")],
+            ..Default::default()
+        };
+
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.nav_menus.len(), 2);
+
+        config.nav_menus.push(menu("footer"));
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_nav 'footer'"));
+    }
+
+    #[test]
+    fn test_parse_config_form_template() {
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            template_path: Some("list.hbs".to_string()),
+            form_template: Some("form.hbs".to_string()),
+            ..Default::default()
+        };
+
+        // Only writes can fail validation
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("sqlite_write_query"));
+
+        config
+            .write_queries
+            .push("INSERT INTO books (title) VALUES (:title)".to_string());
+        let validated = parse_config(&config).unwrap();
+        let form = validated
+            .resolve_form_template_path(&RequestPaths::new("/srv", "/books"))
+            .unwrap();
+        assert_eq!(form.full_path(), "/srv/books/form.hbs");
+    }
+
+    #[test]
+    fn test_parse_config_soft_delete() {
+        use crate::rest::TableColumn;
+        use crate::types::{RestTable, SoftDelete};
+
+        let live = |query: &str| {
+            format!(
+                "SELECT * FROM ({}\n) AS live WHERE live.\"deleted_at\" IS NULL",
+                query
+            )
+        };
+        let config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books".to_string()),
+            fallback_query: Some("SELECT * FROM books LIMIT 1".to_string()),
+            query_rules: vec![(
+                "$arg_q".to_string(),
+                "SELECT * FROM books WHERE title = :q".to_string(),
+            )],
+            template_path: Some("list.hbs".to_string()),
+            write_queries: vec![
+                "DELETE FROM books WHERE id = :id".to_string(),
+                "INSERT INTO audit (book_id) VALUES (:id)".to_string(),
+            ],
+            write_methods: vec![WriteMethod::Delete],
+            soft_delete: Some(SoftDelete::parse("column=deleted_at").unwrap()),
+            ..Default::default()
+        };
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.query.as_str(), live("SELECT * FROM books"));
+        assert_eq!(
+            validated.fallback_query.unwrap().as_str(),
+            live("SELECT * FROM books LIMIT 1")
+        );
+        assert_eq!(
+            validated.query_rules[0].query().as_str(),
+            live("SELECT * FROM books WHERE title = :q")
+        );
+        let writes: Vec<&str> = validated
+            .write_queries
+            .iter()
+            .map(|(_, write)| write.as_str())
+            .collect();
+        assert_eq!(
+            writes,
+            vec![
+                "UPDATE books SET \"deleted_at\" = CURRENT_TIMESTAMP WHERE id = :id",
+                "INSERT INTO audit (book_id) VALUES (:id)",
+            ]
+        );
+
+        // sqlite_rest reads and deletes the same way
+        let column = |name: &str| TableColumn {
+            name: name.to_string(),
+            declared_type: "INTEGER".to_string(),
+            primary_key: name == "id",
+        };
+        let config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            rest: Some(RestTable::parse("books", None).unwrap()),
+            rest_columns: vec![column("id"), column("deleted_at")],
+            soft_delete: Some(SoftDelete::parse("column=deleted_at").unwrap()),
+            ..Default::default()
+        };
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.query.as_str(), live("SELECT * FROM \"books\""));
+        let delete = validated
+            .write_queries
+            .iter()
+            .find(|(method, _)| *method == WriteMethod::Delete)
+            .unwrap();
+        assert_eq!(
+            delete.1.as_str(),
+            "UPDATE \"books\" SET \"deleted_at\" = CURRENT_TIMESTAMP WHERE \"id\" = :id"
+        );
+    }
+
+    #[test]
+    fn test_parse_config_rest() {
+        use crate::rest::TableColumn;
+        use crate::types::RestTable;
+
+        let column = |name: &str, primary_key| TableColumn {
+            name: name.to_string(),
+            declared_type: "INTEGER".to_string(),
+            primary_key,
+        };
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            rest: Some(RestTable::parse("books", None).unwrap()),
+            rest_columns: vec![column("id", true), column("year", false)],
+            ..Default::default()
+        };
+
+        // No template needed; every write method is enabled by default
+        let validated = parse_config(&config).unwrap();
+        assert!(validated.template_path.is_none());
+        assert_eq!(validated.query.as_str(), "SELECT * FROM \"books\"");
+        assert_eq!(validated.query_rules.len(), 1);
+        assert_eq!(validated.write_queries.len(), 4);
+        assert_eq!(validated.parameters.len(), 2);
+
+        config.write_methods = vec![WriteMethod::Post];
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.write_queries.len(), 1);
+
+        config.rest_columns.clear();
+        assert!(parse_config(&config).is_err());
+
+        config.rest_columns = vec![column("id", true)];
+        config.query = Some("SELECT 1".to_string());
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("cannot be combined"));
+    }
+
+    #[test]
+    fn test_parse_config_upsert() {
+        use crate::rest::TableColumn;
+
+        let column = |name: &str, primary_key| TableColumn {
+            name: name.to_string(),
+            declared_type: "TEXT".to_string(),
+            primary_key,
+        };
+        let mut config = ModuleConfig {
+            db_path: Some("test.db".to_string()),
+            query: Some("SELECT * FROM books WHERE isbn = :isbn".to_string()),
+            template_path: Some("edit.hbs".to_string()),
+            upsert: Some(UpsertTable::parse("books", "(isbn)").unwrap()),
+            rest_columns: vec![column("isbn", true), column("title", false)],
+            ..Default::default()
+        };
+
+        // The page reads with its own query and POST upserts
+        let validated = parse_config(&config).unwrap();
+        assert_eq!(validated.write_queries.len(), 1);
+        assert_eq!(validated.write_queries[0].0, WriteMethod::Post);
+        assert!(
+            validated.write_queries[0]
+                .1
+                .as_str()
+                .contains("ON CONFLICT")
+        );
+        assert_eq!(validated.parameters.len(), 2);
+
+        config.write_queries = vec!["DELETE FROM books".to_string()];
+        let result = parse_config(&config);
+        assert!(result.unwrap_err().contains("cannot be combined"));
     }
 
     #[test]
     fn test_parse_config_invalid_empty_db() {
         let config = ModuleConfig {
-            db_path: "".to_string(),
-            query: "SELECT 1".to_string(),
-            template_path: "test.hbs".to_string(),
+            db_path: Some("".to_string()),
+            query: Some("SELECT 1".to_string()),
+            template_path: Some("test.hbs".to_string()),
             query_params: vec![],
+            ..Default::default()
         };
 
-        let result = parse_config(&config, "".into(), "".into());
+        let result = parse_config(&config);
         assert!(result.is_err());
     }
 }
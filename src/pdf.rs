@@ -0,0 +1,133 @@
+//! PDF output for `?format=pdf` (the `pdf` cargo feature)
+//!
+//! The rendered page is laid out as text, as a terminal browser would show
+//! it, and set in Courier on A4 pages. Courier is one of the fonts every PDF
+//! reader has, so nothing is embedded, and being monospaced it keeps tables
+//! lined up. Styling and images are dropped.
+
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref, Str};
+
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 50.0;
+const FONT_SIZE: f32 = 10.0;
+const LEADING: f32 = 12.0;
+/// Courier's glyphs are all 0.6 em wide
+const COLUMNS: usize = ((PAGE_WIDTH - 2.0 * MARGIN) / (FONT_SIZE * 0.6)) as usize;
+const LINES_PER_PAGE: usize = ((PAGE_HEIGHT - 2.0 * MARGIN) / LEADING) as usize;
+
+/// Convert a rendered HTML page to a PDF document
+pub fn render(html: &str) -> Result<Vec<u8>, String> {
+    let text = html2text::from_read(html.as_bytes(), COLUMNS)
+        .map_err(|e| format!("cannot lay out page: {}", e))?;
+    let lines: Vec<&str> = text.lines().collect();
+    let pages: Vec<&[&str]> = if lines.is_empty() {
+        vec![&[]]
+    } else {
+        lines.chunks(LINES_PER_PAGE).collect()
+    };
+
+    let catalog_id = Ref::new(1);
+    let tree_id = Ref::new(2);
+    let font_id = Ref::new(3);
+    let font_name = Name(b"F1");
+    let page_ids: Vec<Ref> = (0..pages.len())
+        .map(|i| Ref::new(4 + 2 * i as i32))
+        .collect();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(tree_id);
+    pdf.pages(tree_id)
+        .kids(page_ids.iter().copied())
+        .count(pages.len() as i32);
+    pdf.type1_font(font_id)
+        .base_font(Name(b"Courier"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+
+    for (page_id, lines) in page_ids.iter().zip(pages) {
+        let content_id = Ref::new(page_id.get() + 1);
+        let mut page = pdf.page(*page_id);
+        page.parent(tree_id)
+            .media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT))
+            .contents(content_id);
+        page.resources().fonts().pair(font_name, font_id);
+        page.finish();
+
+        let mut content = Content::new();
+        content
+            .begin_text()
+            .set_font(font_name, FONT_SIZE)
+            .set_leading(LEADING)
+            .next_line(MARGIN, PAGE_HEIGHT - MARGIN - FONT_SIZE);
+        for line in lines {
+            content.show(Str(&win_ansi(line))).next_line_using_leading();
+        }
+        content.end_text();
+        pdf.stream(content_id, &content.finish());
+    }
+
+    Ok(pdf.finish())
+}
+
+/// Encode text for the standard fonts, which only cover Latin-1 and a few
+/// typographic marks; box-drawing characters become ASCII and anything else
+/// becomes `?`
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            ' '..='~' | '\u{a0}'..='\u{ff}' => c as u8,
+            '€' => 0x80,
+            '…' => 0x85,
+            '‘' => 0x91,
+            '’' => 0x92,
+            '“' => 0x93,
+            '”' => 0x94,
+            '•' => 0x95,
+            '–' => 0x96,
+            '—' => 0x97,
+            '─' | '━' | '═' => b'-',
+            '│' | '┃' | '║' => b'|',
+            '\u{2500}'..='\u{257f}' => b'+',
+            _ => b'?',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_render() {
+        let pdf = render("<h1>Invoice 42</h1><p>Total: 9.99 €</p>").unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+        assert!(contains(&pdf, b"/BaseFont /Courier"));
+        assert!(contains(&pdf, b"Invoice 42"));
+        // Lines beyond ASCII are written as hex strings
+        let total: String = win_ansi("Total: 9.99 €")
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        assert!(contains(&pdf, total.as_bytes()));
+        assert!(contains(&pdf, b"/Count 1"));
+
+        // Long pages continue on the next sheet
+        let rows = "row<br>".repeat(LINES_PER_PAGE + 1);
+        assert!(contains(&render(&rows).unwrap(), b"/Count 2"));
+
+        // An empty page is still a document
+        assert!(contains(&render("").unwrap(), b"/Count 1"));
+    }
+
+    #[test]
+    fn test_win_ansi() {
+        assert_eq!(win_ansi("Café – 5€"), b"Caf\xe9 \x96 5\x80");
+        assert_eq!(win_ansi("─┼│ 日"), b"-+| ?");
+    }
+}
@@ -0,0 +1,1233 @@
+//! Startup inspection of configured locations (configuration validation report)
+
+use crate::config::ModuleConfig;
+use crate::domain::{self, RequestPaths, ValidatedConfig};
+use crate::parsing;
+use crate::query;
+use crate::template;
+use crate::types::{DataSource, PageMode, ParameterBinding};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// Outcome of a single startup check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    Passed(String),
+    Failed(String),
+    Skipped(String),
+}
+
+impl Check {
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Check::Failed(_))
+    }
+
+    fn describe(&self, label: &str) -> String {
+        match self {
+            Check::Passed(detail) if detail.is_empty() => format!("{} ok", label),
+            Check::Passed(detail) => format!("{} ok ({})", label, detail),
+            Check::Failed(detail) => format!("{} FAILED ({})", label, detail),
+            Check::Skipped(detail) => format!("{} skipped ({})", label, detail),
+        }
+    }
+}
+
+/// Startup health of one sqlite-serve location
+#[derive(Debug, Clone)]
+pub struct LocationReport {
+    pub location: String,
+    pub config: Check,
+    pub database: Check,
+    pub query: Check,
+    pub template: Check,
+    pub partials: Check,
+    pub parameters: Check,
+    pub sources: Check,
+    pub tables: Check,
+}
+
+impl LocationReport {
+    pub fn is_healthy(&self) -> bool {
+        ![
+            &self.config,
+            &self.database,
+            &self.query,
+            &self.template,
+            &self.partials,
+            &self.parameters,
+            &self.sources,
+            &self.tables,
+        ]
+        .iter()
+        .any(|c| c.is_failed())
+    }
+
+    /// Whether this location must prevent the configuration from loading
+    ///
+    /// A table allowlist that is violated (or cannot be verified) and a
+    /// partial that cannot be found are fatal; every other problem is only reported.
+    pub fn is_fatal(&self) -> bool {
+        self.tables.is_failed() || self.partials.is_failed()
+    }
+
+    /// Render the report as a single log line
+    pub fn summary(&self) -> String {
+        format!(
+            "{}: {}, {}, {}, {}, {}, {}, {}, {}",
+            self.location,
+            self.config.describe("config"),
+            self.database.describe("database"),
+            self.query.describe("query"),
+            self.template.describe("template"),
+            self.partials.describe("partials"),
+            self.parameters.describe("parameters"),
+            self.sources.describe("sources"),
+            self.tables.describe("tables"),
+        )
+    }
+}
+
+/// Inspect a location's merged configuration: open the database, prepare the
+/// query and compile the template, without executing anything.
+///
+/// `location` doubles as the URI used for template resolution; locations whose
+/// name is not a literal path (regex or named locations) skip the template check.
+/// When `sqlite_allow_tables` is configured, the tables check fails unless the
+/// query could be prepared and every table it reads is allowlisted.
+pub fn inspect_location(
+    location: &str,
+    config: &ModuleConfig,
+    doc_root: &str,
+    global_template_dir: Option<&str>,
+) -> LocationReport {
+    let allowed = &config.allowed_tables;
+    let unverified = |reason: &str| {
+        if allowed.is_empty() {
+            Check::Skipped("no allowlist".to_string())
+        } else {
+            Check::Failed(format!("cannot verify: {}", reason))
+        }
+    };
+    let skipped = || Check::Skipped("invalid config".to_string());
+    let mut report = LocationReport {
+        location: location.to_string(),
+        config: Check::Passed(String::new()),
+        database: skipped(),
+        query: skipped(),
+        template: skipped(),
+        partials: skipped(),
+        parameters: skipped(),
+        sources: skipped(),
+        tables: unverified("invalid config"),
+    };
+
+    let paths = RequestPaths::new(doc_root, location);
+    let validated = match parsing::parse_config(config) {
+        Ok(validated) => validated,
+        Err(e) => {
+            report.config = Check::Failed(e);
+            return report;
+        }
+    };
+
+    if validated.template_path.is_none() {
+        report.template = Check::Skipped("none, JSON only".to_string());
+        report.partials = Check::Skipped("none, JSON only".to_string());
+    } else if let Some(resolved) = validated
+        .resolve_template_path(&paths)
+        .filter(|_| location.starts_with('/'))
+    {
+        match template::compile_template_file(resolved.full_path()) {
+            Ok(()) => {
+                report.template = Check::Passed(String::new());
+                let search_dirs: Vec<&str> = global_template_dir
+                    .into_iter()
+                    .chain([resolved.directory()])
+                    .collect();
+                report.partials = check_partials(resolved.full_path(), &search_dirs);
+            }
+            Err(e) => {
+                report.template = Check::Failed(e.summary());
+                report.partials = Check::Skipped("template unavailable".to_string());
+            }
+        }
+    } else {
+        report.template = Check::Skipped("not a literal path".to_string());
+        report.partials = Check::Skipped("not a literal path".to_string());
+    }
+
+    for extra in [
+        validated.resolve_form_template_path(&paths),
+        validated.resolve_email_template_path(&paths),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(validated.resolve_variant_template_paths(&paths))
+    .filter(|_| location.starts_with('/'))
+    {
+        if let Err(e) = template::compile_template_file(extra.full_path()) {
+            report.template = Check::Failed(e.summary());
+        }
+    }
+
+    let menu_sources = validated
+        .nav_menus
+        .iter()
+        .chain(validated.trending.as_ref())
+        .map(|menu| menu.source());
+    let all_sources: Vec<&DataSource> = validated.sources.iter().chain(menu_sources).collect();
+    let (sources, source_tables) = check_sources(&all_sources);
+    report.sources = sources;
+
+    if config.database_per_request() {
+        report.database = Check::Skipped("chosen per request".to_string());
+        report.query = Check::Skipped("database chosen per request".to_string());
+        report.parameters = Check::Skipped("database chosen per request".to_string());
+        report.tables = unverified("database chosen per request");
+        return report;
+    }
+    let conn = match query::open_read_only(&validated.db_path) {
+        Ok(conn) => conn,
+        Err(e) => {
+            report.database = Check::Failed(format!("{}: {}", validated.db_path.as_str(), e));
+            report.query = Check::Skipped("database unreachable".to_string());
+            report.parameters = Check::Skipped("database unreachable".to_string());
+            report.tables = unverified("database unreachable");
+            return report;
+        }
+    };
+    report.database = Check::Passed(String::new());
+
+    match query::prepare_query(&conn, validated.query.as_str()) {
+        Ok(_) => {
+            report.query = check_alternative_queries(&conn, &validated);
+            report.parameters = check_parameters(&conn, &validated);
+            let queries: Vec<&str> = [
+                Some(&validated.query),
+                validated.fallback_query.as_ref(),
+                validated.count_query.as_ref(),
+                validated
+                    .breadcrumbs
+                    .as_ref()
+                    .and_then(|b| b.labels_query()),
+            ]
+            .into_iter()
+            .flatten()
+            .chain(validated.query_rules.iter().map(|rule| rule.query()))
+            .chain(validated.expansions.iter().map(|e| e.batched()))
+            .map(|q| q.as_str())
+            .chain(distinct_writes(&validated).into_iter().map(|(_, sql)| sql))
+            .collect();
+            report.tables = if allowed.is_empty() {
+                Check::Skipped("no allowlist".to_string())
+            } else {
+                match (referenced_tables_of_all(&conn, &queries), source_tables) {
+                    (Ok(mut tables), Ok(from_sources)) => {
+                        merge_tables(&mut tables, from_sources);
+                        check_allowed_tables(&tables, allowed)
+                    }
+                    (Err(e), _) => unverified(&e.to_string()),
+                    (_, Err(e)) => unverified(&e),
+                }
+            };
+        }
+        Err(e) => {
+            report.query = Check::Failed(e.to_string());
+            report.parameters = Check::Skipped("query not prepared".to_string());
+            report.tables = unverified("query not prepared");
+        }
+    }
+
+    report
+}
+
+/// Follow `{{> partial}}` references from the main template through the
+/// template directories (later directories override earlier ones, as at render
+/// time), reporting every partial that cannot be found.
+fn check_partials(template_path: &str, search_dirs: &[&str]) -> Check {
+    let mut available: HashMap<String, PathBuf> = HashMap::new();
+    for dir in search_dirs {
+        match template::template_files_in_dir(dir) {
+            Ok(files) => available.extend(files),
+            Err(e) => return Check::Failed(format!("{}: {}", dir, e)),
+        }
+    }
+
+    let mut pending = vec![PathBuf::from(template_path)];
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    let mut missing = Vec::new();
+
+    while let Some(path) = pending.pop() {
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        let references = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| template::partial_references(&source));
+        let references = match references {
+            Ok(references) => references,
+            Err(e) => return Check::Failed(format!("{}: {}", path.display(), e)),
+        };
+        for name in references {
+            match available.get(&name) {
+                Some(partial) => pending.push(partial.clone()),
+                None => missing.push(format!("'{}' referenced by {}", name, path.display())),
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        Check::Passed(format!("{} resolved", visited.len() - 1))
+    } else {
+        Check::Failed(format!("missing partial {}", missing.join("; ")))
+    }
+}
+
+/// Prepare the fallback query, every `sqlite_query_if` query and the write statements
+///
+/// The fallback query runs without parameters, so it must prepare with none.
+fn check_alternative_queries(conn: &rusqlite::Connection, validated: &ValidatedConfig) -> Check {
+    let mut problems = Vec::new();
+    let mut notes = Vec::new();
+
+    if let Some(fallback) = &validated.fallback_query {
+        match query::prepare_query(conn, fallback.as_str()) {
+            Ok(0) => notes.push("with fallback".to_string()),
+            Ok(expected) => problems.push(format!(
+                "fallback query expects {} parameter(s), none are bound",
+                expected
+            )),
+            Err(e) => problems.push(format!("fallback query: {}", e)),
+        }
+    }
+
+    if let Some(pagination) = &validated.pagination {
+        match domain::page_queries(validated, pagination, &domain::PagePosition::Number(1)) {
+            Ok((_, Some(count))) => match query::prepare_query(conn, count.as_str()) {
+                Ok(_) => notes.push(format!("{} per page", pagination.per_page().get())),
+                Err(e) => problems.push(format!("count query: {}", e)),
+            },
+            // A cursor page's query orders on the key, which must be a result
+            // column (SQLite would take an unknown quoted name as a string)
+            Ok((paged, None)) => match query::column_names(conn, paged.as_str()) {
+                Ok(columns) => match pagination.mode() {
+                    PageMode::Cursor(key) if !columns.iter().any(|c| c == key.column()) => problems
+                        .push(format!(
+                            "cursor key {} is not a result column",
+                            key.column()
+                        )),
+                    _ => notes.push(format!(
+                        "{} per page by cursor",
+                        pagination.per_page().get()
+                    )),
+                },
+                Err(e) => problems.push(format!("cursor query: {}", e)),
+            },
+            Err(e) => problems.push(format!("count query: {}", e)),
+        }
+    }
+
+    if let Some(breadcrumbs) = &validated.breadcrumbs {
+        match breadcrumbs.labels_query() {
+            Some(labels) => match query::prepare_query(conn, labels.as_str()) {
+                Ok(_) => notes.push("labelled breadcrumbs".to_string()),
+                Err(e) => problems.push(format!("breadcrumb labels query: {}", e)),
+            },
+            None => notes.push("breadcrumbs".to_string()),
+        }
+    }
+
+    // Hits are written in the background, so a missing table would only show
+    // up in the worker's exit report
+    if let Some(counter) = &validated.hit_counter {
+        let tables = [
+            Some(format!("SELECT key, hits FROM \"{}\"", counter.table())),
+            counter
+                .daily()
+                .map(|daily| format!("SELECT key, day, hits FROM \"{}\"", daily)),
+        ];
+        let failures: Vec<String> = tables
+            .iter()
+            .flatten()
+            .filter_map(|sql| query::prepare_query(conn, sql).err())
+            .map(|e| format!("hit counter: {}", e))
+            .collect();
+        if failures.is_empty() {
+            notes.push(format!("hits counted in {}", counter.table()));
+        }
+        problems.extend(failures);
+    }
+    if validated.trending.is_some() {
+        notes.push("trending".to_string());
+    }
+    if !validated.expansions.is_empty() {
+        let failures: Vec<String> = validated
+            .expansions
+            .iter()
+            .filter_map(|expansion| {
+                query::prepare_query(conn, expansion.batched().as_str())
+                    .err()
+                    .map(|e| format!("sqlite_expand {}: {}", expansion.name(), e))
+            })
+            .collect();
+        if failures.is_empty() {
+            let names: Vec<&str> = validated.expansions.iter().map(|e| e.name()).collect();
+            notes.push(format!("expands {}", names.join(", ")));
+        }
+        problems.extend(failures);
+    }
+    if let Some(memo) = &validated.memo_table {
+        let sql = format!("SELECT key, payload, stored_at FROM \"{}\"", memo.table());
+        match query::prepare_query(conn, &sql) {
+            Ok(_) => notes.push(format!("responses kept in {}", memo.table())),
+            Err(e) => problems.push(format!("memo table: {}", e)),
+        }
+    }
+
+    for rule in &validated.query_rules {
+        if let Err(e) = query::prepare_query(conn, rule.query().as_str()) {
+            problems.push(format!(
+                "sqlite_query_if {}: {}",
+                rule.variable().as_str(),
+                e
+            ));
+        }
+    }
+    if !validated.query_rules.is_empty() {
+        notes.push(format!("{} rule(s)", validated.query_rules.len()));
+    }
+
+    for (methods, sql) in distinct_writes(validated) {
+        if let Err(e) = query::prepare_query(conn, sql) {
+            problems.push(format!("{} write: {}", methods, e));
+        }
+    }
+    if !validated.write_queries.is_empty() {
+        let mut methods: Vec<&str> = Vec::new();
+        for (method, _) in &validated.write_queries {
+            if !methods.contains(&method.as_str()) {
+                methods.push(method.as_str());
+            }
+        }
+        notes.push(format!("writes on {}", methods.join(" ")));
+    }
+    if validated.batch {
+        notes.push("batch".to_string());
+    }
+    if validated.honeypot.is_some() {
+        notes.push("honeypot".to_string());
+    }
+    if let Some(captcha) = &validated.captcha {
+        notes.push(format!("{} captcha", captcha.provider().as_str()));
+    }
+    if let Some(try_static) = validated.try_static {
+        notes.push(if try_static.save() {
+            "static pages (saved)".to_string()
+        } else {
+            "static pages".to_string()
+        });
+    }
+    if let Some(directory) = &validated.export_dir {
+        notes.push(format!("exports to {}", directory));
+    }
+    if let Some(report) = &validated.report {
+        notes.push(format!(
+            "report {} to {}",
+            report.name(),
+            report.path().display()
+        ));
+    }
+    if let Some(notification) = &validated.notify_email {
+        notes.push(format!("email to {}", notification.to().join(" ")));
+    }
+    if !validated.nav_menus.is_empty() {
+        let names: Vec<&str> = validated.nav_menus.iter().map(|m| m.name()).collect();
+        notes.push(format!("nav {}", names.join(" ")));
+    }
+
+    if problems.is_empty() {
+        Check::Passed(notes.join(", "))
+    } else {
+        Check::Failed(problems.join("; "))
+    }
+}
+
+/// Open each `sqlite_source` and `sqlite_nav` database and prepare its query
+/// (neither takes parameters), also collecting the tables they read for the
+/// allowlist check
+fn check_sources(sources: &[&DataSource]) -> (Check, Result<Vec<String>, String>) {
+    if sources.is_empty() {
+        return (Check::Skipped("none".to_string()), Ok(Vec::new()));
+    }
+
+    let mut problems = Vec::new();
+    let mut tables: Vec<String> = Vec::new();
+    let mut unverified = None;
+    for source in sources {
+        let prepared = query::open_read_only(source.db_path().as_str()).and_then(|conn| {
+            let expected = query::prepare_query(&conn, source.query().as_str())?;
+            let read = query::referenced_tables(&conn, source.query().as_str())?;
+            Ok((expected, read))
+        });
+        match prepared {
+            Ok((0, read)) => merge_tables(&mut tables, read),
+            Ok((expected, _)) => problems.push(format!(
+                "{}: expects {} parameter(s), none are bound",
+                source.name(),
+                expected
+            )),
+            Err(e) => {
+                problems.push(format!("{}: {}", source.name(), e));
+                unverified.get_or_insert(format!("source {}: {}", source.name(), e));
+            }
+        }
+    }
+
+    let check = if problems.is_empty() {
+        Check::Passed(format!("{} ready", sources.len()))
+    } else {
+        Check::Failed(problems.join("; "))
+    };
+    (check, unverified.map_or(Ok(tables), Err))
+}
+
+/// Union of the tables read by several queries, in first-seen order
+fn referenced_tables_of_all(
+    conn: &rusqlite::Connection,
+    queries: &[&str],
+) -> rusqlite::Result<Vec<String>> {
+    let mut tables: Vec<String> = Vec::new();
+    for sql in queries {
+        merge_tables(&mut tables, query::referenced_tables(conn, sql)?);
+    }
+    Ok(tables)
+}
+
+/// Append tables not already present (table names are case-insensitive)
+fn merge_tables(tables: &mut Vec<String>, more: Vec<String>) {
+    for table in more {
+        if !tables.iter().any(|t| t.eq_ignore_ascii_case(&table)) {
+            tables.push(table);
+        }
+    }
+}
+
+/// Each distinct write statement with the methods that run it (e.g. "POST PUT")
+fn distinct_writes(validated: &ValidatedConfig) -> Vec<(String, &str)> {
+    let mut writes: Vec<(String, &str)> = Vec::new();
+    for (method, write_query) in &validated.write_queries {
+        match writes
+            .iter_mut()
+            .find(|(_, sql)| *sql == write_query.as_str())
+        {
+            Some((methods, _)) => {
+                methods.push(' ');
+                methods.push_str(method.as_str());
+            }
+            None => writes.push((method.as_str().to_string(), write_query.as_str())),
+        }
+    }
+    writes
+}
+
+/// Check the bindings against the location's query, every `sqlite_query_if`
+/// query and the write statements (all of them share the same `sqlite_param` bindings)
+fn check_parameters(conn: &rusqlite::Connection, validated: &ValidatedConfig) -> Check {
+    let named: Vec<&str> = validated
+        .parameters
+        .iter()
+        .filter_map(|binding| match binding {
+            ParameterBinding::Named { name, .. }
+            | ParameterBinding::NamedLiteral { name, .. }
+            | ParameterBinding::OptionalNamed { name, .. }
+            | ParameterBinding::NamedList { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let bind_args = validated
+        .parameters
+        .iter()
+        .any(|binding| matches!(binding, ParameterBinding::QueryArgs { .. }));
+
+    let queries = std::iter::once((None, validated.query.as_str()))
+        .chain(validated.query_rules.iter().map(|rule| {
+            (
+                Some(format!("sqlite_query_if {}", rule.variable().as_str())),
+                rule.query().as_str(),
+            )
+        }))
+        .chain(
+            distinct_writes(validated)
+                .into_iter()
+                .map(|(methods, sql)| (Some(format!("{} write", methods)), sql)),
+        );
+    let mut problems = Vec::new();
+    for (label, sql) in queries {
+        let check = if named.is_empty() && !bind_args {
+            query::prepare_query(conn, sql)
+                .map(|expected| check_parameter_count(expected, validated.parameters.len()))
+        } else {
+            query::placeholder_names(conn, sql).and_then(|names| {
+                let expected = query::prepare_query(conn, sql)?;
+                Ok(check_named_parameters(expected, &names, &named, bind_args))
+            })
+        };
+        match (check, label) {
+            (Ok(Check::Failed(detail)), None) => problems.push(detail),
+            (Ok(Check::Failed(detail)), Some(label)) => {
+                problems.push(format!("{}: {}", label, detail))
+            }
+            // Queries that fail to prepare are reported by the query check
+            _ => {}
+        }
+    }
+
+    if problems.is_empty() {
+        Check::Passed(format!("{} bound", validated.parameters.len()))
+    } else {
+        Check::Failed(problems.join("; "))
+    }
+}
+
+/// Compare the number of SQL placeholders against configured bindings
+fn check_parameter_count(expected: usize, bound: usize) -> Check {
+    if expected == bound {
+        Check::Passed(format!("{} bound", bound))
+    } else {
+        Check::Failed(format!("query expects {}, {} bound", expected, bound))
+    }
+}
+
+/// With named bindings, every placeholder must be named and bound; bindings the
+/// query does not use are fine (they may serve another `sqlite_query_if` query).
+/// With `sqlite_bind_args`, `:arg_` placeholders are bound by the request.
+fn check_named_parameters(
+    expected: usize,
+    placeholders: &[String],
+    bound: &[&str],
+    bind_args: bool,
+) -> Check {
+    if placeholders.len() < expected {
+        return Check::Failed("query mixes ? placeholders with named parameters".to_string());
+    }
+    let unbound: Vec<&str> = placeholders
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !(bound.contains(name) || bind_args && name.starts_with(":arg_")))
+        .collect();
+    if unbound.is_empty() {
+        Check::Passed(format!("{} bound", bound.len()))
+    } else {
+        Check::Failed(format!("query expects {}, not bound", unbound.join(", ")))
+    }
+}
+
+/// Verify every table the query reads is on the location's allowlist
+fn check_allowed_tables(referenced: &[String], allowed: &[String]) -> Check {
+    let denied: Vec<&str> = referenced
+        .iter()
+        .filter(|table| !allowed.iter().any(|a| a.eq_ignore_ascii_case(table)))
+        .map(String::as_str)
+        .collect();
+
+    if denied.is_empty() {
+        Check::Passed(referenced.join(" "))
+    } else {
+        Check::Failed(format!("not allowlisted: {}", denied.join(", ")))
+    }
+}
+
+/// Render the full report as log lines (header first)
+pub fn format_report(reports: &[LocationReport]) -> Vec<String> {
+    let failing = reports.iter().filter(|r| !r.is_healthy()).count();
+    let mut lines = vec![format!(
+        "configuration report: {} location(s), {} with problems",
+        reports.len(),
+        failing
+    )];
+    lines.extend(reports.iter().map(|r| r.summary()));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        Breadcrumbs, DatabaseMap, Expansion, HitCounter, MemoTable, NavMenu, Pagination, Trending,
+        WriteMethod,
+    };
+    use rusqlite::Connection;
+    use std::fs;
+
+    fn config(db_path: &str, query: &str, params: Vec<(String, String)>) -> ModuleConfig {
+        ModuleConfig {
+            db_path: Some(db_path.to_string()),
+            query: Some(query.to_string()),
+            template_path: Some("list.hbs".to_string()),
+            query_params: params,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_parameter_count() {
+        assert!(!check_parameter_count(2, 2).is_failed());
+        assert!(check_parameter_count(2, 1).is_failed());
+    }
+
+    #[test]
+    fn test_check_named_parameters() {
+        let placeholders = vec![":genre".to_string()];
+        assert!(!check_named_parameters(1, &placeholders, &[":genre", ":id"], false).is_failed());
+        assert_eq!(
+            check_named_parameters(1, &placeholders, &[":id"], false),
+            Check::Failed("query expects :genre, not bound".to_string())
+        );
+        assert!(check_named_parameters(2, &placeholders, &[":genre"], false).is_failed());
+
+        // sqlite_bind_args binds :arg_ placeholders
+        let placeholders = vec![":arg_genre".to_string()];
+        assert!(check_named_parameters(1, &placeholders, &[], false).is_failed());
+        assert!(!check_named_parameters(1, &placeholders, &[], true).is_failed());
+    }
+
+    #[test]
+    fn test_inspect_location_query_rules() {
+        let db_path = "/tmp/test_preflight_rules.db";
+        let _ = fs::remove_file(db_path);
+        Connection::open(db_path)
+            .unwrap()
+            .execute_batch("CREATE TABLE books (id INTEGER, genre TEXT);")
+            .unwrap();
+
+        let mut location = config(
+            db_path,
+            "SELECT * FROM books",
+            vec![(":genre".to_string(), "$arg_genre".to_string())],
+        );
+        location.query_rules = vec![(
+            "$arg_genre".to_string(),
+            "SELECT * FROM books WHERE genre = :genre".to_string(),
+        )];
+        let report = inspect_location("@books", &location, "", None);
+        assert!(report.is_healthy(), "{}", report.summary());
+        assert_eq!(report.query, Check::Passed("1 rule(s)".to_string()));
+
+        location.query_rules.push((
+            "$arg_id".to_string(),
+            "SELECT * FROM books WHERE id = :id".to_string(),
+        ));
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.parameters,
+            Check::Failed("sqlite_query_if $arg_id: query expects :id, not bound".to_string())
+        );
+
+        location.query_rules = vec![("$arg_q".to_string(), "SELECT * FROM nope".to_string())];
+        let report = inspect_location("@books", &location, "", None);
+        assert!(report.query.is_failed());
+
+        let _ = fs::remove_file(db_path);
+    }
+
+    #[test]
+    fn test_check_allowed_tables() {
+        let allowed = vec!["books".to_string(), "Authors".to_string()];
+        let ok = check_allowed_tables(&["books".to_string(), "authors".to_string()], &allowed);
+        assert_eq!(ok, Check::Passed("books authors".to_string()));
+
+        let denied = check_allowed_tables(&["books".to_string(), "users".to_string()], &allowed);
+        assert_eq!(denied, Check::Failed("not allowlisted: users".to_string()));
+    }
+
+    #[test]
+    fn test_inspect_location_table_allowlist() {
+        let db_path = "/tmp/test_preflight_allowlist.db";
+        let _ = fs::remove_file(db_path);
+        {
+            let conn = Connection::open(db_path).unwrap();
+            conn.execute_batch("CREATE TABLE books (id INTEGER); CREATE TABLE users (id INTEGER);")
+                .unwrap();
+        }
+
+        let mut allowed = config(db_path, "SELECT * FROM books", vec![]);
+        allowed.allowed_tables = vec!["books".to_string()];
+        let report = inspect_location("@books", &allowed, "", None);
+        assert!(!report.is_fatal(), "{}", report.summary());
+
+        let mut violating = config(db_path, "SELECT * FROM books, users", vec![]);
+        violating.allowed_tables = vec!["books".to_string()];
+        let report = inspect_location("@books", &violating, "", None);
+        assert!(report.is_fatal());
+        assert_eq!(
+            report.tables,
+            Check::Failed("not allowlisted: users".to_string())
+        );
+
+        let unrestricted = inspect_location(
+            "@books",
+            &config(db_path, "SELECT * FROM users", vec![]),
+            "",
+            None,
+        );
+        assert!(!unrestricted.is_fatal());
+        assert!(matches!(unrestricted.tables, Check::Skipped(_)));
+
+        let _ = fs::remove_file(db_path);
+    }
+
+    #[test]
+    fn test_inspect_location_pagination() {
+        let db_path = "/tmp/test_preflight_pagination.db";
+        let _ = fs::remove_file(db_path);
+        Connection::open(db_path)
+            .unwrap()
+            .execute_batch("CREATE TABLE books (id INTEGER); CREATE TABLE users (id INTEGER);")
+            .unwrap();
+
+        let mut location = config(db_path, "SELECT * FROM books ORDER BY id", vec![]);
+        location.pagination = Some(Pagination::parse(&["per_page=20"]).unwrap());
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(report.query, Check::Passed("20 per page".to_string()));
+
+        location.count_query = Some("SELECT COUNT(*) FROM nowhere".to_string());
+        let report = inspect_location("@books", &location, "", None);
+        assert!(matches!(&report.query, Check::Failed(d) if d.starts_with("count query")));
+
+        location.count_query = None;
+        location.breadcrumbs = Some(
+            Breadcrumbs::parse(&[
+                "on",
+                "labels_query=SELECT id FROM books WHERE id = :segment",
+            ])
+            .unwrap(),
+        );
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.query,
+            Check::Passed("20 per page, labelled breadcrumbs".to_string())
+        );
+
+        location.hit_counter = Some(
+            HitCounter::parse(&["table=page_views", "key=$uri", "daily=page_views_daily"]).unwrap(),
+        );
+        let report = inspect_location("@books", &location, "", None);
+        assert!(matches!(&report.query, Check::Failed(d) if d.contains("hit counter")));
+        Connection::open(db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE page_views (key TEXT PRIMARY KEY, hits INTEGER);
+                 CREATE TABLE page_views_daily (key TEXT, day TEXT, hits INTEGER,
+                                                PRIMARY KEY (key, day));",
+            )
+            .unwrap();
+        location.trending = Some(Trending::parse(&["days=7"]).unwrap());
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.query,
+            Check::Passed(
+                "20 per page, labelled breadcrumbs, hits counted in page_views, trending"
+                    .to_string()
+            )
+        );
+        assert!(matches!(report.sources, Check::Passed(_)));
+        location.hit_counter = None;
+        location.trending = None;
+
+        location.memo_table = Some(MemoTable::parse(&["books_memo"]).unwrap());
+        let report = inspect_location("@books", &location, "", None);
+        assert!(matches!(&report.query, Check::Failed(d) if d.starts_with("memo table")));
+        Connection::open(db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE books_memo (key TEXT PRIMARY KEY, payload TEXT, stored_at INTEGER);",
+            )
+            .unwrap();
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.query,
+            Check::Passed(
+                "20 per page, labelled breadcrumbs, responses kept in books_memo".to_string()
+            )
+        );
+        location.memo_table = None;
+
+        location.expansions = vec![
+            Expansion::parse("author", "SELECT * FROM authors WHERE id = :author_id", &[]).unwrap(),
+        ];
+        let report = inspect_location("@books", &location, "", None);
+        assert!(matches!(&report.query, Check::Failed(d) if d.contains("sqlite_expand author")));
+        location.expansions = vec![
+            Expansion::parse("same_author", "SELECT * FROM books WHERE id = :id", &[]).unwrap(),
+        ];
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.query,
+            Check::Passed("20 per page, labelled breadcrumbs, expands same_author".to_string())
+        );
+        location.expansions = vec![];
+
+        // The allowlist covers the count query too
+        location.count_query = Some("SELECT COUNT(*) FROM users".to_string());
+        location.allowed_tables = vec!["books".to_string()];
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.tables,
+            Check::Failed("not allowlisted: users".to_string())
+        );
+
+        let _ = fs::remove_file(db_path);
+    }
+
+    #[test]
+    fn test_inspect_location_fallback_query() {
+        let db_path = "/tmp/test_preflight_fallback.db";
+        let _ = fs::remove_file(db_path);
+        {
+            let conn = Connection::open(db_path).unwrap();
+            conn.execute_batch("CREATE TABLE books (id INTEGER); CREATE TABLE users (id INTEGER);")
+                .unwrap();
+        }
+
+        let mut location = config(db_path, "SELECT * FROM books", vec![]);
+        location.fallback_query = Some("SELECT * FROM books LIMIT 5".to_string());
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(report.query, Check::Passed("with fallback".to_string()));
+
+        location.fallback_query = Some("SELECT * FROM books WHERE id = ?".to_string());
+        let report = inspect_location("@books", &location, "", None);
+        assert!(report.query.is_failed());
+
+        // The allowlist covers the fallback query too
+        location.fallback_query = Some("SELECT * FROM users".to_string());
+        location.allowed_tables = vec!["books".to_string()];
+        let report = inspect_location("@books", &location, "", None);
+        assert!(report.is_fatal());
+        assert_eq!(
+            report.tables,
+            Check::Failed("not allowlisted: users".to_string())
+        );
+
+        let _ = fs::remove_file(db_path);
+    }
+
+    #[test]
+    fn test_inspect_location_write_query() {
+        let db_path = "/tmp/test_preflight_write.db";
+        let _ = fs::remove_file(db_path);
+        {
+            let conn = Connection::open(db_path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE books (id INTEGER, title TEXT); CREATE TABLE users (id INTEGER);",
+            )
+            .unwrap();
+        }
+
+        let mut location = config(
+            db_path,
+            "SELECT * FROM books",
+            vec![(":title".to_string(), "$arg_title".to_string())],
+        );
+        location.write_queries = vec!["INSERT INTO books (title) VALUES (:title)".to_string()];
+        location.write_methods = vec![WriteMethod::Post, WriteMethod::Put];
+        let report = inspect_location("@books", &location, "", None);
+        assert!(report.is_healthy(), "{}", report.summary());
+        assert_eq!(
+            report.query,
+            Check::Passed("writes on POST PUT".to_string())
+        );
+
+        location.batch = Some(true);
+        location.honeypot = Some(crate::types::Honeypot::parse(&["field=website"]).unwrap());
+        location.notify_email = Some(
+            crate::types::EmailNotification::parse(&["template=mail.hbs", "to=owner@example.com"])
+                .unwrap(),
+        );
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.query,
+            Check::Passed(
+                "writes on POST PUT, batch, honeypot, email to owner@example.com".to_string()
+            )
+        );
+
+        location.write_queries = vec!["UPDATE books SET title = :title WHERE id = :id".to_string()];
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.parameters,
+            Check::Failed("POST PUT write: query expects :id, not bound".to_string())
+        );
+
+        // Tables the write modifies must be allowlisted too
+        location.write_queries = vec!["DELETE FROM users".to_string()];
+        location.allowed_tables = vec!["books".to_string()];
+        let report = inspect_location("@books", &location, "", None);
+        assert_eq!(
+            report.tables,
+            Check::Failed("not allowlisted: users".to_string())
+        );
+
+        let _ = fs::remove_file(db_path);
+    }
+
+    #[test]
+    fn test_inspect_location_rest() {
+        let db_path = "/tmp/test_preflight_rest.db";
+        let _ = fs::remove_file(db_path);
+        let conn = Connection::open(db_path).unwrap();
+        conn.execute_batch("CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);")
+            .unwrap();
+
+        let location = ModuleConfig {
+            db_path: Some(db_path.to_string()),
+            rest: Some(crate::types::RestTable::parse("books", None).unwrap()),
+            rest_columns: query::table_columns(&conn, "books").unwrap(),
+            allowed_tables: vec!["books".to_string()],
+            ..Default::default()
+        };
+        let report = inspect_location("/books", &location, "", None);
+        assert!(report.is_healthy(), "{}", report.summary());
+        assert_eq!(
+            report.template,
+            Check::Skipped("none, JSON only".to_string())
+        );
+        assert_eq!(
+            report.query,
+            Check::Passed("1 rule(s), writes on POST PUT PATCH DELETE".to_string())
+        );
+
+        let _ = fs::remove_file(db_path);
+    }
+
+    #[test]
+    fn test_inspect_location_sources() {
+        let db_path = "/tmp/test_preflight_sources_main.db";
+        let stats_path = "/tmp/test_preflight_sources_stats.db";
+        for (path, ddl) in [
+            (db_path, "CREATE TABLE books (id INTEGER);"),
+            (stats_path, "CREATE TABLE visits (id INTEGER);"),
+        ] {
+            let _ = fs::remove_file(path);
+            Connection::open(path).unwrap().execute_batch(ddl).unwrap();
+        }
+        let source = |query: &str| {
+            (
+                "visits".to_string(),
+                stats_path.to_string(),
+                query.to_string(),
+            )
+        };
+
+        let mut location = config(db_path, "SELECT * FROM books", vec![]);
+        location.sources = vec![source("SELECT * FROM visits")];
+        let report = inspect_location("@dashboard", &location, "", None);
+        assert_eq!(report.sources, Check::Passed("1 ready".to_string()));
+
+        location.sources = vec![source("SELECT * FROM visits WHERE id = ?")];
+        let report = inspect_location("@dashboard", &location, "", None);
+        assert!(report.sources.is_failed());
+        assert!(!report.is_fatal());
+
+        // Source tables count against the allowlist, even in another database
+        location.sources = vec![source("SELECT * FROM visits")];
+        location.allowed_tables = vec!["books".to_string()];
+        let report = inspect_location("@dashboard", &location, "", None);
+        assert_eq!(
+            report.tables,
+            Check::Failed("not allowlisted: visits".to_string())
+        );
+
+        location.sources = vec![source("SELECT * FROM nowhere")];
+        let report = inspect_location("@dashboard", &location, "", None);
+        assert!(report.is_fatal());
+        assert!(matches!(&report.tables, Check::Failed(d) if d.starts_with("cannot verify")));
+
+        // sqlite_nav menus are checked like sources
+        location.sources.clear();
+        location.allowed_tables.clear();
+        location.nav_menus =
+            vec![NavMenu::parse("main_menu", stats_path, "SELECT * FROM menu", &[]).unwrap()];
+        let report = inspect_location("@dashboard", &location, "", None);
+        assert!(matches!(&report.sources, Check::Failed(d) if d.starts_with("main_menu:")));
+
+        let _ = fs::remove_file(db_path);
+        let _ = fs::remove_file(stats_path);
+    }
+
+    #[test]
+    fn test_inspect_location_allowlist_fails_closed() {
+        let mut unreachable = config("/nonexistent/preflight.db", "SELECT * FROM books", vec![]);
+        unreachable.allowed_tables = vec!["books".to_string()];
+        let report = inspect_location("@books", &unreachable, "", None);
+        assert!(report.is_fatal());
+    }
+
+    #[test]
+    fn test_check_partials() {
+        let root = "/tmp/test_preflight_partials";
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(format!("{}/global", root)).unwrap();
+        fs::create_dir_all(format!("{}/books", root)).unwrap();
+        fs::write(format!("{}/global/header.hbs", root), "{{> nav}}").unwrap();
+        fs::write(format!("{}/global/nav.hbs", root), "<nav></nav>").unwrap();
+        fs::write(format!("{}/books/card.hbs", root), "{{title}}").unwrap();
+
+        let main = format!("{}/books/list.hbs", root);
+        fs::write(&main, "{{> header}}{{#each results}}{{> card}}{{/each}}").unwrap();
+        let dirs = [format!("{}/global", root), format!("{}/books", root)];
+        let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+        assert_eq!(
+            check_partials(&main, &dirs),
+            Check::Passed("3 resolved".to_string())
+        );
+
+        // A partial that exists only in a directory that is not searched
+        assert!(check_partials(&main, &dirs[1..]).is_failed());
+
+        fs::write(format!("{}/global/nav.hbs", root), "{{> missing_menu}}").unwrap();
+        match check_partials(&main, &dirs) {
+            Check::Failed(detail) => {
+                assert!(detail.contains("'missing_menu'"));
+                assert!(detail.contains("nav.hbs"));
+            }
+            other => panic!("expected failure, got {:?}", other),
+        }
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_inspect_location_invalid_config() {
+        let report = inspect_location(
+            "/books",
+            &config("test.db", "DELETE FROM x", vec![]),
+            "",
+            None,
+        );
+        assert!(report.config.is_failed());
+        assert!(!report.is_healthy());
+        assert!(matches!(report.database, Check::Skipped(_)));
+    }
+
+    #[test]
+    fn test_inspect_location_unreachable_database() {
+        let report = inspect_location(
+            "/books",
+            &config("/nonexistent/preflight.db", "SELECT 1", vec![]),
+            "",
+            None,
+        );
+        assert!(report.database.is_failed());
+        assert!(matches!(report.query, Check::Skipped(_)));
+    }
+
+    #[test]
+    fn test_inspect_location_per_request_database() {
+        let report = inspect_location(
+            "/books",
+            &config("/srv/tenants/$host.db", "SELECT 1", vec![]),
+            "",
+            None,
+        );
+        assert_eq!(
+            report.database,
+            Check::Skipped("chosen per request".to_string())
+        );
+        assert!(matches!(report.query, Check::Skipped(_)));
+        assert!(!report.tables.is_failed());
+
+        // So is one a sqlite_db_map names
+        let mut mapped = config("/srv/shared.db", "SELECT 1", vec![]);
+        mapped.db_path = None;
+        mapped.db_map = Some(
+            DatabaseMap::parse("/nonexistent/tenants.db", "SELECT path FROM tenants", &[]).unwrap(),
+        );
+        let report = inspect_location("/books", &mapped, "", None);
+        assert_eq!(
+            report.database,
+            Check::Skipped("chosen per request".to_string())
+        );
+
+        // An allowlist cannot be checked without a database
+        let mut allowlisted = config("/srv/tenants/$host.db", "SELECT 1", vec![]);
+        allowlisted.allowed_tables = vec!["books".to_string()];
+        let report = inspect_location("/books", &allowlisted, "", None);
+        assert!(report.is_fatal());
+    }
+
+    #[test]
+    fn test_inspect_location_full_report() {
+        let root = "/tmp/test_preflight_root";
+        let db_path = "/tmp/test_preflight_root/preflight.db";
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(format!("{}/books", root)).unwrap();
+        fs::write(
+            format!("{}/books/list.hbs", root),
+            "{{#each results}}{{/each}}",
+        )
+        .unwrap();
+        {
+            let conn = Connection::open(db_path).unwrap();
+            conn.execute("CREATE TABLE books (id INTEGER, genre TEXT)", [])
+                .unwrap();
+        }
+
+        let healthy = inspect_location(
+            "/books",
+            &config(
+                db_path,
+                "SELECT * FROM books WHERE genre = :genre",
+                vec![(":genre".to_string(), "$arg_genre".to_string())],
+            ),
+            root,
+            None,
+        );
+        assert!(healthy.is_healthy(), "{}", healthy.summary());
+
+        let mismatch = inspect_location(
+            "/books",
+            &config(db_path, "SELECT * FROM books WHERE id = ?", vec![]),
+            root,
+            None,
+        );
+        assert!(mismatch.parameters.is_failed());
+        assert!(!mismatch.template.is_failed());
+
+        let bad_query = inspect_location(
+            "/books",
+            &config(db_path, "SELECT * FROM missing_table", vec![]),
+            root,
+            None,
+        );
+        assert!(bad_query.query.is_failed());
+
+        let lines = format_report(&[healthy, mismatch, bad_query]);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("3 location(s), 2 with problems"));
+        assert!(lines[2].contains("parameters FAILED (query expects 1, 0 bound)"));
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_inspect_location_missing_template() {
+        let report = inspect_location(
+            "/nowhere",
+            &config("/nonexistent/preflight.db", "SELECT 1", vec![]),
+            "/tmp/test_preflight_missing",
+            None,
+        );
+        assert!(report.template.is_failed());
+    }
+
+    #[test]
+    fn test_inspect_location_skips_template_for_regex() {
+        let report = inspect_location(
+            "^/books/(\\d+)$",
+            &config("/nonexistent/preflight.db", "SELECT 1", vec![]),
+            "",
+            None,
+        );
+        assert!(matches!(report.template, Check::Skipped(_)));
+    }
+}
@@ -1,70 +1,941 @@
 //! SQL query execution with parameter binding
 
-use rusqlite::{Connection, Result};
+use crate::domain::{ErrorCode, ParameterSet};
+use crate::functions;
+use crate::history;
+use crate::hits::PageHits;
+use crate::rest::TableColumn;
+use crate::types::{
+    AsOf, Attachment, BlobEncoding, BlobRoute, DatabaseMap, DatabasePath, Report, RowShape,
+};
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Result};
 use serde_json::Value;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// A database file to open, along with the databases attached to it
+pub trait Database {
+    fn path(&self) -> &str;
+
+    fn attachments(&self) -> &[Attachment] {
+        &[]
+    }
+
+    /// Tables read as they were at a past time (`?as_of=`), and that time
+    fn as_of(&self) -> Option<(&[String], &AsOf)> {
+        None
+    }
+}
+
+impl Database for str {
+    fn path(&self) -> &str {
+        self
+    }
+}
+
+impl Database for DatabasePath {
+    fn path(&self) -> &str {
+        self.as_str()
+    }
+
+    fn attachments(&self) -> &[Attachment] {
+        DatabasePath::attachments(self)
+    }
+
+    fn as_of(&self) -> Option<(&[String], &AsOf)> {
+        DatabasePath::as_of(self).map(|as_of| (self.history(), as_of))
+    }
+}
 
 /// Execute a SQL query with parameters and return results as JSON-compatible data
 ///
 /// Supports both positional (?) and named (:name) parameters.
-/// If any parameter has a non-empty name, all parameters are treated as named.
+/// If any parameter has a non-empty name, all parameters are treated as named,
+/// and names the query does not use are skipped so one set of bindings can
+/// serve several alternative queries. A name given more than once is a list
+/// (see [`expand_lists`]). The database is opened read-only, and the rows
+/// are shaped as `rows` says: its hidden columns left out and BLOB values
+/// sent in its encoding.
 pub fn execute_query(
-    db_path: &str,
+    db: &(impl Database + ?Sized),
     query: &str,
-    params: &[(String, String)], // (param_name, value) pairs
+    params: &[(String, Value)], // (param_name, value) pairs
+    rows: &RowShape,
 ) -> Result<Vec<HashMap<String, Value>>> {
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare(query)?;
+    let conn = open(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    query_rows(&conn, query, &sql_values(params), rows)
+}
+
+/// Run a query on an open connection, returning its rows as JSON-compatible
+/// maps shaped as `shape` says
+fn query_rows<T: rusqlite::ToSql>(
+    conn: &Connection,
+    query: &str,
+    params: &[(String, T)],
+    shape: &RowShape,
+) -> Result<Vec<HashMap<String, Value>>> {
+    let blobs = shape.blob_encoding();
+    let (query, params) = expand_lists(query, params);
+    let mut stmt = conn.prepare(&query)?;
 
     let column_count = stmt.column_count();
     let column_names: Vec<String> = (0..column_count)
         .map(|i| stmt.column_name(i).unwrap_or("").to_string())
         .collect();
-
-    // Bind parameters (either positional or named)
-    let has_named_params = params.iter().any(|(name, _)| !name.is_empty());
+    // Text in a column declared JSON is nested as the document it holds
+    let json_columns: Vec<bool> = stmt
+        .columns()
+        .iter()
+        .map(|column| {
+            column
+                .decl_type()
+                .is_some_and(|decl| decl.eq_ignore_ascii_case("json"))
+        })
+        .collect();
+    let hidden_columns: Vec<bool> = column_names.iter().map(|name| shape.hides(name)).collect();
 
     // Convert row to JSON map
     let row_to_map = |row: &rusqlite::Row| -> rusqlite::Result<HashMap<String, Value>> {
         let mut map = HashMap::new();
         for (i, col_name) in column_names.iter().enumerate() {
+            if hidden_columns[i] {
+                continue;
+            }
             let value: Value = match row.get_ref(i)? {
                 rusqlite::types::ValueRef::Null => Value::Null,
                 rusqlite::types::ValueRef::Integer(v) => Value::Number(v.into()),
                 rusqlite::types::ValueRef::Real(v) => serde_json::Number::from_f64(v)
                     .map(Value::Number)
                     .unwrap_or(Value::Null),
+                rusqlite::types::ValueRef::Text(v) if json_columns[i] => serde_json::from_slice(v)
+                    .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(v).to_string())),
                 rusqlite::types::ValueRef::Text(v) => {
                     Value::String(String::from_utf8_lossy(v).to_string())
                 }
-                rusqlite::types::ValueRef::Blob(v) => {
-                    // Convert blob to hex string
-                    let hex_string = v.iter().map(|b| format!("{:02x}", b)).collect::<String>();
-                    Value::String(hex_string)
-                }
+                rusqlite::types::ValueRef::Blob(v) => match blobs {
+                    BlobEncoding::Hex => {
+                        Value::String(v.iter().map(|b| format!("{:02x}", b)).collect())
+                    }
+                    BlobEncoding::Base64 => Value::String(encode_base64(v)),
+                    BlobEncoding::Skip => continue,
+                },
             };
             map.insert(col_name.clone(), value);
         }
         Ok(map)
     };
 
-    let rows = if has_named_params {
-        // Use named parameters
-        let named_params: Vec<(&str, &dyn rusqlite::ToSql)> = params
-            .iter()
-            .map(|(name, value)| (name.as_str(), value as &dyn rusqlite::ToSql))
-            .collect();
-        stmt.query_map(named_params.as_slice(), row_to_map)?
+    let rows = match bind(&stmt, &params) {
+        Bindings::Named(named) => stmt.query_map(named.as_slice(), row_to_map)?,
+        Bindings::Positional(positional) => stmt.query_map(positional.as_slice(), row_to_map)?,
+    };
+
+    rows.collect()
+}
+
+/// Why a write failed; nothing it did is kept
+#[derive(Debug)]
+pub enum WriteError {
+    Sqlite(rusqlite::Error),
+    /// The statements changed more rows than the database's
+    /// `sqlite_mutation_max_rows` allows
+    TooManyRows {
+        changes: usize,
+        max: usize,
+    },
+}
+
+impl WriteError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            WriteError::Sqlite(e) => error_code(e),
+            WriteError::TooManyRows { .. } => ErrorCode::TooManyRows,
+        }
+    }
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::Sqlite(e) => e.fmt(f),
+            WriteError::TooManyRows { changes, max } => write!(
+                f,
+                "the write would change {} rows, more than the {} allowed",
+                changes, max
+            ),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for WriteError {
+    fn from(e: rusqlite::Error) -> Self {
+        WriteError::Sqlite(e)
+    }
+}
+
+/// Execute INSERT, UPDATE or DELETE statements in one transaction, with the
+/// same parameter binding as [`execute_query`], returning the number of rows
+/// changed by all of them and the last inserted rowid
+///
+/// Any failure rolls back every statement, as does changing more than
+/// `max_changes` rows (`sqlite_mutation_max_rows`). The database is opened
+/// read-write but never created: a missing file is an error rather than a
+/// new empty database.
+pub fn execute_write(
+    db: &(impl Database + ?Sized),
+    queries: &[&str],
+    params: &[(String, Value)],
+    max_changes: Option<usize>,
+) -> std::result::Result<(usize, i64), WriteError> {
+    let mut conn = open_read_write(db)?;
+    let tx = conn.transaction()?;
+    let outcome = run_writes(&tx, queries, &sql_values(params), max_changes)?;
+    // Dropping an uncommitted transaction rolls it back
+    tx.commit()?;
+    Ok(outcome)
+}
+
+/// Why a batch failed; nothing it did is kept
+#[derive(Debug)]
+pub struct BatchError {
+    /// The parameter set being run, or `None` if the database itself failed
+    pub set: Option<usize>,
+    pub source: WriteError,
+}
+
+impl From<rusqlite::Error> for BatchError {
+    fn from(source: rusqlite::Error) -> Self {
+        BatchError {
+            set: None,
+            source: source.into(),
+        }
+    }
+}
+
+/// Run a query once per parameter set, inside one read transaction so every
+/// set sees the same snapshot of the database, shaping the rows as `rows` says
+pub fn execute_batch_query(
+    db: &(impl Database + ?Sized),
+    query: &str,
+    sets: &[ParameterSet],
+    rows: &RowShape,
+) -> std::result::Result<Vec<Vec<HashMap<String, Value>>>, BatchError> {
+    let mut conn = open(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let tx = conn.transaction()?;
+    let mut results = Vec::with_capacity(sets.len());
+    for (i, set) in sets.iter().enumerate() {
+        let params = sql_values(set);
+        let shaped = query_rows(&tx, query, &params, rows);
+        results.push(shaped.map_err(|e| at_set(i, e.into()))?);
+    }
+    tx.commit()?;
+    Ok(results)
+}
+
+/// Run write statements once per parameter set, all in one transaction,
+/// returning the changes and last inserted rowid of each run
+///
+/// If any run fails, or changes more than `max_changes` rows, none of them
+/// take effect.
+pub fn execute_batch_write(
+    db: &(impl Database + ?Sized),
+    queries: &[&str],
+    sets: &[ParameterSet],
+    max_changes: Option<usize>,
+) -> std::result::Result<Vec<(usize, i64)>, BatchError> {
+    let mut conn = open_read_write(db)?;
+    let tx = conn.transaction()?;
+    let mut outcomes = Vec::with_capacity(sets.len());
+    for (i, set) in sets.iter().enumerate() {
+        let params = sql_values(set);
+        outcomes.push(run_writes(&tx, queries, &params, max_changes).map_err(|e| at_set(i, e))?);
+    }
+    tx.commit()?;
+    Ok(outcomes)
+}
+
+/// Add `sqlite_hit_counter` hits in one transaction; a page's first hits
+/// insert its row
+///
+/// Each table needs a unique `key` column and an integer `hits` column, and a
+/// daily table a `day` column as well, unique together with `key`; hits count
+/// towards the day they are written (UTC). The flusher runs off the event
+/// loop, so it waits for a locked database.
+pub fn add_hits(db: &str, hits: &[PageHits]) -> Result<()> {
+    let mut conn = open_read_write(db)?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    let tx = conn.transaction()?;
+    for page in hits {
+        // Table names are validated as identifiers when the config is parsed
+        tx.prepare_cached(&format!(
+            "INSERT INTO \"{}\" (key, hits) VALUES (?1, ?2) \
+             ON CONFLICT (key) DO UPDATE SET hits = hits + excluded.hits",
+            page.table
+        ))?
+        .execute(rusqlite::params![page.key, page.hits as i64])?;
+        if let Some(daily) = &page.daily {
+            tx.prepare_cached(&format!(
+                "INSERT INTO \"{}\" (key, day, hits) VALUES (?1, date('now'), ?2) \
+                 ON CONFLICT (key, day) DO UPDATE SET hits = hits + excluded.hits",
+                daily
+            ))?
+            .execute(rusqlite::params![page.key, page.hits as i64])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Prepare a `sqlite_db_map` query on its control database, returning the
+/// names of its parameters
+///
+/// The query must return one column, the database path, and name each of
+/// its parameters so it can be bound to a variable.
+pub fn database_map_parameters(map: &DatabaseMap) -> std::result::Result<Vec<String>, String> {
+    let conn = open_read_only(map.control()).map_err(|e| e.to_string())?;
+    let stmt = conn
+        .prepare(map.query().as_str())
+        .map_err(|e| e.to_string())?;
+    if stmt.column_count() != 1 {
+        return Err(format!(
+            "the query must return one column, the database path, not {}",
+            stmt.column_count()
+        ));
+    }
+    (1..=stmt.parameter_count())
+        .map(|i| {
+            stmt.parameter_name(i)
+                .map(str::to_string)
+                .ok_or_else(|| "parameters must be named, like :host".to_string())
+        })
+        .collect()
+}
+
+/// The database `map`'s query names for its parameters' `values`, or `None`
+/// when it returns no row, or an empty or non-text path
+pub fn mapped_database(map: &DatabaseMap, values: &[String]) -> Result<Option<String>> {
+    let params: Vec<(String, Value)> = map
+        .parameters()
+        .iter()
+        .zip(values)
+        .map(|((name, _), value)| (name.clone(), Value::String(value.clone())))
+        .collect();
+    let rows = execute_query(
+        map.control(),
+        map.query().as_str(),
+        &params,
+        &RowShape::default(),
+    )?;
+    Ok(rows
+        .into_iter()
+        .next()
+        .and_then(|row| row.into_values().next())
+        .and_then(|path| match path {
+            Value::String(path) if !path.is_empty() => Some(path),
+            _ => None,
+        }))
+}
+
+/// Check, without writing, that a `sqlite_history` table's history can be
+/// installed: the table exists, has rowids and no column the history keeps
+pub fn check_history(db: &str, table: &str) -> std::result::Result<(), String> {
+    let conn = open(db, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|e| e.to_string())?;
+    history_migration(&conn, table).map(|_| ())
+}
+
+/// Create or update a `sqlite_history` table's history table and triggers,
+/// in one transaction (see [`history::migration`])
+pub fn install_history(db: &str, table: &str) -> std::result::Result<(), String> {
+    let mut conn = open_read_write(db).map_err(|e| e.to_string())?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))
+        .map_err(|e| e.to_string())?;
+    let tx = conn
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|e| e.to_string())?;
+    for statement in history_migration(&tx, table)? {
+        tx.execute(&statement, []).map_err(|e| e.to_string())?;
+    }
+    tx.commit().map_err(|e| e.to_string())
+}
+
+/// The statements that bring `table`'s history up to date
+fn history_migration(conn: &Connection, table: &str) -> std::result::Result<Vec<String>, String> {
+    let without_rowid: bool = conn
+        .query_row(
+            "SELECT wr FROM pragma_table_list(?1) WHERE schema = 'main'",
+            [table],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?
+        .unwrap_or(false);
+    if without_rowid {
+        return Err(format!(
+            "'{}' is a WITHOUT ROWID table, whose rows the history cannot follow",
+            table
+        ));
+    }
+    let columns = table_columns(conn, table).map_err(|e| e.to_string())?;
+    let recorded: Vec<String> = table_columns(conn, &history::history_table(table))
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|c| c.name)
+        .collect();
+    history::migration(table, &columns, &recorded)
+}
+
+/// A page's hits in its `sqlite_hit_counter` table, 0 before its first flush
+pub fn stored_hits(db: &str, table: &str, key: &str) -> Result<u64> {
+    let conn = open(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let hits: Option<i64> = conn
+        .query_row(
+            &format!("SELECT hits FROM \"{}\" WHERE key = ?1", table),
+            [key],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(hits.unwrap_or(0).max(0) as u64)
+}
+
+/// A response stored in a `sqlite_memo_table` within the last `ttl_secs`
+/// seconds
+///
+/// The table needs a unique `key` column, a `payload` column and an integer
+/// `stored_at` column of Unix seconds.
+pub fn memo_lookup(
+    db: &(impl Database + ?Sized),
+    table: &str,
+    key: &str,
+    ttl_secs: u64,
+) -> Result<Option<String>> {
+    let conn = open(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // Table names are validated as identifiers when the config is parsed
+    conn.query_row(
+        &format!(
+            "SELECT payload FROM \"{}\" \
+             WHERE key = ?1 AND stored_at > CAST(strftime('%s', 'now') AS INTEGER) - ?2",
+            table
+        ),
+        rusqlite::params![key, ttl_secs as i64],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// The blob a `sqlite_blob` table keeps under `hash`, with its content type
+/// if the table has a type column; a text value is sent as its bytes, and
+/// NULL or a number as nothing
+pub fn stored_blob(
+    db: &(impl Database + ?Sized),
+    route: &BlobRoute,
+    hash: &str,
+) -> Result<Option<(Vec<u8>, Option<String>)>> {
+    let conn = open(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.query_row(&route.query(), [hash], |row| {
+        let data = match row.get_ref(0)? {
+            rusqlite::types::ValueRef::Blob(bytes) | rusqlite::types::ValueRef::Text(bytes) => {
+                bytes.to_vec()
+            }
+            _ => Vec::new(),
+        };
+        Ok((data, row.get(1)?))
+    })
+    .optional()
+}
+
+/// The names of the columns each query returns, found by preparing the
+/// queries on one read-only connection without running them
+pub fn result_columns(db: &(impl Database + ?Sized), queries: &[&str]) -> Result<Vec<Vec<String>>> {
+    let conn = open(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    queries
+        .iter()
+        .map(|query| column_names(&conn, query))
+        .collect()
+}
+
+/// Store a computed response in a `sqlite_memo_table`, replacing any older one
+///
+/// Runs on the event loop, so a locked database fails at once rather than
+/// waiting.
+pub fn memo_store(
+    db: &(impl Database + ?Sized),
+    table: &str,
+    key: &str,
+    payload: &str,
+) -> Result<()> {
+    let conn = open_read_write(db)?;
+    conn.execute(
+        &format!(
+            "INSERT INTO \"{}\" (key, payload, stored_at) \
+             VALUES (?1, ?2, CAST(strftime('%s', 'now') AS INTEGER)) \
+             ON CONFLICT (key) DO UPDATE SET \
+             payload = excluded.payload, stored_at = excluded.stored_at",
+            table
+        ),
+        [key, payload],
+    )?;
+    Ok(())
+}
+
+/// Record a `sqlite_report` run in the database's `report_runs` table,
+/// creating the table on the first run
+///
+/// A run that saved its file is recorded with the rows it saved, and one that
+/// failed with its error.
+pub fn record_report(
+    db: &(impl Database + ?Sized),
+    report: &Report,
+    outcome: &std::result::Result<usize, String>,
+) -> Result<()> {
+    let conn = open_read_write(db)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS report_runs (\
+         name TEXT NOT NULL, path TEXT NOT NULL, status TEXT NOT NULL, \
+         rows INTEGER, error TEXT, generated_at INTEGER NOT NULL)",
+    )?;
+    let (status, rows, error) = match outcome {
+        Ok(rows) => ("ok", Some(*rows as i64), None),
+        Err(e) => ("failed", None, Some(e.as_str())),
+    };
+    conn.execute(
+        "INSERT INTO report_runs (name, path, status, rows, error, generated_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5, CAST(strftime('%s', 'now') AS INTEGER))",
+        rusqlite::params![
+            report.name(),
+            report.path().to_string_lossy(),
+            status,
+            rows,
+            error
+        ],
+    )?;
+    Ok(())
+}
+
+/// Claim a scheduled report's run if it is due at `now` (Unix seconds),
+/// moving its next run `report.every()` later
+///
+/// The due time lives in the database's `report_schedule` table, so of the
+/// workers (or servers) sharing a database exactly one claims each run, and
+/// a reload or restart does not run a report early. A report never claimed
+/// before is due at once.
+pub fn claim_report(db: &(impl Database + ?Sized), report: &Report, now: i64) -> Result<bool> {
+    let Some(every) = report.every() else {
+        return Ok(false);
+    };
+    let conn = open_read_write(db)?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS report_schedule (\
+         name TEXT PRIMARY KEY, due_at INTEGER NOT NULL)",
+    )?;
+    conn.execute(
+        "INSERT INTO report_schedule (name, due_at) VALUES (?1, 0) ON CONFLICT DO NOTHING",
+        [report.name()],
+    )?;
+    let claimed = conn.execute(
+        "UPDATE report_schedule SET due_at = ?2 + ?3 WHERE name = ?1 AND due_at <= ?2",
+        rusqlite::params![report.name(), now, every.as_secs() as i64],
+    )?;
+    Ok(claimed == 1)
+}
+
+/// A report's latest run, as `report_runs` records it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportRun {
+    pub status: String,
+    pub rows: Option<i64>,
+    pub error: Option<String>,
+    pub generated_at: i64,
+}
+
+/// A report's latest run and, if it is scheduled, when it is next due; a
+/// report that has never run or been scheduled has neither
+pub fn report_state(
+    db: &(impl Database + ?Sized),
+    report: &Report,
+) -> Result<(Option<ReportRun>, Option<i64>)> {
+    let conn = open_read_only(db)?;
+    let has_table = |table: &str| -> Result<bool> {
+        conn.query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+            |_| Ok(()),
+        )
+        .optional()
+        .map(|found| found.is_some())
+    };
+    let last_run = if has_table("report_runs")? {
+        conn.query_row(
+            "SELECT status, rows, error, generated_at FROM report_runs \
+             WHERE name = ?1 ORDER BY rowid DESC LIMIT 1",
+            [report.name()],
+            |row| {
+                Ok(ReportRun {
+                    status: row.get(0)?,
+                    rows: row.get(1)?,
+                    error: row.get(2)?,
+                    generated_at: row.get(3)?,
+                })
+            },
+        )
+        .optional()?
+    } else {
+        None
+    };
+    let due_at = if has_table("report_schedule")? {
+        conn.query_row(
+            "SELECT due_at FROM report_schedule WHERE name = ?1",
+            [report.name()],
+            |row| row.get(0),
+        )
+        .optional()?
     } else {
-        // Use positional parameters
-        let positional_params: Vec<&dyn rusqlite::ToSql> = params
+        None
+    };
+    Ok((last_run, due_at))
+}
+
+fn at_set(set: usize, source: WriteError) -> BatchError {
+    BatchError {
+        set: Some(set),
+        source,
+    }
+}
+
+/// Open a database read-write without creating it
+fn open_read_write(db: &(impl Database + ?Sized)) -> Result<Connection> {
+    open(db, OpenFlags::SQLITE_OPEN_READ_WRITE)
+}
+
+/// Open a database for this worker thread only, with the module's SQL
+/// functions (see [`functions`]) registered and its attachments attached
+///
+/// Attached databases are opened with the same flags as the main one, so a
+/// read-only connection cannot write to them and a missing file is an error.
+/// A read-only connection reading the past sees its history tables as they
+/// were then (see [`history::as_of_view`]).
+fn open(db: &(impl Database + ?Sized), flags: OpenFlags) -> Result<Connection> {
+    let conn = Connection::open_with_flags(db.path(), flags | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+    functions::register(&conn)?;
+    for attachment in db.attachments() {
+        // The alias is validated as an identifier when the config is parsed
+        conn.execute(
+            &format!("ATTACH DATABASE ?1 AS \"{}\"", attachment.alias()),
+            [attachment.path()],
+        )?;
+    }
+    if let Some((tables, as_of)) = db.as_of()
+        && flags.contains(OpenFlags::SQLITE_OPEN_READ_ONLY)
+    {
+        for table in tables {
+            let columns = table_columns(&conn, table)?;
+            conn.execute_batch(&history::as_of_view(table, &columns, as_of))?;
+        }
+    }
+    Ok(conn)
+}
+
+/// Run each statement once, returning the total changes and last inserted
+/// rowid, or refusing them when they change more than `max_changes` rows
+///
+/// Only the statements' own changes count, not those made by triggers (e.g.
+/// `sqlite_history` rows). The caller's transaction undoes a refused run.
+fn run_writes<T: rusqlite::ToSql>(
+    conn: &Connection,
+    queries: &[&str],
+    params: &[(String, T)],
+    max_changes: Option<usize>,
+) -> std::result::Result<(usize, i64), WriteError> {
+    let mut changes = 0;
+    for query in queries {
+        let (query, params) = expand_lists(query, params);
+        let mut stmt = conn.prepare(&query)?;
+        changes += match bind(&stmt, &params) {
+            Bindings::Named(named) => stmt.execute(named.as_slice())?,
+            Bindings::Positional(positional) => stmt.execute(positional.as_slice())?,
+        };
+    }
+    if let Some(max) = max_changes.filter(|max| changes > *max) {
+        return Err(WriteError::TooManyRows { changes, max });
+    }
+    Ok((changes, conn.last_insert_rowid()))
+}
+
+/// Standard base64 with padding, for `sqlite_blob_encoding base64`
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk
             .iter()
-            .map(|(_, value)| value as &dyn rusqlite::ToSql)
-            .collect();
-        stmt.query_map(positional_params.as_slice(), row_to_map)?
+            .enumerate()
+            .fold(0u32, |group, (i, &b)| group | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Convert JSON scalars to SQLite values; booleans become 0 and 1
+fn sql_values(set: &[(String, Value)]) -> Vec<(String, rusqlite::types::Value)> {
+    use rusqlite::types::Value as Sql;
+    set.iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::Null => Sql::Null,
+                Value::Bool(b) => Sql::Integer(i64::from(*b)),
+                Value::Number(n) => match n.as_i64() {
+                    Some(i) => Sql::Integer(i),
+                    None => n.as_f64().map_or(Sql::Null, Sql::Real),
+                },
+                Value::String(s) => Sql::Text(s.clone()),
+                // Parameter sets are checked for scalars before they get here
+                Value::Array(_) | Value::Object(_) => Sql::Text(value.to_string()),
+            };
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+/// Expand list parameters for binding
+///
+/// A name given more than once (one entry per value of a `:name[]` binding)
+/// is a list: each `:name` placeholder in the statement becomes
+/// `:name__1, :name__2, ...`, so `WHERE id IN (:ids)` matches every value.
+/// A list with a single value needs no expansion, and an empty list leaves
+/// `:name` unbound (NULL), which matches nothing.
+fn expand_lists<'q, 'p, T>(
+    query: &'q str,
+    params: &'p [(String, T)],
+) -> (Cow<'q, str>, Vec<(String, &'p T)>) {
+    let mut query = Cow::Borrowed(query);
+    let mut expanded: Vec<(String, &'p T)> = Vec::with_capacity(params.len());
+    for (i, (name, value)) in params.iter().enumerate() {
+        let count = params.iter().filter(|(n, _)| n == name).count();
+        if name.is_empty() || count == 1 {
+            expanded.push((name.clone(), value));
+            continue;
+        }
+        let index = params[..i].iter().filter(|(n, _)| n == name).count() + 1;
+        if index == 1 {
+            let placeholders: Vec<String> =
+                (1..=count).map(|n| format!("{}__{}", name, n)).collect();
+            query = Cow::Owned(replace_placeholder(&query, name, &placeholders.join(", ")));
+        }
+        expanded.push((format!("{}__{}", name, index), value));
+    }
+    (query, expanded)
+}
+
+/// Replace every `name` placeholder in a statement, leaving string literals,
+/// quoted identifiers and comments alone
+fn replace_placeholder(sql: &str, name: &str, replacement: &str) -> String {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    let mut out = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while let Some(c) = rest.chars().next() {
+        let skip = match c {
+            '\'' | '"' | '`' => quoted_len(rest, c),
+            '[' => rest.find(']').map_or(rest.len(), |end| end + 1),
+            '-' if rest.starts_with("--") => rest.find('\n').unwrap_or(rest.len()),
+            '/' if rest.starts_with("/*") => rest[2..].find("*/").map_or(rest.len(), |end| end + 4),
+            _ if rest.starts_with(name) && !rest[name.len()..].starts_with(is_name_char) => {
+                out.push_str(replacement);
+                rest = &rest[name.len()..];
+                continue;
+            }
+            _ => c.len_utf8(),
+        };
+        out.push_str(&rest[..skip]);
+        rest = &rest[skip..];
+    }
+    out
+}
+
+/// Length of the quoted string or identifier at the start of `sql`, where a
+/// doubled quote character stands for itself
+fn quoted_len(sql: &str, quote: char) -> usize {
+    let mut chars = sql.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            if chars.peek().is_some_and(|&(_, next)| next == quote) {
+                chars.next();
+            } else {
+                return i + c.len_utf8();
+            }
+        }
+    }
+    sql.len()
+}
+
+/// Parameters ready to bind to a prepared statement
+enum Bindings<'a> {
+    Named(Vec<(&'a str, &'a dyn rusqlite::ToSql)>),
+    Positional(Vec<&'a dyn rusqlite::ToSql>),
+}
+
+/// Bind parameters (either positional or named)
+///
+/// If any parameter has a non-empty name, all parameters are treated as named,
+/// and names the statement does not use are skipped.
+fn bind<'a, T: rusqlite::ToSql>(
+    stmt: &rusqlite::Statement<'_>,
+    params: &'a [(String, T)],
+) -> Bindings<'a> {
+    if params.iter().any(|(name, _)| !name.is_empty()) {
+        Bindings::Named(
+            params
+                .iter()
+                .filter(|(name, _)| matches!(stmt.parameter_index(name), Ok(Some(_))))
+                .map(|(name, value)| (name.as_str(), value as &dyn rusqlite::ToSql))
+                .collect(),
+        )
+    } else {
+        Bindings::Positional(
+            params
+                .iter()
+                .map(|(_, value)| value as &dyn rusqlite::ToSql)
+                .collect(),
+        )
+    }
+}
+
+/// Classify a failure by SQLite's (extended) result code
+pub fn error_code(error: &rusqlite::Error) -> ErrorCode {
+    use rusqlite::ffi;
+
+    let Some(failure) = error.sqlite_error() else {
+        return ErrorCode::Internal;
     };
+    match failure.extended_code {
+        ffi::SQLITE_CONSTRAINT_UNIQUE => return ErrorCode::ConstraintUnique,
+        ffi::SQLITE_CONSTRAINT_PRIMARYKEY => return ErrorCode::ConstraintPrimaryKey,
+        ffi::SQLITE_CONSTRAINT_NOTNULL => return ErrorCode::ConstraintNotNull,
+        ffi::SQLITE_CONSTRAINT_CHECK => return ErrorCode::ConstraintCheck,
+        ffi::SQLITE_CONSTRAINT_FOREIGNKEY => return ErrorCode::ConstraintForeignKey,
+        _ => {}
+    }
+    match failure.code {
+        ffi::ErrorCode::DatabaseBusy | ffi::ErrorCode::DatabaseLocked => ErrorCode::Busy,
+        ffi::ErrorCode::ReadOnly => ErrorCode::ReadOnly,
+        ffi::ErrorCode::DatabaseCorrupt | ffi::ErrorCode::NotADatabase => ErrorCode::Corrupt,
+        ffi::ErrorCode::CannotOpen => ErrorCode::CannotOpen,
+        ffi::ErrorCode::ConstraintViolation => ErrorCode::Constraint,
+        ffi::ErrorCode::TypeMismatch => ErrorCode::Mismatch,
+        _ => ErrorCode::Internal,
+    }
+}
 
-    rows.collect()
+/// Open a database read-only, confirming the file is an accessible SQLite database
+pub fn open_read_only(db: &(impl Database + ?Sized)) -> Result<Connection> {
+    let conn = open(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.query_row("PRAGMA schema_version", [], |_| Ok(()))?;
+    for attachment in db.attachments() {
+        conn.query_row(
+            &format!("PRAGMA \"{}\".schema_version", attachment.alias()),
+            [],
+            |_| Ok(()),
+        )?;
+    }
+    Ok(conn)
+}
+
+/// Prepare a query without executing it, returning the number of parameters it expects
+pub fn prepare_query(conn: &Connection, query: &str) -> Result<usize> {
+    let stmt = conn.prepare(query)?;
+    Ok(stmt.parameter_count())
+}
+
+/// Prepare a query without executing it, returning the names of its named
+/// placeholders (`:name`, `@name`, `$name`); anonymous `?` placeholders are omitted
+pub fn placeholder_names(conn: &Connection, query: &str) -> Result<Vec<String>> {
+    let stmt = conn.prepare(query)?;
+    Ok((1..=stmt.parameter_count())
+        .filter_map(|i| stmt.parameter_name(i).map(str::to_string))
+        .collect())
+}
+
+/// Prepare a query without executing it, returning the names of its result columns
+pub fn column_names(conn: &Connection, query: &str) -> Result<Vec<String>> {
+    let stmt = conn.prepare(query)?;
+    Ok(stmt
+        .column_names()
+        .into_iter()
+        .map(str::to_string)
+        .collect())
+}
+
+/// List a query's result columns with their declared types (`None` for a
+/// column computed by an expression), without running it
+pub fn column_types(conn: &Connection, query: &str) -> Result<Vec<(String, Option<String>)>> {
+    let stmt = conn.prepare(query)?;
+    Ok(stmt
+        .columns()
+        .iter()
+        .map(|column| {
+            (
+                column.name().to_string(),
+                column.decl_type().map(str::to_string),
+            )
+        })
+        .collect())
+}
+
+/// List a table's columns in declaration order (empty when the table does not exist)
+pub fn table_columns(conn: &Connection, table: &str) -> Result<Vec<TableColumn>> {
+    let mut stmt = conn.prepare("SELECT name, type, pk FROM pragma_table_info(?1)")?;
+    let columns = stmt.query_map([table], |row| {
+        Ok(TableColumn {
+            name: row.get(0)?,
+            declared_type: row.get(1)?,
+            primary_key: row.get::<_, i64>(2)? > 0,
+        })
+    })?;
+    columns.collect()
+}
+
+/// List the tables a query reads or writes, as reported by SQLite's own parser
+///
+/// Uses the authorizer hook while preparing, so views, joins and subqueries are
+/// resolved down to the tables they actually touch. Tables in attached databases
+/// are listed as `alias.table`.
+pub fn referenced_tables(conn: &Connection, query: &str) -> Result<Vec<String>> {
+    let tables = Arc::new(Mutex::new(Vec::<String>::new()));
+    let sink = Arc::clone(&tables);
+
+    conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+        if let AuthAction::Read { table_name, .. }
+        | AuthAction::Insert { table_name }
+        | AuthAction::Update { table_name, .. }
+        | AuthAction::Delete { table_name } = ctx.action
+            && let Ok(mut seen) = sink.lock()
+        {
+            let table = match ctx.database_name {
+                Some(db)
+                    if !db.eq_ignore_ascii_case("main") && !db.eq_ignore_ascii_case("temp") =>
+                {
+                    format!("{}.{}", db, table_name)
+                }
+                _ => table_name.to_string(),
+            };
+            if !seen.iter().any(|t| t.eq_ignore_ascii_case(&table)) {
+                seen.push(table);
+            }
+        }
+        Authorization::Allow
+    }));
+    let prepared = conn.prepare(query).map(|_| ());
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    prepared?;
+
+    let tables = tables.lock().map(|t| t.clone()).unwrap_or_default();
+    Ok(tables)
 }
 
 #[cfg(test)]
@@ -74,7 +945,12 @@ mod tests {
     #[test]
     fn test_execute_query_empty_db() {
         // Test with a non-existent database - should return error
-        let result = execute_query("/nonexistent/test.db", "SELECT 1", &[]);
+        let result = execute_query(
+            "/nonexistent/test.db",
+            "SELECT 1",
+            &[],
+            &RowShape::default(),
+        );
         assert!(result.is_err());
     }
 
@@ -97,7 +973,13 @@ mod tests {
             .unwrap();
         }
 
-        let results = execute_query(temp_path, "SELECT * FROM test ORDER BY id", &[]).unwrap();
+        let results = execute_query(
+            temp_path,
+            "SELECT * FROM test ORDER BY id",
+            &[],
+            &RowShape::default(),
+        )
+        .unwrap();
         assert_eq!(results.len(), 2);
         assert_eq!(results[0].get("id").unwrap(), &Value::Number(1.into()));
         assert_eq!(
@@ -127,9 +1009,14 @@ mod tests {
             .unwrap();
         }
 
-        let params = vec![(String::new(), "2".to_string())];
-        let results =
-            execute_query(temp_path, "SELECT * FROM books WHERE id = ?", &params).unwrap();
+        let params = vec![(String::new(), Value::from("2"))];
+        let results = execute_query(
+            temp_path,
+            "SELECT * FROM books WHERE id = ?",
+            &params,
+            &RowShape::default(),
+        )
+        .unwrap();
         assert_eq!(results.len(), 1);
         assert_eq!(
             results[0].get("title").unwrap(),
@@ -162,13 +1049,14 @@ mod tests {
         }
 
         let params = vec![
-            (":min_year".to_string(), "2015".to_string()),
-            (":max_year".to_string(), "2024".to_string()),
+            (":min_year".to_string(), Value::from("2015")),
+            (":max_year".to_string(), Value::from("2024")),
         ];
         let results = execute_query(
             temp_path,
             "SELECT * FROM books WHERE year >= :min_year AND year <= :max_year ORDER BY year",
             &params,
+            &RowShape::default(),
         )
         .unwrap();
 
@@ -186,92 +1074,648 @@ mod tests {
     }
 
     #[test]
-    fn test_execute_query_data_types() {
+    fn test_execute_query_skips_unused_named_params() {
         use rusqlite::Connection;
         use std::fs;
 
-        let temp_path = "/tmp/test_sqlite_serve_types.db";
+        let temp_path = "/tmp/test_sqlite_serve_unused_named.db";
         let _ = fs::remove_file(temp_path);
 
         {
             let conn = Connection::open(temp_path).unwrap();
-            conn.execute(
-                "CREATE TABLE types (id INTEGER, name TEXT, price REAL, data BLOB, nullable TEXT)",
-                [],
-            )
-            .unwrap();
-            conn.execute(
-                "INSERT INTO types VALUES (42, 'test', 3.14, X'DEADBEEF', NULL)",
-                [],
+            conn.execute_batch(
+                "CREATE TABLE books (id INTEGER, genre TEXT);
+                 INSERT INTO books VALUES (1, 'Fiction'), (2, 'History');",
             )
             .unwrap();
         }
 
-        let results = execute_query(temp_path, "SELECT * FROM types", &[]).unwrap();
+        let params = vec![
+            (":genre".to_string(), Value::from("History")),
+            (":author".to_string(), Value::from("")),
+        ];
+        let results = execute_query(
+            temp_path,
+            "SELECT * FROM books WHERE genre = :genre",
+            &params,
+            &RowShape::default(),
+        )
+        .unwrap();
         assert_eq!(results.len(), 1);
 
-        let row = &results[0];
-
-        assert_eq!(row.get("id").unwrap(), &Value::Number(42.into()));
-        assert_eq!(row.get("name").unwrap(), &Value::String("test".to_string()));
-        assert_eq!(row.get("price").unwrap().as_f64().unwrap(), 3.14);
-        assert_eq!(
-            row.get("data").unwrap(),
-            &Value::String("deadbeef".to_string())
-        );
-        assert_eq!(row.get("nullable").unwrap(), &Value::Null);
+        let results = execute_query(
+            temp_path,
+            "SELECT * FROM books",
+            &params,
+            &RowShape::default(),
+        )
+        .unwrap();
+        assert_eq!(results.len(), 2);
 
         let _ = fs::remove_file(temp_path);
     }
 
     #[test]
-    fn test_execute_query_multiple_named_params() {
+    fn test_execute_query_typed_params() {
         use rusqlite::Connection;
         use std::fs;
 
-        let temp_path = "/tmp/test_sqlite_serve_multi.db";
+        let temp_path = "/tmp/test_sqlite_serve_typed_params.db";
         let _ = fs::remove_file(temp_path);
+        Connection::open(temp_path).unwrap();
 
-        {
-            let conn = Connection::open(temp_path).unwrap();
-            conn.execute(
-                "CREATE TABLE books (id INTEGER, genre TEXT, rating REAL)",
-                [],
-            )
-            .unwrap();
-            conn.execute(
-                "INSERT INTO books VALUES 
-                    (1, 'Fiction', 4.5),
-                    (2, 'Science', 4.8),
-                    (3, 'Fiction', 4.9),
-                    (4, 'Science', 4.2)",
-                [],
-            )
-            .unwrap();
-        }
-
+        // Bound as text, '10' sorts before '9'; bound as integers it does not
         let params = vec![
-            (":min_rating".to_string(), "4.5".to_string()),
-            (":genre".to_string(), "Fiction".to_string()),
+            (":a".to_string(), Value::from(10)),
+            (":b".to_string(), Value::from(9)),
+            (":rating".to_string(), Value::from(2.5)),
+            (":flag".to_string(), Value::Bool(true)),
+            (":missing".to_string(), Value::Null),
         ];
-
         let results = execute_query(
             temp_path,
-            "SELECT * FROM books WHERE genre = :genre AND rating >= :min_rating ORDER BY rating DESC",
+            "SELECT :a > :b AS greater, typeof(:rating) AS rating, :flag AS flag, \
+             :missing IS NULL AS missing",
             &params,
+            &RowShape::default(),
         )
         .unwrap();
-
-        assert_eq!(results.len(), 2);
-        assert_eq!(results[0].get("rating").unwrap().as_f64().unwrap(), 4.9);
-        assert_eq!(results[1].get("rating").unwrap().as_f64().unwrap(), 4.5);
+        assert_eq!(results[0].get("greater").unwrap(), &Value::from(1));
+        assert_eq!(results[0].get("rating").unwrap(), &Value::from("real"));
+        assert_eq!(results[0].get("flag").unwrap(), &Value::from(1));
+        assert_eq!(results[0].get("missing").unwrap(), &Value::from(1));
 
         let _ = fs::remove_file(temp_path);
     }
 
     #[test]
-    fn test_execute_query_with_like_operator() {
-        use rusqlite::Connection;
+    fn test_execute_write() {
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_write.db";
+        let _ = fs::remove_file(temp_path);
+
+        {
+            let conn = Connection::open(temp_path).unwrap();
+            conn.execute(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let params = vec![
+            (":title".to_string(), Value::from("Dune")),
+            (":unused".to_string(), Value::from("x")),
+        ];
+        let (changes, rowid) = execute_write(
+            temp_path,
+            &["INSERT INTO books (title) VALUES (:title)"],
+            &params,
+            None,
+        )
+        .unwrap();
+        assert_eq!((changes, rowid), (1, 1));
+
+        let (changes, _) = execute_write(
+            temp_path,
+            &["UPDATE books SET title = ? WHERE id = ?"],
+            &[
+                (String::new(), Value::from("Emma")),
+                (String::new(), Value::from("1")),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(changes, 1);
+
+        let results = execute_query(
+            temp_path,
+            "SELECT title FROM books",
+            &[],
+            &RowShape::default(),
+        )
+        .unwrap();
+        assert_eq!(results[0].get("title").unwrap(), "Emma");
+
+        // Writes never create a database that does not exist
+        assert!(
+            execute_write(
+                "/tmp/test_sqlite_serve_missing.db",
+                &["DELETE FROM books"],
+                &[],
+                None
+            )
+            .is_err()
+        );
+        assert!(!std::path::Path::new("/tmp/test_sqlite_serve_missing.db").exists());
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_error_code() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             CREATE TABLE authors (id INTEGER PRIMARY KEY);
+             CREATE TABLE books (
+                 id INTEGER PRIMARY KEY,
+                 isbn TEXT UNIQUE,
+                 title TEXT NOT NULL,
+                 year INTEGER CHECK (year > 0),
+                 author INTEGER REFERENCES authors (id)
+             );
+             INSERT INTO books (id, isbn, title) VALUES (1, 'x', 'Dune');",
+        )
+        .unwrap();
+
+        let code = |sql: &str| error_code(&conn.execute(sql, []).unwrap_err());
+        assert_eq!(
+            code("INSERT INTO books (isbn, title) VALUES ('x', 'Emma')"),
+            ErrorCode::ConstraintUnique
+        );
+        assert_eq!(
+            code("INSERT INTO books (id, title) VALUES (1, 'Emma')"),
+            ErrorCode::ConstraintPrimaryKey
+        );
+        assert_eq!(
+            code("INSERT INTO books (title) VALUES (NULL)"),
+            ErrorCode::ConstraintNotNull
+        );
+        assert_eq!(
+            code("INSERT INTO books (title, year) VALUES ('Emma', -1)"),
+            ErrorCode::ConstraintCheck
+        );
+        assert_eq!(
+            code("INSERT INTO books (title, author) VALUES ('Emma', 9)"),
+            ErrorCode::ConstraintForeignKey
+        );
+        assert_eq!(
+            code("INSERT INTO books (id, title) VALUES ('one', 'Emma')"),
+            ErrorCode::Mismatch
+        );
+        assert_eq!(code("SELECT * FROM missing"), ErrorCode::Internal);
+
+        let missing = execute_write(
+            "/tmp/test_sqlite_serve_error_code_missing.db",
+            &["DELETE FROM t"],
+            &[],
+            None,
+        );
+        assert_eq!(missing.unwrap_err().code(), ErrorCode::CannotOpen);
+    }
+
+    #[test]
+    fn test_replace_placeholder() {
+        assert_eq!(
+            replace_placeholder(
+                "SELECT ':ids', \":ids\" FROM t -- :ids\nWHERE id IN (:ids) AND x = :idsx /* :ids */",
+                ":ids",
+                ":ids__1, :ids__2"
+            ),
+            "SELECT ':ids', \":ids\" FROM t -- :ids\nWHERE id IN (:ids__1, :ids__2) AND x = :idsx /* :ids */"
+        );
+        assert_eq!(
+            replace_placeholder("SELECT 'it''s :ids' || :ids", ":ids", "?"),
+            "SELECT 'it''s :ids' || ?"
+        );
+    }
+
+    #[test]
+    fn test_execute_query_list_parameter() {
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_list.db";
+        let _ = fs::remove_file(temp_path);
+
+        {
+            let conn = Connection::open(temp_path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);
+                 INSERT INTO books (title) VALUES ('Dune'), ('Emma'), ('Ulysses');",
+            )
+            .unwrap();
+        }
+
+        let query = "SELECT title FROM books WHERE id IN (:ids) AND title != :skip ORDER BY id";
+        let list = |ids: &[&str]| {
+            let mut params: Vec<(String, Value)> = ids
+                .iter()
+                .map(|id| (":ids".to_string(), Value::from(*id)))
+                .collect();
+            params.push((":skip".to_string(), Value::from("Emma")));
+            params
+        };
+
+        let results = execute_query(
+            temp_path,
+            query,
+            &list(&["1", "2", "3"]),
+            &RowShape::default(),
+        )
+        .unwrap();
+        let titles: Vec<&Value> = results.iter().map(|r| r.get("title").unwrap()).collect();
+        assert_eq!(titles, vec!["Dune", "Ulysses"]);
+
+        let results = execute_query(temp_path, query, &list(&["3"]), &RowShape::default()).unwrap();
+        assert_eq!(results.len(), 1);
+
+        // An empty list matches nothing
+        let results = execute_query(temp_path, query, &list(&[]), &RowShape::default()).unwrap();
+        assert!(results.is_empty());
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_execute_write_transaction() {
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_write_tx.db";
+        let _ = fs::remove_file(temp_path);
+
+        {
+            let conn = Connection::open(temp_path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT NOT NULL);
+                 CREATE TABLE stats (books INTEGER);
+                 INSERT INTO stats VALUES (0);",
+            )
+            .unwrap();
+        }
+
+        let params = vec![(":title".to_string(), Value::from("Dune"))];
+        let (changes, rowid) = execute_write(
+            temp_path,
+            &[
+                "INSERT INTO books (title) VALUES (:title)",
+                "UPDATE stats SET books = books + 1",
+            ],
+            &params,
+            None,
+        )
+        .unwrap();
+        assert_eq!((changes, rowid), (2, 1));
+
+        // The second statement fails, so the first is rolled back
+        let result = execute_write(
+            temp_path,
+            &[
+                "UPDATE stats SET books = books + 1",
+                "INSERT INTO books (title) VALUES (NULL)",
+            ],
+            &[],
+            None,
+        );
+        assert!(result.is_err());
+
+        let stats = execute_query(
+            temp_path,
+            "SELECT books FROM stats",
+            &[],
+            &RowShape::default(),
+        )
+        .unwrap();
+        assert_eq!(stats[0].get("books").unwrap(), 1);
+        let books =
+            execute_query(temp_path, "SELECT * FROM books", &[], &RowShape::default()).unwrap();
+        assert_eq!(books.len(), 1);
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_execute_write_max_changes() {
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_write_max.db";
+        let _ = fs::remove_file(temp_path);
+
+        {
+            let conn = Connection::open(temp_path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);
+                 INSERT INTO books (title) VALUES ('Dune'), ('Emma'), ('Ulysses');",
+            )
+            .unwrap();
+        }
+        let max = Some(1);
+
+        let (changes, _) = execute_write(
+            temp_path,
+            &["DELETE FROM books WHERE id = :id"],
+            &[(":id".to_string(), Value::from(1))],
+            max,
+        )
+        .unwrap();
+        assert_eq!(changes, 1);
+
+        // A DELETE without a WHERE clause is rolled back rather than emptying the table
+        let error = execute_write(temp_path, &["DELETE FROM books"], &[], max).unwrap_err();
+        assert!(matches!(
+            error,
+            WriteError::TooManyRows { changes: 2, max: 1 }
+        ));
+        assert_eq!(error.code(), ErrorCode::TooManyRows);
+        assert_eq!(
+            error.to_string(),
+            "the write would change 2 rows, more than the 1 allowed"
+        );
+        let books =
+            execute_query(temp_path, "SELECT * FROM books", &[], &RowShape::default()).unwrap();
+        assert_eq!(books.len(), 2);
+
+        // The limit is per run of a batch: every set may change one row
+        let set = |id: i64| vec![(":id".to_string(), Value::from(id))];
+        let update = ["UPDATE books SET title = upper(title) WHERE id >= :id"];
+        let error = execute_batch_write(temp_path, &update, &[set(3), set(2)], max).unwrap_err();
+        assert_eq!(error.set, Some(1));
+        assert_eq!(error.source.code(), ErrorCode::TooManyRows);
+        let books = execute_query(
+            temp_path,
+            "SELECT title FROM books ORDER BY id",
+            &[],
+            &RowShape::default(),
+        )
+        .unwrap();
+        assert_eq!(books[1].get("title").unwrap(), "Ulysses");
+        assert_eq!(
+            execute_batch_write(temp_path, &update, &[set(3)], max).unwrap(),
+            vec![(1, 0)]
+        );
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_execute_batch() {
+        use serde_json::json;
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_batch.db";
+        let _ = fs::remove_file(temp_path);
+
+        {
+            let conn = Connection::open(temp_path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT NOT NULL, year INTEGER)",
+            )
+            .unwrap();
+        }
+
+        let set = |title: Value, year: Value| {
+            vec![(":title".to_string(), title), (":year".to_string(), year)]
+        };
+        let insert = ["INSERT INTO books (title, year) VALUES (:title, :year)"];
+        let outcomes = execute_batch_write(
+            temp_path,
+            &insert,
+            &[
+                set(json!("Dune"), json!(1965)),
+                set(json!("Emma"), Value::Null),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcomes, vec![(1, 1), (1, 2)]);
+
+        // Values keep their JSON types
+        let rows = execute_query(
+            temp_path,
+            "SELECT typeof(year) AS t FROM books",
+            &[],
+            &RowShape::default(),
+        )
+        .unwrap();
+        assert_eq!(rows[0].get("t").unwrap(), "integer");
+        assert_eq!(rows[1].get("t").unwrap(), "null");
+
+        // The second set fails, so the first is not kept either
+        let error = execute_batch_write(
+            temp_path,
+            &insert,
+            &[
+                set(json!("Ulysses"), json!(1922)),
+                set(Value::Null, json!(1900)),
+            ],
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(error.set, Some(1));
+        let rows =
+            execute_query(temp_path, "SELECT * FROM books", &[], &RowShape::default()).unwrap();
+        assert_eq!(rows.len(), 2);
+
+        let results = execute_batch_query(
+            temp_path,
+            "SELECT title FROM books WHERE id = ?",
+            &[
+                vec![(String::new(), json!(2))],
+                vec![(String::new(), json!(9))],
+            ],
+            &RowShape::default(),
+        )
+        .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0][0].get("title").unwrap(), "Emma");
+        assert!(results[1].is_empty());
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_execute_query_data_types() {
+        use rusqlite::Connection;
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_types.db";
+        let _ = fs::remove_file(temp_path);
+
+        {
+            let conn = Connection::open(temp_path).unwrap();
+            conn.execute(
+                "CREATE TABLE types (id INTEGER, name TEXT, price REAL, data BLOB, nullable TEXT)",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO types VALUES (42, 'test', 3.14, X'DEADBEEF', NULL)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let results =
+            execute_query(temp_path, "SELECT * FROM types", &[], &RowShape::default()).unwrap();
+        assert_eq!(results.len(), 1);
+
+        let row = &results[0];
+
+        assert_eq!(row.get("id").unwrap(), &Value::Number(42.into()));
+        assert_eq!(row.get("name").unwrap(), &Value::String("test".to_string()));
+        assert_eq!(row.get("price").unwrap().as_f64().unwrap(), 3.14);
+        assert_eq!(
+            row.get("data").unwrap(),
+            &Value::String("deadbeef".to_string())
+        );
+        assert_eq!(row.get("nullable").unwrap(), &Value::Null);
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_execute_query_json_columns() {
+        use rusqlite::Connection;
+
+        let temp_path = "/tmp/test_sqlite_serve_json_columns.db";
+        let _ = std::fs::remove_file(temp_path);
+        Connection::open(temp_path)
+            .unwrap()
+            .execute_batch(
+                r#"CREATE TABLE docs (id INTEGER, doc JSON, body TEXT);
+                   INSERT INTO docs VALUES (1, '{"tags": ["a", "b"]}', '{"tags": []}');
+                   INSERT INTO docs VALUES (2, 'not json', NULL);"#,
+            )
+            .unwrap();
+
+        // Declared JSON columns are nested, through a subquery too; other
+        // text is left alone, as is text that is not JSON
+        let query = "SELECT * FROM (SELECT * FROM docs) ORDER BY id";
+        let rows = execute_query(temp_path, query, &[], &RowShape::default()).unwrap();
+        assert_eq!(rows[0]["doc"], serde_json::json!({"tags": ["a", "b"]}));
+        assert_eq!(rows[0]["body"], serde_json::json!(r#"{"tags": []}"#));
+        assert_eq!(rows[1]["doc"], serde_json::json!("not json"));
+
+        // An expression has no declared type
+        let rows = execute_query(
+            temp_path,
+            "SELECT doc || '' AS doc FROM docs",
+            &[],
+            &RowShape::default(),
+        )
+        .unwrap();
+        assert!(rows[0]["doc"].is_string());
+        let _ = std::fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_execute_query_hidden_columns() {
+        let temp_path = "/tmp/test_sqlite_serve_hidden_columns.db";
+        let _ = std::fs::remove_file(temp_path);
+        Connection::open(temp_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE users (id INTEGER, name TEXT, password_hash TEXT);
+                 INSERT INTO users VALUES (1, 'ada', 'x1'), (2, 'alan', 'x2');",
+            )
+            .unwrap();
+        let shape = RowShape::new().with_hidden_columns(vec!["PASSWORD_HASH".to_string()]);
+
+        let rows =
+            execute_query(temp_path, "SELECT * FROM users ORDER BY id", &[], &shape).unwrap();
+        assert_eq!(rows[0].len(), 2);
+        assert_eq!(rows[0]["name"], "ada");
+        assert!(!rows[0].contains_key("password_hash"));
+
+        // Columns are hidden by their result name, so a query may still alias one
+        let query = "SELECT password_hash AS digest FROM users";
+        let rows = execute_query(temp_path, query, &[], &shape).unwrap();
+        assert_eq!(rows[0]["digest"], "x1");
+
+        let sets = vec![vec![(":id".to_string(), Value::from(2))]];
+        let query = "SELECT * FROM users WHERE id = :id";
+        let batch = execute_batch_query(temp_path, query, &sets, &shape).unwrap();
+        assert!(!batch[0][0].contains_key("password_hash"));
+        let _ = std::fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_execute_query_blob_encoding() {
+        let temp_path = "/tmp/test_sqlite_serve_blob_encoding.db";
+        let _ = std::fs::remove_file(temp_path);
+        Connection::open(temp_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE files (name TEXT, data BLOB);
+                 INSERT INTO files VALUES ('logo', x'89504e47'), ('empty', NULL);",
+            )
+            .unwrap();
+        let query = "SELECT * FROM files ORDER BY name DESC";
+        let rows = |encoding: BlobEncoding| {
+            let shape = RowShape::new().with_blob_encoding(encoding);
+            execute_query(temp_path, query, &[], &shape).unwrap()
+        };
+
+        assert_eq!(rows(BlobEncoding::Hex)[0]["data"], "89504e47");
+        assert_eq!(rows(BlobEncoding::Base64)[0]["data"], "iVBORw==");
+        let skipped = rows(BlobEncoding::Skip);
+        assert!(!skipped[0].contains_key("data"));
+        assert_eq!(skipped[0]["name"], "logo");
+        // NULL is not a blob, so it is sent as it is
+        assert_eq!(skipped[1]["data"], Value::Null);
+
+        let _ = std::fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(encode_base64(b""), "");
+        assert_eq!(encode_base64(b"f"), "Zg==");
+        assert_eq!(encode_base64(b"fo"), "Zm8=");
+        assert_eq!(encode_base64(b"foo"), "Zm9v");
+        assert_eq!(encode_base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(encode_base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[test]
+    fn test_execute_query_multiple_named_params() {
+        use rusqlite::Connection;
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_multi.db";
+        let _ = fs::remove_file(temp_path);
+
+        {
+            let conn = Connection::open(temp_path).unwrap();
+            conn.execute(
+                "CREATE TABLE books (id INTEGER, genre TEXT, rating REAL)",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO books VALUES 
+                    (1, 'Fiction', 4.5),
+                    (2, 'Science', 4.8),
+                    (3, 'Fiction', 4.9),
+                    (4, 'Science', 4.2)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let params = vec![
+            (":min_rating".to_string(), Value::from("4.5")),
+            (":genre".to_string(), Value::from("Fiction")),
+        ];
+
+        let results = execute_query(
+            temp_path,
+            "SELECT * FROM books WHERE genre = :genre AND rating >= :min_rating ORDER BY rating DESC",
+            &params, &RowShape::default(),
+        )
+        .unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].get("rating").unwrap().as_f64().unwrap(), 4.9);
+        assert_eq!(results[1].get("rating").unwrap().as_f64().unwrap(), 4.5);
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_execute_query_with_like_operator() {
+        use rusqlite::Connection;
         use std::fs;
 
         let temp_path = "/tmp/test_sqlite_serve_like.db";
@@ -287,11 +1731,12 @@ mod tests {
             .unwrap();
         }
 
-        let params = vec![(":search".to_string(), "Rust".to_string())];
+        let params = vec![(":search".to_string(), Value::from("Rust"))];
         let results = execute_query(
             temp_path,
             "SELECT * FROM books WHERE title LIKE '%' || :search || '%'",
             &params,
+            &RowShape::default(),
         )
         .unwrap();
 
@@ -300,6 +1745,142 @@ mod tests {
         let _ = fs::remove_file(temp_path);
     }
 
+    #[test]
+    fn test_open_read_only_and_prepare() {
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_prepare.db";
+        let _ = fs::remove_file(temp_path);
+
+        assert!(open_read_only(temp_path).is_err());
+
+        {
+            let conn = Connection::open(temp_path).unwrap();
+            conn.execute("CREATE TABLE books (id INTEGER, year INTEGER)", [])
+                .unwrap();
+        }
+
+        let conn = open_read_only(temp_path).unwrap();
+        assert_eq!(
+            prepare_query(&conn, "SELECT * FROM books WHERE id = ? AND year > :year").unwrap(),
+            2
+        );
+        assert!(prepare_query(&conn, "SELECT * FROM missing").is_err());
+        assert_eq!(
+            placeholder_names(&conn, "SELECT * FROM books WHERE id = ? AND year > :year").unwrap(),
+            vec![":year".to_string()]
+        );
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_referenced_tables() {
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_tables.db";
+        let _ = fs::remove_file(temp_path);
+
+        let conn = Connection::open(temp_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE books (id INTEGER, author_id INTEGER);
+             CREATE TABLE authors (id INTEGER, name TEXT);
+             CREATE TABLE users (id INTEGER, password_hash TEXT);
+             CREATE VIEW credentials AS SELECT password_hash FROM users;",
+        )
+        .unwrap();
+
+        let tables = referenced_tables(
+            &conn,
+            "SELECT b.id, a.name FROM books b JOIN authors a ON a.id = b.author_id",
+        )
+        .unwrap();
+        assert_eq!(tables, vec!["books".to_string(), "authors".to_string()]);
+
+        let through_view = referenced_tables(&conn, "SELECT * FROM credentials").unwrap();
+        assert!(through_view.contains(&"users".to_string()));
+
+        let written = referenced_tables(
+            &conn,
+            "INSERT INTO books (id, author_id) SELECT id, id FROM authors",
+        )
+        .unwrap();
+        assert!(written.contains(&"books".to_string()));
+        assert!(written.contains(&"authors".to_string()));
+
+        assert!(referenced_tables(&conn, "SELECT 1").unwrap().is_empty());
+        assert!(referenced_tables(&conn, "SELECT * FROM missing").is_err());
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_attached_databases() {
+        use std::fs;
+
+        let main_path = "/tmp/test_sqlite_serve_attach_main.db";
+        let stats_path = "/tmp/test_sqlite_serve_attach_stats.db";
+        let _ = fs::remove_file(main_path);
+        let _ = fs::remove_file(stats_path);
+
+        Connection::open(main_path)
+            .unwrap()
+            .execute_batch("CREATE TABLE books (id INTEGER, title TEXT); INSERT INTO books VALUES (1, 'Dune');")
+            .unwrap();
+        Connection::open(stats_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE loans (id INTEGER, count INTEGER); INSERT INTO loans VALUES (1, 7);",
+            )
+            .unwrap();
+
+        let db = DatabasePath::parse(main_path)
+            .unwrap()
+            .with_attachments(vec![Attachment::parse("stats", stats_path).unwrap()]);
+        let join = "SELECT title, count FROM books JOIN stats.loans USING (id)";
+        let rows = execute_query(&db, join, &[], &RowShape::default()).unwrap();
+        assert_eq!(rows[0].get("count"), Some(&Value::from(7)));
+
+        // Attachments share the main database's read-only flag
+        let write = "UPDATE stats.loans SET count = count + 1";
+        assert!(execute_query(&db, write, &[], &RowShape::default()).is_err());
+        execute_write(&db, &[write], &[], None).unwrap();
+
+        let conn = open_read_only(&db).unwrap();
+        assert_eq!(
+            referenced_tables(&conn, join).unwrap(),
+            vec!["books", "stats.loans"]
+        );
+
+        // A missing attachment fails the open instead of creating a database
+        let missing = DatabasePath::parse(main_path)
+            .unwrap()
+            .with_attachments(vec![
+                Attachment::parse("gone", "/tmp/test_sqlite_serve_attach_missing.db").unwrap(),
+            ]);
+        assert!(open_read_only(&missing).is_err());
+        assert!(!std::path::Path::new("/tmp/test_sqlite_serve_attach_missing.db").exists());
+
+        let _ = fs::remove_file(main_path);
+        let _ = fs::remove_file(stats_path);
+    }
+
+    #[test]
+    fn test_table_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT NOT NULL);")
+            .unwrap();
+
+        let columns = table_columns(&conn, "books").unwrap();
+        assert_eq!(columns.len(), 2);
+        assert_eq!(columns[0].name, "id");
+        assert!(columns[0].primary_key);
+        assert_eq!(columns[1].declared_type, "TEXT");
+        assert!(!columns[1].primary_key);
+
+        assert!(table_columns(&conn, "missing").unwrap().is_empty());
+    }
+
     #[test]
     fn test_execute_query_empty_results() {
         use rusqlite::Connection;
@@ -313,9 +1894,238 @@ mod tests {
             conn.execute("CREATE TABLE test (id INTEGER)", []).unwrap();
         }
 
-        let results = execute_query(temp_path, "SELECT * FROM test", &[]).unwrap();
+        let results =
+            execute_query(temp_path, "SELECT * FROM test", &[], &RowShape::default()).unwrap();
         assert_eq!(results.len(), 0);
 
         let _ = fs::remove_file(temp_path);
     }
+
+    #[test]
+    fn test_memo_table() {
+        use rusqlite::Connection;
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_memo.db";
+        let _ = fs::remove_file(temp_path);
+        let conn = Connection::open(temp_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE memo (key TEXT PRIMARY KEY, payload TEXT NOT NULL,
+                                stored_at INTEGER NOT NULL);",
+        )
+        .unwrap();
+
+        assert_eq!(memo_lookup(temp_path, "memo", "k", 60).unwrap(), None);
+        memo_store(temp_path, "memo", "k", "[1]").unwrap();
+        memo_store(temp_path, "memo", "k", "[2]").unwrap();
+        assert_eq!(
+            memo_lookup(temp_path, "memo", "k", 60).unwrap().as_deref(),
+            Some("[2]")
+        );
+
+        // Responses older than the ttl are not served
+        conn.execute("UPDATE memo SET stored_at = stored_at - 120", [])
+            .unwrap();
+        assert_eq!(memo_lookup(temp_path, "memo", "k", 60).unwrap(), None);
+        assert!(memo_lookup(temp_path, "missing", "k", 60).is_err());
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_stored_blob() {
+        use rusqlite::Connection;
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_blob.db";
+        let _ = fs::remove_file(temp_path);
+        let conn = Connection::open(temp_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE files (sha256 TEXT PRIMARY KEY, data BLOB, mime TEXT);
+             INSERT INTO files VALUES ('a', x'00ff', 'image/png'), ('b', 'text', NULL);",
+        )
+        .unwrap();
+
+        let typed = BlobRoute::parse("files", &["type=mime"]).unwrap();
+        assert_eq!(
+            stored_blob(temp_path, &typed, "a").unwrap(),
+            Some((vec![0, 255], Some("image/png".to_string())))
+        );
+        assert_eq!(
+            stored_blob(temp_path, &typed, "b").unwrap(),
+            Some((b"text".to_vec(), None))
+        );
+        assert_eq!(stored_blob(temp_path, &typed, "c").unwrap(), None);
+
+        let untyped = BlobRoute::parse("files", &[]).unwrap();
+        assert_eq!(
+            stored_blob(temp_path, &untyped, "a").unwrap(),
+            Some((vec![0, 255], None))
+        );
+        let missing = BlobRoute::parse("files", &["data=body"]).unwrap();
+        assert!(stored_blob(temp_path, &missing, "a").is_err());
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_mapped_database() {
+        let db = "/tmp/test_mapped_database.db";
+        let _ = std::fs::remove_file(db);
+        Connection::open(db)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE tenants (host TEXT PRIMARY KEY, db_path TEXT);
+                 INSERT INTO tenants VALUES ('acme.example.com', '/srv/acme.db'),
+                                            ('closed.example.com', NULL);",
+            )
+            .unwrap();
+        let map = |query: &str| DatabaseMap::parse(db, query, &[]).unwrap();
+
+        let tenants = map("SELECT db_path FROM tenants WHERE host = :host");
+        let names = database_map_parameters(&tenants).unwrap();
+        assert_eq!(names, vec![":host".to_string()]);
+        let tenants = tenants.with_parameters(&names).unwrap();
+        let lookup = |host: &str| mapped_database(&tenants, &[host.to_string()]).unwrap();
+        assert_eq!(lookup("acme.example.com").as_deref(), Some("/srv/acme.db"));
+        assert_eq!(lookup("closed.example.com"), None);
+        assert_eq!(lookup("unknown.example.com"), None);
+
+        assert!(
+            database_map_parameters(&map("SELECT host, db_path FROM tenants"))
+                .unwrap_err()
+                .contains("one column")
+        );
+        assert!(
+            database_map_parameters(&map("SELECT db_path FROM tenants WHERE host = ?"))
+                .unwrap_err()
+                .contains("named")
+        );
+        assert!(database_map_parameters(&map("SELECT db_path FROM nowhere")).is_err());
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[test]
+    fn test_claim_report() {
+        let db = "/tmp/test_sqlite_serve_claim_report.db";
+        let _ = std::fs::remove_file(db);
+        rusqlite::Connection::open(db).unwrap();
+        let daily = Report::parse(&["daily", "/srv/reports", "every=1d"]).unwrap();
+        let manual = Report::parse(&["manual", "/srv/reports"]).unwrap();
+        assert_eq!(report_state(db, &daily).unwrap(), (None, None));
+
+        // Due at once, then not again until a day has passed, whoever asks
+        assert!(claim_report(db, &daily, 1_000).unwrap());
+        assert!(!claim_report(db, &daily, 1_000).unwrap());
+        assert!(!claim_report(db, &daily, 1_000 + 86_399).unwrap());
+        assert!(claim_report(db, &daily, 1_000 + 86_400).unwrap());
+        assert!(!claim_report(db, &manual, 1_000).unwrap());
+
+        record_report(db, &daily, &Ok(3)).unwrap();
+        record_report(db, &daily, &Err("no such table: sales".to_string())).unwrap();
+        let (last_run, due_at) = report_state(db, &daily).unwrap();
+        let last_run = last_run.unwrap();
+        assert_eq!(last_run.status, "failed");
+        assert_eq!(last_run.rows, None);
+        assert_eq!(last_run.error.as_deref(), Some("no such table: sales"));
+        assert_eq!(due_at, Some(1_000 + 2 * 86_400));
+        assert_eq!(report_state(db, &manual).unwrap(), (None, None));
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[test]
+    fn test_history_as_of() {
+        use rusqlite::Connection;
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_history.db";
+        let _ = fs::remove_file(temp_path);
+        let conn = Connection::open(temp_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);
+             INSERT INTO books VALUES (1, 'Dune');",
+        )
+        .unwrap();
+        check_history(temp_path, "books").unwrap();
+        assert!(check_history(temp_path, "missing").is_err());
+        // Checking writes nothing
+        assert!(table_columns(&conn, "books_history").unwrap().is_empty());
+        install_history(temp_path, "books").unwrap();
+        // Installing again leaves one set of triggers
+        install_history(temp_path, "books").unwrap();
+        assert!(install_history(temp_path, "missing").is_err());
+        conn.execute(
+            "CREATE TABLE tags (name TEXT PRIMARY KEY) WITHOUT ROWID",
+            [],
+        )
+        .unwrap();
+        assert!(
+            install_history(temp_path, "tags")
+                .unwrap_err()
+                .contains("WITHOUT ROWID")
+        );
+        assert!(
+            check_history(temp_path, "tags")
+                .unwrap_err()
+                .contains("WITHOUT ROWID")
+        );
+
+        let past = DatabasePath::parse(temp_path)
+            .unwrap()
+            .with_history(vec!["books".to_string()])
+            .at(AsOf::parse("2024-01-01").unwrap());
+        // Writes change the table itself
+        execute_write(
+            &past,
+            &["UPDATE books SET title = :title WHERE id = 1"],
+            &[(":title".to_string(), Value::from("Dune Messiah"))],
+            None,
+        )
+        .unwrap();
+        conn.execute(
+            "UPDATE books_history SET history_at = '2024-06-01 00:00:00.000'",
+            [],
+        )
+        .unwrap();
+
+        let title = |db: &DatabasePath| {
+            execute_query(db, "SELECT title FROM books", &[], &RowShape::default()).unwrap()[0]["title"].clone()
+        };
+        assert_eq!(title(&past), Value::from("Dune"));
+        let now = DatabasePath::parse(temp_path)
+            .unwrap()
+            .with_history(vec!["books".to_string()]);
+        assert_eq!(title(&now), Value::from("Dune Messiah"));
+
+        let _ = fs::remove_file(temp_path);
+    }
+
+    #[test]
+    fn test_result_columns() {
+        use rusqlite::Connection;
+        use std::fs;
+
+        let temp_path = "/tmp/test_sqlite_serve_result_columns.db";
+        let _ = fs::remove_file(temp_path);
+        Connection::open(temp_path)
+            .unwrap()
+            .execute_batch("CREATE TABLE books (id INTEGER, title TEXT, author_id INTEGER);")
+            .unwrap();
+
+        let columns = result_columns(
+            temp_path,
+            &[
+                "SELECT * FROM books WHERE id = :id",
+                "SELECT title AS name FROM books WHERE id IN (:ids)",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            columns,
+            vec![vec!["id", "title", "author_id"], vec!["name"]]
+        );
+        assert!(result_columns(temp_path, &["SELECT * FROM missing"]).is_err());
+
+        let _ = fs::remove_file(temp_path);
+    }
 }
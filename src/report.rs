@@ -0,0 +1,349 @@
+//! Report files for `sqlite_report`
+//!
+//! A POST to a report location runs its query and saves the rows as the
+//! report's file, replacing the one the previous run saved, then records the
+//! run in the database's `report_runs` table.
+//!
+//! A report with `every=` is also run by each worker's scheduler thread,
+//! which wakes every few seconds and runs the reports that are due. Whether
+//! a report is due is kept in the database's `report_schedule` table (see
+//! [`query::claim_report`]), so only one worker runs each scheduled run.
+//! `sqlite_health` shows when each report last ran, whether it failed and
+//! when it is next due.
+
+use crate::adapters::SqliteQueryExecutor;
+use crate::content_type::ContentType;
+use crate::domain::{self, PagePosition, QueryExecutor, ValidatedConfig};
+use crate::formatter::{FormatterRegistry, Output, ResponseMeta};
+use crate::query;
+use crate::static_site;
+use crate::types::{Report, ReportFormat};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the scheduler looks for due reports
+pub const SCHEDULE_TICK: Duration = Duration::from_secs(15);
+
+/// Run `config`'s query, save its rows as `report`'s file and record the run,
+/// answering how many rows were saved
+///
+/// A failed run is recorded too. A run that cannot be recorded is an error
+/// even if its file was saved.
+pub fn generate(
+    executor: &dyn QueryExecutor,
+    config: &ValidatedConfig,
+    report: &Report,
+    params: &[(String, Value)],
+) -> Result<usize, String> {
+    let outcome = save_rows(executor, config, report, params);
+    query::record_report(&config.db_path, report, &outcome)
+        .map_err(|e| format!("could not record the run in report_runs: {}", e))?;
+    outcome
+}
+
+/// Run the query and save its rows, as the location's JSON responses have
+/// them, in the report's format
+fn save_rows(
+    executor: &dyn QueryExecutor,
+    config: &ValidatedConfig,
+    report: &Report,
+    params: &[(String, Value)],
+) -> Result<usize, String> {
+    let page = PagePosition::Number(1);
+    let (rows, _) = domain::json_rows(executor, config, params, &[], None, &page, None)
+        .map_err(|e| e.to_string())?;
+    let saved = rows.len();
+    let json = serde_json::to_string_pretty(&domain::rows_document(rows, None))
+        .map_err(|e| e.to_string())?;
+
+    let content_type = match report.format() {
+        ReportFormat::Json => ContentType::Json,
+        ReportFormat::Ndjson => ContentType::Ndjson,
+        ReportFormat::Xlsx => ContentType::Xlsx,
+    };
+    let formatter = FormatterRegistry::standard()
+        .get(content_type)
+        .ok_or_else(|| format!("no {} formatter", report.format().extension()))?
+        .render(
+            &Output::Rows(&json),
+            &ResponseMeta {
+                uri: "",
+                base_url: "",
+                feed: None,
+            },
+        )?;
+    let path = report.path();
+    static_site::save(&path, formatter.body)
+        .map_err(|e| format!("could not save {}: {}", path.display(), e))?;
+    Ok(saved)
+}
+
+/// A location whose `sqlite_report` has `every=`, for the scheduler to run
+#[derive(Debug)]
+pub struct ScheduledReport {
+    pub location: String,
+    pub config: ValidatedConfig,
+}
+
+impl ScheduledReport {
+    fn report(&self) -> Option<&Report> {
+        self.config.report.as_ref()
+    }
+}
+
+/// The scheduler thread, once started
+#[derive(Debug)]
+enum Runner {
+    Idle,
+    Running(Sender<()>, JoinHandle<()>),
+    Closed,
+}
+
+/// Runs, failures and the latest error, shared with the scheduler thread
+#[derive(Debug, Default)]
+struct Outcomes {
+    runs: AtomicU64,
+    failed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// A worker's scheduler for `sqlite_report ... every=` reports
+#[derive(Debug)]
+pub struct ReportScheduler {
+    runner: Mutex<Runner>,
+    outcomes: Arc<Outcomes>,
+}
+
+impl ReportScheduler {
+    pub fn new() -> Self {
+        ReportScheduler {
+            runner: Mutex::new(Runner::Idle),
+            outcomes: Arc::new(Outcomes::default()),
+        }
+    }
+
+    /// Start the thread that runs `reports` when they are due, looking every
+    /// `tick`; with no reports there is nothing to start
+    pub fn start(&self, reports: Vec<ScheduledReport>, tick: Duration) -> Result<(), String> {
+        let mut runner = self
+            .runner
+            .lock()
+            .map_err(|_| "report scheduler is poisoned".to_string())?;
+        if !matches!(*runner, Runner::Idle) {
+            return Err("the report scheduler has already started".to_string());
+        }
+        if reports.is_empty() {
+            return Ok(());
+        }
+        let (sender, receiver) = mpsc::channel::<()>();
+        let outcomes = Arc::clone(&self.outcomes);
+        let thread = thread::Builder::new()
+            .name("sqlite-serve-reports".to_string())
+            .spawn(move || {
+                // Closing the channel stops the thread between runs
+                run_due(&reports, &outcomes, now());
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(tick) {
+                    run_due(&reports, &outcomes, now());
+                }
+            })
+            .map_err(|e| format!("cannot start the report scheduler thread: {}", e))?;
+        *runner = Runner::Running(sender, thread);
+        Ok(())
+    }
+
+    /// Stop the thread, waiting for a report it is running to finish
+    pub fn finish(&self) {
+        let Ok(mut runner) = self.runner.lock() else {
+            return;
+        };
+        if let Runner::Running(sender, thread) = std::mem::replace(&mut *runner, Runner::Closed) {
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+
+    /// Scheduled runs made, including failed ones
+    pub fn runs(&self) -> u64 {
+        self.outcomes.runs.load(Ordering::Relaxed)
+    }
+
+    /// Scheduled runs that failed, or could not be claimed or recorded
+    pub fn failed(&self) -> u64 {
+        self.outcomes.failed.load(Ordering::Relaxed)
+    }
+
+    /// Why the most recent failure failed
+    pub fn last_error(&self) -> Option<String> {
+        self.outcomes
+            .last_error
+            .lock()
+            .ok()
+            .and_then(|last| last.clone())
+    }
+}
+
+impl Default for ReportScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run each report due at `now` that this worker claims
+fn run_due(reports: &[ScheduledReport], outcomes: &Outcomes, now: i64) {
+    for scheduled in reports {
+        let Some(report) = scheduled.report() else {
+            continue;
+        };
+        let config = &scheduled.config;
+        let result = query::claim_report(&config.db_path, report, now)
+            .map_err(|e| format!("could not claim the run: {}", e))
+            .and_then(|claimed| {
+                if !claimed {
+                    return Ok(());
+                }
+                outcomes.runs.fetch_add(1, Ordering::Relaxed);
+                let executor = SqliteQueryExecutor::for_location(config);
+                generate(&executor, config, report, &[]).map(|_| ())
+            });
+        if let Err(e) = result {
+            outcomes.failed.fetch_add(1, Ordering::Relaxed);
+            if let Ok(mut last) = outcomes.last_error.lock() {
+                *last = Some(format!(
+                    "location {}: report {}: {}",
+                    scheduled.location,
+                    report.name(),
+                    e
+                ));
+            }
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModuleConfig;
+    use crate::parsing;
+    use rusqlite::Connection;
+    use std::fs;
+
+    #[test]
+    fn test_generate() {
+        let directory = "/tmp/test_sqlite_serve_reports";
+        let db = "/tmp/test_sqlite_serve_reports.db";
+        let _ = fs::remove_dir_all(directory);
+        let _ = fs::remove_file(db);
+        Connection::open(db)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);
+                 INSERT INTO books (title) VALUES ('Dune'), ('Emma');",
+            )
+            .unwrap();
+        let report = Report::parse(&["books", directory, "format=ndjson"]).unwrap();
+        let mut config = parsing::parse_config(&ModuleConfig {
+            db_path: Some(db.to_string()),
+            query: Some("SELECT title FROM books ORDER BY id".to_string()),
+            report: Some(report.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let executor = SqliteQueryExecutor::for_location(&config);
+        assert_eq!(generate(&executor, &config, &report, &[]), Ok(2));
+        assert_eq!(
+            fs::read_to_string(report.path()).unwrap(),
+            "{\"title\":\"Dune\"}\n{\"title\":\"Emma\"}\n"
+        );
+
+        // A failed run keeps the last file and is recorded as failed
+        config.query = crate::types::SqlQuery::parse("SELECT * FROM missing").unwrap();
+        let executor = SqliteQueryExecutor::for_location(&config);
+        assert!(generate(&executor, &config, &report, &[]).is_err());
+        assert!(fs::read_to_string(report.path()).unwrap().contains("Emma"));
+
+        let runs = query::execute_query(
+            db,
+            "SELECT name, status, rows, error IS NULL AS ok FROM report_runs ORDER BY rowid",
+            &[],
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0]["name"], "books");
+        assert_eq!(runs[0]["status"], "ok");
+        assert_eq!(runs[0]["rows"], 2);
+        assert_eq!(runs[1]["status"], "failed");
+        assert_eq!(runs[1]["ok"], 0);
+
+        let _ = fs::remove_dir_all(directory);
+        let _ = fs::remove_file(db);
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let directory = "/tmp/test_sqlite_serve_scheduled_reports";
+        let db = "/tmp/test_sqlite_serve_scheduled_reports.db";
+        let _ = fs::remove_dir_all(directory);
+        let _ = fs::remove_file(db);
+        Connection::open(db)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);
+                 INSERT INTO books (title) VALUES ('Dune');",
+            )
+            .unwrap();
+        let scheduled = |name: &str, query: &str| {
+            let report = Report::parse(&[name, directory, "every=1h"]).unwrap();
+            ScheduledReport {
+                location: format!("/{}", name),
+                config: parsing::parse_config(&ModuleConfig {
+                    db_path: Some(db.to_string()),
+                    query: Some(query.to_string()),
+                    report: Some(report),
+                    ..Default::default()
+                })
+                .unwrap(),
+            }
+        };
+        let reports = vec![
+            scheduled("books", "SELECT title FROM books"),
+            scheduled("broken", "SELECT * FROM missing"),
+        ];
+
+        // Each report runs once when the scheduler starts, then not again
+        // until an hour has passed, however often it looks
+        let scheduler = ReportScheduler::new();
+        scheduler.start(reports, Duration::from_millis(10)).unwrap();
+        let started = std::time::Instant::now();
+        while scheduler.runs() < 2 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(50));
+        scheduler.finish();
+        assert_eq!(scheduler.runs(), 2);
+        assert_eq!(scheduler.failed(), 1);
+        assert!(scheduler.last_error().unwrap().contains("location /broken"));
+        assert!(
+            fs::read_to_string(format!("{}/books.json", directory))
+                .unwrap()
+                .contains("Dune")
+        );
+
+        // A stopped scheduler does not start again
+        assert!(scheduler.start(Vec::new(), SCHEDULE_TICK).is_err());
+
+        let _ = fs::remove_dir_all(directory);
+        let _ = fs::remove_file(db);
+    }
+}
@@ -0,0 +1,427 @@
+//! Statement generation for `sqlite_rest` and `sqlite_upsert` locations (pure
+//! functions)
+
+use crate::types::{
+    NginxVariable, ParamName, ParameterBinding, QueryRule, RestTable, SqlQuery, UpsertTable,
+    WriteMethod, WriteQuery, is_identifier,
+};
+
+/// A table column as reported by `PRAGMA table_info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableColumn {
+    pub name: String,
+    pub declared_type: String,
+    pub primary_key: bool,
+}
+
+/// Everything a `sqlite_rest` location runs, derived from the table's columns
+#[derive(Debug, Clone)]
+pub struct RestStatements {
+    /// GET without a key: every row
+    pub list: SqlQuery,
+    /// GET with a key: the matching row
+    pub lookup: QueryRule,
+    /// POST inserts, PUT replaces every column, PATCH only the non-empty ones,
+    /// DELETE removes the row
+    pub writes: Vec<(WriteMethod, WriteQuery)>,
+    /// The configured bindings plus `:column $arg_column` for every unbound column
+    pub bindings: Vec<ParameterBinding>,
+}
+
+/// Generate the statements and bindings for a table
+///
+/// Each column is bound by name, so `sqlite_param` can override where a
+/// value comes from (e.g. `sqlite_param :id $1;`). Missing or empty values are
+/// stored as NULL, and PATCH leaves such columns unchanged. An `INTEGER PRIMARY
+/// KEY` key is assigned by SQLite on insert.
+pub fn generate(
+    rest: &RestTable,
+    columns: &[TableColumn],
+    bindings: &[ParameterBinding],
+) -> Result<RestStatements, String> {
+    let table = rest.table();
+    let key = rest.key();
+
+    check_columns(table, columns, bindings, "sqlite_rest")?;
+    let Some(key_column) = columns.iter().find(|c| c.name == key) else {
+        return Err(format!("table '{}' has no column '{}'", table, key));
+    };
+
+    let rowid_key = key_column.primary_key
+        && key_column.declared_type.eq_ignore_ascii_case("INTEGER")
+        && columns.iter().filter(|c| c.primary_key).count() == 1;
+    let values: Vec<&str> = columns
+        .iter()
+        .map(|c| c.name.as_str())
+        .filter(|name| *name != key)
+        .collect();
+
+    let list = SqlQuery::parse(format!("SELECT * FROM \"{}\"", table))?;
+    let by_key = format!("\"{}\" = :{}", key, key);
+    let lookup_sql = format!("SELECT * FROM \"{}\" WHERE {}", table, by_key);
+
+    let inserted: Vec<&str> = if rowid_key {
+        values.clone()
+    } else {
+        std::iter::once(key).chain(values.iter().copied()).collect()
+    };
+    let insert = if inserted.is_empty() {
+        format!("INSERT INTO \"{}\" DEFAULT VALUES", table)
+    } else {
+        format!(
+            "INSERT INTO \"{}\" ({}) VALUES ({})",
+            table,
+            join(&inserted, |c| format!("\"{}\"", c)),
+            join(&inserted, |c| format!("NULLIF(:{}, '')", c)),
+        )
+    };
+
+    let mut writes = vec![(WriteMethod::Post, WriteQuery::parse(insert)?)];
+    if !values.is_empty() {
+        let put = format!(
+            "UPDATE \"{}\" SET {} WHERE {}",
+            table,
+            join(&values, |c| format!("\"{}\" = NULLIF(:{}, '')", c, c)),
+            by_key
+        );
+        let patch = format!(
+            "UPDATE \"{}\" SET {} WHERE {}",
+            table,
+            join(&values, |c| format!(
+                "\"{}\" = COALESCE(NULLIF(:{}, ''), \"{}\")",
+                c, c, c
+            )),
+            by_key
+        );
+        writes.push((WriteMethod::Put, WriteQuery::parse(put)?));
+        writes.push((WriteMethod::Patch, WriteQuery::parse(patch)?));
+    }
+    writes.push((
+        WriteMethod::Delete,
+        WriteQuery::parse(format!("DELETE FROM \"{}\" WHERE {}", table, by_key))?,
+    ));
+
+    let all_bindings = bind_columns(columns, bindings)?;
+
+    // The lookup applies whenever the key's variable has a value
+    let key_variable = bound_variable(&all_bindings, &format!(":{}", key))
+        .map(|v| v.as_str().to_string())
+        .unwrap_or_else(|| format!("$arg_{}", key));
+    let lookup = QueryRule::parse(&key_variable, &lookup_sql)?;
+
+    Ok(RestStatements {
+        list,
+        lookup,
+        writes,
+        bindings: all_bindings,
+    })
+}
+
+/// Generate a `sqlite_upsert` location's POST statement and bindings
+///
+/// Every submitted column is inserted, and a row that already has the same
+/// key columns is updated instead (a table of only key columns leaves it
+/// alone). The key columns need a PRIMARY KEY or UNIQUE constraint, which the
+/// startup check catches when preparing the statement. Columns are bound as
+/// for `sqlite_rest`, with missing or empty values stored as NULL.
+pub fn generate_upsert(
+    upsert: &UpsertTable,
+    columns: &[TableColumn],
+    bindings: &[ParameterBinding],
+) -> Result<(WriteQuery, Vec<ParameterBinding>), String> {
+    let table = upsert.table();
+    let keys = upsert.keys();
+
+    check_columns(table, columns, bindings, "sqlite_upsert")?;
+    if let Some(key) = keys.iter().find(|k| !columns.iter().any(|c| c.name == **k)) {
+        return Err(format!("table '{}' has no column '{}'", table, key));
+    }
+
+    let inserted: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+    let updated: Vec<&str> = inserted
+        .iter()
+        .copied()
+        .filter(|name| !keys.iter().any(|k| k == name))
+        .collect();
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+    let on_conflict = if updated.is_empty() {
+        "DO NOTHING".to_string()
+    } else {
+        format!(
+            "DO UPDATE SET {}",
+            join(&updated, |c| format!("\"{}\" = excluded.\"{}\"", c, c))
+        )
+    };
+    let sql = format!(
+        "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) {}",
+        table,
+        join(&inserted, |c| format!("\"{}\"", c)),
+        join(&inserted, |c| format!("NULLIF(:{}, '')", c)),
+        join(&keys, |c| format!("\"{}\"", c)),
+        on_conflict
+    );
+
+    Ok((WriteQuery::parse(sql)?, bind_columns(columns, bindings)?))
+}
+
+/// Check that every column of a generated statement can be bound by name
+fn check_columns(
+    table: &str,
+    columns: &[TableColumn],
+    bindings: &[ParameterBinding],
+    directive: &str,
+) -> Result<(), String> {
+    if columns.is_empty() {
+        return Err(format!("table '{}' has no columns", table));
+    }
+    if let Some(column) = columns.iter().find(|c| !is_identifier(&c.name)) {
+        return Err(format!(
+            "column '{}' cannot be bound by name; use sqlite_query and sqlite_write_query",
+            column.name
+        ));
+    }
+    if bindings.iter().any(|b| {
+        matches!(
+            b,
+            ParameterBinding::Positional { .. } | ParameterBinding::PositionalLiteral { .. }
+        )
+    }) {
+        return Err(format!(
+            "{} binds parameters by name; use :name in sqlite_param",
+            directive
+        ));
+    }
+    Ok(())
+}
+
+/// The configured bindings plus `:column $arg_column` for every unbound column
+fn bind_columns(
+    columns: &[TableColumn],
+    bindings: &[ParameterBinding],
+) -> Result<Vec<ParameterBinding>, String> {
+    let mut all_bindings = bindings.to_vec();
+    for column in columns {
+        let name = format!(":{}", column.name);
+        if !bindings
+            .iter()
+            .any(|b| binding_name(b) == Some(name.as_str()))
+        {
+            all_bindings.push(ParameterBinding::OptionalNamed {
+                name: ParamName::parse(&name)?,
+                variable: NginxVariable::parse(format!("$arg_{}", column.name))?,
+                max_length: None,
+            });
+        }
+    }
+    Ok(all_bindings)
+}
+
+fn join(columns: &[&str], render: impl Fn(&str) -> String) -> String {
+    columns
+        .iter()
+        .map(|c| render(c))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn binding_name(binding: &ParameterBinding) -> Option<&str> {
+    match binding {
+        ParameterBinding::Named { name, .. }
+        | ParameterBinding::NamedLiteral { name, .. }
+        | ParameterBinding::OptionalNamed { name, .. }
+        | ParameterBinding::NamedList { name, .. } => Some(name.as_str()),
+        ParameterBinding::Positional { .. }
+        | ParameterBinding::PositionalLiteral { .. }
+        | ParameterBinding::PositionalDefault { .. }
+        | ParameterBinding::QueryArgs { .. } => None,
+    }
+}
+
+/// The variable a named parameter is bound to, if it is bound to one
+fn bound_variable<'a>(bindings: &'a [ParameterBinding], name: &str) -> Option<&'a NginxVariable> {
+    bindings.iter().find_map(|binding| match binding {
+        ParameterBinding::Named {
+            name: n, variable, ..
+        }
+        | ParameterBinding::OptionalNamed {
+            name: n, variable, ..
+        } if n.as_str() == name => Some(variable),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ParamType;
+
+    fn column(name: &str, declared_type: &str, primary_key: bool) -> TableColumn {
+        TableColumn {
+            name: name.to_string(),
+            declared_type: declared_type.to_string(),
+            primary_key,
+        }
+    }
+
+    fn books() -> Vec<TableColumn> {
+        vec![
+            column("id", "INTEGER", true),
+            column("title", "TEXT", false),
+            column("year", "INTEGER", false),
+        ]
+    }
+
+    fn write_sql(statements: &RestStatements, method: WriteMethod) -> &str {
+        statements
+            .writes
+            .iter()
+            .find(|(m, _)| *m == method)
+            .map(|(_, q)| q.as_str())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_generate_statements() {
+        let rest = RestTable::parse("books", None).unwrap();
+        let statements = generate(&rest, &books(), &[]).unwrap();
+
+        assert_eq!(statements.list.as_str(), "SELECT * FROM \"books\"");
+        assert_eq!(statements.lookup.variable().as_str(), "$arg_id");
+        assert_eq!(
+            statements.lookup.query().as_str(),
+            "SELECT * FROM \"books\" WHERE \"id\" = :id"
+        );
+        // The INTEGER PRIMARY KEY is assigned by SQLite
+        assert_eq!(
+            write_sql(&statements, WriteMethod::Post),
+            "INSERT INTO \"books\" (\"title\", \"year\") VALUES (NULLIF(:title, ''), NULLIF(:year, ''))"
+        );
+        assert_eq!(
+            write_sql(&statements, WriteMethod::Put),
+            "UPDATE \"books\" SET \"title\" = NULLIF(:title, ''), \"year\" = NULLIF(:year, '') WHERE \"id\" = :id"
+        );
+        assert!(
+            write_sql(&statements, WriteMethod::Patch)
+                .contains("COALESCE(NULLIF(:title, ''), \"title\")")
+        );
+        assert_eq!(
+            write_sql(&statements, WriteMethod::Delete),
+            "DELETE FROM \"books\" WHERE \"id\" = :id"
+        );
+        assert_eq!(statements.bindings.len(), 3);
+    }
+
+    #[test]
+    fn test_generate_with_text_key_and_override() {
+        let rest = RestTable::parse("books", Some("key=isbn")).unwrap();
+        let columns = vec![column("isbn", "TEXT", true), column("title", "TEXT", false)];
+        let bindings = vec![ParameterBinding::Named {
+            name: ParamName::parse(":isbn").unwrap(),
+            variable: NginxVariable::parse("$1").unwrap(),
+            param_type: ParamType::Text,
+            default: None,
+            required: false,
+            pattern: None,
+            max_length: None,
+        }];
+        let statements = generate(&rest, &columns, &bindings).unwrap();
+
+        // Text keys are supplied by the client
+        assert!(write_sql(&statements, WriteMethod::Post).contains("(\"isbn\", \"title\")"));
+        // The configured binding wins and drives the lookup
+        assert_eq!(statements.lookup.variable().as_str(), "$1");
+        assert_eq!(statements.bindings.len(), 2);
+    }
+
+    #[test]
+    fn test_generate_rejects_bad_tables() {
+        let rest = RestTable::parse("books", Some("key=isbn")).unwrap();
+        assert!(generate(&rest, &[], &[]).is_err());
+        assert!(
+            generate(&rest, &books(), &[])
+                .unwrap_err()
+                .contains("no column 'isbn'")
+        );
+
+        let rest = RestTable::parse("books", None).unwrap();
+        let odd = vec![
+            column("id", "INTEGER", true),
+            column("first name", "TEXT", false),
+        ];
+        assert!(generate(&rest, &odd, &[]).is_err());
+
+        let positional = vec![ParameterBinding::PositionalLiteral {
+            value: "1".to_string(),
+        }];
+        assert!(generate(&rest, &books(), &positional).is_err());
+    }
+
+    #[test]
+    fn test_generate_upsert() {
+        let upsert = UpsertTable::parse("books", "(id)").unwrap();
+        let (write, bindings) = generate_upsert(&upsert, &books(), &[]).unwrap();
+        assert_eq!(
+            write.as_str(),
+            "INSERT INTO \"books\" (\"id\", \"title\", \"year\") \
+             VALUES (NULLIF(:id, ''), NULLIF(:title, ''), NULLIF(:year, '')) \
+             ON CONFLICT (\"id\") DO UPDATE SET \"title\" = excluded.\"title\", \"year\" = excluded.\"year\""
+        );
+        assert_eq!(bindings.len(), 3);
+
+        let upsert = UpsertTable::parse("books", "(id, title, year)").unwrap();
+        let (write, _) = generate_upsert(&upsert, &books(), &[]).unwrap();
+        assert!(
+            write
+                .as_str()
+                .ends_with("ON CONFLICT (\"id\", \"title\", \"year\") DO NOTHING")
+        );
+
+        let upsert = UpsertTable::parse("books", "(isbn)").unwrap();
+        assert!(
+            generate_upsert(&upsert, &books(), &[])
+                .unwrap_err()
+                .contains("no column 'isbn'")
+        );
+    }
+
+    #[test]
+    fn test_upsert_runs() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE books (isbn TEXT PRIMARY KEY, title TEXT, year INTEGER)")
+            .unwrap();
+        let columns = vec![
+            column("isbn", "TEXT", true),
+            column("title", "TEXT", false),
+            column("year", "INTEGER", false),
+        ];
+        let upsert = UpsertTable::parse("books", "(isbn)").unwrap();
+        let (write, _) = generate_upsert(&upsert, &columns, &[]).unwrap();
+
+        for (title, year) in [("Dune", "1965"), ("Dune Messiah", "")] {
+            conn.execute(
+                write.as_str(),
+                rusqlite::named_params! {":isbn": "0441172717", ":title": title, ":year": year},
+            )
+            .unwrap();
+        }
+        let row: (String, Option<i64>, i64) = conn
+            .query_row("SELECT title, year, COUNT(*) FROM books", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(row, ("Dune Messiah".to_string(), None, 1));
+    }
+
+    #[test]
+    fn test_generate_key_only_table() {
+        let rest = RestTable::parse("tokens", None).unwrap();
+        let statements = generate(&rest, &[column("id", "INTEGER", true)], &[]).unwrap();
+        let methods: Vec<WriteMethod> = statements.writes.iter().map(|(m, _)| *m).collect();
+        assert_eq!(methods, vec![WriteMethod::Post, WriteMethod::Delete]);
+        assert_eq!(
+            write_sql(&statements, WriteMethod::Post),
+            "INSERT INTO \"tokens\" DEFAULT VALUES"
+        );
+    }
+}
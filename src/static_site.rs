@@ -0,0 +1,134 @@
+//! Saved pages for `sqlite_try_static`
+//!
+//! The page for a URI lives at `<root><uri>/index.html`, beside the templates
+//! the location renders it with, so the saved pages form a directory-style
+//! static site that any web server can serve.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Where the page for a URI is saved, or `None` for a URI that could leave
+/// the document root
+pub fn page_path(doc_root: &str, uri: &str) -> Option<PathBuf> {
+    let relative = uri.strip_prefix('/')?;
+    if relative
+        .split('/')
+        .any(|segment| segment == ".." || segment == "." || segment.contains('\0'))
+    {
+        return None;
+    }
+
+    let mut path = PathBuf::from(doc_root);
+    for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+        path.push(segment);
+    }
+    path.push("index.html");
+    Some(path)
+}
+
+/// The saved page at `path`, if there is one and it is still fresh
+///
+/// A page is stale once it is older than `max_age`, or older than any of the
+/// `databases` it was rendered from (including their `-wal` files, which
+/// change first in WAL mode).
+pub fn read_fresh(
+    path: &Path,
+    max_age: Option<Duration>,
+    databases: &[&str],
+    now: SystemTime,
+) -> Option<String> {
+    let saved = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+
+    if let Some(max_age) = max_age
+        && now.duration_since(saved).unwrap_or_default() > max_age
+    {
+        return None;
+    }
+    let changed_since = |file: &str| {
+        fs::metadata(file)
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified > saved)
+    };
+    if databases
+        .iter()
+        .any(|db| changed_since(db) || changed_since(&format!("{}-wal", db)))
+    {
+        return None;
+    }
+
+    fs::read_to_string(path).ok()
+}
+
+/// Save a rendered page (or a `sqlite_report` file), creating its directory
+/// as needed
+///
+/// The file is written beside its final path and renamed into place, so a
+/// concurrent reader sees either the old file or the new one.
+pub fn save(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{}.tmp", std::process::id()));
+    let partial = PathBuf::from(partial);
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, path).inspect_err(|_| {
+        let _ = fs::remove_file(&partial);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_path() {
+        assert_eq!(
+            page_path("/srv/www", "/books/").unwrap(),
+            PathBuf::from("/srv/www/books/index.html")
+        );
+        assert_eq!(
+            page_path("/srv/www", "/book/42").unwrap(),
+            PathBuf::from("/srv/www/book/42/index.html")
+        );
+        assert_eq!(
+            page_path("/srv/www", "/").unwrap(),
+            PathBuf::from("/srv/www/index.html")
+        );
+        assert!(page_path("/srv/www", "/books/../../etc").is_none());
+        assert!(page_path("/srv/www", "books").is_none());
+    }
+
+    #[test]
+    fn test_save_and_read_fresh() {
+        let root = "/tmp/test_sqlite_serve_static";
+        let db = "/tmp/test_sqlite_serve_static.db";
+        let _ = fs::remove_dir_all(root);
+        fs::write(db, "").unwrap();
+
+        let page = page_path(root, "/books").unwrap();
+        assert!(read_fresh(&page, None, &[db], SystemTime::now()).is_none());
+
+        save(&page, "<h1>Books</h1>").unwrap();
+        let now = SystemTime::now();
+        assert_eq!(
+            read_fresh(&page, None, &[db], now).as_deref(),
+            Some("<h1>Books</h1>")
+        );
+
+        // Too old for max_age
+        let later = now + Duration::from_secs(120);
+        assert!(read_fresh(&page, Some(Duration::from_secs(60)), &[db], later).is_none());
+        assert!(read_fresh(&page, Some(Duration::from_secs(600)), &[db], later).is_some());
+
+        // The database changed after the page was saved
+        let file = fs::File::options().write(true).open(db).unwrap();
+        file.set_modified(now + Duration::from_secs(5)).unwrap();
+        assert!(read_fresh(&page, None, &[db], now).is_none());
+
+        let _ = fs::remove_dir_all(root);
+        let _ = fs::remove_file(db);
+    }
+}
@@ -0,0 +1,281 @@
+//! Streamed responses (`sqlite_stream_buffer`), handed to nginx a part at a
+//! time as the client takes them (pure functions)
+//!
+//! Without a buffer a response goes to nginx's output filters in one piece,
+//! and all of it waits in memory for as long as a slow client takes to read
+//! it. A streamed one is sent in parts of whole lines, each only once what
+//! nginx still holds unsent for the client leaves room for it under the
+//! buffer's size. When the next part does not fit, the stream waits for the
+//! client to take some, or drops the connection, as the location's
+//! `sqlite_stream_overflow` says.
+//!
+//! The lines are made as they are needed rather than all at once, so a
+//! stream holds no more than a part and a line of its body at a time.
+//!
+//! The nginx side measures what is unsent and waits for the connection to be
+//! writable; [`ResponseStream`] decides what to send next and keeps the
+//! figures logged for each connection.
+
+use crate::types::{StreamBuffer, StreamOverflow};
+
+/// The lines of a streamed body, each made when the stream reaches it
+pub type Lines = Box<dyn Iterator<Item = Vec<u8>>>;
+
+/// What a stream does next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamStep<'a> {
+    /// Hand these bytes to nginx, flushed to the client
+    Send(&'a [u8]),
+    /// Send nothing until the client has taken some of what is unsent
+    Wait,
+    /// Close the connection of a client too slow for the buffer
+    Drop,
+    /// Everything has been handed over; end the response
+    Done,
+}
+
+/// How a stream ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEnd {
+    /// All of it was handed to nginx
+    Sent,
+    /// Its client was too slow, with `sqlite_stream_overflow drop`
+    Dropped,
+    /// Its client took nothing for longer than `send_timeout`
+    TimedOut,
+    /// nginx could not send it
+    Failed,
+}
+
+impl StreamEnd {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamEnd::Sent => "sent",
+            StreamEnd::Dropped => "dropped",
+            StreamEnd::TimedOut => "timed out",
+            StreamEnd::Failed => "failed",
+        }
+    }
+
+    /// The worker counter of streams that ended this way, if they are counted
+    pub fn counter(&self) -> Option<&'static str> {
+        match self {
+            StreamEnd::Sent => None,
+            StreamEnd::Dropped => Some("stream_drops"),
+            StreamEnd::TimedOut => Some("stream_timeouts"),
+            StreamEnd::Failed => Some("stream_failures"),
+        }
+    }
+}
+
+/// A response being streamed, with what it has cost its connection
+pub struct ResponseStream {
+    lines: Lines,
+    /// Lines made but not yet handed to nginx, after the last part's bytes
+    pending: Vec<u8>,
+    /// How much of `pending` the last part was
+    handed: usize,
+    /// Whether `lines` has no more
+    made: bool,
+    sent: usize,
+    parts: u64,
+    buffer: StreamBuffer,
+    peak: usize,
+    waits: u64,
+}
+
+impl ResponseStream {
+    pub fn new(lines: Lines, buffer: StreamBuffer) -> Self {
+        ResponseStream {
+            lines,
+            pending: Vec::new(),
+            handed: 0,
+            made: false,
+            sent: 0,
+            parts: 0,
+            buffer,
+            peak: 0,
+            waits: 0,
+        }
+    }
+
+    /// The next step, given how many bytes nginx holds unsent for the client
+    ///
+    /// A part is as many whole lines as fit beside what is unsent. A line
+    /// longer than the buffer is sent on its own once nothing is unsent, so
+    /// every stream gets to its end.
+    pub fn next(&mut self, unsent: usize) -> StreamStep<'_> {
+        self.peak = self.peak.max(unsent);
+        // nginx has copied the last part
+        self.pending.drain(..self.handed);
+        self.handed = 0;
+
+        // Make lines until they overflow the room, so it is known which fit
+        let room = self.buffer.size().saturating_sub(unsent);
+        while self.pending.len() <= room && !self.made {
+            match self.lines.next() {
+                Some(line) => self.pending.extend_from_slice(&line),
+                None => self.made = true,
+            }
+        }
+        let rest = &self.pending;
+        if rest.is_empty() {
+            return StreamStep::Done;
+        }
+
+        let fits = &rest[..room.min(rest.len())];
+        let end = match fits.iter().rposition(|&b| b == b'\n') {
+            _ if fits.len() == rest.len() => rest.len(),
+            Some(newline) => newline + 1,
+            None if unsent == 0 => line_end(rest),
+            None => {
+                return match self.buffer.overflow() {
+                    StreamOverflow::Wait => {
+                        self.waits += 1;
+                        StreamStep::Wait
+                    }
+                    StreamOverflow::Drop => StreamStep::Drop,
+                };
+            }
+        };
+        self.handed = end;
+        self.sent += end;
+        self.parts += 1;
+        StreamStep::Send(&self.pending[..end])
+    }
+
+    /// The most bytes a part holds, but for a line longer than that
+    pub fn capacity(&self) -> usize {
+        self.buffer.size()
+    }
+
+    /// The bytes handed to nginx so far
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// The most bytes nginx was seen holding unsent for the client
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    /// How many times the stream waited for the client
+    pub fn waits(&self) -> u64 {
+        self.waits
+    }
+
+    /// The figures logged when the stream ends, e.g.
+    /// `sent 20480 bytes in 3 part(s), at most 8192 unsent, waited 3 time(s)`
+    pub fn summary(&self) -> String {
+        format!(
+            "sent {} bytes in {} part(s), at most {} unsent, waited {} time(s)",
+            self.sent, self.parts, self.peak, self.waits
+        )
+    }
+}
+
+/// The length of the first line of `bytes`, with its newline
+fn line_end(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(bytes.len(), |newline| newline + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(body: &str, size: &str, overflow: StreamOverflow) -> ResponseStream {
+        let buffer = StreamBuffer::parse(size).unwrap().with_overflow(overflow);
+        ResponseStream::new(lines(body), buffer)
+    }
+
+    fn lines(body: &str) -> Lines {
+        let lines: Vec<Vec<u8>> = body
+            .split_inclusive('\n')
+            .map(|line| line.as_bytes().to_vec())
+            .collect();
+        Box::new(lines.into_iter())
+    }
+
+    #[test]
+    fn test_stream_parts() {
+        let mut lines = stream(
+            "{\"a\":1}\n{\"b\":2}\n{\"c\":3}\n",
+            "16",
+            StreamOverflow::Wait,
+        );
+        // Two lines fill the buffer; the third waits for the client
+        assert_eq!(lines.next(0), StreamStep::Send(b"{\"a\":1}\n{\"b\":2}\n"));
+        assert_eq!(lines.next(16), StreamStep::Wait);
+        assert_eq!(lines.next(10), StreamStep::Wait);
+        assert_eq!(lines.next(8), StreamStep::Send(b"{\"c\":3}\n"));
+        assert_eq!(lines.next(8), StreamStep::Done);
+        assert_eq!(lines.sent(), 24);
+        assert_eq!((lines.peak(), lines.waits()), (16, 2));
+        assert_eq!(
+            lines.summary(),
+            "sent 24 bytes in 2 part(s), at most 16 unsent, waited 2 time(s)"
+        );
+
+        // A line longer than the buffer goes on its own once nothing is unsent
+        let mut long = stream("0123456789\nab\ncd", "4", StreamOverflow::Wait);
+        assert_eq!(long.next(0), StreamStep::Send(b"0123456789\n"));
+        assert_eq!(long.next(2), StreamStep::Wait);
+        assert_eq!(long.next(0), StreamStep::Send(b"ab\n"));
+        // The last line needs no newline
+        assert_eq!(long.next(3), StreamStep::Wait);
+        assert_eq!(long.next(0), StreamStep::Send(b"cd"));
+        assert_eq!(long.next(0), StreamStep::Done);
+
+        let mut empty = stream("", "4", StreamOverflow::Wait);
+        assert_eq!(empty.next(0), StreamStep::Done);
+    }
+
+    #[test]
+    fn test_stream_makes_lines_as_needed() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        // Count the lines made, of a body far larger than the buffer
+        let made = Rc::new(Cell::new(0));
+        let counted = Rc::clone(&made);
+        let lines = (0..1000).map(move |i| {
+            counted.set(counted.get() + 1);
+            format!("{{\"id\":{:03}}}\n", i).into_bytes()
+        });
+        let buffer = StreamBuffer::parse("40").unwrap();
+        let mut stream = ResponseStream::new(Box::new(lines), buffer);
+
+        // Each line is 11 bytes: three fit, and a fourth is made to know that
+        assert_eq!(
+            stream.next(0),
+            StreamStep::Send(b"{\"id\":000}\n{\"id\":001}\n{\"id\":002}\n")
+        );
+        assert_eq!(made.get(), 4);
+        assert_eq!(stream.next(33), StreamStep::Wait);
+        assert_eq!(made.get(), 4);
+        assert_eq!(
+            stream.next(0),
+            StreamStep::Send(b"{\"id\":003}\n{\"id\":004}\n{\"id\":005}\n")
+        );
+        assert_eq!(made.get(), 7);
+
+        while let StreamStep::Send(part) = stream.next(0) {
+            assert!(part.len() <= 40);
+        }
+        assert_eq!(made.get(), 1000);
+        assert_eq!(stream.sent(), 11_000);
+        assert_eq!(stream.parts, 334);
+    }
+
+    #[test]
+    fn test_stream_drop() {
+        let mut lines = stream("one\ntwo\nthree\n", "8", StreamOverflow::Drop);
+        assert_eq!(lines.next(0), StreamStep::Send(b"one\ntwo\n"));
+        assert_eq!(lines.next(5), StreamStep::Drop);
+        assert_eq!(lines.waits(), 0);
+        assert_eq!(lines.sent(), 8);
+    }
+}
@@ -1,24 +1,34 @@
 //! Template loading and management
 
-use handlebars::Handlebars;
+use handlebars::template::{Parameter, TemplateElement};
+use handlebars::{
+    BlockContext, Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext,
+    RenderError, RenderErrorReason, Renderable, ScopedJson, Template, TemplateError,
+    handlebars_helper,
+};
 use serde_json::Value;
-use std::{ffi::OsStr, path::Path};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::domain::{TemplateLoader, TemplateRenderer};
+use crate::domain::{self, TemplateLoader, TemplateRenderer};
 
-/// Load all .hbs templates from a directory into the Handlebars registry
+/// List the .hbs files in a directory as (template name, path) pairs
 ///
-/// Each template is registered by its filename (without .hbs extension).
-/// Returns the number of templates successfully loaded.
-fn load_templates_from_dir(reg: &mut Handlebars, dir_path: &str) -> std::io::Result<usize> {
+/// The template name is the filename without its .hbs extension.
+/// A missing directory yields an empty list.
+pub fn template_files_in_dir(dir_path: &str) -> std::io::Result<Vec<(String, PathBuf)>> {
     use std::fs;
 
     let dir = Path::new(dir_path);
     if !dir.exists() || !dir.is_dir() {
-        return Ok(0);
+        return Ok(Vec::new());
     }
 
-    let mut count = 0;
+    let mut files = Vec::new();
     for entry in fs::read_dir(dir)? {
         let path = entry?.path();
 
@@ -28,45 +38,589 @@ fn load_templates_from_dir(reg: &mut Handlebars, dir_path: &str) -> std::io::Res
         }
 
         if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
-            if let Err(e) = reg.register_template_file(name, &path) {
-                eprintln!("Failed to register template {}: {}", path.display(), e);
-            } else {
-                count += 1;
+            files.push((name.to_string(), path.clone()));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Lines of source shown before and after the line a template error is on
+const CONTEXT_LINES: usize = 2;
+
+/// A template file that could not be read or compiled, with where in it
+/// Handlebars stopped and the source around that point
+///
+/// It displays as a summary line, `path:line:column: reason`, then the
+/// numbered source lines with a caret under the column. Only the summary
+/// is shown outside development mode, so the first line must stand alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceError {
+    path: String,
+    position: Option<(usize, usize)>,
+    reason: String,
+    context: Vec<(usize, String)>,
+}
+
+impl SourceError {
+    /// A syntax error Handlebars reported in `source`, read from `path`
+    fn syntax(path: &str, source: &str, error: &TemplateError) -> Self {
+        let position = error.pos();
+        let context = position
+            .map(|(line, _)| {
+                let first = line.saturating_sub(CONTEXT_LINES).max(1);
+                source
+                    .lines()
+                    .enumerate()
+                    .map(|(index, text)| (index + 1, text.to_string()))
+                    .skip(first - 1)
+                    .take(line + CONTEXT_LINES + 1 - first)
+                    .collect()
+            })
+            .unwrap_or_default();
+        SourceError {
+            path: path.to_string(),
+            position,
+            reason: error.reason().to_string(),
+            context,
+        }
+    }
+
+    /// A template file that could not be read
+    fn unreadable(path: &str, error: &std::io::Error) -> Self {
+        SourceError {
+            path: path.to_string(),
+            position: None,
+            reason: error.to_string(),
+            context: Vec::new(),
+        }
+    }
+
+    /// `path:line:column: reason`, or `path: reason` without a position
+    pub fn summary(&self) -> String {
+        match self.position {
+            Some((line, column)) => {
+                format!("{}:{}:{}: {}", self.path, line, column, self.reason)
+            }
+            None => format!("{}: {}", self.path, self.reason),
+        }
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.summary())?;
+        let width = self
+            .context
+            .last()
+            .map_or(0, |(number, _)| number.to_string().len());
+        for (number, text) in &self.context {
+            write!(f, "\n{:>width$} | {}", number, text)?;
+            if let Some((line, column)) = self.position
+                && *number == line
+            {
+                write!(f, "\n{:>width$} | {:>column$}", "", "^")?;
             }
         }
+        Ok(())
     }
+}
 
-    Ok(count)
+/// Read a template file and register it under `name`
+fn register_source_file(reg: &mut Handlebars, name: &str, path: &Path) -> Result<(), SourceError> {
+    let display = path.to_string_lossy();
+    let source =
+        std::fs::read_to_string(path).map_err(|e| SourceError::unreadable(&display, &e))?;
+    reg.register_template_string(name, &source)
+        .map_err(|e| SourceError::syntax(&display, &source, &e))
+}
+
+/// Load all .hbs templates from a directory into the Handlebars registry
+///
+/// Each template is registered by its filename (without .hbs extension),
+/// and returns the number of templates loaded. A template that does not
+/// compile is left out while the rest are loaded, and the load fails with
+/// every such error afterwards.
+fn load_templates_from_dir(reg: &mut Handlebars, dir_path: &str) -> Result<usize, String> {
+    let mut count = 0;
+    let mut failed = Vec::new();
+    for (name, path) in template_files_in_dir(dir_path).map_err(|e| e.to_string())? {
+        match register_source_file(reg, &name, &path) {
+            Ok(()) => count += 1,
+            Err(e) => failed.push(e.to_string()),
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(count)
+    } else {
+        Err(failed.join("\n"))
+    }
+}
+
+/// Read and compile a template file without registering it
+pub fn compile_template_file(path: &str) -> Result<(), SourceError> {
+    let source = std::fs::read_to_string(path).map_err(|e| SourceError::unreadable(path, &e))?;
+    Template::compile(&source)
+        .map(|_| ())
+        .map_err(|e| SourceError::syntax(path, &source, &e))
+}
+
+/// The HTML page answering a request whose page could not be rendered
+///
+/// The details hold the failure's first line, the summary of a
+/// [`SourceError`]; in development mode they hold all of it, with the
+/// template source around a syntax error.
+pub fn render_error_page(error: &str, development: bool) -> String {
+    let details = if development {
+        error
+    } else {
+        error.lines().next().unwrap_or_default()
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><title>Error - sqlite-serve</title></head>
+<body style="font-family: monospace; max-width: 800px; margin: 2rem auto; padding: 0 1rem;">
+    <h1 style="color: #CC9393;">Request Processing Error</h1>
+    <p style="color: #A6A689;">An error occurred while processing your request.</p>
+    <details style="margin-top: 1rem; background: #1111; padding: 1rem; border-left: 3px solid #CC9393;">
+        <summary style="cursor: pointer; color: #DFAF8F; font-weight: bold;">Error Details</summary>
+        <pre style="margin-top: 1rem; color: #DCDCCC; overflow-x: auto;">{}</pre>
+    </details>
+    <p style="margin-top: 2rem;"><a href="/" style="color: #7CB8BB;">← Back to Home</a></p>
+</body>
+</html>"#,
+        handlebars::html_escape(details)
+    )
+}
+
+/// List the partials a template references by static name (`{{> name}}`, `{{#> name}}`)
+///
+/// Partials defined inline with `{{#*inline "name"}}` and `@partial-block` are
+/// not reported, since they never need to exist on disk.
+pub fn partial_references(source: &str) -> Result<Vec<String>, String> {
+    let template = Template::compile(source).map_err(|e| e.to_string())?;
+
+    let mut referenced = Vec::new();
+    let mut inline = Vec::new();
+    collect_partials(&template, &mut referenced, &mut inline);
+
+    referenced.retain(|name| !inline.contains(name));
+    Ok(referenced)
+}
+
+fn collect_partials(template: &Template, referenced: &mut Vec<String>, inline: &mut Vec<String>) {
+    for element in &template.elements {
+        match element {
+            TemplateElement::PartialExpression(partial)
+            | TemplateElement::PartialBlock(partial) => {
+                if let Parameter::Name(name) = &partial.name
+                    && !referenced.contains(name)
+                {
+                    referenced.push(name.clone());
+                }
+                if let Some(inner) = &partial.template {
+                    collect_partials(inner, referenced, inline);
+                }
+            }
+            TemplateElement::DecoratorBlock(decorator)
+            | TemplateElement::DecoratorExpression(decorator) => {
+                if let (Parameter::Name(kind), Some(Parameter::Literal(Value::String(name)))) =
+                    (&decorator.name, decorator.params.first())
+                    && kind == "inline"
+                {
+                    inline.push(name.clone());
+                }
+                if let Some(inner) = &decorator.template {
+                    collect_partials(inner, referenced, inline);
+                }
+            }
+            TemplateElement::HelperBlock(helper) => {
+                for inner in [&helper.template, &helper.inverse].into_iter().flatten() {
+                    collect_partials(inner, referenced, inline);
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct HandlebarsAdapter {
-    registry: Handlebars<'static>,
+    registry: Arc<Handlebars<'static>>,
+    /// The pool this registry's loads are kept in, and the registry's id there
+    pool: Option<(Arc<TemplatePool>, u64)>,
 }
 
 impl HandlebarsAdapter {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// An adapter for plain-text output such as email bodies, where values
+    /// must not be HTML-escaped
+    pub fn plain_text() -> Self {
+        let mut adapter = Self::default();
+        adapter
+            .registry_mut()
+            .register_escape_fn(handlebars::no_escape);
+        adapter
+    }
+
+    /// An adapter that takes what it loads from `pool` when an earlier
+    /// request loaded the same, and leaves what it loads there for the next
+    pub fn pooled(pool: Arc<TemplatePool>) -> Self {
+        HandlebarsAdapter {
+            registry: Arc::clone(&pool.base),
+            pool: Some((pool, TemplatePool::BASE)),
+        }
+    }
+
+    /// The registry to change directly, no longer the pool's
+    fn registry_mut(&mut self) -> &mut Handlebars<'static> {
+        self.pool = None;
+        Arc::make_mut(&mut self.registry)
+    }
+
+    fn load(&mut self, load: Load) -> Result<usize, String> {
+        let Some((pool, id)) = &self.pool else {
+            return load.apply(Arc::make_mut(&mut self.registry));
+        };
+        let stamp = load.stamp().map_err(|e| e.to_string())?;
+        if let Some(warm) = pool.get(*id, &load, &stamp) {
+            self.registry = warm.registry;
+            self.pool = Some((Arc::clone(pool), warm.id));
+            return Ok(warm.loaded);
+        }
+        // A directory may fail after loading its other templates, leaving a
+        // registry the pool does not know
+        let (pool, id) = (Arc::clone(pool), *id);
+        let loaded = load
+            .apply(Arc::make_mut(&mut self.registry))
+            .inspect_err(|_| self.pool = None)?;
+        self.pool = pool
+            .store(id, load, stamp, Arc::clone(&self.registry), loaded)
+            .map(|id| (pool, id));
+        Ok(loaded)
+    }
+}
+
+/// Registries with templates already loaded, kept between a worker's
+/// requests so a location's templates are compiled on its first request
+/// rather than on every one
+///
+/// Every registry starts from one with the helpers registered. A load (a
+/// directory, or one template file) is kept under the registry it was made
+/// into and what it loaded, so a request making the same loads as an
+/// earlier one ends up with the registry that request left, shared rather
+/// than copied. The files a load read are checked against their modification
+/// times each time it is taken, and a template edited, added or removed
+/// since is loaded again. At most [`TemplatePool::MAX_ENTRIES`] are kept.
+#[derive(Debug)]
+pub struct TemplatePool {
+    base: Arc<Handlebars<'static>>,
+    entries: Mutex<PoolEntries>,
+}
+
+#[derive(Debug, Default)]
+struct PoolEntries {
+    warm: HashMap<(u64, Load), Warm>,
+    last_id: u64,
+}
+
+/// What was loaded into a registry
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Load {
+    /// The .hbs files of a directory
+    Dir(String),
+    /// A template file, by name and path
+    Template(String, String),
+}
+
+/// The files a load read, with when each was last modified
+type Stamp = Vec<(PathBuf, Option<SystemTime>)>;
+
+/// A registry after a load, and how many templates the load registered
+#[derive(Debug, Clone)]
+struct Warm {
+    id: u64,
+    registry: Arc<Handlebars<'static>>,
+    loaded: usize,
+    stamp: Stamp,
+}
+
+impl Load {
+    fn apply(&self, registry: &mut Handlebars) -> Result<usize, String> {
+        match self {
+            Load::Dir(dir) => load_templates_from_dir(registry, dir),
+            Load::Template(name, path) => register_source_file(registry, name, Path::new(path))
+                .map(|()| 1)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    fn stamp(&self) -> std::io::Result<Stamp> {
+        let mut paths = match self {
+            Load::Dir(dir) => template_files_in_dir(dir)?
+                .into_iter()
+                .map(|(_, path)| path)
+                .collect(),
+            Load::Template(_, path) => vec![PathBuf::from(path)],
+        };
+        paths.sort();
+        Ok(paths
+            .into_iter()
+            .map(|path| {
+                let modified = path.metadata().and_then(|m| m.modified()).ok();
+                (path, modified)
+            })
+            .collect())
+    }
+}
+
+impl TemplatePool {
+    pub const MAX_ENTRIES: usize = 1024;
+
+    /// The id of the registry loads start from
+    const BASE: u64 = 0;
+
+    pub fn new() -> Self {
+        TemplatePool {
+            base: Arc::clone(&HandlebarsAdapter::default().registry),
+            entries: Mutex::new(PoolEntries::default()),
+        }
+    }
+
+    /// The registry `load` made from registry `id`, if its files have not
+    /// changed since
+    fn get(&self, id: u64, load: &Load, stamp: &Stamp) -> Option<Warm> {
+        let entries = self.entries.lock().ok()?;
+        entries
+            .warm
+            .get(&(id, load.clone()))
+            .filter(|warm| &warm.stamp == stamp)
+            .cloned()
+    }
+
+    /// Keep the registry `load` made from registry `id`, returning the new
+    /// id it is kept under
+    fn store(
+        &self,
+        id: u64,
+        load: Load,
+        stamp: Stamp,
+        registry: Arc<Handlebars<'static>>,
+        loaded: usize,
+    ) -> Option<u64> {
+        let mut entries = self.entries.lock().ok()?;
+        if entries.warm.len() >= Self::MAX_ENTRIES {
+            entries.warm.clear();
+        }
+        entries.last_id += 1;
+        let warm = Warm {
+            id: entries.last_id,
+            registry,
+            loaded,
+            stamp,
+        };
+        entries.warm.insert((id, load), warm);
+        Some(entries.last_id)
+    }
+}
+
+impl Default for TemplatePool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// `{{now}}`: the current Unix time in seconds, e.g. for a form's
+// `sqlite_honeypot` time field
+handlebars_helper!(now: |*_args| {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+});
+
+/// `{{#nav "main_menu"}}...{{else}}...{{/nav}}`: render the block once per
+/// row of a `sqlite_nav` menu, with the row as its context and `@index`,
+/// `@first` and `@last` set as in `{{#each}}`
+///
+/// The `{{else}}` block renders instead when the menu has no rows or the
+/// location defines no menu by that name, so a shared layout can name menus
+/// that only some locations have.
+#[derive(Clone, Copy)]
+struct NavHelper;
+
+impl HelperDef for NavHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let name = h
+            .param(0)
+            .and_then(|param| param.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("nav", 0))?;
+        let template = h
+            .template()
+            .ok_or(RenderErrorReason::BlockContentRequired)?;
+
+        // Menus are in the page's own data, which partials and blocks leave
+        // in place as the root context
+        let rows = ctx
+            .data()
+            .get("nav")
+            .and_then(|menus| menus.get(name))
+            .and_then(Value::as_array)
+            .filter(|rows| !rows.is_empty());
+        render_rows(rows.map(Vec::as_slice), template, h, r, ctx, rc, out)
+    }
+}
+
+/// `{{#trending 5}}...{{else}}...{{/trending}}`: render the block for each of
+/// the first pages of the `sqlite_trending` ranking (all of them without a
+/// count), with `key` and `hits` as its context and the loop variables of
+/// `{{#each}}`
+///
+/// The `{{else}}` block renders instead when nothing has been counted yet or
+/// the location has no ranking.
+#[derive(Clone, Copy)]
+struct TrendingHelper;
+
+impl HelperDef for TrendingHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let count = match h.param(0) {
+            Some(param) => Some(param.value().as_u64().ok_or_else(|| {
+                RenderErrorReason::ParamTypeMismatchForName(
+                    "trending",
+                    "0".to_string(),
+                    "a page count".to_string(),
+                )
+            })? as usize),
+            None => None,
+        };
+        let template = h
+            .template()
+            .ok_or(RenderErrorReason::BlockContentRequired)?;
+
+        let rows = ctx
+            .data()
+            .get("trending")
+            .and_then(Value::as_array)
+            .map(|rows| &rows[..count.unwrap_or(rows.len()).min(rows.len())])
+            .filter(|rows| !rows.is_empty());
+        render_rows(rows, template, h, r, ctx, rc, out)
+    }
+}
+
+/// `{{json_get doc "$.path"}}`: the value at an SQLite JSON path in a
+/// column, whether the column holds JSON text or was already nested (a
+/// column declared `JSON`, or `sqlite_expand_json`)
+///
+/// Nothing renders when the column is not JSON or has nothing at the path.
+/// As a subexpression it gives the value itself, so
+/// `{{#each (json_get doc "$.tags")}}` loops over an array.
+#[derive(Clone, Copy)]
+struct JsonGetHelper;
+
+impl HelperDef for JsonGetHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let column = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("json_get", 0))?
+            .value();
+        let path = h
+            .param(1)
+            .and_then(|param| param.value().as_str())
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("json_get", 1))?;
+
+        let parsed;
+        let document = match column {
+            Value::String(text) => {
+                parsed = serde_json::from_str(text).unwrap_or(Value::Null);
+                &parsed
+            }
+            value => value,
+        };
+        let found = domain::json_path(document, path).map_err(RenderErrorReason::Other)?;
+        Ok(ScopedJson::Derived(found.cloned().unwrap_or(Value::Null)))
+    }
+}
+
+/// Render `template` once per row, with the row as its context and `@index`,
+/// `@first` and `@last` set, or the helper's `{{else}}` block when there are
+/// no rows
+fn render_rows<'reg: 'rc, 'rc>(
+    rows: Option<&[Value]>,
+    template: &'rc Template,
+    h: &Helper<'rc>,
+    r: &'reg Handlebars<'reg>,
+    ctx: &'rc Context,
+    rc: &mut RenderContext<'reg, 'rc>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let Some(rows) = rows else {
+        return match h.inverse() {
+            Some(inverse) => inverse.render(r, ctx, rc, out),
+            None => Ok(()),
+        };
+    };
+
+    for (index, row) in rows.iter().enumerate() {
+        let mut block = BlockContext::new();
+        block.set_base_value(row.clone());
+        block.set_local_var("index", Value::from(index));
+        block.set_local_var("first", Value::from(index == 0));
+        block.set_local_var("last", Value::from(index == rows.len() - 1));
+        rc.push_block(block);
+        let rendered = template.render(r, ctx, rc, out);
+        rc.pop_block();
+        rendered?;
+    }
+    Ok(())
 }
 
 impl Default for HandlebarsAdapter {
     fn default() -> Self {
+        let mut registry = Handlebars::new();
+        registry.register_helper("now", Box::new(now));
+        registry.register_helper("nav", Box::new(NavHelper));
+        registry.register_helper("trending", Box::new(TrendingHelper));
+        registry.register_helper("json_get", Box::new(JsonGetHelper));
         HandlebarsAdapter {
-            registry: Handlebars::new(),
+            registry: Arc::new(registry),
+            pool: None,
         }
     }
 }
 
 impl TemplateLoader for HandlebarsAdapter {
     fn load_from_dir(&mut self, dir_path: &str) -> Result<usize, String> {
-        load_templates_from_dir(&mut self.registry, dir_path).map_err(|e| e.to_string())
+        self.load(Load::Dir(dir_path.to_string()))
     }
 
     fn register_template(&mut self, name: &str, path: &str) -> Result<(), String> {
-        self.registry
-            .register_template_file(name, path)
-            .map_err(|e| e.to_string())
+        self.load(Load::Template(name.to_string(), path.to_string()))
+            .map(|_| ())
     }
 }
 
@@ -76,6 +630,19 @@ impl TemplateRenderer for HandlebarsAdapter {
             .render(template_name, data)
             .map_err(|e| e.to_string())
     }
+
+    fn render_sized(
+        &self,
+        template_name: &str,
+        data: &Value,
+        capacity: usize,
+    ) -> Result<String, String> {
+        let mut buffer = Vec::with_capacity(capacity);
+        self.registry
+            .render_to_write(template_name, data, &mut buffer)
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
 }
 
 #[cfg(test)]
@@ -195,6 +762,112 @@ mod tests {
         let _ = fs::remove_dir_all(temp_dir);
     }
 
+    #[test]
+    fn test_compile_template_file() {
+        use std::fs;
+
+        let temp_dir = "/tmp/test_sqlite_serve_compile";
+        let _ = fs::remove_dir_all(temp_dir);
+        fs::create_dir_all(temp_dir).unwrap();
+
+        let good = format!("{}/good.hbs", temp_dir);
+        fs::write(&good, "{{#each results}}{{name}}{{/each}}").unwrap();
+        assert!(compile_template_file(&good).is_ok());
+
+        let bad = format!("{}/bad.hbs", temp_dir);
+        fs::write(&bad, "{{#each results}}{{name}}").unwrap();
+        assert!(compile_template_file(&bad).is_err());
+
+        assert!(compile_template_file(&format!("{}/missing.hbs", temp_dir)).is_err());
+
+        let _ = fs::remove_dir_all(temp_dir);
+    }
+
+    #[test]
+    fn test_source_error() {
+        let temp_dir = "/tmp/test_sqlite_serve_source_error";
+        let _ = std::fs::remove_dir_all(temp_dir);
+        std::fs::create_dir_all(temp_dir).unwrap();
+        let bad = format!("{}/list.hbs", temp_dir);
+        std::fs::write(
+            &bad,
+            "<ul>\n{{#each rows}}\n<li>{{name}</li>\n{{/each}}\n</ul>\n",
+        )
+        .unwrap();
+
+        let e = compile_template_file(&bad).unwrap_err();
+        // The caret is under the `}` closing the expression too early
+        assert_eq!(e.position, Some((3, 11)));
+        assert!(
+            e.summary()
+                .starts_with(&format!("{}:3:11: invalid handlebars syntax", bad))
+        );
+        let message = e.to_string();
+        let lines: Vec<&str> = message.lines().collect();
+        assert_eq!(lines[0], e.summary());
+        assert_eq!(lines[1], "1 | <ul>");
+        assert_eq!(lines[3], "3 | <li>{{name}</li>");
+        assert_eq!(lines[4], "  |           ^");
+        assert_eq!(lines[6], "5 | </ul>");
+        assert_eq!(lines.len(), 7);
+
+        let missing = compile_template_file(&format!("{}/missing.hbs", temp_dir)).unwrap_err();
+        assert!(
+            missing
+                .summary()
+                .starts_with(&format!("{}/missing.hbs: ", temp_dir))
+        );
+        assert_eq!(missing.to_string(), missing.summary());
+
+        // A directory loads what compiles and reports the rest
+        std::fs::write(format!("{}/good.hbs", temp_dir), "fine").unwrap();
+        let mut reg = Handlebars::new();
+        let failed = load_templates_from_dir(&mut reg, temp_dir).unwrap_err();
+        assert!(failed.starts_with(&e.summary()));
+        assert!(reg.has_template("good"));
+        assert!(!reg.has_template("list"));
+
+        let mut adapter = HandlebarsAdapter::new();
+        let registered = adapter.register_template("template", &bad).unwrap_err();
+        assert_eq!(registered, message);
+
+        let _ = std::fs::remove_dir_all(temp_dir);
+    }
+
+    #[test]
+    fn test_render_error_page() {
+        let error = "failed to register template: list.hbs:1:9: invalid\n1 | <b>{{x</b>";
+        let page = render_error_page(error, false);
+        assert!(page.contains("failed to register template: list.hbs:1:9: invalid</pre>"));
+        assert!(!page.contains("{{x"));
+
+        let page = render_error_page(error, true);
+        assert!(page.contains("invalid\n1 | &lt;b&gt;{{x&lt;/b&gt;</pre>"));
+    }
+
+    #[test]
+    fn test_partial_references() {
+        let refs = partial_references(
+            "{{> header}}{{#each results}}{{> card}}{{else}}{{> empty}}{{/each}}{{> header}}",
+        )
+        .unwrap();
+        assert_eq!(refs, vec!["header", "card", "empty"]);
+    }
+
+    #[test]
+    fn test_partial_references_skips_inline_and_partial_block() {
+        let refs = partial_references(
+            "{{#*inline \"row\"}}x{{/inline}}{{> row}}{{#> layout}}{{> @partial-block}}{{/layout}}",
+        )
+        .unwrap();
+        assert_eq!(refs, vec!["layout"]);
+    }
+
+    #[test]
+    fn test_partial_references_invalid_template() {
+        assert!(partial_references("{{#if x}}").is_err());
+    }
+
     #[test]
     fn test_handlebars_adapter() {
         use std::fs;
@@ -217,6 +890,267 @@ mod tests {
 
         assert_eq!(rendered, "Hello World");
 
+        // A sized buffer renders the same page, into the capacity asked for
+        let sized = adapter.render_sized("test", &data, 4096).unwrap();
+        assert_eq!(sized, "Hello World");
+        assert!(sized.capacity() >= 4096);
+        assert!(adapter.render_sized("missing", &data, 16).is_err());
+
         let _ = fs::remove_dir_all(temp_dir);
     }
+
+    #[test]
+    fn test_now_helper() {
+        let mut adapter = HandlebarsAdapter::new();
+        adapter
+            .registry_mut()
+            .register_template_string("form", "{{now}}")
+            .unwrap();
+
+        let rendered = adapter.render("form", &serde_json::json!({})).unwrap();
+        let expected = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(expected.abs_diff(rendered.parse().unwrap()) <= 1);
+    }
+
+    #[test]
+    fn test_nav_helper() {
+        let mut adapter = HandlebarsAdapter::new();
+        adapter
+            .registry_mut()
+            .register_partial(
+                "menu",
+                r#"{{#nav "main_menu"}}{{#unless @first}} | {{/unless}}<a href="{{url}}">{{label}}</a>{{else}}no menu{{/nav}}"#,
+            )
+            .unwrap();
+        adapter
+            .registry_mut()
+            .register_template_string("page", "{{#each results}}{{> menu}}{{/each}}")
+            .unwrap();
+
+        let data = serde_json::json!({
+            "results": [{"url": "/ignored"}],
+            "nav": {"main_menu": [
+                {"label": "Home", "url": "/"},
+                {"label": "Books", "url": "/books"},
+            ]},
+        });
+        assert_eq!(
+            adapter.render("page", &data).unwrap(),
+            r#"<a href="/">Home</a> | <a href="/books">Books</a>"#
+        );
+
+        let data = serde_json::json!({"results": [{}], "nav": {"main_menu": []}});
+        assert_eq!(adapter.render("page", &data).unwrap(), "no menu");
+        let data = serde_json::json!({"results": [{}]});
+        assert_eq!(adapter.render("page", &data).unwrap(), "no menu");
+    }
+
+    #[test]
+    fn test_trending_helper() {
+        let mut adapter = HandlebarsAdapter::new();
+        adapter
+            .registry_mut()
+            .register_template_string(
+                "top2",
+                r#"{{#trending 2}}{{#unless @first}}, {{/unless}}{{key}} ({{hits}}){{else}}nothing yet{{/trending}}"#,
+            )
+            .unwrap();
+        adapter
+            .registry_mut()
+            .register_template_string("all", "{{#trending}}{{@index}}{{key}} {{/trending}}")
+            .unwrap();
+
+        let data = serde_json::json!({
+            "results": [],
+            "trending": [
+                {"key": "/dune", "hits": 40},
+                {"key": "/emma", "hits": 12},
+                {"key": "/ulysses", "hits": 3},
+            ],
+        });
+        assert_eq!(
+            adapter.render("top2", &data).unwrap(),
+            "/dune (40), /emma (12)"
+        );
+        assert_eq!(
+            adapter.render("all", &data).unwrap(),
+            "0/dune 1/emma 2/ulysses "
+        );
+
+        let data = serde_json::json!({"results": [], "trending": []});
+        assert_eq!(adapter.render("top2", &data).unwrap(), "nothing yet");
+        let data = serde_json::json!({"results": []});
+        assert_eq!(adapter.render("top2", &data).unwrap(), "nothing yet");
+
+        adapter
+            .registry_mut()
+            .register_template_string("bad", r#"{{#trending "five"}}{{/trending}}"#)
+            .unwrap();
+        assert!(adapter.render("bad", &data).is_err());
+    }
+
+    #[test]
+    fn test_json_get_helper() {
+        let mut adapter = HandlebarsAdapter::new();
+        adapter
+            .registry_mut()
+            .register_template_string(
+                "book",
+                r#"{{#each results}}{{json_get doc "$.title"}}: {{#each (json_get doc "$.tags")}}{{this}} {{/each}}{{json_get doc "$.tags[#-1]"}}{{json_get doc "$.missing"}};{{/each}}"#,
+            )
+            .unwrap();
+
+        // JSON text and an already nested document read the same way
+        let data = serde_json::json!({"results": [
+            {"doc": r#"{"title": "Dune", "tags": ["sf", "classic"]}"#},
+            {"doc": {"title": "Emma", "tags": ["romance"]}},
+            {"doc": "not json"},
+            {"doc": null},
+        ]});
+        assert_eq!(
+            adapter.render("book", &data).unwrap(),
+            "Dune: sf classic classic;Emma: romance romance;: ;: ;"
+        );
+
+        adapter
+            .registry_mut()
+            .register_template_string("bad", r#"{{json_get doc "title"}}"#)
+            .unwrap();
+        let data = serde_json::json!({"doc": "{}"});
+        assert!(
+            adapter
+                .render("bad", &data)
+                .unwrap_err()
+                .contains("bad JSON path")
+        );
+    }
+
+    /// A location's templates: a page and the partials beside it
+    fn write_location(dir: &str, partials: usize) -> String {
+        use std::fs;
+
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let row = "<tr>{{#each this}}<td class=\"{{@key}}\">{{#if this}}{{this}}{{else}}-{{/if}}</td>{{/each}}</tr>\n";
+        let mut page = String::new();
+        for n in 0..partials {
+            fs::write(format!("{}/part{}.hbs", dir, n), row.repeat(4)).unwrap();
+            page.push_str(&format!("{{{{> part{}}}}}", n));
+        }
+        let page_path = format!("{}/page.hbs", dir);
+        fs::write(
+            &page_path,
+            format!("{{{{#each results}}}}{}{{{{/each}}}}", page),
+        )
+        .unwrap();
+        page_path
+    }
+
+    /// Load a location's templates the way a request does, and render its page
+    fn render_location(mut adapter: HandlebarsAdapter, dir: &str, page: &str) -> String {
+        adapter.load_from_dir(dir).unwrap();
+        adapter.register_template("template", page).unwrap();
+        adapter
+            .render(
+                "template",
+                &serde_json::json!({"results": [{"title": "Dune"}]}),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_template_pool() {
+        use std::fs;
+        use std::time::Duration;
+
+        let dir = "/tmp/test_template_pool";
+        let page = write_location(dir, 1);
+        fs::write(format!("{}/part0.hbs", dir), "{{title}}").unwrap();
+        let pool = Arc::new(TemplatePool::new());
+
+        let mut first = HandlebarsAdapter::pooled(Arc::clone(&pool));
+        assert_eq!(first.load_from_dir(dir).unwrap(), 2);
+        first.register_template("template", &page).unwrap();
+        assert_eq!(render_location(first.clone(), dir, &page), "Dune");
+
+        // The next request takes the registry the first left
+        let mut second = HandlebarsAdapter::pooled(Arc::clone(&pool));
+        assert_eq!(second.load_from_dir(dir).unwrap(), 2);
+        second.register_template("template", &page).unwrap();
+        assert!(Arc::ptr_eq(&first.registry, &second.registry));
+
+        // An edited partial is loaded again
+        let partial = fs::File::options()
+            .write(true)
+            .open(format!("{}/part0.hbs", dir))
+            .unwrap();
+        partial.set_len(0).unwrap();
+        std::io::Write::write_all(&mut &partial, b"<b>{{title}}</b>").unwrap();
+        partial
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let pooled = HandlebarsAdapter::pooled(Arc::clone(&pool));
+        assert_eq!(render_location(pooled, dir, &page), "<b>Dune</b>");
+
+        // So is a partial added since
+        fs::write(format!("{}/part1.hbs", dir), "!").unwrap();
+        let mut pooled = HandlebarsAdapter::pooled(Arc::clone(&pool));
+        assert_eq!(pooled.load_from_dir(dir).unwrap(), 3);
+
+        // Changing a pooled registry directly leaves the pool's alone
+        let mut changed = HandlebarsAdapter::pooled(Arc::clone(&pool));
+        changed.load_from_dir(dir).unwrap();
+        changed
+            .registry_mut()
+            .register_template_string("part0", "changed")
+            .unwrap();
+        let pooled = HandlebarsAdapter::pooled(Arc::clone(&pool));
+        assert_eq!(render_location(pooled, dir, &page), "<b>Dune</b>");
+
+        assert!(
+            HandlebarsAdapter::pooled(pool)
+                .register_template("missing", "/nonexistent/missing.hbs")
+                .is_err()
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    /// A request's template loads and render take from the pool in well
+    /// under half the time they take from a fresh registry, which reads and
+    /// compiles every template again
+    ///
+    /// Cold and warm requests alternate, and each side's fastest round is
+    /// compared, so other tests running alongside slow both sides alike
+    /// rather than deciding the outcome.
+    #[test]
+    fn test_template_pool_benchmark() {
+        use std::time::{Duration, Instant};
+
+        const ROUNDS: usize = 30;
+        let dir = "/tmp/test_template_pool_benchmark";
+        let page = write_location(dir, 20);
+        let pool = Arc::new(TemplatePool::new());
+        let expected = render_location(HandlebarsAdapter::pooled(Arc::clone(&pool)), dir, &page);
+
+        let time = |adapter: HandlebarsAdapter| {
+            let started = Instant::now();
+            assert_eq!(render_location(adapter, dir, &page), expected);
+            started.elapsed()
+        };
+        let (mut cold, mut warm) = (Duration::MAX, Duration::MAX);
+        for _ in 0..ROUNDS {
+            cold = cold.min(time(HandlebarsAdapter::new()));
+            warm = warm.min(time(HandlebarsAdapter::pooled(Arc::clone(&pool))));
+        }
+        let _ = std::fs::remove_dir_all(dir);
+        assert!(
+            warm * 2 < cold,
+            "warm {:?} is not under half of cold {:?}",
+            warm,
+            cold
+        );
+    }
 }
@@ -0,0 +1,181 @@
+//! Request tracing and timing, kept out of the pure request processing
+//!
+//! A [`Trace`] records the operations of one `sqlite_trace` request and a
+//! [`Stopwatch`] times a request's querying and rendering for
+//! `sqlite_timing` and the worker's metrics. Both live only as long as their request, and reach
+//! [`RequestProcessor`](crate::domain::RequestProcessor) through the
+//! [`Tracer`] and [`Clock`] traits.
+
+use crate::domain::{Clock, SpanId, Tracer, millis};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A [`Clock`] that starts when it is made
+#[derive(Debug)]
+pub struct Stopwatch(Instant);
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Stopwatch(Instant::now())
+    }
+}
+
+impl Clock for Stopwatch {
+    fn elapsed(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Span {
+    operation: String,
+    parent: Option<SpanId>,
+    start: Duration,
+    duration: Option<Duration>,
+    rows: Option<usize>,
+    failed: bool,
+}
+
+/// The operations one request ran, for `sqlite_trace`: when each started,
+/// how long it took and how many rows it returned, nested under the
+/// operation that ran it
+///
+/// Operations may be recorded from several threads, as sources run while
+/// the main query does.
+#[derive(Debug)]
+pub struct Trace {
+    started: Instant,
+    spans: Mutex<Vec<Span>>,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Trace {
+            started: Instant::now(),
+            spans: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The operations as a tree, each with its `operation`, `start_ms` since
+    /// the request began, `duration_ms` (null if it never finished), `rows`
+    /// and `failed` when they apply, and the `children` it ran
+    pub fn to_json(&self) -> Value {
+        span_tree(&self.spans(), None)
+    }
+
+    fn spans(&self) -> std::sync::MutexGuard<'_, Vec<Span>> {
+        // Spans are only pushed or updated while locked, so they stay whole
+        self.spans
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl Tracer for Trace {
+    /// Start an operation under `parent`, or at the top when there is none
+    fn begin(&self, operation: &str, parent: Option<SpanId>) -> SpanId {
+        let start = self.started.elapsed();
+        let mut spans = self.spans();
+        spans.push(Span {
+            operation: operation.to_string(),
+            parent,
+            start,
+            duration: None,
+            rows: None,
+            failed: false,
+        });
+        SpanId(spans.len() - 1)
+    }
+
+    /// Finish an operation, with the rows it returned if it returns rows
+    fn end(&self, span: SpanId, rows: Option<usize>) {
+        let elapsed = self.started.elapsed();
+        if let Some(span) = self.spans().get_mut(span.0) {
+            span.duration = Some(elapsed.saturating_sub(span.start));
+            span.rows = rows;
+        }
+    }
+
+    /// Finish an operation that failed
+    fn fail(&self, span: SpanId) {
+        self.end(span, None);
+        if let Some(span) = self.spans().get_mut(span.0) {
+            span.failed = true;
+        }
+    }
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn span_tree(spans: &[Span], parent: Option<SpanId>) -> Value {
+    let children = spans
+        .iter()
+        .enumerate()
+        .filter(|(_, span)| span.parent == parent)
+        .map(|(id, span)| {
+            let mut node = serde_json::json!({
+                "operation": span.operation,
+                "start_ms": millis(span.start),
+                "duration_ms": span.duration.map(millis),
+            });
+            if let Some(rows) = span.rows {
+                node["rows"] = Value::from(rows);
+            }
+            if span.failed {
+                node["failed"] = Value::Bool(true);
+            }
+            node["children"] = span_tree(spans, Some(SpanId(id)));
+            node
+        })
+        .collect();
+    Value::Array(children)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::traced;
+
+    #[test]
+    fn test_trace() {
+        let trace = Trace::new();
+        let outer = trace.begin("expand author", None);
+        let rows: Result<Vec<u8>, ()> =
+            traced(Some(&trace), "batch query", Some(outer), || Ok(vec![1, 2]));
+        assert_eq!(rows, Ok(vec![1, 2]));
+        let failed: Result<Vec<u8>, &str> =
+            traced(Some(&trace), "batch query", Some(outer), || Err("locked"));
+        assert!(failed.is_err());
+        trace.end(outer, Some(2));
+        let unfinished = trace.begin("render", None);
+
+        let operations = trace.to_json();
+        assert_eq!(operations.as_array().unwrap().len(), 2);
+        let expand = &operations[0];
+        assert_eq!(expand["operation"], "expand author");
+        assert_eq!(expand["rows"], 2);
+        assert!(expand["duration_ms"].as_f64().unwrap() >= 0.0);
+        assert_eq!(expand["children"][0]["rows"], 2);
+        assert_eq!(expand["children"][1]["failed"], true);
+        assert!(expand["children"][1].get("rows").is_none());
+        assert!(operations[1]["duration_ms"].is_null());
+        trace.end(unfinished, None);
+        assert!(trace.to_json()[1]["duration_ms"].is_number());
+
+        // Without a trace the operation just runs
+        let untraced: Result<Vec<u8>, ()> = traced(None, "query", None, || Ok(vec![]));
+        assert_eq!(untraced, Ok(vec![]));
+    }
+
+    #[test]
+    fn test_stopwatch() {
+        let stopwatch = Stopwatch::start();
+        let first = stopwatch.elapsed();
+        assert!(stopwatch.elapsed() >= first);
+    }
+}
@@ -1,10 +1,17 @@
 //! Type-safe wrappers for domain concepts (Parse, Don't Validate)
 
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-/// A validated database path that exists and is accessible
+/// A database path (never empty; whether the file exists is checked when it
+/// is opened), together with the databases attached to it for its queries
 #[derive(Debug, Clone)]
-pub struct DatabasePath(PathBuf);
+pub struct DatabasePath {
+    path: PathBuf,
+    attachments: Vec<Attachment>,
+    history: Vec<String>,
+    as_of: Option<AsOf>,
+}
 
 impl DatabasePath {
     /// Parse and validate a database path
@@ -15,12 +22,344 @@ impl DatabasePath {
         if path.as_os_str().is_empty() {
             Err("database path cannot be empty".to_string())
         } else {
-            Ok(DatabasePath(path.to_path_buf()))
+            Ok(DatabasePath {
+                path: path.to_path_buf(),
+                attachments: Vec::new(),
+                history: Vec::new(),
+                as_of: None,
+            })
         }
     }
 
+    /// Attach these databases whenever this one is opened
+    pub fn with_attachments(mut self, attachments: Vec<Attachment>) -> Self {
+        self.attachments = attachments;
+        self
+    }
+
     pub fn as_str(&self) -> &str {
-        self.0.to_str().unwrap_or("")
+        self.path.to_str().unwrap_or("")
+    }
+
+    pub fn attachments(&self) -> &[Attachment] {
+        &self.attachments
+    }
+
+    /// Keep the history of these `sqlite_history` tables
+    pub fn with_history(mut self, tables: Vec<String>) -> Self {
+        self.history = tables;
+        self
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Read the history tables as they were at `as_of`
+    pub fn at(mut self, as_of: AsOf) -> Self {
+        self.as_of = Some(as_of);
+        self
+    }
+
+    pub fn as_of(&self) -> Option<&AsOf> {
+        self.as_of.as_ref()
+    }
+}
+
+/// How a location's rows are shaped as they are read, whichever of its
+/// queries (main, sources, menus or expansions) reads them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RowShape {
+    hidden_columns: Vec<String>,
+    blob_encoding: BlobEncoding,
+}
+
+impl RowShape {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leave these `sqlite_hide_columns` columns out of every row read
+    pub fn with_hidden_columns(mut self, columns: Vec<String>) -> Self {
+        self.hidden_columns = columns;
+        self
+    }
+
+    /// Whether the result column `name` is left out; column names are
+    /// case-insensitive in SQL, so `Password_Hash` is hidden as well
+    pub fn hides(&self, name: &str) -> bool {
+        self.hidden_columns
+            .iter()
+            .any(|hidden| hidden.eq_ignore_ascii_case(name))
+    }
+
+    /// Send BLOB values as `sqlite_blob_encoding` says
+    pub fn with_blob_encoding(mut self, encoding: BlobEncoding) -> Self {
+        self.blob_encoding = encoding;
+        self
+    }
+
+    pub fn blob_encoding(&self) -> BlobEncoding {
+        self.blob_encoding
+    }
+}
+
+/// How BLOB values appear in the rows a query returns (`sqlite_blob_encoding`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum BlobEncoding {
+    /// Lowercase hex digits, two per byte
+    #[default]
+    Hex,
+    /// Standard base64 with padding (RFC 4648)
+    Base64,
+    /// Left out of the row
+    Skip,
+}
+
+impl BlobEncoding {
+    /// Parse `hex`, `base64` or `skip`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "hex" => Ok(BlobEncoding::Hex),
+            "base64" => Ok(BlobEncoding::Base64),
+            "skip" => Ok(BlobEncoding::Skip),
+            other => Err(format!("expected hex, base64 or skip, got '{}'", other)),
+        }
+    }
+}
+
+/// A `sqlite_db` path with nginx variables in it (`/data/$host.db`), naming
+/// one database per tenant
+///
+/// The directory before the first variable is the root every resolved path
+/// stays under: a variable's value may only be letters, digits, `-`, `_` and
+/// `.`, not first, so it can neither leave the root nor name a hidden file.
+#[derive(Debug, Clone)]
+pub struct DatabaseTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Literal(String),
+    Variable(NginxVariable),
+}
+
+impl DatabaseTemplate {
+    /// Whether a `sqlite_db` path is chosen per request
+    pub fn has_variables(path: &str) -> bool {
+        path.contains('$')
+    }
+
+    /// Parse a path with `$name` or `${name}` variables in it
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = path;
+        while let Some(start) = rest.find('$') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let after = &rest[start + 1..];
+            let (name, next) = match after.strip_prefix('{') {
+                Some(braced) => {
+                    let end = braced
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed ${{ in '{}'", path))?;
+                    (&braced[..end], &braced[end + 1..])
+                }
+                None => {
+                    let end = after
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid variable name '{}' in '{}'", name, path));
+            }
+            parts.push(TemplatePart::Variable(NginxVariable::parse(format!(
+                "${}",
+                name
+            ))?));
+            rest = next;
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+
+        let root = match parts.first() {
+            Some(TemplatePart::Literal(prefix)) => prefix.rfind('/').map(|end| &prefix[..end]),
+            _ => None,
+        };
+        match root {
+            Some(root) if root.starts_with('/') && !root.split('/').any(|s| s == "..") => {}
+            _ => {
+                return Err(format!(
+                    "a database path with variables needs an absolute directory before its first variable: '{}'",
+                    path
+                ));
+            }
+        }
+        Ok(DatabaseTemplate { parts })
+    }
+
+    /// The path with each variable replaced by its value, given by `value`;
+    /// a value that is missing or could leave the root is an error
+    pub fn resolve(
+        &self,
+        mut value: impl FnMut(&NginxVariable) -> Option<String>,
+    ) -> Result<String, String> {
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(text) => path.push_str(text),
+                TemplatePart::Variable(variable) => {
+                    let value = value(variable)
+                        .filter(|value| !value.is_empty())
+                        .ok_or_else(|| format!("{} is not set", variable.as_str()))?;
+                    let safe = !value.starts_with('.')
+                        && value
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+                    if !safe {
+                        return Err(format!(
+                            "{} is not a safe path segment: '{}'",
+                            variable.as_str(),
+                            value
+                        ));
+                    }
+                    path.push_str(&value);
+                }
+            }
+        }
+        Ok(path)
+    }
+}
+
+/// A `sqlite_db_map` control database and the query that names each
+/// request's database in it (`SELECT db_path FROM tenants WHERE host = :host`)
+///
+/// Each `:name` parameter of the query is bound to the nginx variable
+/// `$name`; the parameters are read from the prepared query at startup.
+#[derive(Debug, Clone)]
+pub struct DatabaseMap {
+    control: DatabasePath,
+    query: SqlQuery,
+    max_age: Duration,
+    parameters: Vec<(String, NginxVariable)>,
+}
+
+impl DatabaseMap {
+    /// How long a lookup is reused when no `max_age=` is given
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+    /// Parse a `sqlite_db_map control_db "SELECT ..." [max_age=seconds]`
+    /// definition
+    pub fn parse(control: &str, query: &str, options: &[&str]) -> Result<Self, String> {
+        let control = DatabasePath::parse(control)?;
+        let query = SqlQuery::parse(query)?;
+
+        let mut max_age = Self::DEFAULT_MAX_AGE;
+        for option in options {
+            match option.split_once('=') {
+                Some(("max_age", seconds)) => {
+                    let seconds = seconds.strip_suffix('s').unwrap_or(seconds);
+                    max_age = Duration::from_secs(seconds.parse::<u64>().map_err(|_| {
+                        format!("max_age must be a number of seconds: '{}'", seconds)
+                    })?);
+                }
+                _ => return Err(format!("expected max_age=, got '{}'", option)),
+            }
+        }
+
+        Ok(DatabaseMap {
+            control,
+            query,
+            max_age,
+            parameters: Vec::new(),
+        })
+    }
+
+    pub fn control(&self) -> &DatabasePath {
+        &self.control
+    }
+
+    pub fn query(&self) -> &SqlQuery {
+        &self.query
+    }
+
+    /// How long a lookup is reused; zero looks up every request
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Bind each of the query's parameters, named as SQLite reports them
+    /// (`:host`), to the variable of the same name (`$host`)
+    pub fn with_parameters(mut self, names: &[String]) -> Result<Self, String> {
+        self.parameters = names
+            .iter()
+            .map(|name| {
+                let variable = name
+                    .strip_prefix(':')
+                    .filter(|variable| is_identifier(variable))
+                    .ok_or_else(|| {
+                        format!(
+                            "parameters must be named after a variable, like :host: '{}'",
+                            name
+                        )
+                    })?;
+                Ok((
+                    name.clone(),
+                    NginxVariable::parse(format!("${}", variable))?,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(self)
+    }
+
+    /// The query's parameters and the variables bound to them
+    pub fn parameters(&self) -> &[(String, NginxVariable)] {
+        &self.parameters
+    }
+}
+
+/// A database attached under an alias (`sqlite_attach alias path`), so
+/// queries can join across files with `alias.table`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    alias: String,
+    path: String,
+}
+
+impl Attachment {
+    /// Parse an alias and database path; the alias must be an identifier other
+    /// than SQLite's own `main` and `temp`
+    pub fn parse(alias: &str, path: &str) -> Result<Self, String> {
+        if !is_identifier(alias) {
+            return Err(format!(
+                "alias must be letters, digits or underscores: '{}'",
+                alias
+            ));
+        }
+        if alias.eq_ignore_ascii_case("main") || alias.eq_ignore_ascii_case("temp") {
+            return Err(format!("alias '{}' is reserved by SQLite", alias));
+        }
+        if path.is_empty() {
+            return Err("database path cannot be empty".to_string());
+        }
+
+        Ok(Attachment {
+            alias: alias.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    pub fn alias(&self) -> &str {
+        &self.alias
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
     }
 }
 
@@ -48,8 +387,68 @@ impl SqlQuery {
     }
 }
 
+/// A validated data-modifying statement (INSERT, UPDATE, DELETE or REPLACE)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteQuery(String);
+
+impl WriteQuery {
+    /// Parse and validate a write statement
+    pub fn parse(query: impl Into<String>) -> Result<Self, String> {
+        let query = query.into();
+        let trimmed = query.trim().to_uppercase();
+
+        if trimmed.is_empty() {
+            Err("query cannot be empty".to_string())
+        } else if !["INSERT", "UPDATE", "DELETE", "REPLACE"]
+            .iter()
+            .any(|keyword| trimmed.starts_with(keyword))
+        {
+            Err("only INSERT, UPDATE, DELETE or REPLACE statements are allowed".to_string())
+        } else {
+            Ok(WriteQuery(query))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// An HTTP method that may run a location's write statement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMethod {
+    Post,
+    Put,
+    Patch,
+    Delete,
+}
+
+impl WriteMethod {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "POST" => Ok(WriteMethod::Post),
+            "PUT" => Ok(WriteMethod::Put),
+            "PATCH" => Ok(WriteMethod::Patch),
+            "DELETE" => Ok(WriteMethod::Delete),
+            other => Err(format!(
+                "unsupported write method '{}' (expected POST, PUT, PATCH or DELETE)",
+                other
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WriteMethod::Post => "POST",
+            WriteMethod::Put => "PUT",
+            WriteMethod::Patch => "PATCH",
+            WriteMethod::Delete => "DELETE",
+        }
+    }
+}
+
 /// A validated template path
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TemplatePath(PathBuf);
 
 impl TemplatePath {
@@ -71,9 +470,13 @@ impl TemplatePath {
     }
 }
 
-/// A validated nginx variable name (starts with $)
+/// A validated nginx variable name (starts with $), with the index nginx
+/// gave it once the configuration was loaded
 #[derive(Debug, Clone)]
-pub struct NginxVariable(String);
+pub struct NginxVariable {
+    name: String,
+    index: Option<usize>,
+}
 
 impl NginxVariable {
     /// Parse a nginx variable name
@@ -89,20 +492,69 @@ impl NginxVariable {
             let var_name = &name[1..];
             if var_name.is_empty() {
                 Err("variable name after $ cannot be empty".to_string())
+            } else if let Some(index) = var_name.strip_prefix("uri_segment_")
+                && !matches!(index.parse::<usize>(), Ok(1..))
+            {
+                Err(format!("path segments are numbered from 1: {}", name))
+            } else if var_name == "json_body"
+                || var_name
+                    .strip_prefix("json_body.")
+                    .is_some_and(|path| path.split('.').any(str::is_empty))
+            {
+                Err(format!(
+                    "request body fields are named like $json_body.author.name: {}",
+                    name
+                ))
+            } else if var_name == "post_" {
+                Err("form fields are named like $post_title".to_string())
             } else {
-                Ok(NginxVariable(name))
+                Ok(NginxVariable { name, index: None })
             }
         }
     }
 
+    /// The dotted path of a `$json_body.` variable within the request body
+    pub fn body_path(&self) -> Option<&str> {
+        self.name.strip_prefix("$json_body.")
+    }
+
+    /// The name of a `$post_` variable's field in a form-encoded request body
+    pub fn form_field(&self) -> Option<&str> {
+        self.name.strip_prefix("$post_")
+    }
+
+    /// Whether nginx provides the variable, rather than this module reading
+    /// it from the request itself (`$json_body.`, `$post_`, `$uri_segment_N`
+    /// and regex captures such as `$1`)
+    pub fn provided_by_nginx(&self) -> bool {
+        let name = self.name();
+        !(self.reads_body()
+            || name.starts_with("uri_segment_")
+            || name.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    /// Look the variable up by the index nginx gave it rather than by name
+    pub fn with_index(mut self, index: usize) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    /// Whether the variable is read from the request body
+    pub fn reads_body(&self) -> bool {
+        self.body_path().is_some() || self.form_field().is_some()
+    }
+
     pub fn as_str(&self) -> &str {
-        &self.0
+        &self.name
     }
 
     /// Get the variable name without the $ prefix
-    #[allow(dead_code)]
     pub fn name(&self) -> &str {
-        &self.0[1..]
+        &self.name[1..]
     }
 }
 
@@ -120,45 +572,2408 @@ impl ParamName {
             return Err("parameter name cannot be empty".to_string());
         }
 
-        if !name.starts_with(':') {
-            return Err(format!("parameter name must start with :: {}", name));
+        if !name.starts_with(':') {
+            return Err(format!("parameter name must start with :: {}", name));
+        }
+
+        // The name after : must not be empty
+        if name.len() == 1 {
+            return Err("parameter name after : cannot be empty".to_string());
+        }
+
+        Ok(ParamName(name))
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Get the parameter name without the : prefix
+    #[allow(dead_code)]
+    pub fn name_without_colon(&self) -> &str {
+        &self.0[1..]
+    }
+}
+
+/// `sqlite_try_static` settings: serve a saved page while it is fresh, and
+/// optionally save rendered pages for the next request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TryStatic {
+    enabled: bool,
+    max_age: Option<u64>,
+    save: bool,
+}
+
+impl TryStatic {
+    /// Parse `on` or `off`, then optional `max_age=seconds` and `save=on|off`
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let (enabled, options) = match args.split_first() {
+            Some((&"on", options)) => (true, options),
+            Some((&"off", [])) => (false, &[][..]),
+            _ => return Err("expected on [max_age=seconds] [save=on|off], or off".to_string()),
+        };
+
+        let mut max_age = None;
+        let mut save = false;
+        for option in options {
+            match option.split_once('=') {
+                Some(("max_age", seconds)) => {
+                    let seconds = seconds.strip_suffix('s').unwrap_or(seconds);
+                    max_age = Some(seconds.parse::<u64>().map_err(|_| {
+                        format!("max_age must be a number of seconds: '{}'", seconds)
+                    })?);
+                }
+                Some(("save", "on")) => save = true,
+                Some(("save", "off")) => save = false,
+                _ => {
+                    return Err(format!(
+                        "expected max_age= or save=on|off, got '{}'",
+                        option
+                    ));
+                }
+            }
+        }
+
+        Ok(TryStatic {
+            enabled,
+            max_age,
+            save,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// How old a saved page may be, in seconds; any age when `None`
+    pub fn max_age(&self) -> Option<u64> {
+        self.max_age
+    }
+
+    /// Whether rendered pages are written back as static files
+    pub fn save(&self) -> bool {
+        self.save
+    }
+}
+
+/// The document `sqlite_feed` answers with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+/// `sqlite_feed` settings: the document format, the columns each row's entry
+/// is read from, and the feed's title
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feed {
+    enabled: bool,
+    format: FeedFormat,
+    title: String,
+    link: String,
+    updated: String,
+    content: String,
+    name: Option<String>,
+}
+
+impl Feed {
+    /// Parse `atom` or `rss`, then optional `title=`, `link=`, `updated=` and
+    /// `content=` columns (each named after itself by default) and the
+    /// feed's `name=`; or `off`
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let (format, options) = match args.split_first() {
+            Some((&"atom", options)) => (FeedFormat::Atom, options),
+            Some((&"rss", options)) => (FeedFormat::Rss, options),
+            Some((&"off", [])) => (FeedFormat::Atom, &[][..]),
+            _ => {
+                return Err(
+                    "expected atom or rss [title=column] [link=column] [updated=column] \
+                     [content=column] [name=title], or off"
+                        .to_string(),
+                );
+            }
+        };
+
+        let mut feed = Feed {
+            enabled: args != ["off"],
+            format,
+            title: "title".to_string(),
+            link: "link".to_string(),
+            updated: "updated".to_string(),
+            content: "content".to_string(),
+            name: None,
+        };
+        for option in options {
+            let (key, value) = option
+                .split_once('=')
+                .filter(|(_, value)| !value.is_empty())
+                .ok_or_else(|| format!("expected key=value, got '{}'", option))?;
+            let setting = match key {
+                "title" => &mut feed.title,
+                "link" => &mut feed.link,
+                "updated" => &mut feed.updated,
+                "content" => &mut feed.content,
+                "name" => {
+                    feed.name = Some(value.to_string());
+                    continue;
+                }
+                _ => {
+                    return Err(format!(
+                        "expected title=, link=, updated=, content= or name=, got '{}'",
+                        option
+                    ));
+                }
+            };
+            *setting = value.to_string();
+        }
+        Ok(feed)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn format(&self) -> FeedFormat {
+        self.format
+    }
+
+    /// The column holding each entry's title
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// The column holding each entry's link, absolute or relative to the server
+    pub fn link(&self) -> &str {
+        &self.link
+    }
+
+    /// The column holding when each entry was last updated
+    pub fn updated(&self) -> &str {
+        &self.updated
+    }
+
+    /// The column holding each entry's HTML content
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// The feed's own title, if set
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// What a streamed response does with a client too slow for its
+/// `sqlite_stream_buffer` (`sqlite_stream_overflow`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamOverflow {
+    /// Send no more until the client has taken some of what is buffered
+    #[default]
+    Wait,
+    /// Close the client's connection
+    Drop,
+}
+
+impl StreamOverflow {
+    /// Parse `wait` or `drop`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "wait" => Ok(StreamOverflow::Wait),
+            "drop" => Ok(StreamOverflow::Drop),
+            other => Err(format!("expected wait or drop, got '{}'", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamOverflow::Wait => "wait",
+            StreamOverflow::Drop => "drop",
+        }
+    }
+}
+
+/// `sqlite_stream_buffer` settings: how many bytes of a streamed response
+/// nginx may hold unsent for a client, and what happens when a client is
+/// slower than that
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamBuffer {
+    size: usize,
+    overflow: StreamOverflow,
+}
+
+impl StreamBuffer {
+    /// Parse a size in bytes, with `k` or `m` for kilobytes or megabytes as
+    /// in nginx's own sizes, or `off`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let size = match value.trim() {
+            "off" => 0,
+            size => {
+                let lower = size.to_ascii_lowercase();
+                let (digits, unit) = match lower.strip_suffix('k') {
+                    Some(digits) => (digits, 1024),
+                    None => match lower.strip_suffix('m') {
+                        Some(digits) => (digits, 1024 * 1024),
+                        None => (lower.as_str(), 1),
+                    },
+                };
+                match digits
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| n.checked_mul(unit))
+                {
+                    Some(0) => return Err("buffer size must be greater than zero".to_string()),
+                    Some(size) => size,
+                    None => {
+                        return Err(format!("expected a size like 64k or off, got '{}'", value));
+                    }
+                }
+            }
+        };
+        Ok(StreamBuffer {
+            size,
+            overflow: StreamOverflow::default(),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    /// The most bytes nginx may hold unsent
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn overflow(&self) -> StreamOverflow {
+        self.overflow
+    }
+
+    /// The same buffer with the location's `sqlite_stream_overflow`
+    pub fn with_overflow(self, overflow: StreamOverflow) -> Self {
+        StreamBuffer { overflow, ..self }
+    }
+}
+
+/// `sqlite_theme` settings: the variable naming a request's theme, the theme
+/// used when it names none, and the directory (under the document root)
+/// holding one directory per theme
+#[derive(Debug, Clone)]
+pub struct Theme {
+    variable: NginxVariable,
+    default: String,
+    root: String,
+}
+
+impl Theme {
+    /// Parse `$variable default=name [root=directory]`; the root defaults to
+    /// `themes`
+    pub fn parse(variable: &str, options: &[&str]) -> Result<Self, String> {
+        let variable = NginxVariable::parse(variable)?;
+        let mut default = None;
+        let mut root = "themes";
+        for option in options {
+            match option.split_once('=') {
+                Some(("default", name)) if Self::is_name(name) => default = Some(name),
+                Some(("default", name)) => {
+                    return Err(format!(
+                        "theme names must be letters, digits, '-' or '_': '{}'",
+                        name
+                    ));
+                }
+                Some(("root", directory)) => root = directory.trim_matches('/'),
+                _ => {
+                    return Err(format!(
+                        "expected default=name or root=directory, got '{}'",
+                        option
+                    ));
+                }
+            }
+        }
+        if root.is_empty() || root.split('/').any(|segment| segment == "..") {
+            return Err(format!(
+                "root must be a directory under the document root: '{}'",
+                root
+            ));
+        }
+
+        Ok(Theme {
+            variable,
+            default: default.ok_or("default= is required")?.to_string(),
+            root: root.to_string(),
+        })
+    }
+
+    /// Whether `name` can name a theme directory; anything else (such as a
+    /// cookie holding `../`) selects the default theme
+    pub fn is_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    pub fn variable(&self) -> &NginxVariable {
+        &self.variable
+    }
+
+    pub fn default(&self) -> &str {
+        &self.default
+    }
+
+    /// The theme directory's parent, relative to the document root
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+}
+
+/// `sqlite_template_variant` settings: the variable a request selects an
+/// alternate template with, the allowlisted variants, and the secret their
+/// selections must be signed with (if any)
+#[derive(Debug, Clone)]
+pub struct TemplateVariants {
+    variable: NginxVariable,
+    secret: Option<String>,
+    variants: Vec<(String, TemplatePath)>,
+}
+
+impl TemplateVariants {
+    /// Parse `$variable [secret=key] name=file.hbs...`
+    pub fn parse(variable: &str, options: &[&str]) -> Result<Self, String> {
+        let variable = NginxVariable::parse(variable)?;
+        let mut secret = None;
+        let mut variants: Vec<(String, TemplatePath)> = Vec::new();
+        for option in options {
+            match option.split_once('=') {
+                Some(("secret", "")) => return Err("secret cannot be empty".to_string()),
+                Some(("secret", key)) => secret = Some(key.to_string()),
+                Some((name, _)) if !Theme::is_name(name) => {
+                    return Err(format!(
+                        "variant names must be letters, digits, '-' or '_': '{}'",
+                        name
+                    ));
+                }
+                Some((name, _)) if variants.iter().any(|(n, _)| n == name) => {
+                    return Err(format!("variant '{}' is listed more than once", name));
+                }
+                Some((name, path)) => {
+                    let template = TemplatePath::parse(path)
+                        .map_err(|e| format!("variant '{}': {}", name, e))?;
+                    variants.push((name.to_string(), template));
+                }
+                None => {
+                    return Err(format!(
+                        "expected secret=key or name=file.hbs, got '{}'",
+                        option
+                    ));
+                }
+            }
+        }
+        if variants.is_empty() {
+            return Err("at least one name=file.hbs variant is required".to_string());
+        }
+
+        Ok(TemplateVariants {
+            variable,
+            secret,
+            variants,
+        })
+    }
+
+    pub fn variable(&self) -> &NginxVariable {
+        &self.variable
+    }
+
+    /// When set, a selection is only honoured as `name.signature`
+    pub fn secret(&self) -> Option<&str> {
+        self.secret.as_deref()
+    }
+
+    /// The allowlisted template for a variant name
+    pub fn template(&self, name: &str) -> Option<&TemplatePath> {
+        self.variants
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, template)| template)
+    }
+
+    pub fn templates(&self) -> impl Iterator<Item = &TemplatePath> {
+        self.variants.iter().map(|(_, template)| template)
+    }
+}
+
+/// `sqlite_breadcrumbs` settings: a trail of links to each parent of the
+/// request path, optionally labelled by a query
+#[derive(Debug, Clone)]
+pub struct Breadcrumbs {
+    enabled: bool,
+    labels_query: Option<SqlQuery>,
+}
+
+impl Breadcrumbs {
+    /// Parse `on` with an optional `labels_query=SELECT ...`, or `off`
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        match args {
+            ["on"] => Ok(Breadcrumbs {
+                enabled: true,
+                labels_query: None,
+            }),
+            ["on", option] => {
+                let query = option
+                    .strip_prefix("labels_query=")
+                    .ok_or_else(|| format!("expected labels_query=, got '{}'", option))?;
+                Ok(Breadcrumbs {
+                    enabled: true,
+                    labels_query: Some(
+                        SqlQuery::parse(query)
+                            .map_err(|e| format!("invalid labels_query: {}", e))?,
+                    ),
+                })
+            }
+            ["off"] => Ok(Breadcrumbs {
+                enabled: false,
+                labels_query: None,
+            }),
+            _ => Err("expected on [labels_query=SELECT ...], or off".to_string()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Looks up a crumb's label from its `:path` and `:segment`
+    pub fn labels_query(&self) -> Option<&SqlQuery> {
+        self.labels_query.as_ref()
+    }
+}
+
+/// `sqlite_paginate` settings: pages of `per_page` rows, numbered from 1 by
+/// a query argument or found from a cursor
+#[derive(Debug, Clone)]
+pub struct Pagination {
+    per_page: RowLimit,
+    mode: PageMode,
+    count: CountStrategy,
+}
+
+/// How `sqlite_count` finds the total a numbered page's `pagination` reports
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CountStrategy {
+    /// A `COUNT(*)` over the location's query (or `sqlite_count_query`)
+    #[default]
+    Exact,
+    /// The highest rowid of a table: the query's first `FROM` table, or the
+    /// one named by `table=`
+    Estimate(Option<String>),
+    /// No total; pages ask for one extra row to tell whether another follows
+    None,
+}
+
+impl CountStrategy {
+    /// Parse `exact`, `estimate [table=name]` or `none`
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        match args {
+            ["exact"] => Ok(CountStrategy::Exact),
+            ["estimate"] => Ok(CountStrategy::Estimate(None)),
+            ["estimate", table] => match table.strip_prefix("table=") {
+                Some(table) if is_identifier(table) => {
+                    Ok(CountStrategy::Estimate(Some(table.to_string())))
+                }
+                _ => Err(format!("expected table=name, got '{}'", table)),
+            },
+            ["none"] => Ok(CountStrategy::None),
+            _ => Err(format!(
+                "expected exact, estimate [table=name] or none, got '{}'",
+                args.join(" ")
+            )),
+        }
+    }
+
+    /// How the total was found, as the `pagination` object's `count`
+    pub fn name(&self) -> &'static str {
+        match self {
+            CountStrategy::Exact => "exact",
+            CountStrategy::Estimate(_) => "estimate",
+            CountStrategy::None => "none",
+        }
+    }
+}
+
+/// How a `sqlite_paginate` request says which page it wants
+#[derive(Debug, Clone)]
+pub enum PageMode {
+    /// A page number in the `$arg_...` variable (`mode=offset`, the default)
+    Offset(NginxVariable),
+    /// A signed cursor in `?after=` or `?before=` (`mode=cursor`)
+    Cursor(CursorKey),
+}
+
+/// The column `mode=cursor` pages on, its direction, and the secret cursors
+/// are signed with
+#[derive(Debug, Clone)]
+pub struct CursorKey {
+    column: String,
+    descending: bool,
+    secret: String,
+}
+
+impl CursorKey {
+    /// The result column rows are ordered and compared on
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    /// Whether pages run from the highest key down (`order=desc`)
+    pub fn descending(&self) -> bool {
+        self.descending
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+}
+
+impl Pagination {
+    /// Parse `per_page=rows` and an optional `param=name` (default `page`),
+    /// or, with `mode=cursor`, `key=column`, `secret=key` and an optional
+    /// `order=asc|desc`
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut per_page = None;
+        let mut param = None;
+        let mut cursor = false;
+        let mut key = None;
+        let mut secret = None;
+        let mut descending = None;
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("per_page", rows)) => per_page = Some(RowLimit::parse(rows)?),
+                Some(("param", name))
+                    if !name.is_empty()
+                        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+                {
+                    param = Some(name);
+                }
+                Some(("mode", "offset")) => cursor = false,
+                Some(("mode", "cursor")) => cursor = true,
+                Some(("key", column)) if is_identifier(column) => key = Some(column),
+                Some(("secret", "")) => return Err("secret cannot be empty".to_string()),
+                Some(("secret", value)) => secret = Some(value),
+                Some(("order", "asc")) => descending = Some(false),
+                Some(("order", "desc")) => descending = Some(true),
+                _ => {
+                    return Err(format!(
+                        "expected per_page=rows, param=name, mode=offset|cursor, key=column, \
+                         secret=key or order=asc|desc, got '{}'",
+                        arg
+                    ));
+                }
+            }
+        }
+
+        let per_page = per_page.ok_or("per_page= is required")?;
+        let mode = if cursor {
+            if param.is_some() {
+                return Err("mode=cursor reads ?after= and ?before=, not param=".to_string());
+            }
+            PageMode::Cursor(CursorKey {
+                column: key.ok_or("mode=cursor needs key=column")?.to_string(),
+                descending: descending.unwrap_or(false),
+                secret: secret
+                    .ok_or("mode=cursor needs secret= to sign its cursors")?
+                    .to_string(),
+            })
+        } else {
+            if key.is_some() || secret.is_some() || descending.is_some() {
+                return Err("key=, secret= and order= need mode=cursor".to_string());
+            }
+            PageMode::Offset(NginxVariable::parse(format!(
+                "$arg_{}",
+                param.unwrap_or("page")
+            ))?)
+        };
+        Ok(Pagination {
+            per_page,
+            mode,
+            count: CountStrategy::Exact,
+        })
+    }
+
+    /// These settings with pages counted by `count` (`sqlite_count`)
+    pub fn with_count(self, count: CountStrategy) -> Self {
+        Pagination { count, ..self }
+    }
+
+    pub fn per_page(&self) -> RowLimit {
+        self.per_page
+    }
+
+    pub fn mode(&self) -> &PageMode {
+        &self.mode
+    }
+
+    pub fn count(&self) -> &CountStrategy {
+        &self.count
+    }
+}
+
+/// A validated row limit (a positive integer)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowLimit(usize);
+
+impl RowLimit {
+    /// Parse a row limit from a directive argument
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().parse::<usize>() {
+            Ok(0) => Err("row limit must be greater than zero".to_string()),
+            Ok(n) => Ok(RowLimit(n)),
+            Err(_) => Err(format!("row limit must be a positive integer: {}", value)),
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.0
+    }
+}
+
+/// A summary statistic computed over a result column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFn {
+    Min,
+    Max,
+    Sum,
+    Avg,
+}
+
+impl AggregateFn {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "min" => Ok(AggregateFn::Min),
+            "max" => Ok(AggregateFn::Max),
+            "sum" => Ok(AggregateFn::Sum),
+            "avg" => Ok(AggregateFn::Avg),
+            other => Err(format!(
+                "unknown aggregate '{}' (expected min, max, sum or avg)",
+                other
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AggregateFn::Min => "min",
+            AggregateFn::Max => "max",
+            AggregateFn::Sum => "sum",
+            AggregateFn::Avg => "avg",
+        }
+    }
+}
+
+/// A validated `sqlite_aggregate` specification (functions over named columns)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregateSpec {
+    functions: Vec<AggregateFn>,
+    columns: Vec<String>,
+}
+
+impl AggregateSpec {
+    /// Parse `min,max,...` and `columns=a,b,...` directive arguments
+    pub fn parse(functions: &str, columns: &str) -> Result<Self, String> {
+        let functions = functions
+            .split(',')
+            .map(AggregateFn::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let columns = columns
+            .strip_prefix("columns=")
+            .ok_or_else(|| format!("expected columns=name[,name...]: {}", columns))?
+            .split(',')
+            .map(|c| c.trim().to_string())
+            .collect::<Vec<_>>();
+        if columns.iter().any(|c| c.is_empty()) {
+            return Err("column names cannot be empty".to_string());
+        }
+
+        Ok(AggregateSpec { functions, columns })
+    }
+
+    pub fn functions(&self) -> &[AggregateFn] {
+        &self.functions
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+/// Which result columns `sqlite_expand_json` turns from JSON text into nested values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum JsonExpansion {
+    #[default]
+    Off,
+    /// Any text that holds a JSON object or array
+    All,
+    /// Any valid JSON text in the named columns
+    Columns(Vec<String>),
+}
+
+impl JsonExpansion {
+    /// Parse `on`, `off` or `columns=a,b,...`
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        match arg {
+            "on" => Ok(JsonExpansion::All),
+            "off" => Ok(JsonExpansion::Off),
+            _ => {
+                let columns = arg
+                    .strip_prefix("columns=")
+                    .ok_or_else(|| format!("expected on, off or columns=name[,name...]: {}", arg))?
+                    .split(',')
+                    .map(|c| c.trim().to_string())
+                    .collect::<Vec<_>>();
+                if columns.iter().any(|c| c.is_empty()) {
+                    return Err("column names cannot be empty".to_string());
+                }
+                Ok(JsonExpansion::Columns(columns))
+            }
+        }
+    }
+}
+
+/// Whether JSON responses keep the keys of NULL values (`sqlite_json_nulls`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JsonNulls {
+    /// `{"id": 1, "subtitle": null}`
+    #[default]
+    Keep,
+    /// `{"id": 1}`
+    Omit,
+}
+
+impl JsonNulls {
+    /// Parse `keep` or `omit`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "keep" => Ok(JsonNulls::Keep),
+            "omit" => Ok(JsonNulls::Omit),
+            other => Err(format!("expected keep or omit, got '{}'", other)),
+        }
+    }
+}
+
+/// A named extra query whose rows are exposed to the template under its name
+#[derive(Debug, Clone)]
+pub struct DataSource {
+    name: String,
+    db_path: DatabasePath,
+    query: SqlQuery,
+}
+
+impl DataSource {
+    /// Template context keys that sources may not shadow
+    const RESERVED_NAMES: [&'static str; 7] = [
+        "results",
+        "meta",
+        "params",
+        "stats",
+        "nav",
+        "pagination",
+        "breadcrumbs",
+    ];
+
+    /// Parse a `sqlite_source name db_path "SELECT ..."` definition
+    pub fn parse(name: &str, db_path: &str, query: &str) -> Result<Self, String> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!(
+                "source name must be letters, digits or underscores: '{}'",
+                name
+            ));
+        }
+        if Self::RESERVED_NAMES.contains(&name) {
+            return Err(format!("source name '{}' is reserved", name));
+        }
+
+        Ok(DataSource {
+            name: name.to_string(),
+            db_path: DatabasePath::parse(db_path)?,
+            query: SqlQuery::parse(query)?,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn db_path(&self) -> &DatabasePath {
+        &self.db_path
+    }
+
+    pub fn query(&self) -> &SqlQuery {
+        &self.query
+    }
+}
+
+/// A `sqlite_nav` menu: a named query whose rows the `{{#nav}}` helper
+/// renders, cached for `max_age` between requests
+#[derive(Debug, Clone)]
+pub struct NavMenu {
+    source: DataSource,
+    max_age: Duration,
+}
+
+impl NavMenu {
+    /// How long menu rows are reused when no `max_age=` is given
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+    /// Parse a `sqlite_nav name db_path "SELECT ..." [max_age=seconds]`
+    /// definition
+    pub fn parse(name: &str, db_path: &str, query: &str, options: &[&str]) -> Result<Self, String> {
+        let source = DataSource::parse(name, db_path, query)?;
+
+        let mut max_age = Self::DEFAULT_MAX_AGE;
+        for option in options {
+            match option.split_once('=') {
+                Some(("max_age", seconds)) => {
+                    let seconds = seconds.strip_suffix('s').unwrap_or(seconds);
+                    max_age = Duration::from_secs(seconds.parse::<u64>().map_err(|_| {
+                        format!("max_age must be a number of seconds: '{}'", seconds)
+                    })?);
+                }
+                _ => return Err(format!("expected max_age=, got '{}'", option)),
+            }
+        }
+
+        Ok(NavMenu { source, max_age })
+    }
+
+    pub fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    /// The menu's database and query, run like a `sqlite_source`
+    pub fn source(&self) -> &DataSource {
+        &self.source
+    }
+
+    /// How long fetched rows are reused; zero disables caching
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+    /// Reuse fetched rows for `max_age` instead
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+}
+
+/// A `sqlite_expand` relation: a query for the rows related to each result
+/// row, nested under `name` in JSON responses that ask for it
+///
+/// The query compares one column with one named parameter, and the parameter
+/// names the result column holding the related key
+/// (`SELECT * FROM authors WHERE id = :author_id`). It is run once per
+/// response over every key, with the comparison rewritten as `IN (...)`, and
+/// its rows are matched back to result rows on the compared column.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    name: String,
+    query: SqlQuery,
+    batched: SqlQuery,
+    param: String,
+    column: String,
+    many: bool,
+}
+
+impl Expansion {
+    /// Parse a `sqlite_expand name "SELECT ..." [many]` definition; a colon
+    /// after the name is allowed (`author:`)
+    pub fn parse(name: &str, query: &str, options: &[&str]) -> Result<Self, String> {
+        let name = name.strip_suffix(':').unwrap_or(name);
+        if !is_identifier(name) {
+            return Err(format!(
+                "expansion name must be letters, digits or underscores: '{}'",
+                name
+            ));
+        }
+        let many = match options {
+            [] => false,
+            ["many"] => true,
+            _ => return Err(format!("expected many, got '{}'", options.join(" "))),
+        };
+        let query = SqlQuery::parse(query)?;
+
+        let comparison = regex::Regex::new(
+            r"([A-Za-z_][A-Za-z0-9_]*(?:\.[A-Za-z_][A-Za-z0-9_]*)?)\s*=\s*:([A-Za-z_][A-Za-z0-9_]*)\b",
+        )
+        .expect("the comparison pattern is valid");
+        let mut comparisons = comparison.captures_iter(query.as_str());
+        let (compared, column, param) = match (comparisons.next(), comparisons.next()) {
+            (Some(found), None) => (
+                found[0].to_string(),
+                found[1].to_string(),
+                found[2].to_string(),
+            ),
+            _ => {
+                return Err(
+                    "query must compare one column with one :parameter (id = :author_id)"
+                        .to_string(),
+                );
+            }
+        };
+        let batched = query
+            .as_str()
+            .replacen(&compared, &format!("{} IN (:{})", column, param), 1);
+        // Rows come back with the column's own name, without its table
+        let column = column.rsplit('.').next().unwrap_or(&column).to_string();
+
+        Ok(Expansion {
+            name: name.to_string(),
+            batched: SqlQuery::parse(batched)?,
+            query,
+            param: format!(":{}", param),
+            column,
+            many,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The query as written
+    pub fn query(&self) -> &SqlQuery {
+        &self.query
+    }
+
+    /// The query over a list of keys, bound to [`Self::param`]
+    pub fn batched(&self) -> &SqlQuery {
+        &self.batched
+    }
+
+    /// The placeholder, with its colon
+    pub fn param(&self) -> &str {
+        &self.param
+    }
+
+    /// The result column holding each row's related key
+    pub fn key(&self) -> &str {
+        &self.param[1..]
+    }
+
+    /// The related rows' column matched against the key
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    /// Whether each row gets every related row (an array) or the first (an
+    /// object, or null)
+    pub fn many(&self) -> bool {
+        self.many
+    }
+}
+
+/// A `sqlite_query_if` rule: use `query` when `variable` is non-empty
+#[derive(Debug, Clone)]
+pub struct QueryRule {
+    variable: NginxVariable,
+    query: SqlQuery,
+}
+
+impl QueryRule {
+    pub fn parse(variable: &str, query: &str) -> Result<Self, String> {
+        Ok(QueryRule {
+            variable: NginxVariable::parse(variable)?,
+            query: SqlQuery::parse(query)?,
+        })
+    }
+
+    pub fn variable(&self) -> &NginxVariable {
+        &self.variable
+    }
+
+    pub fn query(&self) -> &SqlQuery {
+        &self.query
+    }
+}
+
+/// A `sqlite_sort_param`: the variable a request names its sort columns in,
+/// and the columns it may name
+#[derive(Debug, Clone)]
+pub struct SortParam {
+    variable: NginxVariable,
+    allowed: Vec<String>,
+}
+
+impl SortParam {
+    /// Parse the variable and its `allow=column,...` option
+    pub fn parse(variable: &str, options: &[&str]) -> Result<Self, String> {
+        let variable = NginxVariable::parse(variable)?;
+        let mut allowed: Vec<String> = Vec::new();
+        for option in options {
+            let Some(columns) = option.strip_prefix("allow=") else {
+                return Err(format!("expected allow=column,..., got '{}'", option));
+            };
+            for column in columns.split(',') {
+                if !is_identifier(column) {
+                    return Err(format!(
+                        "sort columns must be letters, digits or underscores: '{}'",
+                        column
+                    ));
+                }
+                allowed.push(column.to_string());
+            }
+        }
+        if allowed.is_empty() {
+            return Err("expected allow=column,... naming the sortable columns".to_string());
+        }
+        Ok(SortParam { variable, allowed })
+    }
+
+    pub fn variable(&self) -> &NginxVariable {
+        &self.variable
+    }
+
+    pub fn allowed(&self) -> &[String] {
+        &self.allowed
+    }
+
+    /// The `ORDER BY` terms a request's value asks for, or `None` for an
+    /// empty value
+    ///
+    /// The value lists allowed columns separated by commas, each sorted in
+    /// descending order when it starts with `-` (`-year,title`).
+    pub fn order_by(&self, value: &str) -> Result<Option<String>, String> {
+        if value.trim().is_empty() {
+            return Ok(None);
+        }
+        let terms = value
+            .split(',')
+            .map(|key| {
+                let key = key.trim();
+                let (column, direction) = match key.strip_prefix('-') {
+                    Some(column) => (column, "DESC"),
+                    None => (key, "ASC"),
+                };
+                if self.allowed.iter().any(|allowed| allowed == column) {
+                    Ok(format!("\"{}\" {}", column, direction))
+                } else {
+                    Err(format!(
+                        "cannot sort by '{}'; expected one of {}",
+                        column,
+                        self.allowed.join(", ")
+                    ))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(terms.join(", ")))
+    }
+}
+
+/// A `sqlite_dry_run`: the variable that turns a request into a dry run,
+/// and the parameters whose values its report leaves out
+#[derive(Debug, Clone)]
+pub struct DryRun {
+    gate: NginxVariable,
+    redacted: Vec<String>,
+}
+
+impl DryRun {
+    /// Parse the gate variable and its `redact=:name,...` option
+    pub fn parse(gate: &str, options: &[&str]) -> Result<Self, String> {
+        let gate = NginxVariable::parse(gate)?;
+        let mut redacted: Vec<String> = Vec::new();
+        for option in options {
+            let Some(names) = option.strip_prefix("redact=") else {
+                return Err(format!("expected redact=:name,..., got '{}'", option));
+            };
+            for name in names.split(',') {
+                redacted.push(ParamName::parse(name)?.as_str().to_string());
+            }
+        }
+        Ok(DryRun { gate, redacted })
+    }
+
+    /// The variable a request sets, to any non-empty value, to be a dry run
+    pub fn gate(&self) -> &NginxVariable {
+        &self.gate
+    }
+
+    /// Whether the report hides the value bound to `name`
+    pub fn redacts(&self, name: &str) -> bool {
+        self.redacted.iter().any(|redacted| redacted == name)
+    }
+}
+
+/// How a `sqlite_filter` compares its column with the request's value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterOperator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Like,
+}
+
+impl FilterOperator {
+    /// Parse `=`, `!=`, `<`, `<=`, `>`, `>=` or `like`
+    pub fn parse(operator: &str) -> Result<Self, String> {
+        match operator.to_ascii_lowercase().as_str() {
+            "=" => Ok(FilterOperator::Equal),
+            "!=" => Ok(FilterOperator::NotEqual),
+            "<" => Ok(FilterOperator::Less),
+            "<=" => Ok(FilterOperator::LessOrEqual),
+            ">" => Ok(FilterOperator::Greater),
+            ">=" => Ok(FilterOperator::GreaterOrEqual),
+            "like" => Ok(FilterOperator::Like),
+            _ => Err(format!(
+                "unknown operator '{}' (expected =, !=, <, <=, >, >= or like)",
+                operator
+            )),
+        }
+    }
+
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            FilterOperator::Equal => "=",
+            FilterOperator::NotEqual => "<>",
+            FilterOperator::Less => "<",
+            FilterOperator::LessOrEqual => "<=",
+            FilterOperator::Greater => ">",
+            FilterOperator::GreaterOrEqual => ">=",
+            FilterOperator::Like => "LIKE",
+        }
+    }
+}
+
+/// A `sqlite_filter`: a condition on a result column that applies when its
+/// variable has a value
+#[derive(Debug, Clone)]
+pub struct Filter {
+    column: String,
+    operator: FilterOperator,
+    variable: NginxVariable,
+    param_type: ParamType,
+}
+
+impl Filter {
+    /// Parse `column operator $variable` and an optional type (text by default)
+    pub fn parse(
+        column: &str,
+        operator: &str,
+        variable: &str,
+        param_type: Option<&str>,
+    ) -> Result<Self, String> {
+        if !is_identifier(column) {
+            return Err(format!(
+                "filter columns must be letters, digits or underscores: '{}'",
+                column
+            ));
+        }
+        Ok(Filter {
+            column: column.to_string(),
+            operator: FilterOperator::parse(operator)?,
+            variable: NginxVariable::parse(variable)?,
+            param_type: param_type
+                .map(ParamType::parse)
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn operator(&self) -> FilterOperator {
+        self.operator
+    }
+
+    pub fn variable(&self) -> &NginxVariable {
+        &self.variable
+    }
+
+    pub fn param_type(&self) -> ParamType {
+        self.param_type
+    }
+}
+
+/// A `sqlite_rest` table and the column that identifies one row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestTable {
+    table: String,
+    key: String,
+}
+
+impl RestTable {
+    /// Parse `table` and an optional `key=column` argument (the key defaults to `id`)
+    pub fn parse(table: &str, key: Option<&str>) -> Result<Self, String> {
+        let key = match key {
+            Some(arg) => arg
+                .strip_prefix("key=")
+                .ok_or_else(|| format!("expected key=column, got '{}'", arg))?,
+            None => "id",
+        };
+        for (what, name) in [("table", table), ("key column", key)] {
+            if !is_identifier(name) {
+                return Err(format!(
+                    "{} name must be letters, digits or underscores: '{}'",
+                    what, name
+                ));
+            }
+        }
+
+        Ok(RestTable {
+            table: table.to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// A `sqlite_upsert` table and the columns that identify one row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpsertTable {
+    table: String,
+    keys: Vec<String>,
+}
+
+impl UpsertTable {
+    /// Parse `table` and its parenthesised key columns, e.g. `(isbn)` or
+    /// `(shelf, position)`
+    pub fn parse(table: &str, keys: &str) -> Result<Self, String> {
+        let list = keys
+            .trim()
+            .strip_prefix('(')
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or_else(|| format!("expected (key_column, ...), got '{}'", keys))?;
+        let keys: Vec<String> = list.split(',').map(|k| k.trim().to_string()).collect();
+
+        for (what, name) in
+            std::iter::once(("table", table)).chain(keys.iter().map(|k| ("key column", k.as_str())))
+        {
+            if !is_identifier(name) {
+                return Err(format!(
+                    "{} name must be letters, digits or underscores: '{}'",
+                    what, name
+                ));
+            }
+        }
+        if let Some(key) = keys
+            .iter()
+            .enumerate()
+            .find_map(|(i, key)| keys[..i].contains(key).then_some(key))
+        {
+            return Err(format!("key column '{}' is listed more than once", key));
+        }
+
+        Ok(UpsertTable {
+            table: table.to_string(),
+            keys,
+        })
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+}
+
+/// A `sqlite_blob` table: blobs kept under the SHA-256 of their content,
+/// served at `<location>/<hash>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobRoute {
+    table: String,
+    data: String,
+    hash: String,
+    content_type: Option<String>,
+}
+
+impl BlobRoute {
+    /// Parse `table` and optional `data=column`, `hash=column` and
+    /// `type=column` arguments (the columns default to `data` and `sha256`;
+    /// without a type column blobs are sent as `application/octet-stream`)
+    pub fn parse(table: &str, args: &[&str]) -> Result<Self, String> {
+        let mut route = BlobRoute {
+            table: table.to_string(),
+            data: "data".to_string(),
+            hash: "sha256".to_string(),
+            content_type: None,
+        };
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("data", column)) => route.data = column.to_string(),
+                Some(("hash", column)) => route.hash = column.to_string(),
+                Some(("type", column)) => route.content_type = Some(column.to_string()),
+                _ => return Err(format!("expected data=, hash= or type=, got '{}'", arg)),
+            }
+        }
+
+        let columns = [&route.data, &route.hash]
+            .into_iter()
+            .chain(&route.content_type);
+        for (what, name) in
+            std::iter::once(("table", &route.table)).chain(columns.map(|column| ("column", column)))
+        {
+            if !is_identifier(name) {
+                return Err(format!(
+                    "{} name must be letters, digits or underscores: '{}'",
+                    what, name
+                ));
+            }
+        }
+        Ok(route)
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    /// The statement reading a blob and its content type by hash (`?1`)
+    ///
+    /// Columns are qualified with the table, so a misspelt one is an error
+    /// rather than a double-quoted string.
+    pub fn query(&self) -> String {
+        let column = |name: &str| format!("\"{}\".\"{}\"", self.table, name);
+        let content_type = self
+            .content_type
+            .as_deref()
+            .map(column)
+            .unwrap_or_else(|| "NULL".to_string());
+        format!(
+            "SELECT {}, {} FROM \"{}\" WHERE {} = ?1 LIMIT 1",
+            column(&self.data),
+            content_type,
+            self.table,
+            column(&self.hash)
+        )
+    }
+}
+
+/// A `sqlite_soft_delete` column: rows with a value in it are deleted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoftDelete {
+    column: String,
+}
+
+impl SoftDelete {
+    /// Parse `column=name`
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let column = arg
+            .strip_prefix("column=")
+            .ok_or_else(|| format!("expected column=name, got '{}'", arg))?;
+        if !is_identifier(column) {
+            return Err(format!(
+                "column name must be letters, digits or underscores: '{}'",
+                column
+            ));
+        }
+        Ok(SoftDelete {
+            column: column.to_string(),
+        })
+    }
+
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    /// `query`, without the rows that are deleted
+    ///
+    /// The column is qualified with the subquery's alias, so a query that
+    /// does not return it fails to prepare instead of comparing a
+    /// double-quoted string.
+    pub fn live_rows(&self, query: &SqlQuery) -> Result<SqlQuery, String> {
+        // The newline ends any trailing `--` comment before the parenthesis
+        SqlQuery::parse(format!(
+            "SELECT * FROM ({}\n) AS live WHERE live.\"{}\" IS NULL",
+            query.as_str().trim().trim_end_matches(';'),
+            self.column
+        ))
+    }
+
+    /// A `DELETE FROM table ...` statement as an update that marks the rows
+    /// deleted at the current time; other statements are left as they are
+    pub fn mark_deleted(&self, write: &WriteQuery) -> Result<WriteQuery, String> {
+        let delete =
+            regex::Regex::new(r"(?is)^\s*DELETE\s+FROM\s+(\S+)(.*)$").map_err(|e| e.to_string())?;
+        match delete.captures(write.as_str()) {
+            Some(captures) => WriteQuery::parse(format!(
+                "UPDATE {} SET \"{}\" = CURRENT_TIMESTAMP{}",
+                &captures[1], self.column, &captures[2]
+            )),
+            None => Ok(write.clone()),
+        }
+    }
+}
+
+/// A time to read `sqlite_history` tables at (`?as_of=`), in UTC
+///
+/// Kept as `YYYY-MM-DD HH:MM:SS.SSS`, the form the history triggers stamp
+/// changes with, so the two compare as text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsOf(String);
+
+impl AsOf {
+    /// Parse a date (`2024-05-01`, meaning its midnight) or a date and time
+    /// (`2024-05-01T09:30`, `2024-05-01 09:30:15.250Z`)
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let format = regex::Regex::new(
+            r"^(\d{4})-(\d{2})-(\d{2})(?:[T ](\d{2}):(\d{2})(?::(\d{2})(?:\.(\d{1,9}))?)?)?Z?$",
+        )
+        .map_err(|e| e.to_string())?;
+        let invalid = || {
+            format!(
+                "expected a UTC date or time like 2024-05-01T09:30:00, got '{}'",
+                value
+            )
+        };
+        let captures = format.captures(value).ok_or_else(invalid)?;
+        let field = |i: usize| captures.get(i).map_or("00", |m| m.as_str());
+        let in_range = |i: usize, low: u32, high: u32| {
+            field(i)
+                .parse::<u32>()
+                .is_ok_and(|n| (low..=high).contains(&n))
+        };
+        if !(in_range(2, 1, 12)
+            && in_range(3, 1, 31)
+            && in_range(4, 0, 23)
+            && in_range(5, 0, 59)
+            && in_range(6, 0, 59))
+        {
+            return Err(invalid());
+        }
+        let millis: String = captures
+            .get(7)
+            .map_or("", |m| m.as_str())
+            .chars()
+            .chain(std::iter::repeat('0'))
+            .take(3)
+            .collect();
+        Ok(AsOf(format!(
+            "{}-{}-{} {}:{}:{}.{}",
+            field(1),
+            field(2),
+            field(3),
+            field(4),
+            field(5),
+            field(6),
+            millis
+        )))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// `sqlite_hit_counter` settings: the table hits are counted in, the
+/// variable naming the page a request counts towards and, optionally, a
+/// table of daily counts for `sqlite_trending` windows
+#[derive(Debug, Clone)]
+pub struct HitCounter {
+    table: String,
+    key: NginxVariable,
+    daily: Option<String>,
+}
+
+impl HitCounter {
+    /// Parse `table=name key=$variable [daily=name]`
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut table = None;
+        let mut key = None;
+        let mut daily = None;
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("table", name)) => table = Some(name),
+                Some(("key", variable)) => key = Some(variable),
+                Some(("daily", name)) => daily = Some(name),
+                _ => return Err(format!("expected table=, key= or daily=, got '{}'", arg)),
+            }
+        }
+
+        let table = table.ok_or_else(|| "table=name is required".to_string())?;
+        for name in std::iter::once(table).chain(daily) {
+            if !is_identifier(name) {
+                return Err(format!(
+                    "table name must be letters, digits or underscores: '{}'",
+                    name
+                ));
+            }
+        }
+        let key = key.ok_or_else(|| "key=$variable is required".to_string())?;
+        let key = NginxVariable::parse(key).map_err(|e| format!("invalid key: {}", e))?;
+
+        Ok(HitCounter {
+            table: table.to_string(),
+            key,
+            daily: daily.map(str::to_string),
+        })
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn key(&self) -> &NginxVariable {
+        &self.key
+    }
+
+    /// The table of per-day counts, if any
+    pub fn daily(&self) -> Option<&str> {
+        self.daily.as_deref()
+    }
+}
+
+/// `sqlite_trending` settings: which pages to rank for the `{{#trending}}`
+/// helper and how long to reuse the ranking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trending {
+    enabled: bool,
+    days: Option<u32>,
+    limit: u32,
+    max_age: Duration,
+}
+
+impl Trending {
+    /// Pages ranked when no `limit=` is given
+    pub const DEFAULT_LIMIT: u32 = 10;
+
+    /// Parse `on | [days=N] [limit=N] [max_age=seconds]`, or `off`
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut trending = Trending {
+            enabled: args != ["off"],
+            days: None,
+            limit: Self::DEFAULT_LIMIT,
+            max_age: NavMenu::DEFAULT_MAX_AGE,
+        };
+        for arg in args {
+            let number = |value: &str, what: &str| {
+                value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("{} must be a positive number: '{}'", what, value))
+            };
+            match arg.split_once('=') {
+                None if *arg == "on" || args == ["off"] => {}
+                Some(("days", days)) => trending.days = Some(number(days, "days")?),
+                Some(("limit", limit)) => trending.limit = number(limit, "limit")?,
+                Some(("max_age", seconds)) => {
+                    let seconds = seconds.strip_suffix('s').unwrap_or(seconds);
+                    trending.max_age =
+                        Duration::from_secs(seconds.parse::<u64>().map_err(|_| {
+                            format!("max_age must be a number of seconds: '{}'", seconds)
+                        })?);
+                }
+                _ => {
+                    return Err(format!(
+                        "expected on, off, days=, limit= or max_age=, got '{}'",
+                        arg
+                    ));
+                }
+            }
+        }
+        Ok(trending)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Rank pages over this many days, or over all time
+    pub fn days(&self) -> Option<u32> {
+        self.days
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// The ranking query over `counter`'s tables: `key` and `hits` for the
+    /// most visited pages, busiest first
+    pub fn query(&self, counter: &HitCounter) -> Result<String, String> {
+        // Table names are validated as identifiers by HitCounter::parse
+        match self.days {
+            None => Ok(format!(
+                "SELECT key, hits FROM \"{}\" ORDER BY hits DESC, key LIMIT {}",
+                counter.table(),
+                self.limit
+            )),
+            Some(days) => {
+                let daily = counter.daily().ok_or_else(|| {
+                    "days= needs sqlite_hit_counter to keep daily counts (daily=table)".to_string()
+                })?;
+                Ok(format!(
+                    "SELECT key, SUM(hits) AS hits FROM \"{}\" \
+                     WHERE day > date('now', '-{} days') \
+                     GROUP BY key ORDER BY hits DESC, key LIMIT {}",
+                    daily, days, self.limit
+                ))
+            }
+        }
+    }
+}
+
+/// `sqlite_memo_table` settings: the table computed JSON responses are kept
+/// in, and how long a stored response is served before it is recomputed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoTable {
+    enabled: bool,
+    table: String,
+    ttl: Duration,
+}
+
+impl MemoTable {
+    /// How long responses are kept when no `ttl=` is given
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(3600);
+
+    /// Parse `name [ttl=duration]`, or `off`; a duration is a number of
+    /// seconds, or of minutes, hours or days with an `m`, `h` or `d` suffix
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        if args == ["off"] {
+            return Ok(MemoTable {
+                enabled: false,
+                table: String::new(),
+                ttl: Self::DEFAULT_TTL,
+            });
+        }
+        let (table, options) = args
+            .split_first()
+            .ok_or_else(|| "expected a table name".to_string())?;
+        if !is_identifier(table) {
+            return Err(format!(
+                "table name must be letters, digits or underscores: '{}'",
+                table
+            ));
+        }
+
+        let mut ttl = Self::DEFAULT_TTL;
+        for option in options {
+            match option.split_once('=') {
+                Some(("ttl", value)) => ttl = parse_period("ttl", value)?,
+                _ => return Err(format!("expected ttl=, got '{}'", option)),
+            }
+        }
+
+        Ok(MemoTable {
+            enabled: true,
+            table: table.to_string(),
+            ttl,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+}
+
+/// A `sqlite_report`: a file a POST, or its schedule, saves the location's
+/// rows in, replacing the one the previous run saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    name: String,
+    directory: PathBuf,
+    format: ReportFormat,
+    every: Option<Duration>,
+}
+
+/// What a report's rows are saved as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// The JSON array the location answers with
+    #[default]
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// A spreadsheet with a header row (the `xlsx` cargo feature)
+    Xlsx,
+}
+
+impl ReportFormat {
+    /// The file extension, which is also the format's name
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Json => "json",
+            ReportFormat::Ndjson => "ndjson",
+            ReportFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+impl Report {
+    /// Parse `name /absolute/directory [format=json|ndjson|xlsx] [every=interval]`
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let [name, directory, options @ ..] = args else {
+            return Err("expected a name and a directory".to_string());
+        };
+        if !Theme::is_name(name) {
+            return Err(format!(
+                "report name must be letters, digits, - or _: '{}'",
+                name
+            ));
+        }
+        let directory = Path::new(directory);
+        if !directory.is_absolute() {
+            return Err(format!(
+                "report directory must be an absolute path: '{}'",
+                directory.display()
+            ));
+        }
+
+        let mut format = ReportFormat::default();
+        let mut every = None;
+        for option in options {
+            match option.split_once('=') {
+                Some(("format", "json")) => format = ReportFormat::Json,
+                Some(("format", "ndjson")) => format = ReportFormat::Ndjson,
+                Some(("format", "xlsx")) if cfg!(feature = "xlsx") => format = ReportFormat::Xlsx,
+                Some(("format", "xlsx")) => {
+                    return Err("format=xlsx needs the module built with the xlsx feature".into());
+                }
+                Some(("format", other)) => {
+                    return Err(format!(
+                        "format must be json, ndjson or xlsx, got '{}'",
+                        other
+                    ));
+                }
+                Some(("every", value)) => every = Some(parse_period("every", value)?),
+                _ => return Err(format!("expected format= or every=, got '{}'", option)),
+            }
+        }
+
+        Ok(Report {
+            name: name.to_string(),
+            directory: directory.to_path_buf(),
+            format,
+            every,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn format(&self) -> ReportFormat {
+        self.format
+    }
+
+    /// How often the report runs by itself, if it has a schedule
+    pub fn every(&self) -> Option<Duration> {
+        self.every
+    }
+
+    /// Where the report is saved: `<directory>/<name>.<format>`
+    pub fn path(&self) -> PathBuf {
+        self.directory
+            .join(format!("{}.{}", self.name, self.format.extension()))
+    }
+}
+
+/// A period of `what` (`ttl`, `every`): seconds, or a number ending in m, h or d
+fn parse_period(what: &str, value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, unit @ ('s' | 'm' | 'h' | 'd'))) => (&value[..i], unit),
+        _ => (value, 's'),
+    };
+    let scale = match unit {
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => 1,
+    };
+    match number.parse::<u64>() {
+        Ok(0) => Err(format!("{} must be greater than zero", what)),
+        Ok(n) => Ok(Duration::from_secs(n.saturating_mul(scale))),
+        Err(_) => Err(format!(
+            "{} must be a number of seconds, or end in m, h or d: '{}'",
+            what, value
+        )),
+    }
+}
+
+/// `sqlite_honeypot` settings: a form field people leave empty (it is hidden
+/// from them) and, optionally, the least time a person takes to submit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Honeypot {
+    field: String,
+    min_time: Option<u64>,
+    time_field: String,
+}
+
+impl Honeypot {
+    /// Parse `field=name` and optional `min_time=seconds` and `time_field=name`
+    /// arguments (the time field defaults to `form_time`)
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut field = None;
+        let mut min_time = None;
+        let mut time_field = "form_time";
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("field", name)) => field = Some(name),
+                Some(("time_field", name)) => time_field = name,
+                Some(("min_time", seconds)) => {
+                    let seconds = seconds.strip_suffix('s').unwrap_or(seconds);
+                    min_time = Some(seconds.parse::<u64>().map_err(|_| {
+                        format!("min_time must be a number of seconds: '{}'", seconds)
+                    })?);
+                }
+                _ => {
+                    return Err(format!(
+                        "expected field=, min_time= or time_field=, got '{}'",
+                        arg
+                    ));
+                }
+            }
+        }
+
+        let field = field.ok_or_else(|| "field=name is required".to_string())?;
+        for name in [field, time_field] {
+            if !is_identifier(name) {
+                return Err(format!(
+                    "field name must be letters, digits or underscores: '{}'",
+                    name
+                ));
+            }
+        }
+
+        Ok(Honeypot {
+            field: field.to_string(),
+            min_time,
+            time_field: time_field.to_string(),
+        })
+    }
+
+    /// The field that must stay empty, e.g. `website`
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// Seconds that must pass between rendering the form and submitting it
+    pub fn min_time(&self) -> Option<u64> {
+        self.min_time
+    }
+
+    /// The field carrying the time the form was rendered (`{{now}}`)
+    pub fn time_field(&self) -> &str {
+        &self.time_field
+    }
+}
+
+/// A captcha service whose widget supplies the token `sqlite_captcha` verifies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptchaProvider {
+    Turnstile,
+    HCaptcha,
+    ReCaptcha,
+}
+
+impl CaptchaProvider {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "turnstile" => Ok(CaptchaProvider::Turnstile),
+            "hcaptcha" => Ok(CaptchaProvider::HCaptcha),
+            "recaptcha" => Ok(CaptchaProvider::ReCaptcha),
+            _ => Err(format!(
+                "unknown captcha provider '{}', expected turnstile, hcaptcha or recaptcha",
+                s
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CaptchaProvider::Turnstile => "turnstile",
+            CaptchaProvider::HCaptcha => "hcaptcha",
+            CaptchaProvider::ReCaptcha => "recaptcha",
+        }
+    }
+
+    /// The form field the provider's widget puts its token in
+    pub fn token_field(self) -> &'static str {
+        match self {
+            CaptchaProvider::Turnstile => "cf-turnstile-response",
+            CaptchaProvider::HCaptcha => "h-captcha-response",
+            CaptchaProvider::ReCaptcha => "g-recaptcha-response",
+        }
+    }
+}
+
+/// `sqlite_captcha` settings: the provider, its secret key, and the internal
+/// location that forwards verification requests to the provider
+#[derive(Clone, PartialEq, Eq)]
+pub struct Captcha {
+    provider: CaptchaProvider,
+    secret: String,
+    verify_uri: String,
+    field: String,
+}
+
+impl Captcha {
+    /// Parse `provider=name secret=key` and optional `verify=/uri` and
+    /// `field=name` arguments (by default `/_sqlite_captcha` and the
+    /// provider's token field)
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut provider = None;
+        let mut secret = None;
+        let mut verify_uri = "/_sqlite_captcha";
+        let mut field = None;
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("provider", name)) => provider = Some(CaptchaProvider::parse(name)?),
+                Some(("secret", key)) => secret = Some(key),
+                Some(("verify", uri)) => verify_uri = uri,
+                Some(("field", name)) => field = Some(name),
+                _ => {
+                    // Never echo the argument: it may be the secret
+                    return Err(
+                        "expected provider=, secret=, verify= or field= arguments".to_string()
+                    );
+                }
+            }
+        }
+
+        let provider = provider.ok_or_else(|| "provider=name is required".to_string())?;
+        let secret = secret
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| "secret=key is required".to_string())?;
+        if !verify_uri.starts_with('/') || verify_uri.contains(['?', ' ']) {
+            return Err(format!(
+                "verify must be a location path like /_sqlite_captcha: '{}'",
+                verify_uri
+            ));
+        }
+        let field = field.unwrap_or(provider.token_field());
+        if field.is_empty()
+            || !field
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "field name must be letters, digits, '-' or '_': '{}'",
+                field
+            ));
+        }
+
+        Ok(Captcha {
+            provider,
+            secret: secret.to_string(),
+            verify_uri: verify_uri.to_string(),
+            field: field.to_string(),
+        })
+    }
+
+    pub fn provider(&self) -> CaptchaProvider {
+        self.provider
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// The internal location verification subrequests go to
+    pub fn verify_uri(&self) -> &str {
+        &self.verify_uri
+    }
+
+    /// The query argument carrying the widget's token
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+}
+
+// The secret stays out of debug output
+impl std::fmt::Debug for Captcha {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Captcha")
+            .field("provider", &self.provider)
+            .field("secret", &"<redacted>")
+            .field("verify_uri", &self.verify_uri)
+            .field("field", &self.field)
+            .finish()
+    }
+}
+
+/// `sqlite_notify_email` settings: who is told, and how, when a write succeeds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailNotification {
+    template: TemplatePath,
+    to: Vec<String>,
+    from: Option<String>,
+    subject: String,
+    reply_to: Option<String>,
+    methods: Vec<WriteMethod>,
+    sendmail: String,
+}
+
+impl EmailNotification {
+    /// Parse `template=file.hbs` and `to=address[,address]` plus optional
+    /// `from=`, `subject=`, `reply_to=field`, `on=POST,PUT` (every write method
+    /// by default) and `sendmail=/path` (`/usr/sbin/sendmail` by default)
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut template = None;
+        let mut to = Vec::new();
+        let mut from = None;
+        let mut subject = "Form submission";
+        let mut reply_to = None;
+        let mut methods = Vec::new();
+        let mut sendmail = "/usr/sbin/sendmail";
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("template", path)) => template = Some(TemplatePath::parse(path)?),
+                Some(("to", addresses)) => {
+                    for address in addresses.split(',') {
+                        to.push(email_address(address)?);
+                    }
+                }
+                Some(("from", address)) => from = Some(email_address(address)?),
+                Some(("subject", text)) => subject = text,
+                Some(("reply_to", field)) => reply_to = Some(field),
+                Some(("on", names)) => {
+                    for name in names.split(',') {
+                        methods.push(WriteMethod::parse(name)?);
+                    }
+                }
+                Some(("sendmail", path)) => sendmail = path,
+                _ => {
+                    return Err(format!(
+                        "expected template=, to=, from=, subject=, reply_to=, on= or sendmail=, got '{}'",
+                        arg
+                    ));
+                }
+            }
+        }
+
+        let template = template.ok_or_else(|| "template=file.hbs is required".to_string())?;
+        if to.is_empty() {
+            return Err("to=address is required".to_string());
+        }
+        // The subject goes into a header as-is, so it must be plain ASCII text
+        if subject.is_empty()
+            || !subject
+                .chars()
+                .all(|c| c.is_ascii() && !c.is_ascii_control())
+        {
+            return Err(format!("subject must be printable ASCII: '{}'", subject));
+        }
+        if let Some(field) = reply_to
+            && !is_identifier(field)
+        {
+            return Err(format!(
+                "reply_to must name a parameter (letters, digits or underscores): '{}'",
+                field
+            ));
+        }
+        if !sendmail.starts_with('/') {
+            return Err(format!("sendmail must be an absolute path: '{}'", sendmail));
+        }
+        if methods.is_empty() {
+            methods = vec![
+                WriteMethod::Post,
+                WriteMethod::Put,
+                WriteMethod::Patch,
+                WriteMethod::Delete,
+            ];
+        }
+
+        Ok(EmailNotification {
+            template,
+            to,
+            from,
+            subject: subject.to_string(),
+            reply_to: reply_to.map(str::to_string),
+            methods,
+            sendmail: sendmail.to_string(),
+        })
+    }
+
+    /// The body template, relative to the location like `sqlite_template`
+    pub fn template(&self) -> &TemplatePath {
+        &self.template
+    }
+
+    pub fn to(&self) -> &[String] {
+        &self.to
+    }
+
+    pub fn from(&self) -> Option<&str> {
+        self.from.as_deref()
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// The parameter (without `:`) whose submitted address becomes `Reply-To`
+    pub fn reply_to(&self) -> Option<&str> {
+        self.reply_to.as_deref()
+    }
+
+    /// Whether a successful write with this method sends the email
+    pub fn applies_to(&self, method: WriteMethod) -> bool {
+        self.methods.contains(&method)
+    }
+
+    pub fn sendmail(&self) -> &str {
+        &self.sendmail
+    }
+}
+
+fn email_address(address: &str) -> Result<String, String> {
+    let address = address.trim();
+    if is_email_address(address) {
+        Ok(address.to_string())
+    } else {
+        Err(format!("invalid email address '{}'", address))
+    }
+}
+
+/// Whether an address looks like `local@domain` and is safe to put in a
+/// header (no whitespace or control characters)
+pub fn is_email_address(address: &str) -> bool {
+    address.split_once('@').is_some_and(|(local, domain)| {
+        !local.is_empty()
+            && !domain.is_empty()
+            && !address.chars().any(|c| c.is_whitespace() || c.is_control())
+    })
+}
+
+/// Whether a name can be used unquoted as a SQL identifier and a parameter name
+pub fn is_identifier(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A parameter binding (param name + variable or literal)
+#[derive(Debug, Clone)]
+pub enum ParameterBinding {
+    Positional {
+        variable: NginxVariable,
+        max_length: Option<MaxLength>,
+    },
+    PositionalLiteral {
+        value: String,
+    },
+    /// Like `Positional`, but a variable that is missing or empty binds
+    /// `default` (`sqlite_param "$arg_page|1"`)
+    PositionalDefault {
+        variable: NginxVariable,
+        default: String,
+        max_length: Option<MaxLength>,
+    },
+    /// With a `default`, a variable that is missing or empty binds the
+    /// default instead (`sqlite_param :page $arg_page 1`, or
+    /// `sqlite_param :page "$arg_page|1"`); a `required` one
+    /// fails the request (`sqlite_param_required :id $arg_id`); otherwise a
+    /// missing variable binds NULL
+    Named {
+        name: ParamName,
+        variable: NginxVariable,
+        param_type: ParamType,
+        default: Option<String>,
+        required: bool,
+        pattern: Option<ParamPattern>,
+        max_length: Option<MaxLength>,
+    },
+    NamedLiteral {
+        name: ParamName,
+        value: String,
+        param_type: ParamType,
+    },
+    /// Like `Named`, but a missing variable binds an empty string
+    OptionalNamed {
+        name: ParamName,
+        variable: NginxVariable,
+        max_length: Option<MaxLength>,
+    },
+    /// A comma-separated variable bound as a list (`sqlite_param :ids[] $arg_ids`);
+    /// a `required` list needs at least one value
+    NamedList {
+        name: ParamName,
+        variable: NginxVariable,
+        param_type: ParamType,
+        required: bool,
+        pattern: Option<ParamPattern>,
+        max_length: Option<MaxLength>,
+    },
+    /// Every query-string argument not bound otherwise, as `:arg_<name>` text
+    /// (`sqlite_bind_args on`)
+    QueryArgs {
+        max_length: Option<MaxLength>,
+    },
+}
+
+impl ParameterBinding {
+    /// The named parameter the binding binds, if it is named
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            ParameterBinding::Named { name, .. }
+            | ParameterBinding::NamedLiteral { name, .. }
+            | ParameterBinding::OptionalNamed { name, .. }
+            | ParameterBinding::NamedList { name, .. } => Some(name.as_str()),
+            ParameterBinding::Positional { .. }
+            | ParameterBinding::PositionalLiteral { .. }
+            | ParameterBinding::PositionalDefault { .. }
+            | ParameterBinding::QueryArgs { .. } => None,
+        }
+    }
+
+    /// The variable the binding reads, if it reads one
+    pub fn variable(&self) -> Option<&NginxVariable> {
+        match self {
+            ParameterBinding::Positional { variable, .. }
+            | ParameterBinding::PositionalDefault { variable, .. }
+            | ParameterBinding::Named { variable, .. }
+            | ParameterBinding::OptionalNamed { variable, .. }
+            | ParameterBinding::NamedList { variable, .. } => Some(variable),
+            ParameterBinding::PositionalLiteral { .. }
+            | ParameterBinding::NamedLiteral { .. }
+            | ParameterBinding::QueryArgs { .. } => None,
+        }
+    }
+
+    /// Give the binding's variable the index nginx gave it, if `indexes`
+    /// (variable names and their indexes) has one
+    pub fn with_variable_index(mut self, indexes: &[(String, usize)]) -> Self {
+        let variable = match &mut self {
+            ParameterBinding::Positional { variable, .. }
+            | ParameterBinding::PositionalDefault { variable, .. }
+            | ParameterBinding::Named { variable, .. }
+            | ParameterBinding::OptionalNamed { variable, .. }
+            | ParameterBinding::NamedList { variable, .. } => variable,
+            ParameterBinding::PositionalLiteral { .. }
+            | ParameterBinding::NamedLiteral { .. }
+            | ParameterBinding::QueryArgs { .. } => return self,
+        };
+        if let Some((_, index)) = indexes.iter().find(|(name, _)| name == variable.as_str()) {
+            variable.index = Some(*index);
+        }
+        self
+    }
+
+    /// Cap a variable's values at `limit` unless the binding has its own cap;
+    /// literals are left alone
+    pub fn cap_length(&mut self, limit: MaxLength) {
+        match self {
+            ParameterBinding::Positional { max_length, .. }
+            | ParameterBinding::PositionalDefault { max_length, .. }
+            | ParameterBinding::Named { max_length, .. }
+            | ParameterBinding::OptionalNamed { max_length, .. }
+            | ParameterBinding::NamedList { max_length, .. }
+            | ParameterBinding::QueryArgs { max_length } => {
+                max_length.get_or_insert(limit);
+            }
+            ParameterBinding::PositionalLiteral { .. } | ParameterBinding::NamedLiteral { .. } => {}
+        }
+    }
+}
+
+/// The most characters a parameter value may have (`sqlite_param_max_length`
+/// or `max_length=`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxLength(usize);
+
+impl MaxLength {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().parse::<usize>() {
+            Ok(0) => Err("maximum length must be greater than zero".to_string()),
+            Ok(n) => Ok(MaxLength(n)),
+            Err(_) => Err(format!(
+                "maximum length must be a positive integer: {}",
+                value
+            )),
         }
+    }
 
-        // The name after : must not be empty
-        if name.len() == 1 {
-            return Err("parameter name after : cannot be empty".to_string());
-        }
+    pub fn get(&self) -> usize {
+        self.0
+    }
+}
 
-        Ok(ParamName(name))
+/// A `pattern=regex` that a named `sqlite_param` value must match in full,
+/// as if the regex were anchored at both ends
+#[derive(Debug, Clone)]
+pub struct ParamPattern {
+    source: String,
+    regex: regex::Regex,
+}
+
+impl ParamPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        if pattern.is_empty() {
+            return Err("pattern cannot be empty".to_string());
+        }
+        let regex = regex::Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?;
+        Ok(ParamPattern {
+            source: pattern.to_string(),
+            regex,
+        })
     }
+
     pub fn as_str(&self) -> &str {
-        &self.0
+        &self.source
     }
 
-    /// Get the parameter name without the : prefix
-    #[allow(dead_code)]
-    pub fn name_without_colon(&self) -> &str {
-        &self.0[1..]
+    pub fn is_match(&self, value: &str) -> bool {
+        self.regex.is_match(value)
     }
 }
 
-/// A parameter binding (param name + variable or literal)
-#[derive(Debug, Clone)]
-pub enum ParameterBinding {
-    Positional {
-        variable: NginxVariable,
-    },
-    PositionalLiteral {
-        value: String,
-    },
-    Named {
-        name: ParamName,
-        variable: NginxVariable,
-    },
-    NamedLiteral {
-        name: ParamName,
-        value: String,
-    },
+/// The SQLite type a named `sqlite_param` value is bound as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParamType {
+    #[default]
+    Text,
+    Integer,
+    Real,
+    Bool,
+}
+
+impl ParamType {
+    /// Parse `text`, `int`/`integer`, `float`/`real` or `bool`/`boolean`
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "text" => Ok(ParamType::Text),
+            "int" | "integer" => Ok(ParamType::Integer),
+            "float" | "real" => Ok(ParamType::Real),
+            "bool" | "boolean" => Ok(ParamType::Bool),
+            _ => Err(format!(
+                "unknown parameter type '{}' (expected text, int, float or bool)",
+                name
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParamType::Text => "text",
+            ParamType::Integer => "int",
+            ParamType::Real => "float",
+            ParamType::Bool => "bool",
+        }
+    }
 }
 
 #[cfg(test)]
@@ -214,6 +3029,29 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_write_query_valid() {
+        assert!(WriteQuery::parse("INSERT INTO books (title) VALUES (:title)").is_ok());
+        assert!(WriteQuery::parse("  update books SET title = ?").is_ok());
+        assert!(WriteQuery::parse("DELETE FROM books WHERE id = ?").is_ok());
+        assert!(WriteQuery::parse("REPLACE INTO books VALUES (?, ?)").is_ok());
+    }
+
+    #[test]
+    fn test_write_query_rejects_others() {
+        assert!(WriteQuery::parse("").is_err());
+        assert!(WriteQuery::parse("SELECT * FROM books").is_err());
+        assert!(WriteQuery::parse("DROP TABLE books").is_err());
+    }
+
+    #[test]
+    fn test_write_method() {
+        assert_eq!(WriteMethod::parse("POST").unwrap(), WriteMethod::Post);
+        assert_eq!(WriteMethod::parse("PATCH").unwrap().as_str(), "PATCH");
+        assert!(WriteMethod::parse("GET").is_err());
+        assert!(WriteMethod::parse("post").is_err());
+    }
+
     #[test]
     fn test_template_path_valid() {
         let path = TemplatePath::parse("template.hbs").unwrap();
@@ -239,6 +3077,808 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_row_limit_valid() {
+        assert_eq!(RowLimit::parse("1000").unwrap().get(), 1000);
+        assert_eq!(RowLimit::parse(" 25 ").unwrap().get(), 25);
+    }
+
+    #[test]
+    fn test_row_limit_rejects_invalid() {
+        assert!(
+            RowLimit::parse("0")
+                .unwrap_err()
+                .contains("greater than zero")
+        );
+        assert!(RowLimit::parse("-5").is_err());
+        assert!(RowLimit::parse("many").is_err());
+        assert!(RowLimit::parse("").is_err());
+    }
+
+    #[test]
+    fn test_theme() {
+        let theme =
+            Theme::parse("$cookie_theme", &["default=light", "root=/site/themes/"]).unwrap();
+        assert_eq!(theme.variable().as_str(), "$cookie_theme");
+        assert_eq!(theme.default(), "light");
+        assert_eq!(theme.root(), "site/themes");
+        assert_eq!(
+            Theme::parse("$cookie_theme", &["default=light"])
+                .unwrap()
+                .root(),
+            "themes"
+        );
+
+        assert!(Theme::is_name("high-contrast_2"));
+        assert!(!Theme::is_name("../etc"));
+        assert!(!Theme::is_name(""));
+
+        assert!(
+            Theme::parse("$cookie_theme", &[])
+                .unwrap_err()
+                .contains("default=")
+        );
+        assert!(Theme::parse("cookie_theme", &["default=light"]).is_err());
+        assert!(Theme::parse("$cookie_theme", &["default=../light"]).is_err());
+        assert!(Theme::parse("$cookie_theme", &["default=light", "root=../themes"]).is_err());
+        assert!(Theme::parse("$cookie_theme", &["default=light", "dir=themes"]).is_err());
+    }
+
+    #[test]
+    fn test_breadcrumbs() {
+        let plain = Breadcrumbs::parse(&["on"]).unwrap();
+        assert!(plain.enabled());
+        assert!(plain.labels_query().is_none());
+
+        let labelled = Breadcrumbs::parse(&[
+            "on",
+            "labels_query=SELECT title FROM pages WHERE path = :path",
+        ])
+        .unwrap();
+        assert!(labelled.labels_query().unwrap().as_str().contains(":path"));
+
+        assert!(!Breadcrumbs::parse(&["off"]).unwrap().enabled());
+        assert!(Breadcrumbs::parse(&["on", "labels_query=DELETE FROM pages"]).is_err());
+        assert!(Breadcrumbs::parse(&["on", "query=SELECT 1"]).is_err());
+        assert!(Breadcrumbs::parse(&["off", "labels_query=SELECT 1"]).is_err());
+        assert!(Breadcrumbs::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn test_pagination() {
+        let pagination = Pagination::parse(&["per_page=20"]).unwrap();
+        assert_eq!(pagination.per_page().get(), 20);
+        assert!(matches!(pagination.mode(), PageMode::Offset(v) if v.as_str() == "$arg_page"));
+
+        let pagination = Pagination::parse(&["per_page=5", "param=p"]).unwrap();
+        assert!(matches!(pagination.mode(), PageMode::Offset(v) if v.as_str() == "$arg_p"));
+
+        assert!(Pagination::parse(&[]).unwrap_err().contains("per_page"));
+        assert!(Pagination::parse(&["per_page=0"]).is_err());
+        assert!(Pagination::parse(&["per_page=5", "param=$p"]).is_err());
+        assert!(Pagination::parse(&["size=5"]).is_err());
+        assert_eq!(pagination.count(), &CountStrategy::Exact);
+    }
+
+    #[test]
+    fn test_count_strategy() {
+        assert_eq!(CountStrategy::parse(&["exact"]), Ok(CountStrategy::Exact));
+        assert_eq!(
+            CountStrategy::parse(&["estimate"]),
+            Ok(CountStrategy::Estimate(None))
+        );
+        assert_eq!(
+            CountStrategy::parse(&["estimate", "table=books"]),
+            Ok(CountStrategy::Estimate(Some("books".to_string())))
+        );
+        assert_eq!(CountStrategy::parse(&["none"]), Ok(CountStrategy::None));
+        assert_eq!(CountStrategy::None.name(), "none");
+
+        assert!(CountStrategy::parse(&["estimate", "table=a;b"]).is_err());
+        assert!(CountStrategy::parse(&["estimate", "books"]).is_err());
+        assert!(CountStrategy::parse(&["none", "table=books"]).is_err());
+        assert!(CountStrategy::parse(&["approximate"]).is_err());
+    }
+
+    #[test]
+    fn test_cursor_pagination() {
+        let args = ["per_page=20", "mode=cursor", "key=id", "secret=s3cret"];
+        let pagination = Pagination::parse(&args).unwrap();
+        let PageMode::Cursor(key) = pagination.mode() else {
+            panic!("expected mode=cursor");
+        };
+        assert_eq!(key.column(), "id");
+        assert!(!key.descending());
+        assert_eq!(key.secret(), "s3cret");
+
+        let pagination = Pagination::parse(&[&args[..], &["order=desc"]].concat()).unwrap();
+        assert!(matches!(pagination.mode(), PageMode::Cursor(key) if key.descending()));
+
+        for (args, error) in [
+            (
+                &["per_page=20", "mode=cursor", "secret=s"][..],
+                "key=column",
+            ),
+            (&["per_page=20", "mode=cursor", "key=id"], "secret="),
+            (
+                &[
+                    "per_page=20",
+                    "mode=cursor",
+                    "key=id",
+                    "secret=s",
+                    "param=p",
+                ],
+                "param=",
+            ),
+            (&["per_page=20", "key=id"], "mode=cursor"),
+            (
+                &["per_page=20", "mode=cursor", "key=a.b", "secret=s"],
+                "key=column",
+            ),
+        ] {
+            let e = Pagination::parse(args).unwrap_err();
+            assert!(e.contains(error), "{}: {}", args.join(" "), e);
+        }
+    }
+
+    #[test]
+    fn test_aggregate_spec_valid() {
+        let spec = AggregateSpec::parse("min,max,SUM,avg", "columns=price,pages").unwrap();
+        assert_eq!(
+            spec.functions(),
+            &[
+                AggregateFn::Min,
+                AggregateFn::Max,
+                AggregateFn::Sum,
+                AggregateFn::Avg
+            ]
+        );
+        assert_eq!(spec.columns(), &["price".to_string(), "pages".to_string()]);
+    }
+
+    #[test]
+    fn test_aggregate_spec_rejects_invalid() {
+        assert!(
+            AggregateSpec::parse("median", "columns=price")
+                .unwrap_err()
+                .contains("median")
+        );
+        assert!(AggregateSpec::parse("min", "price").is_err());
+        assert!(AggregateSpec::parse("min", "columns=").is_err());
+        assert!(AggregateSpec::parse("min,", "columns=price").is_err());
+    }
+
+    #[test]
+    fn test_data_source_valid() {
+        let source = DataSource::parse("top_rated", "stats.db", "SELECT * FROM books").unwrap();
+        assert_eq!(source.name(), "top_rated");
+        assert_eq!(source.db_path().as_str(), "stats.db");
+        assert_eq!(source.query().as_str(), "SELECT * FROM books");
+    }
+
+    #[test]
+    fn test_data_source_rejects_invalid() {
+        assert!(DataSource::parse("top-rated", "a.db", "SELECT 1").is_err());
+        assert!(DataSource::parse("", "a.db", "SELECT 1").is_err());
+        assert!(
+            DataSource::parse("meta", "a.db", "SELECT 1")
+                .unwrap_err()
+                .contains("reserved")
+        );
+        assert!(DataSource::parse("recent", "", "SELECT 1").is_err());
+        assert!(DataSource::parse("recent", "a.db", "DROP TABLE books").is_err());
+    }
+
+    #[test]
+    fn test_nav_menu() {
+        let menu =
+            NavMenu::parse("main_menu", "site.db", "SELECT label, url FROM menu", &[]).unwrap();
+        assert_eq!(menu.name(), "main_menu");
+        assert_eq!(menu.source().db_path().as_str(), "site.db");
+        assert_eq!(menu.max_age(), NavMenu::DEFAULT_MAX_AGE);
+
+        let menu = NavMenu::parse("footer", "site.db", "SELECT 1", &["max_age=300s"]).unwrap();
+        assert_eq!(menu.max_age(), Duration::from_secs(300));
+
+        assert!(NavMenu::parse("footer", "site.db", "SELECT 1", &["max_age=soon"]).is_err());
+        assert!(NavMenu::parse("footer", "site.db", "SELECT 1", &["ttl=5"]).is_err());
+        assert!(NavMenu::parse("main-menu", "site.db", "SELECT 1", &[]).is_err());
+    }
+
+    #[test]
+    fn test_query_rule() {
+        let rule =
+            QueryRule::parse("$arg_genre", "SELECT * FROM books WHERE genre = :genre").unwrap();
+        assert_eq!(rule.variable().as_str(), "$arg_genre");
+        assert!(rule.query().as_str().contains(":genre"));
+
+        assert!(QueryRule::parse("arg_genre", "SELECT 1").is_err());
+        assert!(QueryRule::parse("$arg_genre", "DELETE FROM books").is_err());
+    }
+
+    #[test]
+    fn test_sort_param() {
+        let sort = SortParam::parse("$arg_sort", &["allow=title,year,rating"]).unwrap();
+        assert_eq!(sort.variable().as_str(), "$arg_sort");
+        assert_eq!(sort.allowed(), ["title", "year", "rating"]);
+        assert_eq!(
+            sort.order_by("title").unwrap().as_deref(),
+            Some("\"title\" ASC")
+        );
+        assert_eq!(
+            sort.order_by("-year, title").unwrap().as_deref(),
+            Some("\"year\" DESC, \"title\" ASC")
+        );
+        assert_eq!(sort.order_by("").unwrap(), None);
+        assert!(
+            sort.order_by("title; DROP TABLE books")
+                .unwrap_err()
+                .contains("expected one of title, year, rating")
+        );
+        assert!(sort.order_by("--year").is_err());
+        assert!(sort.order_by("title,").is_err());
+
+        assert!(SortParam::parse("$arg_sort", &[]).is_err());
+        assert!(SortParam::parse("$arg_sort", &["allow=title,\"year\""]).is_err());
+        assert!(SortParam::parse("$arg_sort", &["columns=title"]).is_err());
+        assert!(SortParam::parse("arg_sort", &["allow=title"]).is_err());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dry_run = DryRun::parse("$sqlite_dry_run", &["redact=:password,:token"]).unwrap();
+        assert_eq!(dry_run.gate().as_str(), "$sqlite_dry_run");
+        assert!(dry_run.redacts(":password"));
+        assert!(dry_run.redacts(":token"));
+        assert!(!dry_run.redacts(":name"));
+        assert!(!DryRun::parse("$debug", &[]).unwrap().redacts(":password"));
+
+        assert!(DryRun::parse("debug", &[]).is_err());
+        assert!(DryRun::parse("$debug", &["redact=password"]).is_err());
+        assert!(DryRun::parse("$debug", &["hide=:password"]).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse("year", ">=", "$arg_min_year", Some("int")).unwrap();
+        assert_eq!(filter.column(), "year");
+        assert_eq!(filter.operator(), FilterOperator::GreaterOrEqual);
+        assert_eq!(filter.variable().as_str(), "$arg_min_year");
+        assert_eq!(filter.param_type(), ParamType::Integer);
+
+        let filter = Filter::parse("title", "LIKE", "$arg_q", None).unwrap();
+        assert_eq!(filter.operator().as_sql(), "LIKE");
+        assert_eq!(filter.param_type(), ParamType::Text);
+        assert_eq!(FilterOperator::parse("!=").unwrap().as_sql(), "<>");
+
+        assert!(Filter::parse("year; --", "=", "$arg_year", None).is_err());
+        assert!(Filter::parse("year", "~", "$arg_year", None).is_err());
+        assert!(Filter::parse("year", "=", "arg_year", None).is_err());
+        assert!(Filter::parse("year", "=", "$arg_year", Some("date")).is_err());
+    }
+
+    #[test]
+    fn test_upsert_table() {
+        let upsert = UpsertTable::parse("books", "(isbn)").unwrap();
+        assert_eq!(
+            (upsert.table(), upsert.keys()),
+            ("books", &["isbn".to_string()][..])
+        );
+
+        let upsert = UpsertTable::parse("shelves", "( shelf, position )").unwrap();
+        assert_eq!(upsert.keys(), ["shelf", "position"]);
+
+        assert!(UpsertTable::parse("books", "isbn").is_err());
+        assert!(UpsertTable::parse("books", "()").is_err());
+        assert!(UpsertTable::parse("books; DROP", "(id)").is_err());
+        assert!(UpsertTable::parse("books", "(id, id)").is_err());
+    }
+
+    #[test]
+    fn test_template_variants() {
+        let variants = TemplateVariants::parse(
+            "$http_x_sqlite_template",
+            &[
+                "secret=k3y",
+                "variant-b=list-b.hbs",
+                "compact=list-compact.hbs",
+            ],
+        )
+        .unwrap();
+        assert_eq!(variants.variable().as_str(), "$http_x_sqlite_template");
+        assert_eq!(variants.secret(), Some("k3y"));
+        assert_eq!(
+            variants.template("variant-b").unwrap().as_str(),
+            "list-b.hbs"
+        );
+        assert!(variants.template("list-b").is_none());
+        assert_eq!(variants.templates().count(), 2);
+
+        let unsigned = TemplateVariants::parse("$bucket", &["b=list-b.hbs"]).unwrap();
+        assert!(unsigned.secret().is_none());
+
+        assert!(TemplateVariants::parse("$bucket", &[]).is_err());
+        assert!(TemplateVariants::parse("$bucket", &["secret=k"]).is_err());
+        assert!(TemplateVariants::parse("$bucket", &["b=list-b.txt"]).is_err());
+        assert!(TemplateVariants::parse("$bucket", &["../b=list-b.hbs"]).is_err());
+        assert!(TemplateVariants::parse("$bucket", &["b=x.hbs", "b=y.hbs"]).is_err());
+        assert!(TemplateVariants::parse("$bucket", &["secret=", "b=x.hbs"]).is_err());
+    }
+
+    #[test]
+    fn test_param_type() {
+        assert_eq!(ParamType::parse("int").unwrap(), ParamType::Integer);
+        assert_eq!(ParamType::parse("INTEGER").unwrap(), ParamType::Integer);
+        assert_eq!(ParamType::parse("float").unwrap(), ParamType::Real);
+        assert_eq!(ParamType::parse("bool").unwrap(), ParamType::Bool);
+        assert_eq!(ParamType::parse("text").unwrap(), ParamType::default());
+        assert!(ParamType::parse("date").is_err());
+    }
+
+    #[test]
+    fn test_rest_table() {
+        let rest = RestTable::parse("books", None).unwrap();
+        assert_eq!((rest.table(), rest.key()), ("books", "id"));
+
+        let rest = RestTable::parse("books", Some("key=isbn")).unwrap();
+        assert_eq!(rest.key(), "isbn");
+
+        assert!(RestTable::parse("books; DROP", None).is_err());
+        assert!(RestTable::parse("books", Some("isbn")).is_err());
+        assert!(RestTable::parse("books", Some("key=1st")).is_err());
+    }
+
+    #[test]
+    fn test_soft_delete() {
+        let soft_delete = SoftDelete::parse("column=deleted_at").unwrap();
+        assert_eq!(soft_delete.column(), "deleted_at");
+        assert!(SoftDelete::parse("deleted_at").is_err());
+        assert!(SoftDelete::parse("column=deleted at").is_err());
+
+        let query = SqlQuery::parse("SELECT * FROM books -- all of them").unwrap();
+        assert_eq!(
+            soft_delete.live_rows(&query).unwrap().as_str(),
+            "SELECT * FROM (SELECT * FROM books -- all of them\n) AS live \
+             WHERE live.\"deleted_at\" IS NULL"
+        );
+
+        let delete = WriteQuery::parse("  delete from \"books\"\n WHERE id = :id").unwrap();
+        assert_eq!(
+            soft_delete.mark_deleted(&delete).unwrap().as_str(),
+            "UPDATE \"books\" SET \"deleted_at\" = CURRENT_TIMESTAMP\n WHERE id = :id"
+        );
+        let insert = WriteQuery::parse("INSERT INTO deletions VALUES (:id)").unwrap();
+        assert_eq!(soft_delete.mark_deleted(&insert).unwrap(), insert);
+    }
+
+    #[test]
+    fn test_blob_route() {
+        let route = BlobRoute::parse("files", &[]).unwrap();
+        assert_eq!(route.table(), "files");
+        assert_eq!(
+            route.query(),
+            "SELECT \"files\".\"data\", NULL FROM \"files\" WHERE \"files\".\"sha256\" = ?1 LIMIT 1"
+        );
+
+        let route = BlobRoute::parse("assets", &["data=body", "hash=digest", "type=mime"]).unwrap();
+        assert_eq!(
+            route.query(),
+            "SELECT \"assets\".\"body\", \"assets\".\"mime\" FROM \"assets\" \
+             WHERE \"assets\".\"digest\" = ?1 LIMIT 1"
+        );
+
+        assert!(BlobRoute::parse("files; DROP", &[]).is_err());
+        assert!(BlobRoute::parse("files", &["type=\"mime\""]).is_err());
+        assert!(BlobRoute::parse("files", &["mime"]).is_err());
+        assert!(BlobRoute::parse("files", &["size=bytes"]).is_err());
+    }
+
+    #[test]
+    fn test_max_length() {
+        assert_eq!(MaxLength::parse("256").unwrap().get(), 256);
+        assert!(MaxLength::parse("0").is_err());
+        assert!(MaxLength::parse("2MB").is_err());
+
+        let mut named = ParameterBinding::Named {
+            name: ParamName::parse(":q").unwrap(),
+            variable: NginxVariable::parse("$arg_q").unwrap(),
+            param_type: ParamType::Text,
+            default: None,
+            required: false,
+            pattern: None,
+            max_length: Some(MaxLength::parse("10").unwrap()),
+        };
+        // A parameter's own cap wins
+        named.cap_length(MaxLength::parse("100").unwrap());
+        assert!(matches!(
+            named,
+            ParameterBinding::Named {
+                max_length: Some(MaxLength(10)),
+                ..
+            }
+        ));
+        let mut positional = ParameterBinding::Positional {
+            variable: NginxVariable::parse("$arg_q").unwrap(),
+            max_length: None,
+        };
+        positional.cap_length(MaxLength::parse("100").unwrap());
+        assert!(matches!(
+            positional,
+            ParameterBinding::Positional {
+                max_length: Some(MaxLength(100)),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_param_pattern() {
+        let pattern = ParamPattern::parse("^[a-z0-9-]+$").unwrap();
+        assert_eq!(pattern.as_str(), "^[a-z0-9-]+$");
+        assert!(pattern.is_match("dune-messiah"));
+        assert!(!pattern.is_match("dune%"));
+        assert!(!pattern.is_match(""));
+
+        // The whole value must match, anchored or not
+        let unanchored = ParamPattern::parse("[0-9]+|new").unwrap();
+        assert!(unanchored.is_match("42"));
+        assert!(unanchored.is_match("new"));
+        assert!(!unanchored.is_match("42; DROP"));
+        assert!(!unanchored.is_match("renew"));
+
+        assert!(ParamPattern::parse("").is_err());
+        assert!(ParamPattern::parse("[a-z").is_err());
+    }
+
+    #[test]
+    fn test_hit_counter() {
+        let counter = HitCounter::parse(&["table=page_views", "key=$uri"]).unwrap();
+        assert_eq!(counter.table(), "page_views");
+        assert_eq!(counter.key().as_str(), "$uri");
+
+        assert!(HitCounter::parse(&["key=$uri"]).is_err());
+        assert!(HitCounter::parse(&["table=page_views"]).is_err());
+        assert!(HitCounter::parse(&["table=page views", "key=$uri"]).is_err());
+        assert!(HitCounter::parse(&["table=page_views", "key=uri"]).is_err());
+        assert!(HitCounter::parse(&["table=page_views", "key=$uri", "flush=1"]).is_err());
+        assert!(counter.daily().is_none());
+
+        let daily =
+            HitCounter::parse(&["table=page_views", "key=$uri", "daily=page_views_daily"]).unwrap();
+        assert_eq!(daily.daily(), Some("page_views_daily"));
+        assert!(HitCounter::parse(&["table=page_views", "key=$uri", "daily=by day"]).is_err());
+    }
+
+    #[test]
+    fn test_trending() {
+        let counter = HitCounter::parse(&["table=page_views", "key=$uri"]).unwrap();
+        let all_time = Trending::parse(&["on"]).unwrap();
+        assert!(all_time.enabled());
+        assert_eq!(all_time.days(), None);
+        assert_eq!(all_time.limit(), Trending::DEFAULT_LIMIT);
+        assert_eq!(all_time.max_age(), NavMenu::DEFAULT_MAX_AGE);
+        assert_eq!(
+            all_time.query(&counter).unwrap(),
+            r#"SELECT key, hits FROM "page_views" ORDER BY hits DESC, key LIMIT 10"#
+        );
+
+        let weekly = Trending::parse(&["days=7", "limit=5", "max_age=300s"]).unwrap();
+        assert_eq!(weekly.days(), Some(7));
+        assert_eq!(weekly.max_age(), Duration::from_secs(300));
+        // A window needs daily counts
+        assert!(weekly.query(&counter).is_err());
+        let counter =
+            HitCounter::parse(&["table=page_views", "key=$uri", "daily=page_views_daily"]).unwrap();
+        let query = weekly.query(&counter).unwrap();
+        assert!(query.contains(r#"FROM "page_views_daily""#));
+        assert!(query.contains("date('now', '-7 days')"));
+        assert!(query.ends_with("LIMIT 5"));
+
+        assert!(Trending::parse(&["days=0"]).is_err());
+        assert!(Trending::parse(&["limit=ten"]).is_err());
+        assert!(!Trending::parse(&["off"]).unwrap().enabled());
+        assert!(Trending::parse(&["off", "days=7"]).is_err());
+    }
+
+    #[test]
+    fn test_expansion() {
+        let author = Expansion::parse(
+            "author:",
+            "SELECT * FROM authors WHERE id = :author_id",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(author.name(), "author");
+        assert_eq!(author.param(), ":author_id");
+        assert_eq!(author.key(), "author_id");
+        assert_eq!(author.column(), "id");
+        assert!(!author.many());
+        assert_eq!(
+            author.batched().as_str(),
+            "SELECT * FROM authors WHERE id IN (:author_id)"
+        );
+
+        let books = Expansion::parse(
+            "books",
+            "SELECT b.* FROM books b WHERE b.author_id=:id ORDER BY b.title",
+            &["many"],
+        )
+        .unwrap();
+        assert!(books.many());
+        assert_eq!(books.key(), "id");
+        assert_eq!(books.column(), "author_id");
+        assert_eq!(
+            books.batched().as_str(),
+            "SELECT b.* FROM books b WHERE b.author_id IN (:id) ORDER BY b.title"
+        );
+
+        let query = "SELECT * FROM authors WHERE id = :author_id";
+        assert!(Expansion::parse("the author", query, &[]).is_err());
+        assert!(Expansion::parse("author", query, &["all"]).is_err());
+        assert!(Expansion::parse("author", "SELECT * FROM authors", &[]).is_err());
+        assert!(
+            Expansion::parse(
+                "author",
+                "SELECT * FROM authors WHERE id = :a OR name = :b",
+                &[]
+            )
+            .is_err()
+        );
+        assert!(Expansion::parse("author", "DELETE FROM authors WHERE id = :id", &[]).is_err());
+    }
+
+    #[test]
+    fn test_memo_table() {
+        let memo = MemoTable::parse(&["report_memo", "ttl=1h"]).unwrap();
+        assert!(memo.enabled());
+        assert_eq!(memo.table(), "report_memo");
+        assert_eq!(memo.ttl(), Duration::from_secs(3600));
+        let ttl = |value: &str| {
+            MemoTable::parse(&["memo", &format!("ttl={}", value)]).map(|memo| memo.ttl().as_secs())
+        };
+        assert_eq!(ttl("90"), Ok(90));
+        assert_eq!(ttl("90s"), Ok(90));
+        assert_eq!(ttl("15m"), Ok(900));
+        assert_eq!(ttl("2d"), Ok(172800));
+        assert!(ttl("0h").is_err());
+        assert!(ttl("1w").is_err());
+        assert!(ttl("h").is_err());
+        assert_eq!(
+            MemoTable::parse(&["memo"]).unwrap().ttl(),
+            MemoTable::DEFAULT_TTL
+        );
+
+        assert!(!MemoTable::parse(&["off"]).unwrap().enabled());
+        assert!(MemoTable::parse(&["report memo"]).is_err());
+        assert!(MemoTable::parse(&["memo", "max_age=60"]).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let report = Report::parse(&["weekly-sales", "/var/reports"]).unwrap();
+        assert_eq!(report.name(), "weekly-sales");
+        assert_eq!(report.format(), ReportFormat::Json);
+        assert_eq!(report.path(), Path::new("/var/reports/weekly-sales.json"));
+        let report = Report::parse(&["weekly", "/var/reports/", "format=ndjson"]).unwrap();
+        assert_eq!(report.path(), Path::new("/var/reports/weekly.ndjson"));
+
+        assert!(Report::parse(&["weekly"]).is_err());
+        assert!(Report::parse(&["../weekly", "/var/reports"]).is_err());
+        assert!(Report::parse(&["weekly", "reports"]).is_err());
+        assert_eq!(report.every(), None);
+        let report = Report::parse(&["weekly", "/var/reports", "every=7d"]).unwrap();
+        assert_eq!(report.every(), Some(Duration::from_secs(7 * 86400)));
+        assert_eq!(report.format(), ReportFormat::Json);
+        assert_eq!(
+            Report::parse(&["weekly", "/var/reports", "format=xlsx"]).is_ok(),
+            cfg!(feature = "xlsx")
+        );
+        assert!(Report::parse(&["weekly", "/var/reports", "format=csv"]).is_err());
+        assert!(Report::parse(&["weekly", "/var/reports", "every=0"]).is_err());
+        assert!(Report::parse(&["weekly", "/var/reports", "every=soon"]).is_err());
+        assert!(Report::parse(&["weekly", "/var/reports", "at=6am"]).is_err());
+    }
+
+    #[test]
+    fn test_captcha() {
+        let captcha = Captcha::parse(&["provider=turnstile", "secret=s3cret"]).unwrap();
+        assert_eq!(captcha.provider(), CaptchaProvider::Turnstile);
+        assert_eq!(captcha.secret(), "s3cret");
+        assert_eq!(captcha.verify_uri(), "/_sqlite_captcha");
+        assert_eq!(captcha.field(), "cf-turnstile-response");
+        assert!(!format!("{:?}", captcha).contains("s3cret"));
+
+        let captcha = Captcha::parse(&[
+            "provider=hcaptcha",
+            "secret=k",
+            "verify=/captcha/verify",
+            "field=token",
+        ])
+        .unwrap();
+        assert_eq!(captcha.verify_uri(), "/captcha/verify");
+        assert_eq!(captcha.field(), "token");
+
+        assert!(Captcha::parse(&["secret=k"]).is_err());
+        assert!(Captcha::parse(&["provider=turnstile"]).is_err());
+        assert!(Captcha::parse(&["provider=turnstile", "secret="]).is_err());
+        assert!(Captcha::parse(&["provider=friendly", "secret=k"]).is_err());
+        assert!(Captcha::parse(&["provider=turnstile", "secret=k", "verify=http://x"]).is_err());
+        let error = Captcha::parse(&["provider=turnstile", "s3cret"]).unwrap_err();
+        assert!(!error.contains("s3cret"));
+    }
+
+    #[test]
+    fn test_email_notification() {
+        let notification =
+            EmailNotification::parse(&["template=contact.hbs", "to=owner@example.com"]).unwrap();
+        assert_eq!(notification.template().as_str(), "contact.hbs");
+        assert_eq!(notification.to(), &["owner@example.com"]);
+        assert_eq!(notification.from(), None);
+        assert_eq!(notification.subject(), "Form submission");
+        assert_eq!(notification.sendmail(), "/usr/sbin/sendmail");
+        assert!(notification.applies_to(WriteMethod::Delete));
+
+        let notification = EmailNotification::parse(&[
+            "template=contact.hbs",
+            "to=a@example.com,b@example.com",
+            "from=site@example.com",
+            "subject=New message",
+            "reply_to=email",
+            "on=POST",
+            "sendmail=/usr/local/bin/msmtp",
+        ])
+        .unwrap();
+        assert_eq!(notification.to().len(), 2);
+        assert_eq!(notification.reply_to(), Some("email"));
+        assert!(notification.applies_to(WriteMethod::Post));
+        assert!(!notification.applies_to(WriteMethod::Put));
+
+        let parse = |extra: &str| {
+            EmailNotification::parse(&["template=contact.hbs", "to=owner@example.com", extra])
+        };
+        assert!(EmailNotification::parse(&["to=owner@example.com"]).is_err());
+        assert!(EmailNotification::parse(&["template=contact.hbs"]).is_err());
+        assert!(EmailNotification::parse(&["template=contact.txt", "to=a@b"]).is_err());
+        assert!(parse("to=owner").is_err());
+        assert!(parse("from=a@b\r\nBcc: x@y").is_err());
+        assert!(parse("subject=Caf\u{e9}").is_err());
+        assert!(parse("reply_to=e-mail").is_err());
+        assert!(parse("on=GET").is_err());
+        assert!(parse("sendmail=sendmail").is_err());
+        assert!(parse("cc=x@y").is_err());
+    }
+
+    #[test]
+    fn test_json_expansion() {
+        assert_eq!(JsonExpansion::parse("on").unwrap(), JsonExpansion::All);
+        assert_eq!(JsonExpansion::parse("off").unwrap(), JsonExpansion::Off);
+        assert_eq!(
+            JsonExpansion::parse("columns=tags, meta").unwrap(),
+            JsonExpansion::Columns(vec!["tags".to_string(), "meta".to_string()])
+        );
+        assert!(JsonExpansion::parse("yes").is_err());
+        assert!(JsonExpansion::parse("columns=tags,").is_err());
+    }
+
+    #[test]
+    fn test_row_shape() {
+        let shape = RowShape::new().with_hidden_columns(vec!["password_hash".to_string()]);
+        assert!(shape.hides("password_hash"));
+        assert!(shape.hides("Password_Hash"));
+        assert!(!shape.hides("password"));
+        assert!(!RowShape::default().hides("password_hash"));
+    }
+
+    #[test]
+    fn test_blob_encoding() {
+        assert_eq!(BlobEncoding::parse("hex").unwrap(), BlobEncoding::Hex);
+        assert_eq!(BlobEncoding::parse("base64").unwrap(), BlobEncoding::Base64);
+        assert_eq!(BlobEncoding::parse("skip").unwrap(), BlobEncoding::Skip);
+        assert!(BlobEncoding::parse("base32").is_err());
+        assert_eq!(RowShape::default().blob_encoding(), BlobEncoding::Hex);
+    }
+
+    #[test]
+    fn test_json_nulls() {
+        assert_eq!(JsonNulls::parse("keep").unwrap(), JsonNulls::Keep);
+        assert_eq!(JsonNulls::parse("omit").unwrap(), JsonNulls::Omit);
+        assert_eq!(JsonNulls::default(), JsonNulls::Keep);
+        assert!(JsonNulls::parse("skip").is_err());
+    }
+
+    #[test]
+    fn test_try_static() {
+        let try_static = TryStatic::parse(&["on"]).unwrap();
+        assert!(try_static.enabled());
+        assert_eq!((try_static.max_age(), try_static.save()), (None, false));
+
+        let try_static = TryStatic::parse(&["on", "max_age=300s", "save=on"]).unwrap();
+        assert_eq!((try_static.max_age(), try_static.save()), (Some(300), true));
+
+        assert!(!TryStatic::parse(&["off"]).unwrap().enabled());
+        assert!(TryStatic::parse(&["off", "save=on"]).is_err());
+        assert!(TryStatic::parse(&["yes"]).is_err());
+        assert!(TryStatic::parse(&["on", "max_age=soon"]).is_err());
+        assert!(TryStatic::parse(&["on", "save=yes"]).is_err());
+    }
+
+    #[test]
+    fn test_feed() {
+        let feed = Feed::parse(&["atom"]).unwrap();
+        assert!(feed.enabled());
+        assert_eq!(feed.format(), FeedFormat::Atom);
+        assert_eq!(
+            (feed.title(), feed.link(), feed.updated(), feed.content()),
+            ("title", "link", "updated", "content")
+        );
+        assert_eq!(feed.name(), None);
+
+        let feed = Feed::parse(&[
+            "rss",
+            "title=headline",
+            "link=url",
+            "updated=published_at",
+            "content=body_html",
+            "name=My Blog",
+        ])
+        .unwrap();
+        assert_eq!(feed.format(), FeedFormat::Rss);
+        assert_eq!(
+            (feed.title(), feed.link(), feed.updated(), feed.content()),
+            ("headline", "url", "published_at", "body_html")
+        );
+        assert_eq!(feed.name(), Some("My Blog"));
+
+        assert!(!Feed::parse(&["off"]).unwrap().enabled());
+        assert!(Feed::parse(&["off", "title=x"]).is_err());
+        assert!(Feed::parse(&["json"]).is_err());
+        assert!(Feed::parse(&[]).is_err());
+        assert!(Feed::parse(&["atom", "title="]).is_err());
+        assert!(Feed::parse(&["atom", "author=name"]).is_err());
+    }
+
+    #[test]
+    fn test_stream_buffer() {
+        let buffer = StreamBuffer::parse("64k").unwrap();
+        assert!(buffer.enabled());
+        assert_eq!(buffer.size(), 65536);
+        assert_eq!(buffer.overflow(), StreamOverflow::Wait);
+        assert_eq!(StreamBuffer::parse("1M").unwrap().size(), 1048576);
+        assert_eq!(StreamBuffer::parse("512").unwrap().size(), 512);
+        assert!(!StreamBuffer::parse("off").unwrap().enabled());
+
+        let buffer = buffer.with_overflow(StreamOverflow::parse("drop").unwrap());
+        assert_eq!(buffer.overflow(), StreamOverflow::Drop);
+
+        assert!(StreamBuffer::parse("0").is_err());
+        assert!(StreamBuffer::parse("64g").is_err());
+        assert!(StreamBuffer::parse("k").is_err());
+        assert!(StreamOverflow::parse("block").is_err());
+    }
+
+    #[test]
+    fn test_honeypot() {
+        let honeypot = Honeypot::parse(&["field=website"]).unwrap();
+        assert_eq!(honeypot.field(), "website");
+        assert_eq!(honeypot.min_time(), None);
+        assert_eq!(honeypot.time_field(), "form_time");
+
+        let honeypot = Honeypot::parse(&["field=url", "min_time=3s", "time_field=ts"]).unwrap();
+        assert_eq!(honeypot.min_time(), Some(3));
+        assert_eq!(honeypot.time_field(), "ts");
+        assert_eq!(
+            Honeypot::parse(&["field=url", "min_time=5"])
+                .unwrap()
+                .min_time(),
+            Some(5)
+        );
+
+        assert!(Honeypot::parse(&[]).is_err());
+        assert!(Honeypot::parse(&["website"]).is_err());
+        assert!(Honeypot::parse(&["field=web site"]).is_err());
+        assert!(Honeypot::parse(&["field=url", "min_time=soon"]).is_err());
+    }
+
     #[test]
     fn test_nginx_variable_valid() {
         let var = NginxVariable::parse("$arg_id").unwrap();
@@ -246,6 +3886,78 @@ mod tests {
         assert_eq!(var.name(), "arg_id");
     }
 
+    #[test]
+    fn test_nginx_variable_uri_segment() {
+        assert!(NginxVariable::parse("$uri_segment_2").is_ok());
+        assert!(NginxVariable::parse("$uri_segment_0").is_err());
+        assert!(NginxVariable::parse("$uri_segment_last").is_err());
+    }
+
+    #[test]
+    fn test_nginx_variable_json_body() {
+        let variable = NginxVariable::parse("$json_body.author.name").unwrap();
+        assert_eq!(variable.body_path(), Some("author.name"));
+        assert_eq!(
+            NginxVariable::parse("$arg_title").unwrap().body_path(),
+            None
+        );
+        assert!(NginxVariable::parse("$json_body").is_err());
+        assert!(NginxVariable::parse("$json_body.").is_err());
+        assert!(NginxVariable::parse("$json_body.author..name").is_err());
+    }
+
+    #[test]
+    fn test_nginx_variable_post() {
+        let variable = NginxVariable::parse("$post_title").unwrap();
+        assert_eq!(variable.form_field(), Some("title"));
+        assert!(variable.reads_body());
+        assert!(
+            NginxVariable::parse("$json_body.title")
+                .unwrap()
+                .reads_body()
+        );
+        assert!(!NginxVariable::parse("$arg_title").unwrap().reads_body());
+        assert!(NginxVariable::parse("$post_").is_err());
+    }
+
+    #[test]
+    fn test_nginx_variable_index() {
+        let provided_by_nginx =
+            |name: &str| NginxVariable::parse(name).unwrap().provided_by_nginx();
+        assert!(provided_by_nginx("$arg_id"));
+        assert!(provided_by_nginx("$cookie_session"));
+        assert!(provided_by_nginx("$host"));
+        assert!(!provided_by_nginx("$post_title"));
+        assert!(!provided_by_nginx("$json_body.title"));
+        assert!(!provided_by_nginx("$uri_segment_2"));
+        assert!(!provided_by_nginx("$1"));
+
+        let variable = NginxVariable::parse("$arg_id").unwrap();
+        assert_eq!(variable.index(), None);
+        assert_eq!(variable.with_index(7).index(), Some(7));
+
+        let indexes = vec![("$arg_id".to_string(), 3)];
+        let indexed = |binding: ParameterBinding| {
+            binding
+                .with_variable_index(&indexes)
+                .variable()
+                .and_then(NginxVariable::index)
+        };
+        let named = |variable: &str| ParameterBinding::OptionalNamed {
+            name: ParamName::parse(":id").unwrap(),
+            variable: NginxVariable::parse(variable).unwrap(),
+            max_length: None,
+        };
+        assert_eq!(indexed(named("$arg_id")), Some(3));
+        assert_eq!(indexed(named("$arg_page")), None);
+        assert_eq!(
+            indexed(ParameterBinding::PositionalLiteral {
+                value: "1".to_string()
+            }),
+            None
+        );
+    }
+
     #[test]
     fn test_nginx_variable_rejects_without_dollar() {
         let result = NginxVariable::parse("arg_id");
@@ -446,4 +4158,121 @@ mod tests {
         let path = DatabasePath::parse("test.db").unwrap();
         assert_eq!(path.as_str(), "test.db");
     }
+
+    #[test]
+    fn test_as_of() {
+        let parsed = |value: &str| AsOf::parse(value).map(|a| a.as_str().to_string());
+        assert_eq!(parsed("2024-05-01").unwrap(), "2024-05-01 00:00:00.000");
+        assert_eq!(
+            parsed("2024-05-01T09:30").unwrap(),
+            "2024-05-01 09:30:00.000"
+        );
+        assert_eq!(
+            parsed("2024-05-01 09:30:15.25Z").unwrap(),
+            "2024-05-01 09:30:15.250"
+        );
+        assert_eq!(
+            parsed("2024-05-01T09:30:15.123456").unwrap(),
+            "2024-05-01 09:30:15.123"
+        );
+
+        assert!(parsed("2024-13-01").is_err());
+        assert!(parsed("2024-05-01T24:00").is_err());
+        assert!(parsed("2024-05-01T09:30+02:00").is_err());
+        assert!(parsed("yesterday").is_err());
+        assert!(parsed("2024-05-01' OR 1").is_err());
+    }
+
+    #[test]
+    fn test_database_template() {
+        assert!(DatabaseTemplate::has_variables("/data/$host.db"));
+        assert!(!DatabaseTemplate::has_variables("/data/books.db"));
+
+        let values = |name: &NginxVariable| match name.as_str() {
+            "$host" => Some("example.com".to_string()),
+            "$arg_tenant" => Some("acme".to_string()),
+            "$arg_empty" => Some(String::new()),
+            "$arg_up" => Some("..".to_string()),
+            "$arg_slash" => Some("a/b".to_string()),
+            _ => None,
+        };
+        let resolve = |path: &str| DatabaseTemplate::parse(path).unwrap().resolve(values);
+        assert_eq!(resolve("/data/$host.db").unwrap(), "/data/example.com.db");
+        assert_eq!(
+            resolve("/data/${arg_tenant}_site/$host.db").unwrap(),
+            "/data/acme_site/example.com.db"
+        );
+
+        // Values that are missing or could leave the directory are refused
+        assert!(
+            resolve("/data/$arg_missing.db")
+                .unwrap_err()
+                .contains("not set")
+        );
+        assert!(resolve("/data/$arg_empty.db").is_err());
+        assert!(
+            resolve("/data/$arg_up")
+                .unwrap_err()
+                .contains("not a safe path segment")
+        );
+        assert!(resolve("/data/$arg_slash.db").is_err());
+
+        // The path must start with an absolute directory
+        assert!(DatabaseTemplate::parse("$host.db").is_err());
+        assert!(DatabaseTemplate::parse("data/$host.db").is_err());
+        assert!(DatabaseTemplate::parse("/$host.db").is_err());
+        assert!(DatabaseTemplate::parse("/data/../$host.db").is_err());
+        assert!(DatabaseTemplate::parse("/data/${host.db").is_err());
+        assert!(DatabaseTemplate::parse("/data/$.db").is_err());
+    }
+
+    #[test]
+    fn test_database_map() {
+        let query = "SELECT db_path FROM tenants WHERE host = :host";
+        let map = DatabaseMap::parse("/etc/nginx/tenants.db", query, &[]).unwrap();
+        assert_eq!(map.control().as_str(), "/etc/nginx/tenants.db");
+        assert_eq!(map.max_age(), DatabaseMap::DEFAULT_MAX_AGE);
+        assert!(map.parameters().is_empty());
+
+        let map = map
+            .with_parameters(&[":host".to_string(), ":arg_tenant".to_string()])
+            .unwrap();
+        let variables: Vec<(&str, &str)> = map
+            .parameters()
+            .iter()
+            .map(|(name, variable)| (name.as_str(), variable.as_str()))
+            .collect();
+        assert_eq!(
+            variables,
+            vec![(":host", "$host"), (":arg_tenant", "$arg_tenant")]
+        );
+        assert!(map.clone().with_parameters(&["?1".to_string()]).is_err());
+        assert!(map.clone().with_parameters(&["@host".to_string()]).is_err());
+
+        let map = DatabaseMap::parse("tenants.db", query, &["max_age=5s"]).unwrap();
+        assert_eq!(map.max_age(), Duration::from_secs(5));
+        assert!(DatabaseMap::parse("tenants.db", query, &["max_age=soon"]).is_err());
+        assert!(DatabaseMap::parse("tenants.db", query, &["ttl=5"]).is_err());
+        assert!(DatabaseMap::parse("tenants.db", "DELETE FROM tenants", &[]).is_err());
+        assert!(DatabaseMap::parse("", query, &[]).is_err());
+    }
+
+    #[test]
+    fn test_attachment() {
+        let stats = Attachment::parse("stats", "/var/data/stats.db").unwrap();
+        assert_eq!(
+            (stats.alias(), stats.path()),
+            ("stats", "/var/data/stats.db")
+        );
+
+        let path = DatabasePath::parse("test.db")
+            .unwrap()
+            .with_attachments(vec![stats.clone()]);
+        assert_eq!(path.attachments(), &[stats]);
+
+        assert!(Attachment::parse("main", "other.db").is_err());
+        assert!(Attachment::parse("TEMP", "other.db").is_err());
+        assert!(Attachment::parse("my-db", "other.db").is_err());
+        assert!(Attachment::parse("other", "").is_err());
+    }
 }
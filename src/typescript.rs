@@ -0,0 +1,325 @@
+//! TypeScript declarations for the JSON responses of every location
+//! (`sqlite_typescript`)
+//!
+//! Each location's rows become an interface whose fields follow its query's
+//! result columns, typed by the columns' declared types the way SQLite's
+//! type affinity reads them. A column computed by an expression has no
+//! declared type and may hold any scalar. Fields may be `null`, since a
+//! prepared statement does not say which columns are `NOT NULL`.
+
+use crate::domain::ValidatedConfig;
+use crate::query;
+use crate::types::{BlobEncoding, JsonExpansion, PageMode};
+
+/// The `pagination` objects of numbered and cursor pages
+const PAGINATION: &str = "export interface OffsetPagination {
+  page: number;
+  per_page: number;
+  total: number | null;
+  page_count: number | null;
+  prev: number | null;
+  next: number | null;
+  count: \"exact\" | \"estimate\" | \"none\";
+}
+
+export interface CursorPagination {
+  per_page: number;
+  next: string | null;
+  prev: string | null;
+}
+";
+
+/// Declarations for the responses of `locations`, given by name with their
+/// configuration (or why it is invalid)
+///
+/// A location whose query cannot be prepared is listed as a comment saying
+/// why, so one broken location does not hide the others.
+pub fn declarations(locations: &[(String, Result<ValidatedConfig, String>)]) -> String {
+    let mut out = String::from(
+        "// Response types of this server's sqlite-serve JSON endpoints, generated\n\
+         // from their queries' result columns\n\n",
+    );
+    out.push_str(PAGINATION);
+
+    let mut names: Vec<String> = Vec::new();
+    for (location, config) in locations {
+        let declared = config
+            .as_ref()
+            .map_err(String::clone)
+            .and_then(|config| endpoint(location, config, &mut names));
+        match declared {
+            Ok(declared) => out.push_str(&declared),
+            Err(e) => out.push_str(&format!("\n// {}: not generated: {}\n", location, e)),
+        }
+    }
+    out
+}
+
+/// The row interfaces and response type of one location
+fn endpoint(
+    location: &str,
+    config: &ValidatedConfig,
+    names: &mut Vec<String>,
+) -> Result<String, String> {
+    let conn = query::open_read_only(&config.db_path).map_err(|e| e.to_string())?;
+    // Skipped blobs leave their column out of the row
+    let skip_blobs = config.rows.blob_encoding() == BlobEncoding::Skip;
+    let columns = |sql: &str| {
+        query::column_types(&conn, sql)
+            .map(|columns| {
+                columns
+                    .into_iter()
+                    .filter(|(name, _)| !config.rows.hides(name))
+                    .filter(|(_, declared)| {
+                        !(skip_blobs
+                            && declared
+                                .as_deref()
+                                .is_some_and(|d| d.to_ascii_uppercase().contains("BLOB")))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .map_err(|e| e.to_string())
+    };
+
+    let base = unique_name(&pascal_case(location), names);
+    let row = format!("{}Row", base);
+    let mut fields: Vec<String> = columns(config.query.as_str())?
+        .iter()
+        .map(|(name, declared)| field(name, &column_type(name, declared.as_deref(), config)))
+        .collect();
+    let mut related = String::new();
+    for expansion in &config.expansions {
+        let name = format!("{}{}Row", base, pascal_case(expansion.name()));
+        let related_fields: Vec<String> = columns(expansion.query().as_str())?
+            .iter()
+            .map(|(column, declared)| {
+                field(column, &column_type(column, declared.as_deref(), config))
+            })
+            .collect();
+        related.push_str(&interface(&name, None, &related_fields));
+        // Relations are only nested when ?expand= asks for them
+        let nested = if expansion.many() {
+            format!("{}[]", name)
+        } else {
+            format!("{} | null", name)
+        };
+        fields.push(format!("  {}?: {};", property(expansion.name()), nested));
+    }
+
+    let response = match config.pagination.as_ref().map(|p| p.mode()) {
+        Some(PageMode::Offset(_)) => {
+            format!("{{ results: {}[]; pagination: OffsetPagination }}", row)
+        }
+        Some(PageMode::Cursor(_)) => {
+            format!("{{ results: {}[]; pagination: CursorPagination }}", row)
+        }
+        None => format!("{}[]", row),
+    };
+    Ok(format!(
+        "{}{}\nexport type {}Response = {};\n",
+        interface(&row, Some(location), &fields),
+        related,
+        base,
+        response
+    ))
+}
+
+fn interface(name: &str, location: Option<&str>, fields: &[String]) -> String {
+    let doc = location
+        .map(|location| format!("/** Rows of {} */\n", location.replace("*/", "*\\/")))
+        .unwrap_or_default();
+    format!(
+        "\n{}export interface {} {{\n{}\n}}\n",
+        doc,
+        name,
+        fields.join("\n")
+    )
+}
+
+fn field(name: &str, ts_type: &str) -> String {
+    format!("  {}: {};", property(name), ts_type)
+}
+
+/// A column's TypeScript type from its declared type, by SQLite's affinity
+/// rules; text that is turned into JSON (a `JSON` column, or
+/// `sqlite_expand_json`) may be any value
+fn column_type(name: &str, declared: Option<&str>, config: &ValidatedConfig) -> String {
+    let declared = declared.unwrap_or_default().to_ascii_uppercase();
+    let expanded = match &config.expand_json {
+        JsonExpansion::Off => false,
+        JsonExpansion::All => !(declared.contains("INT") || is_real(&declared)),
+        JsonExpansion::Columns(columns) => columns.iter().any(|c| c == name),
+    };
+    if declared == "JSON" || expanded {
+        return "unknown".to_string();
+    }
+    let ts_type = if declared.contains("INT") {
+        "number"
+    } else if ["CHAR", "CLOB", "TEXT"]
+        .iter()
+        .any(|t| declared.contains(t))
+    {
+        "string"
+    } else if declared.contains("BLOB") {
+        // Blobs are sent as hex or base64 text (`sqlite_blob_encoding`)
+        "string"
+    } else if is_real(&declared) {
+        "number"
+    } else {
+        // Expressions and NUMERIC affinity keep text that is not a number
+        "number | string"
+    };
+    format!("{} | null", ts_type)
+}
+
+fn is_real(declared: &str) -> bool {
+    ["REAL", "FLOA", "DOUB"]
+        .iter()
+        .any(|t| declared.contains(t))
+}
+
+/// A property name, quoted unless it is an identifier
+fn property(name: &str) -> String {
+    let identifier = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if identifier {
+        name.to_string()
+    } else {
+        serde_json::Value::from(name).to_string()
+    }
+}
+
+/// `/api/books` as `ApiBooks`; a location with no letters or digits is `Root`
+fn pascal_case(text: &str) -> String {
+    let name: String = text
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect();
+    match name.chars().next() {
+        None => "Root".to_string(),
+        Some(first) if first.is_ascii_digit() => format!("Location{}", name),
+        Some(_) => name,
+    }
+}
+
+/// `name`, or with a number after it when another location took it
+fn unique_name(name: &str, taken: &mut Vec<String>) -> String {
+    let unique = (1..)
+        .map(|n| match n {
+            1 => name.to_string(),
+            n => format!("{}{}", name, n),
+        })
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap_or_default();
+    taken.push(unique.clone());
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModuleConfig;
+    use crate::parsing;
+    use crate::types::{Expansion, Pagination};
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_declarations() {
+        let db = "/tmp/test_typescript.db";
+        let _ = std::fs::remove_file(db);
+        Connection::open(db)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT NOT NULL, price REAL,
+                                     published DATE, cover BLOB, meta JSON, author_id INTEGER);
+                 CREATE TABLE authors (id INTEGER PRIMARY KEY, name VARCHAR(80));",
+            )
+            .unwrap();
+        let location = |query: &str| ModuleConfig {
+            db_path: Some(db.to_string()),
+            query: Some(query.to_string()),
+            template_path: Some("list.hbs".to_string()),
+            ..Default::default()
+        };
+
+        let mut books = location("SELECT *, count(*) OVER () AS \"row count\" FROM books");
+        books.pagination = Some(Pagination::parse(&["per_page=20"]).unwrap());
+        books.expansions = vec![
+            Expansion::parse("author", "SELECT * FROM authors WHERE id = :author_id", &[]).unwrap(),
+        ];
+        let mut covers = location("SELECT id, cover FROM books");
+        covers.blob_encoding = Some(BlobEncoding::Skip);
+        let locations = vec![
+            ("/api/books".to_string(), parsing::parse_config(&books)),
+            ("/covers".to_string(), parsing::parse_config(&covers)),
+            (
+                "/".to_string(),
+                parsing::parse_config(&location("SELECT name FROM authors")),
+            ),
+            (
+                "/missing".to_string(),
+                parsing::parse_config(&location("SELECT * FROM nowhere")),
+            ),
+            (
+                "/broken".to_string(),
+                Err("sqlite_db is not set".to_string()),
+            ),
+        ];
+        let declared = declarations(&locations);
+
+        assert!(declared.contains("export interface OffsetPagination {"));
+        assert!(declared.contains(
+            "/** Rows of /api/books */\nexport interface ApiBooksRow {
+  id: number | null;
+  title: string | null;
+  price: number | null;
+  published: number | string | null;
+  cover: string | null;
+  meta: unknown;
+  author_id: number | null;
+  \"row count\": number | string | null;
+  author?: ApiBooksAuthorRow | null;
+}"
+        ));
+        assert!(declared.contains(
+            "export interface ApiBooksAuthorRow {\n  id: number | null;\n  name: string | null;\n}"
+        ));
+        assert!(declared.contains(
+            "export type ApiBooksResponse = { results: ApiBooksRow[]; pagination: OffsetPagination };"
+        ));
+        assert!(declared.contains("export type RootResponse = RootRow[];"));
+        // Skipped blobs are not declared
+        assert!(declared.contains("export interface CoversRow {\n  id: number | null;\n}"));
+        assert!(declared.contains("// /missing: not generated: no such table: nowhere"));
+        assert!(declared.contains("// /broken: not generated: sqlite_db is not set"));
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(pascal_case("/api/book-reviews/"), "ApiBookReviews");
+        assert_eq!(pascal_case("/"), "Root");
+        assert_eq!(pascal_case("~ ^/v2/(.*)$"), "V2");
+        assert_eq!(pascal_case("/2024"), "Location2024");
+
+        let mut taken = Vec::new();
+        assert_eq!(unique_name("Books", &mut taken), "Books");
+        assert_eq!(unique_name("Books", &mut taken), "Books2");
+
+        assert_eq!(property("title"), "title");
+        assert_eq!(property("row count"), "\"row count\"");
+        assert_eq!(property("2nd"), "\"2nd\"");
+    }
+}
@@ -1,21 +1,161 @@
 //! Nginx variable resolution utilities
 
-use ngx::ffi::{ngx_hash_key, ngx_http_get_variable, ngx_str_t};
+use crate::types::NginxVariable;
+use ngx::core::Status;
+use ngx::ffi::{
+    ngx_conf_t, ngx_hash_key, ngx_http_add_variable, ngx_http_get_flushed_variable,
+    ngx_http_get_variable, ngx_http_get_variable_index, ngx_http_request_t,
+    ngx_http_variable_value_t, ngx_int_t, ngx_str_t, ngx_uint_t,
+};
 use ngx::http::Request;
 use ngx::ngx_log_debug_http;
 
+/// The variables a failed request sets: its error code and message
+pub const ERROR_VARIABLES: [&str; 2] = ["sqlite_error_code", "sqlite_error_message"];
+
+/// The variable a streamed response sets: the most bytes of it nginx held
+/// unsent for the client at once
+pub const STREAM_VARIABLE: &str = "sqlite_stream_buffered";
+
 /// Resolve a variable name (with $ prefix) or return literal value
 ///
 /// If var_name starts with '$', resolves it as an nginx variable.
 /// Otherwise, returns var_name as a literal string.
+///
+/// `$uri_segment_N` is the Nth segment of the request's path (see
+/// [`path_segment`]), and `$1`, `$2`, ... are the captures of the regex
+/// that matched last, normally the location's; nginx only provides either
+/// to its own scripts.
 pub fn resolve_variable(request: &mut Request, var_name: &str) -> Result<String, String> {
-    if var_name.starts_with('$') {
+    if let Some(n) = var_name
+        .strip_prefix('$')
+        .and_then(|n| n.parse::<usize>().ok())
+    {
+        let r: *mut ngx::ffi::ngx_http_request_t = request.into();
+        let (captures, data) = unsafe {
+            let r = &*r;
+            if r.captures.is_null() || r.captures_data.is_null() {
+                (&[][..], &[][..])
+            } else {
+                let captures = std::slice::from_raw_parts(r.captures, r.ncaptures);
+                // Every capture lies within the whole match, capture 0
+                let end = captures.get(1).map_or(0, |&end| end.max(0) as usize);
+                (captures, std::slice::from_raw_parts(r.captures_data, end))
+            }
+        };
+        let value = capture(captures, data, n)
+            .ok_or_else(|| format!("variable not found: {}", var_name))?;
+        std::str::from_utf8(value)
+            .map(str::to_string)
+            .map_err(|_| format!("invalid UTF-8 in variable: {}", var_name))
+    } else if let Some(index) = var_name.strip_prefix("$uri_segment_") {
+        let path = request
+            .path()
+            .to_str()
+            .map_err(|_| format!("invalid UTF-8 in variable: {}", var_name))?;
+        index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| path_segment(path, index))
+            .map(str::to_string)
+            .ok_or_else(|| format!("variable not found: {}", var_name))
+    } else if var_name.starts_with('$') {
         resolve_nginx_variable(request, var_name)
     } else {
         Ok(var_name.to_string())
     }
 }
 
+/// Capture `n` of a regex match, from nginx's offsets into the subject:
+/// capture `n` runs from `captures[2n]` to `captures[2n + 1]`, and a group
+/// that took no part in the match has negative offsets
+fn capture<'a>(captures: &[isize], data: &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let start = usize::try_from(*captures.get(2 * n)?).ok()?;
+    let end = usize::try_from(*captures.get(2 * n + 1)?).ok()?;
+    data.get(start..end)
+}
+
+/// The `index`th segment of a decoded URI path, counting from 1 and skipping
+/// empty segments, so `/books/123/` has `books` and `123`
+pub fn path_segment(path: &str, index: usize) -> Option<&str> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .nth(index.checked_sub(1)?)
+}
+
+/// Resolve every value of a variable
+///
+/// An `$arg_name` repeated in the query string (`?tag=a&tag=b`) yields each
+/// value in order, raw like `$arg_name` itself; anything else resolves to its
+/// single value.
+pub fn resolve_variable_values(
+    request: &mut Request,
+    var_name: &str,
+) -> Result<Vec<String>, String> {
+    if let Some(arg_name) = var_name.strip_prefix("$arg_") {
+        let values = arg_values(query_string(request), arg_name)?;
+        if !values.is_empty() {
+            return Ok(values);
+        }
+    }
+    resolve_variable(request, var_name).map(|value| vec![value])
+}
+
+/// The name of every argument in the request's query string, lowercased and
+/// without repeats, in order of first appearance
+///
+/// Only names SQLite accepts in a parameter (letters, digits and `_`) are
+/// listed; nginx matches `$arg_name` case-insensitively, so `?Genre=` and
+/// `?genre=` are the same argument.
+pub fn arg_names(request: &mut Request) -> Vec<String> {
+    query_arg_names(query_string(request))
+}
+
+/// The raw query string of a request
+fn query_string(request: &mut Request) -> &[u8] {
+    let r: *mut ngx::ffi::ngx_http_request_t = request.into();
+    let args = unsafe { (*r).args };
+    if args.len == 0 || args.data.is_null() {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(args.data, args.len) }
+    }
+}
+
+pub fn query_arg_names(query: &[u8]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for pair in query.split(|&b| b == b'&') {
+        let name = pair.split(|&b| b == b'=').next().unwrap_or_default();
+        if name.is_empty() || !name.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'_') {
+            continue;
+        }
+        let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Every value of `name` in a query string, matching names case-insensitively
+/// as nginx does for `$arg_name`
+pub fn arg_values(query: &[u8], name: &str) -> Result<Vec<String>, String> {
+    query
+        .split(|&b| b == b'&')
+        .filter_map(|pair| {
+            let eq = pair.iter().position(|&b| b == b'=')?;
+            pair[..eq]
+                .eq_ignore_ascii_case(name.as_bytes())
+                .then(|| &pair[eq + 1..])
+        })
+        .map(|value| {
+            std::str::from_utf8(value)
+                .map(str::to_string)
+                .map_err(|_| format!("invalid UTF-8 in variable: $arg_{}", name))
+        })
+        .collect()
+}
+
 /// Resolve an nginx variable by name
 fn resolve_nginx_variable(request: &mut Request, var_name: &str) -> Result<String, String> {
     let var_name_str = &var_name[1..]; // Remove the '$' prefix
@@ -29,7 +169,128 @@ fn resolve_nginx_variable(request: &mut Request, var_name: &str) -> Result<Strin
     let key = unsafe { ngx_hash_key(name.data, name.len) };
     let r: *mut ngx::ffi::ngx_http_request_t = request.into();
     let var_value = unsafe { ngx_http_get_variable(r, &mut name, key) };
+    variable_value(request, var_value, var_name)
+}
+
+/// Resolve a variable by the index nginx gave it (see [`variable_index`]),
+/// which skips looking its name up in nginx's variable hash
+pub fn resolve_indexed_variable(
+    request: &mut Request,
+    index: usize,
+    var_name: &str,
+) -> Result<String, String> {
+    let r: *mut ngx::ffi::ngx_http_request_t = request.into();
+    // Flushed, as by name, so values nginx does not cache are read afresh
+    let var_value = unsafe { ngx_http_get_flushed_variable(r, index as ngx_uint_t) };
+    variable_value(request, var_value, var_name)
+}
 
+/// Have nginx index a variable while the configuration is loaded, so requests
+/// can resolve it by index
+///
+/// nginx checks every indexed variable exists once the configuration is
+/// loaded, and refuses to start when one does not.
+pub fn variable_index(cf: *mut ngx_conf_t, variable: &NginxVariable) -> Result<usize, String> {
+    let name = variable.name();
+    let mut name = ngx_str_t {
+        len: name.len(),
+        data: name.as_ptr() as *mut u8,
+    };
+    // nginx copies the name into the configuration's pool; NGX_ERROR is negative
+    let index = unsafe { ngx_http_get_variable_index(cf, &mut name) };
+    usize::try_from(index).map_err(|_| format!("cannot index variable {}", variable.as_str()))
+}
+
+/// Add [`ERROR_VARIABLES`] and [`STREAM_VARIABLE`] to nginx's variables
+/// (before the configuration is read, so `log_format` and `error_page`
+/// locations can use them)
+///
+/// Until a request sets them they are not found, which logs as `-`.
+pub fn add_module_variables(cf: *mut ngx_conf_t) -> Result<(), String> {
+    for name in ERROR_VARIABLES.iter().chain([&STREAM_VARIABLE]) {
+        let mut name_str = ngx_str_t {
+            len: name.len(),
+            data: name.as_ptr() as *mut u8,
+        };
+        // nginx copies the name into the configuration's pool
+        let added = unsafe { ngx_http_add_variable(cf, &mut name_str, 0) };
+        if added.is_null() {
+            return Err(format!("cannot add variable ${}", name));
+        }
+        unsafe { (*added).get_handler = Some(unset_module_variable) };
+    }
+    Ok(())
+}
+
+/// The indexes of [`ERROR_VARIABLES`], in the same order
+pub fn error_variable_indexes(cf: *mut ngx_conf_t) -> Result<[usize; 2], String> {
+    let [code, message] = ERROR_VARIABLES
+        .map(|name| variable_index(cf, &NginxVariable::parse(format!("${}", name))?));
+    Ok([code?, message?])
+}
+
+/// The index of [`STREAM_VARIABLE`]
+pub fn stream_variable_index(cf: *mut ngx_conf_t) -> Result<usize, String> {
+    variable_index(cf, &NginxVariable::parse(format!("${}", STREAM_VARIABLE))?)
+}
+
+/// Asked for before a request set it, a module variable is not found
+unsafe extern "C" fn unset_module_variable(
+    _r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    unsafe { (*v).set_not_found(1) };
+    Status::NGX_OK.into()
+}
+
+/// Set a request's [`ERROR_VARIABLES`] (indexed by `indexes`) to a failure's
+/// code and message
+///
+/// The values are stored in the request's own variables rather than the
+/// module's context, which nginx clears on an internal redirect; an
+/// `error_page` location, and the access log, still see them.
+pub fn set_error_variables(request: &mut Request, indexes: [usize; 2], code: &str, message: &str) {
+    for (index, value) in indexes.into_iter().zip([code, message]) {
+        if !set_variable(request, index, value) {
+            return;
+        }
+    }
+}
+
+/// Set a request's [`STREAM_VARIABLE`] (indexed by `index`) to the most bytes
+/// its stream had unsent at once
+pub fn set_stream_variable(request: &mut Request, index: usize, peak: usize) {
+    set_variable(request, index, &peak.to_string());
+}
+
+/// Store `value` as the request's variable `index`, copied into its pool;
+/// false when it could not be allocated
+fn set_variable(request: &mut Request, index: usize, value: &str) -> bool {
+    let r: *mut ngx_http_request_t = request.into();
+    let data = request.pool().alloc(value.len()) as *mut u8;
+    if data.is_null() || unsafe { (*r).variables.is_null() } {
+        return false;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(value.as_ptr(), data, value.len());
+        let slot = &mut *(*r).variables.add(index);
+        slot.set_len(value.len() as u32);
+        slot.set_valid(1);
+        slot.set_no_cacheable(0);
+        slot.set_not_found(0);
+        slot.set_escape(0);
+        slot.data = data;
+    }
+    true
+}
+
+/// A variable's value as nginx returned it
+fn variable_value(
+    request: &mut Request,
+    var_value: *mut ngx_http_variable_value_t,
+    var_name: &str,
+) -> Result<String, String> {
     if var_value.is_null() {
         ngx_log_debug_http!(request, "variable not found: {}", var_name);
         return Err(format!("variable not found: {}", var_name));
@@ -52,6 +313,47 @@ fn resolve_nginx_variable(request: &mut Request, var_name: &str) -> Result<Strin
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arg_values() {
+        let query = b"tag=a&page=2&TAG=b%20c&tag=&tagged=x&tag";
+        assert_eq!(arg_values(query, "tag").unwrap(), vec!["a", "b%20c", ""]);
+        assert_eq!(arg_values(query, "page").unwrap(), vec!["2"]);
+        assert!(arg_values(query, "missing").unwrap().is_empty());
+        assert!(arg_values(b"", "tag").unwrap().is_empty());
+        assert!(arg_values(&[b't', b'=', 0xff], "t").is_err());
+    }
+
+    #[test]
+    fn test_capture() {
+        // "/books/42" against ^/(\w+)/(\d+)(/edit)?$
+        let data = b"/books/42";
+        let captures = [0, 9, 1, 6, 7, 9, -1, -1];
+        assert_eq!(capture(&captures, data, 0), Some(&b"/books/42"[..]));
+        assert_eq!(capture(&captures, data, 1), Some(&b"books"[..]));
+        assert_eq!(capture(&captures, data, 2), Some(&b"42"[..]));
+        assert_eq!(capture(&captures, data, 3), None);
+        assert_eq!(capture(&captures, data, 4), None);
+        assert_eq!(capture(&[], b"", 1), None);
+    }
+
+    #[test]
+    fn test_path_segment() {
+        assert_eq!(path_segment("/books/123", 1), Some("books"));
+        assert_eq!(path_segment("/books/123", 2), Some("123"));
+        assert_eq!(path_segment("//books//123/", 2), Some("123"));
+        assert_eq!(path_segment("/books/123", 3), None);
+        assert_eq!(path_segment("/books/123", 0), None);
+        assert_eq!(path_segment("/", 1), None);
+    }
+
+    #[test]
+    fn test_query_arg_names() {
+        let query = b"genre=sf&Page=2&GENRE=x&flag&bad-name=1&=empty&a%20b=c&page=3";
+        assert_eq!(query_arg_names(query), vec!["genre", "page", "flag"]);
+        assert!(query_arg_names(b"").is_empty());
+    }
 
     #[test]
     fn test_resolve_literal_value() {
@@ -0,0 +1,198 @@
+//! Per-worker-process state (created by `init_process`, dropped by `exit_process`)
+//!
+//! Subsystems that live for the lifetime of a worker (connection pools, caches,
+//! scheduled jobs, metrics) belong on [`WorkerState`]. Request code reaches it
+//! through [`current`] or [`with_state`], which return `None` outside a worker
+//! (e.g. while nginx is still parsing configuration, or in unit tests).
+
+use crate::cache::{DatabaseMapCache, NavCache, RenderSizes};
+use crate::config_cache::ConfigCache;
+use crate::domain::Metrics;
+use crate::hits::HitCounters;
+use crate::mail::Mailer;
+use crate::metrics::MetricsStore;
+use crate::report::ReportScheduler;
+use crate::stream::{ResponseStream, StreamEnd};
+use crate::template::TemplatePool;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// State owned by one nginx worker process
+#[derive(Debug)]
+pub struct WorkerState {
+    pid: u32,
+    started: Instant,
+    metrics: Arc<MetricsStore>,
+    mailer: Mailer,
+    hit_counters: HitCounters,
+    report_scheduler: ReportScheduler,
+    nav_cache: Arc<NavCache>,
+    db_map_cache: Arc<DatabaseMapCache>,
+    render_sizes: Arc<RenderSizes>,
+    config_cache: Arc<ConfigCache>,
+    template_pool: Arc<TemplatePool>,
+}
+
+impl WorkerState {
+    fn new() -> Self {
+        WorkerState {
+            pid: std::process::id(),
+            started: Instant::now(),
+            metrics: Arc::new(MetricsStore::new()),
+            mailer: Mailer::new(),
+            hit_counters: HitCounters::new(),
+            report_scheduler: ReportScheduler::new(),
+            nav_cache: Arc::new(NavCache::new()),
+            db_map_cache: Arc::new(DatabaseMapCache::new()),
+            render_sizes: Arc::new(RenderSizes::new()),
+            config_cache: Arc::new(ConfigCache::new()),
+            template_pool: Arc::new(TemplatePool::new()),
+        }
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Count a request handled by this worker
+    pub fn record_request(&self) {
+        self.metrics.count("requests", 1);
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.metrics.counter("requests")
+    }
+
+    /// Count a write rejected by `sqlite_honeypot`
+    pub fn record_spam_rejection(&self) {
+        self.metrics.count("spam_rejections", 1);
+    }
+
+    pub fn spam_rejections(&self) -> u64 {
+        self.metrics.counter("spam_rejections")
+    }
+
+    /// Count a streamed response that ended: the bytes it sent, how often it
+    /// waited for its client, and whether it ended early
+    pub fn record_stream(&self, stream: &ResponseStream, end: StreamEnd) {
+        self.metrics.count("streams", 1);
+        self.metrics.count("stream_bytes", stream.sent() as u64);
+        self.metrics.count("stream_waits", stream.waits());
+        if let Some(counter) = end.counter() {
+            self.metrics.count(counter, 1);
+        }
+    }
+
+    /// Counters and timings recorded by this worker's requests
+    pub fn metrics(&self) -> Arc<MetricsStore> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// This worker's outgoing mail (`sqlite_notify_email`)
+    pub fn mailer(&self) -> &Mailer {
+        &self.mailer
+    }
+
+    /// This worker's `sqlite_hit_counter` counts
+    pub fn hit_counters(&self) -> &HitCounters {
+        &self.hit_counters
+    }
+
+    /// This worker's runs of `sqlite_report ... every=` reports
+    pub fn report_scheduler(&self) -> &ReportScheduler {
+        &self.report_scheduler
+    }
+
+    /// `sqlite_nav` rows shared by this worker's requests
+    pub fn nav_cache(&self) -> Arc<NavCache> {
+        Arc::clone(&self.nav_cache)
+    }
+
+    /// `sqlite_db_map` lookups shared by this worker's requests
+    pub fn db_map_cache(&self) -> Arc<DatabaseMapCache> {
+        Arc::clone(&self.db_map_cache)
+    }
+
+    /// How large this worker's pages have rendered, by template
+    pub fn render_sizes(&self) -> Arc<RenderSizes> {
+        Arc::clone(&self.render_sizes)
+    }
+
+    /// Locations' validated configuration, shared by this worker's requests
+    pub fn config_cache(&self) -> Arc<ConfigCache> {
+        Arc::clone(&self.config_cache)
+    }
+
+    /// Locations' loaded templates, shared by this worker's requests
+    pub fn template_pool(&self) -> Arc<TemplatePool> {
+        Arc::clone(&self.template_pool)
+    }
+}
+
+static STATE: Mutex<Option<Arc<WorkerState>>> = Mutex::new(None);
+
+/// Create this worker's state, replacing any left over from a previous cycle
+pub fn init() -> Arc<WorkerState> {
+    let state = Arc::new(WorkerState::new());
+    if let Ok(mut slot) = STATE.lock() {
+        *slot = Some(Arc::clone(&state));
+    }
+    state
+}
+
+/// Drop this worker's state, returning it so the caller can report on it
+pub fn shutdown() -> Option<Arc<WorkerState>> {
+    STATE.lock().ok().and_then(|mut slot| slot.take())
+}
+
+/// This worker's state, if `init_process` has run
+pub fn current() -> Option<Arc<WorkerState>> {
+    STATE.lock().ok().and_then(|slot| slot.clone())
+}
+
+/// Run `f` against this worker's state, if there is one
+pub fn with_state<R>(f: impl FnOnce(&WorkerState) -> R) -> Option<R> {
+    current().map(|state| f(&state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::StreamStep;
+    use crate::types::StreamBuffer;
+
+    #[test]
+    fn test_worker_lifecycle() {
+        let state = init();
+        assert_eq!(state.pid(), std::process::id());
+
+        with_state(|s| s.record_request());
+        with_state(|s| s.record_request());
+        assert_eq!(current().unwrap().requests(), 2);
+
+        with_state(|s| s.record_spam_rejection());
+        assert_eq!(current().unwrap().spam_rejections(), 1);
+
+        let buffer = StreamBuffer::parse("4").unwrap();
+        let mut stream = ResponseStream::new(
+            Box::new([b"ab\n", b"cd\n"].map(|line| line.to_vec()).into_iter()),
+            buffer,
+        );
+        while let StreamStep::Send(_) = stream.next(0) {}
+        with_state(|s| s.record_stream(&stream, StreamEnd::Sent));
+        with_state(|s| s.record_stream(&stream, StreamEnd::Dropped));
+        let metrics = current().unwrap().metrics();
+        assert_eq!(metrics.counter("streams"), 2);
+        assert_eq!(metrics.counter("stream_bytes"), 12);
+        assert_eq!(metrics.counter("stream_drops"), 1);
+
+        let finished = shutdown().unwrap();
+        assert_eq!(finished.requests(), 2);
+        assert!(current().is_none());
+        assert!(with_state(|s| s.requests()).is_none());
+    }
+}
@@ -0,0 +1,79 @@
+//! Excel output for `?format=xlsx` and `sqlite_report ... format=xlsx` (the
+//! `xlsx` cargo feature)
+//!
+//! The rows become one worksheet: a bold header row of the first row's
+//! column names, then a row per result. Numbers and booleans keep their
+//! type, so spreadsheets can sum and filter them; NULLs are left blank and
+//! nested JSON is written as its text.
+
+use rust_xlsxwriter::{ColNum, Format, RowNum, Workbook};
+use serde_json::Value;
+
+/// Write rows (JSON objects) to an Excel workbook
+pub fn render(rows: &[Value]) -> Result<Vec<u8>, String> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    let columns: Vec<&String> = match rows.first() {
+        Some(Value::Object(first)) => first.keys().collect(),
+        Some(_) => return Err("a workbook is made from rows of columns".to_string()),
+        None => Vec::new(),
+    };
+    let bold = Format::new().set_bold();
+    for (col, name) in columns.iter().enumerate() {
+        sheet
+            .write_string_with_format(0, column(col)?, name.as_str(), &bold)
+            .map_err(|e| e.to_string())?;
+    }
+    if !columns.is_empty() {
+        sheet.set_freeze_panes(1, 0).map_err(|e| e.to_string())?;
+    }
+
+    for (index, row) in rows.iter().enumerate() {
+        let Value::Object(row) = row else {
+            return Err("a workbook is made from rows of columns".to_string());
+        };
+        let line = RowNum::try_from(index + 1).map_err(|_| "too many rows for a worksheet")?;
+        for (col, name) in columns.iter().enumerate() {
+            let col = column(col)?;
+            let written = match row.get(name.as_str()) {
+                None | Some(Value::Null) => continue,
+                Some(Value::Bool(b)) => sheet.write_boolean(line, col, *b),
+                Some(Value::Number(n)) => match n.as_f64() {
+                    Some(n) => sheet.write_number(line, col, n),
+                    None => sheet.write_string(line, col, n.to_string()),
+                },
+                Some(Value::String(s)) => sheet.write_string(line, col, s.as_str()),
+                Some(nested) => sheet.write_string(line, col, nested.to_string()),
+            };
+            written.map_err(|e| e.to_string())?;
+        }
+    }
+
+    workbook.save_to_buffer().map_err(|e| e.to_string())
+}
+
+fn column(index: usize) -> Result<ColNum, String> {
+    ColNum::try_from(index).map_err(|_| "too many columns for a worksheet".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let rows = vec![
+            json!({"id": 1, "name": "Ada", "paid": true, "tags": ["a"], "note": null}),
+            json!({"id": 2.5, "name": "Grace"}),
+        ];
+        // A workbook is a zip archive
+        assert!(render(&rows).unwrap().starts_with(b"PK"));
+
+        // No rows is still a workbook, with an empty sheet
+        assert!(render(&[]).unwrap().starts_with(b"PK"));
+
+        assert!(render(&[json!(1)]).is_err());
+        assert!(render(&[json!({"id": 1}), json!([2])]).is_err());
+    }
+}